@@ -11,6 +11,7 @@ use std::fs::File;
 use std::os::unix::net::UnixListener;
 
 use uffd_utils::{Runtime, UffdHandler};
+use vmm::snapshot::integrity::SnapshotManifest;
 
 fn main() {
     let mut args = std::env::args();
@@ -24,6 +25,12 @@ fn main() {
     let (stream, _) = listener.accept().expect("Cannot listen on UDS socket");
 
     let mut runtime = Runtime::new(stream, file);
+    // The integrity manifest of the snapshot is optional.
+    if let Some(manifest_path) = args.next() {
+        let manifest =
+            SnapshotManifest::load(manifest_path.as_ref()).expect("Cannot load the manifest");
+        runtime.verify_with(manifest);
+    }
     runtime.run(|uffd_handler: &mut UffdHandler| {
         // Read an event from the userfaultfd.
         let event = uffd_handler
@@ -4,15 +4,20 @@
 // Not everything is used by both binaries
 #![allow(dead_code)]
 
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fs::File;
 use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
 use std::os::unix::net::UnixStream;
 use std::ptr;
+use std::rc::Rc;
 
 use serde::{Deserialize, Serialize};
 use userfaultfd::{Error, Event, Uffd};
 use utils::sock_ctrl_msg::ScmSocket;
+use utils::u64_to_usize;
+use vmm::snapshot::compression::CompressedMemoryFile;
+use vmm::snapshot::integrity::SnapshotManifest;
 
 // This is the same with the one used in src/vmm.
 /// This describes the mapping between Firecracker base virtual address and offset in the
@@ -42,6 +47,53 @@ pub enum MemPageState {
     Anonymous,
 }
 
+/// Where the guest memory contents are read from.
+#[derive(Debug, Clone)]
+pub enum MemoryBacking {
+    /// The memory file, mapped in the address space of the handler.
+    Mapped(*const u8),
+    /// A compressed memory file, decompressed on demand.
+    Compressed(Rc<CompressedMemoryFile>),
+}
+
+/// Verifies the chunks of a mapped memory file against the integrity manifest of the snapshot
+/// the first time they are read. Firecracker authenticates the manifest when loading the
+/// snapshot.
+#[derive(Debug)]
+pub struct ChunkVerifier {
+    manifest: SnapshotManifest,
+    verified: RefCell<Vec<bool>>,
+}
+
+impl ChunkVerifier {
+    pub fn new(manifest: SnapshotManifest) -> Self {
+        let verified = RefCell::new(vec![false; u64_to_usize(manifest.chunk_count())]);
+        Self { manifest, verified }
+    }
+
+    /// Verifies the chunks covering `len` bytes at `offset` in the memory file mapped at
+    /// `buffer`.
+    fn verify(&self, buffer: *const u8, offset: u64, len: usize) {
+        let chunk_size = self.manifest.chunk_size();
+        let mut verified = self.verified.borrow_mut();
+        for index in offset / chunk_size..(offset + len as u64).div_ceil(chunk_size) {
+            if verified[u64_to_usize(index)] {
+                continue;
+            }
+            let (chunk_offset, chunk_len) = self.manifest.chunk_bounds(index);
+            // SAFETY: The manifest matches the size of the memory file, so the chunk lies within
+            // the mapping.
+            let chunk = unsafe {
+                std::slice::from_raw_parts(buffer.add(u64_to_usize(chunk_offset)), chunk_len)
+            };
+            self.manifest
+                .verify_chunk(index, chunk)
+                .expect("Memory file failed integrity verification");
+            verified[u64_to_usize(index)] = true;
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MemRegion {
     pub mapping: GuestRegionUffdMapping,
@@ -52,12 +104,18 @@ pub struct MemRegion {
 pub struct UffdHandler {
     pub mem_regions: Vec<MemRegion>,
     pub page_size: usize,
-    backing_buffer: *const u8,
+    backing: MemoryBacking,
+    verifier: Option<Rc<ChunkVerifier>>,
     uffd: Uffd,
 }
 
 impl UffdHandler {
-    pub fn from_unix_stream(stream: &UnixStream, backing_buffer: *const u8, size: usize) -> Self {
+    pub fn from_unix_stream(
+        stream: &UnixStream,
+        backing: MemoryBacking,
+        verifier: Option<Rc<ChunkVerifier>>,
+        size: usize,
+    ) -> Self {
         let mut message_buf = vec![0u8; 1024];
         let (bytes_read, file) = stream
             .recv_with_fd(&mut message_buf[..])
@@ -84,7 +142,8 @@ impl UffdHandler {
         Self {
             mem_regions,
             page_size,
-            backing_buffer,
+            backing,
+            verifier,
             uffd,
         }
     }
@@ -141,7 +200,22 @@ impl UffdHandler {
 
     fn populate_from_file(&self, region: &MemRegion, dst: u64, len: usize) -> (u64, u64) {
         let offset = dst - region.mapping.base_host_virt_addr;
-        let src = self.backing_buffer as u64 + region.mapping.offset + offset;
+        let mut bounce_buffer = Vec::new();
+        let src = match &self.backing {
+            MemoryBacking::Mapped(buffer) => {
+                if let Some(verifier) = &self.verifier {
+                    verifier.verify(*buffer, region.mapping.offset + offset, len);
+                }
+                *buffer as u64 + region.mapping.offset + offset
+            }
+            MemoryBacking::Compressed(file) => {
+                // Only decompress the chunks covering the faulting range.
+                bounce_buffer.resize(len, 0);
+                file.read_at(&mut bounce_buffer, region.mapping.offset + offset)
+                    .expect("Cannot decompress memory");
+                bounce_buffer.as_ptr() as u64
+            }
+        };
 
         let ret = unsafe {
             self.uffd
@@ -172,13 +246,33 @@ impl UffdHandler {
 pub struct Runtime {
     stream: UnixStream,
     backing_file: File,
-    backing_memory: *mut u8,
+    backing_memory: MemoryBacking,
     backing_memory_size: usize,
+    verifier: Option<Rc<ChunkVerifier>>,
     uffds: HashMap<i32, UffdHandler>,
 }
 
 impl Runtime {
     pub fn new(stream: UnixStream, backing_file: File) -> Self {
+        if CompressedMemoryFile::is_compressed(&backing_file).expect("can not read backing file") {
+            let compressed = CompressedMemoryFile::open(
+                backing_file
+                    .try_clone()
+                    .expect("can not clone backing file"),
+            )
+            .expect("invalid compressed backing file");
+            let backing_memory_size = compressed.memory_size() as usize;
+
+            return Self {
+                stream,
+                backing_file,
+                backing_memory: MemoryBacking::Compressed(Rc::new(compressed)),
+                backing_memory_size,
+                verifier: None,
+                uffds: HashMap::default(),
+            };
+        }
+
         let file_meta = backing_file
             .metadata()
             .expect("can not get backing file metadata");
@@ -202,12 +296,32 @@ impl Runtime {
         Self {
             stream,
             backing_file,
-            backing_memory: ret.cast(),
+            backing_memory: MemoryBacking::Mapped(ret.cast()),
             backing_memory_size,
+            verifier: None,
             uffds: HashMap::default(),
         }
     }
 
+    /// Verifies the memory file against the integrity manifest of the snapshot. Mapped memory
+    /// files are verified chunk by chunk as pages are served, compressed ones are verified at
+    /// once.
+    pub fn verify_with(&mut self, manifest: SnapshotManifest) {
+        match self.backing_memory {
+            MemoryBacking::Mapped(_) => {
+                assert_eq!(
+                    manifest.memory_file_size(),
+                    self.backing_memory_size as u64,
+                    "Memory file size does not match the manifest"
+                );
+                self.verifier = Some(Rc::new(ChunkVerifier::new(manifest)));
+            }
+            MemoryBacking::Compressed(_) => manifest
+                .verify_memory_file(&self.backing_file)
+                .expect("Memory file failed integrity verification"),
+        }
+    }
+
     /// Polls the `UnixStream` and UFFD fds in a loop.
     /// When stream is polled, new uffd is retrieved.
     /// When uffd is polled, page fault is handled by
@@ -248,7 +362,8 @@ impl Runtime {
                         // Handle new uffd from stream
                         let handler = UffdHandler::from_unix_stream(
                             &self.stream,
-                            self.backing_memory,
+                            self.backing_memory.clone(),
+                            self.verifier.clone(),
                             self.backing_memory_size,
                         );
                         pollfds.push(libc::pollfd {
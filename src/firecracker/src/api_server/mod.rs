@@ -212,7 +212,7 @@ mod tests {
     use vmm::rpc_interface::{VmmActionError, VmmData};
     use vmm::seccomp_filters::get_empty_filters;
     use vmm::vmm_config::instance_info::InstanceInfo;
-    use vmm::vmm_config::snapshot::CreateSnapshotParams;
+    use vmm::vmm_config::snapshot::{CreateSnapshotParams, MemoryCompression, SnapshotOutput};
 
     use super::request::cpu_configuration::parse_put_cpu_config;
     use super::*;
@@ -274,8 +274,12 @@ mod tests {
         let response = api_server.serve_vmm_action_request(
             Box::new(VmmAction::CreateSnapshot(CreateSnapshotParams {
                 snapshot_type: SnapshotType::Diff,
-                snapshot_path: PathBuf::new(),
-                mem_file_path: PathBuf::new(),
+                snapshot_output: SnapshotOutput::Path(PathBuf::new()),
+                mem_file_output: SnapshotOutput::Path(PathBuf::new()),
+                compression: MemoryCompression::None,
+                encryption: None,
+                background: false,
+                integrity: None,
             })),
             start_time_us,
         );
@@ -287,8 +291,12 @@ mod tests {
         let response = api_server.serve_vmm_action_request(
             Box::new(VmmAction::CreateSnapshot(CreateSnapshotParams {
                 snapshot_type: SnapshotType::Diff,
-                snapshot_path: PathBuf::new(),
-                mem_file_path: PathBuf::new(),
+                snapshot_output: SnapshotOutput::Path(PathBuf::new()),
+                mem_file_output: SnapshotOutput::Path(PathBuf::new()),
+                compression: MemoryCompression::None,
+                encryption: None,
+                background: false,
+                integrity: None,
             })),
             start_time_us,
         );
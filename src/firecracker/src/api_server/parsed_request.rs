@@ -21,9 +21,13 @@ use super::request::machine_configuration::{
     parse_get_machine_config, parse_patch_machine_config, parse_put_machine_config,
 };
 use super::request::metrics::parse_put_metrics;
+use super::request::migration::parse_put_migration;
 use super::request::mmds::{parse_get_mmds, parse_patch_mmds, parse_put_mmds};
 use super::request::net::{parse_patch_net, parse_put_net};
-use super::request::snapshot::{parse_patch_vm_state, parse_put_snapshot};
+use super::request::rate_limiter_group::{
+    parse_patch_rate_limiter_group, parse_put_rate_limiter_group,
+};
+use super::request::snapshot::{parse_get_snapshot, parse_patch_vm_state, parse_put_snapshot};
 use super::request::version::parse_get_version;
 use super::request::vsock::parse_put_vsock;
 use super::ApiServer;
@@ -83,6 +87,7 @@ impl TryFrom<&Request> for ParsedRequest {
             }
             (Method::Get, "machine-config", None) => parse_get_machine_config(),
             (Method::Get, "mmds", None) => parse_get_mmds(),
+            (Method::Get, "snapshot", None) => parse_get_snapshot(path_tokens.next()),
             (Method::Get, _, Some(_)) => method_to_error(Method::Get),
             (Method::Put, "actions", Some(body)) => parse_put_actions(body),
             (Method::Put, "balloon", Some(body)) => parse_put_balloon(body),
@@ -92,11 +97,17 @@ impl TryFrom<&Request> for ParsedRequest {
             (Method::Put, "logger", Some(body)) => parse_put_logger(body),
             (Method::Put, "machine-config", Some(body)) => parse_put_machine_config(body),
             (Method::Put, "metrics", Some(body)) => parse_put_metrics(body),
+            (Method::Put, "migration", Some(body)) => parse_put_migration(body, path_tokens.next()),
             (Method::Put, "mmds", Some(body)) => parse_put_mmds(body, path_tokens.next()),
             (Method::Put, "network-interfaces", Some(body)) => {
                 parse_put_net(body, path_tokens.next())
             }
-            (Method::Put, "snapshot", Some(body)) => parse_put_snapshot(body, path_tokens.next()),
+            (Method::Put, "rate-limiter-groups", Some(body)) => {
+                parse_put_rate_limiter_group(body, path_tokens.next())
+            }
+            (Method::Put, "snapshot", Some(body)) => {
+                parse_put_snapshot(body, path_tokens.next(), &request.files)
+            }
             (Method::Put, "vsock", Some(body)) => parse_put_vsock(body),
             (Method::Put, "entropy", Some(body)) => parse_put_entropy(body),
             (Method::Put, _, None) => method_to_error(Method::Put),
@@ -107,6 +118,9 @@ impl TryFrom<&Request> for ParsedRequest {
             (Method::Patch, "network-interfaces", Some(body)) => {
                 parse_patch_net(body, path_tokens.next())
             }
+            (Method::Patch, "rate-limiter-groups", Some(body)) => {
+                parse_patch_rate_limiter_group(body, path_tokens.next())
+            }
             (Method::Patch, "vm", Some(body)) => parse_patch_vm_state(body),
             (Method::Patch, _, None) => method_to_error(Method::Patch),
             (method, unknown_uri, _) => Err(RequestError::InvalidPathMethod(
@@ -176,6 +190,7 @@ impl ParsedRequest {
                     &serde_json::json!({ "firecracker_version": version.as_str() }),
                 ),
                 VmmData::FullVmConfig(config) => Self::success_response_with_data(config),
+                VmmData::SnapshotStatus(status) => Self::success_response_with_data(status),
             },
             Err(vmm_action_error) => {
                 let mut response = match vmm_action_error {
@@ -231,6 +246,23 @@ fn describe(method: Method, path: &str, body: Option<&Body>) -> String {
                 )
             }
         }
+        ("/snapshot/create" | "/snapshot/load", Some(payload_value)) => {
+            // Snapshot requests may carry encryption or integrity keys, which must not be logged.
+            const KEY_FIELDS: [&str; 2] = ["encryption", "integrity"];
+            match serde_json::from_slice::<Value>(payload_value.raw()) {
+                Ok(Value::Object(mut fields))
+                    if KEY_FIELDS.iter().any(|field| fields.contains_key(*field)) =>
+                {
+                    for field in KEY_FIELDS {
+                        if let Some(value) = fields.get_mut(field) {
+                            *value = Value::from("[redacted]");
+                        }
+                    }
+                    describe_with_body(method, path, &Body::new(Value::Object(fields).to_string()))
+                }
+                _ => describe_with_body(method, path, payload_value),
+            }
+        }
         (_, Some(payload_value)) => describe_with_body(method, path, payload_value),
     }
 }
@@ -326,6 +358,7 @@ pub mod tests {
     use vmm::vmm_config::balloon::{BalloonDeviceConfig, BalloonStats};
     use vmm::vmm_config::instance_info::InstanceInfo;
     use vmm::vmm_config::machine_config::MachineConfig;
+    use vmm::vmm_config::snapshot::SnapshotStatus;
 
     use super::*;
 
@@ -541,6 +574,24 @@ pub mod tests {
             describe(Method::Put, "path", Some(&Body::new("body"))),
             "Put request on \"path\" with body \"body\""
         );
+        assert_eq!(
+            describe(
+                Method::Put,
+                "/snapshot/load",
+                Some(&Body::new(r#"{"encryption": {"key": "c2VjcmV0"}}"#))
+            ),
+            r#"Put request on "/snapshot/load" with body "{\"encryption\":\"[redacted]\"}""#
+        );
+        assert_eq!(
+            describe(
+                Method::Put,
+                "/snapshot/create",
+                Some(&Body::new(
+                    r#"{"integrity": {"manifest_path": "foo", "key": "c2VjcmV0"}}"#
+                ))
+            ),
+            r#"Put request on "/snapshot/create" with body "{\"integrity\":\"[redacted]\"}""#
+        );
     }
 
     #[test]
@@ -568,6 +619,9 @@ pub mod tests {
                 VmmData::InstanceInformation(info) => {
                     http_response(&serde_json::to_string(info).unwrap(), 200)
                 }
+                VmmData::SnapshotStatus(status) => {
+                    http_response(&serde_json::to_string(status).unwrap(), 200)
+                }
                 VmmData::VmmVersion(version) => http_response(
                     &serde_json::json!({ "firecracker_version": version.as_str() }).to_string(),
                     200,
@@ -589,6 +643,9 @@ pub mod tests {
         verify_ok_response_with(VmmData::MachineConfiguration(MachineConfig::default()));
         verify_ok_response_with(VmmData::MmdsValue(serde_json::from_str("{}").unwrap()));
         verify_ok_response_with(VmmData::InstanceInformation(InstanceInfo::default()));
+        verify_ok_response_with(VmmData::SnapshotStatus(SnapshotStatus::Failed {
+            error: "error".to_string(),
+        }));
         verify_ok_response_with(VmmData::VmmVersion(String::default()));
 
         // Error.
@@ -650,6 +707,18 @@ pub mod tests {
         ParsedRequest::try_from(&req).unwrap();
     }
 
+    #[test]
+    fn test_try_from_get_snapshot_status() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(http_request("GET", "/snapshot/status", None).as_bytes())
+            .unwrap();
+        connection.try_read().unwrap();
+        let req = connection.pop_parsed_request().unwrap();
+        ParsedRequest::try_from(&req).unwrap();
+    }
+
     #[test]
     fn test_try_from_get_mmds() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -836,6 +905,21 @@ pub mod tests {
         ParsedRequest::try_from(&req).unwrap();
     }
 
+    #[test]
+    fn test_try_from_put_rate_limiter_group() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \"group_id\": \"string\", \"bandwidth\": { \"size\": 0, \"one_time_burst\": \
+                    0, \"refill_time\": 0 }, \"ops\": { \"size\": 0, \"one_time_burst\": 0, \
+                    \"refill_time\": 0 } }";
+        sender
+            .write_all(http_request("PUT", "/rate-limiter-groups/string", Some(body)).as_bytes())
+            .unwrap();
+        connection.try_read().unwrap();
+        let req = connection.pop_parsed_request().unwrap();
+        ParsedRequest::try_from(&req).unwrap();
+    }
+
     #[test]
     fn test_try_from_put_snapshot() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -869,6 +953,27 @@ pub mod tests {
         ParsedRequest::try_from(&req).unwrap();
     }
 
+    #[test]
+    fn test_try_from_put_migration() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \"socket_path\": \"foo\", \"max_dirty_pages\": 256 }";
+        sender
+            .write_all(http_request("PUT", "/migration/send", Some(body)).as_bytes())
+            .unwrap();
+        connection.try_read().unwrap();
+        let req = connection.pop_parsed_request().unwrap();
+        ParsedRequest::try_from(&req).unwrap();
+
+        let body = "{ \"socket_path\": \"foo\", \"resume_vm\": true }";
+        sender
+            .write_all(http_request("PUT", "/migration/receive", Some(body)).as_bytes())
+            .unwrap();
+        connection.try_read().unwrap();
+        let req = connection.pop_parsed_request().unwrap();
+        ParsedRequest::try_from(&req).unwrap();
+    }
+
     #[test]
     fn test_try_from_patch_vm() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
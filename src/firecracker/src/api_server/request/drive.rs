@@ -245,5 +245,171 @@ mod tests {
             }
         }"#;
         parse_put_drive(&Body::new(body), Some("1000")).unwrap();
+
+        // PUT with a copy-on-write overlay.
+        let body = r#"{
+            "drive_id": "1000",
+            "path_on_host": "base",
+            "is_root_device": true,
+            "is_read_only": false,
+            "overlay": {
+                "path_on_host": "overlay",
+                "bitmap_path_on_host": "overlay.bitmap"
+            }
+        }"#;
+        parse_put_drive(&Body::new(body), Some("1000")).unwrap();
+
+        // PUT with an incomplete overlay.
+        let body = r#"{
+            "drive_id": "1000",
+            "path_on_host": "base",
+            "is_root_device": true,
+            "is_read_only": false,
+            "overlay": {
+                "path_on_host": "overlay"
+            }
+        }"#;
+        parse_put_drive(&Body::new(body), Some("1000")).unwrap_err();
+
+        // PUT with multiple queues.
+        let body = r#"{
+            "drive_id": "1000",
+            "path_on_host": "dummy",
+            "is_root_device": true,
+            "is_read_only": false,
+            "num_queues": 4
+        }"#;
+        parse_put_drive(&Body::new(body), Some("1000")).unwrap();
+
+        // PUT with tuned Async engine options.
+        let body = r#"{
+            "drive_id": "1000",
+            "path_on_host": "dummy",
+            "is_root_device": true,
+            "is_read_only": false,
+            "io_engine": "Async",
+            "async_io": {
+                "fixed_buffers": true,
+                "sq_poll": true,
+                "sq_poll_idle_ms": 100
+            }
+        }"#;
+        parse_put_drive(&Body::new(body), Some("1000")).unwrap();
+
+        // PUT with direct IO.
+        let body = r#"{
+            "drive_id": "1000",
+            "path_on_host": "dummy",
+            "is_root_device": true,
+            "is_read_only": false,
+            "host_cache_mode": "Direct"
+        }"#;
+        parse_put_drive(&Body::new(body), Some("1000")).unwrap();
+
+        // PUT with an unknown host cache mode.
+        let body = r#"{
+            "drive_id": "1000",
+            "path_on_host": "dummy",
+            "is_root_device": true,
+            "is_read_only": false,
+            "host_cache_mode": "None"
+        }"#;
+        parse_put_drive(&Body::new(body), Some("1000")).unwrap_err();
+
+        // PUT with a rate limiter group.
+        let body = r#"{
+            "drive_id": "1000",
+            "path_on_host": "dummy",
+            "is_root_device": true,
+            "is_read_only": false,
+            "rate_limiter_group": "group"
+        }"#;
+        parse_put_drive(&Body::new(body), Some("1000")).unwrap();
+
+        // PUT with a hash tree.
+        let body = r#"{
+            "drive_id": "1000",
+            "path_on_host": "dummy",
+            "is_root_device": true,
+            "is_read_only": true,
+            "verity": {
+                "hash_tree_path_on_host": "dummy.hashtree",
+                "root_hash": "a9c4e8c6"
+            }
+        }"#;
+        parse_put_drive(&Body::new(body), Some("1000")).unwrap();
+
+        // PUT with a hash tree missing its root hash.
+        let body = r#"{
+            "drive_id": "1000",
+            "path_on_host": "dummy",
+            "is_root_device": true,
+            "is_read_only": true,
+            "verity": {
+                "hash_tree_path_on_host": "dummy.hashtree"
+            }
+        }"#;
+        parse_put_drive(&Body::new(body), Some("1000")).unwrap_err();
+
+        // PUT with an NBD export.
+        let body = r#"{
+            "drive_id": "1000",
+            "is_root_device": false,
+            "is_read_only": false,
+            "nbd": {
+                "transport": "Tcp",
+                "address": "127.0.0.1:10809",
+                "export_name": "disk"
+            }
+        }"#;
+        parse_put_drive(&Body::new(body), Some("1000")).unwrap();
+
+        // PUT with an unknown NBD transport.
+        let body = r#"{
+            "drive_id": "1000",
+            "is_root_device": false,
+            "is_read_only": false,
+            "nbd": {
+                "transport": "Vsock",
+                "address": "3:10809"
+            }
+        }"#;
+        parse_put_drive(&Body::new(body), Some("1000")).unwrap_err();
+
+        // PUT with an encryption key passed as a file descriptor.
+        let body = r#"{
+            "drive_id": "1000",
+            "path_on_host": "dummy",
+            "is_root_device": false,
+            "is_read_only": false,
+            "encryption": {
+                "key_fd": 3
+            }
+        }"#;
+        parse_put_drive(&Body::new(body), Some("1000")).unwrap();
+
+        // PUT with an unknown encryption option.
+        let body = r#"{
+            "drive_id": "1000",
+            "path_on_host": "dummy",
+            "is_root_device": false,
+            "is_read_only": false,
+            "encryption": {
+                "cipher": "aes-xts-plain64"
+            }
+        }"#;
+        parse_put_drive(&Body::new(body), Some("1000")).unwrap_err();
+
+        // PUT with an unknown Async engine option.
+        let body = r#"{
+            "drive_id": "1000",
+            "path_on_host": "dummy",
+            "is_root_device": true,
+            "is_read_only": false,
+            "async_io": {
+                "sq_poll_cpu": 1
+            }
+        }"#;
+        parse_put_drive(&Body::new(body), Some("1000")).unwrap_err();
     }
 }
@@ -74,7 +74,7 @@ pub(crate) fn parse_patch_machine_config(body: &Body) -> Result<ParsedRequest, R
 #[cfg(test)]
 mod tests {
     use vmm::cpu_config::templates::StaticCpuTemplate;
-    use vmm::vmm_config::machine_config::HugePageConfig;
+    use vmm::vmm_config::machine_config::{DirtyTrackingBackend, HugePageConfig};
 
     use super::*;
     use crate::api_server::parsed_request::tests::{depr_action_from_req, vmm_action_from_request};
@@ -123,6 +123,7 @@ mod tests {
                 cpu_template: None,
                 track_dirty_pages: Some(false),
                 huge_pages: Some(expected),
+                dirty_tracking_backend: Some(DirtyTrackingBackend::Bitmap),
             };
             assert_eq!(
                 vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()),
@@ -142,6 +143,7 @@ mod tests {
             cpu_template: Some(StaticCpuTemplate::None),
             track_dirty_pages: Some(false),
             huge_pages: Some(HugePageConfig::None),
+            dirty_tracking_backend: Some(DirtyTrackingBackend::Bitmap),
         };
         assert_eq!(
             vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()),
@@ -161,6 +163,27 @@ mod tests {
             cpu_template: None,
             track_dirty_pages: Some(true),
             huge_pages: Some(HugePageConfig::None),
+            dirty_tracking_backend: Some(DirtyTrackingBackend::Bitmap),
+        };
+        assert_eq!(
+            vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()),
+            VmmAction::UpdateVmConfiguration(expected_config)
+        );
+
+        let body = r#"{
+            "vcpu_count": 8,
+            "mem_size_mib": 1024,
+            "track_dirty_pages": true,
+            "dirty_tracking_backend": "Ring"
+        }"#;
+        let expected_config = MachineConfigUpdate {
+            vcpu_count: Some(8),
+            mem_size_mib: Some(1024),
+            smt: Some(false),
+            cpu_template: None,
+            track_dirty_pages: Some(true),
+            huge_pages: Some(HugePageConfig::None),
+            dirty_tracking_backend: Some(DirtyTrackingBackend::Ring),
         };
         assert_eq!(
             vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()),
@@ -184,6 +207,7 @@ mod tests {
                 cpu_template: Some(StaticCpuTemplate::T2),
                 track_dirty_pages: Some(true),
                 huge_pages: Some(HugePageConfig::None),
+                dirty_tracking_backend: Some(DirtyTrackingBackend::Bitmap),
             };
             assert_eq!(
                 vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()),
@@ -209,6 +233,7 @@ mod tests {
             cpu_template: None,
             track_dirty_pages: Some(true),
             huge_pages: Some(HugePageConfig::None),
+            dirty_tracking_backend: Some(DirtyTrackingBackend::Bitmap),
         };
         assert_eq!(
             vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()),
@@ -0,0 +1,124 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use vmm::rpc_interface::VmmAction;
+use vmm::vmm_config::migration::{ReceiveMigrationParams, SendMigrationParams};
+
+use super::super::parsed_request::{ParsedRequest, RequestError};
+use super::super::request::{Body, Method, StatusCode};
+
+pub(crate) fn parse_put_migration(
+    body: &Body,
+    request_type_from_path: Option<&str>,
+) -> Result<ParsedRequest, RequestError> {
+    match request_type_from_path {
+        Some(request_type) => match request_type {
+            "send" => parse_put_migration_send(body),
+            "receive" => parse_put_migration_receive(body),
+            _ => Err(RequestError::InvalidPathMethod(
+                format!("/migration/{}", request_type),
+                Method::Put,
+            )),
+        },
+        None => Err(RequestError::Generic(
+            StatusCode::BadRequest,
+            "Missing migration operation type.".to_string(),
+        )),
+    }
+}
+
+fn parse_put_migration_send(body: &Body) -> Result<ParsedRequest, RequestError> {
+    let migration_config = serde_json::from_slice::<SendMigrationParams>(body.raw())?;
+    Ok(ParsedRequest::new_sync(VmmAction::SendMigration(
+        migration_config,
+    )))
+}
+
+fn parse_put_migration_receive(body: &Body) -> Result<ParsedRequest, RequestError> {
+    let migration_config = serde_json::from_slice::<ReceiveMigrationParams>(body.raw())?;
+    Ok(ParsedRequest::new_sync(VmmAction::ReceiveMigration(
+        migration_config,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use vmm::vmm_config::migration::{DEFAULT_MAX_DIRTY_PAGES, DEFAULT_MAX_PRECOPY_ITERATIONS};
+
+    use super::*;
+    use crate::api_server::parsed_request::tests::vmm_action_from_request;
+
+    #[test]
+    fn test_parse_put_migration() {
+        let body = r#"{
+            "socket_path": "foo"
+        }"#;
+        let expected_config = SendMigrationParams {
+            socket_path: PathBuf::from("foo"),
+            max_precopy_iterations: DEFAULT_MAX_PRECOPY_ITERATIONS,
+            max_dirty_pages: DEFAULT_MAX_DIRTY_PAGES,
+        };
+        assert_eq!(
+            vmm_action_from_request(parse_put_migration(&Body::new(body), Some("send")).unwrap()),
+            VmmAction::SendMigration(expected_config)
+        );
+
+        let body = r#"{
+            "socket_path": "foo",
+            "max_precopy_iterations": 3,
+            "max_dirty_pages": 256
+        }"#;
+        let expected_config = SendMigrationParams {
+            socket_path: PathBuf::from("foo"),
+            max_precopy_iterations: 3,
+            max_dirty_pages: 256,
+        };
+        assert_eq!(
+            vmm_action_from_request(parse_put_migration(&Body::new(body), Some("send")).unwrap()),
+            VmmAction::SendMigration(expected_config)
+        );
+
+        let body = r#"{
+            "socket_path": "foo",
+            "resume_vm": true
+        }"#;
+        let expected_config = ReceiveMigrationParams {
+            socket_path: PathBuf::from("foo"),
+            enable_diff_snapshots: false,
+            resume_vm: true,
+        };
+        assert_eq!(
+            vmm_action_from_request(
+                parse_put_migration(&Body::new(body), Some("receive")).unwrap()
+            ),
+            VmmAction::ReceiveMigration(expected_config)
+        );
+
+        let invalid_body = r#"{
+            "socket_path": "foo",
+            "invalid_field": "bar"
+        }"#;
+        parse_put_migration(&Body::new(invalid_body), Some("send")).unwrap_err();
+        parse_put_migration(&Body::new(invalid_body), Some("receive")).unwrap_err();
+
+        let body = r#"{
+            "max_dirty_pages": 256
+        }"#;
+        assert_eq!(
+            parse_put_migration(&Body::new(body), Some("send"))
+                .err()
+                .unwrap()
+                .to_string(),
+            "An error occurred when deserializing the json body of a request: missing field \
+             `socket_path` at line 3 column 9."
+        );
+
+        let body = r#"{
+            "socket_path": "foo"
+        }"#;
+        parse_put_migration(&Body::new(body), Some("invalid")).unwrap_err();
+        parse_put_migration(&Body::new(body), None).unwrap_err();
+    }
+}
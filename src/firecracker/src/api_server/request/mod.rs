@@ -11,8 +11,10 @@ pub mod instance_info;
 pub mod logger;
 pub mod machine_configuration;
 pub mod metrics;
+pub mod migration;
 pub mod mmds;
 pub mod net;
+pub mod rate_limiter_group;
 pub mod snapshot;
 pub mod version;
 pub mod vsock;
@@ -0,0 +1,153 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use vmm::logger::{IncMetric, METRICS};
+use vmm::rpc_interface::VmmAction;
+use vmm::vmm_config::rate_limiter_group::RateLimiterGroupConfig;
+
+use super::super::parsed_request::{checked_id, ParsedRequest, RequestError};
+use super::{Body, StatusCode};
+
+pub(crate) fn parse_put_rate_limiter_group(
+    body: &Body,
+    id_from_path: Option<&str>,
+) -> Result<ParsedRequest, RequestError> {
+    METRICS.put_api_requests.rate_limiter_group_count.inc();
+    let id = if let Some(id) = id_from_path {
+        checked_id(id)?
+    } else {
+        METRICS.put_api_requests.rate_limiter_group_fails.inc();
+        return Err(RequestError::EmptyID);
+    };
+
+    let group = serde_json::from_slice::<RateLimiterGroupConfig>(body.raw()).map_err(|err| {
+        METRICS.put_api_requests.rate_limiter_group_fails.inc();
+        err
+    })?;
+    if id != group.group_id {
+        METRICS.put_api_requests.rate_limiter_group_fails.inc();
+        return Err(RequestError::Generic(
+            StatusCode::BadRequest,
+            format!(
+                "The id from the path [{}] does not match the id from the body [{}]!",
+                id, group.group_id
+            ),
+        ));
+    }
+    Ok(ParsedRequest::new_sync(VmmAction::InsertRateLimiterGroup(
+        group,
+    )))
+}
+
+pub(crate) fn parse_patch_rate_limiter_group(
+    body: &Body,
+    id_from_path: Option<&str>,
+) -> Result<ParsedRequest, RequestError> {
+    METRICS.patch_api_requests.rate_limiter_group_count.inc();
+    let id = if let Some(id) = id_from_path {
+        checked_id(id)?
+    } else {
+        METRICS.patch_api_requests.rate_limiter_group_fails.inc();
+        return Err(RequestError::EmptyID);
+    };
+
+    let group = serde_json::from_slice::<RateLimiterGroupConfig>(body.raw()).map_err(|err| {
+        METRICS.patch_api_requests.rate_limiter_group_fails.inc();
+        err
+    })?;
+    if id != group.group_id {
+        METRICS.patch_api_requests.rate_limiter_group_fails.inc();
+        return Err(RequestError::Generic(
+            StatusCode::BadRequest,
+            format!(
+                "The id from the path [{}] does not match the id from the body [{}]!",
+                id, group.group_id
+            ),
+        ));
+    }
+    Ok(ParsedRequest::new_sync(VmmAction::UpdateRateLimiterGroup(
+        group,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use vmm::vmm_config::TokenBucketConfig;
+
+    use super::*;
+    use crate::api_server::parsed_request::tests::vmm_action_from_request;
+
+    #[test]
+    fn test_parse_put_rate_limiter_group_request() {
+        let body = r#"{
+            "group_id": "group",
+            "bandwidth": {
+                "size": 1000,
+                "refill_time": 100
+            }
+        }"#;
+        parse_put_rate_limiter_group(&Body::new(body), None).unwrap_err();
+        parse_put_rate_limiter_group(&Body::new(body), Some("bar")).unwrap_err();
+
+        let expected_config = RateLimiterGroupConfig {
+            group_id: "group".to_string(),
+            bandwidth: Some(TokenBucketConfig {
+                size: 1000,
+                one_time_burst: None,
+                refill_time: 100,
+            }),
+            ops: None,
+        };
+        assert_eq!(
+            vmm_action_from_request(
+                parse_put_rate_limiter_group(&Body::new(body), Some("group")).unwrap()
+            ),
+            VmmAction::InsertRateLimiterGroup(expected_config)
+        );
+
+        // PUT with invalid fields.
+        let body = r#"{
+            "group_id": "group",
+            "invalid_field": true
+        }"#;
+        parse_put_rate_limiter_group(&Body::new(body), Some("group")).unwrap_err();
+    }
+
+    #[test]
+    fn test_parse_patch_rate_limiter_group_request() {
+        let body = r#"{
+            "group_id": "group",
+            "ops": {
+                "size": 100,
+                "refill_time": 1000
+            }
+        }"#;
+        parse_patch_rate_limiter_group(&Body::new(body), None).unwrap_err();
+        parse_patch_rate_limiter_group(&Body::new(body), Some("bar")).unwrap_err();
+
+        let expected_config = RateLimiterGroupConfig {
+            group_id: "group".to_string(),
+            bandwidth: None,
+            ops: Some(TokenBucketConfig {
+                size: 100,
+                one_time_burst: None,
+                refill_time: 1000,
+            }),
+        };
+        assert_eq!(
+            vmm_action_from_request(
+                parse_patch_rate_limiter_group(&Body::new(body), Some("group")).unwrap()
+            ),
+            VmmAction::UpdateRateLimiterGroup(expected_config)
+        );
+
+        // PATCH with an invalid bucket.
+        let body = r#"{
+            "group_id": "group",
+            "ops": {
+                "size": 100
+            }
+        }"#;
+        parse_patch_rate_limiter_group(&Body::new(body), Some("group")).unwrap_err();
+    }
+}
@@ -1,12 +1,15 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::fs::File;
+use std::path::PathBuf;
+
 use serde::de::Error as DeserializeError;
 use vmm::logger::{IncMetric, METRICS};
 use vmm::rpc_interface::VmmAction;
 use vmm::vmm_config::snapshot::{
-    CreateSnapshotParams, LoadSnapshotConfig, LoadSnapshotParams, MemBackendConfig, MemBackendType,
-    Vm, VmState,
+    CreateSnapshotConfig, CreateSnapshotParams, LoadSnapshotConfig, LoadSnapshotParams,
+    MemBackendConfig, MemBackendType, SnapshotOutput, Vm, VmState,
 };
 
 use super::super::parsed_request::{ParsedRequest, RequestError};
@@ -25,10 +28,11 @@ pub const TOO_MANY_FIELDS: &str =
 pub(crate) fn parse_put_snapshot(
     body: &Body,
     request_type_from_path: Option<&str>,
+    files: &[File],
 ) -> Result<ParsedRequest, RequestError> {
     match request_type_from_path {
         Some(request_type) => match request_type {
-            "create" => parse_put_snapshot_create(body),
+            "create" => parse_put_snapshot_create(body, files),
             "load" => parse_put_snapshot_load(body),
             _ => Err(RequestError::InvalidPathMethod(
                 format!("/snapshot/{}", request_type),
@@ -42,6 +46,22 @@ pub(crate) fn parse_put_snapshot(
     }
 }
 
+pub(crate) fn parse_get_snapshot(
+    request_type_from_path: Option<&str>,
+) -> Result<ParsedRequest, RequestError> {
+    match request_type_from_path {
+        Some("status") => Ok(ParsedRequest::new_sync(VmmAction::GetSnapshotStatus)),
+        Some(request_type) => Err(RequestError::InvalidPathMethod(
+            format!("/snapshot/{}", request_type),
+            Method::Get,
+        )),
+        None => Err(RequestError::Generic(
+            StatusCode::BadRequest,
+            "Missing snapshot operation type.".to_string(),
+        )),
+    }
+}
+
 pub(crate) fn parse_patch_vm_state(body: &Body) -> Result<ParsedRequest, RequestError> {
     let vm = serde_json::from_slice::<Vm>(body.raw())?;
 
@@ -51,13 +71,67 @@ pub(crate) fn parse_patch_vm_state(body: &Body) -> Result<ParsedRequest, Request
     }
 }
 
-fn parse_put_snapshot_create(body: &Body) -> Result<ParsedRequest, RequestError> {
-    let snapshot_config = serde_json::from_slice::<CreateSnapshotParams>(body.raw())?;
+fn parse_put_snapshot_create(body: &Body, files: &[File]) -> Result<ParsedRequest, RequestError> {
+    let snapshot_config = serde_json::from_slice::<CreateSnapshotConfig>(body.raw())?;
+    let snapshot_output = parse_snapshot_output(
+        "snapshot",
+        snapshot_config.snapshot_path,
+        snapshot_config.snapshot_fd,
+        files,
+    )?;
+    let mem_file_output = parse_snapshot_output(
+        "mem_file",
+        snapshot_config.mem_file_path,
+        snapshot_config.mem_file_fd,
+        files,
+    )?;
+
     Ok(ParsedRequest::new_sync(VmmAction::CreateSnapshot(
-        snapshot_config,
+        CreateSnapshotParams {
+            snapshot_type: snapshot_config.snapshot_type,
+            snapshot_output,
+            mem_file_output,
+            compression: snapshot_config.compression,
+            encryption: snapshot_config.encryption,
+            background: snapshot_config.background,
+            integrity: snapshot_config.integrity,
+        },
     )))
 }
 
+/// Builds the destination of a snapshot file out of exactly one of its `{name}_path` and
+/// `{name}_fd` fields, the latter indexing the file descriptors passed along with the request.
+fn parse_snapshot_output(
+    name: &str,
+    path: Option<PathBuf>,
+    fd: Option<usize>,
+    files: &[File],
+) -> Result<SnapshotOutput, RequestError> {
+    match (path, fd) {
+        (Some(path), None) => Ok(SnapshotOutput::Path(path)),
+        (None, Some(index)) => {
+            let file = files.get(index).ok_or_else(|| {
+                RequestError::Generic(
+                    StatusCode::BadRequest,
+                    format!(
+                        "`{name}_fd` does not match a file descriptor passed with the request."
+                    ),
+                )
+            })?;
+            let file = file.try_clone().map_err(|err| {
+                RequestError::Generic(
+                    StatusCode::BadRequest,
+                    format!("Cannot duplicate `{name}_fd`: {err}"),
+                )
+            })?;
+            Ok(SnapshotOutput::File(file))
+        }
+        _ => Err(RequestError::SerdeJson(serde_json::Error::custom(format!(
+            "either `{name}_path` or `{name}_fd` exclusively is required"
+        )))),
+    }
+}
+
 fn parse_put_snapshot_load(body: &Body) -> Result<ParsedRequest, RequestError> {
     let snapshot_config = serde_json::from_slice::<LoadSnapshotConfig>(body.raw())?;
 
@@ -105,6 +179,12 @@ fn parse_put_snapshot_load(body: &Body) -> Result<ParsedRequest, RequestError> {
         mem_backend,
         enable_diff_snapshots: snapshot_config.enable_diff_snapshots,
         resume_vm: snapshot_config.resume_vm,
+        encryption: snapshot_config.encryption,
+        integrity: snapshot_config.integrity,
+        drive_overrides: snapshot_config.drive_overrides,
+        network_overrides: snapshot_config.network_overrides,
+        vsock_override: snapshot_config.vsock_override,
+        clone: snapshot_config.clone,
     };
 
     // Construct the `ParsedRequest` object.
@@ -120,7 +200,13 @@ fn parse_put_snapshot_load(body: &Body) -> Result<ParsedRequest, RequestError> {
 
 #[cfg(test)]
 mod tests {
-    use vmm::vmm_config::snapshot::{MemBackendConfig, MemBackendType};
+    use std::os::unix::fs::MetadataExt;
+
+    use utils::tempfile::TempFile;
+    use vmm::vmm_config::snapshot::{
+        DriveOverride, MemBackendConfig, MemBackendType, NetworkOverride, SnapshotEncryptionConfig,
+        SnapshotIntegrityConfig, VsockOverride,
+    };
 
     use super::*;
     use crate::api_server::parsed_request::tests::{depr_action_from_req, vmm_action_from_request};
@@ -129,7 +215,7 @@ mod tests {
     fn test_parse_put_snapshot() {
         use std::path::PathBuf;
 
-        use vmm::vmm_config::snapshot::SnapshotType;
+        use vmm::vmm_config::snapshot::{MemoryCompression, SnapshotType};
 
         let body = r#"{
             "snapshot_type": "Diff",
@@ -138,11 +224,17 @@ mod tests {
         }"#;
         let expected_config = CreateSnapshotParams {
             snapshot_type: SnapshotType::Diff,
-            snapshot_path: PathBuf::from("foo"),
-            mem_file_path: PathBuf::from("bar"),
+            snapshot_output: SnapshotOutput::Path(PathBuf::from("foo")),
+            mem_file_output: SnapshotOutput::Path(PathBuf::from("bar")),
+            compression: MemoryCompression::None,
+            encryption: None,
+            background: false,
+            integrity: None,
         };
         assert_eq!(
-            vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some("create")).unwrap()),
+            vmm_action_from_request(
+                parse_put_snapshot(&Body::new(body), Some("create"), &[]).unwrap()
+            ),
             VmmAction::CreateSnapshot(expected_config)
         );
 
@@ -152,11 +244,129 @@ mod tests {
         }"#;
         let expected_config = CreateSnapshotParams {
             snapshot_type: SnapshotType::Full,
-            snapshot_path: PathBuf::from("foo"),
-            mem_file_path: PathBuf::from("bar"),
+            snapshot_output: SnapshotOutput::Path(PathBuf::from("foo")),
+            mem_file_output: SnapshotOutput::Path(PathBuf::from("bar")),
+            compression: MemoryCompression::None,
+            encryption: None,
+            background: false,
+            integrity: None,
         };
         assert_eq!(
-            vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some("create")).unwrap()),
+            vmm_action_from_request(
+                parse_put_snapshot(&Body::new(body), Some("create"), &[]).unwrap()
+            ),
+            VmmAction::CreateSnapshot(expected_config)
+        );
+
+        let body = r#"{
+            "snapshot_path": "foo",
+            "mem_file_path": "bar",
+            "compression": "Lz4"
+        }"#;
+        let expected_config = CreateSnapshotParams {
+            snapshot_type: SnapshotType::Full,
+            snapshot_output: SnapshotOutput::Path(PathBuf::from("foo")),
+            mem_file_output: SnapshotOutput::Path(PathBuf::from("bar")),
+            compression: MemoryCompression::Lz4,
+            encryption: None,
+            background: false,
+            integrity: None,
+        };
+        assert_eq!(
+            vmm_action_from_request(
+                parse_put_snapshot(&Body::new(body), Some("create"), &[]).unwrap()
+            ),
+            VmmAction::CreateSnapshot(expected_config)
+        );
+
+        let body = r#"{
+            "snapshot_path": "foo",
+            "mem_file_path": "bar",
+            "encryption": {
+                "key": "c2VjcmV0"
+            }
+        }"#;
+        let expected_config = CreateSnapshotParams {
+            snapshot_type: SnapshotType::Full,
+            snapshot_output: SnapshotOutput::Path(PathBuf::from("foo")),
+            mem_file_output: SnapshotOutput::Path(PathBuf::from("bar")),
+            compression: MemoryCompression::None,
+            encryption: Some(SnapshotEncryptionConfig {
+                key: Some("c2VjcmV0".to_string()),
+                key_fd: None,
+            }),
+            background: false,
+            integrity: None,
+        };
+        assert_eq!(
+            vmm_action_from_request(
+                parse_put_snapshot(&Body::new(body), Some("create"), &[]).unwrap()
+            ),
+            VmmAction::CreateSnapshot(expected_config)
+        );
+
+        let body = r#"{
+            "snapshot_path": "foo",
+            "mem_file_path": "bar",
+            "integrity": {
+                "manifest_path": "baz",
+                "key_fd": 3
+            }
+        }"#;
+        let expected_config = CreateSnapshotParams {
+            snapshot_type: SnapshotType::Full,
+            snapshot_output: SnapshotOutput::Path(PathBuf::from("foo")),
+            mem_file_output: SnapshotOutput::Path(PathBuf::from("bar")),
+            compression: MemoryCompression::None,
+            encryption: None,
+            background: false,
+            integrity: Some(SnapshotIntegrityConfig {
+                manifest_path: PathBuf::from("baz"),
+                key: None,
+                key_fd: Some(3),
+            }),
+        };
+        assert_eq!(
+            vmm_action_from_request(
+                parse_put_snapshot(&Body::new(body), Some("create"), &[]).unwrap()
+            ),
+            VmmAction::CreateSnapshot(expected_config)
+        );
+
+        let invalid_body = r#"{
+            "snapshot_path": "foo",
+            "mem_file_path": "bar",
+            "integrity": {
+                "key": "c2VjcmV0"
+            }
+        }"#;
+        parse_put_snapshot(&Body::new(invalid_body), Some("create"), &[]).unwrap_err();
+
+        let invalid_body = r#"{
+            "snapshot_path": "foo",
+            "mem_file_path": "bar",
+            "compression": "Zstd"
+        }"#;
+        parse_put_snapshot(&Body::new(invalid_body), Some("create"), &[]).unwrap_err();
+
+        let body = r#"{
+            "snapshot_path": "foo",
+            "mem_file_path": "bar",
+            "background": true
+        }"#;
+        let expected_config = CreateSnapshotParams {
+            snapshot_type: SnapshotType::Full,
+            snapshot_output: SnapshotOutput::Path(PathBuf::from("foo")),
+            mem_file_output: SnapshotOutput::Path(PathBuf::from("bar")),
+            compression: MemoryCompression::None,
+            encryption: None,
+            background: true,
+            integrity: None,
+        };
+        assert_eq!(
+            vmm_action_from_request(
+                parse_put_snapshot(&Body::new(body), Some("create"), &[]).unwrap()
+            ),
             VmmAction::CreateSnapshot(expected_config)
         );
 
@@ -164,7 +374,50 @@ mod tests {
             "invalid_field": "foo",
             "mem_file_path": "bar"
         }"#;
-        parse_put_snapshot(&Body::new(invalid_body), Some("create")).unwrap_err();
+        parse_put_snapshot(&Body::new(invalid_body), Some("create"), &[]).unwrap_err();
+
+        // Snapshot files can be passed along with the request.
+        let files = [
+            TempFile::new().unwrap().into_file(),
+            TempFile::new().unwrap().into_file(),
+        ];
+        let body = r#"{
+            "snapshot_path": "foo",
+            "mem_file_fd": 1
+        }"#;
+        let parsed_request = parse_put_snapshot(&Body::new(body), Some("create"), &files).unwrap();
+        match vmm_action_from_request(parsed_request) {
+            VmmAction::CreateSnapshot(CreateSnapshotParams {
+                snapshot_output: SnapshotOutput::Path(snapshot_path),
+                mem_file_output: SnapshotOutput::File(mem_file),
+                ..
+            }) => {
+                assert_eq!(snapshot_path, PathBuf::from("foo"));
+                assert_eq!(
+                    mem_file.metadata().unwrap().ino(),
+                    files[1].metadata().unwrap().ino()
+                );
+            }
+            action => panic!("Unexpected action: {:?}", action),
+        }
+
+        let invalid_body = r#"{
+            "snapshot_path": "foo",
+            "mem_file_fd": 2
+        }"#;
+        parse_put_snapshot(&Body::new(invalid_body), Some("create"), &files).unwrap_err();
+
+        let invalid_body = r#"{
+            "snapshot_path": "foo",
+            "mem_file_path": "bar",
+            "mem_file_fd": 0
+        }"#;
+        parse_put_snapshot(&Body::new(invalid_body), Some("create"), &files).unwrap_err();
+
+        let invalid_body = r#"{
+            "mem_file_path": "bar"
+        }"#;
+        parse_put_snapshot(&Body::new(invalid_body), Some("create"), &[]).unwrap_err();
 
         let body = r#"{
             "snapshot_path": "foo",
@@ -181,8 +434,14 @@ mod tests {
             },
             enable_diff_snapshots: false,
             resume_vm: false,
+            encryption: None,
+            integrity: None,
+            drive_overrides: vec![],
+            network_overrides: vec![],
+            vsock_override: None,
+            clone: false,
         };
-        let mut parsed_request = parse_put_snapshot(&Body::new(body), Some("load")).unwrap();
+        let mut parsed_request = parse_put_snapshot(&Body::new(body), Some("load"), &[]).unwrap();
         assert!(parsed_request
             .parsing_info()
             .take_deprecation_message()
@@ -208,8 +467,14 @@ mod tests {
             },
             enable_diff_snapshots: true,
             resume_vm: false,
+            encryption: None,
+            integrity: None,
+            drive_overrides: vec![],
+            network_overrides: vec![],
+            vsock_override: None,
+            clone: false,
         };
-        let mut parsed_request = parse_put_snapshot(&Body::new(body), Some("load")).unwrap();
+        let mut parsed_request = parse_put_snapshot(&Body::new(body), Some("load"), &[]).unwrap();
         assert!(parsed_request
             .parsing_info()
             .take_deprecation_message()
@@ -235,8 +500,14 @@ mod tests {
             },
             enable_diff_snapshots: false,
             resume_vm: true,
+            encryption: None,
+            integrity: None,
+            drive_overrides: vec![],
+            network_overrides: vec![],
+            vsock_override: None,
+            clone: false,
         };
-        let mut parsed_request = parse_put_snapshot(&Body::new(body), Some("load")).unwrap();
+        let mut parsed_request = parse_put_snapshot(&Body::new(body), Some("load"), &[]).unwrap();
         assert!(parsed_request
             .parsing_info()
             .take_deprecation_message()
@@ -259,13 +530,155 @@ mod tests {
             },
             enable_diff_snapshots: false,
             resume_vm: true,
+            encryption: None,
+            integrity: None,
+            drive_overrides: vec![],
+            network_overrides: vec![],
+            vsock_override: None,
+            clone: false,
         };
-        let parsed_request = parse_put_snapshot(&Body::new(body), Some("load")).unwrap();
+        let parsed_request = parse_put_snapshot(&Body::new(body), Some("load"), &[]).unwrap();
         assert_eq!(
             depr_action_from_req(parsed_request, Some(LOAD_DEPRECATION_MESSAGE.to_string())),
             VmmAction::LoadSnapshot(expected_config)
         );
 
+        let body = r#"{
+            "snapshot_path": "foo",
+            "mem_backend": {
+                "backend_path": "bar",
+                "backend_type": "File"
+            },
+            "encryption": {
+                "key_fd": 3
+            }
+        }"#;
+        let expected_config = LoadSnapshotParams {
+            snapshot_path: PathBuf::from("foo"),
+            mem_backend: MemBackendConfig {
+                backend_path: PathBuf::from("bar"),
+                backend_type: MemBackendType::File,
+            },
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            encryption: Some(SnapshotEncryptionConfig {
+                key: None,
+                key_fd: Some(3),
+            }),
+            integrity: None,
+            drive_overrides: vec![],
+            network_overrides: vec![],
+            vsock_override: None,
+            clone: false,
+        };
+        let parsed_request = parse_put_snapshot(&Body::new(body), Some("load"), &[]).unwrap();
+        assert_eq!(
+            vmm_action_from_request(parsed_request),
+            VmmAction::LoadSnapshot(expected_config)
+        );
+
+        let body = r#"{
+            "snapshot_path": "foo",
+            "mem_backend": {
+                "backend_path": "bar",
+                "backend_type": "Uffd"
+            },
+            "integrity": {
+                "manifest_path": "baz",
+                "key": "c2VjcmV0"
+            }
+        }"#;
+        let expected_config = LoadSnapshotParams {
+            snapshot_path: PathBuf::from("foo"),
+            mem_backend: MemBackendConfig {
+                backend_path: PathBuf::from("bar"),
+                backend_type: MemBackendType::Uffd,
+            },
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            encryption: None,
+            integrity: Some(SnapshotIntegrityConfig {
+                manifest_path: PathBuf::from("baz"),
+                key: Some("c2VjcmV0".to_string()),
+                key_fd: None,
+            }),
+            drive_overrides: vec![],
+            network_overrides: vec![],
+            vsock_override: None,
+            clone: false,
+        };
+        let parsed_request = parse_put_snapshot(&Body::new(body), Some("load"), &[]).unwrap();
+        assert_eq!(
+            vmm_action_from_request(parsed_request),
+            VmmAction::LoadSnapshot(expected_config)
+        );
+
+        let body = r#"{
+            "snapshot_path": "foo",
+            "mem_backend": {
+                "backend_path": "bar",
+                "backend_type": "File"
+            },
+            "drive_overrides": [
+                {
+                    "drive_id": "rootfs",
+                    "path_on_host": "/srv/rootfs.ext4"
+                },
+                {
+                    "drive_id": "scratch",
+                    "socket": "/tmp/vhost-user-blk.sock"
+                }
+            ],
+            "network_overrides": [
+                {
+                    "iface_id": "eth0",
+                    "host_dev_name": "tap1"
+                }
+            ],
+            "vsock_override": {
+                "uds_path": "/tmp/vsock.sock"
+            },
+            "clone": true
+        }"#;
+        let expected_config = LoadSnapshotParams {
+            snapshot_path: PathBuf::from("foo"),
+            mem_backend: MemBackendConfig {
+                backend_path: PathBuf::from("bar"),
+                backend_type: MemBackendType::File,
+            },
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            encryption: None,
+            integrity: None,
+            drive_overrides: vec![
+                DriveOverride {
+                    drive_id: "rootfs".to_string(),
+                    path_on_host: Some("/srv/rootfs.ext4".to_string()),
+                    socket: None,
+                    encryption: None,
+                },
+                DriveOverride {
+                    drive_id: "scratch".to_string(),
+                    path_on_host: None,
+                    socket: Some("/tmp/vhost-user-blk.sock".to_string()),
+                    encryption: None,
+                },
+            ],
+            network_overrides: vec![NetworkOverride {
+                iface_id: "eth0".to_string(),
+                host_dev_name: "tap1".to_string(),
+            }],
+            vsock_override: Some(VsockOverride {
+                uds_path: "/tmp/vsock.sock".to_string(),
+            }),
+            clone: true,
+        };
+        let parsed_request = parse_put_snapshot(&Body::new(body), Some("load"), &[]).unwrap();
+        assert_eq!(
+            vmm_action_from_request(parsed_request),
+            VmmAction::LoadSnapshot(expected_config)
+        );
+
         let body = r#"{
             "snapshot_path": "foo",
             "mem_backend": {
@@ -273,7 +686,7 @@ mod tests {
             }
         }"#;
         assert_eq!(
-            parse_put_snapshot(&Body::new(body), Some("load"))
+            parse_put_snapshot(&Body::new(body), Some("load"), &[])
                 .err()
                 .unwrap()
                 .to_string(),
@@ -288,7 +701,7 @@ mod tests {
             }
         }"#;
         assert_eq!(
-            parse_put_snapshot(&Body::new(body), Some("load"))
+            parse_put_snapshot(&Body::new(body), Some("load"), &[])
                 .err()
                 .unwrap()
                 .to_string(),
@@ -305,7 +718,7 @@ mod tests {
             }
         }"#;
         assert_eq!(
-            parse_put_snapshot(&Body::new(body), Some("load"))
+            parse_put_snapshot(&Body::new(body), Some("load"), &[])
                 .err()
                 .unwrap()
                 .to_string(),
@@ -317,7 +730,7 @@ mod tests {
             "snapshot_path": "foo"
         }"#;
         assert_eq!(
-            parse_put_snapshot(&Body::new(body), Some("load"))
+            parse_put_snapshot(&Body::new(body), Some("load"), &[])
                 .err()
                 .unwrap()
                 .to_string(),
@@ -332,15 +745,25 @@ mod tests {
             }
         }"#;
         assert_eq!(
-            parse_put_snapshot(&Body::new(body), Some("load"))
+            parse_put_snapshot(&Body::new(body), Some("load"), &[])
                 .err()
                 .unwrap()
                 .to_string(),
             "An error occurred when deserializing the json body of a request: missing field \
              `snapshot_path` at line 6 column 9."
         );
-        parse_put_snapshot(&Body::new(body), Some("invalid")).unwrap_err();
-        parse_put_snapshot(&Body::new(body), None).unwrap_err();
+        parse_put_snapshot(&Body::new(body), Some("invalid"), &[]).unwrap_err();
+        parse_put_snapshot(&Body::new(body), None, &[]).unwrap_err();
+    }
+
+    #[test]
+    fn test_parse_get_snapshot() {
+        assert_eq!(
+            vmm_action_from_request(parse_get_snapshot(Some("status")).unwrap()),
+            VmmAction::GetSnapshotStatus
+        );
+        parse_get_snapshot(Some("create")).unwrap_err();
+        parse_get_snapshot(None).unwrap_err();
     }
 
     #[test]
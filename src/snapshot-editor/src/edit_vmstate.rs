@@ -4,21 +4,39 @@
 use std::path::PathBuf;
 
 use clap::Subcommand;
+#[cfg(target_arch = "aarch64")]
 use clap_num::maybe_hex;
+use fc_utils::net::mac::MacAddr;
+#[cfg(target_arch = "aarch64")]
 use vmm::arch::aarch64::regs::Aarch64RegisterVec;
-use vmm::persist::MicrovmState;
+use vmm::devices::virtio::block::persist::BlockState;
+use vmm::devices::virtio::vsock::persist::VsockBackendState;
+use vmm::persist::{MicrovmState, SNAPSHOT_VERSION};
 
-use crate::utils::{open_vmstate, save_vmstate, UtilsError};
+use crate::utils::{open_vmstate, open_vmstate_upgraded, save_vmstate, UtilsError};
 
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
 pub enum EditVmStateError {
     /// {0}
     Utils(#[from] UtilsError),
+    /// Network interface {0} not found in the vmstate.
+    UnknownNetworkInterface(String),
+    /// Network interface {0} was not configured with a guest MAC address.
+    MissingGuestMac(String),
+    /// Drive {0} not found in the vmstate.
+    UnknownDrive(String),
+    /// Drive {0} is a vhost-user drive, which has no path on host.
+    VhostUserDrive(String),
+    /// The vmstate does not contain a vsock device.
+    MissingVsockDevice,
+    /// Device {0} not found in the vmstate.
+    UnknownDevice(String),
 }
 
 #[derive(Debug, Subcommand)]
 pub enum EditVmStateSubCommand {
     /// Remove registers from vcpu states.
+    #[cfg(target_arch = "aarch64")]
     RemoveRegs {
         /// Set of registers to remove.
         /// Values should be registers ids as the are defined in KVM.
@@ -31,10 +49,89 @@ pub enum EditVmStateSubCommand {
         #[arg(short, long)]
         output_path: PathBuf,
     },
+    /// Change the host tap device backing a network interface.
+    SetNetTap {
+        /// Id of the network interface.
+        #[arg(short, long)]
+        iface_id: String,
+        /// Name of the new host tap device.
+        #[arg(long)]
+        host_dev_name: String,
+        /// Path to the vmstate file.
+        #[arg(short, long)]
+        vmstate_path: PathBuf,
+        /// Path of output file.
+        #[arg(short, long)]
+        output_path: PathBuf,
+    },
+    /// Change the guest MAC address of a network interface.
+    SetNetMac {
+        /// Id of the network interface.
+        #[arg(short, long)]
+        iface_id: String,
+        /// New guest MAC address.
+        #[arg(short, long)]
+        guest_mac: MacAddr,
+        /// Path to the vmstate file.
+        #[arg(short, long)]
+        vmstate_path: PathBuf,
+        /// Path of output file.
+        #[arg(short, long)]
+        output_path: PathBuf,
+    },
+    /// Change the host file backing a block device.
+    SetDrivePath {
+        /// Id of the drive.
+        #[arg(short, long)]
+        drive_id: String,
+        /// Path of the new host file.
+        #[arg(short, long)]
+        path_on_host: String,
+        /// Path to the vmstate file.
+        #[arg(short, long)]
+        vmstate_path: PathBuf,
+        /// Path of output file.
+        #[arg(short, long)]
+        output_path: PathBuf,
+    },
+    /// Change the Unix socket path of the vsock device.
+    SetVsockUds {
+        /// Path of the new Unix domain socket.
+        #[arg(short, long)]
+        uds_path: String,
+        /// Path to the vmstate file.
+        #[arg(short, long)]
+        vmstate_path: PathBuf,
+        /// Path of output file.
+        #[arg(short, long)]
+        output_path: PathBuf,
+    },
+    /// Upgrade a vmstate file to the current snapshot format version.
+    Upgrade {
+        /// Path to the vmstate file.
+        #[arg(short, long)]
+        vmstate_path: PathBuf,
+        /// Path of output file.
+        #[arg(short, long)]
+        output_path: PathBuf,
+    },
+    /// Remove a device from the device states.
+    RemoveDevice {
+        /// Id of the device, such as a drive or network interface id.
+        #[arg(short, long)]
+        device_id: String,
+        /// Path to the vmstate file.
+        #[arg(short, long)]
+        vmstate_path: PathBuf,
+        /// Path of output file.
+        #[arg(short, long)]
+        output_path: PathBuf,
+    },
 }
 
 pub fn edit_vmstate_command(command: EditVmStateSubCommand) -> Result<(), EditVmStateError> {
     match command {
+        #[cfg(target_arch = "aarch64")]
         EditVmStateSubCommand::RemoveRegs {
             regs,
             vmstate_path,
@@ -42,6 +139,48 @@ pub fn edit_vmstate_command(command: EditVmStateSubCommand) -> Result<(), EditVm
         } => edit(&vmstate_path, &output_path, |state| {
             remove_regs(state, &regs)
         })?,
+        EditVmStateSubCommand::SetNetTap {
+            iface_id,
+            host_dev_name,
+            vmstate_path,
+            output_path,
+        } => edit(&vmstate_path, &output_path, |state| {
+            set_net_tap(state, &iface_id, &host_dev_name)
+        })?,
+        EditVmStateSubCommand::SetNetMac {
+            iface_id,
+            guest_mac,
+            vmstate_path,
+            output_path,
+        } => edit(&vmstate_path, &output_path, |state| {
+            set_net_mac(state, &iface_id, guest_mac)
+        })?,
+        EditVmStateSubCommand::SetDrivePath {
+            drive_id,
+            path_on_host,
+            vmstate_path,
+            output_path,
+        } => edit(&vmstate_path, &output_path, |state| {
+            set_drive_path(state, &drive_id, &path_on_host)
+        })?,
+        EditVmStateSubCommand::SetVsockUds {
+            uds_path,
+            vmstate_path,
+            output_path,
+        } => edit(&vmstate_path, &output_path, |state| {
+            set_vsock_uds(state, &uds_path)
+        })?,
+        EditVmStateSubCommand::Upgrade {
+            vmstate_path,
+            output_path,
+        } => upgrade(&vmstate_path, &output_path)?,
+        EditVmStateSubCommand::RemoveDevice {
+            device_id,
+            vmstate_path,
+            output_path,
+        } => edit(&vmstate_path, &output_path, |state| {
+            remove_device(state, &device_id)
+        })?,
     }
     Ok(())
 }
@@ -57,6 +196,14 @@ fn edit(
     Ok(())
 }
 
+fn upgrade(vmstate_path: &PathBuf, output_path: &PathBuf) -> Result<(), EditVmStateError> {
+    let (microvm_state, version) = open_vmstate_upgraded(vmstate_path)?;
+    save_vmstate(microvm_state, output_path, SNAPSHOT_VERSION)?;
+    println!("Upgraded snapshot from v{version} to v{SNAPSHOT_VERSION}");
+    Ok(())
+}
+
+#[cfg(target_arch = "aarch64")]
 fn remove_regs(
     mut state: MicrovmState,
     remove_regs: &[u64],
@@ -88,10 +235,145 @@ fn remove_regs(
     Ok(state)
 }
 
+fn set_net_tap(
+    mut state: MicrovmState,
+    iface_id: &str,
+    host_dev_name: &str,
+) -> Result<MicrovmState, EditVmStateError> {
+    let net_state = state
+        .device_states
+        .net_devices
+        .iter_mut()
+        .find(|net| net.device_id == iface_id)
+        .ok_or_else(|| EditVmStateError::UnknownNetworkInterface(iface_id.to_string()))?;
+    net_state.device_state.tap_if_name = host_dev_name.to_string();
+    Ok(state)
+}
+
+fn set_net_mac(
+    mut state: MicrovmState,
+    iface_id: &str,
+    guest_mac: MacAddr,
+) -> Result<MicrovmState, EditVmStateError> {
+    let net_state = state
+        .device_states
+        .net_devices
+        .iter_mut()
+        .find(|net| net.device_id == iface_id)
+        .ok_or_else(|| EditVmStateError::UnknownNetworkInterface(iface_id.to_string()))?;
+    // The MAC address feature was negotiated with the guest driver based on the presence of
+    // a MAC address, so only an existing address can be replaced.
+    let config_mac = net_state
+        .device_state
+        .config_space
+        .guest_mac
+        .as_mut()
+        .ok_or_else(|| EditVmStateError::MissingGuestMac(iface_id.to_string()))?;
+    *config_mac = guest_mac;
+    Ok(state)
+}
+
+fn set_drive_path(
+    mut state: MicrovmState,
+    drive_id: &str,
+    path_on_host: &str,
+) -> Result<MicrovmState, EditVmStateError> {
+    let block_state = state
+        .device_states
+        .block_devices
+        .iter_mut()
+        .find(|block| block.device_id == drive_id)
+        .ok_or_else(|| EditVmStateError::UnknownDrive(drive_id.to_string()))?;
+    match &mut block_state.device_state {
+        BlockState::Virtio(virtio_state) => virtio_state.disk_path = path_on_host.to_string(),
+        BlockState::VhostUser(_) => {
+            return Err(EditVmStateError::VhostUserDrive(drive_id.to_string()))
+        }
+    }
+    Ok(state)
+}
+
+fn set_vsock_uds(
+    mut state: MicrovmState,
+    uds_path: &str,
+) -> Result<MicrovmState, EditVmStateError> {
+    let vsock_state = state
+        .device_states
+        .vsock_device
+        .as_mut()
+        .ok_or(EditVmStateError::MissingVsockDevice)?;
+    match &mut vsock_state.device_state.backend {
+        VsockBackendState::Uds(uds_state) => uds_state.path = uds_path.to_string(),
+    }
+    Ok(state)
+}
+
+fn remove_device(
+    mut state: MicrovmState,
+    device_id: &str,
+) -> Result<MicrovmState, EditVmStateError> {
+    fn remove_if<T>(device: &mut Option<T>, matches: impl Fn(&T) -> bool) -> bool {
+        let remove = device.as_ref().is_some_and(matches);
+        if remove {
+            *device = None;
+        }
+        remove
+    }
+
+    let devices = &mut state.device_states;
+    let block_count = devices.block_devices.len();
+    let net_count = devices.net_devices.len();
+    devices
+        .block_devices
+        .retain(|block| block.device_id != device_id);
+    devices.net_devices.retain(|net| net.device_id != device_id);
+
+    let removed = devices.block_devices.len() != block_count
+        || devices.net_devices.len() != net_count
+        || remove_if(&mut devices.vsock_device, |vsock| {
+            vsock.device_id == device_id
+        })
+        || remove_if(&mut devices.balloon_device, |balloon| {
+            balloon.device_id == device_id
+        })
+        || remove_if(&mut devices.entropy_device, |entropy| {
+            entropy.device_id == device_id
+        });
+    if !removed {
+        return Err(EditVmStateError::UnknownDevice(device_id.to_string()));
+    }
+    Ok(state)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_edit_missing_devices() {
+        assert!(matches!(
+            set_net_tap(MicrovmState::default(), "eth0", "tap0"),
+            Err(EditVmStateError::UnknownNetworkInterface(id)) if id == "eth0"
+        ));
+        assert!(matches!(
+            set_net_mac(MicrovmState::default(), "eth0", MacAddr::from([0x06, 0, 0, 0, 0, 1])),
+            Err(EditVmStateError::UnknownNetworkInterface(id)) if id == "eth0"
+        ));
+        assert!(matches!(
+            set_drive_path(MicrovmState::default(), "rootfs", "/rootfs.ext4"),
+            Err(EditVmStateError::UnknownDrive(id)) if id == "rootfs"
+        ));
+        assert!(matches!(
+            set_vsock_uds(MicrovmState::default(), "/vsock.sock"),
+            Err(EditVmStateError::MissingVsockDevice)
+        ));
+        assert!(matches!(
+            remove_device(MicrovmState::default(), "rootfs"),
+            Err(EditVmStateError::UnknownDevice(id)) if id == "rootfs"
+        ));
+    }
+
+    #[cfg(target_arch = "aarch64")]
     #[test]
     fn test_remove_regs() {
         const KVM_REG_SIZE_U8: u64 = 0;
@@ -151,6 +433,7 @@ mod tests {
         assert_eq!(new_state.vcpu_states[0].regs, expected_vcpu_state.regs);
     }
 
+    #[cfg(target_arch = "aarch64")]
     #[test]
     fn test_remove_non_existed_regs() {
         const KVM_REG_SIZE_U8: u64 = 0;
@@ -179,7 +462,7 @@ mod tests {
         };
 
         let state_clone = MicrovmState {
-            vcpu_states: vec![vcpu_state.clone()],
+            vcpu_states: vec![vcpu_MicrovmState::default()],
             ..Default::default()
         };
 
@@ -3,8 +3,10 @@
 
 use std::path::PathBuf;
 
-use clap::Subcommand;
+use clap::{Subcommand, ValueEnum};
 use semver::Version;
+use serde::Serialize;
+use serde_json::Value;
 use vmm::persist::MicrovmState;
 
 use crate::utils::*;
@@ -13,6 +15,18 @@ use crate::utils::*;
 pub enum InfoVmStateError {
     /// {0}
     Utils(#[from] UtilsError),
+    /// Can not serialize vmstate to JSON: {0}
+    Json(#[from] serde_json::Error),
+}
+
+/// Format of the printed information.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable text.
+    #[default]
+    Text,
+    /// JSON document.
+    Json,
 }
 
 #[derive(Debug, Subcommand)]
@@ -22,55 +36,323 @@ pub enum InfoVmStateSubCommand {
         /// Path to the vmstate file.
         #[arg(short, long)]
         vmstate_path: PathBuf,
+        /// Output format.
+        #[arg(short, long, value_enum, default_value_t)]
+        format: OutputFormat,
     },
     /// Print info about vcpu states.
     VcpuStates {
         /// Path to the vmstate file.
         #[arg(short, long)]
         vmstate_path: PathBuf,
+        /// Output format.
+        #[arg(short, long, value_enum, default_value_t)]
+        format: OutputFormat,
     },
     /// Print readable MicroVM state.
     VmState {
         /// Path to the vmstate file.
         #[arg(short, long)]
         vmstate_path: PathBuf,
+        /// Output format.
+        #[arg(short, long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Print the fields that differ between two vmstate files.
+    Diff {
+        /// Path to the first vmstate file.
+        #[arg(short, long)]
+        vmstate_path: PathBuf,
+        /// Path to the second vmstate file.
+        #[arg(short, long)]
+        other_vmstate_path: PathBuf,
+        /// Output format.
+        #[arg(short, long, value_enum, default_value_t)]
+        format: OutputFormat,
     },
 }
 
 pub fn info_vmstate_command(command: InfoVmStateSubCommand) -> Result<(), InfoVmStateError> {
     match command {
-        InfoVmStateSubCommand::Version { vmstate_path } => info(&vmstate_path, info_version)?,
-        InfoVmStateSubCommand::VcpuStates { vmstate_path } => {
-            info(&vmstate_path, info_vcpu_states)?
-        }
-        InfoVmStateSubCommand::VmState { vmstate_path } => info(&vmstate_path, info_vmstate)?,
+        InfoVmStateSubCommand::Version {
+            vmstate_path,
+            format,
+        } => info(&vmstate_path, format, info_version)?,
+        InfoVmStateSubCommand::VcpuStates {
+            vmstate_path,
+            format,
+        } => info(&vmstate_path, format, info_vcpu_states)?,
+        InfoVmStateSubCommand::VmState {
+            vmstate_path,
+            format,
+        } => info(&vmstate_path, format, info_vmstate)?,
+        InfoVmStateSubCommand::Diff {
+            vmstate_path,
+            other_vmstate_path,
+            format,
+        } => diff(&vmstate_path, &other_vmstate_path, format)?,
     }
     Ok(())
 }
 
 fn info(
     vmstate_path: &PathBuf,
-    f: impl Fn(&MicrovmState, Version) -> Result<(), InfoVmStateError>,
+    format: OutputFormat,
+    f: impl Fn(&MicrovmState, Version, OutputFormat) -> Result<(), InfoVmStateError>,
 ) -> Result<(), InfoVmStateError> {
     let (vmstate, version) = open_vmstate(vmstate_path)?;
-    f(&vmstate, version)?;
+    f(&vmstate, version, format)?;
     Ok(())
 }
 
-fn info_version(_: &MicrovmState, version: Version) -> Result<(), InfoVmStateError> {
-    println!("v{version}");
+fn print_json<T: Serialize + ?Sized>(value: &T) -> Result<(), InfoVmStateError> {
+    println!("{}", serde_json::to_string_pretty(value)?);
     Ok(())
 }
 
-fn info_vcpu_states(state: &MicrovmState, _: Version) -> Result<(), InfoVmStateError> {
-    for (i, state) in state.vcpu_states.iter().enumerate() {
-        println!("vcpu {i}:");
-        println!("{state:#?}");
+fn info_version(
+    _: &MicrovmState,
+    version: Version,
+    format: OutputFormat,
+) -> Result<(), InfoVmStateError> {
+    match format {
+        OutputFormat::Text => println!("v{version}"),
+        OutputFormat::Json => print_json(&serde_json::json!({ "version": version }))?,
     }
     Ok(())
 }
 
-fn info_vmstate(vmstate: &MicrovmState, _version: Version) -> Result<(), InfoVmStateError> {
-    println!("{vmstate:#?}");
+fn info_vcpu_states(
+    state: &MicrovmState,
+    _: Version,
+    format: OutputFormat,
+) -> Result<(), InfoVmStateError> {
+    match format {
+        OutputFormat::Text => {
+            for (i, state) in state.vcpu_states.iter().enumerate() {
+                println!("vcpu {i}:");
+                println!("{state:#?}");
+            }
+        }
+        OutputFormat::Json => print_json(&state.vcpu_states)?,
+    }
+    Ok(())
+}
+
+fn info_vmstate(
+    vmstate: &MicrovmState,
+    _version: Version,
+    format: OutputFormat,
+) -> Result<(), InfoVmStateError> {
+    match format {
+        OutputFormat::Text => println!("{vmstate:#?}"),
+        OutputFormat::Json => print_json(vmstate)?,
+    }
+    Ok(())
+}
+
+/// Value of a field that differs between two vmstate files.
+#[derive(Debug, PartialEq, Serialize)]
+struct StateDifference {
+    /// Path of the field, such as `vcpu_states[0].regs`.
+    path: String,
+    /// Value of the field in the first vmstate file, if present.
+    left: Option<Value>,
+    /// Value of the field in the second vmstate file, if present.
+    right: Option<Value>,
+}
+
+fn diff(
+    vmstate_path: &PathBuf,
+    other_vmstate_path: &PathBuf,
+    format: OutputFormat,
+) -> Result<(), InfoVmStateError> {
+    let (vmstate, version) = open_vmstate(vmstate_path)?;
+    let (other_vmstate, other_version) = open_vmstate(other_vmstate_path)?;
+
+    let mut differences = Vec::new();
+    diff_values(
+        "version",
+        &serde_json::to_value(version)?,
+        &serde_json::to_value(other_version)?,
+        &mut differences,
+    );
+    diff_values(
+        "",
+        &serde_json::to_value(vmstate)?,
+        &serde_json::to_value(other_vmstate)?,
+        &mut differences,
+    );
+
+    match format {
+        OutputFormat::Text if differences.is_empty() => println!("No differences."),
+        OutputFormat::Text => {
+            for difference in differences {
+                let describe = |value: Option<Value>| {
+                    value.map_or_else(|| "<missing>".to_string(), |value| value.to_string())
+                };
+                println!(
+                    "{}: {} -> {}",
+                    difference.path,
+                    describe(difference.left),
+                    describe(difference.right)
+                );
+            }
+        }
+        OutputFormat::Json => print_json(&differences)?,
+    }
     Ok(())
 }
+
+/// Recursively compares the fields of `left` and `right`, found at `path`, and records the
+/// differing ones in `differences`.
+fn diff_values(path: &str, left: &Value, right: &Value, differences: &mut Vec<StateDifference>) {
+    fn push(
+        differences: &mut Vec<StateDifference>,
+        path: String,
+        left: Option<&Value>,
+        right: Option<&Value>,
+    ) {
+        differences.push(StateDifference {
+            path,
+            left: left.cloned(),
+            right: right.cloned(),
+        })
+    }
+
+    match (left, right) {
+        (Value::Object(left_fields), Value::Object(right_fields)) => {
+            for (name, left_value) in left_fields {
+                let field_path = match path {
+                    "" => name.clone(),
+                    _ => format!("{path}.{name}"),
+                };
+                match right_fields.get(name) {
+                    Some(right_value) => {
+                        diff_values(&field_path, left_value, right_value, differences)
+                    }
+                    None => push(differences, field_path, Some(left_value), None),
+                }
+            }
+            for (name, right_value) in right_fields {
+                if !left_fields.contains_key(name) {
+                    let field_path = match path {
+                        "" => name.clone(),
+                        _ => format!("{path}.{name}"),
+                    };
+                    push(differences, field_path, None, Some(right_value));
+                }
+            }
+        }
+        // KVM structures are serialized as byte arrays, in which consecutive differing bytes
+        // are reported together.
+        (Value::Array(left_items), Value::Array(right_items))
+            if left_items.len() == right_items.len()
+                && left_items.iter().chain(right_items).all(Value::is_number) =>
+        {
+            let mut start = None;
+            for i in 0..=left_items.len() {
+                let differs = i < left_items.len() && left_items[i] != right_items[i];
+                match (start, differs) {
+                    (None, true) => start = Some(i),
+                    (Some(first), false) => {
+                        push(
+                            differences,
+                            format!("{path}[{first}..{i}]"),
+                            Some(&Value::from(&left_items[first..i])),
+                            Some(&Value::from(&right_items[first..i])),
+                        );
+                        start = None;
+                    }
+                    _ => (),
+                }
+            }
+        }
+        (Value::Array(left_items), Value::Array(right_items)) => {
+            for i in 0..left_items.len().max(right_items.len()) {
+                let item_path = format!("{path}[{i}]");
+                match (left_items.get(i), right_items.get(i)) {
+                    (Some(left_item), Some(right_item)) => {
+                        diff_values(&item_path, left_item, right_item, differences)
+                    }
+                    (left_item, right_item) => push(differences, item_path, left_item, right_item),
+                }
+            }
+        }
+        _ if left != right => push(differences, path.to_string(), Some(left), Some(right)),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn difference(path: &str, left: Option<Value>, right: Option<Value>) -> StateDifference {
+        StateDifference {
+            path: path.to_string(),
+            left,
+            right,
+        }
+    }
+
+    #[test]
+    fn test_diff_values() {
+        let left = json!({
+            "vcpu_states": [{ "regs": [1, 2, 3, 4, 5], "mp_state": 0 }],
+            "memory_state": { "regions": [{ "base_address": 0, "size": 4096 }] },
+            "device_states": { "Virtio": { "id": "rootfs" } },
+        });
+
+        let mut differences = Vec::new();
+        diff_values("", &left, &left, &mut differences);
+        assert!(differences.is_empty());
+
+        let right = json!({
+            "vcpu_states": [
+                { "regs": [1, 0, 0, 4, 6], "mp_state": 0 },
+                { "regs": [1, 2, 3, 4, 5], "mp_state": 1 },
+            ],
+            "memory_state": { "regions": [{ "base_address": 0, "size": 8192 }] },
+            "device_states": { "VhostUser": { "id": "rootfs" } },
+        });
+        diff_values("", &left, &right, &mut differences);
+        assert_eq!(
+            differences,
+            vec![
+                difference(
+                    "device_states.Virtio",
+                    Some(json!({ "id": "rootfs" })),
+                    None
+                ),
+                difference(
+                    "device_states.VhostUser",
+                    None,
+                    Some(json!({ "id": "rootfs" }))
+                ),
+                difference(
+                    "memory_state.regions[0].size",
+                    Some(json!(4096)),
+                    Some(json!(8192))
+                ),
+                difference(
+                    "vcpu_states[0].regs[1..3]",
+                    Some(json!([2, 3])),
+                    Some(json!([0, 0]))
+                ),
+                difference(
+                    "vcpu_states[0].regs[4..5]",
+                    Some(json!([5])),
+                    Some(json!([6]))
+                ),
+                difference(
+                    "vcpu_states[1]",
+                    None,
+                    Some(json!({ "regs": [1, 2, 3, 4, 5], "mp_state": 1 }))
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,451 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+
+use clap::Args;
+use fc_utils::seek_hole::SeekHole;
+use fc_utils::u64_to_usize;
+use serde::Serialize;
+use vmm::vstate::memory::{GuestMemoryRegionState, GuestMemoryState};
+
+use crate::info::OutputFormat;
+use crate::utils::*;
+
+/// Size of the huge pages guest memory regions are checked to be aligned to.
+const HUGEPAGE_SIZE: u64 = 2 << 20;
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum InfoMemoryError {
+    /// {0}
+    Utils(#[from] UtilsError),
+    /// Can not open memory file: {0}
+    OpenMemoryFile(io::Error),
+    /// Can not read memory file: {0}
+    ReadMemoryFile(io::Error),
+    /// Can not look for holes in memory file: {0}
+    SeekHole(io::Error),
+    /// Invalid page size: {0}
+    InvalidPageSize(usize),
+    /// Range {0} is not backed by guest memory.
+    RangeOutsideMemory(GuestRange),
+    /// Can not open output file: {0}
+    OpenOutputFile(io::Error),
+    /// Can not dump range to output file: {0}
+    DumpRange(io::Error),
+    /// Can not serialize memory info to JSON: {0}
+    Json(#[from] serde_json::Error),
+}
+
+/// Range of guest physical addresses, end excluded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct GuestRange {
+    start: u64,
+    end: u64,
+}
+
+impl std::fmt::Display for GuestRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#x}-{:#x}", self.start, self.end)
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct InfoMemoryArgs {
+    /// Path to the vmstate file.
+    #[arg(short, long)]
+    vmstate_path: PathBuf,
+    /// Path to the memory file.
+    #[arg(short, long)]
+    memory_path: PathBuf,
+    /// Size of the pages guest memory is inspected in, in bytes.
+    #[arg(short, long, default_value_t = 4096)]
+    page_size: usize,
+    /// Guest physical range to write to the output file instead of printing statistics, as
+    /// `START-END` with END excluded. Addresses are decimal, or hexadecimal with a `0x` prefix.
+    #[arg(long, value_parser = parse_range, requires = "output_path")]
+    dump_range: Option<GuestRange>,
+    /// Path to the file the dumped range is written to.
+    #[arg(short, long)]
+    output_path: Option<PathBuf>,
+    /// Output format.
+    #[arg(short, long, value_enum, default_value_t)]
+    format: OutputFormat,
+}
+
+/// Statistics of a guest memory region.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct RegionInfo {
+    /// Guest physical address of the region.
+    base_address: u64,
+    /// Size of the region, in bytes.
+    size: u64,
+    /// Offset of the region in the memory file.
+    offset: u64,
+    /// Whether the address, size and file offset of the region are aligned to 2 MiB huge pages.
+    hugepage_aligned: bool,
+    /// Number of pages of the region.
+    pages: u64,
+    /// Number of pages only holding zeros.
+    zero_pages: u64,
+    /// Number of pages with different contents.
+    distinct_pages: u64,
+    /// Guest physical ranges of the region that are holes in the memory file.
+    holes: Vec<GuestRange>,
+}
+
+/// Statistics of a memory file.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct MemoryInfo {
+    /// Size of the pages memory was inspected in, in bytes.
+    page_size: usize,
+    /// Statistics of the guest memory regions.
+    regions: Vec<RegionInfo>,
+    /// Number of pages with different contents in all regions.
+    distinct_pages: u64,
+}
+
+pub fn info_memory_command(args: InfoMemoryArgs) -> Result<(), InfoMemoryError> {
+    let (vmstate, _) = open_vmstate_upgraded(&args.vmstate_path)?;
+    let mut memory_file = File::open(&args.memory_path).map_err(InfoMemoryError::OpenMemoryFile)?;
+
+    if let (Some(range), Some(output_path)) = (args.dump_range, &args.output_path) {
+        let mut output_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(output_path)
+            .map_err(InfoMemoryError::OpenOutputFile)?;
+        return dump_range(&vmstate.memory_state, &memory_file, range, &mut output_file);
+    }
+
+    let info = memory_info(&vmstate.memory_state, &mut memory_file, args.page_size)?;
+    match args.format {
+        OutputFormat::Text => print_memory_info(&info),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&info)?),
+    }
+    Ok(())
+}
+
+/// Parses a `START-END` range of guest physical addresses.
+fn parse_range(range: &str) -> Result<GuestRange, String> {
+    fn parse_address(address: &str) -> Result<u64, String> {
+        let address = address.trim();
+        match address.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => address.parse(),
+        }
+        .map_err(|err| format!("invalid address {address}: {err}"))
+    }
+
+    let (start, end) = range
+        .split_once('-')
+        .ok_or_else(|| "expected a range formatted as START-END".to_string())?;
+    let range = GuestRange {
+        start: parse_address(start)?,
+        end: parse_address(end)?,
+    };
+    if range.start >= range.end {
+        return Err(format!("range {range} is empty"));
+    }
+    Ok(range)
+}
+
+fn print_memory_info(info: &MemoryInfo) {
+    for (i, region) in info.regions.iter().enumerate() {
+        println!(
+            "region {i}: {}, {} bytes at file offset {:#x}",
+            GuestRange {
+                start: region.base_address,
+                end: region.base_address + region.size,
+            },
+            region.size,
+            region.offset
+        );
+        println!("  hugepage aligned: {}", region.hugepage_aligned);
+        println!(
+            "  pages: {}, zero pages: {}, distinct pages: {}",
+            region.pages, region.zero_pages, region.distinct_pages
+        );
+        if region.holes.is_empty() {
+            println!("  holes: none");
+        } else {
+            let holes: Vec<String> = region.holes.iter().map(ToString::to_string).collect();
+            println!("  holes: {}", holes.join(", "));
+        }
+    }
+    println!(
+        "distinct pages of {} bytes: {}",
+        info.page_size, info.distinct_pages
+    );
+}
+
+/// Hashes the contents of a page. Pages are compared by a 128-bit hash of their contents, so
+/// the number of distinct pages is an estimate.
+fn page_hash(page: &[u8]) -> u128 {
+    let mut low = DefaultHasher::new();
+    page.hash(&mut low);
+    let mut high = DefaultHasher::new();
+    1u8.hash(&mut high);
+    page.hash(&mut high);
+    u128::from(high.finish()) << 64 | u128::from(low.finish())
+}
+
+fn memory_info(
+    memory_state: &GuestMemoryState,
+    memory_file: &mut File,
+    page_size: usize,
+) -> Result<MemoryInfo, InfoMemoryError> {
+    if page_size == 0 {
+        return Err(InfoMemoryError::InvalidPageSize(page_size));
+    }
+
+    let mut page = vec![0u8; page_size];
+    let mut all_hashes = HashSet::new();
+    let mut regions = Vec::with_capacity(memory_state.regions.len());
+    for region in &memory_state.regions {
+        let size = region.size as u64;
+        let mut hashes = HashSet::new();
+        let mut zero_pages = 0;
+        for page_offset in (0..size).step_by(page_size) {
+            let page = &mut page[..u64_to_usize(size - page_offset).min(page_size)];
+            memory_file
+                .read_exact_at(page, region.offset + page_offset)
+                .map_err(InfoMemoryError::ReadMemoryFile)?;
+            if page.iter().all(|&byte| byte == 0) {
+                zero_pages += 1;
+            }
+            let hash = page_hash(page);
+            hashes.insert(hash);
+            all_hashes.insert(hash);
+        }
+
+        regions.push(RegionInfo {
+            base_address: region.base_address,
+            size,
+            offset: region.offset,
+            hugepage_aligned: region.base_address % HUGEPAGE_SIZE == 0
+                && size % HUGEPAGE_SIZE == 0
+                && region.offset % HUGEPAGE_SIZE == 0,
+            pages: size.div_ceil(page_size as u64),
+            zero_pages,
+            distinct_pages: hashes.len() as u64,
+            holes: region_holes(region, memory_file)?,
+        });
+    }
+
+    Ok(MemoryInfo {
+        page_size,
+        regions,
+        distinct_pages: all_hashes.len() as u64,
+    })
+}
+
+/// Returns the guest physical ranges of `region` that are holes in the memory file.
+fn region_holes(
+    region: &GuestMemoryRegionState,
+    memory_file: &mut File,
+) -> Result<Vec<GuestRange>, InfoMemoryError> {
+    let region_end = region.offset + region.size as u64;
+    let to_guest_range = |start: u64, end: u64| GuestRange {
+        start: region.base_address + (start - region.offset),
+        end: region.base_address + (end - region.offset),
+    };
+
+    let mut holes = Vec::new();
+    let mut cursor = region.offset;
+    while cursor < region_end {
+        match memory_file
+            .seek_data(cursor)
+            .map_err(InfoMemoryError::SeekHole)?
+        {
+            Some(data_start) if data_start < region_end => {
+                if cursor < data_start {
+                    holes.push(to_guest_range(cursor, data_start));
+                }
+                cursor = memory_file
+                    .seek_hole(data_start)
+                    .map_err(InfoMemoryError::SeekHole)?
+                    .unwrap_or(region_end);
+            }
+            _ => {
+                holes.push(to_guest_range(cursor, region_end));
+                break;
+            }
+        }
+    }
+    Ok(holes)
+}
+
+/// Writes the contents of the guest physical `range` to `output`.
+fn dump_range(
+    memory_state: &GuestMemoryState,
+    memory_file: &File,
+    range: GuestRange,
+    output: &mut impl io::Write,
+) -> Result<(), InfoMemoryError> {
+    let mut reader = memory_file;
+    let mut cursor = range.start;
+    while cursor < range.end {
+        let region = memory_state
+            .regions
+            .iter()
+            .find(|region| {
+                region.base_address <= cursor && cursor < region.base_address + region.size as u64
+            })
+            .ok_or(InfoMemoryError::RangeOutsideMemory(range))?;
+        let chunk_end = range.end.min(region.base_address + region.size as u64);
+        reader
+            .seek(SeekFrom::Start(
+                region.offset + (cursor - region.base_address),
+            ))
+            .map_err(InfoMemoryError::DumpRange)?;
+        let len = chunk_end - cursor;
+        let copied = io::copy(&mut reader.take(len), output).map_err(InfoMemoryError::DumpRange)?;
+        if copied != len {
+            return Err(InfoMemoryError::DumpRange(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+        cursor = chunk_end;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use fc_utils::tempfile::TempFile;
+
+    use super::*;
+
+    const PAGE_SIZE: usize = 4096;
+
+    fn region(base_address: u64, size: usize, offset: u64) -> GuestMemoryRegionState {
+        GuestMemoryRegionState {
+            base_address,
+            size,
+            offset,
+        }
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(
+            parse_range("0x1000-8192").unwrap(),
+            GuestRange {
+                start: 0x1000,
+                end: 0x2000
+            }
+        );
+        parse_range("0x1000").unwrap_err();
+        parse_range("0x2000-0x1000").unwrap_err();
+        parse_range("foo-0x1000").unwrap_err();
+    }
+
+    #[test]
+    fn test_memory_info() {
+        let memory_state = GuestMemoryState {
+            regions: vec![
+                region(0, 4 * PAGE_SIZE, 0),
+                region(0x10_0000, 2 * PAGE_SIZE, 4 * PAGE_SIZE as u64),
+            ],
+        };
+        // The first region holds two identical pages, a zero page and a hole. The second one
+        // holds a hole and a page identical to the ones of the first region.
+        let mut memory_file = TempFile::new().unwrap().into_file();
+        memory_file.set_len(6 * PAGE_SIZE as u64).unwrap();
+        let data = vec![0xaa; PAGE_SIZE];
+        memory_file.write_all_at(&data, 0).unwrap();
+        memory_file.write_all_at(&data, PAGE_SIZE as u64).unwrap();
+        memory_file
+            .write_all_at(&[0; PAGE_SIZE], 2 * PAGE_SIZE as u64)
+            .unwrap();
+        memory_file
+            .write_all_at(&data, 5 * PAGE_SIZE as u64)
+            .unwrap();
+
+        let info = memory_info(&memory_state, &mut memory_file, PAGE_SIZE).unwrap();
+        assert_eq!(
+            info,
+            MemoryInfo {
+                page_size: PAGE_SIZE,
+                regions: vec![
+                    RegionInfo {
+                        base_address: 0,
+                        size: 4 * PAGE_SIZE as u64,
+                        offset: 0,
+                        hugepage_aligned: false,
+                        pages: 4,
+                        zero_pages: 2,
+                        distinct_pages: 2,
+                        holes: vec![GuestRange {
+                            start: 3 * PAGE_SIZE as u64,
+                            end: 4 * PAGE_SIZE as u64,
+                        }],
+                    },
+                    RegionInfo {
+                        base_address: 0x10_0000,
+                        size: 2 * PAGE_SIZE as u64,
+                        offset: 4 * PAGE_SIZE as u64,
+                        hugepage_aligned: false,
+                        pages: 2,
+                        zero_pages: 1,
+                        distinct_pages: 2,
+                        holes: vec![GuestRange {
+                            start: 0x10_0000,
+                            end: 0x10_0000 + PAGE_SIZE as u64,
+                        }],
+                    },
+                ],
+                distinct_pages: 2,
+            }
+        );
+
+        memory_info(&memory_state, &mut memory_file, 0).unwrap_err();
+        // The memory file is too short for the regions.
+        memory_file.set_len(5 * PAGE_SIZE as u64).unwrap();
+        memory_info(&memory_state, &mut memory_file, PAGE_SIZE).unwrap_err();
+    }
+
+    #[test]
+    fn test_dump_range() {
+        let memory_state = GuestMemoryState {
+            regions: vec![
+                region(0, PAGE_SIZE, 0),
+                region(PAGE_SIZE as u64, PAGE_SIZE, PAGE_SIZE as u64),
+                region(0x10_0000, PAGE_SIZE, 2 * PAGE_SIZE as u64),
+            ],
+        };
+        let contents: Vec<u8> = (0..3 * PAGE_SIZE)
+            .map(|i| u8::try_from(i % 251).unwrap())
+            .collect();
+        let mut memory_file = TempFile::new().unwrap().into_file();
+        memory_file.write_all(&contents).unwrap();
+
+        // Ranges can span adjacent regions.
+        let mut output = Vec::new();
+        let range = parse_range("0x800-0x1800").unwrap();
+        dump_range(&memory_state, &memory_file, range, &mut output).unwrap();
+        assert_eq!(output, contents[0x800..0x1800]);
+
+        let mut output = Vec::new();
+        let range = parse_range("0x100010-0x100020").unwrap();
+        dump_range(&memory_state, &memory_file, range, &mut output).unwrap();
+        assert_eq!(output, contents[2 * PAGE_SIZE + 0x10..2 * PAGE_SIZE + 0x20]);
+
+        // Ranges can not span unbacked guest addresses.
+        let range = parse_range("0x1800-0x100010").unwrap();
+        assert!(matches!(
+            dump_range(&memory_state, &memory_file, range, &mut Vec::new()),
+            Err(InfoMemoryError::RangeOutsideMemory(r)) if r == range
+        ));
+    }
+}
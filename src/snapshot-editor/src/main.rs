@@ -4,25 +4,26 @@
 use clap::{Parser, Subcommand};
 
 mod edit_memory;
-#[cfg(target_arch = "aarch64")]
 mod edit_vmstate;
 mod info;
+mod info_memory;
 mod utils;
 
 use edit_memory::{edit_memory_command, EditMemoryError, EditMemorySubCommand};
-#[cfg(target_arch = "aarch64")]
 use edit_vmstate::{edit_vmstate_command, EditVmStateError, EditVmStateSubCommand};
 use info::{info_vmstate_command, InfoVmStateError, InfoVmStateSubCommand};
+use info_memory::{info_memory_command, InfoMemoryArgs, InfoMemoryError};
 
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
 enum SnapEditorError {
     /// Error during editing memory file: {0}
     EditMemory(#[from] EditMemoryError),
-    #[cfg(target_arch = "aarch64")]
     /// Error during editing vmstate file: {0}
     EditVmState(#[from] EditVmStateError),
     /// Error during getting info from a vmstate file: {0}
     InfoVmState(#[from] InfoVmStateError),
+    /// Error during getting info from a memory file: {0}
+    InfoMemory(#[from] InfoMemoryError),
 }
 
 #[derive(Debug, Parser)]
@@ -36,11 +37,12 @@ struct Cli {
 enum Command {
     #[command(subcommand)]
     EditMemory(EditMemorySubCommand),
-    #[cfg(target_arch = "aarch64")]
     #[command(subcommand)]
     EditVmstate(EditVmStateSubCommand),
     #[command(subcommand)]
     InfoVmstate(InfoVmStateSubCommand),
+    /// Print statistics of a memory file, or extract a guest physical range from it.
+    InfoMemory(InfoMemoryArgs),
 }
 
 fn main_exec() -> Result<(), SnapEditorError> {
@@ -48,9 +50,9 @@ fn main_exec() -> Result<(), SnapEditorError> {
 
     match cli.command {
         Command::EditMemory(command) => edit_memory_command(command)?,
-        #[cfg(target_arch = "aarch64")]
         Command::EditVmstate(command) => edit_vmstate_command(command)?,
         Command::InfoVmstate(command) => info_vmstate_command(command)?,
+        Command::InfoMemory(args) => info_memory_command(args)?,
     }
 
     Ok(())
@@ -6,11 +6,9 @@ use std::path::PathBuf;
 
 use fc_utils::u64_to_usize;
 use semver::Version;
-use vmm::persist::MicrovmState;
+use vmm::persist::{microvm_state_translators, MicrovmState, SNAPSHOT_VERSION};
 use vmm::snapshot::Snapshot;
 
-// Some errors are only used in aarch64 code
-#[allow(unused)]
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
 pub enum UtilsError {
     /// Can not open snapshot file: {0}
@@ -19,13 +17,14 @@ pub enum UtilsError {
     VmStateFileMeta(std::io::Error),
     /// Can not load snapshot: {0}
     VmStateLoad(vmm::snapshot::SnapshotError),
+    /// Can not upgrade snapshot: {0}
+    VmStateUpgrade(vmm::snapshot::SnapshotError),
     /// Can not open output file: {0}
     OutputFileOpen(std::io::Error),
     /// Can not save snapshot: {0}
     VmStateSave(vmm::snapshot::SnapshotError),
 }
 
-#[allow(unused)]
 pub fn open_vmstate(snapshot_path: &PathBuf) -> Result<(MicrovmState, Version), UtilsError> {
     let mut snapshot_reader = File::open(snapshot_path).map_err(UtilsError::VmStateFileOpen)?;
     let metadata = std::fs::metadata(snapshot_path).map_err(UtilsError::VmStateFileMeta)?;
@@ -33,8 +32,23 @@ pub fn open_vmstate(snapshot_path: &PathBuf) -> Result<(MicrovmState, Version),
     Snapshot::load(&mut snapshot_reader, snapshot_len).map_err(UtilsError::VmStateLoad)
 }
 
-// This method is used only in aarch64 code so far
-#[allow(unused)]
+/// Opens a vmstate file written with any supported snapshot format version, translating the
+/// state to the current format version. Also returns the format version of the file.
+pub fn open_vmstate_upgraded(
+    snapshot_path: &PathBuf,
+) -> Result<(MicrovmState, Version), UtilsError> {
+    let mut snapshot_reader = File::open(snapshot_path).map_err(UtilsError::VmStateFileOpen)?;
+    let metadata = std::fs::metadata(snapshot_path).map_err(UtilsError::VmStateFileMeta)?;
+    let snapshot_len = u64_to_usize(metadata.len());
+    Snapshot::new(SNAPSHOT_VERSION)
+        .load_with_translation(
+            &mut snapshot_reader,
+            snapshot_len,
+            &microvm_state_translators(),
+        )
+        .map_err(UtilsError::VmStateUpgrade)
+}
+
 pub fn save_vmstate(
     microvm_state: MicrovmState,
     output_path: &PathBuf,
@@ -7,8 +7,8 @@
 // of the `utils` crate.
 pub use vmm_sys_util::ioctl::ioctl_expr;
 pub use vmm_sys_util::{
-    epoll, errno, eventfd, fam, generate_fam_struct_impl, ioctl, ioctl_ioc_nr, ioctl_iow_nr, rand,
-    seek_hole, sock_ctrl_msg, syscall, tempdir, tempfile, terminal,
+    epoll, errno, eventfd, fam, generate_fam_struct_impl, ioctl, ioctl_io_nr, ioctl_ioc_nr,
+    ioctl_iow_nr, rand, seek_hole, sock_ctrl_msg, syscall, tempdir, tempfile, terminal,
 };
 
 pub mod arg_parser;
@@ -20,7 +20,11 @@ pub mod sm;
 pub mod time;
 pub mod validators;
 
+use std::fs::File;
+use std::io::Read;
+use std::mem::ManuallyDrop;
 use std::num::Wrapping;
+use std::os::unix::io::{FromRawFd, RawFd};
 use std::result::Result;
 
 /// Return the default page size of the platform, in bytes.
@@ -46,3 +50,16 @@ pub const fn u64_to_usize(num: u64) -> usize {
 pub const fn wrap_usize_to_u32(num: usize) -> Wrapping<u32> {
     Wrapping(((num as u64) & 0xFFFFFFFF) as u32)
 }
+
+/// Fills `buf` with bytes read from `fd`, which is borrowed and left open, such as a file
+/// descriptor inherited by the process to pass it a secret.
+pub fn read_exact_from_fd(fd: RawFd, buf: &mut [u8]) -> std::io::Result<()> {
+    if fd < 0 {
+        return Err(std::io::Error::from_raw_os_error(libc::EBADF));
+    }
+    // SAFETY: The file descriptor is not negative, and wrapping the `File` in `ManuallyDrop`
+    // ensures that it is never closed, so it stays owned by whoever passed it to the process.
+    // Reading from an invalid file descriptor is reported as an error.
+    let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    file.read_exact(buf)
+}
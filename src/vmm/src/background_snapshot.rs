@@ -0,0 +1,415 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Writes the guest memory of a running microVM to a snapshot memory file.
+//!
+//! Before the microVM is resumed, its guest memory is write protected using userfaultfd. A
+//! background thread then copies guest memory to the memory file, removing the write protection
+//! of the pages it copied. When the guest writes to a page that was not copied yet, the write
+//! faults and waits until the thread copied the page, so that the memory file holds the contents
+//! guest memory had when the microVM state was saved.
+//!
+//! The seccomp filters of the VMM thread don't allow creating threads, so the background thread
+//! is spawned when the microVM is built, and waits for the snapshots it has to write.
+
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::raw::c_void;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use seccompiler::BpfProgram;
+use userfaultfd::{Event, FeatureFlags, RegisterMode, Uffd, UffdBuilder};
+use utils::{get_page_size, u64_to_usize};
+
+use crate::logger::{error, info};
+use crate::vmm_config::snapshot::SnapshotStatus;
+use crate::vstate::memory::{
+    Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap,
+    MemoryError,
+};
+
+/// Number of pages copied between two checks for write faults.
+const PAGES_PER_CHUNK: usize = 256;
+/// Populates page tables as if every page of a range was read, without writing to it. Available
+/// since Linux 5.14, and not exposed by the libc crate on all targets.
+const MADV_POPULATE_READ: libc::c_int = 22;
+
+/// Errors associated with writing guest memory in the background.
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum BackgroundSnapshotError {
+    /// Cannot create a userfaultfd supporting write protection: {0}
+    CreateUffd(userfaultfd::Error),
+    /// Cannot map guest memory: {0}
+    Populate(io::Error),
+    /// Cannot register guest memory with userfaultfd: {0}
+    RegisterUffd(userfaultfd::Error),
+    /// Cannot change the write protection of guest memory: {0}
+    WriteProtect(userfaultfd::Error),
+    /// Cannot read userfaultfd event: {0}
+    ReadUffd(userfaultfd::Error),
+    /// Write fault at host address {0:#x}, outside of guest memory.
+    UnknownFaultAddress(usize),
+    /// Cannot read guest memory: {0}
+    Memory(MemoryError),
+    /// Cannot write memory file: {0}
+    WriteMemoryFile(io::Error),
+    /// Cannot spawn the snapshot thread: {0}
+    SpawnThread(io::Error),
+    /// The snapshot thread is not running.
+    ThreadExited,
+}
+
+/// Guest memory region being written to the memory file.
+#[derive(Debug)]
+struct ProtectedRegion {
+    /// Guest physical address of the region.
+    guest_addr: GuestAddress,
+    /// Host virtual address the region is mapped at.
+    host_addr: usize,
+    /// Length of the region, in bytes.
+    len: usize,
+    /// Offset of the region in the memory file.
+    file_offset: u64,
+    /// Pages of the region already written to the memory file.
+    written: Vec<bool>,
+}
+
+/// Copies write protected guest memory to the memory file.
+#[derive(Debug)]
+struct MemoryWriter {
+    guest_memory: GuestMemoryMmap,
+    uffd: Uffd,
+    file: File,
+    page_size: usize,
+    regions: Vec<ProtectedRegion>,
+    page: Vec<u8>,
+}
+
+/// Guest memory to write to a memory file, and the status to update once it is written.
+type WriterJob = (MemoryWriter, Arc<Mutex<SnapshotStatus>>);
+
+/// Thread writing guest memory to memory files in the background, one snapshot at a time.
+#[derive(Debug)]
+pub struct SnapshotWriter {
+    sender: Sender<WriterJob>,
+}
+
+impl SnapshotWriter {
+    /// Spawns the snapshot thread, which installs `seccomp_filter` before waiting for snapshots
+    /// to write. The thread exits once the `SnapshotWriter` is dropped.
+    pub fn spawn(seccomp_filter: Arc<BpfProgram>) -> Result<Self, BackgroundSnapshotError> {
+        let (sender, receiver) = mpsc::channel::<WriterJob>();
+        thread::Builder::new()
+            .name("fc_snapshot".to_string())
+            .spawn(move || {
+                // Execution panics if filters cannot be loaded, use --no-seccomp if skipping
+                // filters altogether is the desired behaviour.
+                if let Err(err) = seccompiler::apply_filter(&seccomp_filter) {
+                    panic!(
+                        "Failed to set the requested seccomp filters on the snapshot thread: \
+                         Error: {}",
+                        err
+                    );
+                }
+                for (mut writer, status) in receiver {
+                    let result = writer.run();
+                    writer.release();
+                    let new_status = match result {
+                        Ok(()) => {
+                            info!("Background snapshot completed.");
+                            SnapshotStatus::Completed
+                        }
+                        Err(err) => {
+                            error!("Background snapshot failed: {}", err);
+                            SnapshotStatus::Failed {
+                                error: err.to_string(),
+                            }
+                        }
+                    };
+                    *status.lock().expect("Poisoned lock") = new_status;
+                }
+            })
+            .map_err(BackgroundSnapshotError::SpawnThread)?;
+        Ok(Self { sender })
+    }
+}
+
+/// Write protects `guest_memory` and has the thread of `snapshot_writer` write it to `file`, which
+/// must be an empty regular file. `status` is updated once the thread is done.
+///
+/// Guest memory must be anonymous memory backed by pages of the host page size. The microVM
+/// must be paused while this function runs; it can be resumed as soon as it returns.
+pub fn start_background_snapshot(
+    snapshot_writer: &SnapshotWriter,
+    guest_memory: &GuestMemoryMmap,
+    file: File,
+    status: Arc<Mutex<SnapshotStatus>>,
+) -> Result<(), BackgroundSnapshotError> {
+    let page_size = get_page_size()
+        .map_err(|err| BackgroundSnapshotError::Memory(MemoryError::PageSize(err)))?;
+    let uffd = UffdBuilder::new()
+        .close_on_exec(true)
+        .non_blocking(true)
+        .user_mode_only(false)
+        .require_features(FeatureFlags::PAGEFAULT_FLAG_WP)
+        .create()
+        .map_err(BackgroundSnapshotError::CreateUffd)?;
+
+    let mut regions = Vec::with_capacity(guest_memory.num_regions());
+    let mut file_offset = 0;
+    for region in guest_memory.iter() {
+        let len = u64_to_usize(region.len());
+        populate_region(guest_memory, region, page_size)?;
+        let host_addr = region.as_ptr() as usize;
+        uffd.register_with_mode(host_addr as *mut c_void, len, RegisterMode::WRITE_PROTECT)
+            .map_err(BackgroundSnapshotError::RegisterUffd)?;
+        regions.push(ProtectedRegion {
+            guest_addr: region.start_addr(),
+            host_addr,
+            len,
+            file_offset,
+            written: vec![false; len.div_ceil(page_size)],
+        });
+        file_offset += region.len();
+    }
+
+    let writer = MemoryWriter {
+        guest_memory: guest_memory.clone(),
+        uffd,
+        file,
+        page_size,
+        regions,
+        page: vec![0; page_size],
+    };
+    let protected = writer.regions.iter().try_for_each(|region| {
+        writer
+            .uffd
+            .write_protect(region.host_addr as *mut c_void, region.len)
+    });
+    if let Err(err) = protected {
+        writer.release();
+        return Err(BackgroundSnapshotError::WriteProtect(err));
+    }
+
+    snapshot_writer
+        .sender
+        .send((writer, status))
+        .map_err(|mpsc::SendError((writer, _))| {
+            writer.release();
+            BackgroundSnapshotError::ThreadExited
+        })
+}
+
+/// Maps all the pages of `region`, as write protection only applies to pages that are mapped.
+/// Untouched anonymous pages are mapped to the zero page, which does not allocate memory.
+fn populate_region(
+    guest_memory: &GuestMemoryMmap,
+    region: &GuestRegionMmap,
+    page_size: usize,
+) -> Result<(), BackgroundSnapshotError> {
+    let len = u64_to_usize(region.len());
+    // SAFETY: The range is the mapping of the guest memory region, and populating page tables
+    // doesn't change its contents.
+    let ret = unsafe { libc::madvise(region.as_ptr().cast(), len, MADV_POPULATE_READ) };
+    if ret == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() != Some(libc::EINVAL) {
+        return Err(BackgroundSnapshotError::Populate(err));
+    }
+
+    // Older kernels don't support MADV_POPULATE_READ, so each page is read instead, which is
+    // several times slower.
+    for offset in (0..len).step_by(page_size) {
+        guest_memory
+            .read_obj::<u8>(region.start_addr().unchecked_add(offset as u64))
+            .map_err(|err| BackgroundSnapshotError::Memory(MemoryError::WriteMemory(err)))?;
+    }
+    Ok(())
+}
+
+impl MemoryWriter {
+    /// Writes all of guest memory to the memory file, copying the pages the guest writes to
+    /// first.
+    fn run(&mut self) -> Result<(), BackgroundSnapshotError> {
+        for region in 0..self.regions.len() {
+            let pages = self.regions[region].written.len();
+            for chunk_start in (0..pages).step_by(PAGES_PER_CHUNK) {
+                self.handle_write_faults()?;
+                let chunk_end = pages.min(chunk_start + PAGES_PER_CHUNK);
+                for page in chunk_start..chunk_end {
+                    if !self.regions[region].written[page] {
+                        self.write_page(region, page)?;
+                    }
+                }
+                let region = &self.regions[region];
+                let start = chunk_start * self.page_size;
+                let end = region.len.min(chunk_end * self.page_size);
+                self.uffd
+                    .remove_write_protection(
+                        (region.host_addr + start) as *mut c_void,
+                        end - start,
+                        true,
+                    )
+                    .map_err(BackgroundSnapshotError::WriteProtect)?;
+            }
+        }
+        self.file
+            .sync_all()
+            .map_err(BackgroundSnapshotError::WriteMemoryFile)
+    }
+
+    /// Copies the pages the guest is waiting to write to, then lets the guest write them.
+    fn handle_write_faults(&mut self) -> Result<(), BackgroundSnapshotError> {
+        while let Some(event) = self
+            .uffd
+            .read_event()
+            .map_err(BackgroundSnapshotError::ReadUffd)?
+        {
+            // Only write protection faults are reported for the registered memory.
+            let Event::Pagefault { addr, .. } = event else {
+                continue;
+            };
+            let addr = addr as usize;
+            let region = self
+                .regions
+                .iter()
+                .position(|region| region.host_addr <= addr && addr < region.host_addr + region.len)
+                .ok_or(BackgroundSnapshotError::UnknownFaultAddress(addr))?;
+            let page = (addr - self.regions[region].host_addr) / self.page_size;
+            if !self.regions[region].written[page] {
+                self.write_page(region, page)?;
+            }
+            let region = &self.regions[region];
+            let start = page * self.page_size;
+            self.uffd
+                .remove_write_protection(
+                    (region.host_addr + start) as *mut c_void,
+                    self.page_size.min(region.len - start),
+                    true,
+                )
+                .map_err(BackgroundSnapshotError::WriteProtect)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a page of guest memory to the memory file. Zero pages are left as holes.
+    fn write_page(&mut self, region: usize, page: usize) -> Result<(), BackgroundSnapshotError> {
+        let region = &mut self.regions[region];
+        let offset = page * self.page_size;
+        let buf = &mut self.page[..self.page_size.min(region.len - offset)];
+        self.guest_memory
+            .read_slice(buf, region.guest_addr.unchecked_add(offset as u64))
+            .map_err(|err| BackgroundSnapshotError::Memory(MemoryError::WriteMemory(err)))?;
+        if buf.iter().any(|&byte| byte != 0) {
+            self.file
+                .seek(SeekFrom::Start(region.file_offset + offset as u64))
+                .map_err(BackgroundSnapshotError::WriteMemoryFile)?;
+            self.file
+                .write_all(buf)
+                .map_err(BackgroundSnapshotError::WriteMemoryFile)?;
+        }
+        region.written[page] = true;
+        Ok(())
+    }
+
+    /// Removes the write protection of guest memory and unregisters it from the userfaultfd, so
+    /// that no guest write waits for this writer anymore.
+    fn release(&self) {
+        for region in &self.regions {
+            let addr = region.host_addr as *mut c_void;
+            if let Err(err) = self.uffd.remove_write_protection(addr, region.len, true) {
+                error!("Cannot remove guest memory write protection: {}", err);
+            }
+            if let Err(err) = self.uffd.unregister(addr, region.len) {
+                error!("Cannot unregister guest memory from userfaultfd: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::time::Duration;
+
+    use utils::tempfile::TempFile;
+
+    use super::*;
+    use crate::vmm_config::machine_config::HugePageConfig;
+    use crate::vstate::memory::GuestMemoryExtension;
+
+    #[test]
+    fn test_background_snapshot() {
+        let page_size = get_page_size().unwrap();
+        let region_size = page_size * 2 * PAGES_PER_CHUNK;
+        let guest_memory = GuestMemoryMmap::from_raw_regions(
+            &[
+                (GuestAddress(0), region_size),
+                (GuestAddress((region_size * 2) as u64), region_size),
+            ],
+            false,
+            HugePageConfig::None,
+        )
+        .unwrap();
+        // Every other page of the first region holds data, the second region is left untouched.
+        for page in (0..2 * PAGES_PER_CHUNK).step_by(2) {
+            guest_memory
+                .write(
+                    &vec![1u8; page_size],
+                    GuestAddress((page * page_size) as u64),
+                )
+                .unwrap();
+        }
+        let mut expected_contents = vec![0u8; region_size * 2];
+        for page in (0..2 * PAGES_PER_CHUNK).step_by(2) {
+            expected_contents[page * page_size..(page + 1) * page_size].fill(1);
+        }
+
+        let mut memory_file = TempFile::new().unwrap().into_file();
+        memory_file.set_len((region_size * 2) as u64).unwrap();
+        let status = Arc::new(Mutex::new(SnapshotStatus::InProgress));
+        let snapshot_writer = SnapshotWriter::spawn(Arc::new(BpfProgram::new())).unwrap();
+        start_background_snapshot(
+            &snapshot_writer,
+            &guest_memory,
+            memory_file.try_clone().unwrap(),
+            status.clone(),
+        )
+        .unwrap();
+
+        // Writes issued while the snapshot is written are not part of it.
+        for page in (0..2 * PAGES_PER_CHUNK).rev() {
+            guest_memory
+                .write(
+                    &vec![2u8; page_size],
+                    GuestAddress((page * page_size) as u64),
+                )
+                .unwrap();
+        }
+        guest_memory
+            .write(
+                &vec![3u8; page_size],
+                GuestAddress((region_size * 2) as u64),
+            )
+            .unwrap();
+
+        while *status.lock().unwrap() == SnapshotStatus::InProgress {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(*status.lock().unwrap(), SnapshotStatus::Completed);
+
+        let mut file_contents = vec![0u8; region_size * 2];
+        memory_file.rewind().unwrap();
+        memory_file.read_exact(&mut file_contents).unwrap();
+        assert_eq!(file_contents, expected_contents);
+
+        // Guest memory is writable again once the snapshot is written.
+        guest_memory
+            .write(&vec![4u8; page_size], GuestAddress(0))
+            .unwrap();
+    }
+}
@@ -10,6 +10,7 @@ use std::io::{self, Seek, SeekFrom};
 use std::sync::{Arc, Mutex};
 
 use event_manager::{MutEventSubscriber, SubscriberOps};
+use kvm_ioctls::Kvm;
 use libc::EFD_NONBLOCK;
 use linux_loader::cmdline::Cmdline as LoaderKernelCmdline;
 #[cfg(target_arch = "x86_64")]
@@ -30,6 +31,7 @@ use vm_superio::Serial;
 #[cfg(target_arch = "x86_64")]
 use crate::acpi;
 use crate::arch::InitrdConfig;
+use crate::background_snapshot::{BackgroundSnapshotError, SnapshotWriter};
 #[cfg(target_arch = "aarch64")]
 use crate::construct_kvm_mpidrs;
 use crate::cpu_config::templates::{
@@ -61,13 +63,14 @@ use crate::devices::virtio::net::Net;
 use crate::devices::virtio::rng::Entropy;
 use crate::devices::virtio::vsock::{Vsock, VsockUnixBackend};
 use crate::devices::BusDevice;
-use crate::logger::{debug, error};
+use crate::logger::{debug, error, warn};
 use crate::persist::{MicrovmState, MicrovmStateError};
 use crate::resources::VmResources;
 use crate::snapshot::Persist;
 use crate::vmm_config::boot_source::BootConfig;
 use crate::vmm_config::instance_info::InstanceInfo;
-use crate::vmm_config::machine_config::{VmConfig, VmConfigError};
+use crate::vmm_config::machine_config::{DirtyTrackingBackend, VmConfig, VmConfigError};
+use crate::vstate::dirty_ring::DirtyRingSupport;
 use crate::vstate::memory::{GuestAddress, GuestMemory, GuestMemoryExtension, GuestMemoryMmap};
 use crate::vstate::vcpu::{Vcpu, VcpuConfig, VcpuError};
 use crate::vstate::vm::Vm;
@@ -151,6 +154,7 @@ fn create_vmm_and_vcpus(
     guest_memory: GuestMemoryMmap,
     uffd: Option<Uffd>,
     track_dirty_pages: bool,
+    dirty_tracking_backend: DirtyTrackingBackend,
     vcpu_count: u8,
     kvm_capabilities: Vec<KvmCapability>,
 ) -> Result<(Vmm, Vec<Vcpu>), StartMicrovmError> {
@@ -164,6 +168,12 @@ fn create_vmm_and_vcpus(
     vm.memory_init(&guest_memory, track_dirty_pages)
         .map_err(VmmError::Vm)
         .map_err(StartMicrovmError::Internal)?;
+    // The dirty ring must be enabled before creating the vCPUs.
+    if track_dirty_pages && dirty_tracking_backend == DirtyTrackingBackend::Ring {
+        vm.enable_dirty_ring()
+            .map_err(VmmError::Vm)
+            .map_err(StartMicrovmError::Internal)?;
+    }
 
     let vcpus_exit_evt = EventFd::new(libc::EFD_NONBLOCK)
         .map_err(VmmError::EventFd)
@@ -235,6 +245,8 @@ fn create_vmm_and_vcpus(
         pio_device_manager,
         #[cfg(target_arch = "x86_64")]
         acpi_device_manager,
+        snapshot_status: Arc::default(),
+        snapshot_writer: None,
     };
 
     Ok((vmm, vcpus))
@@ -308,6 +320,7 @@ pub fn build_microvm_for_boot(
         guest_memory,
         None,
         track_dirty_pages,
+        vm_resources.vm_config.dirty_tracking_backend,
         vm_resources.vm_config.vcpu_count,
         cpu_template.kvm_capabilities.clone(),
     )?;
@@ -371,17 +384,23 @@ pub fn build_microvm_for_boot(
     .map_err(VmmError::VcpuStart)
     .map_err(Internal)?;
 
+    let vmm_seccomp_filter = seccomp_filters
+        .get("vmm")
+        .ok_or_else(|| MissingSeccompFilters("vmm".to_string()))?;
+    // The VMM thread cannot create threads once its seccomp filters are loaded.
+    vmm.snapshot_writer = Some(
+        SnapshotWriter::spawn(vmm_seccomp_filter.clone())
+            .map_err(VmmError::SnapshotWriter)
+            .map_err(Internal)?,
+    );
+
     // Load seccomp filters for the VMM thread.
     // Execution panics if filters cannot be loaded, use --no-seccomp if skipping filters
     // altogether is the desired behaviour.
     // Keep this as the last step before resuming vcpus.
-    seccompiler::apply_filter(
-        seccomp_filters
-            .get("vmm")
-            .ok_or_else(|| MissingSeccompFilters("vmm".to_string()))?,
-    )
-    .map_err(VmmError::SeccompFilters)
-    .map_err(Internal)?;
+    seccompiler::apply_filter(vmm_seccomp_filter)
+        .map_err(VmmError::SeccompFilters)
+        .map_err(Internal)?;
 
     let vmm = Arc::new(Mutex::new(vmm));
     event_manager.add_subscriber(vmm.clone());
@@ -453,6 +472,8 @@ pub enum BuildMicrovmFromSnapshotError {
     ACPIDeviManager(#[from] ACPIDeviceManagerRestoreError),
     /// VMGenID update failed: {0}
     VMGenIDUpdate(std::io::Error),
+    /// Failed to start the background snapshot thread: {0}
+    SnapshotWriter(#[from] BackgroundSnapshotError),
 }
 
 /// Builds and starts a microVM based on the provided MicrovmState.
@@ -469,6 +490,16 @@ pub fn build_microvm_from_snapshot(
     seccomp_filters: &BpfThreadMap,
     vm_resources: &mut VmResources,
 ) -> Result<Arc<Mutex<Vmm>>, BuildMicrovmFromSnapshotError> {
+    // Microvms tracking dirty pages with dirty rings can be restored on hosts without dirty ring
+    // support, which track them with bitmaps instead.
+    if vm_resources.vm_config.track_dirty_pages
+        && vm_resources.vm_config.dirty_tracking_backend == DirtyTrackingBackend::Ring
+        && DirtyRingSupport::check(&Kvm::new()?).is_none()
+    {
+        warn!("The host does not support KVM dirty rings, tracking dirty pages with bitmaps.");
+        vm_resources.vm_config.dirty_tracking_backend = DirtyTrackingBackend::Bitmap;
+    }
+
     // Build Vmm.
     debug!("event_start: build microvm from snapshot");
     let (mut vmm, mut vcpus) = create_vmm_and_vcpus(
@@ -477,6 +508,7 @@ pub fn build_microvm_from_snapshot(
         guest_memory.clone(),
         uffd,
         vm_resources.vm_config.track_dirty_pages,
+        vm_resources.vm_config.dirty_tracking_backend,
         vm_resources.vm_config.vcpu_count,
         microvm_state.vm_state.kvm_cap_modifiers.clone(),
     )?;
@@ -567,16 +599,18 @@ pub fn build_microvm_from_snapshot(
             .clone(),
     )?;
 
+    let vmm_seccomp_filter = seccomp_filters
+        .get("vmm")
+        .ok_or(BuildMicrovmFromSnapshotError::MissingVmmSeccompFilters)?;
+    // The VMM thread cannot create threads once its seccomp filters are loaded.
+    vmm.snapshot_writer = Some(SnapshotWriter::spawn(vmm_seccomp_filter.clone())?);
+
     let vmm = Arc::new(Mutex::new(vmm));
     event_manager.add_subscriber(vmm.clone());
 
     // Load seccomp filters for the VMM thread.
     // Keep this as the last step of the building process.
-    seccompiler::apply_filter(
-        seccomp_filters
-            .get("vmm")
-            .ok_or(BuildMicrovmFromSnapshotError::MissingVmmSeccompFilters)?,
-    )?;
+    seccompiler::apply_filter(vmm_seccomp_filter)?;
     debug!("event_end: build microvm from snapshot");
 
     Ok(vmm)
@@ -1168,6 +1202,8 @@ pub mod tests {
             pio_device_manager,
             #[cfg(target_arch = "x86_64")]
             acpi_device_manager,
+            snapshot_status: Arc::default(),
+            snapshot_writer: None,
         }
     }
 
@@ -1200,6 +1236,14 @@ pub mod tests {
                 ),
                 rate_limiter: None,
                 file_engine_type: None,
+                overlay: None,
+                num_queues: None,
+                async_io: None,
+                host_cache_mode: None,
+                rate_limiter_group: None,
+                verity: None,
+                nbd: None,
+                encryption: None,
 
                 socket: None,
             };
@@ -1398,6 +1442,8 @@ pub mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rate_limiter_group: None,
+            link_status: false,
         };
 
         let mut cmdline = default_kernel_cmdline();
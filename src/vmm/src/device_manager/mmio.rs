@@ -29,6 +29,7 @@ use crate::devices::legacy::RTCDevice;
 use crate::devices::pseudo::BootTimer;
 use crate::devices::virtio::balloon::Balloon;
 use crate::devices::virtio::block::device::Block;
+use crate::devices::virtio::block::BlockError;
 use crate::devices::virtio::device::VirtioDevice;
 use crate::devices::virtio::mmio::MmioTransport;
 use crate::devices::virtio::net::Net;
@@ -434,6 +435,39 @@ impl MMIODeviceManager {
         Ok(())
     }
 
+    /// Prepares the block devices for being snapshotted, so that their saved state accounts for
+    /// all the requests they took.
+    pub fn prepare_save(&self) -> Result<(), BlockError> {
+        self.for_each_virtio_device(|virtio_type, _id, _info, dev| {
+            if virtio_type == TYPE_BLOCK {
+                let mut virtio = dev.lock().expect("Poisoned lock");
+                virtio
+                    .as_mut_any()
+                    .downcast_mut::<Block>()
+                    .unwrap()
+                    .prepare_save()?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Returns whether any block device registered the guest memory as io_uring fixed buffers.
+    pub fn has_fixed_buffers_block_device(&self) -> bool {
+        let mut fixed_buffers = false;
+        let _: Result<(), ()> = self.for_each_virtio_device(|virtio_type, _id, _info, dev| {
+            if virtio_type == TYPE_BLOCK {
+                let virtio = dev.lock().expect("Poisoned lock");
+                fixed_buffers |= virtio
+                    .as_any()
+                    .downcast_ref::<Block>()
+                    .unwrap()
+                    .fixed_buffers();
+            }
+            Ok(())
+        });
+        fixed_buffers
+    }
+
     /// Artificially kick devices as if they had external events.
     pub fn kick_devices(&self) {
         info!("Artificially kick devices.");
@@ -8,7 +8,7 @@ use std::sync::{Arc, Mutex};
 
 use event_manager::{MutEventSubscriber, SubscriberOps};
 use kvm_ioctls::VmFd;
-use log::{error, warn};
+use log::error;
 use serde::{Deserialize, Serialize};
 use vm_allocator::AllocPolicy;
 
@@ -23,12 +23,12 @@ use crate::devices::acpi::vmgenid::{VMGenIDState, VMGenIdConstructorArgs, VmGenI
 use crate::devices::virtio::balloon::persist::{BalloonConstructorArgs, BalloonState};
 use crate::devices::virtio::balloon::{Balloon, BalloonError};
 use crate::devices::virtio::block::device::Block;
-use crate::devices::virtio::block::persist::{BlockConstructorArgs, BlockState};
+use crate::devices::virtio::block::persist::{BlockConstructorArgs, BlockState, BlockStateV2};
 use crate::devices::virtio::block::BlockError;
 use crate::devices::virtio::device::VirtioDevice;
 use crate::devices::virtio::mmio::MmioTransport;
 use crate::devices::virtio::net::persist::{
-    NetConstructorArgs, NetPersistError as NetError, NetState,
+    NetConstructorArgs, NetPersistError as NetError, NetState, NetStateV2,
 };
 use crate::devices::virtio::net::Net;
 use crate::devices::virtio::persist::{MmioTransportConstructorArgs, MmioTransportState};
@@ -44,9 +44,12 @@ use crate::devices::virtio::vsock::{
 };
 use crate::devices::virtio::{TYPE_BALLOON, TYPE_BLOCK, TYPE_NET, TYPE_RNG};
 use crate::mmds::data_store::MmdsVersion;
+use crate::rate_limiter::group::RateLimiterGroup;
+use crate::rate_limiter::persist::RateLimiterGroupState;
 use crate::resources::{ResourcesError, VmResources};
 use crate::snapshot::Persist;
 use crate::vmm_config::mmds::MmdsConfigError;
+use crate::vmm_config::rate_limiter_group::RateLimiterGroupError;
 use crate::vstate::memory::GuestMemoryMmap;
 use crate::EventManager;
 
@@ -74,6 +77,10 @@ pub enum DevicePersistError {
     MmdsConfig(#[from] MmdsConfigError),
     /// Entropy: {0}
     Entropy(#[from] EntropyError),
+    /// Rate limiter group: {0}
+    RateLimiterGroup(#[from] RateLimiterGroupError),
+    /// Failed to restore rate limiter group: {0}
+    RateLimiterGroupRestore(std::io::Error),
     /// Resource misconfiguration: {0}. Is the snapshot file corrupted?
     ResourcesError(#[from] ResourcesError),
 }
@@ -196,6 +203,87 @@ pub struct DeviceStates {
     pub mmds_version: Option<MmdsVersionState>,
     /// Entropy device state.
     pub entropy_device: Option<ConnectedEntropyState>,
+    /// Rate limiter groups shared by the devices.
+    pub rate_limiter_groups: Vec<RateLimiterGroupState>,
+}
+
+/// Layout of [`ConnectedBlockState`] in snapshot format version 2.0.0.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectedBlockStateV2 {
+    device_id: String,
+    device_state: BlockStateV2,
+    transport_state: MmioTransportState,
+    device_info: MMIODeviceInfo,
+}
+
+/// Layout of [`ConnectedNetState`] in snapshot format version 2.0.0.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectedNetStateV2 {
+    device_id: String,
+    device_state: NetStateV2,
+    transport_state: MmioTransportState,
+    device_info: MMIODeviceInfo,
+}
+
+/// Layout of [`DeviceStates`] in snapshot format version 2.0.0.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceStatesV2 {
+    #[cfg(target_arch = "aarch64")]
+    legacy_devices: Vec<ConnectedLegacyState>,
+    block_devices: Vec<ConnectedBlockStateV2>,
+    net_devices: Vec<ConnectedNetStateV2>,
+    vsock_device: Option<ConnectedVsockState>,
+    balloon_device: Option<ConnectedBalloonState>,
+    mmds_version: Option<MmdsVersionState>,
+    entropy_device: Option<ConnectedEntropyState>,
+}
+
+impl From<DeviceStatesV2> for DeviceStates {
+    fn from(states: DeviceStatesV2) -> Self {
+        DeviceStates {
+            #[cfg(target_arch = "aarch64")]
+            legacy_devices: states.legacy_devices,
+            block_devices: states
+                .block_devices
+                .into_iter()
+                .map(|block| ConnectedBlockState {
+                    device_id: block.device_id,
+                    device_state: block.device_state.into(),
+                    transport_state: block.transport_state,
+                    device_info: block.device_info,
+                })
+                .collect(),
+            net_devices: states
+                .net_devices
+                .into_iter()
+                .map(|net| ConnectedNetState {
+                    device_id: net.device_id,
+                    device_state: net.device_state.into(),
+                    transport_state: net.transport_state,
+                    device_info: net.device_info,
+                })
+                .collect(),
+            vsock_device: states.vsock_device,
+            balloon_device: states.balloon_device,
+            mmds_version: states.mmds_version,
+            entropy_device: states.entropy_device,
+            // Devices could not share rate limiters before format version 3.0.0.
+            rate_limiter_groups: Vec::new(),
+        }
+    }
+}
+
+impl DeviceStates {
+    // Saves the state of the group, unless another member of the group already did.
+    fn save_rate_limiter_group(&mut self, group: &RateLimiterGroup) {
+        if !self
+            .rate_limiter_groups
+            .iter()
+            .any(|state| state.id == group.id())
+        {
+            self.rate_limiter_groups.push(group.save());
+        }
+    }
 }
 
 /// A type used to extract the concrete `Arc<Mutex<T>>` for each of the device
@@ -333,20 +421,15 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                 // Both virtio-block and vhost-user-block share same device type.
                 TYPE_BLOCK => {
                     let block = locked_device.as_mut_any().downcast_mut::<Block>().unwrap();
-                    if block.is_vhost_user() {
-                        warn!(
-                            "Skipping vhost-user-block device. VhostUserBlock does not support \
-                             snapshotting yet"
-                        );
-                    } else {
-                        block.prepare_save();
-                        states.block_devices.push(ConnectedBlockState {
-                            device_id: devid.clone(),
-                            device_state: block.save(),
-                            transport_state,
-                            device_info: device_info.clone(),
-                        })
+                    if let Some(group) = block.rate_limiter_group() {
+                        states.save_rate_limiter_group(group);
                     }
+                    states.block_devices.push(ConnectedBlockState {
+                        device_id: devid.clone(),
+                        device_state: block.save(),
+                        transport_state,
+                        device_info: device_info.clone(),
+                    })
                 }
                 TYPE_NET => {
                     let net = locked_device.as_any().downcast_ref::<Net>().unwrap();
@@ -356,6 +439,9 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                         states.mmds_version =
                             Some(mmds_ns.mmds.lock().expect("Poisoned lock").version().into());
                     }
+                    if let Some(group) = net.rx_rate_limiter().group() {
+                        states.save_rate_limiter_group(group);
+                    }
 
                     states.net_devices.push(ConnectedNetState {
                         device_id: devid.clone(),
@@ -514,6 +600,16 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             Ok(())
         };
 
+        // The groups must be restored before the devices joining them.
+        for group_state in &state.rate_limiter_groups {
+            let group = RateLimiterGroup::restore((), group_state)
+                .map_err(DevicePersistError::RateLimiterGroupRestore)?;
+            constructor_args
+                .vm_resources
+                .rate_limiter_groups
+                .add_group(group);
+        }
+
         if let Some(balloon_state) = &state.balloon_device {
             let device = Arc::new(Mutex::new(Balloon::restore(
                 BalloonConstructorArgs { mem: mem.clone() },
@@ -540,6 +636,18 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                 BlockConstructorArgs { mem: mem.clone() },
                 &block_state.device_state,
             )?));
+            let is_vhost_user = device.lock().expect("Poisoned lock").is_vhost_user();
+            if let Some(group_id) = block_state.device_state.rate_limiter_group() {
+                let group = constructor_args
+                    .vm_resources
+                    .rate_limiter_groups
+                    .get(group_id)
+                    .ok_or_else(|| RateLimiterGroupError::GroupNotFound(group_id.to_string()))?;
+                device
+                    .lock()
+                    .expect("Poisoned lock")
+                    .join_rate_limiter_group(group)?;
+            }
 
             constructor_args
                 .vm_resources
@@ -547,7 +655,7 @@ impl<'a> Persist<'a> for MMIODeviceManager {
 
             restore_helper(
                 device.clone(),
-                false,
+                is_vhost_user,
                 device,
                 &block_state.device_id,
                 &block_state.transport_state,
@@ -585,6 +693,17 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                 },
                 &net_state.device_state,
             )?));
+            if let Some(group_id) = net_state.device_state.rate_limiter_group.as_deref() {
+                let group = constructor_args
+                    .vm_resources
+                    .rate_limiter_groups
+                    .get(group_id)
+                    .ok_or_else(|| RateLimiterGroupError::GroupNotFound(group_id.to_string()))?;
+                device
+                    .lock()
+                    .expect("Poisoned lock")
+                    .join_rate_limiter_group(group);
+            }
 
             constructor_args
                 .vm_resources
@@ -707,6 +826,44 @@ mod tests {
         }
     }
 
+    impl From<DeviceStates> for DeviceStatesV2 {
+        fn from(states: DeviceStates) -> Self {
+            DeviceStatesV2 {
+                #[cfg(target_arch = "aarch64")]
+                legacy_devices: states.legacy_devices,
+                block_devices: states
+                    .block_devices
+                    .into_iter()
+                    .map(|block| ConnectedBlockStateV2 {
+                        device_id: block.device_id,
+                        device_state: match block.device_state {
+                            BlockState::Virtio(state) => BlockStateV2::Virtio(state.into()),
+                            BlockState::VhostUser(_) => {
+                                panic!("vhost-user block devices were not saved")
+                            }
+                        },
+                        transport_state: block.transport_state,
+                        device_info: block.device_info,
+                    })
+                    .collect(),
+                net_devices: states
+                    .net_devices
+                    .into_iter()
+                    .map(|net| ConnectedNetStateV2 {
+                        device_id: net.device_id,
+                        device_state: net.device_state.into(),
+                        transport_state: net.transport_state,
+                        device_info: net.device_info,
+                    })
+                    .collect(),
+                vsock_device: states.vsock_device,
+                balloon_device: states.balloon_device,
+                mmds_version: states.mmds_version,
+                entropy_device: states.entropy_device,
+            }
+        }
+    }
+
     impl MMIODeviceManager {
         fn soft_clone(&self) -> Self {
             // We can unwrap here as we create with values directly in scope we
@@ -773,6 +930,8 @@ mod tests {
                 guest_mac: None,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                rate_limiter_group: None,
+                link_status: false,
             };
             insert_net_device_with_mmds(
                 &mut vmm,
@@ -832,6 +991,18 @@ mod tests {
       "path_on_host": "{}",
       "rate_limiter": null,
       "io_engine": "Sync",
+      "overlay": null,
+      "num_queues": 1,
+      "async_io": {{
+        "fixed_buffers": false,
+        "sq_poll": false,
+        "sq_poll_idle_ms": 0
+      }},
+      "host_cache_mode": "Buffered",
+      "rate_limiter_group": null,
+      "verity": null,
+      "nbd": null,
+      "encryption": null,
       "socket": null
     }}
   ],
@@ -847,7 +1018,8 @@ mod tests {
     "mem_size_mib": 128,
     "smt": false,
     "track_dirty_pages": false,
-    "huge_pages": "None"
+    "huge_pages": "None",
+    "dirty_tracking_backend": "Bitmap"
   }},
   "metrics": null,
   "mmds-config": {{
@@ -863,9 +1035,11 @@ mod tests {
       "host_dev_name": "hostname",
       "guest_mac": null,
       "rx_rate_limiter": null,
-      "tx_rate_limiter": null
+      "tx_rate_limiter": null,
+      "rate_limiter_group": null
     }}
   ],
+  "rate-limiter-groups": [],
   "vsock": {{
     "guest_cid": 3,
     "uds_path": "{}"
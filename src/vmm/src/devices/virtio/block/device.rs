@@ -14,6 +14,7 @@ use super::BlockError;
 use crate::devices::virtio::device::VirtioDevice;
 use crate::devices::virtio::queue::Queue;
 use crate::devices::virtio::{ActivateError, TYPE_BLOCK};
+use crate::rate_limiter::group::RateLimiterGroup;
 use crate::rate_limiter::BucketUpdate;
 use crate::snapshot::Persist;
 use crate::vmm_config::drive::BlockDeviceConfig;
@@ -72,6 +73,30 @@ impl Block {
         }
     }
 
+    pub fn join_rate_limiter_group(&mut self, group: &RateLimiterGroup) -> Result<(), BlockError> {
+        match self {
+            Self::Virtio(b) => {
+                b.join_rate_limiter_group(group);
+                Ok(())
+            }
+            Self::VhostUser(_) => Err(BlockError::InvalidBlockBackend),
+        }
+    }
+
+    pub fn rate_limiter_group(&self) -> Option<&RateLimiterGroup> {
+        match self {
+            Self::Virtio(b) => b.rate_limiter.group(),
+            Self::VhostUser(_) => None,
+        }
+    }
+
+    pub fn fixed_buffers(&self) -> bool {
+        match self {
+            Self::Virtio(b) => b.disk.async_io.fixed_buffers,
+            Self::VhostUser(_) => false,
+        }
+    }
+
     pub fn update_config(&mut self) -> Result<(), BlockError> {
         match self {
             Self::Virtio(_) => Err(BlockError::InvalidBlockBackend),
@@ -79,10 +104,13 @@ impl Block {
         }
     }
 
-    pub fn prepare_save(&mut self) {
+    pub fn prepare_save(&mut self) -> Result<(), BlockError> {
         match self {
-            Self::Virtio(b) => b.prepare_save(),
-            Self::VhostUser(b) => b.prepare_save(),
+            Self::Virtio(b) => {
+                b.prepare_save();
+                Ok(())
+            }
+            Self::VhostUser(b) => b.prepare_save().map_err(BlockError::VhostUserBackend),
         }
     }
 
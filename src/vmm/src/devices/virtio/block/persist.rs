@@ -4,7 +4,7 @@
 use serde::{Deserialize, Serialize};
 
 use super::vhost_user::persist::VhostUserBlockState;
-use super::virtio::persist::VirtioBlockState;
+use super::virtio::persist::{VirtioBlockState, VirtioBlockStateV2};
 use crate::vstate::memory::GuestMemoryMmap;
 
 /// Block device state.
@@ -14,6 +14,31 @@ pub enum BlockState {
     VhostUser(VhostUserBlockState),
 }
 
+impl BlockState {
+    /// Returns the rate limiter group the device was a member of, if any.
+    pub fn rate_limiter_group(&self) -> Option<&str> {
+        match self {
+            BlockState::Virtio(state) => state.rate_limiter_group.as_deref(),
+            BlockState::VhostUser(_) => None,
+        }
+    }
+}
+
+/// Layout of [`BlockState`] in snapshot format version 2.0.0, which did not save vhost-user block
+/// devices.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BlockStateV2 {
+    Virtio(VirtioBlockStateV2),
+}
+
+impl From<BlockStateV2> for BlockState {
+    fn from(state: BlockStateV2) -> Self {
+        match state {
+            BlockStateV2::Virtio(state) => BlockState::Virtio(state.into()),
+        }
+    }
+}
+
 /// Auxiliary structure for creating a device when resuming from a snapshot.
 #[derive(Debug)]
 pub struct BlockConstructorArgs {
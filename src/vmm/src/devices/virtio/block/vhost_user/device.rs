@@ -6,6 +6,7 @@
 
 use std::cmp;
 use std::io::Write;
+use std::num::Wrapping;
 use std::sync::atomic::AtomicU32;
 use std::sync::Arc;
 
@@ -73,6 +74,14 @@ impl TryFrom<&BlockDeviceConfig> for VhostUserBlockConfig {
             && value.path_on_host.is_none()
             && value.rate_limiter.is_none()
             && value.file_engine_type.is_none()
+            && value.overlay.is_none()
+            && value.num_queues.is_none()
+            && value.async_io.is_none()
+            && value.host_cache_mode.is_none()
+            && value.rate_limiter_group.is_none()
+            && value.verity.is_none()
+            && value.nbd.is_none()
+            && value.encryption.is_none()
         {
             Ok(Self {
                 drive_id: value.drive_id.clone(),
@@ -100,6 +109,14 @@ impl From<VhostUserBlockConfig> for BlockDeviceConfig {
             path_on_host: None,
             rate_limiter: None,
             file_engine_type: None,
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: Some(value.socket),
         }
@@ -132,6 +149,9 @@ pub struct VhostUserBlockImpl<T: VhostUserHandleBackend> {
     // Vhost user protocol handle
     pub vu_handle: VhostUserHandleImpl<T>,
     pub vu_acked_protocol_features: u64,
+    // Indexes of the next available descriptors of the backend rings, retrieved when
+    // preparing the device for being snapshotted.
+    pub vring_bases: Vec<u16>,
     pub metrics: Arc<VhostUserDeviceMetrics>,
 }
 
@@ -157,6 +177,7 @@ impl<T: VhostUserHandleBackend> std::fmt::Debug for VhostUserBlockImpl<T> {
                 "vu_acked_protocol_features",
                 &self.vu_acked_protocol_features,
             )
+            .field("vring_bases", &self.vring_bases)
             .field("metrics", &self.metrics)
             .finish()
     }
@@ -238,13 +259,44 @@ impl<T: VhostUserHandleBackend> VhostUserBlockImpl<T> {
 
             vu_handle,
             vu_acked_protocol_features: acked_protocol_features,
+            vring_bases: vec![],
             metrics,
         })
     }
 
     /// Prepare device for being snapshotted.
-    pub fn prepare_save(&mut self) {
-        unimplemented!("VhostUserBlock does not support snapshotting yet");
+    ///
+    /// Stopping the backend rings makes the backend complete in-flight requests and report
+    /// where it stopped. The rings are then restarted from there, so the microVM can keep
+    /// running after the snapshot is taken.
+    pub fn prepare_save(&mut self) -> Result<(), VhostUserBlockError> {
+        self.vring_bases.clear();
+
+        let mem = match &self.device_state {
+            DeviceState::Activated(mem) => mem.clone(),
+            DeviceState::Inactive => return Ok(()),
+        };
+        let queues = [(0, &self.queues[0], &self.queue_evts[0])];
+
+        let vring_bases = queues
+            .iter()
+            .map(|(queue_index, _, _)| self.vu_handle.get_vring_base(*queue_index))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(VhostUserBlockError::VhostUser)?;
+
+        self.vu_handle
+            .setup_backend_with_vring_bases(&mem, &queues, &vring_bases, &self.irq_trigger)
+            .map_err(VhostUserBlockError::VhostUser)?;
+
+        // Descriptors are only processed by the backend, so the queues only learn how far it
+        // got here. As the backend completed all the requests it took, the used rings are
+        // at the same position.
+        for (queue, vring_base) in self.queues.iter_mut().zip(vring_bases.iter()) {
+            queue.next_avail = Wrapping(*vring_base);
+            queue.next_used = Wrapping(*vring_base);
+        }
+        self.vring_bases = vring_bases;
+        Ok(())
     }
 
     pub fn config(&self) -> VhostUserBlockConfig {
@@ -400,6 +452,14 @@ mod tests {
             path_on_host: None,
             rate_limiter: None,
             file_engine_type: None,
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: Some("sock".to_string()),
         };
@@ -415,6 +475,14 @@ mod tests {
             path_on_host: Some("path".to_string()),
             rate_limiter: None,
             file_engine_type: Some(FileEngineType::Sync),
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: None,
         };
@@ -430,6 +498,14 @@ mod tests {
             path_on_host: Some("path".to_string()),
             rate_limiter: None,
             file_engine_type: Some(FileEngineType::Sync),
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: Some("sock".to_string()),
         };
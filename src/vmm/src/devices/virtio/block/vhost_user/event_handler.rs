@@ -68,9 +68,9 @@ impl MutEventSubscriber for VhostUserBlock {
         //  - shortly after device creation,
         //  - on device activation (is-activated already true at this point),
         //  - on device restore from snapshot.
-        if self.is_activated() {
-            warn!("Vhost-user block: unexpected init event");
-        } else {
+        // Devices restored in the activated state already had their rings set up with the
+        // backend, so there is nothing left to register for them.
+        if !self.is_activated() {
             self.register_activate_event(ops);
         }
     }
@@ -29,4 +29,6 @@ pub enum VhostUserBlockError {
     EventFd(std::io::Error),
     /// Error creating irqfd: {0}
     IrqTrigger(std::io::Error),
+    /// Backend does not support the features negotiated before the snapshot was taken
+    IncompatibleBackend,
 }
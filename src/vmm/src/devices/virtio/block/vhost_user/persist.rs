@@ -3,41 +3,369 @@
 
 //! Defines the structures needed for saving/restoring block devices.
 
+use std::sync::atomic::AtomicU32;
+use std::sync::Arc;
+
 use serde::{Deserialize, Serialize};
+use utils::eventfd::EventFd;
+use utils::u64_to_usize;
+use vhost::vhost_user::message::VhostUserProtocolFeatures;
 
-use super::device::VhostUserBlock;
-use super::VhostUserBlockError;
+use super::device::VhostUserBlockImpl;
+use super::{VhostUserBlockError, NUM_QUEUES, QUEUE_SIZE};
 use crate::devices::virtio::block::persist::BlockConstructorArgs;
 use crate::devices::virtio::block::CacheType;
-use crate::devices::virtio::persist::VirtioDeviceState;
+use crate::devices::virtio::device::{DeviceState, IrqTrigger};
+use crate::devices::virtio::gen::virtio_blk::VIRTIO_BLK_F_RO;
+use crate::devices::virtio::persist::{PersistError, VirtioDeviceState};
+use crate::devices::virtio::vhost_user::{VhostUserHandleBackend, VhostUserHandleImpl};
+use crate::devices::virtio::vhost_user_metrics::VhostUserMetricsPerDevice;
+use crate::devices::virtio::TYPE_BLOCK;
 use crate::snapshot::Persist;
 
 /// vhost-user block device state.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VhostUserBlockState {
-    id: String,
+    /// Unique identifier of the drive.
+    pub id: String,
     partuuid: Option<String>,
     cache_type: CacheType,
     root_device: bool,
-    socket_path: String,
+    /// Path of the socket the vhost-user backend is listening on.
+    pub socket_path: String,
     vu_acked_protocol_features: u64,
     config_space: Vec<u8>,
+    vring_bases: Vec<u16>,
     virtio_state: VirtioDeviceState,
 }
 
-impl Persist<'_> for VhostUserBlock {
+impl<T: VhostUserHandleBackend + Send + 'static> Persist<'_> for VhostUserBlockImpl<T> {
     type State = VhostUserBlockState;
     type ConstructorArgs = BlockConstructorArgs;
     type Error = VhostUserBlockError;
 
     fn save(&self) -> Self::State {
-        unimplemented!("VhostUserBlock does not support snapshotting yet");
+        VhostUserBlockState {
+            id: self.id.clone(),
+            partuuid: self.partuuid.clone(),
+            cache_type: self.cache_type,
+            root_device: self.root_device,
+            socket_path: self.vu_handle.socket_path.clone(),
+            vu_acked_protocol_features: self.vu_acked_protocol_features,
+            config_space: self.config_space.clone(),
+            vring_bases: self.vring_bases.clone(),
+            virtio_state: VirtioDeviceState::from_device(self),
+        }
     }
 
     fn restore(
-        _constructor_args: Self::ConstructorArgs,
-        _state: &Self::State,
+        constructor_args: Self::ConstructorArgs,
+        state: &Self::State,
     ) -> Result<Self, Self::Error> {
-        unimplemented!("VhostUserBlock does not support snapshotting yet");
+        let queues = state
+            .virtio_state
+            .build_queues_checked(
+                &constructor_args.mem,
+                TYPE_BLOCK,
+                u64_to_usize(NUM_QUEUES),
+                QUEUE_SIZE,
+            )
+            .map_err(VhostUserBlockError::Persist)?;
+        // Rings of an activated device can only be resumed from where the backend stopped.
+        if state.virtio_state.activated && state.vring_bases.len() != queues.len() {
+            return Err(VhostUserBlockError::Persist(PersistError::InvalidInput));
+        }
+
+        let mut vu_handle = VhostUserHandleImpl::<T>::new(&state.socket_path, NUM_QUEUES)
+            .map_err(VhostUserBlockError::VhostUser)?;
+
+        // The guest driver already relies on the features offered before the snapshot was
+        // taken, so the backend we reconnect to has to support all of them.
+        let (avail_features, acked_protocol_features) = vu_handle
+            .negotiate_features(
+                state.virtio_state.avail_features,
+                VhostUserProtocolFeatures::from_bits_truncate(state.vu_acked_protocol_features),
+            )
+            .map_err(VhostUserBlockError::VhostUser)?;
+        if avail_features != state.virtio_state.avail_features
+            || acked_protocol_features != state.vu_acked_protocol_features
+        {
+            return Err(VhostUserBlockError::IncompatibleBackend);
+        }
+
+        let queue_evts = [EventFd::new(libc::EFD_NONBLOCK).map_err(VhostUserBlockError::EventFd)?;
+            u64_to_usize(NUM_QUEUES)];
+        let mut irq_trigger = IrqTrigger::new().map_err(VhostUserBlockError::IrqTrigger)?;
+        irq_trigger.irq_status = Arc::new(AtomicU32::new(state.virtio_state.interrupt_status));
+
+        let acked_features = state.virtio_state.acked_features;
+        let device_state = if state.virtio_state.activated {
+            vu_handle
+                .set_features(acked_features)
+                .map_err(VhostUserBlockError::VhostUser)?;
+            vu_handle
+                .setup_backend_with_vring_bases(
+                    &constructor_args.mem,
+                    &[(0, &queues[0], &queue_evts[0])],
+                    &state.vring_bases,
+                    &irq_trigger,
+                )
+                .map_err(VhostUserBlockError::VhostUser)?;
+            DeviceState::Activated(constructor_args.mem)
+        } else {
+            DeviceState::Inactive
+        };
+
+        Ok(Self {
+            avail_features,
+            acked_features,
+            config_space: state.config_space.clone(),
+            activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(VhostUserBlockError::EventFd)?,
+
+            queues,
+            queue_evts,
+            device_state,
+            irq_trigger,
+
+            id: state.id.clone(),
+            partuuid: state.partuuid.clone(),
+            cache_type: state.cache_type,
+            root_device: state.root_device,
+            read_only: avail_features & (1u64 << VIRTIO_BLK_F_RO) != 0,
+
+            vu_handle,
+            vu_acked_protocol_features: acked_protocol_features,
+            vring_bases: state.vring_bases.clone(),
+            metrics: VhostUserMetricsPerDevice::alloc(format!("block_{}", state.id)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::undocumented_unsafe_blocks)]
+
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use utils::tempfile::TempFile;
+    use vhost::vhost_user::message::*;
+    use vhost::{VhostUserMemoryRegionInfo, VringConfigData};
+
+    use super::*;
+    use crate::devices::virtio::block::vhost_user::device::VhostUserBlockConfig;
+    use crate::devices::virtio::device::VirtioDevice;
+    use crate::devices::virtio::vhost_user::VhostUserError;
+    use crate::snapshot::Snapshot;
+    use crate::utilities::test_utils::create_tmp_socket;
+    use crate::vstate::memory::{
+        Bytes, FileOffset, GuestAddress, GuestMemoryExtension, GuestMemoryMmap,
+    };
+
+    static BACKEND_FEATURES: AtomicU64 = AtomicU64::new(u64::MAX);
+
+    struct MockMaster {
+        vring_base: std::cell::UnsafeCell<u16>,
+        vring_enabled: std::cell::UnsafeCell<bool>,
+        backend_stopped: std::cell::UnsafeCell<bool>,
+    }
+
+    impl VhostUserHandleBackend for MockMaster {
+        fn from_stream(_sock: UnixStream, _max_queue_num: u64) -> Self {
+            Self {
+                vring_base: std::cell::UnsafeCell::new(0),
+                vring_enabled: std::cell::UnsafeCell::new(false),
+                backend_stopped: std::cell::UnsafeCell::new(false),
+            }
+        }
+
+        fn set_owner(&self) -> Result<(), vhost::Error> {
+            Ok(())
+        }
+
+        fn set_hdr_flags(&self, _flags: VhostUserHeaderFlag) {}
+
+        fn get_features(&self) -> Result<u64, vhost::Error> {
+            Ok(BACKEND_FEATURES.load(Ordering::SeqCst))
+        }
+
+        fn set_features(&self, _features: u64) -> Result<(), vhost::Error> {
+            Ok(())
+        }
+
+        fn get_protocol_features(&mut self) -> Result<VhostUserProtocolFeatures, vhost::Error> {
+            Ok(VhostUserProtocolFeatures::all())
+        }
+
+        fn set_protocol_features(
+            &mut self,
+            _features: VhostUserProtocolFeatures,
+        ) -> Result<(), vhost::Error> {
+            Ok(())
+        }
+
+        fn get_config(
+            &mut self,
+            _offset: u32,
+            _size: u32,
+            _flags: VhostUserConfigFlags,
+            _buf: &[u8],
+        ) -> Result<(VhostUserConfig, VhostUserConfigPayload), vhost::Error> {
+            Ok((VhostUserConfig::default(), vec![0x69, 0x69, 0x69]))
+        }
+
+        fn set_mem_table(
+            &self,
+            _regions: &[VhostUserMemoryRegionInfo],
+        ) -> Result<(), vhost::Error> {
+            Ok(())
+        }
+
+        fn set_vring_num(&self, _queue_index: usize, _num: u16) -> Result<(), vhost::Error> {
+            Ok(())
+        }
+
+        fn set_vring_addr(
+            &self,
+            _queue_index: usize,
+            _config_data: &VringConfigData,
+        ) -> Result<(), vhost::Error> {
+            Ok(())
+        }
+
+        fn set_vring_base(&self, _queue_index: usize, base: u16) -> Result<(), vhost::Error> {
+            unsafe { *self.vring_base.get() = base };
+            Ok(())
+        }
+
+        fn get_vring_base(&self, _queue_index: usize) -> Result<u32, vhost::Error> {
+            if unsafe { *self.backend_stopped.get() } {
+                return Err(vhost::Error::InvalidQueue);
+            }
+            // The backend processed 0x42 requests.
+            Ok(0x42)
+        }
+
+        fn set_vring_call(&self, _queue_index: usize, _fd: &EventFd) -> Result<(), vhost::Error> {
+            Ok(())
+        }
+
+        fn set_vring_kick(&self, _queue_index: usize, _fd: &EventFd) -> Result<(), vhost::Error> {
+            Ok(())
+        }
+
+        fn set_vring_enable(
+            &mut self,
+            _queue_index: usize,
+            enable: bool,
+        ) -> Result<(), vhost::Error> {
+            unsafe { *self.vring_enabled.get() = enable };
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_persistence() {
+        let (_tmp_dir, tmp_socket_path) = create_tmp_socket();
+        let vhost_block_config = VhostUserBlockConfig {
+            drive_id: "test_drive".to_string(),
+            partuuid: None,
+            is_root_device: false,
+            cache_type: CacheType::Writeback,
+            socket: tmp_socket_path.clone(),
+        };
+        let mut vhost_block = VhostUserBlockImpl::<MockMaster>::new(vhost_block_config).unwrap();
+
+        let region_size = 0x10000;
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(region_size as u64).unwrap();
+        let regions = vec![(
+            FileOffset::new(file.try_clone().unwrap(), 0x0),
+            GuestAddress(0x0),
+            region_size,
+        )];
+        let guest_memory = GuestMemoryMmap::from_raw_regions_file(regions, false, true).unwrap();
+
+        // The guest made 0x42 requests available.
+        let queue = &mut vhost_block.queues[0];
+        queue.size = 16;
+        queue.ready = true;
+        queue.desc_table = GuestAddress(0x0);
+        queue.avail_ring = GuestAddress(0x1000);
+        queue.used_ring = GuestAddress(0x2000);
+        guest_memory
+            .write_obj(0x42u16, GuestAddress(0x1002))
+            .unwrap();
+        vhost_block.activate(guest_memory.clone()).unwrap();
+
+        // Preparing the save retrieves the vring bases and restarts the rings from them.
+        vhost_block.prepare_save().unwrap();
+        assert_eq!(vhost_block.vring_bases, vec![0x42]);
+        assert_eq!(vhost_block.queues[0].next_avail.0, 0x42);
+        assert_eq!(unsafe { *vhost_block.vu_handle.vu.vring_base.get() }, 0x42);
+        assert!(unsafe { *vhost_block.vu_handle.vu.vring_enabled.get() });
+
+        let mut mem = vec![0; 4096];
+        Snapshot::serialize(&mut mem.as_mut_slice(), &vhost_block.save()).unwrap();
+        let state: VhostUserBlockState = Snapshot::deserialize(&mut mem.as_slice()).unwrap();
+
+        // The restored device reconnects to the backend and resumes the rings.
+        let restored_block = VhostUserBlockImpl::<MockMaster>::restore(
+            BlockConstructorArgs {
+                mem: guest_memory.clone(),
+            },
+            &state,
+        )
+        .unwrap();
+        assert!(restored_block.is_activated());
+        assert_eq!(restored_block.id, "test_drive");
+        assert_eq!(restored_block.vu_handle.socket_path, tmp_socket_path);
+        assert_eq!(restored_block.avail_features, vhost_block.avail_features);
+        assert_eq!(restored_block.acked_features, vhost_block.acked_features);
+        assert_eq!(
+            restored_block.vu_acked_protocol_features,
+            vhost_block.vu_acked_protocol_features
+        );
+        assert_eq!(restored_block.config_space, vec![0x69, 0x69, 0x69]);
+        assert_eq!(restored_block.vring_bases, vec![0x42]);
+        assert_eq!(
+            unsafe { *restored_block.vu_handle.vu.vring_base.get() },
+            0x42
+        );
+        assert!(unsafe { *restored_block.vu_handle.vu.vring_enabled.get() });
+
+        // Activated devices cannot be restored without vring bases.
+        let mut invalid_state = state.clone();
+        invalid_state.vring_bases.clear();
+        assert!(matches!(
+            VhostUserBlockImpl::<MockMaster>::restore(
+                BlockConstructorArgs {
+                    mem: guest_memory.clone(),
+                },
+                &invalid_state,
+            )
+            .unwrap_err(),
+            VhostUserBlockError::Persist(PersistError::InvalidInput)
+        ));
+
+        // The backend has to support the features negotiated before the snapshot.
+        BACKEND_FEATURES.store(0, Ordering::SeqCst);
+        assert!(matches!(
+            VhostUserBlockImpl::<MockMaster>::restore(
+                BlockConstructorArgs { mem: guest_memory },
+                &state,
+            )
+            .unwrap_err(),
+            VhostUserBlockError::IncompatibleBackend
+        ));
+
+        // Failing to stop the rings fails the save, instead of saving a state that cannot be
+        // restored.
+        unsafe { *vhost_block.vu_handle.vu.backend_stopped.get() = true };
+        assert!(matches!(
+            vhost_block.prepare_save().unwrap_err(),
+            VhostUserBlockError::VhostUser(VhostUserError::VhostUserGetVringBase(_))
+        ));
+        assert!(vhost_block.vring_bases.is_empty());
     }
 }
@@ -5,43 +5,62 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
-use std::cmp;
 use std::convert::From;
 use std::fs::{File, OpenOptions};
 use std::io::{Seek, SeekFrom, Write};
 use std::os::linux::fs::MetadataExt;
-use std::path::PathBuf;
+use std::os::unix::fs::{FileExt, FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicU32;
 use std::sync::Arc;
+use std::{cmp, fmt};
 
 use block_io::FileEngine;
 use serde::{Deserialize, Serialize};
 use utils::eventfd::EventFd;
 use utils::kernel_version::{min_kernel_version_for_io_uring, KernelVersion};
 use utils::u64_to_usize;
+use zeroize::Zeroizing;
 
 use super::io::async_io;
 use super::request::*;
 use super::{
-    io as block_io, VirtioBlockError, BLOCK_CONFIG_SPACE_SIZE, BLOCK_QUEUE_SIZES, SECTOR_SHIFT,
-    SECTOR_SIZE,
+    io as block_io, VirtioBlockError, BLOCK_CONFIG_SPACE_SIZE, BLOCK_DEFAULT_NUM_QUEUES,
+    BLOCK_MAX_NUM_QUEUES, BLOCK_QUEUE_SIZE, SECTOR_SHIFT, SECTOR_SIZE,
 };
 use crate::devices::virtio::block::virtio::metrics::{BlockDeviceMetrics, BlockMetricsPerDevice};
 use crate::devices::virtio::block::CacheType;
 use crate::devices::virtio::device::{DeviceState, IrqTrigger, IrqType, VirtioDevice};
 use crate::devices::virtio::gen::virtio_blk::{
-    VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO, VIRTIO_BLK_ID_BYTES, VIRTIO_F_VERSION_1,
+    VIRTIO_BLK_F_BLK_SIZE, VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_MQ,
+    VIRTIO_BLK_F_RO, VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_BLK_ID_BYTES, VIRTIO_F_VERSION_1,
 };
 use crate::devices::virtio::gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use crate::devices::virtio::queue::Queue;
 use crate::devices::virtio::{ActivateError, TYPE_BLOCK};
 use crate::logger::{error, warn, IncMetric};
+use crate::rate_limiter::group::RateLimiterGroup;
 use crate::rate_limiter::{BucketUpdate, RateLimiter};
 use crate::vmm_config::drive::BlockDeviceConfig;
 use crate::vmm_config::RateLimiterConfig;
 use crate::vstate::memory::GuestMemoryMmap;
 
-/// The engine file type, either Sync or Async (through io_uring).
+// Offsets of the fields of the virtio block config space we populate.
+const CONFIG_CAPACITY_OFFSET: usize = 0;
+const CONFIG_BLK_SIZE_OFFSET: usize = 20;
+const CONFIG_NUM_QUEUES_OFFSET: usize = 34;
+const CONFIG_MAX_DISCARD_SECTORS_OFFSET: usize = 36;
+const CONFIG_MAX_DISCARD_SEG_OFFSET: usize = 40;
+const CONFIG_DISCARD_SECTOR_ALIGNMENT_OFFSET: usize = 44;
+const CONFIG_MAX_WRITE_ZEROES_SECTORS_OFFSET: usize = 48;
+const CONFIG_MAX_WRITE_ZEROES_SEG_OFFSET: usize = 52;
+const CONFIG_WRITE_ZEROES_MAY_UNMAP_OFFSET: usize = 56;
+
+// Largest logical block size probed for the backing files of drives using direct IO.
+const MAX_PROBED_LOGICAL_BLOCK_SIZE: u32 = 4096;
+
+/// The engine file type, either Sync, Async (through io_uring) or Qcow2.
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 pub enum FileEngineType {
     /// Use an Async engine, based on io_uring.
@@ -49,6 +68,8 @@ pub enum FileEngineType {
     /// Use a Sync engine, based on blocking system calls.
     #[default]
     Sync,
+    /// Use a Sync engine reading and writing a qcow2 disk image.
+    Qcow2,
 }
 
 impl FileEngineType {
@@ -61,30 +82,215 @@ impl FileEngineType {
     }
 }
 
+/// How the host caches the data of the file backing a drive.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum HostCacheMode {
+    /// Go through the host page cache.
+    #[default]
+    Buffered,
+    /// Bypass the host page cache by opening the file with `O_DIRECT`.
+    Direct,
+}
+
+/// Copy-on-write overlay of a drive. The file at `path_on_host` is then only read, while the
+/// blocks written by the guest are stored in the overlay file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OverlayConfig {
+    /// Path of the sparse file holding the blocks written by the guest.
+    pub path_on_host: String,
+    /// Path of the file recording which blocks are held by the overlay file. An empty file
+    /// creates a new overlay.
+    pub bitmap_path_on_host: String,
+}
+
+/// Hash tree checking the integrity of the data read from a read-only drive, in the dm-verity
+/// format.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VerityConfig {
+    /// Path of the file holding the hash tree, without superblock.
+    pub hash_tree_path_on_host: String,
+    /// Hex encoded SHA-256 root hash of the tree, from a trusted source.
+    pub root_hash: String,
+    /// Hex encoded salt of the digests of the tree, if any.
+    pub salt: Option<String>,
+}
+
+/// Transport of the connection to an NBD server.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum NbdTransport {
+    /// Connect to a Unix domain socket.
+    #[default]
+    Unix,
+    /// Connect to a TCP socket.
+    Tcp,
+}
+
+/// Export of an NBD server backing a drive in place of a host file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NbdConfig {
+    /// How to connect to the server.
+    #[serde(default)]
+    pub transport: NbdTransport,
+    /// Path of the Unix domain socket of the server, or its IP address and port for TCP.
+    pub address: String,
+    /// Name of the export. The default export of the server is used when empty.
+    #[serde(default)]
+    pub export_name: String,
+}
+
+/// Key of the AES-256-XTS encryption of the data of a drive, so that its backing file only holds
+/// ciphertext. Exactly one of the two fields must be provided.
+#[derive(Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DriveEncryptionConfig {
+    /// Base64 encoding of the 64 bytes key. It is never serialized, so that the configuration
+    /// of the microVM doesn't reveal it.
+    #[serde(default, skip_serializing)]
+    pub key: Option<Zeroizing<String>>,
+    /// File descriptor, inherited by the Firecracker process, from which the key is read.
+    #[serde(default)]
+    pub key_fd: Option<RawFd>,
+}
+
+// The key must never end up in logs.
+impl fmt::Debug for DriveEncryptionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DriveEncryptionConfig")
+            .field("key", &self.key.as_ref().map(|_| ".."))
+            .field("key_fd", &self.key_fd)
+            .finish()
+    }
+}
+
+/// Tuning options of the Async engine.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AsyncIoConfig {
+    /// Register the guest memory with io_uring, so that requests don't map it on every
+    /// submission.
+    #[serde(default)]
+    pub fixed_buffers: bool,
+    /// Poll the submission queue from a kernel thread, so that requests are submitted
+    /// without a system call.
+    #[serde(default)]
+    pub sq_poll: bool,
+    /// Milliseconds without requests after which the polling thread goes to sleep. 0 uses
+    /// the kernel default.
+    #[serde(default)]
+    pub sq_poll_idle_ms: u32,
+}
+
 /// Helper object for setting up all `Block` fields derived from its backing file.
 #[derive(Debug)]
 pub struct DiskProperties {
     pub file_path: String,
     pub file_engine: FileEngine<PendingRequest>,
+    // IO engines of the queues after the first one, which uses `file_engine`. Only the Async
+    // engine has one per queue, the other engines are shared by all the queues.
+    pub queue_file_engines: Vec<FileEngine<PendingRequest>>,
+    pub num_queues: u16,
     pub nsectors: u64,
     pub image_id: [u8; VIRTIO_BLK_ID_BYTES as usize],
+    pub overlay: Option<OverlayConfig>,
+    pub verity: Option<VerityConfig>,
+    pub nbd: Option<NbdConfig>,
+    pub cipher: Option<block_io::XtsCipher>,
+    pub async_io: AsyncIoConfig,
+    pub host_cache_mode: HostCacheMode,
+    // Logical block size advertised to the driver, to which the buffers, offsets and lengths of
+    // direct IO must be aligned.
+    pub logical_block_size: u32,
 }
 
 impl DiskProperties {
     // Helper function that opens the file with the proper access permissions
-    fn open_file(disk_image_path: &str, is_disk_read_only: bool) -> Result<File, VirtioBlockError> {
+    fn open_file(
+        disk_image_path: &str,
+        is_disk_read_only: bool,
+        host_cache_mode: HostCacheMode,
+    ) -> Result<File, VirtioBlockError> {
+        let custom_flags = match host_cache_mode {
+            HostCacheMode::Buffered => 0,
+            HostCacheMode::Direct => libc::O_DIRECT,
+        };
         OpenOptions::new()
             .read(true)
             .write(!is_disk_read_only)
+            .custom_flags(custom_flags)
             .open(PathBuf::from(&disk_image_path))
             .map_err(|x| VirtioBlockError::BackingFile(x, disk_image_path.to_string()))
     }
 
     // Helper function that gets the size of the file
     fn file_size(disk_image_path: &str, disk_image: &mut File) -> Result<u64, VirtioBlockError> {
-        let disk_size = disk_image
+        disk_image
             .seek(SeekFrom::End(0))
-            .map_err(|x| VirtioBlockError::BackingFile(x, disk_image_path.to_string()))?;
+            .map_err(|x| VirtioBlockError::BackingFile(x, disk_image_path.to_string()))
+    }
+
+    // Helper function that gets the logical block size of the storage backing a file opened for
+    // direct IO.
+    fn file_logical_block_size(
+        disk_image_path: &str,
+        disk_image: &File,
+    ) -> Result<u32, VirtioBlockError> {
+        let file_type = disk_image
+            .metadata()
+            .map_err(|x| VirtioBlockError::BackingFile(x, disk_image_path.to_string()))?
+            .file_type();
+        if file_type.is_block_device() {
+            let mut logical_block_size: libc::c_int = 0;
+            // SAFETY: BLKSSZGET stores an int at the given address, and the result is checked.
+            let ret = unsafe {
+                libc::ioctl(
+                    disk_image.as_raw_fd(),
+                    libc::BLKSSZGET,
+                    std::ptr::addr_of_mut!(logical_block_size),
+                )
+            };
+            if ret < 0 {
+                return Err(VirtioBlockError::LogicalBlockSize(
+                    std::io::Error::last_os_error(),
+                ));
+            }
+            return u32::try_from(logical_block_size)
+                .ok()
+                .filter(|size| size.is_power_of_two())
+                .ok_or_else(|| {
+                    VirtioBlockError::LogicalBlockSize(std::io::Error::from(
+                        std::io::ErrorKind::InvalidData,
+                    ))
+                });
+        }
+
+        // Direct IO on regular files fails with EINVAL until reads are aligned to the logical
+        // blocks of their file system.
+        let mut buf = block_io::AlignedBuffer::new(
+            MAX_PROBED_LOGICAL_BLOCK_SIZE as usize,
+            MAX_PROBED_LOGICAL_BLOCK_SIZE as usize,
+        );
+        let mut logical_block_size = SECTOR_SIZE;
+        loop {
+            match disk_image.read_at(&mut buf.as_mut_slice()[..logical_block_size as usize], 0) {
+                Ok(_) => return Ok(logical_block_size),
+                Err(err)
+                    if err.raw_os_error() == Some(libc::EINVAL)
+                        && logical_block_size < MAX_PROBED_LOGICAL_BLOCK_SIZE =>
+                {
+                    logical_block_size *= 2
+                }
+                Err(err) => return Err(VirtioBlockError::LogicalBlockSize(err)),
+            }
+        }
+    }
+
+    // Helper function that gets the size of the disk exposed to the guest, which is the size of
+    // the file unless the file engine reads a disk image format.
+    fn disk_size(file_engine: &FileEngine<PendingRequest>, file_size: u64) -> u64 {
+        let disk_size = file_engine.virtual_size().unwrap_or(file_size);
 
         // We only support disk size, which uses the first two words of the configuration space.
         // If the image is not a multiple of the sector size, the tail bits are not exposed.
@@ -96,7 +302,7 @@ impl DiskProperties {
             );
         }
 
-        Ok(disk_size)
+        disk_size
     }
 
     /// Create a new file for the block device using a FileEngine
@@ -104,34 +310,258 @@ impl DiskProperties {
         disk_image_path: String,
         is_disk_read_only: bool,
         file_engine_type: FileEngineType,
+        overlay: Option<OverlayConfig>,
+        verity: Option<VerityConfig>,
+        async_io: AsyncIoConfig,
+        host_cache_mode: HostCacheMode,
     ) -> Result<Self, VirtioBlockError> {
-        let mut disk_image = Self::open_file(&disk_image_path, is_disk_read_only)?;
-        let disk_size = Self::file_size(&disk_image_path, &mut disk_image)?;
-        let image_id = Self::build_disk_image_id(&disk_image);
+        // Disk image formats, overlays and verified drives access the file with no regard for
+        // alignment.
+        if host_cache_mode == HostCacheMode::Direct
+            && (overlay.is_some() || verity.is_some() || file_engine_type == FileEngineType::Qcow2)
+        {
+            return Err(VirtioBlockError::DirectIoUnsupported);
+        }
+        // Only the data of drives which can't be modified can be checked against a hash tree.
+        if verity.is_some() && (!is_disk_read_only || overlay.is_some()) {
+            return Err(VirtioBlockError::VerityUnsupported);
+        }
+
+        // The base image of an overlay is never written to.
+        let mut disk_image = Self::open_file(
+            &disk_image_path,
+            is_disk_read_only || overlay.is_some(),
+            host_cache_mode,
+        )?;
+        let file_size = Self::file_size(&disk_image_path, &mut disk_image)?;
+        let logical_block_size = match host_cache_mode {
+            HostCacheMode::Buffered => SECTOR_SIZE,
+            HostCacheMode::Direct => Self::file_logical_block_size(&disk_image_path, &disk_image)?,
+        };
+
+        let (file_engine, image_id) = match (&overlay, &verity) {
+            (Some(overlay), _) => {
+                let overlay_file = Self::open_file(
+                    &overlay.path_on_host,
+                    is_disk_read_only,
+                    HostCacheMode::Buffered,
+                )?;
+                let bitmap_file = Self::open_file(
+                    &overlay.bitmap_path_on_host,
+                    is_disk_read_only,
+                    HostCacheMode::Buffered,
+                )?;
+                // Drives sharing a base image get the identity of their overlay.
+                let image_id = Self::build_disk_image_id(&overlay_file);
+                let file_engine = FileEngine::from_overlay(
+                    disk_image,
+                    overlay_file,
+                    bitmap_file,
+                    file_engine_type,
+                )
+                .map_err(VirtioBlockError::FileEngine)?;
+                (file_engine, image_id)
+            }
+            (None, Some(verity)) => {
+                let hash_tree = Self::open_file(
+                    &verity.hash_tree_path_on_host,
+                    true,
+                    HostCacheMode::Buffered,
+                )?;
+                let image_id = Self::build_disk_image_id(&disk_image);
+                let file_engine = FileEngine::from_verity(
+                    disk_image,
+                    hash_tree,
+                    &verity.root_hash,
+                    verity.salt.as_deref(),
+                    file_engine_type,
+                )
+                .map_err(VirtioBlockError::FileEngine)?;
+                (file_engine, image_id)
+            }
+            (None, None) => {
+                let image_id = Self::build_disk_image_id(&disk_image);
+                let file_engine = FileEngine::from_file(
+                    disk_image,
+                    Path::new(&disk_image_path),
+                    file_engine_type,
+                    async_io,
+                )
+                .map_err(VirtioBlockError::FileEngine)?;
+                (file_engine, image_id)
+            }
+        };
+        let disk_size = Self::disk_size(&file_engine, file_size);
 
         Ok(Self {
             file_path: disk_image_path,
-            file_engine: FileEngine::from_file(disk_image, file_engine_type)
-                .map_err(VirtioBlockError::FileEngine)?,
+            file_engine,
+            queue_file_engines: Vec::new(),
+            num_queues: BLOCK_DEFAULT_NUM_QUEUES,
+            nsectors: disk_size >> SECTOR_SHIFT,
+            image_id,
+            overlay,
+            verity,
+            nbd: None,
+            cipher: None,
+            async_io,
+            host_cache_mode,
+            logical_block_size,
+        })
+    }
+
+    /// Create the properties of a block device served by an NBD server.
+    pub fn new_nbd(
+        nbd: NbdConfig,
+        is_disk_read_only: bool,
+        file_engine_type: FileEngineType,
+    ) -> Result<Self, VirtioBlockError> {
+        let image_id = Self::build_nbd_image_id(&nbd);
+        let file_engine = FileEngine::from_nbd(nbd.clone(), is_disk_read_only, file_engine_type)
+            .map_err(VirtioBlockError::FileEngine)?;
+        let disk_size = Self::disk_size(&file_engine, 0);
+
+        Ok(Self {
+            file_path: String::new(),
+            file_engine,
+            queue_file_engines: Vec::new(),
+            num_queues: BLOCK_DEFAULT_NUM_QUEUES,
             nsectors: disk_size >> SECTOR_SHIFT,
             image_id,
+            overlay: None,
+            verity: None,
+            nbd: Some(nbd),
+            cipher: None,
+            async_io: AsyncIoConfig::default(),
+            host_cache_mode: HostCacheMode::Buffered,
+            logical_block_size: SECTOR_SIZE,
         })
     }
 
+    /// Set the number of queues of the block device, creating an IO engine for each of them
+    /// when using the Async engine, so that each queue has its own io_uring instance.
+    pub fn set_num_queues(
+        &mut self,
+        num_queues: u16,
+        is_disk_read_only: bool,
+    ) -> Result<(), VirtioBlockError> {
+        self.queue_file_engines.clear();
+        if let FileEngine::Async(_) = self.file_engine {
+            for _ in 1..num_queues {
+                let disk_image =
+                    Self::open_file(&self.file_path, is_disk_read_only, self.host_cache_mode)?;
+                let mut file_engine = FileEngine::from_file(
+                    disk_image,
+                    Path::new(&self.file_path),
+                    FileEngineType::Async,
+                    self.async_io,
+                )
+                .map_err(VirtioBlockError::FileEngine)?;
+                if let Some(cipher) = &self.cipher {
+                    file_engine
+                        .set_cipher(cipher.clone())
+                        .map_err(VirtioBlockError::FileEngine)?;
+                }
+                self.queue_file_engines.push(file_engine);
+            }
+        }
+        self.num_queues = num_queues;
+
+        Ok(())
+    }
+
+    /// Encrypt the data of the drive with `cipher`. Must be called before `set_num_queues`.
+    pub fn set_cipher(&mut self, cipher: block_io::XtsCipher) -> Result<(), VirtioBlockError> {
+        self.file_engine
+            .set_cipher(cipher.clone())
+            .map_err(|err| match err {
+                block_io::BlockIoError::EncryptionUnsupported => {
+                    VirtioBlockError::EncryptionUnsupported
+                }
+                other => VirtioBlockError::FileEngine(other),
+            })?;
+        self.cipher = Some(cipher);
+
+        Ok(())
+    }
+
+    /// Keeps advertising the logical block size the driver was told about, which must be a
+    /// multiple of the one of the backing file.
+    pub fn keep_logical_block_size(
+        &mut self,
+        logical_block_size: u32,
+    ) -> Result<(), VirtioBlockError> {
+        if logical_block_size % self.logical_block_size != 0 {
+            return Err(VirtioBlockError::LogicalBlockSizeMismatch(
+                self.logical_block_size,
+                logical_block_size,
+            ));
+        }
+        self.logical_block_size = logical_block_size;
+        Ok(())
+    }
+
+    /// Returns the IO engine serving the requests of a queue.
+    pub fn file_engine_mut(&mut self, queue_index: usize) -> &mut FileEngine<PendingRequest> {
+        match queue_index
+            .checked_sub(1)
+            .and_then(|index| self.queue_file_engines.get_mut(index))
+        {
+            Some(file_engine) => file_engine,
+            None => &mut self.file_engine,
+        }
+    }
+
+    /// Returns the IO engines of all the queues.
+    pub fn file_engines(&self) -> impl Iterator<Item = &FileEngine<PendingRequest>> {
+        std::iter::once(&self.file_engine).chain(self.queue_file_engines.iter())
+    }
+
+    /// Returns the IO engines of all the queues.
+    pub fn file_engines_mut(&mut self) -> impl Iterator<Item = &mut FileEngine<PendingRequest>> {
+        std::iter::once(&mut self.file_engine).chain(self.queue_file_engines.iter_mut())
+    }
+
     /// Update the path to the file backing the block device
     pub fn update(
         &mut self,
         disk_image_path: String,
         is_disk_read_only: bool,
     ) -> Result<(), VirtioBlockError> {
-        let mut disk_image = Self::open_file(&disk_image_path, is_disk_read_only)?;
-        let disk_size = Self::file_size(&disk_image_path, &mut disk_image)?;
+        if self.nbd.is_some() {
+            return Err(VirtioBlockError::NbdUnsupported);
+        }
+        let mut disk_image = Self::open_file(
+            &disk_image_path,
+            is_disk_read_only || self.overlay.is_some(),
+            self.host_cache_mode,
+        )?;
+        let file_size = Self::file_size(&disk_image_path, &mut disk_image)?;
+        // The driver keeps using the logical block size it was told about.
+        if self.host_cache_mode == HostCacheMode::Direct {
+            let logical_block_size = Self::file_logical_block_size(&disk_image_path, &disk_image)?;
+            if self.logical_block_size % logical_block_size != 0 {
+                return Err(VirtioBlockError::LogicalBlockSizeMismatch(
+                    logical_block_size,
+                    self.logical_block_size,
+                ));
+            }
+        }
 
-        self.image_id = Self::build_disk_image_id(&disk_image);
+        if self.overlay.is_none() {
+            self.image_id = Self::build_disk_image_id(&disk_image);
+        }
         self.file_engine
-            .update_file_path(disk_image)
+            .update_file_path(disk_image, Path::new(&disk_image_path))
             .map_err(VirtioBlockError::FileEngine)?;
-        self.nsectors = disk_size >> SECTOR_SHIFT;
+        for file_engine in self.queue_file_engines.iter_mut() {
+            let disk_image =
+                Self::open_file(&disk_image_path, is_disk_read_only, self.host_cache_mode)?;
+            file_engine
+                .update_file_path(disk_image, Path::new(&disk_image_path))
+                .map_err(VirtioBlockError::FileEngine)?;
+        }
+        self.nsectors = Self::disk_size(&self.file_engine, file_size) >> SECTOR_SHIFT;
         self.file_path = disk_image_path;
 
         Ok(())
@@ -168,15 +598,43 @@ impl DiskProperties {
         default_id
     }
 
+    // NBD drives are identified by their export, or by their server when using the default
+    // export.
+    fn build_nbd_image_id(nbd: &NbdConfig) -> [u8; VIRTIO_BLK_ID_BYTES as usize] {
+        let mut image_id = [0; VIRTIO_BLK_ID_BYTES as usize];
+        let id = match nbd.export_name.is_empty() {
+            true => nbd.address.as_bytes(),
+            false => nbd.export_name.as_bytes(),
+        };
+        let bytes_to_copy = cmp::min(id.len(), VIRTIO_BLK_ID_BYTES as usize);
+        image_id[..bytes_to_copy].copy_from_slice(&id[..bytes_to_copy]);
+        image_id
+    }
+
     /// Provides vec containing the virtio block configuration space
     /// buffer. The config space is populated with the disk size based
-    /// on the backing file size.
+    /// on the backing file size, the number of queues, and with the
+    /// limits of discard and write zeroes requests.
     pub fn virtio_block_config_space(&self) -> Vec<u8> {
         // The config space is little endian.
-        let mut config = Vec::with_capacity(BLOCK_CONFIG_SPACE_SIZE);
-        for i in 0..BLOCK_CONFIG_SPACE_SIZE {
-            config.push(((self.nsectors >> (8 * i)) & 0xff) as u8);
-        }
+        let mut config = vec![0u8; BLOCK_CONFIG_SPACE_SIZE];
+        let mut write_u32 = |offset: usize, value: u32| {
+            config[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        // Requests carry a single segment of any length. Segments are aligned to sectors,
+        // as the backing file can be deallocated with the granularity of a sector.
+        write_u32(CONFIG_MAX_DISCARD_SECTORS_OFFSET, u32::MAX);
+        write_u32(CONFIG_MAX_DISCARD_SEG_OFFSET, 1);
+        write_u32(CONFIG_DISCARD_SECTOR_ALIGNMENT_OFFSET, 1);
+        write_u32(CONFIG_MAX_WRITE_ZEROES_SECTORS_OFFSET, u32::MAX);
+        write_u32(CONFIG_MAX_WRITE_ZEROES_SEG_OFFSET, 1);
+        // Write zeroes requests with the unmap flag punch holes in the backing file.
+        config[CONFIG_WRITE_ZEROES_MAY_UNMAP_OFFSET] = 1;
+        write_u32(CONFIG_BLK_SIZE_OFFSET, self.logical_block_size);
+        config[CONFIG_NUM_QUEUES_OFFSET..CONFIG_NUM_QUEUES_OFFSET + 2]
+            .copy_from_slice(&self.num_queues.to_le_bytes());
+        config[CONFIG_CAPACITY_OFFSET..CONFIG_CAPACITY_OFFSET + 8]
+            .copy_from_slice(&self.nsectors.to_le_bytes());
         config
     }
 }
@@ -202,7 +660,7 @@ pub struct VirtioBlockConfig {
     /// If set to true, the drive is opened in read-only mode. Otherwise, the
     /// drive is opened as read-write.
     pub is_read_only: bool,
-    /// Path of the backing file on the host
+    /// Path of the backing file on the host, empty for NBD drives.
     pub path_on_host: String,
     /// Rate Limiter for I/O operations.
     pub rate_limiter: Option<RateLimiterConfig>,
@@ -210,13 +668,38 @@ pub struct VirtioBlockConfig {
     #[serde(default)]
     #[serde(rename = "io_engine")]
     pub file_engine_type: FileEngineType,
+    /// Copy-on-write overlay receiving the writes to the drive.
+    pub overlay: Option<OverlayConfig>,
+    /// The number of request queues of the device.
+    #[serde(default = "default_num_queues")]
+    pub num_queues: u16,
+    /// Tuning options of the Async engine.
+    #[serde(default)]
+    pub async_io: AsyncIoConfig,
+    /// How the host caches the data of the backing file.
+    #[serde(default)]
+    pub host_cache_mode: HostCacheMode,
+    /// Rate limiter group whose budget is shared with other devices. The device joins the group
+    /// once it is inserted in the `VmResources`.
+    pub rate_limiter_group: Option<String>,
+    /// Hash tree checking the integrity of the data read from the drive.
+    pub verity: Option<VerityConfig>,
+    /// Export of an NBD server backing the drive in place of the file at `path_on_host`.
+    pub nbd: Option<NbdConfig>,
+    /// Key encrypting the data of the drive in the backing file.
+    pub encryption: Option<DriveEncryptionConfig>,
+}
+
+fn default_num_queues() -> u16 {
+    BLOCK_DEFAULT_NUM_QUEUES
 }
 
 impl TryFrom<&BlockDeviceConfig> for VirtioBlockConfig {
     type Error = VirtioBlockError;
 
     fn try_from(value: &BlockDeviceConfig) -> Result<Self, Self::Error> {
-        if value.path_on_host.is_some() && value.socket.is_none() {
+        // The drive is backed either by a file or by an NBD server.
+        if value.path_on_host.is_some() != value.nbd.is_some() && value.socket.is_none() {
             Ok(Self {
                 drive_id: value.drive_id.clone(),
                 partuuid: value.partuuid.clone(),
@@ -224,9 +707,17 @@ impl TryFrom<&BlockDeviceConfig> for VirtioBlockConfig {
                 cache_type: value.cache_type,
 
                 is_read_only: value.is_read_only.unwrap_or(false),
-                path_on_host: value.path_on_host.as_ref().unwrap().clone(),
+                path_on_host: value.path_on_host.clone().unwrap_or_default(),
                 rate_limiter: value.rate_limiter,
                 file_engine_type: value.file_engine_type.unwrap_or_default(),
+                overlay: value.overlay.clone(),
+                num_queues: value.num_queues.unwrap_or(BLOCK_DEFAULT_NUM_QUEUES),
+                async_io: value.async_io.unwrap_or_default(),
+                host_cache_mode: value.host_cache_mode.unwrap_or_default(),
+                rate_limiter_group: value.rate_limiter_group.clone(),
+                verity: value.verity.clone(),
+                nbd: value.nbd.clone(),
+                encryption: value.encryption.clone(),
             })
         } else {
             Err(VirtioBlockError::Config)
@@ -243,9 +734,17 @@ impl From<VirtioBlockConfig> for BlockDeviceConfig {
             cache_type: value.cache_type,
 
             is_read_only: Some(value.is_read_only),
-            path_on_host: Some(value.path_on_host),
+            path_on_host: value.nbd.is_none().then_some(value.path_on_host),
             rate_limiter: value.rate_limiter,
             file_engine_type: Some(value.file_engine_type),
+            overlay: value.overlay,
+            num_queues: Some(value.num_queues),
+            async_io: Some(value.async_io),
+            host_cache_mode: Some(value.host_cache_mode),
+            rate_limiter_group: value.rate_limiter_group,
+            verity: value.verity,
+            nbd: value.nbd,
+            encryption: value.encryption,
 
             socket: None,
         }
@@ -263,7 +762,7 @@ pub struct VirtioBlock {
 
     // Transport related fields.
     pub queues: Vec<Queue>,
-    pub queue_evts: [EventFd; 1],
+    pub queue_evts: Vec<EventFd>,
     pub device_state: DeviceState,
     pub irq_trigger: IrqTrigger,
 
@@ -285,7 +784,7 @@ macro_rules! unwrap_async_file_engine_or_return {
     ($file_engine: expr) => {
         match $file_engine {
             FileEngine::Async(engine) => engine,
-            FileEngine::Sync(_) => {
+            _ => {
                 error!("The block device doesn't use an async IO engine");
                 return;
             }
@@ -298,11 +797,45 @@ impl VirtioBlock {
     ///
     /// The given file must be seekable and sizable.
     pub fn new(config: VirtioBlockConfig) -> Result<VirtioBlock, VirtioBlockError> {
-        let disk_properties = DiskProperties::new(
-            config.path_on_host,
-            config.is_read_only,
-            config.file_engine_type,
-        )?;
+        if !(1..=BLOCK_MAX_NUM_QUEUES).contains(&config.num_queues) {
+            return Err(VirtioBlockError::InvalidNumQueues(config.num_queues));
+        }
+        if config.async_io != AsyncIoConfig::default()
+            && config.file_engine_type != FileEngineType::Async
+        {
+            return Err(VirtioBlockError::AsyncIoConfig);
+        }
+        // Encrypted data is accessed with no regard for alignment.
+        if config.encryption.is_some() && config.host_cache_mode == HostCacheMode::Direct {
+            return Err(VirtioBlockError::EncryptionUnsupported);
+        }
+
+        let mut disk_properties = match config.nbd {
+            Some(nbd) => {
+                if config.overlay.is_some()
+                    || config.verity.is_some()
+                    || config.host_cache_mode == HostCacheMode::Direct
+                {
+                    return Err(VirtioBlockError::NbdUnsupported);
+                }
+                DiskProperties::new_nbd(nbd, config.is_read_only, config.file_engine_type)?
+            }
+            None => DiskProperties::new(
+                config.path_on_host,
+                config.is_read_only,
+                config.file_engine_type,
+                config.overlay,
+                config.verity,
+                config.async_io,
+                config.host_cache_mode,
+            )?,
+        };
+        if let Some(encryption) = &config.encryption {
+            let cipher = block_io::XtsCipher::from_config(encryption)
+                .map_err(VirtioBlockError::Encryption)?;
+            disk_properties.set_cipher(cipher)?;
+        }
+        disk_properties.set_num_queues(config.num_queues, config.is_read_only)?;
 
         let rate_limiter = config
             .rate_limiter
@@ -319,11 +852,32 @@ impl VirtioBlock {
 
         if config.is_read_only {
             avail_features |= 1u64 << VIRTIO_BLK_F_RO;
+        } else {
+            avail_features |= 1u64 << VIRTIO_BLK_F_DISCARD;
+            // Sectors deallocated from the backing file of encrypted drives don't read as zeros.
+            if disk_properties.cipher.is_none() {
+                avail_features |= 1u64 << VIRTIO_BLK_F_WRITE_ZEROES;
+            }
         };
 
-        let queue_evts = [EventFd::new(libc::EFD_NONBLOCK).map_err(VirtioBlockError::EventFd)?];
+        if config.num_queues > 1 {
+            avail_features |= 1u64 << VIRTIO_BLK_F_MQ;
+        }
+
+        // Drivers only send requests aligned to the logical blocks of the storage used for
+        // direct IO when they are larger than sectors.
+        if disk_properties.logical_block_size != SECTOR_SIZE {
+            avail_features |= 1u64 << VIRTIO_BLK_F_BLK_SIZE;
+        }
+
+        let queue_evts = (0..config.num_queues)
+            .map(|_| EventFd::new(libc::EFD_NONBLOCK))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(VirtioBlockError::EventFd)?;
 
-        let queues = BLOCK_QUEUE_SIZES.iter().map(|&s| Queue::new(s)).collect();
+        let queues = (0..config.num_queues)
+            .map(|_| Queue::new(BLOCK_QUEUE_SIZE))
+            .collect();
 
         Ok(VirtioBlock {
             avail_features,
@@ -361,16 +915,32 @@ impl VirtioBlock {
             cache_type: self.cache_type,
             rate_limiter: rl.into_option(),
             file_engine_type: self.file_engine_type(),
+            overlay: self.disk.overlay.clone(),
+            num_queues: self.disk.num_queues,
+            async_io: self.disk.async_io,
+            host_cache_mode: self.disk.host_cache_mode,
+            rate_limiter_group: self
+                .rate_limiter
+                .group()
+                .map(|group| group.id().to_string()),
+            verity: self.disk.verity.clone(),
+            nbd: self.disk.nbd.clone(),
+            // The key never leaves Firecracker.
+            encryption: self
+                .disk
+                .cipher
+                .as_ref()
+                .map(|_| DriveEncryptionConfig::default()),
         }
     }
 
-    /// Process a single event in the Virtio queue.
+    /// Process a single event in a Virtio queue.
     ///
     /// This function is called by the event manager when the guest notifies us
     /// about new buffers in the queue.
-    pub(crate) fn process_queue_event(&mut self) {
+    pub(crate) fn process_queue_event(&mut self, queue_index: usize) {
         self.metrics.queue_event_count.inc();
-        if let Err(err) = self.queue_evts[0].read() {
+        if let Err(err) = self.queue_evts[queue_index].read() {
             error!("Failed to get queue event: {:?}", err);
             self.metrics.event_fails.inc();
         } else if self.rate_limiter.is_blocked() {
@@ -378,21 +948,23 @@ impl VirtioBlock {
         } else if self.is_io_engine_throttled {
             self.metrics.io_engine_throttled_events.inc();
         } else {
-            self.process_virtio_queues();
+            self.process_queue(queue_index);
         }
     }
 
     /// Process device virtio queue(s).
     pub fn process_virtio_queues(&mut self) {
-        self.process_queue(0);
+        for queue_index in 0..self.queues.len() {
+            self.process_queue(queue_index);
+        }
     }
 
     pub(crate) fn process_rate_limiter_event(&mut self) {
         self.metrics.rate_limiter_event_count.inc();
         // Upon rate limiter event, call the rate limiter handler
-        // and restart processing the queue.
+        // and restart processing the queues.
         if self.rate_limiter.event_handler().is_ok() {
-            self.process_queue(0);
+            self.process_virtio_queues();
         }
     }
 
@@ -436,7 +1008,7 @@ impl VirtioBlock {
                     }
 
                     used_any = true;
-                    request.process(&mut self.disk, head.index, mem, &self.metrics)
+                    request.process(&mut self.disk, queue_index, head.index, mem, &self.metrics)
                 }
                 Err(err) => {
                     error!("Failed to parse available descriptor chain: {:?}", err);
@@ -468,7 +1040,7 @@ impl VirtioBlock {
             }
         }
 
-        if let FileEngine::Async(ref mut engine) = self.disk.file_engine {
+        if let FileEngine::Async(engine) = self.disk.file_engine_mut(queue_index) {
             if let Err(err) = engine.kick_submission_queue() {
                 error!("BlockError submitting pending block requests: {:?}", err);
             }
@@ -479,12 +1051,12 @@ impl VirtioBlock {
         }
     }
 
-    fn process_async_completion_queue(&mut self) {
-        let engine = unwrap_async_file_engine_or_return!(&mut self.disk.file_engine);
+    fn process_async_completion_queue(&mut self, queue_index: usize) {
+        let engine = unwrap_async_file_engine_or_return!(self.disk.file_engine_mut(queue_index));
 
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
-        let queue = &mut self.queues[0];
+        let queue = &mut self.queues[queue_index];
 
         loop {
             match engine.pop(mem) {
@@ -521,17 +1093,17 @@ impl VirtioBlock {
         }
     }
 
-    pub fn process_async_completion_event(&mut self) {
-        let engine = unwrap_async_file_engine_or_return!(&mut self.disk.file_engine);
+    pub fn process_async_completion_event(&mut self, queue_index: usize) {
+        let engine = unwrap_async_file_engine_or_return!(self.disk.file_engine_mut(queue_index));
 
         if let Err(err) = engine.completion_evt().read() {
             error!("Failed to get async completion event: {:?}", err);
         } else {
-            self.process_async_completion_queue();
+            self.process_async_completion_queue(queue_index);
 
             if self.is_io_engine_throttled {
                 self.is_io_engine_throttled = false;
-                self.process_queue(0);
+                self.process_virtio_queues();
             }
         }
     }
@@ -553,17 +1125,28 @@ impl VirtioBlock {
         self.rate_limiter.update_buckets(bytes, ops);
     }
 
+    /// Makes the rate limiter of the device a member of `group`.
+    pub fn join_rate_limiter_group(&mut self, group: &RateLimiterGroup) {
+        self.rate_limiter.join_group(group);
+    }
+
     /// Retrieve the file engine type.
     pub fn file_engine_type(&self) -> FileEngineType {
         match self.disk.file_engine {
             FileEngine::Sync(_) => FileEngineType::Sync,
             FileEngine::Async(_) => FileEngineType::Async,
+            FileEngine::Qcow2(_) => FileEngineType::Qcow2,
+            FileEngine::Overlay(_) | FileEngine::Verity(_) | FileEngine::Nbd(_) => {
+                FileEngineType::Sync
+            }
         }
     }
 
     fn drain_and_flush(&mut self, discard: bool) {
-        if let Err(err) = self.disk.file_engine.drain_and_flush(discard) {
-            error!("Failed to drain ops and flush block data: {:?}", err);
+        for file_engine in self.disk.file_engines_mut() {
+            if let Err(err) = file_engine.drain_and_flush(discard) {
+                error!("Failed to drain ops and flush block data: {:?}", err);
+            }
         }
     }
 
@@ -575,7 +1158,9 @@ impl VirtioBlock {
 
         self.drain_and_flush(false);
         if let FileEngine::Async(ref _engine) = self.disk.file_engine {
-            self.process_async_completion_queue();
+            for queue_index in 0..self.queues.len() {
+                self.process_async_completion_queue(queue_index);
+            }
         }
     }
 }
@@ -674,8 +1259,10 @@ impl Drop for VirtioBlock {
     fn drop(&mut self) {
         match self.cache_type {
             CacheType::Unsafe => {
-                if let Err(err) = self.disk.file_engine.drain(true) {
-                    error!("Failed to drain ops on drop: {:?}", err);
+                for file_engine in self.disk.file_engines_mut() {
+                    if let Err(err) = file_engine.drain(true) {
+                        error!("Failed to drain ops on drop: {:?}", err);
+                    }
                 }
             }
             CacheType::Writeback => {
@@ -690,14 +1277,20 @@ mod tests {
     use std::fs::metadata;
     use std::io::Read;
     use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::FileExt;
     use std::time::Duration;
     use std::{thread, u32};
 
+    use base64::Engine;
     use utils::skip_if_io_uring_unsupported;
     use utils::tempfile::TempFile;
 
     use super::*;
     use crate::check_metric_after_block;
+    use crate::devices::virtio::block::virtio::io::nbd::tests::{
+        TestServer, NBD_TEST_EXPORT_FLAGS,
+    };
+    use crate::devices::virtio::block::virtio::io::verity::tests::create_hash_tree;
     use crate::devices::virtio::block::virtio::test_utils::{
         default_block, default_engine_type_for_kv, read_blk_req_descriptors, set_queue,
         set_rate_limiter, simulate_async_completion_event,
@@ -716,123 +1309,722 @@ mod tests {
             partuuid: None,
             is_root_device: false,
             cache_type: CacheType::Unsafe,
-
-            is_read_only: Some(true),
-            path_on_host: Some("path".to_string()),
+
+            is_read_only: Some(true),
+            path_on_host: Some("path".to_string()),
+            rate_limiter: None,
+            file_engine_type: Default::default(),
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
+
+            socket: None,
+        };
+        VirtioBlockConfig::try_from(&block_config).unwrap();
+
+        let block_config = BlockDeviceConfig {
+            drive_id: "".to_string(),
+            partuuid: None,
+            is_root_device: false,
+            cache_type: CacheType::Unsafe,
+
+            is_read_only: None,
+            path_on_host: None,
+            rate_limiter: None,
+            file_engine_type: Default::default(),
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
+
+            socket: Some("sock".to_string()),
+        };
+        VirtioBlockConfig::try_from(&block_config).unwrap_err();
+
+        let block_config = BlockDeviceConfig {
+            drive_id: "".to_string(),
+            partuuid: None,
+            is_root_device: false,
+            cache_type: CacheType::Unsafe,
+
+            is_read_only: Some(true),
+            path_on_host: Some("path".to_string()),
+            rate_limiter: None,
+            file_engine_type: Default::default(),
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
+
+            socket: Some("sock".to_string()),
+        };
+        VirtioBlockConfig::try_from(&block_config).unwrap_err();
+    }
+
+    #[test]
+    fn test_disk_backing_file_helper() {
+        let num_sectors = 2;
+        let f = TempFile::new().unwrap();
+        let size = u64::from(SECTOR_SIZE) * num_sectors;
+        f.as_file().set_len(size).unwrap();
+
+        let disk_properties = DiskProperties::new(
+            String::from(f.as_path().to_str().unwrap()),
+            true,
+            default_engine_type_for_kv(),
+            None,
+            None,
+            AsyncIoConfig::default(),
+            HostCacheMode::Buffered,
+        )
+        .unwrap();
+
+        assert_eq!(size, u64::from(SECTOR_SIZE) * num_sectors);
+        assert_eq!(disk_properties.nsectors, num_sectors);
+        let cfg = disk_properties.virtio_block_config_space();
+        assert_eq!(cfg.len(), BLOCK_CONFIG_SPACE_SIZE);
+        assert_eq!(cfg[..8], num_sectors.to_le_bytes());
+        assert_eq!(
+            cfg[CONFIG_MAX_DISCARD_SECTORS_OFFSET..][..4],
+            u32::MAX.to_le_bytes()
+        );
+        assert_eq!(
+            cfg[CONFIG_MAX_DISCARD_SEG_OFFSET..][..4],
+            1u32.to_le_bytes()
+        );
+        assert_eq!(
+            cfg[CONFIG_MAX_WRITE_ZEROES_SEG_OFFSET..][..4],
+            1u32.to_le_bytes()
+        );
+        assert_eq!(cfg[CONFIG_WRITE_ZEROES_MAY_UNMAP_OFFSET], 1);
+        assert_eq!(
+            cfg[CONFIG_BLK_SIZE_OFFSET..][..4],
+            SECTOR_SIZE.to_le_bytes()
+        );
+        // Testing `backing_file.virtio_block_disk_image_id()` implies
+        // duplicating that logic in tests, so skipping it.
+
+        let res = DiskProperties::new(
+            "invalid-disk-path".to_string(),
+            true,
+            default_engine_type_for_kv(),
+            None,
+            None,
+            AsyncIoConfig::default(),
+            HostCacheMode::Buffered,
+        );
+        assert!(
+            matches!(res, Err(VirtioBlockError::BackingFile(_, _))),
+            "{:?}",
+            res
+        );
+    }
+
+    #[test]
+    fn test_overlay_disk_properties() {
+        let base = TempFile::new().unwrap();
+        base.as_file().write_all(&[1u8; 0x2000]).unwrap();
+        let overlay_file = TempFile::new().unwrap();
+        let bitmap_file = TempFile::new().unwrap();
+        let overlay = OverlayConfig {
+            path_on_host: overlay_file.as_path().to_str().unwrap().to_string(),
+            bitmap_path_on_host: bitmap_file.as_path().to_str().unwrap().to_string(),
+        };
+
+        // Overlays are only supported by the Sync engine.
+        let res = DiskProperties::new(
+            base.as_path().to_str().unwrap().to_string(),
+            false,
+            FileEngineType::Qcow2,
+            Some(overlay.clone()),
+            None,
+            AsyncIoConfig::default(),
+            HostCacheMode::Buffered,
+        );
+        assert!(
+            matches!(
+                res,
+                Err(VirtioBlockError::FileEngine(
+                    block_io::BlockIoError::UnsupportedEngine(FileEngineType::Qcow2)
+                ))
+            ),
+            "{:?}",
+            res
+        );
+
+        let disk_properties = DiskProperties::new(
+            base.as_path().to_str().unwrap().to_string(),
+            false,
+            FileEngineType::Sync,
+            Some(overlay.clone()),
+            None,
+            AsyncIoConfig::default(),
+            HostCacheMode::Buffered,
+        )
+        .unwrap();
+        assert!(matches!(
+            disk_properties.file_engine,
+            FileEngine::Overlay(_)
+        ));
+        assert_eq!(disk_properties.nsectors, 0x2000 >> SECTOR_SHIFT);
+        assert_eq!(disk_properties.overlay, Some(overlay));
+        // The overlay is sized like the base image.
+        assert_eq!(overlay_file.as_file().metadata().unwrap().len(), 0x2000);
+    }
+
+    #[test]
+    fn test_virtio_features() {
+        let mut block = default_block(default_engine_type_for_kv());
+
+        assert_eq!(block.device_type(), TYPE_BLOCK);
+
+        let features: u64 = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+            | (1u64 << VIRTIO_BLK_F_DISCARD)
+            | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
+
+        assert_eq!(
+            block.avail_features_by_page(0),
+            (features & 0xffffffff) as u32,
+        );
+        assert_eq!(block.avail_features_by_page(1), (features >> 32) as u32);
+
+        for i in 2..10 {
+            assert_eq!(block.avail_features_by_page(i), 0u32);
+        }
+
+        for i in 0..10 {
+            block.ack_features_by_page(i, u32::MAX);
+        }
+        assert_eq!(block.acked_features, features);
+    }
+
+    #[test]
+    fn test_async_io_config() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let config = |file_engine_type| VirtioBlockConfig {
+            drive_id: "test".to_string(),
+            partuuid: None,
+            is_root_device: false,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            path_on_host: f.as_path().to_str().unwrap().to_string(),
+            rate_limiter: None,
+            file_engine_type,
+            overlay: None,
+            num_queues: 2,
+            async_io: AsyncIoConfig {
+                fixed_buffers: true,
+                sq_poll: false,
+                sq_poll_idle_ms: 0,
+            },
+            host_cache_mode: HostCacheMode::Buffered,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
+        };
+
+        // The options only apply to the Async engine.
+        let res = VirtioBlock::new(config(FileEngineType::Sync));
+        assert!(
+            matches!(res, Err(VirtioBlockError::AsyncIoConfig)),
+            "{:?}",
+            res
+        );
+
+        if default_engine_type_for_kv() == FileEngineType::Async {
+            let block = VirtioBlock::new(config(FileEngineType::Async)).unwrap();
+            assert!(block.config().async_io.fixed_buffers);
+            assert_eq!(block.disk.async_io, block.config().async_io);
+        }
+    }
+
+    #[test]
+    fn test_direct_io() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let config = |file_engine_type, overlay| VirtioBlockConfig {
+            drive_id: "test".to_string(),
+            partuuid: None,
+            is_root_device: false,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            path_on_host: f.as_path().to_str().unwrap().to_string(),
+            rate_limiter: None,
+            file_engine_type,
+            overlay,
+            num_queues: 1,
+            async_io: AsyncIoConfig::default(),
+            host_cache_mode: HostCacheMode::Direct,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
+        };
+
+        // Disk image formats and overlays don't support direct IO.
+        let res = VirtioBlock::new(config(FileEngineType::Qcow2, None));
+        assert!(
+            matches!(res, Err(VirtioBlockError::DirectIoUnsupported)),
+            "{:?}",
+            res
+        );
+        let overlay = OverlayConfig {
+            path_on_host: "overlay".to_string(),
+            bitmap_path_on_host: "overlay.bitmap".to_string(),
+        };
+        let res = VirtioBlock::new(config(FileEngineType::Sync, Some(overlay)));
+        assert!(
+            matches!(res, Err(VirtioBlockError::DirectIoUnsupported)),
+            "{:?}",
+            res
+        );
+
+        // Skip the rest of the test if the file system of the file doesn't support direct IO.
+        let mut block = match VirtioBlock::new(config(default_engine_type_for_kv(), None)) {
+            Ok(block) => block,
+            Err(VirtioBlockError::BackingFile(err, _))
+                if err.raw_os_error() == Some(libc::EINVAL) =>
+            {
+                return;
+            }
+            Err(err) => panic!("{:?}", err),
+        };
+        assert_eq!(block.config().host_cache_mode, HostCacheMode::Direct);
+        // The rest of the test transfers sectors.
+        if block.disk.logical_block_size != SECTOR_SIZE {
+            assert_ne!(block.avail_features() & (1u64 << VIRTIO_BLK_F_BLK_SIZE), 0);
+            return;
+        }
+        assert_eq!(block.avail_features() & (1u64 << VIRTIO_BLK_F_BLK_SIZE), 0);
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        read_blk_req_descriptors(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        let rand_data = utils::rand::rand_alphanumerics(1024).as_bytes().to_vec();
+
+        // Write from an aligned buffer, which goes through the IO engine.
+        {
+            mem.write_obj::<u32>(VIRTIO_BLK_T_OUT, request_type_addr)
+                .unwrap();
+            vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+            vq.dtable[1].len.set(1024);
+            mem.write_slice(&rand_data, data_addr).unwrap();
+
+            check_metric_after_block!(
+                &block.metrics.bounced_reqs_count,
+                0,
+                simulate_queue_and_async_completion_events(&mut block, true)
+            );
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        }
+
+        // Read into an unaligned buffer, which goes through a bounce buffer.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            let unaligned_addr = data_addr.unchecked_add(1);
+
+            mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_type_addr)
+                .unwrap();
+            vq.dtable[1].set(
+                unaligned_addr.0,
+                1024,
+                VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+                2,
+            );
+
+            check_metric_after_block!(
+                &block.metrics.bounced_reqs_count,
+                1,
+                simulate_queue_and_async_completion_events(&mut block, true)
+            );
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, 1025);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+            let mut buf = vec![0u8; 1024];
+            mem.read_slice(&mut buf, unaligned_addr).unwrap();
+            assert_eq!(buf, rand_data);
+        }
+
+        // Write from an unaligned buffer, which goes through a bounce buffer.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            let unaligned_addr = data_addr.unchecked_add(3);
+
+            mem.write_obj::<u32>(VIRTIO_BLK_T_OUT, request_type_addr)
+                .unwrap();
+            vq.dtable[1].set(unaligned_addr.0, 1024, VIRTQ_DESC_F_NEXT, 2);
+            let new_data = utils::rand::rand_alphanumerics(1024).as_bytes().to_vec();
+            mem.write_slice(&new_data, unaligned_addr).unwrap();
+
+            check_metric_after_block!(
+                &block.metrics.bounced_reqs_count,
+                1,
+                simulate_queue_and_async_completion_events(&mut block, true)
+            );
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+            let mut buf = vec![0u8; 1024];
+            f.as_file().read_exact_at(&mut buf, 0).unwrap();
+            assert_eq!(buf, new_data);
+        }
+
+        // The driver keeps the logical block size it was told about, which must be a multiple of
+        // the one of the backing file.
+        assert!(matches!(
+            block.disk.keep_logical_block_size(SECTOR_SIZE / 2),
+            Err(VirtioBlockError::LogicalBlockSizeMismatch(SECTOR_SIZE, _))
+        ));
+        block.disk.keep_logical_block_size(2 * SECTOR_SIZE).unwrap();
+
+        // Requests which are not aligned to the logical blocks fail without reaching the file.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+
+            mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_type_addr)
+                .unwrap();
+            vq.dtable[1].set(data_addr.0, 512, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 2);
+
+            check_metric_after_block!(
+                &block.metrics.bounced_reqs_count,
+                0,
+                simulate_queue_and_async_completion_events(&mut block, true)
+            );
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(
+                mem.read_obj::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+    }
+
+    #[test]
+    fn test_verity() {
+        let f = TempFile::new().unwrap();
+        let data = utils::rand::rand_alphanumerics(0x3000).as_bytes().to_vec();
+        f.as_file().write_all(&data).unwrap();
+        let hash_tree = TempFile::new().unwrap();
+        let verity = VerityConfig {
+            hash_tree_path_on_host: hash_tree.as_path().to_str().unwrap().to_string(),
+            root_hash: create_hash_tree(&data, &[], hash_tree.as_file()),
+            salt: None,
+        };
+        let config = |is_read_only| VirtioBlockConfig {
+            drive_id: "test".to_string(),
+            partuuid: None,
+            is_root_device: false,
+            cache_type: CacheType::Unsafe,
+            is_read_only,
+            path_on_host: f.as_path().to_str().unwrap().to_string(),
             rate_limiter: None,
-            file_engine_type: Default::default(),
-
-            socket: None,
+            file_engine_type: FileEngineType::Sync,
+            overlay: None,
+            num_queues: 1,
+            async_io: AsyncIoConfig::default(),
+            host_cache_mode: HostCacheMode::Buffered,
+            rate_limiter_group: None,
+            verity: Some(verity.clone()),
+            nbd: None,
+            encryption: None,
         };
-        VirtioBlockConfig::try_from(&block_config).unwrap();
 
-        let block_config = BlockDeviceConfig {
-            drive_id: "".to_string(),
-            partuuid: None,
-            is_root_device: false,
-            cache_type: CacheType::Unsafe,
+        // Only read-only drives can be verified.
+        let res = VirtioBlock::new(config(false));
+        assert!(
+            matches!(res, Err(VirtioBlockError::VerityUnsupported)),
+            "{:?}",
+            res
+        );
 
-            is_read_only: None,
-            path_on_host: None,
-            rate_limiter: None,
-            file_engine_type: Default::default(),
+        let mut block = VirtioBlock::new(config(true)).unwrap();
+        assert_eq!(block.config().verity, Some(verity));
+        assert_eq!(block.file_engine_type(), FileEngineType::Sync);
 
-            socket: Some("sock".to_string()),
-        };
-        VirtioBlockConfig::try_from(&block_config).unwrap_err();
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        read_blk_req_descriptors(&vq);
 
-        let block_config = BlockDeviceConfig {
-            drive_id: "".to_string(),
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_type_addr)
+            .unwrap();
+
+        // Reads of untouched data succeed.
+        check_metric_after_block!(
+            &block.metrics.verity_fails,
+            0,
+            simulate_queue_event(&mut block, Some(true))
+        );
+        assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        let mut buf = vec![0u8; 0x1000];
+        mem.read_slice(&mut buf, data_addr).unwrap();
+        assert_eq!(buf, data[..0x1000]);
+
+        // Reads of tampered data fail.
+        f.as_file().write_all_at(b"x", 10).unwrap();
+        vq.used.idx.set(0);
+        set_queue(&mut block, 0, vq.create_queue());
+        check_metric_after_block!(
+            &block.metrics.verity_fails,
+            1,
+            simulate_queue_event(&mut block, Some(true))
+        );
+        assert_eq!(
+            mem.read_obj::<u32>(status_addr).unwrap(),
+            VIRTIO_BLK_S_IOERR
+        );
+    }
+
+    #[test]
+    fn test_nbd() {
+        let data = utils::rand::rand_alphanumerics(0x2000).as_bytes().to_vec();
+        let mut server = TestServer::new(data.clone(), NBD_TEST_EXPORT_FLAGS, 1, None);
+        let config = |overlay| VirtioBlockConfig {
+            drive_id: "test".to_string(),
             partuuid: None,
             is_root_device: false,
             cache_type: CacheType::Unsafe,
-
-            is_read_only: Some(true),
-            path_on_host: Some("path".to_string()),
+            is_read_only: false,
+            path_on_host: String::new(),
             rate_limiter: None,
-            file_engine_type: Default::default(),
+            file_engine_type: FileEngineType::Sync,
+            overlay,
+            num_queues: 1,
+            async_io: AsyncIoConfig::default(),
+            host_cache_mode: HostCacheMode::Buffered,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: Some(server.config()),
+            encryption: None,
+        };
 
-            socket: Some("sock".to_string()),
+        // NBD drives have no base image to put an overlay on.
+        let overlay = OverlayConfig {
+            path_on_host: "overlay".to_string(),
+            bitmap_path_on_host: "overlay.bitmap".to_string(),
         };
-        VirtioBlockConfig::try_from(&block_config).unwrap_err();
+        let res = VirtioBlock::new(config(Some(overlay)));
+        assert!(
+            matches!(res, Err(VirtioBlockError::NbdUnsupported)),
+            "{:?}",
+            res
+        );
+
+        let mut block = VirtioBlock::new(config(None)).unwrap();
+        assert_eq!(block.disk.nsectors, 0x2000 >> SECTOR_SHIFT);
+        assert_eq!(block.config().nbd, Some(server.config()));
+        assert_eq!(BlockDeviceConfig::from(block.config()).path_on_host, None);
+        assert_eq!(block.file_engine_type(), FileEngineType::Sync);
+        assert!(matches!(
+            block.update_disk_image("dummy".to_string()),
+            Err(VirtioBlockError::NbdUnsupported)
+        ));
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        read_blk_req_descriptors(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_type_addr)
+            .unwrap();
+
+        simulate_queue_event(&mut block, Some(true));
+        assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        let mut buf = vec![0u8; 0x1000];
+        mem.read_slice(&mut buf, data_addr).unwrap();
+        assert_eq!(buf, data[..0x1000]);
+
+        drop(block);
+        server.join();
     }
 
     #[test]
-    fn test_disk_backing_file_helper() {
-        let num_sectors = 2;
+    fn test_encryption() {
         let f = TempFile::new().unwrap();
-        let size = u64::from(SECTOR_SIZE) * num_sectors;
-        f.as_file().set_len(size).unwrap();
-
-        let disk_properties = DiskProperties::new(
-            String::from(f.as_path().to_str().unwrap()),
-            true,
-            default_engine_type_for_kv(),
-        )
-        .unwrap();
-
-        assert_eq!(size, u64::from(SECTOR_SIZE) * num_sectors);
-        assert_eq!(disk_properties.nsectors, num_sectors);
-        let cfg = disk_properties.virtio_block_config_space();
-        assert_eq!(cfg.len(), BLOCK_CONFIG_SPACE_SIZE);
-        for (i, byte) in cfg.iter().enumerate() {
-            assert_eq!(*byte, ((num_sectors >> (8 * i)) & 0xff) as u8);
-        }
-        // Testing `backing_file.virtio_block_disk_image_id()` implies
-        // duplicating that logic in tests, so skipping it.
+        f.as_file().set_len(0x1000).unwrap();
+        let config = |host_cache_mode, key: &str| VirtioBlockConfig {
+            drive_id: "test".to_string(),
+            partuuid: None,
+            is_root_device: false,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            path_on_host: f.as_path().to_str().unwrap().to_string(),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::Sync,
+            overlay: None,
+            num_queues: 1,
+            async_io: AsyncIoConfig::default(),
+            host_cache_mode,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: Some(DriveEncryptionConfig {
+                key: Some(Zeroizing::new(key.to_string())),
+                key_fd: None,
+            }),
+        };
+        let key = base64::engine::general_purpose::STANDARD.encode((0..64).collect::<Vec<u8>>());
 
-        let res = DiskProperties::new(
-            "invalid-disk-path".to_string(),
-            true,
-            default_engine_type_for_kv(),
+        let res = VirtioBlock::new(config(HostCacheMode::Direct, &key));
+        assert!(
+            matches!(res, Err(VirtioBlockError::EncryptionUnsupported)),
+            "{:?}",
+            res
         );
+        let res = VirtioBlock::new(config(HostCacheMode::Buffered, "invalid"));
         assert!(
-            matches!(res, Err(VirtioBlockError::BackingFile(_, _))),
+            matches!(
+                res,
+                Err(VirtioBlockError::Encryption(block_io::CryptError::Base64(
+                    _
+                )))
+            ),
             "{:?}",
             res
         );
+
+        // Write zeroes requests would punch holes, which don't read as encrypted zeros.
+        let block = VirtioBlock::new(config(HostCacheMode::Buffered, &key)).unwrap();
+        assert_ne!(block.avail_features() & (1u64 << VIRTIO_BLK_F_DISCARD), 0);
+        assert_eq!(
+            block.avail_features() & (1u64 << VIRTIO_BLK_F_WRITE_ZEROES),
+            0
+        );
+
+        // The configuration of the drive doesn't reveal the key.
+        assert_eq!(
+            block.config().encryption,
+            Some(DriveEncryptionConfig::default())
+        );
+        let mut block_config = BlockDeviceConfig::from(config(HostCacheMode::Buffered, &key));
+        assert!(!serde_json::to_string(&block_config).unwrap().contains(&key));
+        block_config.encryption.as_mut().unwrap().key_fd = Some(3);
+        assert!(!format!("{:?}", block_config).contains(&key));
     }
 
     #[test]
-    fn test_virtio_features() {
-        let mut block = default_block(default_engine_type_for_kv());
-
-        assert_eq!(block.device_type(), TYPE_BLOCK);
+    fn test_multi_queue() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let config = |num_queues| VirtioBlockConfig {
+            drive_id: "test".to_string(),
+            partuuid: None,
+            is_root_device: false,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            path_on_host: f.as_path().to_str().unwrap().to_string(),
+            rate_limiter: None,
+            file_engine_type: default_engine_type_for_kv(),
+            overlay: None,
+            num_queues,
+            async_io: AsyncIoConfig::default(),
+            host_cache_mode: HostCacheMode::Buffered,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
+        };
 
-        let features: u64 = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_RING_F_EVENT_IDX);
+        for num_queues in [0, BLOCK_MAX_NUM_QUEUES + 1] {
+            let res = VirtioBlock::new(config(num_queues));
+            assert!(
+                matches!(res, Err(VirtioBlockError::InvalidNumQueues(n)) if n == num_queues),
+                "{:?}",
+                res
+            );
+        }
 
+        let mut block = VirtioBlock::new(config(4)).unwrap();
+        assert_eq!(block.queues.len(), 4);
+        assert_eq!(block.queue_evts.len(), 4);
+        assert_ne!(block.avail_features() & (1u64 << VIRTIO_BLK_F_MQ), 0);
         assert_eq!(
-            block.avail_features_by_page(0),
-            (features & 0xffffffff) as u32,
+            block.config_space[CONFIG_NUM_QUEUES_OFFSET..][..2],
+            4u16.to_le_bytes()
         );
-        assert_eq!(block.avail_features_by_page(1), (features >> 32) as u32);
-
-        for i in 2..10 {
-            assert_eq!(block.avail_features_by_page(i), 0u32);
+        assert_eq!(block.config().num_queues, 4);
+        // Each queue gets its own io_uring instance.
+        if let FileEngine::Async(_) = block.disk.file_engine {
+            assert_eq!(block.disk.queue_file_engines.len(), 3);
+        } else {
+            assert!(block.disk.queue_file_engines.is_empty());
         }
 
-        for i in 0..10 {
-            block.ack_features_by_page(i, u32::MAX);
+        // Requests are processed on the queue they were notified for.
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 2, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        read_blk_req_descriptors(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        vq.dtable[0].next.set(2);
+        mem.write_obj::<u32>(VIRTIO_BLK_T_FLUSH, request_type_addr)
+            .unwrap();
+
+        block.queue_evts[2].write(1).unwrap();
+        block.process_queue_event(2);
+        if let FileEngine::Async(engine) = block.disk.file_engine_mut(2) {
+            engine.drain(false).unwrap();
+            thread::sleep(Duration::from_millis(150));
+            block.process_async_completion_event(2);
         }
-        assert_eq!(block.acked_features, features);
+
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(vq.used.ring[0].get().id, 0);
+        assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        assert!(block.irq_trigger.has_pending_irq(IrqType::Vring));
     }
 
     #[test]
     fn test_virtio_read_config() {
         let block = default_block(default_engine_type_for_kv());
 
-        let mut actual_config_space = [0u8; BLOCK_CONFIG_SPACE_SIZE];
+        let mut actual_config_space = [0u8; 8];
         block.read_config(0, &mut actual_config_space);
         // This will read the number of sectors.
         // The block's backing file size is 0x1000, so there are 8 (4096/512) sectors.
         // The config space is little endian.
-        let expected_config_space: [u8; BLOCK_CONFIG_SPACE_SIZE] =
-            [0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let expected_config_space: [u8; 8] = [0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
         assert_eq!(actual_config_space, expected_config_space);
 
         // Invalid read.
-        let expected_config_space: [u8; BLOCK_CONFIG_SPACE_SIZE] =
-            [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
+        let expected_config_space: [u8; 8] = [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
         actual_config_space = expected_config_space;
         block.read_config(BLOCK_CONFIG_SPACE_SIZE as u64 + 1, &mut actual_config_space);
 
@@ -844,11 +2036,10 @@ mod tests {
     fn test_virtio_write_config() {
         let mut block = default_block(default_engine_type_for_kv());
 
-        let expected_config_space: [u8; BLOCK_CONFIG_SPACE_SIZE] =
-            [0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let expected_config_space: [u8; 8] = [0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
         block.write_config(0, &expected_config_space);
 
-        let mut actual_config_space = [0u8; BLOCK_CONFIG_SPACE_SIZE];
+        let mut actual_config_space = [0u8; 8];
         block.read_config(0, &mut actual_config_space);
         assert_eq!(actual_config_space, expected_config_space);
 
@@ -862,7 +2053,7 @@ mod tests {
 
         // Invalid write.
         let new_config_space = [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
-        block.write_config(5, &new_config_space);
+        block.write_config(BLOCK_CONFIG_SPACE_SIZE as u64 - 3, &new_config_space);
         // Make sure nothing got written.
         block.read_config(0, &mut actual_config_space);
         assert_eq!(actual_config_space, expected_config_space);
@@ -1022,8 +2213,8 @@ mod tests {
         let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
         let status_addr = GuestAddress(vq.dtable[2].addr.get());
 
-        // Currently only VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
-        // VIRTIO_BLK_T_FLUSH and VIRTIO_BLK_T_GET_ID  are supported.
+        // Currently only VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT, VIRTIO_BLK_T_FLUSH,
+        // VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_DISCARD and VIRTIO_BLK_T_WRITE_ZEROES are supported.
         // Generate an unsupported request.
         let request_header = RequestHeader::new(42, 0);
         mem.write_obj::<RequestHeader>(request_header, request_type_addr)
@@ -1110,9 +2301,16 @@ mod tests {
                 .disk
                 .file_engine
                 .file()
+                .unwrap()
                 .seek(SeekFrom::Start(0))
                 .unwrap();
-            block.disk.file_engine.file().read_exact(&mut buf).unwrap();
+            block
+                .disk
+                .file_engine
+                .file()
+                .unwrap()
+                .read_exact(&mut buf)
+                .unwrap();
             assert_eq!(buf, empty_data.as_slice());
         }
 
@@ -1249,9 +2447,16 @@ mod tests {
                 .disk
                 .file_engine
                 .file()
+                .unwrap()
                 .seek(SeekFrom::End(0))
                 .unwrap();
-            block.disk.file_engine.file().set_len(size / 2).unwrap();
+            block
+                .disk
+                .file_engine
+                .file()
+                .unwrap()
+                .set_len(size / 2)
+                .unwrap();
             mem.write_obj(10, GuestAddress(request_type_addr.0 + 8))
                 .unwrap();
 
@@ -1283,9 +2488,16 @@ mod tests {
                 .disk
                 .file_engine
                 .file()
+                .unwrap()
                 .seek(SeekFrom::End(0))
                 .unwrap();
-            block.disk.file_engine.file().set_len(size / 2).unwrap();
+            block
+                .disk
+                .file_engine
+                .file()
+                .unwrap()
+                .set_len(size / 2)
+                .unwrap();
             // Update sector number: stored at `request_type_addr.0 + 8`
             mem.write_obj(5, GuestAddress(request_type_addr.0 + 8))
                 .unwrap();
@@ -1331,12 +2543,14 @@ mod tests {
                 .disk
                 .file_engine
                 .file()
+                .unwrap()
                 .seek(SeekFrom::Start(512))
                 .unwrap();
             block
                 .disk
                 .file_engine
                 .file()
+                .unwrap()
                 .write_all(&rand_data[512..])
                 .unwrap();
 
@@ -1439,6 +2653,112 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_discard_write_zeroes() {
+        let mut block = default_block(default_engine_type_for_kv());
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        read_blk_req_descriptors(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+
+        let rand_data = utils::rand::rand_alphanumerics(0x1000).as_bytes().to_vec();
+        block
+            .disk
+            .file_engine
+            .file()
+            .unwrap()
+            .write_all(&rand_data)
+            .unwrap();
+
+        // Make data read only and one segment long.
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        vq.dtable[1].len.set(DISCARD_WRITE_ZEROES_SEGMENT_SIZE);
+
+        // Discard the first two sectors.
+        {
+            mem.write_obj::<u32>(VIRTIO_BLK_T_DISCARD, request_type_addr)
+                .unwrap();
+            mem.write_obj(DiscardWriteZeroesSegment::new(0, 2, 0), data_addr)
+                .unwrap();
+
+            check_metric_after_block!(
+                &block.metrics.discard_count,
+                1,
+                simulate_queue_and_async_completion_events(&mut block, true)
+            );
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        }
+
+        // Zero the last sector, allowing it to be deallocated.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            mem.write_obj::<u32>(VIRTIO_BLK_T_WRITE_ZEROES, request_type_addr)
+                .unwrap();
+            mem.write_obj(
+                DiscardWriteZeroesSegment::new(7, 1, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP),
+                data_addr,
+            )
+            .unwrap();
+
+            check_metric_after_block!(
+                &block.metrics.write_zeroes_count,
+                1,
+                simulate_queue_and_async_completion_events(&mut block, true)
+            );
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        }
+
+        // Check the file contents.
+        let mut expected = rand_data;
+        expected[..2 * SECTOR_SIZE as usize].fill(0);
+        expected[7 * SECTOR_SIZE as usize..].fill(0);
+        let mut buf = vec![0u8; 0x1000];
+        block
+            .disk
+            .file_engine
+            .file()
+            .unwrap()
+            .seek(SeekFrom::Start(0))
+            .unwrap();
+        block
+            .disk
+            .file_engine
+            .file()
+            .unwrap()
+            .read_exact(&mut buf)
+            .unwrap();
+        assert_eq!(buf, expected);
+
+        // Segments beyond the end of the disk are rejected.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            mem.write_obj::<u32>(VIRTIO_BLK_T_DISCARD, request_type_addr)
+                .unwrap();
+            mem.write_obj(DiscardWriteZeroesSegment::new(7, 2, 0), data_addr)
+                .unwrap();
+
+            check_metric_after_block!(
+                &block.metrics.execute_fails,
+                1,
+                simulate_queue_and_async_completion_events(&mut block, true)
+            );
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, 0);
+        }
+    }
+
     #[test]
     fn test_get_device_id() {
         let mut block = default_block(default_engine_type_for_kv());
@@ -1451,7 +2771,7 @@ mod tests {
         let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
         let data_addr = GuestAddress(vq.dtable[1].addr.get());
         let status_addr = GuestAddress(vq.dtable[2].addr.get());
-        let blk_metadata = block.disk.file_engine.file().metadata();
+        let blk_metadata = block.disk.file_engine.file().unwrap().metadata();
 
         // Test that the driver receives the correct device id.
         {
@@ -1806,7 +3126,14 @@ mod tests {
             .unwrap();
 
         assert_eq!(
-            block.disk.file_engine.file().metadata().unwrap().st_ino(),
+            block
+                .disk
+                .file_engine
+                .file()
+                .unwrap()
+                .metadata()
+                .unwrap()
+                .st_ino(),
             mdata.st_ino()
         );
         assert_eq!(block.disk.image_id, id.as_slice());
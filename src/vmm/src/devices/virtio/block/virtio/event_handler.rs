@@ -10,17 +10,25 @@ use crate::logger::{error, warn};
 
 impl VirtioBlock {
     const PROCESS_ACTIVATE: u32 = 0;
-    const PROCESS_QUEUE: u32 = 1;
-    const PROCESS_RATE_LIMITER: u32 = 2;
-    const PROCESS_ASYNC_COMPLETION: u32 = 3;
+    const PROCESS_RATE_LIMITER: u32 = 1;
+    // The events of the queue at index `i` are registered with `PROCESS_QUEUE + i` and
+    // `PROCESS_ASYNC_COMPLETION + i`.
+    const PROCESS_QUEUE: u32 = 0x100;
+    const PROCESS_ASYNC_COMPLETION: u32 = 0x200;
+
+    // Returns the index of the queue an event was registered for, with `base` as event data.
+    fn event_queue_index(&self, source: u32, base: u32) -> Option<usize> {
+        source
+            .checked_sub(base)
+            .map(|index| index as usize)
+            .filter(|&index| index < self.queues.len())
+    }
 
     fn register_runtime_events(&self, ops: &mut EventOps) {
-        if let Err(err) = ops.add(Events::with_data(
-            &self.queue_evts[0],
-            Self::PROCESS_QUEUE,
-            EventSet::IN,
-        )) {
-            error!("Failed to register queue event: {}", err);
+        for (data, queue_evt) in (Self::PROCESS_QUEUE..).zip(self.queue_evts.iter()) {
+            if let Err(err) = ops.add(Events::with_data(queue_evt, data, EventSet::IN)) {
+                error!("Failed to register queue event: {}", err);
+            }
         }
         if let Err(err) = ops.add(Events::with_data(
             &self.rate_limiter,
@@ -29,13 +37,16 @@ impl VirtioBlock {
         )) {
             error!("Failed to register ratelimiter event: {}", err);
         }
-        if let FileEngine::Async(ref engine) = self.disk.file_engine {
-            if let Err(err) = ops.add(Events::with_data(
-                engine.completion_evt(),
-                Self::PROCESS_ASYNC_COMPLETION,
-                EventSet::IN,
-            )) {
-                error!("Failed to register IO engine completion event: {}", err);
+        for (data, file_engine) in (Self::PROCESS_ASYNC_COMPLETION..).zip(self.disk.file_engines())
+        {
+            if let FileEngine::Async(engine) = file_engine {
+                if let Err(err) = ops.add(Events::with_data(
+                    engine.completion_evt(),
+                    data,
+                    EventSet::IN,
+                )) {
+                    error!("Failed to register IO engine completion event: {}", err);
+                }
             }
         }
     }
@@ -85,10 +96,18 @@ impl MutEventSubscriber for VirtioBlock {
         if self.is_activated() {
             match source {
                 Self::PROCESS_ACTIVATE => self.process_activate_event(ops),
-                Self::PROCESS_QUEUE => self.process_queue_event(),
                 Self::PROCESS_RATE_LIMITER => self.process_rate_limiter_event(),
-                Self::PROCESS_ASYNC_COMPLETION => self.process_async_completion_event(),
-                _ => warn!("Block: Spurious event received: {:?}", source),
+                _ => {
+                    if let Some(index) = self.event_queue_index(source, Self::PROCESS_QUEUE) {
+                        self.process_queue_event(index);
+                    } else if let Some(index) =
+                        self.event_queue_index(source, Self::PROCESS_ASYNC_COMPLETION)
+                    {
+                        self.process_async_completion_event(index);
+                    } else {
+                        warn!("Block: Spurious event received: {:?}", source);
+                    }
+                }
             }
         } else {
             warn!(
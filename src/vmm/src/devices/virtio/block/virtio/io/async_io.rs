@@ -7,15 +7,24 @@ use std::os::fd::RawFd;
 use std::os::unix::io::AsRawFd;
 
 use utils::eventfd::EventFd;
+use utils::u64_to_usize;
 use vm_memory::GuestMemoryError;
 
-use crate::devices::virtio::block::virtio::io::UserDataError;
+use crate::devices::virtio::block::virtio::device::AsyncIoConfig;
+use crate::devices::virtio::block::virtio::io::{
+    AlignedBuffer, CryptError, FallocateMode, UserDataError, XtsCipher, XTS_SECTOR_SIZE,
+};
 use crate::devices::virtio::block::virtio::IO_URING_NUM_ENTRIES;
-use crate::io_uring::operation::{Cqe, OpCode, Operation};
+use crate::io_uring::operation::{Cqe, FixedBuffer, OpCode, Operation};
 use crate::io_uring::restriction::Restriction;
 use crate::io_uring::{self, IoUring, IoUringError};
-use crate::logger::log_dev_preview_warning;
-use crate::vstate::memory::{GuestAddress, GuestMemory, GuestMemoryExtension, GuestMemoryMmap};
+use crate::logger::{log_dev_preview_warning, warn};
+use crate::vstate::memory::{
+    Bytes, GuestAddress, GuestMemory, GuestMemoryExtension, GuestMemoryMmap, GuestMemoryRegion,
+};
+
+// The kernel rejects registered buffers larger than 1 GiB.
+const MAX_FIXED_BUFFER_SIZE: usize = 1 << 30;
 
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
 pub enum AsyncIoError {
@@ -31,6 +40,8 @@ pub enum AsyncIoError {
     EventFd(std::io::Error),
     /// GuestMemory: {0}
     GuestMemory(GuestMemoryError),
+    /// Encryption: {0}
+    Crypt(CryptError),
 }
 
 #[derive(Debug)]
@@ -38,11 +49,24 @@ pub struct AsyncFileEngine<T> {
     file: File,
     ring: IoUring<WrappedUserData<T>>,
     completion_evt: EventFd,
+    config: AsyncIoConfig,
+    // Host memory ranges of the guest memory registered as fixed buffers, indexed by buffer.
+    // Registration happens on the first request, as the guest memory is not known before.
+    fixed_buffers: Option<Vec<(usize, usize)>>,
+    cipher: Option<XtsCipher>,
 }
 
 #[derive(Debug)]
 pub struct WrappedUserData<T> {
     addr: Option<GuestAddress>,
+    // Offset of the encrypted data read into `buffer`, which gets decrypted and copied to guest
+    // memory once the read completes.
+    decrypt_offset: Option<u64>,
+    // Encrypted data being read or written, which must outlive the operation.
+    buffer: Option<Vec<u8>>,
+    // Buffer of a direct IO operation on a guest buffer which isn't aligned, which must outlive
+    // the operation. Data read into it is copied to guest memory once the read completes.
+    bounce_buffer: Option<AlignedBuffer>,
     user_data: T,
 }
 
@@ -50,6 +74,9 @@ impl<T: Debug> WrappedUserData<T> {
     fn new(user_data: T) -> Self {
         WrappedUserData {
             addr: None,
+            decrypt_offset: None,
+            buffer: None,
+            bounce_buffer: None,
             user_data,
         }
     }
@@ -57,10 +84,58 @@ impl<T: Debug> WrappedUserData<T> {
     fn new_with_dirty_tracking(addr: GuestAddress, user_data: T) -> Self {
         WrappedUserData {
             addr: Some(addr),
+            decrypt_offset: None,
+            buffer: None,
+            bounce_buffer: None,
+            user_data,
+        }
+    }
+
+    fn new_with_buffer(buffer: Vec<u8>, user_data: T) -> Self {
+        WrappedUserData {
+            addr: None,
+            decrypt_offset: None,
+            buffer: Some(buffer),
+            bounce_buffer: None,
             user_data,
         }
     }
 
+    // Decrypts the `count` bytes read into the buffer and copies the plaintext to guest memory,
+    // if the operation read encrypted data. Returns whether the guest got the data.
+    fn copy_decrypted(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        cipher: Option<&XtsCipher>,
+        count: u32,
+    ) -> bool {
+        let (Some(addr), Some(offset), Some(cipher)) = (self.addr, self.decrypt_offset, cipher)
+        else {
+            return true;
+        };
+        // Only whole sectors can be decrypted.
+        count as usize % XTS_SECTOR_SIZE == 0
+            && self
+                .buffer
+                .as_mut()
+                .and_then(|buffer| buffer.get_mut(..count as usize))
+                .is_some_and(|data| {
+                    cipher.decrypt(offset, data).is_ok() && mem.write_slice(data, addr).is_ok()
+                })
+    }
+
+    // Copies the `count` bytes read into the bounce buffer to guest memory, if the operation is a
+    // bounced read. Returns whether the guest got the data.
+    fn copy_bounced(&self, mem: &GuestMemoryMmap, count: u32) -> bool {
+        let (Some(addr), Some(bounce_buffer)) = (self.addr, &self.bounce_buffer) else {
+            return true;
+        };
+        bounce_buffer
+            .as_slice()
+            .get(..count as usize)
+            .is_some_and(|data| mem.write_slice(data, addr).is_ok())
+    }
+
     fn mark_dirty_mem_and_unwrap(self, mem: &GuestMemoryMmap, count: u32) -> T {
         if let Some(addr) = self.addr {
             mem.mark_dirty(addr, count as usize)
@@ -74,50 +149,134 @@ impl<T: Debug> AsyncFileEngine<T> {
     fn new_ring(
         file: &File,
         completion_fd: RawFd,
+        config: AsyncIoConfig,
     ) -> Result<IoUring<WrappedUserData<T>>, io_uring::IoUringError> {
+        let mut restrictions = vec![
+            // Make sure we only allow operations on pre-registered fds.
+            Restriction::RequireFixedFds,
+            // Allowlist of opcodes.
+            Restriction::AllowOpCode(OpCode::Read),
+            Restriction::AllowOpCode(OpCode::Write),
+            Restriction::AllowOpCode(OpCode::Fsync),
+            Restriction::AllowOpCode(OpCode::Fallocate),
+        ];
+        if config.fixed_buffers {
+            restrictions.extend([
+                Restriction::AllowOpCode(OpCode::ReadFixed),
+                Restriction::AllowOpCode(OpCode::WriteFixed),
+                Restriction::AllowBufferRegistration,
+            ]);
+        }
+
         IoUring::new(
             u32::from(IO_URING_NUM_ENTRIES),
             vec![file],
-            vec![
-                // Make sure we only allow operations on pre-registered fds.
-                Restriction::RequireFixedFds,
-                // Allowlist of opcodes.
-                Restriction::AllowOpCode(OpCode::Read),
-                Restriction::AllowOpCode(OpCode::Write),
-                Restriction::AllowOpCode(OpCode::Fsync),
-            ],
+            restrictions,
             Some(completion_fd),
+            config.sq_poll.then_some(config.sq_poll_idle_ms),
         )
     }
 
-    pub fn from_file(file: File) -> Result<AsyncFileEngine<T>, AsyncIoError> {
+    pub fn from_file(
+        file: File,
+        config: AsyncIoConfig,
+    ) -> Result<AsyncFileEngine<T>, AsyncIoError> {
         log_dev_preview_warning("Async file IO", Option::None);
 
         let completion_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(AsyncIoError::EventFd)?;
-        let ring =
-            Self::new_ring(&file, completion_evt.as_raw_fd()).map_err(AsyncIoError::IoUring)?;
+        let ring = Self::new_ring(&file, completion_evt.as_raw_fd(), config)
+            .map_err(AsyncIoError::IoUring)?;
 
         Ok(AsyncFileEngine {
             file,
             ring,
             completion_evt,
+            config,
+            fixed_buffers: None,
+            cipher: None,
         })
     }
 
+    /// Encrypt the data written to the backing file, and decrypt the data read from it.
+    pub fn set_cipher(&mut self, cipher: XtsCipher) {
+        self.cipher = Some(cipher)
+    }
+
     pub fn update_file(&mut self, file: File) -> Result<(), AsyncIoError> {
-        let ring = Self::new_ring(&file, self.completion_evt.as_raw_fd())
+        let ring = Self::new_ring(&file, self.completion_evt.as_raw_fd(), self.config)
             .map_err(AsyncIoError::IoUring)?;
 
         self.file = file;
         self.ring = ring;
+        // The guest memory gets registered with the new ring on the next request.
+        self.fixed_buffers = None;
         Ok(())
     }
 
+    // Returns the index of the fixed buffer holding the `len` bytes at host address `addr`, if
+    // the engine uses fixed buffers and the bytes do not cross the end of a buffer.
+    fn fixed_buffer(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        addr: usize,
+        len: u32,
+    ) -> Option<FixedBuffer> {
+        if !self.config.fixed_buffers {
+            return None;
+        }
+
+        let ring = &mut self.ring;
+        let fixed_buffers = self
+            .fixed_buffers
+            .get_or_insert_with(|| Self::register_guest_memory(ring, mem));
+        let end = addr.checked_add(len as usize)?;
+        fixed_buffers
+            .iter()
+            .position(|&(start, size)| start <= addr && end <= start + size)
+            .and_then(|index| FixedBuffer::try_from(index).ok())
+    }
+
+    // Registers the guest memory as fixed buffers, returning the registered host memory ranges.
+    // The engine falls back to regular operations if the registration fails, e.g. because of
+    // the limit of locked memory.
+    fn register_guest_memory(
+        ring: &mut IoUring<WrappedUserData<T>>,
+        mem: &GuestMemoryMmap,
+    ) -> Vec<(usize, usize)> {
+        let buffers = mem
+            .iter()
+            .flat_map(|region| {
+                let start = region.as_ptr() as usize;
+                let len = u64_to_usize(region.len());
+                (0..len)
+                    .step_by(MAX_FIXED_BUFFER_SIZE)
+                    .map(move |offset| (start + offset, MAX_FIXED_BUFFER_SIZE.min(len - offset)))
+            })
+            .collect::<Vec<_>>();
+
+        match ring.register_buffers(&buffers) {
+            Ok(()) => buffers,
+            Err(err) => {
+                warn!(
+                    "Failed to register the guest memory as io_uring fixed buffers, using regular \
+                     operations instead: {}",
+                    err
+                );
+                Vec::new()
+            }
+        }
+    }
+
     #[cfg(test)]
     pub fn file(&self) -> &File {
         &self.file
     }
 
+    #[cfg(test)]
+    pub fn fixed_buffers(&self) -> Option<&Vec<(usize, usize)>> {
+        self.fixed_buffers.as_ref()
+    }
+
     pub fn completion_evt(&self) -> &EventFd {
         &self.completion_evt
     }
@@ -140,16 +299,31 @@ impl<T: Debug> AsyncFileEngine<T> {
             }
         };
 
-        let wrapped_user_data = WrappedUserData::new_with_dirty_tracking(addr, user_data);
+        let mut wrapped_user_data = WrappedUserData::new_with_dirty_tracking(addr, user_data);
+        // Encrypted data is read into a buffer, as the guest must never see the ciphertext.
+        let buf = match self.cipher {
+            Some(_) => {
+                let mut encrypted = vec![0u8; count as usize];
+                let buf = encrypted.as_mut_ptr() as usize;
+                wrapped_user_data.decrypt_offset = Some(offset);
+                wrapped_user_data.buffer = Some(encrypted);
+                buf
+            }
+            None => buf as usize,
+        };
+        let fixed_buffer = match wrapped_user_data.buffer {
+            Some(_) => None,
+            None => self.fixed_buffer(mem, buf, count),
+        };
+        let operation = match fixed_buffer {
+            Some(buf_index) => {
+                Operation::read_fixed(0, buf, count, offset, buf_index, wrapped_user_data)
+            }
+            None => Operation::read(0, buf, count, offset, wrapped_user_data),
+        };
 
         self.ring
-            .push(Operation::read(
-                0,
-                buf as usize,
-                count,
-                offset,
-                wrapped_user_data,
-            ))
+            .push(operation)
             .map_err(|(io_uring_error, data)| UserDataError {
                 user_data: data.user_data,
                 error: AsyncIoError::IoUring(io_uring_error),
@@ -174,16 +348,103 @@ impl<T: Debug> AsyncFileEngine<T> {
             }
         };
 
-        let wrapped_user_data = WrappedUserData::new(user_data);
+        // Encrypted data is written from a copy of the guest memory.
+        let (buf, wrapped_user_data) = match &self.cipher {
+            Some(cipher) => {
+                let mut encrypted = vec![0u8; count as usize];
+                let res = mem
+                    .read_slice(&mut encrypted, addr)
+                    .map_err(AsyncIoError::GuestMemory)
+                    .and_then(|()| {
+                        cipher
+                            .encrypt(offset, &mut encrypted)
+                            .map_err(AsyncIoError::Crypt)
+                    });
+                if let Err(error) = res {
+                    return Err(UserDataError { user_data, error });
+                }
+                (
+                    encrypted.as_ptr() as usize,
+                    WrappedUserData::new_with_buffer(encrypted, user_data),
+                )
+            }
+            None => (buf as usize, WrappedUserData::new(user_data)),
+        };
+        let fixed_buffer = match wrapped_user_data.buffer {
+            Some(_) => None,
+            None => self.fixed_buffer(mem, buf, count),
+        };
+        let operation = match fixed_buffer {
+            Some(buf_index) => {
+                Operation::write_fixed(0, buf, count, offset, buf_index, wrapped_user_data)
+            }
+            None => Operation::write(0, buf, count, offset, wrapped_user_data),
+        };
 
         self.ring
-            .push(Operation::write(
-                0,
-                buf as usize,
-                count,
-                offset,
-                wrapped_user_data,
-            ))
+            .push(operation)
+            .map_err(|(io_uring_error, data)| UserDataError {
+                user_data: data.user_data,
+                error: AsyncIoError::IoUring(io_uring_error),
+            })
+    }
+
+    /// Reads into a buffer aligned to `alignment` bytes, for direct IO into a guest buffer which
+    /// isn't aligned. The data is copied to guest memory once the read completes.
+    pub fn push_read_bounced(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        alignment: usize,
+        user_data: T,
+    ) -> Result<(), UserDataError<T, AsyncIoError>> {
+        // The guest buffer is checked now, as errors can't be reported on completion.
+        if let Err(err) = mem.get_slice(addr, count as usize) {
+            return Err(UserDataError {
+                user_data,
+                error: AsyncIoError::GuestMemory(err),
+            });
+        }
+
+        let mut bounce_buffer = AlignedBuffer::new(count as usize, alignment);
+        let buf = bounce_buffer.as_mut_slice().as_mut_ptr() as usize;
+        let mut wrapped_user_data = WrappedUserData::new_with_dirty_tracking(addr, user_data);
+        wrapped_user_data.bounce_buffer = Some(bounce_buffer);
+
+        self.ring
+            .push(Operation::read(0, buf, count, offset, wrapped_user_data))
+            .map_err(|(io_uring_error, data)| UserDataError {
+                user_data: data.user_data,
+                error: AsyncIoError::IoUring(io_uring_error),
+            })
+    }
+
+    /// Writes from a buffer aligned to `alignment` bytes, for direct IO from a guest buffer
+    /// which isn't aligned.
+    pub fn push_write_bounced(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        alignment: usize,
+        user_data: T,
+    ) -> Result<(), UserDataError<T, AsyncIoError>> {
+        let mut bounce_buffer = AlignedBuffer::new(count as usize, alignment);
+        if let Err(err) = mem.read_slice(bounce_buffer.as_mut_slice(), addr) {
+            return Err(UserDataError {
+                user_data,
+                error: AsyncIoError::GuestMemory(err),
+            });
+        }
+        let buf = bounce_buffer.as_slice().as_ptr() as usize;
+        let mut wrapped_user_data = WrappedUserData::new(user_data);
+        wrapped_user_data.bounce_buffer = Some(bounce_buffer);
+
+        self.ring
+            .push(Operation::write(0, buf, count, offset, wrapped_user_data))
             .map_err(|(io_uring_error, data)| UserDataError {
                 user_data: data.user_data,
                 error: AsyncIoError::IoUring(io_uring_error),
@@ -201,6 +462,37 @@ impl<T: Debug> AsyncFileEngine<T> {
             })
     }
 
+    pub fn push_fallocate(
+        &mut self,
+        mode: FallocateMode,
+        offset: u64,
+        len: u64,
+        user_data: T,
+    ) -> Result<(), UserDataError<T, AsyncIoError>> {
+        // Deallocated ranges don't read as encrypted zeros.
+        if self.cipher.is_some() && mode != FallocateMode::Discard {
+            return Err(UserDataError {
+                user_data,
+                error: AsyncIoError::Crypt(CryptError::WriteZeroes),
+            });
+        }
+        let wrapped_user_data = WrappedUserData::new(user_data);
+
+        // The mode flags are positive, so they are preserved by the conversion.
+        self.ring
+            .push(Operation::fallocate(
+                0,
+                mode.flags().unsigned_abs(),
+                offset,
+                len,
+                wrapped_user_data,
+            ))
+            .map_err(|(io_uring_error, data)| UserDataError {
+                user_data: data.user_data,
+                error: AsyncIoError::IoUring(io_uring_error),
+            })
+    }
+
     pub fn kick_submission_queue(&mut self) -> Result<(), AsyncIoError> {
         self.ring
             .submit()
@@ -240,9 +532,18 @@ impl<T: Debug> AsyncFileEngine<T> {
     pub fn pop(&mut self, mem: &GuestMemoryMmap) -> Result<Option<Cqe<T>>, AsyncIoError> {
         let cqe = self.do_pop()?.map(|cqe| {
             let count = cqe.count();
-            cqe.map_user_data(|wrapped_user_data| {
+            let mut usable = true;
+            let mut cqe = cqe.map_user_data(|mut wrapped_user_data| {
+                usable = wrapped_user_data.copy_decrypted(mem, self.cipher.as_ref(), count)
+                    && wrapped_user_data.copy_bounced(mem, count);
                 wrapped_user_data.mark_dirty_mem_and_unwrap(mem, count)
-            })
+            });
+            // The guest must not get the data it can't read as plaintext, nor a completion for
+            // data it didn't get.
+            if !usable {
+                cqe.set_error(libc::EIO);
+            }
+            cqe
         });
 
         Ok(cqe)
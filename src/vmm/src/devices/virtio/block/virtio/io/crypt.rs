@@ -0,0 +1,276 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements the encryption of the data of drives with AES-256-XTS.
+//!
+//! Every 512 bytes sector of the backing file is encrypted independently, using its index as
+//! the tweak, in little endian (the `plain64` IV of dm-crypt). The first half of the 64 bytes
+//! key encrypts the data and the second half encrypts the tweaks, so that the backing file is
+//! in the format of the `aes-xts-plain64` cipher of dm-crypt with a 512 bits key.
+//!
+//! The XTS mode itself is implemented by the `xts-mode` crate. Key material is zeroed when it is
+//! dropped: the AES key schedules through the `zeroize` feature of the `aes` crate, and the raw
+//! key buffers through [`Zeroizing`].
+
+use std::fmt;
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+
+use aes::cipher::{Key, KeyInit};
+use aes::Aes256;
+use base64::Engine;
+use xts_mode::{get_tweak_default, Xts128};
+use zeroize::Zeroizing;
+
+use crate::devices::virtio::block::virtio::device::DriveEncryptionConfig;
+
+/// Length of the key, made of the keys encrypting the data and the tweaks.
+pub const XTS_KEY_LEN: usize = 64;
+/// Size of the units of data sharing a tweak.
+pub const XTS_SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum CryptError {
+    /// Exactly one of `key` and `key_fd` must be provided.
+    InvalidConfig,
+    /// Invalid base64 encoding of the encryption key: {0}
+    Base64(base64::DecodeError),
+    /// Invalid encryption key length: expected 64 bytes, got {0}.
+    InvalidLength(usize),
+    /// The two halves of the encryption key must differ.
+    WeakKey,
+    /// Cannot read the encryption key from its file descriptor: {0}
+    ReadFd(std::io::Error),
+    /// Range {0:#x}+{1:#x} is not aligned to sectors.
+    Unaligned(u64, usize),
+    /// Encrypted drives don't support write zeroes requests.
+    WriteZeroes,
+}
+
+/// AES-256-XTS cipher of the sectors of a drive.
+///
+/// Clones share the same key schedules, which are zeroed once the last clone is dropped.
+#[derive(Clone)]
+pub struct XtsCipher(Arc<Xts128<Aes256>>);
+
+// The key must never end up in logs.
+impl fmt::Debug for XtsCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("XtsCipher(..)")
+    }
+}
+
+impl XtsCipher {
+    /// Creates a cipher from the raw bytes of its key.
+    pub fn new(key: &[u8]) -> Result<Self, CryptError> {
+        if key.len() != XTS_KEY_LEN {
+            return Err(CryptError::InvalidLength(key.len()));
+        }
+        let (data_key, tweak_key) = key.split_at(XTS_KEY_LEN / 2);
+        // XTS is not secure when the data and the tweaks are encrypted with the same key.
+        if data_key == tweak_key {
+            return Err(CryptError::WeakKey);
+        }
+
+        Ok(Self(Arc::new(Xts128::new(
+            Aes256::new(Key::<Aes256>::from_slice(data_key)),
+            Aes256::new(Key::<Aes256>::from_slice(tweak_key)),
+        ))))
+    }
+
+    /// Creates a cipher from exactly one of the base64 encoding of the key and the file
+    /// descriptor it can be read from.
+    pub fn from_config(config: &DriveEncryptionConfig) -> Result<Self, CryptError> {
+        match (config.key.as_deref(), config.key_fd) {
+            (Some(key), None) => {
+                let key = Zeroizing::new(
+                    base64::engine::general_purpose::STANDARD
+                        .decode(key)
+                        .map_err(CryptError::Base64)?,
+                );
+                Self::new(&key)
+            }
+            (None, Some(fd)) => Self::new(Self::read_key(fd)?.as_slice()),
+            _ => Err(CryptError::InvalidConfig),
+        }
+    }
+
+    // Reads the key from `fd`, which is left open.
+    fn read_key(fd: RawFd) -> Result<Zeroizing<[u8; XTS_KEY_LEN]>, CryptError> {
+        let mut key = Zeroizing::new([0u8; XTS_KEY_LEN]);
+        utils::read_exact_from_fd(fd, key.as_mut_slice()).map_err(CryptError::ReadFd)?;
+        Ok(key)
+    }
+
+    /// Encrypts in place the sectors in `data`, starting at byte `offset` of the drive.
+    pub fn encrypt(&self, offset: u64, data: &mut [u8]) -> Result<(), CryptError> {
+        let first_sector = Self::first_sector(offset, data)?;
+        self.0
+            .encrypt_area(data, XTS_SECTOR_SIZE, first_sector, get_tweak_default);
+        Ok(())
+    }
+
+    /// Decrypts in place the sectors in `data`, starting at byte `offset` of the drive.
+    pub fn decrypt(&self, offset: u64, data: &mut [u8]) -> Result<(), CryptError> {
+        let first_sector = Self::first_sector(offset, data)?;
+        self.0
+            .decrypt_area(data, XTS_SECTOR_SIZE, first_sector, get_tweak_default);
+        Ok(())
+    }
+
+    // Returns the index of the sector at `offset`, checking that `data` only spans whole sectors.
+    fn first_sector(offset: u64, data: &[u8]) -> Result<u128, CryptError> {
+        if offset % XTS_SECTOR_SIZE as u64 != 0 || data.len() % XTS_SECTOR_SIZE != 0 {
+            return Err(CryptError::Unaligned(offset, data.len()));
+        }
+        Ok(u128::from(offset / XTS_SECTOR_SIZE as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, Write};
+    use std::os::unix::io::AsRawFd;
+
+    use utils::tempfile::TempFile;
+
+    use super::*;
+
+    fn test_key() -> Vec<u8> {
+        (0..XTS_KEY_LEN).map(|i| u8::try_from(i).unwrap()).collect()
+    }
+
+    // Returns the encryption of the first sector with `cipher`, which identifies its key.
+    fn first_sector(cipher: &XtsCipher) -> Vec<u8> {
+        let mut sector = vec![0u8; XTS_SECTOR_SIZE];
+        cipher.encrypt(0, &mut sector).unwrap();
+        sector
+    }
+
+    #[test]
+    fn test_encryption_key() {
+        let config = DriveEncryptionConfig {
+            key: Some(Zeroizing::new(
+                base64::engine::general_purpose::STANDARD.encode(test_key()),
+            )),
+            key_fd: None,
+        };
+        assert_eq!(
+            first_sector(&XtsCipher::from_config(&config).unwrap()),
+            first_sector(&XtsCipher::new(&test_key()).unwrap())
+        );
+        assert_eq!(
+            format!("{:?}", XtsCipher::new(&test_key()).unwrap()),
+            "XtsCipher(..)"
+        );
+
+        let config = DriveEncryptionConfig {
+            key: Some(Zeroizing::new(
+                base64::engine::general_purpose::STANDARD.encode([0xAA; 32]),
+            )),
+            key_fd: None,
+        };
+        assert!(matches!(
+            XtsCipher::from_config(&config),
+            Err(CryptError::InvalidLength(32))
+        ));
+        assert!(matches!(
+            XtsCipher::new(&[0xAA; XTS_KEY_LEN]),
+            Err(CryptError::WeakKey)
+        ));
+
+        let config = DriveEncryptionConfig::default();
+        assert!(matches!(
+            XtsCipher::from_config(&config),
+            Err(CryptError::InvalidConfig)
+        ));
+
+        let key_file = TempFile::new().unwrap();
+        key_file.as_file().write_all(&test_key()).unwrap();
+        key_file.as_file().rewind().unwrap();
+        let config = DriveEncryptionConfig {
+            key: None,
+            key_fd: Some(key_file.as_file().as_raw_fd()),
+        };
+        assert_eq!(
+            first_sector(&XtsCipher::from_config(&config).unwrap()),
+            first_sector(&XtsCipher::new(&test_key()).unwrap())
+        );
+        // The whole key was consumed.
+        assert!(matches!(
+            XtsCipher::from_config(&config),
+            Err(CryptError::ReadFd(_))
+        ));
+        let config = DriveEncryptionConfig {
+            key: None,
+            key_fd: Some(-1),
+        };
+        assert!(matches!(
+            XtsCipher::from_config(&config),
+            Err(CryptError::ReadFd(_))
+        ));
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let cipher = XtsCipher::new(&test_key()).unwrap();
+        let plaintext = (0..2 * XTS_SECTOR_SIZE)
+            .map(|i| u8::try_from(i * 7 % 256).unwrap())
+            .collect::<Vec<_>>();
+
+        let mut data = plaintext.clone();
+        cipher
+            .encrypt(5 * XTS_SECTOR_SIZE as u64, &mut data)
+            .unwrap();
+        // Ciphertext of the aes-xts-plain64 cipher of dm-crypt.
+        assert_eq!(
+            data[..32],
+            [
+                0x35, 0xaf, 0x68, 0x69, 0x01, 0xa5, 0xcb, 0x6d, 0xf9, 0xc1, 0x80, 0xd9, 0x92, 0x26,
+                0xdf, 0xef, 0x39, 0x24, 0x1e, 0x48, 0xcd, 0x3d, 0x86, 0x56, 0x0a, 0x80, 0x94, 0xe7,
+                0x99, 0x36, 0xea, 0x78
+            ]
+        );
+        assert_eq!(
+            data[XTS_SECTOR_SIZE..XTS_SECTOR_SIZE + 32],
+            [
+                0x37, 0x37, 0x3b, 0x13, 0xbb, 0x33, 0x5f, 0x99, 0x95, 0xf6, 0x45, 0x78, 0x08, 0x34,
+                0xce, 0x75, 0x8c, 0xdf, 0x8e, 0x28, 0xa3, 0x2d, 0x44, 0x9d, 0xce, 0xdb, 0x8b, 0xfc,
+                0xcf, 0x3e, 0x2f, 0x0f
+            ]
+        );
+        assert_eq!(
+            data[2 * XTS_SECTOR_SIZE - 16..],
+            [
+                0xff, 0x4b, 0xf6, 0x61, 0x14, 0x34, 0x80, 0x9c, 0xfc, 0x6e, 0x3b, 0x08, 0xbf, 0x76,
+                0xe8, 0x17
+            ]
+        );
+
+        // Sectors are encrypted independently.
+        let mut sector = plaintext[XTS_SECTOR_SIZE..].to_vec();
+        cipher
+            .encrypt(6 * XTS_SECTOR_SIZE as u64, &mut sector)
+            .unwrap();
+        assert_eq!(sector, data[XTS_SECTOR_SIZE..]);
+
+        cipher
+            .decrypt(5 * XTS_SECTOR_SIZE as u64, &mut data)
+            .unwrap();
+        assert_eq!(data, plaintext);
+
+        // Sectors can only be decrypted at their offset.
+        cipher.encrypt(0, &mut data).unwrap();
+        cipher.decrypt(XTS_SECTOR_SIZE as u64, &mut data).unwrap();
+        assert_ne!(data, plaintext);
+
+        assert!(matches!(
+            cipher.encrypt(1, &mut data),
+            Err(CryptError::Unaligned(1, 1024))
+        ));
+        assert!(matches!(
+            cipher.decrypt(0, &mut data[..100]),
+            Err(CryptError::Unaligned(0, 100))
+        ));
+    }
+}
@@ -2,14 +2,25 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod async_io;
+pub mod crypt;
+pub mod nbd;
+pub mod overlay;
+pub mod qcow2;
 pub mod sync_io;
+pub mod verity;
 
 use std::fmt::Debug;
 use std::fs::File;
+use std::path::Path;
 
 pub use self::async_io::{AsyncFileEngine, AsyncIoError};
+pub use self::crypt::{CryptError, XtsCipher, XTS_SECTOR_SIZE};
+pub use self::nbd::{NbdError, NbdFileEngine};
+pub use self::overlay::{OverlayError, OverlayFileEngine};
+pub use self::qcow2::{Qcow2Error, Qcow2FileEngine};
 pub use self::sync_io::{SyncFileEngine, SyncIoError};
-use crate::devices::virtio::block::virtio::device::FileEngineType;
+pub use self::verity::{VerityError, VerityFileEngine};
+use crate::devices::virtio::block::virtio::device::{AsyncIoConfig, FileEngineType, NbdConfig};
 use crate::vstate::memory::{GuestAddress, GuestMemoryMmap};
 
 #[derive(Debug, PartialEq, Eq)]
@@ -30,8 +41,20 @@ pub enum BlockIoError {
     Sync(SyncIoError),
     /// Async error: {0}
     Async(AsyncIoError),
+    /// Qcow2 error: {0}
+    Qcow2(Qcow2Error),
+    /// Overlay error: {0}
+    Overlay(OverlayError),
+    /// Integrity verification error: {0}
+    Verity(VerityError),
+    /// NBD error: {0}
+    Nbd(NbdError),
     /// Unsupported engine type: {0:?}
     UnsupportedEngine(FileEngineType),
+    /// Encryption is only supported by the Sync and Async engines.
+    EncryptionUnsupported,
+    /// Direct IO is only supported by the Sync and Async engines.
+    DirectIoUnsupported,
     /// Could not get kernel version: {0}
     GetKernelVersion(utils::kernel_version::KernelVersionError),
 }
@@ -43,6 +66,60 @@ impl BlockIoError {
             _ => false,
         }
     }
+
+    /// Whether the error comes from data failing integrity verification.
+    pub fn is_integrity_err(&self) -> bool {
+        matches!(self, BlockIoError::Verity(VerityError::HashMismatch(_)))
+    }
+}
+
+/// Ways of changing the allocation of a range of the backing file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FallocateMode {
+    /// Let the storage know the data of the range is not needed anymore.
+    Discard,
+    /// Deallocate the range, which then reads as zeros.
+    PunchHole,
+    /// Zero the range, keeping it allocated.
+    ZeroRange,
+}
+
+impl FallocateMode {
+    /// The `fallocate` mode flags implementing this mode.
+    pub fn flags(self) -> libc::c_int {
+        match self {
+            FallocateMode::Discard | FallocateMode::PunchHole => {
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE
+            }
+            FallocateMode::ZeroRange => libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE,
+        }
+    }
+}
+
+/// Buffer aligned for direct IO, through which the data of guest buffers which are not aligned
+/// is transferred.
+#[derive(Debug)]
+pub struct AlignedBuffer {
+    buf: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    /// Allocates a buffer of `len` bytes starting at a multiple of `alignment`.
+    pub fn new(len: usize, alignment: usize) -> Self {
+        let buf = vec![0u8; len + alignment];
+        let start = (alignment - buf.as_ptr() as usize % alignment) % alignment;
+        Self { buf, start, len }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[self.start..self.start + self.len]
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buf[self.start..self.start + self.len]
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -57,12 +134,18 @@ pub enum FileEngine<T> {
     #[allow(unused)]
     Async(AsyncFileEngine<T>),
     Sync(SyncFileEngine),
+    Qcow2(Qcow2FileEngine),
+    Overlay(OverlayFileEngine),
+    Verity(VerityFileEngine),
+    Nbd(NbdFileEngine),
 }
 
 impl<T: Debug> FileEngine<T> {
     pub fn from_file(
         file: File,
+        path: &Path,
         engine_type: FileEngineType,
+        async_io: AsyncIoConfig,
     ) -> Result<FileEngine<T>, BlockIoError> {
         if !engine_type
             .is_supported()
@@ -72,26 +155,115 @@ impl<T: Debug> FileEngine<T> {
         }
         match engine_type {
             FileEngineType::Async => Ok(FileEngine::Async(
-                AsyncFileEngine::from_file(file).map_err(BlockIoError::Async)?,
+                AsyncFileEngine::from_file(file, async_io).map_err(BlockIoError::Async)?,
             )),
             FileEngineType::Sync => Ok(FileEngine::Sync(SyncFileEngine::from_file(file))),
+            FileEngineType::Qcow2 => Ok(FileEngine::Qcow2(
+                Qcow2FileEngine::from_file(file, path).map_err(BlockIoError::Qcow2)?,
+            )),
+        }
+    }
+
+    /// Creates an engine writing to a copy-on-write overlay of the base image in `file`. Only the
+    /// Sync engine supports overlays.
+    pub fn from_overlay(
+        file: File,
+        overlay: File,
+        bitmap: File,
+        engine_type: FileEngineType,
+    ) -> Result<FileEngine<T>, BlockIoError> {
+        if engine_type != FileEngineType::Sync {
+            return Err(BlockIoError::UnsupportedEngine(engine_type));
         }
+        Ok(FileEngine::Overlay(
+            OverlayFileEngine::new(file, overlay, bitmap).map_err(BlockIoError::Overlay)?,
+        ))
     }
 
-    pub fn update_file_path(&mut self, file: File) -> Result<(), BlockIoError> {
+    /// Creates an engine checking the blocks read from `file` against the hash tree stored in
+    /// `hash_tree`. Only the Sync engine supports integrity verification.
+    pub fn from_verity(
+        file: File,
+        hash_tree: File,
+        root_hash: &str,
+        salt: Option<&str>,
+        engine_type: FileEngineType,
+    ) -> Result<FileEngine<T>, BlockIoError> {
+        if engine_type != FileEngineType::Sync {
+            return Err(BlockIoError::UnsupportedEngine(engine_type));
+        }
+        Ok(FileEngine::Verity(
+            VerityFileEngine::new(file, hash_tree, root_hash, salt)
+                .map_err(BlockIoError::Verity)?,
+        ))
+    }
+
+    /// Creates an engine serving the requests from an export of an NBD server. Only the Sync
+    /// engine supports NBD drives.
+    pub fn from_nbd(
+        nbd: NbdConfig,
+        is_read_only: bool,
+        engine_type: FileEngineType,
+    ) -> Result<FileEngine<T>, BlockIoError> {
+        if engine_type != FileEngineType::Sync {
+            return Err(BlockIoError::UnsupportedEngine(engine_type));
+        }
+        Ok(FileEngine::Nbd(
+            NbdFileEngine::new(nbd, is_read_only).map_err(BlockIoError::Nbd)?,
+        ))
+    }
+
+    /// Encrypts the data written to the backing file, and decrypts the data read from it. Only
+    /// the Sync and Async engines support encryption.
+    pub fn set_cipher(&mut self, cipher: XtsCipher) -> Result<(), BlockIoError> {
+        match self {
+            FileEngine::Async(engine) => engine.set_cipher(cipher),
+            FileEngine::Sync(engine) => engine.set_cipher(cipher),
+            FileEngine::Qcow2(_)
+            | FileEngine::Overlay(_)
+            | FileEngine::Verity(_)
+            | FileEngine::Nbd(_) => return Err(BlockIoError::EncryptionUnsupported),
+        };
+
+        Ok(())
+    }
+
+    pub fn update_file_path(&mut self, file: File, path: &Path) -> Result<(), BlockIoError> {
         match self {
             FileEngine::Async(engine) => engine.update_file(file).map_err(BlockIoError::Async)?,
             FileEngine::Sync(engine) => engine.update_file(file),
+            FileEngine::Qcow2(engine) => engine
+                .update_file(file, path)
+                .map_err(BlockIoError::Qcow2)?,
+            FileEngine::Overlay(engine) => {
+                engine.update_base(file).map_err(BlockIoError::Overlay)?
+            }
+            FileEngine::Verity(engine) => engine.update_file(file).map_err(BlockIoError::Verity)?,
+            FileEngine::Nbd(_) => return Err(BlockIoError::Nbd(NbdError::NoFile)),
         };
 
         Ok(())
     }
 
+    /// Size of the disk, for engines reading disk image formats or not backed by a file.
+    pub fn virtual_size(&self) -> Option<u64> {
+        match self {
+            FileEngine::Qcow2(engine) => Some(engine.virtual_size()),
+            FileEngine::Nbd(engine) => Some(engine.size()),
+            _ => None,
+        }
+    }
+
+    /// File backing the disk, unless it is served by an NBD server.
     #[cfg(test)]
-    pub fn file(&self) -> &File {
+    pub fn file(&self) -> Option<&File> {
         match self {
-            FileEngine::Async(engine) => engine.file(),
-            FileEngine::Sync(engine) => engine.file(),
+            FileEngine::Async(engine) => Some(engine.file()),
+            FileEngine::Sync(engine) => Some(engine.file()),
+            FileEngine::Qcow2(engine) => Some(engine.file()),
+            FileEngine::Overlay(engine) => Some(engine.file()),
+            FileEngine::Verity(engine) => Some(engine.file()),
+            FileEngine::Nbd(_) => None,
         }
     }
 
@@ -120,6 +292,34 @@ impl<T: Debug> FileEngine<T> {
                     error: BlockIoError::Sync(err),
                 }),
             },
+            FileEngine::Qcow2(engine) => match engine.read(offset, mem, addr, count) {
+                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: BlockIoError::Qcow2(err),
+                }),
+            },
+            FileEngine::Overlay(engine) => match engine.read(offset, mem, addr, count) {
+                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: BlockIoError::Overlay(err),
+                }),
+            },
+            FileEngine::Verity(engine) => match engine.read(offset, mem, addr, count) {
+                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: BlockIoError::Verity(err),
+                }),
+            },
+            FileEngine::Nbd(engine) => match engine.read(offset, mem, addr, count) {
+                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: BlockIoError::Nbd(err),
+                }),
+            },
         }
     }
 
@@ -148,6 +348,111 @@ impl<T: Debug> FileEngine<T> {
                     error: BlockIoError::Sync(err),
                 }),
             },
+            FileEngine::Qcow2(engine) => match engine.write(offset, mem, addr, count) {
+                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: BlockIoError::Qcow2(err),
+                }),
+            },
+            FileEngine::Overlay(engine) => match engine.write(offset, mem, addr, count) {
+                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: BlockIoError::Overlay(err),
+                }),
+            },
+            FileEngine::Verity(_) => Err(UserDataError {
+                user_data,
+                error: BlockIoError::Verity(VerityError::ReadOnly),
+            }),
+            FileEngine::Nbd(engine) => match engine.write(offset, mem, addr, count) {
+                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: BlockIoError::Nbd(err),
+                }),
+            },
+        }
+    }
+
+    /// Reads into a buffer aligned to `alignment` bytes, whose data is then copied to guest
+    /// memory. Only the Sync and Async engines support direct IO.
+    pub fn read_bounced(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        alignment: usize,
+        user_data: T,
+    ) -> Result<FileEngineOk<T>, UserDataError<T, BlockIoError>> {
+        match self {
+            FileEngine::Async(engine) => {
+                match engine.push_read_bounced(offset, mem, addr, count, alignment, user_data) {
+                    Ok(_) => Ok(FileEngineOk::Submitted),
+                    Err(err) => Err(UserDataError {
+                        user_data: err.user_data,
+                        error: BlockIoError::Async(err.error),
+                    }),
+                }
+            }
+            FileEngine::Sync(engine) => {
+                match engine.read_bounced(offset, mem, addr, count, alignment) {
+                    Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                    Err(err) => Err(UserDataError {
+                        user_data,
+                        error: BlockIoError::Sync(err),
+                    }),
+                }
+            }
+            FileEngine::Qcow2(_)
+            | FileEngine::Overlay(_)
+            | FileEngine::Verity(_)
+            | FileEngine::Nbd(_) => Err(UserDataError {
+                user_data,
+                error: BlockIoError::DirectIoUnsupported,
+            }),
+        }
+    }
+
+    /// Writes from a buffer aligned to `alignment` bytes, holding a copy of the data in guest
+    /// memory. Only the Sync and Async engines support direct IO.
+    pub fn write_bounced(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        alignment: usize,
+        user_data: T,
+    ) -> Result<FileEngineOk<T>, UserDataError<T, BlockIoError>> {
+        match self {
+            FileEngine::Async(engine) => {
+                match engine.push_write_bounced(offset, mem, addr, count, alignment, user_data) {
+                    Ok(_) => Ok(FileEngineOk::Submitted),
+                    Err(err) => Err(UserDataError {
+                        user_data: err.user_data,
+                        error: BlockIoError::Async(err.error),
+                    }),
+                }
+            }
+            FileEngine::Sync(engine) => {
+                match engine.write_bounced(offset, mem, addr, count, alignment) {
+                    Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                    Err(err) => Err(UserDataError {
+                        user_data,
+                        error: BlockIoError::Sync(err),
+                    }),
+                }
+            }
+            FileEngine::Qcow2(_)
+            | FileEngine::Overlay(_)
+            | FileEngine::Verity(_)
+            | FileEngine::Nbd(_) => Err(UserDataError {
+                user_data,
+                error: BlockIoError::DirectIoUnsupported,
+            }),
         }
     }
 
@@ -173,13 +478,116 @@ impl<T: Debug> FileEngine<T> {
                     error: BlockIoError::Sync(err),
                 }),
             },
+            FileEngine::Qcow2(engine) => match engine.flush() {
+                Ok(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: BlockIoError::Qcow2(err),
+                }),
+            },
+            FileEngine::Overlay(engine) => match engine.flush() {
+                Ok(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: BlockIoError::Overlay(err),
+                }),
+            },
+            // Verified images are never written to.
+            FileEngine::Verity(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                user_data,
+                count: 0,
+            })),
+            FileEngine::Nbd(engine) => match engine.flush() {
+                Ok(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: BlockIoError::Nbd(err),
+                }),
+            },
+        }
+    }
+
+    pub fn fallocate(
+        &mut self,
+        mode: FallocateMode,
+        offset: u64,
+        len: u64,
+        user_data: T,
+    ) -> Result<FileEngineOk<T>, UserDataError<T, BlockIoError>> {
+        match self {
+            FileEngine::Async(engine) => {
+                match engine.push_fallocate(mode, offset, len, user_data) {
+                    Ok(_) => Ok(FileEngineOk::Submitted),
+                    Err(err) => Err(UserDataError {
+                        user_data: err.user_data,
+                        error: BlockIoError::Async(err.error),
+                    }),
+                }
+            }
+            FileEngine::Sync(engine) => match engine.fallocate(mode, offset, len) {
+                Ok(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: BlockIoError::Sync(err),
+                }),
+            },
+            FileEngine::Qcow2(engine) => match engine.fallocate(mode, offset, len) {
+                Ok(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: BlockIoError::Qcow2(err),
+                }),
+            },
+            FileEngine::Overlay(engine) => match engine.fallocate(mode, offset, len) {
+                Ok(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: BlockIoError::Overlay(err),
+                }),
+            },
+            FileEngine::Verity(_) => Err(UserDataError {
+                user_data,
+                error: BlockIoError::Verity(VerityError::ReadOnly),
+            }),
+            FileEngine::Nbd(engine) => match engine.fallocate(mode, offset, len) {
+                Ok(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: BlockIoError::Nbd(err),
+                }),
+            },
         }
     }
 
     pub fn drain(&mut self, discard: bool) -> Result<(), BlockIoError> {
         match self {
             FileEngine::Async(engine) => engine.drain(discard).map_err(BlockIoError::Async),
-            FileEngine::Sync(_engine) => Ok(()),
+            FileEngine::Sync(_)
+            | FileEngine::Qcow2(_)
+            | FileEngine::Overlay(_)
+            | FileEngine::Verity(_)
+            | FileEngine::Nbd(_) => Ok(()),
         }
     }
 
@@ -189,6 +597,10 @@ impl<T: Debug> FileEngine<T> {
                 engine.drain_and_flush(discard).map_err(BlockIoError::Async)
             }
             FileEngine::Sync(engine) => engine.flush().map_err(BlockIoError::Sync),
+            FileEngine::Qcow2(engine) => engine.flush().map_err(BlockIoError::Qcow2),
+            FileEngine::Overlay(engine) => engine.flush().map_err(BlockIoError::Overlay),
+            FileEngine::Verity(_) => Ok(()),
+            FileEngine::Nbd(engine) => engine.flush().map_err(BlockIoError::Nbd),
         }
     }
 }
@@ -197,6 +609,7 @@ impl<T: Debug> FileEngine<T> {
 pub mod tests {
     #![allow(clippy::undocumented_unsafe_blocks)]
     use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::FileExt;
     use std::os::unix::io::FromRawFd;
 
     use utils::kernel_version::{min_kernel_version_for_io_uring, KernelVersion};
@@ -256,6 +669,20 @@ pub mod tests {
         }
     }
 
+    // Checks that a request completed, whether it was executed or submitted, transferring
+    // `count` bytes.
+    fn assert_executed(
+        mem: &GuestMemoryMmap,
+        engine: &mut FileEngine<()>,
+        res: Result<FileEngineOk<()>, UserDataError<(), BlockIoError>>,
+        count: u32,
+    ) {
+        match res.unwrap() {
+            FileEngineOk::Executed(res) => assert_eq!(res.count, count),
+            FileEngineOk::Submitted => assert_async_execution(mem, engine, count),
+        }
+    }
+
     fn create_mem() -> GuestMemoryMmap {
         GuestMemoryMmap::from_raw_regions(&[(GuestAddress(0), MEM_LEN)], true, HugePageConfig::None)
             .unwrap()
@@ -282,7 +709,9 @@ pub mod tests {
         assert!(matches!(
             FileEngine::<PendingRequest>::from_file(
                 TempFile::new().unwrap().into_file(),
-                FileEngineType::Async
+                Path::new(""),
+                FileEngineType::Async,
+                AsyncIoConfig::default()
             ),
             Err(BlockIoError::UnsupportedEngine(FileEngineType::Async))
         ));
@@ -293,17 +722,31 @@ pub mod tests {
         // Check invalid file
         let mem = create_mem();
         let file = unsafe { File::from_raw_fd(-2) };
-        let mut engine = FileEngine::from_file(file, FileEngineType::Sync).unwrap();
+        let mut engine = FileEngine::from_file(
+            file,
+            Path::new(""),
+            FileEngineType::Sync,
+            AsyncIoConfig::default(),
+        )
+        .unwrap();
         let res = engine.read(0, &mem, GuestAddress(0), 0, ());
         assert_err!(res, BlockIoError::Sync(sync_io::SyncIoError::Seek(_e)));
         let res = engine.write(0, &mem, GuestAddress(0), 0, ());
         assert_err!(res, BlockIoError::Sync(sync_io::SyncIoError::Seek(_e)));
         let res = engine.flush(());
         assert_err!(res, BlockIoError::Sync(sync_io::SyncIoError::SyncAll(_e)));
+        let res = engine.fallocate(FallocateMode::PunchHole, 0, 0, ());
+        assert_err!(res, BlockIoError::Sync(sync_io::SyncIoError::Fallocate(_e)));
 
         // Create backing file.
         let file = TempFile::new().unwrap().into_file();
-        let mut engine = FileEngine::from_file(file, FileEngineType::Sync).unwrap();
+        let mut engine = FileEngine::from_file(
+            file,
+            Path::new(""),
+            FileEngineType::Sync,
+            AsyncIoConfig::default(),
+        )
+        .unwrap();
 
         let data = utils::rand::rand_alphanumerics(FILE_LEN as usize)
             .as_bytes()
@@ -359,6 +802,23 @@ pub mod tests {
         mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
         assert_eq!(buf, data.as_slice());
 
+        // Punch hole
+        let hole_len = 100;
+        assert_sync_execution!(
+            engine.fallocate(FallocateMode::PunchHole, offset, hole_len, ()),
+            0
+        );
+        let mem = create_mem();
+        assert_sync_execution!(
+            engine.read(0, &mem, GuestAddress(0), FILE_LEN, ()),
+            FILE_LEN
+        );
+        // Check data
+        let mut expected = data.clone();
+        expected[u64_to_usize(offset)..u64_to_usize(offset + hole_len)].fill(0);
+        mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+        assert_eq!(buf, expected);
+
         // Check other ops
         engine.flush(()).unwrap();
         engine.drain(true).unwrap();
@@ -371,11 +831,23 @@ pub mod tests {
 
         // Check invalid file
         let file = unsafe { File::from_raw_fd(-2) };
-        FileEngine::<()>::from_file(file, FileEngineType::Async).unwrap_err();
+        FileEngine::<()>::from_file(
+            file,
+            Path::new(""),
+            FileEngineType::Async,
+            AsyncIoConfig::default(),
+        )
+        .unwrap_err();
 
         // Create backing file.
         let file = TempFile::new().unwrap().into_file();
-        let mut engine = FileEngine::<()>::from_file(file, FileEngineType::Async).unwrap();
+        let mut engine = FileEngine::<()>::from_file(
+            file,
+            Path::new(""),
+            FileEngineType::Async,
+            AsyncIoConfig::default(),
+        )
+        .unwrap();
 
         let data = utils::rand::rand_alphanumerics(FILE_LEN as usize)
             .as_bytes()
@@ -421,6 +893,21 @@ pub mod tests {
         check_dirty_mem(&mem, addr, FILE_LEN);
         check_clean_mem(&mem, GuestAddress(4096), 4096);
 
+        // Punch hole
+        let hole_len = 100;
+        assert_queued!(engine.fallocate(FallocateMode::PunchHole, offset, hole_len, ()));
+        assert_async_execution(&mem, &mut engine, 0);
+        let mem = create_mem();
+        assert_queued!(engine.read(0, &mem, addr, FILE_LEN, ()));
+        assert_async_execution(&mem, &mut engine, FILE_LEN);
+        // Check data
+        let mut expected = data.clone();
+        expected[u64_to_usize(offset)..u64_to_usize(offset + hole_len)].fill(0);
+        mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+        assert_eq!(buf, expected);
+        // Punching holes does not touch guest memory
+        check_clean_mem(&mem, GuestAddress(4096), 4096);
+
         // Check other ops
         assert_queued!(engine.flush(()));
         assert_async_execution(&mem, &mut engine, 0);
@@ -428,4 +915,153 @@ pub mod tests {
         engine.drain(true).unwrap();
         engine.drain_and_flush(true).unwrap();
     }
+
+    #[test]
+    fn test_async_fixed_buffers() {
+        skip_if_io_uring_unsupported!();
+
+        let config = AsyncIoConfig {
+            fixed_buffers: true,
+            sq_poll: true,
+            sq_poll_idle_ms: 100,
+        };
+        let file = TempFile::new().unwrap().into_file();
+        let mut engine =
+            FileEngine::<()>::from_file(file, Path::new(""), FileEngineType::Async, config)
+                .unwrap();
+
+        let data = utils::rand::rand_alphanumerics(FILE_LEN as usize)
+            .as_bytes()
+            .to_vec();
+
+        // The guest memory is registered on the first request, so all the requests use the
+        // same memory.
+        let mem = create_mem();
+        let addr = GuestAddress(0);
+        mem.write(&data, addr).unwrap();
+        assert_queued!(engine.write(0, &mem, addr, FILE_LEN, ()));
+        assert_async_execution(&mem, &mut engine, FILE_LEN);
+        if let FileEngine::Async(ref engine) = engine {
+            assert_eq!(engine.fixed_buffers().unwrap().len(), 1);
+        }
+
+        // Read the data back into the second page.
+        let read_addr = GuestAddress(4096);
+        assert_queued!(engine.read(0, &mem, read_addr, FILE_LEN, ()));
+        assert_async_execution(&mem, &mut engine, FILE_LEN);
+        let mut buf = vec![0u8; FILE_LEN as usize];
+        mem.read_slice(&mut buf, read_addr).unwrap();
+        assert_eq!(buf, data);
+        check_dirty_mem(&mem, read_addr, FILE_LEN);
+
+        engine.drain(true).unwrap();
+        engine.drain_and_flush(true).unwrap();
+    }
+
+    fn check_encrypted(engine_type: FileEngineType) {
+        let key = (0..64).collect::<Vec<u8>>();
+        let cipher = XtsCipher::new(&key).unwrap();
+        let file = TempFile::new().unwrap();
+        let mut engine = FileEngine::<()>::from_file(
+            file.as_file().try_clone().unwrap(),
+            Path::new(""),
+            engine_type,
+            AsyncIoConfig::default(),
+        )
+        .unwrap();
+        engine.set_cipher(cipher.clone()).unwrap();
+
+        let data = utils::rand::rand_alphanumerics(FILE_LEN as usize)
+            .as_bytes()
+            .to_vec();
+        let offset = u64::from(FILE_LEN);
+        let addr = GuestAddress(0);
+
+        // The backing file only holds ciphertext.
+        let mem = create_mem();
+        mem.write(&data, addr).unwrap();
+        let res = engine.write(offset, &mem, addr, FILE_LEN, ());
+        assert_executed(&mem, &mut engine, res, FILE_LEN);
+        let mut buf = vec![0u8; FILE_LEN as usize];
+        file.as_file().read_exact_at(&mut buf, offset).unwrap();
+        assert_ne!(buf, data);
+        cipher.decrypt(offset, &mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        // The guest reads the plaintext.
+        let mem = create_mem();
+        let res = engine.read(offset, &mem, addr, FILE_LEN, ());
+        assert_executed(&mem, &mut engine, res, FILE_LEN);
+        mem.read_slice(&mut buf, addr).unwrap();
+        assert_eq!(buf, data);
+        check_dirty_mem(&mem, addr, FILE_LEN);
+        check_clean_mem(&mem, GuestAddress(4096), 4096);
+
+        // Discarded sectors don't need to read as zeros, unlike the sectors of write zeroes
+        // requests.
+        let res = engine.fallocate(FallocateMode::Discard, offset, u64::from(FILE_LEN), ());
+        assert_executed(&mem, &mut engine, res, 0);
+        engine
+            .fallocate(FallocateMode::PunchHole, 0, u64::from(FILE_LEN), ())
+            .unwrap_err();
+        engine
+            .fallocate(FallocateMode::ZeroRange, 0, u64::from(FILE_LEN), ())
+            .unwrap_err();
+
+        engine.drain(true).unwrap();
+        engine.drain_and_flush(true).unwrap();
+    }
+
+    #[test]
+    fn test_sync_encrypted() {
+        check_encrypted(FileEngineType::Sync);
+
+        // Only sectors can be encrypted.
+        let mut engine = FileEngine::<()>::from_file(
+            TempFile::new().unwrap().into_file(),
+            Path::new(""),
+            FileEngineType::Sync,
+            AsyncIoConfig::default(),
+        )
+        .unwrap();
+        engine
+            .set_cipher(XtsCipher::new(&(0..64).collect::<Vec<u8>>()).unwrap())
+            .unwrap();
+        let mem = create_mem();
+        let res = engine.write(100, &mem, GuestAddress(0), FILE_LEN, ());
+        assert_err!(
+            res,
+            BlockIoError::Sync(sync_io::SyncIoError::Crypt(CryptError::Unaligned(100, _)))
+        );
+    }
+
+    #[test]
+    fn test_async_encrypted() {
+        skip_if_io_uring_unsupported!();
+
+        check_encrypted(FileEngineType::Async);
+
+        // Data which can't be decrypted, here a partial sector, never reaches guest memory.
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all_at(&[0xffu8; 100], 0).unwrap();
+        let mut engine = FileEngine::<()>::from_file(
+            file.into_file(),
+            Path::new(""),
+            FileEngineType::Async,
+            AsyncIoConfig::default(),
+        )
+        .unwrap();
+        engine
+            .set_cipher(XtsCipher::new(&(0..64).collect::<Vec<u8>>()).unwrap())
+            .unwrap();
+        let mem = create_mem();
+        assert_queued!(engine.read(0, &mem, GuestAddress(0), FILE_LEN, ()));
+        if let FileEngine::Async(ref mut engine) = engine {
+            engine.drain(false).unwrap();
+            engine.pop(&mem).unwrap().unwrap().result().unwrap_err();
+        }
+        let mut buf = vec![0xffu8; FILE_LEN as usize];
+        mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+        assert_eq!(buf, vec![0u8; FILE_LEN as usize]);
+    }
 }
@@ -0,0 +1,933 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Client of the Network Block Device protocol, backing a drive with an export of an NBD server.
+//!
+//! The export is negotiated with the newstyle handshake and the requests get simple replies, the
+//! engine waiting for the reply of each request before sending the next one. Requests larger than
+//! [`NBD_MAX_REQUEST_LEN`] are split, and their data goes through a buffer since the connection
+//! can't access guest memory.
+//!
+//! When the connection to the server is lost, the request is sent again on a new connection to
+//! the same export, which is attempted up to [`NBD_MAX_ATTEMPTS`] times. Requests failing all
+//! the attempts report an error to the guest, and the next request connects again.
+//!
+//! Requests are served on the VMM thread, and block the emulation of all the devices until they
+//! complete. Serving a guest request, over all the requests it is split into and their attempts,
+//! including connecting to the server, is thus bounded by [`NBD_REQUEST_TIMEOUT`].
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use vm_memory::GuestMemoryError;
+
+use super::FallocateMode;
+use crate::devices::virtio::block::virtio::device::{NbdConfig, NbdTransport};
+use crate::logger::warn;
+use crate::vstate::memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+/// Maximum length of the data of a request sent to the server.
+pub const NBD_MAX_REQUEST_LEN: u32 = 1 << 20;
+/// Number of connections a request is attempted on before failing.
+pub const NBD_MAX_ATTEMPTS: u32 = 3;
+/// Time after which a guest request fails, including the time spent connecting to the server.
+pub const NBD_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// Maximum length of the ranges of trim and write zeroes requests.
+const NBD_MAX_RANGE_LEN: u32 = 1 << 30;
+// Servers may reject longer export names.
+const NBD_MAX_EXPORT_NAME_LEN: usize = 4096;
+
+// Handshake magic numbers and flags.
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+const NBD_IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const NBD_OLDSTYLE_MAGIC: u64 = 0x0042_0281_8612_53;
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+const NBD_EXPORT_PADDING_LEN: usize = 124;
+
+// Transmission flags of the export.
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+// Requests and replies.
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+const NBD_REQUEST_HEADER_LEN: usize = 28;
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_CMD_TRIM: u16 = 4;
+const NBD_CMD_WRITE_ZEROES: u16 = 6;
+const NBD_CMD_FLAG_NO_HOLE: u16 = 1 << 1;
+
+/// Errors associated with NBD drives.
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum NbdError {
+    /// The address of a TCP server must be an IP address and a port: {0}
+    InvalidAddress(String),
+    /// Cannot connect to the NBD server: {0}
+    Connect(std::io::Error),
+    /// Cannot communicate with the NBD server: {0}
+    Io(std::io::Error),
+    /// The NBD server did not answer in time.
+    Timeout,
+    /// The server only supports the oldstyle handshake.
+    UnsupportedHandshake,
+    /// The server sent the invalid magic number {0:#x}.
+    InvalidMagic(u64),
+    /// The export name is longer than 4096 bytes.
+    ExportNameTooLong,
+    /// The server refused the export: {0}
+    ExportName(std::io::Error),
+    /// The export is read-only, while the drive is not.
+    ReadOnlyExport,
+    /// The export is {0} bytes long after reconnecting, instead of {1} bytes.
+    SizeMismatch(u64, u64),
+    /// The server replied to the unknown request {0}.
+    UnexpectedReply(u64),
+    /// The server failed the request with error {0}.
+    Request(u32),
+    /// NBD drives are not backed by a host file.
+    NoFile,
+    /// Cannot access guest memory: {0}
+    GuestMemory(GuestMemoryError),
+}
+
+impl NbdError {
+    // Whether the error leaves the connection unusable, so that the request can be sent again on
+    // a new connection.
+    fn is_connection_err(&self) -> bool {
+        matches!(
+            self,
+            NbdError::Connect(_)
+                | NbdError::Io(_)
+                | NbdError::Timeout
+                | NbdError::InvalidMagic(_)
+                | NbdError::UnexpectedReply(_)
+        )
+    }
+}
+
+#[derive(Debug)]
+enum NbdStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+// Returns the time left until `deadline`.
+fn time_left(deadline: Instant) -> Result<Duration, NbdError> {
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    if timeout.is_zero() {
+        return Err(NbdError::Timeout);
+    }
+    Ok(timeout)
+}
+
+// Connects to the Unix socket at `path`, waiting at most `timeout` for the server to accept the
+// connection when its backlog is full.
+fn connect_unix(path: &str, timeout: Duration) -> io::Result<UnixStream> {
+    // SAFETY: `sockaddr_un` is a plain C struct, for which zeroes are valid.
+    let mut address: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    // The path must fit in the address with its terminating null byte.
+    if path.len() >= address.sun_path.len() || path.as_bytes().contains(&0) {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+    address.sun_family = libc::sa_family_t::try_from(libc::AF_UNIX).unwrap();
+    for (dst, src) in address.sun_path.iter_mut().zip(path.as_bytes()) {
+        *dst = libc::c_char::from_ne_bytes([*src]);
+    }
+
+    // SAFETY: Creating a socket has no memory safety implications, and the result is checked.
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: The socket was just created and is owned by nothing else.
+    let stream = unsafe { UnixStream::from_raw_fd(fd) };
+    // Connecting to a Unix socket waits for room in the backlog of the server for at most the
+    // send timeout of the socket.
+    stream.set_write_timeout(Some(timeout))?;
+    // SAFETY: `address` is a valid Unix socket address, of the given length.
+    let ret = unsafe {
+        libc::connect(
+            stream.as_raw_fd(),
+            std::ptr::addr_of!(address).cast(),
+            libc::socklen_t::try_from(std::mem::size_of::<libc::sockaddr_un>()).unwrap(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stream)
+}
+
+impl NbdStream {
+    fn connect(config: &NbdConfig, timeout: Duration) -> Result<Self, NbdError> {
+        let stream = match config.transport {
+            NbdTransport::Unix => {
+                NbdStream::Unix(connect_unix(&config.address, timeout).map_err(NbdError::Connect)?)
+            }
+            NbdTransport::Tcp => {
+                // Resolving host names is left to the user.
+                let address = config
+                    .address
+                    .parse::<SocketAddr>()
+                    .map_err(|_| NbdError::InvalidAddress(config.address.clone()))?;
+                NbdStream::Tcp(
+                    TcpStream::connect_timeout(&address, timeout).map_err(NbdError::Connect)?,
+                )
+            }
+        };
+        stream.set_timeout(timeout).map_err(NbdError::Connect)?;
+        Ok(stream)
+    }
+
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        match self {
+            NbdStream::Unix(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))
+            }
+            NbdStream::Tcp(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))
+            }
+        }
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        match self {
+            NbdStream::Unix(stream) => stream.read_exact(buf),
+            NbdStream::Tcp(stream) => stream.read_exact(buf),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            NbdStream::Unix(stream) => stream.write_all(buf),
+            NbdStream::Tcp(stream) => stream.write_all(buf),
+        }
+    }
+
+    fn read_bytes<const N: usize>(&mut self) -> Result<[u8; N], NbdError> {
+        let mut buf = [0u8; N];
+        self.read_exact(&mut buf).map_err(NbdError::Io)?;
+        Ok(buf)
+    }
+
+    // Negotiates the export with the server, returning its size and transmission flags.
+    fn handshake(&mut self, export_name: &str) -> Result<(u64, u16), NbdError> {
+        let magic = u64::from_be_bytes(self.read_bytes()?);
+        if magic != NBD_MAGIC {
+            return Err(NbdError::InvalidMagic(magic));
+        }
+        match u64::from_be_bytes(self.read_bytes()?) {
+            NBD_IHAVEOPT => (),
+            NBD_OLDSTYLE_MAGIC => return Err(NbdError::UnsupportedHandshake),
+            magic => return Err(NbdError::InvalidMagic(magic)),
+        }
+
+        // Only the flags supported by the server can be sent back.
+        let handshake_flags = u16::from_be_bytes(self.read_bytes()?);
+        let mut client_flags = 0;
+        if handshake_flags & NBD_FLAG_FIXED_NEWSTYLE != 0 {
+            client_flags |= NBD_FLAG_C_FIXED_NEWSTYLE;
+        }
+        let no_zeroes = handshake_flags & NBD_FLAG_NO_ZEROES != 0;
+        if no_zeroes {
+            client_flags |= NBD_FLAG_C_NO_ZEROES;
+        }
+
+        let export_name_len =
+            u32::try_from(export_name.len()).map_err(|_| NbdError::ExportNameTooLong)?;
+        let mut message = Vec::with_capacity(20 + export_name.len());
+        message.extend_from_slice(&client_flags.to_be_bytes());
+        message.extend_from_slice(&NBD_IHAVEOPT.to_be_bytes());
+        message.extend_from_slice(&NBD_OPT_EXPORT_NAME.to_be_bytes());
+        message.extend_from_slice(&export_name_len.to_be_bytes());
+        message.extend_from_slice(export_name.as_bytes());
+        self.write_all(&message).map_err(NbdError::Io)?;
+
+        // Servers close the connection when they don't have the export.
+        let mut export = [0u8; 10];
+        self.read_exact(&mut export).map_err(NbdError::ExportName)?;
+        if !no_zeroes {
+            let mut padding = [0u8; NBD_EXPORT_PADDING_LEN];
+            self.read_exact(&mut padding).map_err(NbdError::Io)?;
+        }
+        let (size, flags) = export.split_at(8);
+        Ok((
+            u64::from_be_bytes(size.try_into().unwrap()),
+            u16::from_be_bytes(flags.try_into().unwrap()),
+        ))
+    }
+}
+
+fn request_header(command: u16, flags: u16, handle: u64, offset: u64, len: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(NBD_REQUEST_HEADER_LEN);
+    header.extend_from_slice(&NBD_REQUEST_MAGIC.to_be_bytes());
+    header.extend_from_slice(&flags.to_be_bytes());
+    header.extend_from_slice(&command.to_be_bytes());
+    header.extend_from_slice(&handle.to_be_bytes());
+    header.extend_from_slice(&offset.to_be_bytes());
+    header.extend_from_slice(&len.to_be_bytes());
+    header
+}
+
+/// Engine serving the requests of a drive from an export of an NBD server.
+#[derive(Debug)]
+pub struct NbdFileEngine {
+    config: NbdConfig,
+    is_read_only: bool,
+    // Connection to the server, unless it was lost.
+    stream: Option<NbdStream>,
+    size: u64,
+    flags: u16,
+    next_handle: u64,
+}
+
+impl NbdFileEngine {
+    /// Connects to the server and negotiates the export of `config`, which must be writable
+    /// unless the drive is read-only.
+    pub fn new(config: NbdConfig, is_read_only: bool) -> Result<Self, NbdError> {
+        if config.export_name.len() > NBD_MAX_EXPORT_NAME_LEN {
+            return Err(NbdError::ExportNameTooLong);
+        }
+        let mut stream = NbdStream::connect(&config, NBD_REQUEST_TIMEOUT)?;
+        let (size, flags) = stream.handshake(&config.export_name)?;
+        if !is_read_only && flags & NBD_FLAG_READ_ONLY != 0 {
+            return Err(NbdError::ReadOnlyExport);
+        }
+        Ok(Self {
+            config,
+            is_read_only,
+            stream: Some(stream),
+            size,
+            flags,
+            next_handle: 0,
+        })
+    }
+
+    /// Size of the export.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // Connects to the server again, which must still serve the same export.
+    fn reconnect(&mut self, timeout: Duration) -> Result<NbdStream, NbdError> {
+        let mut stream = NbdStream::connect(&self.config, timeout)?;
+        let (size, flags) = stream.handshake(&self.config.export_name)?;
+        if size != self.size {
+            return Err(NbdError::SizeMismatch(size, self.size));
+        }
+        if !self.is_read_only && flags & NBD_FLAG_READ_ONLY != 0 {
+            return Err(NbdError::ReadOnlyExport);
+        }
+        self.flags = flags;
+        Ok(stream)
+    }
+
+    // Returns the connection to the server, connecting again if it was lost, with its timeouts
+    // expiring at `deadline`.
+    fn stream(&mut self, deadline: Instant) -> Result<&mut NbdStream, NbdError> {
+        let stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => self.reconnect(time_left(deadline)?)?,
+        };
+        stream
+            .set_timeout(time_left(deadline)?)
+            .map_err(NbdError::Io)?;
+        Ok(self.stream.insert(stream))
+    }
+
+    // Sends a request and waits for its reply until `deadline`. The data of write requests is
+    // taken from `buf`, and the data of read requests is stored in it.
+    fn exchange(
+        &mut self,
+        command: u16,
+        flags: u16,
+        offset: u64,
+        len: u32,
+        buf: &mut [u8],
+        deadline: Instant,
+    ) -> Result<(), NbdError> {
+        let handle = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(1);
+
+        let stream = self.stream(deadline)?;
+        let mut message = request_header(command, flags, handle, offset, len);
+        if command == NBD_CMD_WRITE {
+            message.extend_from_slice(buf);
+        }
+        stream.write_all(&message).map_err(NbdError::Io)?;
+
+        let magic = u32::from_be_bytes(stream.read_bytes()?);
+        if magic != NBD_SIMPLE_REPLY_MAGIC {
+            return Err(NbdError::InvalidMagic(u64::from(magic)));
+        }
+        let error = u32::from_be_bytes(stream.read_bytes()?);
+        let reply_handle = u64::from_be_bytes(stream.read_bytes()?);
+        if reply_handle != handle {
+            return Err(NbdError::UnexpectedReply(reply_handle));
+        }
+        if error != 0 {
+            return Err(NbdError::Request(error));
+        }
+        if command == NBD_CMD_READ {
+            stream.read_exact(buf).map_err(NbdError::Io)?;
+        }
+        Ok(())
+    }
+
+    // Sends a request, on new connections to the server if the current one is lost, until
+    // `deadline`. The requests split from a guest request share its deadline.
+    fn transmit(
+        &mut self,
+        command: u16,
+        flags: u16,
+        offset: u64,
+        len: u32,
+        buf: &mut [u8],
+        deadline: Instant,
+    ) -> Result<(), NbdError> {
+        let mut attempt = 1;
+        loop {
+            match self.exchange(command, flags, offset, len, buf, deadline) {
+                Err(err) if err.is_connection_err() => {
+                    self.stream = None;
+                    if attempt == NBD_MAX_ATTEMPTS || Instant::now() >= deadline {
+                        return Err(err);
+                    }
+                    warn!(
+                        "Lost the connection to the NBD server: {}. Reconnecting.",
+                        err
+                    );
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    pub fn read(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32, NbdError> {
+        let deadline = Instant::now() + NBD_REQUEST_TIMEOUT;
+        let mut buf = vec![0u8; count.min(NBD_MAX_REQUEST_LEN) as usize];
+        let mut done = 0;
+        while done < count {
+            let len = (count - done).min(NBD_MAX_REQUEST_LEN);
+            let chunk = &mut buf[..len as usize];
+            self.transmit(
+                NBD_CMD_READ,
+                0,
+                offset + u64::from(done),
+                len,
+                chunk,
+                deadline,
+            )?;
+            mem.write_slice(chunk, GuestAddress(addr.0 + u64::from(done)))
+                .map_err(NbdError::GuestMemory)?;
+            done += len;
+        }
+        Ok(count)
+    }
+
+    pub fn write(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32, NbdError> {
+        let deadline = Instant::now() + NBD_REQUEST_TIMEOUT;
+        let mut buf = vec![0u8; count.min(NBD_MAX_REQUEST_LEN) as usize];
+        let mut done = 0;
+        while done < count {
+            let len = (count - done).min(NBD_MAX_REQUEST_LEN);
+            let chunk = &mut buf[..len as usize];
+            mem.read_slice(chunk, GuestAddress(addr.0 + u64::from(done)))
+                .map_err(NbdError::GuestMemory)?;
+            self.transmit(
+                NBD_CMD_WRITE,
+                0,
+                offset + u64::from(done),
+                len,
+                chunk,
+                deadline,
+            )?;
+            done += len;
+        }
+        Ok(count)
+    }
+
+    pub fn flush(&mut self) -> Result<(), NbdError> {
+        // Servers which don't support flushing write their data before replying.
+        if self.flags & NBD_FLAG_SEND_FLUSH == 0 {
+            return Ok(());
+        }
+        let deadline = Instant::now() + NBD_REQUEST_TIMEOUT;
+        self.transmit(NBD_CMD_FLUSH, 0, 0, 0, &mut [], deadline)
+    }
+
+    pub fn fallocate(
+        &mut self,
+        mode: FallocateMode,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), NbdError> {
+        let deadline = Instant::now() + NBD_REQUEST_TIMEOUT;
+        let (command, flags) = match mode {
+            // Discarding data is only a hint, so servers which can't trim are left untouched.
+            FallocateMode::Discard if self.flags & NBD_FLAG_SEND_TRIM == 0 => return Ok(()),
+            FallocateMode::Discard => (NBD_CMD_TRIM, 0),
+            _ if self.flags & NBD_FLAG_SEND_WRITE_ZEROES == 0 => {
+                return self.write_zeroes(offset, len, deadline)
+            }
+            FallocateMode::PunchHole => (NBD_CMD_WRITE_ZEROES, 0),
+            FallocateMode::ZeroRange => (NBD_CMD_WRITE_ZEROES, NBD_CMD_FLAG_NO_HOLE),
+        };
+        let mut done = 0;
+        while done < len {
+            let chunk_len = u32::try_from(len - done)
+                .map_or(NBD_MAX_RANGE_LEN, |len| len.min(NBD_MAX_RANGE_LEN));
+            self.transmit(command, flags, offset + done, chunk_len, &mut [], deadline)?;
+            done += u64::from(chunk_len);
+        }
+        Ok(())
+    }
+
+    // Zeroes a range of the export through write requests, until `deadline`.
+    fn write_zeroes(&mut self, offset: u64, len: u64, deadline: Instant) -> Result<(), NbdError> {
+        let max_len =
+            u32::try_from(len).map_or(NBD_MAX_REQUEST_LEN, |len| len.min(NBD_MAX_REQUEST_LEN));
+        let mut buf = vec![0u8; max_len as usize];
+        let mut done = 0;
+        while done < len {
+            let chunk_len = u32::try_from(len - done).map_or(max_len, |len| len.min(max_len));
+            let chunk = &mut buf[..chunk_len as usize];
+            self.transmit(NBD_CMD_WRITE, 0, offset + done, chunk_len, chunk, deadline)?;
+            done += u64::from(chunk_len);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for NbdFileEngine {
+    fn drop(&mut self) {
+        // Let the server know the connection is closed on purpose. Disconnect requests get no
+        // reply.
+        if let Some(stream) = self.stream.as_mut() {
+            let header = request_header(NBD_CMD_DISC, 0, self.next_handle, 0, 0);
+            if let Err(err) = stream.write_all(&header) {
+                warn!("Cannot disconnect from the NBD server: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::thread::JoinHandle;
+
+    use utils::tempfile::TempFile;
+    use utils::u64_to_usize;
+
+    use super::*;
+    use crate::vmm_config::machine_config::HugePageConfig;
+    use crate::vstate::memory::GuestMemoryExtension;
+
+    const MEM_LEN: usize = 0x4000;
+    const EINVAL: u32 = 22;
+    const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+
+    /// Transmission flags of a writable export supporting all the requests.
+    pub(crate) const NBD_TEST_EXPORT_FLAGS: u16 =
+        NBD_FLAG_HAS_FLAGS | NBD_FLAG_SEND_FLUSH | NBD_FLAG_SEND_TRIM | NBD_FLAG_SEND_WRITE_ZEROES;
+
+    /// NBD server serving a disk held in memory as its default export, on a Unix socket.
+    #[derive(Debug)]
+    pub(crate) struct TestServer {
+        socket: TempFile,
+        pub(crate) disk: Arc<Mutex<Vec<u8>>>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl TestServer {
+        /// Serves `disk` to `connections` clients in turn, with the transmission flags `flags`.
+        /// Each connection is dropped after serving `requests` requests, if any.
+        pub(crate) fn new(
+            disk: Vec<u8>,
+            flags: u16,
+            connections: usize,
+            requests: Option<usize>,
+        ) -> Self {
+            Self::spawn(disk, flags, connections, requests, Duration::ZERO)
+        }
+
+        /// Serves `disk` to a single client, waiting `reply_delay` before replying to each
+        /// request.
+        pub(crate) fn with_reply_delay(disk: Vec<u8>, flags: u16, reply_delay: Duration) -> Self {
+            Self::spawn(disk, flags, 1, None, reply_delay)
+        }
+
+        fn spawn(
+            disk: Vec<u8>,
+            flags: u16,
+            connections: usize,
+            requests: Option<usize>,
+            reply_delay: Duration,
+        ) -> Self {
+            let socket = TempFile::new().unwrap();
+            std::fs::remove_file(socket.as_path()).unwrap();
+            let listener = UnixListener::bind(socket.as_path()).unwrap();
+            let disk = Arc::new(Mutex::new(disk));
+
+            let served_disk = disk.clone();
+            let thread = std::thread::spawn(move || {
+                for _ in 0..connections {
+                    let (stream, _) = listener.accept().unwrap();
+                    serve(stream, &served_disk, flags, requests, reply_delay);
+                }
+            });
+            Self {
+                socket,
+                disk,
+                thread: Some(thread),
+            }
+        }
+
+        pub(crate) fn config(&self) -> NbdConfig {
+            NbdConfig {
+                transport: NbdTransport::Unix,
+                address: self.path().to_str().unwrap().to_string(),
+                export_name: String::new(),
+            }
+        }
+
+        fn path(&self) -> PathBuf {
+            self.socket.as_path().to_path_buf()
+        }
+
+        /// Waits for the clients of the server to disconnect.
+        pub(crate) fn join(&mut self) {
+            self.thread.take().unwrap().join().unwrap();
+        }
+    }
+
+    fn serve(
+        mut stream: UnixStream,
+        disk: &Mutex<Vec<u8>>,
+        flags: u16,
+        requests: Option<usize>,
+        reply_delay: Duration,
+    ) {
+        let read_u32 = |stream: &mut UnixStream| {
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).map(|_| u32::from_be_bytes(buf))
+        };
+
+        stream.write_all(&NBD_MAGIC.to_be_bytes()).unwrap();
+        stream.write_all(&NBD_IHAVEOPT.to_be_bytes()).unwrap();
+        stream
+            .write_all(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes())
+            .unwrap();
+        let client_flags = read_u32(&mut stream).unwrap();
+        let mut option = [0u8; 12];
+        stream.read_exact(&mut option).unwrap();
+        assert_eq!(option[..8], NBD_IHAVEOPT.to_be_bytes());
+        assert_eq!(option[8..], NBD_OPT_EXPORT_NAME.to_be_bytes());
+        let mut export_name = vec![0u8; read_u32(&mut stream).unwrap() as usize];
+        stream.read_exact(&mut export_name).unwrap();
+        assert!(export_name.is_empty());
+        let size = disk.lock().unwrap().len() as u64;
+        stream.write_all(&size.to_be_bytes()).unwrap();
+        stream.write_all(&flags.to_be_bytes()).unwrap();
+        if client_flags & NBD_FLAG_C_NO_ZEROES == 0 {
+            stream.write_all(&[0u8; NBD_EXPORT_PADDING_LEN]).unwrap();
+        }
+
+        let mut served = 0;
+        loop {
+            let mut header = [0u8; NBD_REQUEST_HEADER_LEN];
+            if stream.read_exact(&mut header).is_err() {
+                return;
+            }
+            if requests == Some(served) {
+                return;
+            }
+            served += 1;
+
+            assert_eq!(header[..4], NBD_REQUEST_MAGIC.to_be_bytes());
+            let command = u16::from_be_bytes(header[6..8].try_into().unwrap());
+            let handle = &header[8..16];
+            let offset = u64_to_usize(u64::from_be_bytes(header[16..24].try_into().unwrap()));
+            let len = u32::from_be_bytes(header[24..].try_into().unwrap()) as usize;
+            if command == NBD_CMD_DISC {
+                return;
+            }
+
+            let mut disk = disk.lock().unwrap();
+            let mut data = vec![0u8; len];
+            if command == NBD_CMD_WRITE {
+                stream.read_exact(&mut data).unwrap();
+            }
+            let error = if offset + len > disk.len() {
+                EINVAL
+            } else {
+                match command {
+                    NBD_CMD_READ => data.copy_from_slice(&disk[offset..offset + len]),
+                    NBD_CMD_WRITE => disk[offset..offset + len].copy_from_slice(&data),
+                    NBD_CMD_TRIM | NBD_CMD_WRITE_ZEROES => disk[offset..offset + len].fill(0),
+                    _ => (),
+                }
+                0
+            };
+
+            drop(disk);
+            std::thread::sleep(reply_delay);
+
+            let mut reply = Vec::new();
+            reply.extend_from_slice(&NBD_SIMPLE_REPLY_MAGIC.to_be_bytes());
+            reply.extend_from_slice(&error.to_be_bytes());
+            reply.extend_from_slice(handle);
+            if command == NBD_CMD_READ && error == 0 {
+                reply.extend_from_slice(&data);
+            }
+            // The client gives up on requests which take too long.
+            if stream.write_all(&reply).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn create_mem() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_raw_regions(
+            &[(GuestAddress(0), MEM_LEN)],
+            false,
+            HugePageConfig::None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_nbd_io() {
+        let data = utils::rand::rand_alphanumerics(0x2000).as_bytes().to_vec();
+        let mut server = TestServer::new(vec![0u8; 0x2000], NBD_TEST_EXPORT_FLAGS, 1, None);
+        let mut engine = NbdFileEngine::new(server.config(), false).unwrap();
+        assert_eq!(engine.size(), 0x2000);
+
+        // Write the data and read it back.
+        let mem = create_mem();
+        mem.write_slice(&data, GuestAddress(0)).unwrap();
+        assert_eq!(
+            engine.write(0, &mem, GuestAddress(0), 0x2000).unwrap(),
+            0x2000
+        );
+        assert_eq!(*server.disk.lock().unwrap(), data);
+        assert_eq!(
+            engine
+                .read(0x1000, &mem, GuestAddress(0x2000), 0x800)
+                .unwrap(),
+            0x800
+        );
+        let mut buf = vec![0u8; 0x800];
+        mem.read_slice(&mut buf, GuestAddress(0x2000)).unwrap();
+        assert_eq!(buf, data[0x1000..0x1800]);
+        engine.flush().unwrap();
+
+        // Zero some ranges.
+        engine.fallocate(FallocateMode::Discard, 0, 0x200).unwrap();
+        engine
+            .fallocate(FallocateMode::ZeroRange, 0x1000, 0x200)
+            .unwrap();
+        let mut expected = data.clone();
+        expected[..0x200].fill(0);
+        expected[0x1000..0x1200].fill(0);
+        assert_eq!(*server.disk.lock().unwrap(), expected);
+
+        // Requests beyond the end of the export are failed by the server.
+        assert!(matches!(
+            engine.read(0x1f00, &mem, GuestAddress(0), 0x200),
+            Err(NbdError::Request(EINVAL))
+        ));
+        // Guest memory errors don't involve the server.
+        assert!(matches!(
+            engine.write(0, &mem, GuestAddress(MEM_LEN as u64), 0x200),
+            Err(NbdError::GuestMemory(_))
+        ));
+
+        // The engine disconnects when dropped.
+        drop(engine);
+        server.join();
+    }
+
+    #[test]
+    fn test_nbd_write_zeroes_fallback() {
+        let data = vec![0xffu8; 0x1000];
+        let mut server = TestServer::new(data, NBD_FLAG_HAS_FLAGS, 1, None);
+        let mut engine = NbdFileEngine::new(server.config(), false).unwrap();
+
+        // Zeroes are written when the server doesn't support write zeroes requests, while
+        // discard requests are ignored when it doesn't support trim requests.
+        engine
+            .fallocate(FallocateMode::PunchHole, 0, 0x200)
+            .unwrap();
+        engine
+            .fallocate(FallocateMode::Discard, 0x200, 0x200)
+            .unwrap();
+        let mut expected = vec![0xffu8; 0x1000];
+        expected[..0x200].fill(0);
+        assert_eq!(*server.disk.lock().unwrap(), expected);
+
+        drop(engine);
+        server.join();
+    }
+
+    #[test]
+    fn test_nbd_read_only_export() {
+        let flags = NBD_FLAG_HAS_FLAGS | NBD_FLAG_READ_ONLY;
+        let mut server = TestServer::new(vec![0u8; 0x1000], flags, 2, None);
+        assert!(matches!(
+            NbdFileEngine::new(server.config(), false),
+            Err(NbdError::ReadOnlyExport)
+        ));
+        let engine = NbdFileEngine::new(server.config(), true).unwrap();
+        drop(engine);
+        server.join();
+    }
+
+    #[test]
+    fn test_nbd_reconnect() {
+        let data = utils::rand::rand_alphanumerics(0x1000).as_bytes().to_vec();
+        // Each connection serves a single request.
+        let mut server = TestServer::new(
+            data.clone(),
+            NBD_TEST_EXPORT_FLAGS,
+            NBD_MAX_ATTEMPTS as usize,
+            Some(1),
+        );
+        let mut engine = NbdFileEngine::new(server.config(), false).unwrap();
+        let mem = create_mem();
+
+        // The connection is lost after every request, so requests are sent again on a new
+        // connection.
+        for offset in [0, 0x800] {
+            engine
+                .read(offset, &mem, GuestAddress(offset), 0x800)
+                .unwrap();
+        }
+        let mut buf = vec![0u8; 0x1000];
+        mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+        assert_eq!(buf, data);
+
+        engine.flush().unwrap();
+
+        // The requests fail once the server goes away.
+        engine.flush().unwrap_err();
+        server.join();
+        assert!(matches!(engine.flush(), Err(NbdError::Connect(_))));
+    }
+
+    #[test]
+    fn test_nbd_timeout() {
+        // The server never answers the connections waiting in its backlog.
+        let socket = TempFile::new().unwrap();
+        std::fs::remove_file(socket.as_path()).unwrap();
+        let _listener = UnixListener::bind(socket.as_path()).unwrap();
+        let config = NbdConfig {
+            transport: NbdTransport::Unix,
+            address: socket.as_path().to_str().unwrap().to_string(),
+            export_name: String::new(),
+        };
+
+        let start = Instant::now();
+        assert!(matches!(
+            NbdFileEngine::new(config, true),
+            Err(NbdError::Io(_))
+        ));
+        assert!(start.elapsed() < NBD_REQUEST_TIMEOUT + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_nbd_split_request_timeout() {
+        // Every request split from a guest request is answered, but too late for all of them to
+        // complete in time.
+        let len = 3 * NBD_MAX_REQUEST_LEN;
+        let server = TestServer::with_reply_delay(
+            vec![0xffu8; len as usize],
+            NBD_FLAG_HAS_FLAGS,
+            NBD_REQUEST_TIMEOUT * 2 / 5,
+        );
+        let mut engine = NbdFileEngine::new(server.config(), false).unwrap();
+
+        // Zeroing the export takes a write request per chunk, which share the same deadline.
+        let start = Instant::now();
+        let res = engine.fallocate(FallocateMode::ZeroRange, 0, u64::from(len));
+        assert!(
+            matches!(res, Err(NbdError::Io(_)) | Err(NbdError::Timeout)),
+            "{:?}",
+            res
+        );
+        assert!(start.elapsed() < NBD_REQUEST_TIMEOUT + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_nbd_invalid_config() {
+        let config = NbdConfig {
+            transport: NbdTransport::Tcp,
+            address: "localhost:10809".to_string(),
+            export_name: String::new(),
+        };
+        assert!(matches!(
+            NbdFileEngine::new(config, true),
+            Err(NbdError::InvalidAddress(_))
+        ));
+
+        let config = NbdConfig {
+            transport: NbdTransport::Unix,
+            address: "/invalid/path".to_string(),
+            export_name: String::new(),
+        };
+        assert!(matches!(
+            NbdFileEngine::new(config, true),
+            Err(NbdError::Connect(_))
+        ));
+
+        let config = NbdConfig {
+            transport: NbdTransport::Unix,
+            address: "a".repeat(108),
+            export_name: String::new(),
+        };
+        assert!(matches!(
+            NbdFileEngine::new(config, true),
+            Err(NbdError::Connect(err)) if err.kind() == io::ErrorKind::InvalidInput
+        ));
+
+        let config = NbdConfig {
+            transport: NbdTransport::Unix,
+            address: "/invalid/path".to_string(),
+            export_name: "a".repeat(NBD_MAX_EXPORT_NAME_LEN + 1),
+        };
+        assert!(matches!(
+            NbdFileEngine::new(config, true),
+            Err(NbdError::ExportNameTooLong)
+        ));
+    }
+}
@@ -0,0 +1,471 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Copy-on-write overlay of a read-only base image.
+//!
+//! The disk is split in blocks of [`OVERLAY_BLOCK_SIZE`] bytes. Blocks written by the guest are
+//! stored at the same offset of a sparse overlay file, while the other blocks are read from the
+//! base image, which is never modified. Which blocks are held by the overlay file is recorded in
+//! an allocation bitmap, persisted in its own file so that the overlay can be reopened.
+
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+
+use utils::u64_to_usize;
+use vm_memory::{GuestMemoryError, ReadVolatile, WriteVolatile};
+
+use super::FallocateMode;
+use crate::vstate::memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+
+/// Granularity at which blocks are copied to the overlay file.
+pub const OVERLAY_BLOCK_SIZE: u64 = 4096;
+
+/// "FCOVERLY"
+const BITMAP_MAGIC: u64 = 0x594c_5245_564f_4346;
+// The bitmap file starts with a header made of the magic, the block size and the size of the
+// base image, all little endian, followed by one bit per block.
+const BITMAP_HEADER_SIZE: usize = 24;
+
+/// Errors associated with copy-on-write overlays.
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum OverlayError {
+    /// Cannot access the overlay files: {0}
+    Io(std::io::Error),
+    /// The allocation bitmap file is invalid.
+    InvalidBitmap,
+    /// The overlay was created with {0} bytes blocks instead of {1} bytes blocks.
+    BlockSizeMismatch(u64, u64),
+    /// The overlay was created for a base image of {0} bytes, not {1} bytes.
+    SizeMismatch(u64, u64),
+    /// Cannot access guest memory: {0}
+    GuestMemory(GuestMemoryError),
+}
+
+impl From<std::io::Error> for OverlayError {
+    fn from(err: std::io::Error) -> Self {
+        OverlayError::Io(err)
+    }
+}
+
+/// File engine reading the blocks of the disk either from the base image or from the overlay
+/// file, and writing them to the overlay file.
+#[derive(Debug)]
+pub struct OverlayFileEngine {
+    base: File,
+    overlay: File,
+    bitmap_file: File,
+    // One bit per block, set for the blocks held by the overlay file.
+    bitmap: Vec<u8>,
+    disk_size: u64,
+    // Holds the blocks being copied from the base image.
+    buffer: Vec<u8>,
+}
+
+impl OverlayFileEngine {
+    /// Opens the overlay of `base` stored in `overlay` and `bitmap_file`. An empty bitmap file
+    /// creates a new overlay, in which all blocks are read from the base image.
+    pub fn new(base: File, overlay: File, bitmap_file: File) -> Result<Self, OverlayError> {
+        let disk_size = base.metadata()?.len();
+        let bitmap_len = u64_to_usize(disk_size.div_ceil(OVERLAY_BLOCK_SIZE).div_ceil(8));
+        let mut bitmap = vec![0u8; bitmap_len];
+
+        if bitmap_file.metadata()?.len() == 0 {
+            let mut header = [0u8; BITMAP_HEADER_SIZE];
+            header[0..8].copy_from_slice(&BITMAP_MAGIC.to_le_bytes());
+            header[8..16].copy_from_slice(&OVERLAY_BLOCK_SIZE.to_le_bytes());
+            header[16..24].copy_from_slice(&disk_size.to_le_bytes());
+            bitmap_file.write_all_at(&bitmap, BITMAP_HEADER_SIZE as u64)?;
+            bitmap_file.write_all_at(&header, 0)?;
+            bitmap_file.sync_all()?;
+        } else {
+            let mut header = [0u8; BITMAP_HEADER_SIZE];
+            bitmap_file
+                .read_exact_at(&mut header, 0)
+                .map_err(|_| OverlayError::InvalidBitmap)?;
+            let field =
+                |offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap());
+            if field(0) != BITMAP_MAGIC {
+                return Err(OverlayError::InvalidBitmap);
+            }
+            if field(8) != OVERLAY_BLOCK_SIZE {
+                return Err(OverlayError::BlockSizeMismatch(
+                    field(8),
+                    OVERLAY_BLOCK_SIZE,
+                ));
+            }
+            if field(16) != disk_size {
+                return Err(OverlayError::SizeMismatch(field(16), disk_size));
+            }
+            bitmap_file
+                .read_exact_at(&mut bitmap, BITMAP_HEADER_SIZE as u64)
+                .map_err(|_| OverlayError::InvalidBitmap)?;
+        }
+        // Blocks zeroed in the overlay file read as holes rather than past its end.
+        if overlay.metadata()?.len() < disk_size {
+            overlay.set_len(disk_size)?;
+        }
+
+        Ok(OverlayFileEngine {
+            base,
+            overlay,
+            bitmap_file,
+            bitmap,
+            disk_size,
+            buffer: vec![0u8; u64_to_usize(OVERLAY_BLOCK_SIZE)],
+        })
+    }
+
+    /// Replaces the base image, which must have the same size as the previous one.
+    pub fn update_base(&mut self, base: File) -> Result<(), OverlayError> {
+        let size = base.metadata()?.len();
+        if size != self.disk_size {
+            return Err(OverlayError::SizeMismatch(self.disk_size, size));
+        }
+        self.base = base;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn file(&self) -> &File {
+        &self.overlay
+    }
+
+    fn is_allocated(&self, block: u64) -> bool {
+        self.bitmap[u64_to_usize(block / 8)] & (1 << (block % 8)) != 0
+    }
+
+    // Marks the blocks from `first` to `last` as held by the overlay file, in memory only.
+    fn set_allocated(&mut self, first: u64, last: u64) {
+        for block in first..=last {
+            self.bitmap[u64_to_usize(block / 8)] |= 1 << (block % 8);
+        }
+    }
+
+    // Writes the part of the bitmap holding the blocks from `first` to `last` to the bitmap file.
+    fn persist_bitmap(&self, first: u64, last: u64) -> Result<(), OverlayError> {
+        let start = u64_to_usize(first / 8);
+        let end = u64_to_usize(last / 8) + 1;
+        Ok(self.bitmap_file.write_all_at(
+            &self.bitmap[start..end],
+            BITMAP_HEADER_SIZE as u64 + first / 8,
+        )?)
+    }
+
+    // Copies the block from the base image to the overlay file, along with the bytes of `data`
+    // starting at `data_offset` in the block.
+    fn copy_block(
+        &mut self,
+        block: u64,
+        data_offset: usize,
+        data: impl FnOnce(&mut [u8]) -> Result<(), OverlayError>,
+    ) -> Result<(), OverlayError> {
+        let block_start = block * OVERLAY_BLOCK_SIZE;
+        // The last block of the disk may be shorter.
+        let len = u64_to_usize(OVERLAY_BLOCK_SIZE.min(self.disk_size - block_start));
+        let buffer = &mut self.buffer[..len];
+        self.base.read_exact_at(buffer, block_start)?;
+        data(&mut buffer[data_offset..])?;
+        Ok(self.overlay.write_all_at(buffer, block_start)?)
+    }
+
+    pub fn read(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32, OverlayError> {
+        let end = offset + u64::from(count);
+        let mut pos = offset;
+        while pos < end {
+            let allocated = self.is_allocated(pos / OVERLAY_BLOCK_SIZE);
+            // Read the following blocks held by the same file at once.
+            let mut run_end = (pos / OVERLAY_BLOCK_SIZE + 1) * OVERLAY_BLOCK_SIZE;
+            while run_end < end && self.is_allocated(run_end / OVERLAY_BLOCK_SIZE) == allocated {
+                run_end += OVERLAY_BLOCK_SIZE;
+            }
+            let run_end = run_end.min(end);
+
+            let file = if allocated {
+                &mut self.overlay
+            } else {
+                &mut self.base
+            };
+            file.seek(SeekFrom::Start(pos))?;
+            mem.get_slice(
+                addr.unchecked_add(pos - offset),
+                u64_to_usize(run_end - pos),
+            )
+            .and_then(|mut slice| Ok(file.read_exact_volatile(&mut slice)?))
+            .map_err(OverlayError::GuestMemory)?;
+            pos = run_end;
+        }
+        Ok(count)
+    }
+
+    pub fn write(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32, OverlayError> {
+        if count == 0 {
+            return Ok(0);
+        }
+        let end = offset + u64::from(count);
+        let first_block = offset / OVERLAY_BLOCK_SIZE;
+        let last_block = (end - 1) / OVERLAY_BLOCK_SIZE;
+
+        for block in first_block..=last_block {
+            let block_start = block * OVERLAY_BLOCK_SIZE;
+            let block_end = (block_start + OVERLAY_BLOCK_SIZE).min(self.disk_size);
+            let start = offset.max(block_start);
+            let len = end.min(block_end) - start;
+            let guest_addr = addr.unchecked_add(start - offset);
+
+            if self.is_allocated(block) || len == block_end - block_start {
+                self.overlay.seek(SeekFrom::Start(start))?;
+                let overlay = &mut self.overlay;
+                mem.get_slice(guest_addr, u64_to_usize(len))
+                    .and_then(|slice| Ok(overlay.write_all_volatile(&slice)?))
+                    .map_err(OverlayError::GuestMemory)?;
+            } else {
+                self.copy_block(block, u64_to_usize(start - block_start), |buf| {
+                    mem.read_slice(&mut buf[..u64_to_usize(len)], guest_addr)
+                        .map_err(OverlayError::GuestMemory)
+                })?;
+            }
+        }
+
+        // The blocks are only marked as allocated once the overlay file holds their data.
+        self.set_allocated(first_block, last_block);
+        self.persist_bitmap(first_block, last_block)?;
+        Ok(count)
+    }
+
+    pub fn fallocate(
+        &mut self,
+        mode: FallocateMode,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), OverlayError> {
+        if len == 0 {
+            return Ok(());
+        }
+        let end = offset + len;
+        let first_block = offset / OVERLAY_BLOCK_SIZE;
+        let last_block = (end - 1) / OVERLAY_BLOCK_SIZE;
+
+        // The rest of the partially covered blocks must come from the base image.
+        for block in [first_block, last_block] {
+            let block_start = block * OVERLAY_BLOCK_SIZE;
+            let block_end = (block_start + OVERLAY_BLOCK_SIZE).min(self.disk_size);
+            let covered = offset <= block_start && end >= block_end;
+            if !covered && !self.is_allocated(block) {
+                self.copy_block(block, 0, |_| Ok(()))?;
+                self.set_allocated(block, block);
+            }
+        }
+
+        let invalid_input =
+            |_| OverlayError::Io(std::io::Error::from(std::io::ErrorKind::InvalidInput));
+        let offset = libc::off_t::try_from(offset).map_err(invalid_input)?;
+        let len = libc::off_t::try_from(len).map_err(invalid_input)?;
+        // SAFETY: `fallocate` only operates on the file referred to by the valid descriptor.
+        let ret = unsafe { libc::fallocate(self.overlay.as_raw_fd(), mode.flags(), offset, len) };
+        if ret < 0 {
+            return Err(OverlayError::Io(std::io::Error::last_os_error()));
+        }
+
+        self.set_allocated(first_block, last_block);
+        self.persist_bitmap(first_block, last_block)
+    }
+
+    pub fn flush(&mut self) -> Result<(), OverlayError> {
+        // The bitmap only refers to data written to the overlay file.
+        self.overlay.sync_all()?;
+        Ok(self.bitmap_file.sync_all()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+
+    use utils::tempfile::TempFile;
+
+    use super::*;
+    use crate::vmm_config::machine_config::HugePageConfig;
+    use crate::vstate::memory::GuestMemoryExtension;
+
+    const DISK_SIZE: u64 = 4 * OVERLAY_BLOCK_SIZE + 512;
+    const MEM_LEN: usize = 0x8000;
+
+    struct Files {
+        base: TempFile,
+        overlay: TempFile,
+        bitmap: TempFile,
+    }
+
+    impl Files {
+        fn new() -> Self {
+            let base = TempFile::new().unwrap();
+            let data = utils::rand::rand_alphanumerics(u64_to_usize(DISK_SIZE));
+            base.as_file().write_all_at(data.as_bytes(), 0).unwrap();
+            Files {
+                base,
+                overlay: TempFile::new().unwrap(),
+                bitmap: TempFile::new().unwrap(),
+            }
+        }
+
+        fn engine(&self) -> Result<OverlayFileEngine, OverlayError> {
+            let open = |file: &TempFile| {
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(file.as_path())
+                    .unwrap()
+            };
+            OverlayFileEngine::new(
+                File::open(self.base.as_path()).unwrap(),
+                open(&self.overlay),
+                open(&self.bitmap),
+            )
+        }
+
+        fn base_data(&self) -> Vec<u8> {
+            let mut data = vec![0u8; u64_to_usize(DISK_SIZE)];
+            self.base.as_file().read_exact_at(&mut data, 0).unwrap();
+            data
+        }
+    }
+
+    fn create_mem() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_raw_regions(
+            &[(GuestAddress(0), MEM_LEN)],
+            false,
+            HugePageConfig::None,
+        )
+        .unwrap()
+    }
+
+    fn read_disk(engine: &mut OverlayFileEngine, mem: &GuestMemoryMmap) -> Vec<u8> {
+        let count = u32::try_from(DISK_SIZE).unwrap();
+        assert_eq!(engine.read(0, mem, GuestAddress(0), count).unwrap(), count);
+        let mut data = vec![0u8; u64_to_usize(DISK_SIZE)];
+        mem.read_slice(&mut data, GuestAddress(0)).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_new_overlay() {
+        let files = Files::new();
+        let mut engine = files.engine().unwrap();
+        assert_eq!(
+            files.bitmap.as_file().metadata().unwrap().len(),
+            BITMAP_HEADER_SIZE as u64 + 1
+        );
+        // All blocks are read from the base image.
+        let mem = create_mem();
+        assert_eq!(read_disk(&mut engine, &mem), files.base_data());
+
+        // The bitmap must match the base image.
+        files.base.as_file().set_len(DISK_SIZE + 1).unwrap();
+        assert!(matches!(
+            files.engine(),
+            Err(OverlayError::SizeMismatch(DISK_SIZE, size)) if size == DISK_SIZE + 1
+        ));
+        assert!(matches!(
+            engine.update_base(File::open(files.base.as_path()).unwrap()),
+            Err(OverlayError::SizeMismatch(_, _))
+        ));
+        files
+            .bitmap
+            .as_file()
+            .write_all_at(&0u64.to_le_bytes(), 0)
+            .unwrap();
+        assert!(matches!(files.engine(), Err(OverlayError::InvalidBitmap)));
+    }
+
+    #[test]
+    fn test_read_write() {
+        let files = Files::new();
+        let base_data = files.base_data();
+        let mut engine = files.engine().unwrap();
+        let mem = create_mem();
+        let data = utils::rand::rand_alphanumerics(MEM_LEN).as_bytes().to_vec();
+        mem.write_slice(&data, GuestAddress(0)).unwrap();
+
+        // Partial block, then a write spanning a full block and two partial ones.
+        let mut expected = base_data.clone();
+        engine.write(100, &mem, GuestAddress(0), 10).unwrap();
+        expected[100..110].copy_from_slice(&data[..10]);
+        let offset = OVERLAY_BLOCK_SIZE + 1000;
+        let len = 2 * OVERLAY_BLOCK_SIZE;
+        engine
+            .write(
+                offset,
+                &mem,
+                GuestAddress(0x100),
+                u32::try_from(len).unwrap(),
+            )
+            .unwrap();
+        expected[u64_to_usize(offset)..u64_to_usize(offset + len)]
+            .copy_from_slice(&data[0x100..0x100 + u64_to_usize(len)]);
+        // Overwrite within an allocated block.
+        engine.write(5, &mem, GuestAddress(0x10), 100).unwrap();
+        expected[5..105].copy_from_slice(&data[0x10..0x110]);
+        // The short last block.
+        engine
+            .write(DISK_SIZE - 10, &mem, GuestAddress(0), 10)
+            .unwrap();
+        expected[u64_to_usize(DISK_SIZE) - 10..].copy_from_slice(&data[..10]);
+
+        let mem = create_mem();
+        assert_eq!(read_disk(&mut engine, &mem), expected);
+        assert_eq!(engine.bitmap, vec![0b1_1111]);
+        engine.flush().unwrap();
+
+        // The base image is not modified, and the overlay can be reopened.
+        assert_eq!(files.base_data(), base_data);
+        let mut engine = files.engine().unwrap();
+        assert_eq!(read_disk(&mut engine, &mem), expected);
+        engine
+            .update_base(File::open(files.base.as_path()).unwrap())
+            .unwrap();
+        assert_eq!(read_disk(&mut engine, &mem), expected);
+
+        // Guest memory errors.
+        assert!(matches!(
+            engine.read(0, &mem, GuestAddress(MEM_LEN as u64 - 10), 20),
+            Err(OverlayError::GuestMemory(_))
+        ));
+    }
+
+    #[test]
+    fn test_fallocate() {
+        let files = Files::new();
+        let mut expected = files.base_data();
+        let mut engine = files.engine().unwrap();
+        let mem = create_mem();
+
+        // A full block and a partial one.
+        let offset = OVERLAY_BLOCK_SIZE;
+        let len = OVERLAY_BLOCK_SIZE + 100;
+        engine
+            .fallocate(FallocateMode::PunchHole, offset, len)
+            .unwrap();
+        expected[u64_to_usize(offset)..u64_to_usize(offset + len)].fill(0);
+        assert_eq!(read_disk(&mut engine, &mem), expected);
+        assert_eq!(engine.bitmap, vec![0b0110]);
+
+        engine.fallocate(FallocateMode::PunchHole, 10, 20).unwrap();
+        expected[10..30].fill(0);
+        assert_eq!(read_disk(&mut engine, &mem), expected);
+        assert_eq!(engine.bitmap, vec![0b0111]);
+    }
+}
@@ -0,0 +1,1149 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reads and writes disk images in the qcow2 format.
+//!
+//! Guest offsets are translated to offsets in the image file through a two level table: the L1
+//! table, kept in memory, points to L2 tables, whose entries point to the clusters holding guest
+//! data. Clusters that are not allocated in the image are read from its backing file, if any, and
+//! read as zeros otherwise. Writing to them allocates clusters at the end of the image file, whose
+//! reference counts are kept up to date so that the image stays consistent for other qcow2 tools.
+//! Discarding clusters releases them, and returns their space in the image file to the host.
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use utils::u64_to_usize;
+use vm_memory::GuestMemoryError;
+
+use super::FallocateMode;
+use crate::vstate::memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+/// "QFI\xfb"
+const QCOW2_MAGIC: u32 = 0x5146_49fb;
+const V2_HEADER_LENGTH: u32 = 72;
+const V3_HEADER_LENGTH: u32 = 104;
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+// Only byte aligned reference counts are supported, from 8 to 64 bits.
+const MIN_REFCOUNT_ORDER: u32 = 3;
+const MAX_REFCOUNT_ORDER: u32 = 6;
+// Same limits as QEMU, to bound the memory used by the in-memory tables.
+const MAX_L1_TABLE_BYTES: u64 = 32 << 20;
+const MAX_REFCOUNT_TABLE_BYTES: u64 = 8 << 20;
+const MAX_BACKING_FILE_NAME_BYTES: u32 = 1023;
+/// Maximum number of images in a chain of backing files.
+const MAX_CHAIN_LENGTH: usize = 16;
+
+// Header extensions.
+const END_OF_EXTENSIONS: u32 = 0;
+const BACKING_FORMAT_EXTENSION: u32 = 0xe279_2aca;
+
+// Image file offset bits of L1 and L2 table entries.
+const L1_L2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+// Image file offset bits of refcount table entries.
+const REFCOUNT_TABLE_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+// Set in the L1 and L2 entries of clusters whose reference count is exactly one.
+const COPIED_FLAG: u64 = 1 << 63;
+const COMPRESSED_FLAG: u64 = 1 << 62;
+// Set in the L2 entries of clusters reading as zeros, starting with version 3.
+const ZERO_FLAG: u64 = 1;
+
+/// Errors associated with qcow2 images.
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum Qcow2Error {
+    /// Cannot access the image: {0}
+    Io(std::io::Error),
+    /// The file is not a qcow2 image.
+    InvalidMagic,
+    /// Unsupported qcow2 version: {0}
+    UnsupportedVersion(u32),
+    /// Invalid cluster size: 2^{0} bytes
+    InvalidClusterBits(u32),
+    /// Encrypted images are not supported.
+    Encrypted,
+    /// Unsupported incompatible features: {0:#x}
+    UnsupportedFeatures(u64),
+    /// Unsupported reference count width: 2^{0} bits
+    UnsupportedRefcountOrder(u32),
+    /// Writing to images with internal snapshots is not supported.
+    InternalSnapshots,
+    /// Invalid image: {0}
+    Corrupt(&'static str),
+    /// Cannot open backing file {0:?}: {1}
+    OpenBackingFile(PathBuf, std::io::Error),
+    /// Unsupported backing file format: {0}
+    UnsupportedBackingFormat(String),
+    /// The chain of backing files is too long.
+    ChainTooLong,
+    /// Compressed clusters are not supported.
+    CompressedCluster,
+    /// Writing to clusters shared with other images is not supported.
+    SharedCluster,
+    /// The reference count table of the image is full.
+    RefcountTableFull,
+    /// Unsupported fallocate mode for this image: {0:?}
+    UnsupportedFallocateMode(FallocateMode),
+    /// Cannot access guest memory: {0}
+    GuestMemory(GuestMemoryError),
+}
+
+impl From<std::io::Error> for Qcow2Error {
+    fn from(err: std::io::Error) -> Self {
+        Qcow2Error::Io(err)
+    }
+}
+
+fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn be_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+// Reads a table of `entries` big endian 64 bit values starting at `offset`.
+fn read_table(file: &File, offset: u64, entries: u64) -> Result<Vec<u64>, Qcow2Error> {
+    let mut bytes = vec![0u8; u64_to_usize(entries * 8)];
+    file.read_exact_at(&mut bytes, offset)?;
+    Ok(bytes
+        .chunks_exact(8)
+        .map(|entry| be_u64(entry, 0))
+        .collect())
+}
+
+// Whether `file` was opened for writing.
+fn is_writable(file: &File) -> Result<bool, Qcow2Error> {
+    // SAFETY: F_GETFL only reads the status flags of the valid descriptor.
+    let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+    if flags < 0 {
+        return Err(Qcow2Error::Io(std::io::Error::last_os_error()));
+    }
+    Ok(flags & libc::O_ACCMODE != libc::O_RDONLY)
+}
+
+/// The image whose contents show through the clusters not allocated in a qcow2 image.
+#[derive(Debug)]
+enum BackingFile {
+    Raw { file: File, size: u64 },
+    Qcow2(Qcow2Image),
+}
+
+impl BackingFile {
+    fn size(&self) -> u64 {
+        match self {
+            BackingFile::Raw { size, .. } => *size,
+            BackingFile::Qcow2(image) => image.virtual_size,
+        }
+    }
+
+    // Reads `buf.len()` bytes starting at `offset`, reading zeros past the end of the image.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), Qcow2Error> {
+        let available = u64_to_usize(self.size().saturating_sub(offset)).min(buf.len());
+        let (data, past_end) = buf.split_at_mut(available);
+        match self {
+            BackingFile::Raw { file, .. } => file.read_exact_at(data, offset)?,
+            BackingFile::Qcow2(image) => image.read_at(offset, data)?,
+        }
+        past_end.fill(0);
+        Ok(())
+    }
+}
+
+/// Where the data of a guest cluster comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClusterState {
+    /// The cluster is allocated at this offset of the image file.
+    Allocated(u64),
+    /// The cluster reads as zeros.
+    Zero,
+    /// The cluster is read from the backing file.
+    Unallocated,
+}
+
+/// A qcow2 image, along with its chain of backing files.
+#[derive(Debug)]
+pub struct Qcow2Image {
+    file: File,
+    version: u32,
+    cluster_bits: u32,
+    virtual_size: u64,
+    l1_table_offset: u64,
+    l1_table: Vec<u64>,
+    refcount_order: u32,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    backing_file: Option<Box<BackingFile>>,
+    // Offset of the image file at which the next cluster gets allocated.
+    next_cluster_offset: u64,
+}
+
+impl Qcow2Image {
+    /// Opens the qcow2 image stored in `file`, found at `path`. Relative backing file names are
+    /// resolved from the directory of `path`.
+    pub fn open(file: File, path: &Path) -> Result<Self, Qcow2Error> {
+        Self::open_chain(file, path, 1)
+    }
+
+    fn open_chain(file: File, path: &Path, chain_length: usize) -> Result<Self, Qcow2Error> {
+        let mut header = [0u8; V3_HEADER_LENGTH as usize];
+        file.read_exact_at(&mut header[..V2_HEADER_LENGTH as usize], 0)?;
+        if be_u32(&header, 0) != QCOW2_MAGIC {
+            return Err(Qcow2Error::InvalidMagic);
+        }
+        let version = be_u32(&header, 4);
+        match version {
+            2 => {}
+            3 => file.read_exact_at(&mut header, 0)?,
+            _ => return Err(Qcow2Error::UnsupportedVersion(version)),
+        }
+
+        let backing_file_offset = be_u64(&header, 8);
+        let backing_file_size = be_u32(&header, 16);
+        let cluster_bits = be_u32(&header, 20);
+        let virtual_size = be_u64(&header, 24);
+        let crypt_method = be_u32(&header, 32);
+        let l1_size = be_u32(&header, 36);
+        let l1_table_offset = be_u64(&header, 40);
+        let refcount_table_offset = be_u64(&header, 48);
+        let refcount_table_clusters = be_u32(&header, 56);
+        let nb_snapshots = be_u32(&header, 60);
+        let (incompatible_features, refcount_order, header_length, min_header_length) =
+            match version {
+                2 => (0, 4, V2_HEADER_LENGTH, V2_HEADER_LENGTH),
+                _ => (
+                    be_u64(&header, 72),
+                    be_u32(&header, 96),
+                    be_u32(&header, 100),
+                    V3_HEADER_LENGTH,
+                ),
+            };
+
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+            return Err(Qcow2Error::InvalidClusterBits(cluster_bits));
+        }
+        if crypt_method != 0 {
+            return Err(Qcow2Error::Encrypted);
+        }
+        if incompatible_features != 0 {
+            return Err(Qcow2Error::UnsupportedFeatures(incompatible_features));
+        }
+        if !(MIN_REFCOUNT_ORDER..=MAX_REFCOUNT_ORDER).contains(&refcount_order) {
+            return Err(Qcow2Error::UnsupportedRefcountOrder(refcount_order));
+        }
+        // Writes would have to take the clusters shared with snapshots into account.
+        if nb_snapshots != 0 && is_writable(&file)? {
+            return Err(Qcow2Error::InternalSnapshots);
+        }
+
+        let cluster_size = 1u64 << cluster_bits;
+        let cluster_mask = cluster_size - 1;
+        if header_length < min_header_length || u64::from(header_length) > cluster_size {
+            return Err(Qcow2Error::Corrupt("invalid header length"));
+        }
+        if l1_table_offset & cluster_mask != 0 || refcount_table_offset & cluster_mask != 0 {
+            return Err(Qcow2Error::Corrupt("unaligned table"));
+        }
+        // Each L2 table maps a cluster of guest data per 8 bytes entry.
+        let l1_entry_coverage = cluster_size * (cluster_size / 8);
+        if u64::from(l1_size) < virtual_size.div_ceil(l1_entry_coverage) {
+            return Err(Qcow2Error::Corrupt("L1 table too small for the disk size"));
+        }
+        if u64::from(l1_size) * 8 > MAX_L1_TABLE_BYTES {
+            return Err(Qcow2Error::Corrupt("L1 table too large"));
+        }
+        let refcount_table_bytes = u64::from(refcount_table_clusters) * cluster_size;
+        if refcount_table_bytes > MAX_REFCOUNT_TABLE_BYTES {
+            return Err(Qcow2Error::Corrupt("reference count table too large"));
+        }
+
+        let l1_table = read_table(&file, l1_table_offset, u64::from(l1_size))?;
+        let refcount_table = read_table(&file, refcount_table_offset, refcount_table_bytes / 8)?;
+
+        let backing_file = match backing_file_offset {
+            0 => None,
+            _ => {
+                if backing_file_size > MAX_BACKING_FILE_NAME_BYTES {
+                    return Err(Qcow2Error::Corrupt("backing file name too long"));
+                }
+                let mut name = vec![0u8; backing_file_size as usize];
+                file.read_exact_at(&mut name, backing_file_offset)?;
+                let name = String::from_utf8(name)
+                    .map_err(|_| Qcow2Error::Corrupt("invalid backing file name"))?;
+                let format = Self::read_backing_format(&file, header_length, cluster_size)?;
+                let backing_path = path.parent().unwrap_or(Path::new("")).join(name);
+                Some(Box::new(Self::open_backing_file(
+                    &backing_path,
+                    format.as_deref(),
+                    chain_length,
+                )?))
+            }
+        };
+
+        let file_size = file.metadata()?.len();
+        Ok(Qcow2Image {
+            file,
+            version,
+            cluster_bits,
+            virtual_size,
+            l1_table_offset,
+            l1_table,
+            refcount_order,
+            refcount_table_offset,
+            refcount_table,
+            backing_file,
+            next_cluster_offset: file_size.next_multiple_of(cluster_size),
+        })
+    }
+
+    // Reads the backing file format from the header extensions, which follow the header in the
+    // first cluster.
+    fn read_backing_format(
+        file: &File,
+        header_length: u32,
+        cluster_size: u64,
+    ) -> Result<Option<String>, Qcow2Error> {
+        let mut offset = u64::from(header_length);
+        while offset + 8 <= cluster_size {
+            let mut extension_header = [0u8; 8];
+            file.read_exact_at(&mut extension_header, offset)?;
+            let extension_type = be_u32(&extension_header, 0);
+            let length = be_u32(&extension_header, 4);
+            if extension_type == END_OF_EXTENSIONS {
+                break;
+            }
+            offset += 8;
+            if extension_type == BACKING_FORMAT_EXTENSION {
+                if offset + u64::from(length) > cluster_size {
+                    return Err(Qcow2Error::Corrupt("invalid header extension"));
+                }
+                let mut format = vec![0u8; length as usize];
+                file.read_exact_at(&mut format, offset)?;
+                return String::from_utf8(format)
+                    .map(Some)
+                    .map_err(|_| Qcow2Error::Corrupt("invalid backing file format"));
+            }
+            // Extension data is padded to a multiple of 8 bytes.
+            offset += u64::from(length).next_multiple_of(8);
+        }
+        Ok(None)
+    }
+
+    fn open_backing_file(
+        path: &Path,
+        format: Option<&str>,
+        chain_length: usize,
+    ) -> Result<BackingFile, Qcow2Error> {
+        if chain_length >= MAX_CHAIN_LENGTH {
+            return Err(Qcow2Error::ChainTooLong);
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .open(path)
+            .map_err(|err| Qcow2Error::OpenBackingFile(path.to_path_buf(), err))?;
+        let is_qcow2 = match format {
+            Some("qcow2") => true,
+            Some("raw") => false,
+            Some(format) => return Err(Qcow2Error::UnsupportedBackingFormat(format.to_string())),
+            // Without an explicit format, probe the file.
+            None => {
+                let mut magic = [0u8; 4];
+                file.read_exact_at(&mut magic, 0).is_ok()
+                    && u32::from_be_bytes(magic) == QCOW2_MAGIC
+            }
+        };
+        match is_qcow2 {
+            true => Ok(BackingFile::Qcow2(Self::open_chain(
+                file,
+                path,
+                chain_length + 1,
+            )?)),
+            false => {
+                let size = file.metadata()?.len();
+                Ok(BackingFile::Raw { file, size })
+            }
+        }
+    }
+
+    /// Size of the disk stored in the image, in bytes.
+    pub fn virtual_size(&self) -> u64 {
+        self.virtual_size
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1u64 << self.cluster_bits
+    }
+
+    // Returns the index of the L1 entry and the offset in the L2 table of the L2 entry of the
+    // cluster holding `guest_offset`.
+    fn table_indices(&self, guest_offset: u64) -> Result<(usize, u64), Qcow2Error> {
+        let l2_bits = self.cluster_bits - 3;
+        let l1_index = u64_to_usize(guest_offset >> (self.cluster_bits + l2_bits));
+        if l1_index >= self.l1_table.len() {
+            return Err(Qcow2Error::Corrupt("offset beyond the L1 table"));
+        }
+        let l2_index = (guest_offset >> self.cluster_bits) & ((1 << l2_bits) - 1);
+        Ok((l1_index, l2_index * 8))
+    }
+
+    fn read_u64(&self, offset: u64) -> Result<u64, Qcow2Error> {
+        let mut bytes = [0u8; 8];
+        self.file.read_exact_at(&mut bytes, offset)?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn write_u64(&self, offset: u64, value: u64) -> Result<(), Qcow2Error> {
+        Ok(self.file.write_all_at(&value.to_be_bytes(), offset)?)
+    }
+
+    fn cluster_state(&self, guest_offset: u64) -> Result<ClusterState, Qcow2Error> {
+        let (l1_index, l2_entry_offset) = self.table_indices(guest_offset)?;
+        let l2_table_offset = self.l1_table[l1_index] & L1_L2_OFFSET_MASK;
+        if l2_table_offset == 0 {
+            return Ok(ClusterState::Unallocated);
+        }
+        let l2_entry = self.read_u64(l2_table_offset + l2_entry_offset)?;
+        if l2_entry & COMPRESSED_FLAG != 0 {
+            return Err(Qcow2Error::CompressedCluster);
+        }
+        if self.version >= 3 && l2_entry & ZERO_FLAG != 0 {
+            return Ok(ClusterState::Zero);
+        }
+        match l2_entry & L1_L2_OFFSET_MASK {
+            0 => Ok(ClusterState::Unallocated),
+            offset => Ok(ClusterState::Allocated(offset)),
+        }
+    }
+
+    // Reads the contents of the unallocated range of `buf.len()` bytes at `guest_offset`.
+    fn read_unallocated(&self, guest_offset: u64, buf: &mut [u8]) -> Result<(), Qcow2Error> {
+        match self.backing_file.as_deref() {
+            Some(backing_file) => backing_file.read_at(guest_offset, buf),
+            None => {
+                buf.fill(0);
+                Ok(())
+            }
+        }
+    }
+
+    /// Reads `buf.len()` bytes of the disk starting at `guest_offset`.
+    pub fn read_at(&self, mut guest_offset: u64, mut buf: &mut [u8]) -> Result<(), Qcow2Error> {
+        while !buf.is_empty() {
+            let in_cluster = guest_offset & (self.cluster_size() - 1);
+            let len = buf
+                .len()
+                .min(u64_to_usize(self.cluster_size() - in_cluster));
+            let (chunk, rest) = buf.split_at_mut(len);
+            match self.cluster_state(guest_offset)? {
+                ClusterState::Allocated(offset) => {
+                    self.file.read_exact_at(chunk, offset + in_cluster)?
+                }
+                ClusterState::Zero => chunk.fill(0),
+                ClusterState::Unallocated => self.read_unallocated(guest_offset, chunk)?,
+            }
+            guest_offset += len as u64;
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` to the disk starting at `guest_offset`, allocating clusters as needed.
+    pub fn write_at(&mut self, mut guest_offset: u64, mut buf: &[u8]) -> Result<(), Qcow2Error> {
+        while !buf.is_empty() {
+            let in_cluster = guest_offset & (self.cluster_size() - 1);
+            let len = buf
+                .len()
+                .min(u64_to_usize(self.cluster_size() - in_cluster));
+            let (chunk, rest) = buf.split_at(len);
+            self.write_cluster(guest_offset, chunk)?;
+            guest_offset += len as u64;
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    /// Makes `len` bytes of the disk starting at `guest_offset` read as zeros.
+    pub fn write_zeroes(&mut self, mut guest_offset: u64, len: u64) -> Result<(), Qcow2Error> {
+        let end = guest_offset + len;
+        let zeros = vec![0u8; u64_to_usize(self.cluster_size())];
+        while guest_offset < end {
+            let in_cluster = guest_offset & (self.cluster_size() - 1);
+            let len = (end - guest_offset).min(self.cluster_size() - in_cluster);
+            let reads_as_zeros = match self.cluster_state(guest_offset)? {
+                ClusterState::Allocated(_) => false,
+                ClusterState::Zero => true,
+                ClusterState::Unallocated => self.backing_file.is_none(),
+            };
+            if !reads_as_zeros {
+                self.write_cluster(guest_offset, &zeros[..u64_to_usize(len)])?;
+            }
+            guest_offset += len;
+        }
+        Ok(())
+    }
+
+    /// Deallocates the clusters fully covered by `len` bytes of the disk starting at
+    /// `guest_offset`, which then read as zeros. The rest of the range is zeroed with
+    /// `FallocateMode::PunchHole`, and left untouched with `FallocateMode::Discard`.
+    pub fn deallocate(
+        &mut self,
+        mode: FallocateMode,
+        mut guest_offset: u64,
+        len: u64,
+    ) -> Result<(), Qcow2Error> {
+        let end = guest_offset + len;
+        while guest_offset < end {
+            let in_cluster = guest_offset & (self.cluster_size() - 1);
+            let len = (end - guest_offset).min(self.cluster_size() - in_cluster);
+            if len == self.cluster_size() {
+                self.deallocate_cluster(mode, guest_offset)?;
+            } else if mode == FallocateMode::PunchHole {
+                self.write_zeroes(guest_offset, len)?;
+            }
+            guest_offset += len;
+        }
+        Ok(())
+    }
+
+    /// Writes the image data and metadata to the host storage.
+    pub fn flush(&self) -> Result<(), Qcow2Error> {
+        Ok(self.file.sync_all()?)
+    }
+
+    // Writes `data` at `guest_offset`, which must not cross a cluster boundary.
+    fn write_cluster(&mut self, guest_offset: u64, data: &[u8]) -> Result<(), Qcow2Error> {
+        let cluster_size = self.cluster_size();
+        let in_cluster = guest_offset & (cluster_size - 1);
+        let l2_entry_offset = self.writable_l2_entry(guest_offset)?;
+        let l2_entry = self.read_u64(l2_entry_offset)?;
+        if l2_entry & COMPRESSED_FLAG != 0 {
+            return Err(Qcow2Error::CompressedCluster);
+        }
+        let offset = l2_entry & L1_L2_OFFSET_MASK;
+        let zero = self.version >= 3 && l2_entry & ZERO_FLAG != 0;
+        if offset != 0 && l2_entry & COPIED_FLAG == 0 {
+            return Err(Qcow2Error::SharedCluster);
+        }
+        if offset != 0 && !zero {
+            return Ok(self.file.write_all_at(data, offset + in_cluster)?);
+        }
+
+        // Fill the whole cluster, merging the data with the current contents of the cluster.
+        let cluster_start = guest_offset - in_cluster;
+        let mut cluster = vec![0u8; u64_to_usize(cluster_size)];
+        if !zero && data.len() as u64 != cluster_size {
+            self.read_unallocated(cluster_start, &mut cluster)?;
+        }
+        let in_cluster = u64_to_usize(in_cluster);
+        cluster[in_cluster..in_cluster + data.len()].copy_from_slice(data);
+
+        // Zero clusters may still own an allocated cluster, which can be reused.
+        let offset = match offset {
+            0 => self.allocate_cluster()?,
+            offset => offset,
+        };
+        self.file.write_all_at(&cluster, offset)?;
+        // Only point to the cluster once it holds its data.
+        self.write_u64(l2_entry_offset, offset | COPIED_FLAG)
+    }
+
+    // Makes the cluster holding `guest_offset` read as zeros without holding any data, releasing
+    // the cluster of the image file it points to, if any.
+    fn deallocate_cluster(
+        &mut self,
+        mode: FallocateMode,
+        guest_offset: u64,
+    ) -> Result<(), Qcow2Error> {
+        let (l1_index, l2_entry_offset) = self.table_indices(guest_offset)?;
+        let l2_table_offset = self.l1_table[l1_index] & L1_L2_OFFSET_MASK;
+        let l2_entry = match l2_table_offset {
+            0 => 0,
+            _ => self.read_u64(l2_table_offset + l2_entry_offset)?,
+        };
+        if l2_entry & COMPRESSED_FLAG != 0 {
+            return Err(Qcow2Error::CompressedCluster);
+        }
+        let offset = l2_entry & L1_L2_OFFSET_MASK;
+        let zero = self.version >= 3 && l2_entry & ZERO_FLAG != 0;
+        if offset == 0 && (zero || self.backing_file.is_none()) {
+            return Ok(());
+        }
+        if offset != 0 && l2_entry & COPIED_FLAG == 0 {
+            return Err(Qcow2Error::SharedCluster);
+        }
+
+        // Unallocated clusters show the backing file through: they must be marked as zero
+        // clusters instead, which version 2 images do not support.
+        let new_l2_entry = match self.backing_file {
+            None => 0,
+            Some(_) if self.version >= 3 => ZERO_FLAG,
+            Some(_) => return Err(Qcow2Error::UnsupportedFallocateMode(mode)),
+        };
+        let l2_entry_offset = self.writable_l2_entry(guest_offset)?;
+        // Only release the cluster once nothing points to it.
+        self.write_u64(l2_entry_offset, new_l2_entry)?;
+        if offset != 0 {
+            self.set_refcount(offset, 0)?;
+            self.punch_hole(offset)?;
+        }
+        Ok(())
+    }
+
+    // Returns the space of the released cluster at `offset` of the image file to the host.
+    fn punch_hole(&self, offset: u64) -> Result<(), Qcow2Error> {
+        let out_of_range = |_| Qcow2Error::Corrupt("cluster offset out of range");
+        let offset = libc::off_t::try_from(offset).map_err(out_of_range)?;
+        let len = libc::off_t::try_from(self.cluster_size()).map_err(out_of_range)?;
+        let mode = FallocateMode::PunchHole.flags();
+        // SAFETY: `fallocate` only operates on the file referred to by the valid descriptor.
+        let ret = unsafe { libc::fallocate(self.file.as_raw_fd(), mode, offset, len) };
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            // The cluster is released even if the file system cannot deallocate its space.
+            if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                return Err(Qcow2Error::Io(err));
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the image file offset of the L2 entry of the cluster holding `guest_offset`,
+    // allocating its L2 table if needed.
+    fn writable_l2_entry(&mut self, guest_offset: u64) -> Result<u64, Qcow2Error> {
+        let (l1_index, l2_entry_offset) = self.table_indices(guest_offset)?;
+        let l1_entry = self.l1_table[l1_index];
+        let l2_table_offset = l1_entry & L1_L2_OFFSET_MASK;
+        if l2_table_offset != 0 {
+            if l1_entry & COPIED_FLAG == 0 {
+                return Err(Qcow2Error::SharedCluster);
+            }
+            return Ok(l2_table_offset + l2_entry_offset);
+        }
+
+        let l2_table_offset = self.allocate_cluster()?;
+        self.file.write_all_at(
+            &vec![0u8; u64_to_usize(self.cluster_size())],
+            l2_table_offset,
+        )?;
+        let l1_entry = l2_table_offset | COPIED_FLAG;
+        self.write_u64(self.l1_table_offset + l1_index as u64 * 8, l1_entry)?;
+        self.l1_table[l1_index] = l1_entry;
+        Ok(l2_table_offset + l2_entry_offset)
+    }
+
+    // Allocates a cluster at the end of the image file.
+    fn allocate_cluster(&mut self) -> Result<u64, Qcow2Error> {
+        let offset = self.next_cluster_offset;
+        self.next_cluster_offset += self.cluster_size();
+        self.set_refcount(offset, 1)?;
+        Ok(offset)
+    }
+
+    // Sets the reference count of the cluster at `offset` of the image file, allocating the
+    // refcount block holding it if needed.
+    fn set_refcount(&mut self, offset: u64, refcount: u64) -> Result<(), Qcow2Error> {
+        let refcount_bytes = 1u64 << (self.refcount_order - 3);
+        let refcounts_per_block = self.cluster_size() / refcount_bytes;
+        let cluster_index = offset >> self.cluster_bits;
+        let table_index = u64_to_usize(cluster_index / refcounts_per_block);
+        let table_entry = *self
+            .refcount_table
+            .get(table_index)
+            .ok_or(Qcow2Error::RefcountTableFull)?;
+
+        let mut block_offset = table_entry & REFCOUNT_TABLE_OFFSET_MASK;
+        if block_offset == 0 {
+            // The new refcount block is allocated right away, and only accounted for once the
+            // table points to it.
+            block_offset = self.next_cluster_offset;
+            self.next_cluster_offset += self.cluster_size();
+            self.file
+                .write_all_at(&vec![0u8; u64_to_usize(self.cluster_size())], block_offset)?;
+            self.write_u64(
+                self.refcount_table_offset + table_index as u64 * 8,
+                block_offset,
+            )?;
+            self.refcount_table[table_index] = block_offset;
+            self.set_refcount(block_offset, 1)?;
+        }
+
+        let entry_offset = block_offset + (cluster_index % refcounts_per_block) * refcount_bytes;
+        let refcount = refcount.to_be_bytes();
+        Ok(self.file.write_all_at(
+            &refcount[refcount.len() - u64_to_usize(refcount_bytes)..],
+            entry_offset,
+        )?)
+    }
+}
+
+/// File engine exposing a qcow2 image to the guest.
+///
+/// Requests are executed synchronously, going through a bounce buffer of one cluster.
+#[derive(Debug)]
+pub struct Qcow2FileEngine {
+    image: Qcow2Image,
+    buffer: Vec<u8>,
+}
+
+impl Qcow2FileEngine {
+    pub fn from_file(file: File, path: &Path) -> Result<Qcow2FileEngine, Qcow2Error> {
+        let image = Qcow2Image::open(file, path)?;
+        let buffer = vec![0u8; u64_to_usize(image.cluster_size())];
+        Ok(Qcow2FileEngine { image, buffer })
+    }
+
+    /// Update the image of the engine
+    pub fn update_file(&mut self, file: File, path: &Path) -> Result<(), Qcow2Error> {
+        *self = Self::from_file(file, path)?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn file(&self) -> &File {
+        &self.image.file
+    }
+
+    pub fn virtual_size(&self) -> u64 {
+        self.image.virtual_size()
+    }
+
+    pub fn read(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32, Qcow2Error> {
+        let mut done = 0;
+        while done < count {
+            let len = self.buffer.len().min((count - done) as usize);
+            let chunk = &mut self.buffer[..len];
+            self.image.read_at(offset + u64::from(done), chunk)?;
+            mem.write_slice(chunk, addr.unchecked_add(u64::from(done)))
+                .map_err(Qcow2Error::GuestMemory)?;
+            // `len` is at most `count`.
+            done += u32::try_from(len).unwrap();
+        }
+        Ok(count)
+    }
+
+    pub fn write(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32, Qcow2Error> {
+        let mut done = 0;
+        while done < count {
+            let len = self.buffer.len().min((count - done) as usize);
+            let chunk = &mut self.buffer[..len];
+            mem.read_slice(chunk, addr.unchecked_add(u64::from(done)))
+                .map_err(Qcow2Error::GuestMemory)?;
+            self.image.write_at(offset + u64::from(done), chunk)?;
+            // `len` is at most `count`.
+            done += u32::try_from(len).unwrap();
+        }
+        Ok(count)
+    }
+
+    pub fn flush(&mut self) -> Result<(), Qcow2Error> {
+        self.image.flush()
+    }
+
+    pub fn fallocate(
+        &mut self,
+        mode: FallocateMode,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), Qcow2Error> {
+        match mode {
+            FallocateMode::Discard | FallocateMode::PunchHole => {
+                self.image.deallocate(mode, offset, len)
+            }
+            FallocateMode::ZeroRange => self.image.write_zeroes(offset, len),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use utils::tempfile::TempFile;
+
+    use super::*;
+    use crate::vmm_config::machine_config::HugePageConfig;
+    use crate::vstate::memory::GuestMemoryExtension;
+
+    const CLUSTER_BITS: u32 = 9;
+    const CLUSTER_SIZE: u64 = 1 << CLUSTER_BITS;
+    const DISK_SIZE: u64 = 1 << 20;
+
+    // Creates an empty version 3 image, laid out as QEMU does: the header is followed by the
+    // reference count table, the first reference count block and the L1 table.
+    fn create_image(size: u64, backing_file: Option<(&str, &str)>) -> TempFile {
+        let temp_file = TempFile::new().unwrap();
+        let file = temp_file.as_file();
+        let l2_coverage = CLUSTER_SIZE * (CLUSTER_SIZE / 8);
+        let l1_size = u32::try_from(size.div_ceil(l2_coverage)).unwrap();
+        assert!(u64::from(l1_size) * 8 <= CLUSTER_SIZE);
+
+        let mut header = vec![0u8; u64_to_usize(CLUSTER_SIZE)];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            header[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        put(0, &QCOW2_MAGIC.to_be_bytes());
+        put(4, &3u32.to_be_bytes());
+        put(20, &CLUSTER_BITS.to_be_bytes());
+        put(24, &size.to_be_bytes());
+        put(36, &l1_size.to_be_bytes());
+        put(40, &(3 * CLUSTER_SIZE).to_be_bytes());
+        put(48, &CLUSTER_SIZE.to_be_bytes());
+        put(56, &1u32.to_be_bytes());
+        put(96, &4u32.to_be_bytes());
+        put(100, &V3_HEADER_LENGTH.to_be_bytes());
+        if let Some((name, format)) = backing_file {
+            let format_length = u32::try_from(format.len()).unwrap();
+            put(104, &BACKING_FORMAT_EXTENSION.to_be_bytes());
+            put(108, &format_length.to_be_bytes());
+            put(112, format.as_bytes());
+            let name_offset = 112 + format.len().next_multiple_of(8) + 8;
+            put(8, &(name_offset as u64).to_be_bytes());
+            put(16, &u32::try_from(name.len()).unwrap().to_be_bytes());
+            put(name_offset, name.as_bytes());
+        }
+        file.write_all_at(&header, 0).unwrap();
+        file.write_all_at(&(2 * CLUSTER_SIZE).to_be_bytes(), CLUSTER_SIZE)
+            .unwrap();
+        // The first four clusters are in use.
+        for cluster in 0..4 {
+            file.write_all_at(&1u16.to_be_bytes(), 2 * CLUSTER_SIZE + cluster * 2)
+                .unwrap();
+        }
+        file.set_len(4 * CLUSTER_SIZE).unwrap();
+        temp_file
+    }
+
+    fn open_image(temp_file: &TempFile) -> Qcow2Image {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(temp_file.as_path())
+            .unwrap();
+        Qcow2Image::open(file, temp_file.as_path()).unwrap()
+    }
+
+    fn read_vec(image: &Qcow2Image, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0xffu8; len];
+        image.read_at(offset, &mut buf).unwrap();
+        buf
+    }
+
+    // Checks that the reference count of each cluster of the image file is one, except for the
+    // `released` clusters, whose reference count is zero.
+    fn check_refcounts(image: &Qcow2Image, released: &[u64]) {
+        let file_size = image.file.metadata().unwrap().len();
+        assert_eq!(file_size % CLUSTER_SIZE, 0);
+        for cluster in 0..file_size / CLUSTER_SIZE {
+            let refcounts_per_block = CLUSTER_SIZE / 2;
+            let block = image.refcount_table[u64_to_usize(cluster / refcounts_per_block)];
+            assert_ne!(block, 0);
+            let mut refcount = [0u8; 2];
+            image
+                .file
+                .read_exact_at(&mut refcount, block + (cluster % refcounts_per_block) * 2)
+                .unwrap();
+            let expected = u16::from(!released.contains(&(cluster * CLUSTER_SIZE)));
+            assert_eq!(
+                u16::from_be_bytes(refcount),
+                expected,
+                "cluster {}",
+                cluster
+            );
+        }
+    }
+
+    #[test]
+    fn test_open_invalid_image() {
+        let temp_file = TempFile::new().unwrap();
+        temp_file.as_file().set_len(CLUSTER_SIZE).unwrap();
+        assert!(matches!(
+            Qcow2Image::open(temp_file.into_file(), Path::new("")),
+            Err(Qcow2Error::InvalidMagic)
+        ));
+
+        let temp_file = create_image(DISK_SIZE, None);
+        temp_file
+            .as_file()
+            .write_all_at(&1u64.to_be_bytes(), 72)
+            .unwrap();
+        let file = temp_file.as_file().try_clone().unwrap();
+        assert!(matches!(
+            Qcow2Image::open(file, temp_file.as_path()),
+            Err(Qcow2Error::UnsupportedFeatures(1))
+        ));
+
+        let temp_file = create_image(DISK_SIZE, None);
+        temp_file
+            .as_file()
+            .write_all_at(&1u32.to_be_bytes(), 60)
+            .unwrap();
+        let file = temp_file.as_file().try_clone().unwrap();
+        assert!(matches!(
+            Qcow2Image::open(file, temp_file.as_path()),
+            Err(Qcow2Error::InternalSnapshots)
+        ));
+        // Images with internal snapshots can be read.
+        let file = File::open(temp_file.as_path()).unwrap();
+        Qcow2Image::open(file, temp_file.as_path()).unwrap();
+
+        let temp_file = create_image(DISK_SIZE, Some(("missing", "raw")));
+        let file = temp_file.as_file().try_clone().unwrap();
+        assert!(matches!(
+            Qcow2Image::open(file, temp_file.as_path()),
+            Err(Qcow2Error::OpenBackingFile(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_read_write() {
+        let temp_file = create_image(DISK_SIZE, None);
+        let mut image = open_image(&temp_file);
+        assert_eq!(image.virtual_size(), DISK_SIZE);
+
+        // Unallocated clusters read as zeros.
+        assert_eq!(read_vec(&image, 0, 4096), vec![0u8; 4096]);
+
+        // Write across clusters and L2 tables.
+        let data = utils::rand::rand_alphanumerics(3 * 4096)
+            .as_bytes()
+            .to_vec();
+        let offset = 32 * 1024 - 1000;
+        image.write_at(offset, &data).unwrap();
+        assert_eq!(read_vec(&image, offset, data.len()), data);
+        assert_eq!(read_vec(&image, offset - 100, 100), vec![0u8; 100]);
+
+        // Overwrite allocated clusters in place.
+        let file_size = image.file.metadata().unwrap().len();
+        image.write_at(offset, &data[..100]).unwrap();
+        image.write_at(offset + 100, &data[..100]).unwrap();
+        assert_eq!(image.file.metadata().unwrap().len(), file_size);
+        assert_eq!(read_vec(&image, offset + 100, 100), data[..100]);
+
+        // Fill the whole disk, which needs new reference count blocks.
+        let fill = vec![0x5au8; u64_to_usize(DISK_SIZE)];
+        image.write_at(0, &fill).unwrap();
+        image.flush().unwrap();
+        check_refcounts(&image, &[]);
+
+        // The data persists.
+        let image = open_image(&temp_file);
+        assert_eq!(read_vec(&image, 0, fill.len()), fill);
+    }
+
+    #[test]
+    fn test_write_zeroes() {
+        let temp_file = create_image(DISK_SIZE, None);
+        let mut image = open_image(&temp_file);
+
+        image.write_at(0, &[1u8; 4096]).unwrap();
+        let file_size = image.file.metadata().unwrap().len();
+        image.write_zeroes(100, 1000).unwrap();
+        let mut expected = vec![1u8; 4096];
+        expected[100..1100].fill(0);
+        assert_eq!(read_vec(&image, 0, 4096), expected);
+
+        // Zeroing unallocated clusters does not allocate them.
+        image.write_zeroes(8192, 8192).unwrap();
+        assert_eq!(image.file.metadata().unwrap().len(), file_size);
+        check_refcounts(&image, &[]);
+    }
+
+    #[test]
+    fn test_deallocate() {
+        let temp_file = create_image(DISK_SIZE, None);
+        let mut image = open_image(&temp_file);
+
+        image.write_at(0, &[1u8; 2048]).unwrap();
+        let released: Vec<u64> = (1..3)
+            .map(
+                |cluster| match image.cluster_state(cluster * CLUSTER_SIZE) {
+                    Ok(ClusterState::Allocated(offset)) => offset,
+                    state => panic!("unexpected cluster state {:?}", state),
+                },
+            )
+            .collect();
+
+        // Discarding only deallocates whole clusters.
+        image
+            .deallocate(FallocateMode::Discard, 100, 2 * CLUSTER_SIZE - 100)
+            .unwrap();
+        assert_eq!(read_vec(&image, 0, 512), vec![1u8; 512]);
+        assert_eq!(read_vec(&image, 512, 512), vec![0u8; 512]);
+        assert_eq!(
+            image.cluster_state(CLUSTER_SIZE).unwrap(),
+            ClusterState::Unallocated
+        );
+        // Punching holes zeroes partial clusters.
+        image
+            .deallocate(FallocateMode::PunchHole, 1024, 1000)
+            .unwrap();
+        let mut expected = vec![0u8; 2048];
+        expected[..512].fill(1);
+        expected[2024..].fill(1);
+        assert_eq!(read_vec(&image, 0, 2048), expected);
+        assert_eq!(
+            image.cluster_state(1024).unwrap(),
+            ClusterState::Unallocated
+        );
+        check_refcounts(&image, &released);
+
+        // Deallocating unallocated clusters does not allocate them.
+        let file_size = image.file.metadata().unwrap().len();
+        image
+            .deallocate(FallocateMode::PunchHole, 8192, 8192)
+            .unwrap();
+        assert_eq!(image.file.metadata().unwrap().len(), file_size);
+
+        // Clusters taken from a backing file become zero clusters.
+        let mut base = TempFile::new().unwrap();
+        base.as_file().write_all(&[2u8; 2048]).unwrap();
+        let base_name = base.as_path().file_name().unwrap().to_str().unwrap();
+        let overlay = create_image(DISK_SIZE, Some((base_name, "raw")));
+        let mut image = open_image(&overlay);
+        image.write_at(0, &[3u8; 512]).unwrap();
+        image.deallocate(FallocateMode::PunchHole, 0, 1024).unwrap();
+        assert_eq!(read_vec(&image, 0, 1024), vec![0u8; 1024]);
+        assert_eq!(read_vec(&image, 1024, 1024), vec![2u8; 1024]);
+        assert_eq!(image.cluster_state(0).unwrap(), ClusterState::Zero);
+        assert_eq!(image.cluster_state(512).unwrap(), ClusterState::Zero);
+
+        // Version 2 images have no zero clusters.
+        overlay
+            .as_file()
+            .write_all_at(&2u32.to_be_bytes(), 4)
+            .unwrap();
+        let mut image = open_image(&overlay);
+        assert_eq!(read_vec(&image, 1024, 1024), vec![2u8; 1024]);
+        assert!(matches!(
+            image.deallocate(FallocateMode::Discard, 1024, 512),
+            Err(Qcow2Error::UnsupportedFallocateMode(FallocateMode::Discard))
+        ));
+        assert_eq!(read_vec(&image, 1024, 1024), vec![2u8; 1024]);
+        base.remove().unwrap();
+    }
+
+    #[test]
+    fn test_backing_files() {
+        // Raw base image.
+        let mut base = TempFile::new().unwrap();
+        let base_data = utils::rand::rand_alphanumerics(8192).as_bytes().to_vec();
+        base.as_file().write_all(&base_data).unwrap();
+        let base_name = base.as_path().file_name().unwrap().to_str().unwrap();
+
+        // qcow2 image on top of the raw image, named relative to its directory.
+        let middle = create_image(DISK_SIZE, Some((base_name, "raw")));
+        let mut image = open_image(&middle);
+        assert_eq!(read_vec(&image, 0, 8192), base_data);
+        // Past the end of the base image.
+        assert_eq!(read_vec(&image, 8192, 4096), vec![0u8; 4096]);
+
+        // Partial writes preserve the rest of the cluster.
+        image.write_at(1000, &[1u8; 10]).unwrap();
+        let mut expected = base_data.clone();
+        expected[1000..1010].fill(1);
+        assert_eq!(read_vec(&image, 0, 8192), expected);
+        // Zeroing clusters taken from the base image allocates them.
+        image.write_zeroes(4096, 512).unwrap();
+        expected[4096..4608].fill(0);
+        assert_eq!(read_vec(&image, 0, 8192), expected);
+        check_refcounts(&image, &[]);
+
+        // The base image is not modified.
+        let mut buf = vec![0u8; 8192];
+        base.as_file().read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, base_data);
+
+        // qcow2 image on top of the qcow2 image, whose format is probed.
+        let middle_path = middle.as_path().to_str().unwrap();
+        let top = create_image(DISK_SIZE, None);
+        let mut header = vec![0u8; u64_to_usize(CLUSTER_SIZE)];
+        top.as_file().read_exact_at(&mut header, 0).unwrap();
+        header[8..16].copy_from_slice(&256u64.to_be_bytes());
+        header[16..20].copy_from_slice(&u32::try_from(middle_path.len()).unwrap().to_be_bytes());
+        header[256..256 + middle_path.len()].copy_from_slice(middle_path.as_bytes());
+        top.as_file().write_all_at(&header, 0).unwrap();
+
+        let mut image = open_image(&top);
+        assert_eq!(read_vec(&image, 0, 8192), expected);
+        image.write_at(0, &[2u8; 10]).unwrap();
+        expected[..10].fill(2);
+        assert_eq!(read_vec(&image, 0, 8192), expected);
+        assert_eq!(read_vec(&open_image(&middle), 0, 10), base_data[..10]);
+
+        // Backing files referring to themselves are rejected.
+        let looping = create_image(DISK_SIZE, None);
+        let looping_path = looping.as_path().to_str().unwrap();
+        header[16..20].copy_from_slice(&u32::try_from(looping_path.len()).unwrap().to_be_bytes());
+        header[256..256 + looping_path.len()].copy_from_slice(looping_path.as_bytes());
+        looping.as_file().write_all_at(&header, 0).unwrap();
+        let file = looping.as_file().try_clone().unwrap();
+        assert!(matches!(
+            Qcow2Image::open(file, looping.as_path()),
+            Err(Qcow2Error::ChainTooLong)
+        ));
+        base.remove().unwrap();
+    }
+
+    #[test]
+    fn test_engine() {
+        let temp_file = create_image(DISK_SIZE, None);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(temp_file.as_path())
+            .unwrap();
+        let mut engine = Qcow2FileEngine::from_file(file, temp_file.as_path()).unwrap();
+        assert_eq!(engine.virtual_size(), DISK_SIZE);
+
+        let mem = GuestMemoryMmap::from_raw_regions(
+            &[(GuestAddress(0), 0x4000)],
+            false,
+            HugePageConfig::None,
+        )
+        .unwrap();
+        let data = utils::rand::rand_alphanumerics(0x2000).as_bytes().to_vec();
+        mem.write_slice(&data, GuestAddress(0)).unwrap();
+        assert_eq!(
+            engine.write(512, &mem, GuestAddress(0), 0x2000).unwrap(),
+            0x2000
+        );
+        assert_eq!(
+            engine
+                .read(512, &mem, GuestAddress(0x2000), 0x2000)
+                .unwrap(),
+            0x2000
+        );
+        let mut buf = vec![0u8; 0x2000];
+        mem.read_slice(&mut buf, GuestAddress(0x2000)).unwrap();
+        assert_eq!(buf, data);
+
+        engine
+            .fallocate(FallocateMode::PunchHole, 512, 0x1000)
+            .unwrap();
+        engine.flush().unwrap();
+        engine.read(0, &mem, GuestAddress(0), 0x2000).unwrap();
+        mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+        assert_eq!(buf[..0x1200], vec![0u8; 0x1200]);
+        assert_eq!(buf[0x1200..], data[0x1000..0x1e00]);
+
+        // Guest memory errors.
+        assert!(matches!(
+            engine.read(0, &mem, GuestAddress(0x3000), 0x2000),
+            Err(Qcow2Error::GuestMemory(_))
+        ));
+    }
+}
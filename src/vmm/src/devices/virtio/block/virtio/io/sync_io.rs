@@ -2,14 +2,18 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::fs::File;
-use std::io::{Seek, SeekFrom, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
 
 use vm_memory::{GuestMemoryError, ReadVolatile, WriteVolatile};
 
-use crate::vstate::memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
+use super::{AlignedBuffer, CryptError, FallocateMode, XtsCipher};
+use crate::vstate::memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
 
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
 pub enum SyncIoError {
+    /// Fallocate: {0}
+    Fallocate(std::io::Error),
     /// Flush: {0}
     Flush(std::io::Error),
     /// Seek: {0}
@@ -18,11 +22,14 @@ pub enum SyncIoError {
     SyncAll(std::io::Error),
     /// Transfer: {0}
     Transfer(GuestMemoryError),
+    /// Encryption: {0}
+    Crypt(CryptError),
 }
 
 #[derive(Debug)]
 pub struct SyncFileEngine {
     file: File,
+    cipher: Option<XtsCipher>,
 }
 
 // SAFETY: `File` is send and ultimately a POD.
@@ -30,7 +37,7 @@ unsafe impl Send for SyncFileEngine {}
 
 impl SyncFileEngine {
     pub fn from_file(file: File) -> SyncFileEngine {
-        SyncFileEngine { file }
+        SyncFileEngine { file, cipher: None }
     }
 
     #[cfg(test)]
@@ -43,6 +50,11 @@ impl SyncFileEngine {
         self.file = file
     }
 
+    /// Encrypt the data written to the backing file, and decrypt the data read from it.
+    pub fn set_cipher(&mut self, cipher: XtsCipher) {
+        self.cipher = Some(cipher)
+    }
+
     pub fn read(
         &mut self,
         offset: u64,
@@ -53,9 +65,23 @@ impl SyncFileEngine {
         self.file
             .seek(SeekFrom::Start(offset))
             .map_err(SyncIoError::Seek)?;
-        mem.get_slice(addr, count as usize)
-            .and_then(|mut slice| Ok(self.file.read_exact_volatile(&mut slice)?))
-            .map_err(SyncIoError::Transfer)?;
+        match &self.cipher {
+            // The data is decrypted in a buffer, as the guest must never see the ciphertext.
+            Some(cipher) => {
+                let mut buf = vec![0u8; count as usize];
+                self.file
+                    .read_exact(&mut buf)
+                    .map_err(|err| SyncIoError::Transfer(GuestMemoryError::IOError(err)))?;
+                cipher
+                    .decrypt(offset, &mut buf)
+                    .map_err(SyncIoError::Crypt)?;
+                mem.write_slice(&buf, addr).map_err(SyncIoError::Transfer)?;
+            }
+            None => mem
+                .get_slice(addr, count as usize)
+                .and_then(|mut slice| Ok(self.file.read_exact_volatile(&mut slice)?))
+                .map_err(SyncIoError::Transfer)?,
+        }
         Ok(count)
     }
 
@@ -69,12 +95,92 @@ impl SyncFileEngine {
         self.file
             .seek(SeekFrom::Start(offset))
             .map_err(SyncIoError::Seek)?;
-        mem.get_slice(addr, count as usize)
-            .and_then(|slice| Ok(self.file.write_all_volatile(&slice)?))
+        match &self.cipher {
+            Some(cipher) => {
+                let mut buf = vec![0u8; count as usize];
+                mem.read_slice(&mut buf, addr)
+                    .map_err(SyncIoError::Transfer)?;
+                cipher
+                    .encrypt(offset, &mut buf)
+                    .map_err(SyncIoError::Crypt)?;
+                self.file
+                    .write_all(&buf)
+                    .map_err(|err| SyncIoError::Transfer(GuestMemoryError::IOError(err)))?;
+            }
+            None => mem
+                .get_slice(addr, count as usize)
+                .and_then(|slice| Ok(self.file.write_all_volatile(&slice)?))
+                .map_err(SyncIoError::Transfer)?,
+        }
+        Ok(count)
+    }
+
+    /// Reads into a buffer aligned to `alignment` bytes, for direct IO into a guest buffer which
+    /// isn't aligned.
+    pub fn read_bounced(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        alignment: usize,
+    ) -> Result<u32, SyncIoError> {
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .map_err(SyncIoError::Seek)?;
+        let mut buf = AlignedBuffer::new(count as usize, alignment);
+        self.file
+            .read_exact(buf.as_mut_slice())
+            .map_err(|err| SyncIoError::Transfer(GuestMemoryError::IOError(err)))?;
+        mem.write_slice(buf.as_slice(), addr)
+            .map_err(SyncIoError::Transfer)?;
+        Ok(count)
+    }
+
+    /// Writes from a buffer aligned to `alignment` bytes, for direct IO from a guest buffer
+    /// which isn't aligned.
+    pub fn write_bounced(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        alignment: usize,
+    ) -> Result<u32, SyncIoError> {
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .map_err(SyncIoError::Seek)?;
+        let mut buf = AlignedBuffer::new(count as usize, alignment);
+        mem.read_slice(buf.as_mut_slice(), addr)
             .map_err(SyncIoError::Transfer)?;
+        self.file
+            .write_all(buf.as_slice())
+            .map_err(|err| SyncIoError::Transfer(GuestMemoryError::IOError(err)))?;
         Ok(count)
     }
 
+    pub fn fallocate(
+        &mut self,
+        mode: FallocateMode,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), SyncIoError> {
+        // Deallocated ranges don't read as encrypted zeros.
+        if self.cipher.is_some() && mode != FallocateMode::Discard {
+            return Err(SyncIoError::Crypt(CryptError::WriteZeroes));
+        }
+        let invalid_input =
+            |_| SyncIoError::Fallocate(std::io::Error::from(std::io::ErrorKind::InvalidInput));
+        let offset = libc::off_t::try_from(offset).map_err(invalid_input)?;
+        let len = libc::off_t::try_from(len).map_err(invalid_input)?;
+        // SAFETY: `fallocate` only operates on the file referred to by the valid descriptor.
+        let ret = unsafe { libc::fallocate(self.file.as_raw_fd(), mode.flags(), offset, len) };
+        if ret < 0 {
+            return Err(SyncIoError::Fallocate(std::io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
     pub fn flush(&mut self) -> Result<(), SyncIoError> {
         // flush() first to force any cached data out of rust buffers.
         self.file.flush().map_err(SyncIoError::Flush)?;
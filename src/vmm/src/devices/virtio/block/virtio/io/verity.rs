@@ -0,0 +1,411 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Integrity verification of read-only images through a hash tree.
+//!
+//! The image is split in blocks of [`VERITY_BLOCK_SIZE`] bytes, whose SHA-256 digests form the
+//! lowest level of a Merkle tree. Every level is made of hash blocks of the same size, each one
+//! holding the digests of 128 blocks of the level below it, and the digest of the single block
+//! of the top level is the root hash, which the user provides from a trusted source. Like with
+//! dm-verity (format 1), each digest is computed over an optional salt followed by the block, and
+//! the levels are stored in the hash tree file starting from the top one, so that trees created
+//! by `veritysetup format --no-superblock` can be used as they are.
+//!
+//! The whole tree is checked against the root hash when the drive is created and only the
+//! digests of the data blocks are kept, so that the hash tree file is not read afterwards. Every
+//! read then checks the blocks it covers before copying them to guest memory.
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+use aws_lc_rs::digest;
+use utils::u64_to_usize;
+use vm_memory::GuestMemoryError;
+
+use crate::vstate::memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+/// Size of the data blocks and of the hash blocks of the tree.
+pub const VERITY_BLOCK_SIZE: u64 = 4096;
+// Size of a SHA-256 digest.
+const DIGEST_SIZE: usize = 32;
+// Each hash block holds 4096 / 32 = 128 digests.
+const DIGESTS_PER_BLOCK_SHIFT: u64 = 7;
+
+/// Errors associated with integrity verification.
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum VerityError {
+    /// Cannot access the image or hash tree files: {0}
+    Io(std::io::Error),
+    /// The root hash is not the hex encoding of a SHA-256 digest.
+    InvalidRootHash,
+    /// The salt is not hex encoded.
+    InvalidSalt,
+    /// The image is {0} bytes long, which is not a non-zero multiple of the 4096 bytes blocks.
+    InvalidImageSize(u64),
+    /// The hash tree file is {0} bytes long, but the tree takes {1} bytes.
+    HashTreeSize(u64, u64),
+    /// The hash tree does not match the root hash.
+    HashTreeMismatch,
+    /// Hash mismatch of block {0}: the image was tampered with.
+    HashMismatch(u64),
+    /// The image is {0} bytes long instead of {1} bytes.
+    SizeMismatch(u64, u64),
+    /// Verified images cannot be modified.
+    ReadOnly,
+    /// Cannot access guest memory: {0}
+    GuestMemory(GuestMemoryError),
+}
+
+impl From<std::io::Error> for VerityError {
+    fn from(err: std::io::Error) -> Self {
+        VerityError::Io(err)
+    }
+}
+
+fn decode_hex(encoded: &str) -> Option<Vec<u8>> {
+    if encoded.len() % 2 != 0 {
+        return None;
+    }
+    (0..encoded.len())
+        .step_by(2)
+        .map(|i| {
+            encoded
+                .get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect()
+}
+
+// Number of levels of the tree of an image made of `data_blocks` blocks. An image made of a
+// single block has no tree, the digest of its block being the root hash.
+fn level_count(data_blocks: u64) -> u64 {
+    let mut levels = 0;
+    while (data_blocks - 1) >> (DIGESTS_PER_BLOCK_SHIFT * levels) != 0 {
+        levels += 1;
+    }
+    levels
+}
+
+// Number of hash blocks of the given level of the tree, the lowest one being level 0.
+fn level_size(data_blocks: u64, level: u64) -> u64 {
+    ((data_blocks - 1) >> (DIGESTS_PER_BLOCK_SHIFT * (level + 1))) + 1
+}
+
+fn block_digest(salt: &[u8], block: &[u8]) -> digest::Digest {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(salt);
+    ctx.update(block);
+    ctx.finish()
+}
+
+/// File engine checking the blocks read from a read-only image against a hash tree.
+#[derive(Debug)]
+pub struct VerityFileEngine {
+    file: File,
+    disk_size: u64,
+    salt: Vec<u8>,
+    // Trusted digests of the data blocks.
+    digests: Vec<u8>,
+    // Holds the blocks covered by a read while they are checked.
+    buffer: Vec<u8>,
+}
+
+impl VerityFileEngine {
+    /// Checks the hash tree stored in `hash_tree` against the hex encoded `root_hash` and
+    /// returns an engine reading `file` through it.
+    pub fn new(
+        file: File,
+        hash_tree: File,
+        root_hash: &str,
+        salt: Option<&str>,
+    ) -> Result<Self, VerityError> {
+        let root_hash = decode_hex(root_hash)
+            .filter(|hash| hash.len() == DIGEST_SIZE)
+            .ok_or(VerityError::InvalidRootHash)?;
+        let salt = match salt {
+            Some(salt) => decode_hex(salt).ok_or(VerityError::InvalidSalt)?,
+            None => Vec::new(),
+        };
+
+        let disk_size = file.metadata()?.len();
+        if disk_size == 0 || disk_size % VERITY_BLOCK_SIZE != 0 {
+            return Err(VerityError::InvalidImageSize(disk_size));
+        }
+        let data_blocks = disk_size / VERITY_BLOCK_SIZE;
+        let levels = level_count(data_blocks);
+        let tree_size = (0..levels)
+            .map(|level| level_size(data_blocks, level) * VERITY_BLOCK_SIZE)
+            .sum::<u64>();
+        let hash_tree_size = hash_tree.metadata()?.len();
+        if hash_tree_size < tree_size {
+            return Err(VerityError::HashTreeSize(hash_tree_size, tree_size));
+        }
+        let mut tree = vec![0u8; u64_to_usize(tree_size)];
+        hash_tree.read_exact_at(&mut tree, 0)?;
+
+        // Check the levels from the top one, each against the digests held by the level above.
+        let mut digests = root_hash.as_slice();
+        let mut level_start = 0;
+        for level in (0..levels).rev() {
+            let level_end =
+                level_start + u64_to_usize(level_size(data_blocks, level) * VERITY_BLOCK_SIZE);
+            let hash_blocks = &tree[level_start..level_end];
+            for (block, expected) in hash_blocks
+                .chunks_exact(u64_to_usize(VERITY_BLOCK_SIZE))
+                .zip(digests.chunks_exact(DIGEST_SIZE))
+            {
+                if block_digest(&salt, block).as_ref() != expected {
+                    return Err(VerityError::HashTreeMismatch);
+                }
+            }
+            digests = hash_blocks;
+            level_start = level_end;
+        }
+
+        Ok(VerityFileEngine {
+            file,
+            disk_size,
+            salt,
+            // The lowest level may end with unused digests.
+            digests: digests[..u64_to_usize(data_blocks) * DIGEST_SIZE].to_vec(),
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Replaces the image, which must have the same size as the previous one. Its blocks are
+    /// still checked against the same hash tree.
+    pub fn update_file(&mut self, file: File) -> Result<(), VerityError> {
+        let size = file.metadata()?.len();
+        if size != self.disk_size {
+            return Err(VerityError::SizeMismatch(self.disk_size, size));
+        }
+        self.file = file;
+        Ok(())
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn read(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32, VerityError> {
+        if count == 0 {
+            return Ok(0);
+        }
+        // Only whole blocks can be checked.
+        let first_block = offset / VERITY_BLOCK_SIZE;
+        let start = first_block * VERITY_BLOCK_SIZE;
+        let end = (offset + u64::from(count)).div_ceil(VERITY_BLOCK_SIZE) * VERITY_BLOCK_SIZE;
+        self.buffer.resize(u64_to_usize(end - start), 0);
+        self.file.read_exact_at(&mut self.buffer, start)?;
+
+        for (index, block) in self
+            .buffer
+            .chunks_exact(u64_to_usize(VERITY_BLOCK_SIZE))
+            .enumerate()
+        {
+            let block_index = first_block + index as u64;
+            let digest_start = u64_to_usize(block_index) * DIGEST_SIZE;
+            let expected = &self.digests[digest_start..digest_start + DIGEST_SIZE];
+            if block_digest(&self.salt, block).as_ref() != expected {
+                return Err(VerityError::HashMismatch(block_index));
+            }
+        }
+
+        let data_start = u64_to_usize(offset - start);
+        mem.write_slice(&self.buffer[data_start..data_start + count as usize], addr)
+            .map_err(VerityError::GuestMemory)?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use utils::tempfile::TempFile;
+
+    use super::*;
+    use crate::vmm_config::machine_config::HugePageConfig;
+    use crate::vstate::memory::GuestMemoryExtension;
+
+    const MEM_LEN: usize = 0x8000;
+
+    fn encode_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// Writes the hash tree of `image` to `hash_tree` and returns the hex encoded root hash.
+    pub(crate) fn create_hash_tree(image: &[u8], salt: &[u8], hash_tree: &File) -> String {
+        let block_size = u64_to_usize(VERITY_BLOCK_SIZE);
+        let mut levels: Vec<Vec<u8>> = Vec::new();
+        let mut blocks = image.to_vec();
+        while blocks.len() > block_size {
+            let mut level: Vec<u8> = blocks
+                .chunks_exact(block_size)
+                .flat_map(|block| block_digest(salt, block).as_ref().to_vec())
+                .collect();
+            level.resize(level.len().div_ceil(block_size) * block_size, 0);
+            levels.push(level.clone());
+            blocks = level;
+        }
+
+        let tree: Vec<u8> = levels.into_iter().rev().flatten().collect();
+        hash_tree.write_all_at(&tree, 0).unwrap();
+        encode_hex(block_digest(salt, &blocks).as_ref())
+    }
+
+    struct Files {
+        image: TempFile,
+        hash_tree: TempFile,
+        data: Vec<u8>,
+        root_hash: String,
+    }
+
+    impl Files {
+        fn new(blocks: u64, salt: &[u8]) -> Self {
+            let image = TempFile::new().unwrap();
+            let hash_tree = TempFile::new().unwrap();
+            let data = utils::rand::rand_alphanumerics(u64_to_usize(blocks * VERITY_BLOCK_SIZE))
+                .as_bytes()
+                .to_vec();
+            image.as_file().write_all_at(&data, 0).unwrap();
+            let root_hash = create_hash_tree(&data, salt, hash_tree.as_file());
+            Files {
+                image,
+                hash_tree,
+                data,
+                root_hash,
+            }
+        }
+
+        fn engine(&self, salt: Option<&str>) -> Result<VerityFileEngine, VerityError> {
+            VerityFileEngine::new(
+                self.image.as_file().try_clone().unwrap(),
+                self.hash_tree.as_file().try_clone().unwrap(),
+                &self.root_hash,
+                salt,
+            )
+        }
+    }
+
+    fn create_mem() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_raw_regions(
+            &[(GuestAddress(0), MEM_LEN)],
+            false,
+            HugePageConfig::None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_level_count() {
+        assert_eq!(level_count(1), 0);
+        assert_eq!(level_count(2), 1);
+        assert_eq!(level_count(128), 1);
+        assert_eq!(level_count(129), 2);
+        assert_eq!(level_count(128 * 128 + 1), 3);
+        assert_eq!(level_size(129, 0), 2);
+        assert_eq!(level_size(129, 1), 1);
+    }
+
+    #[test]
+    fn test_verity_read() {
+        let mem = create_mem();
+        // An image made of a single block, and images with one and two levels.
+        for blocks in [1, 5, 130] {
+            let files = Files::new(blocks, &[]);
+            let mut engine = files.engine(None).unwrap();
+
+            // Unaligned read.
+            let (offset, len) = (100, 5000);
+            assert_eq!(
+                engine.read(offset, &mem, GuestAddress(0), len).unwrap(),
+                len
+            );
+            let mut buf = vec![0u8; len as usize];
+            mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+            assert_eq!(buf, files.data[100..5100]);
+
+            // Read of the last block.
+            let offset = (blocks - 1) * VERITY_BLOCK_SIZE;
+            assert_eq!(
+                engine.read(offset, &mem, GuestAddress(0), 4096).unwrap(),
+                4096
+            );
+            let mut buf = vec![0u8; 4096];
+            mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+            assert_eq!(buf, files.data[u64_to_usize(offset)..]);
+        }
+    }
+
+    #[test]
+    fn test_verity_salt() {
+        let files = Files::new(5, &[0xde, 0xad, 0xbe, 0xef]);
+        let mut engine = files.engine(Some("deadbeef")).unwrap();
+        engine.read(0, &create_mem(), GuestAddress(0), 512).unwrap();
+
+        assert!(matches!(
+            files.engine(None),
+            Err(VerityError::HashTreeMismatch)
+        ));
+        assert!(matches!(
+            files.engine(Some("xyz")),
+            Err(VerityError::InvalidSalt)
+        ));
+    }
+
+    #[test]
+    fn test_verity_tampering() {
+        let mem = create_mem();
+        let files = Files::new(130, &[]);
+
+        // Tampered data blocks fail the reads covering them.
+        let mut engine = files.engine(None).unwrap();
+        files
+            .image
+            .as_file()
+            .write_all_at(b"x", 4096 * 129 + 7)
+            .unwrap();
+        engine.read(0, &mem, GuestAddress(0), 4096).unwrap();
+        assert!(matches!(
+            engine.read(4096 * 128 + 4000, &mem, GuestAddress(0), 512),
+            Err(VerityError::HashMismatch(129))
+        ));
+
+        // Tampered hash trees are rejected.
+        files.hash_tree.as_file().write_all_at(b"x", 5000).unwrap();
+        assert!(matches!(
+            files.engine(None),
+            Err(VerityError::HashTreeMismatch)
+        ));
+        files.hash_tree.as_file().set_len(4096).unwrap();
+        assert!(matches!(
+            files.engine(None),
+            Err(VerityError::HashTreeSize(4096, 12288))
+        ));
+
+        // Invalid root hashes and images.
+        let engine = |root_hash: &str| {
+            VerityFileEngine::new(
+                files.image.as_file().try_clone().unwrap(),
+                files.hash_tree.as_file().try_clone().unwrap(),
+                root_hash,
+                None,
+            )
+        };
+        assert!(matches!(engine("abcd"), Err(VerityError::InvalidRootHash)));
+        assert!(matches!(
+            engine(&"g".repeat(64)),
+            Err(VerityError::InvalidRootHash)
+        ));
+        files.image.as_file().set_len(4097).unwrap();
+        assert!(matches!(
+            engine(&files.root_hash),
+            Err(VerityError::InvalidImageSize(4097))
+        ));
+    }
+}
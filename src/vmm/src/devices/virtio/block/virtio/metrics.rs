@@ -178,6 +178,14 @@ pub struct BlockDeviceMetrics {
     pub read_count: SharedIncMetric,
     /// Number of successful write operations.
     pub write_count: SharedIncMetric,
+    /// Number of successful discard operations.
+    pub discard_count: SharedIncMetric,
+    /// Number of successful write zeroes operations.
+    pub write_zeroes_count: SharedIncMetric,
+    /// Number of direct IO requests transferred through a bounce buffer.
+    pub bounced_reqs_count: SharedIncMetric,
+    /// Number of reads failing integrity verification.
+    pub verity_fails: SharedIncMetric,
     /// Duration of all read operations.
     pub read_agg: LatencyAggregateMetrics,
     /// Duration of all write operations.
@@ -225,6 +233,12 @@ impl BlockDeviceMetrics {
         self.write_bytes.add(other.write_bytes.fetch_diff());
         self.read_count.add(other.read_count.fetch_diff());
         self.write_count.add(other.write_count.fetch_diff());
+        self.discard_count.add(other.discard_count.fetch_diff());
+        self.write_zeroes_count
+            .add(other.write_zeroes_count.fetch_diff());
+        self.bounced_reqs_count
+            .add(other.bounced_reqs_count.fetch_diff());
+        self.verity_fails.add(other.verity_fails.fetch_diff());
         self.read_agg.sum_us.add(other.read_agg.sum_us.fetch_diff());
         self.write_agg
             .sum_us
@@ -19,14 +19,17 @@ pub use crate::devices::virtio::block::CacheType;
 use crate::devices::virtio::queue::FIRECRACKER_MAX_QUEUE_SIZE;
 
 /// Size of config space for block device.
-pub const BLOCK_CONFIG_SPACE_SIZE: usize = 8;
+pub const BLOCK_CONFIG_SPACE_SIZE: usize = 60;
 /// Sector shift for block device.
 pub const SECTOR_SHIFT: u8 = 9;
 /// Size of block sector.
 pub const SECTOR_SIZE: u32 = (0x01_u32) << SECTOR_SHIFT;
-/// The number of queues of block device.
-pub const BLOCK_NUM_QUEUES: usize = 1;
-pub const BLOCK_QUEUE_SIZES: [u16; BLOCK_NUM_QUEUES] = [FIRECRACKER_MAX_QUEUE_SIZE];
+/// The default number of queues of block device.
+pub const BLOCK_DEFAULT_NUM_QUEUES: u16 = 1;
+/// The maximum number of queues of block device.
+pub const BLOCK_MAX_NUM_QUEUES: u16 = 16;
+/// The size of each queue of block device.
+pub const BLOCK_QUEUE_SIZE: u16 = FIRECRACKER_MAX_QUEUE_SIZE;
 // The virtio queue can hold up to 256 descriptors, but 1 request spreads across 2-3 descriptors.
 // So we can use 128 IO_URING entries without ever triggering a FullSq Error.
 /// Maximum number of io uring entries we allow in the queue.
@@ -61,6 +64,29 @@ pub enum VirtioBlockError {
     EventFd(std::io::Error),
     /// Error creating an irqfd: {0}
     IrqTrigger(std::io::Error),
+    /// Invalid number of queues: {0}
+    InvalidNumQueues(u16),
+    /// Async IO options are only supported by the Async engine.
+    AsyncIoConfig,
+    /// Direct IO is not supported by qcow2 images, overlays and verified drives.
+    DirectIoUnsupported,
+    /// Cannot get the logical block size of the backing file for direct IO: {0}
+    LogicalBlockSize(std::io::Error),
+    /// The backing file has logical blocks of {0} bytes, while the driver uses blocks of {1}
+    /// bytes.
+    LogicalBlockSizeMismatch(u32, u32),
+    /// Integrity verification is only supported by read-only drives without overlay.
+    VerityUnsupported,
+    /// NBD drives don't support overlays, integrity verification, direct IO and file updates.
+    NbdUnsupported,
+    /// Encryption is only supported by raw drives using the Sync or Async engine, without
+    /// overlay, integrity verification, NBD or direct IO.
+    EncryptionUnsupported,
+    /// Invalid drive encryption key: {0}
+    Encryption(io::CryptError),
+    /// The key of an encrypted drive must be provided through a drive override when loading a
+    /// snapshot.
+    MissingEncryptionKey,
     /// Error coming from the rate limiter: {0}
     RateLimiter(std::io::Error),
     /// Persistence error: {0}
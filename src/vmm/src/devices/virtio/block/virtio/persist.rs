@@ -12,11 +12,14 @@ use utils::eventfd::EventFd;
 use super::device::DiskProperties;
 use super::*;
 use crate::devices::virtio::block::persist::BlockConstructorArgs;
-use crate::devices::virtio::block::virtio::device::FileEngineType;
+use crate::devices::virtio::block::virtio::device::{
+    AsyncIoConfig, DriveEncryptionConfig, FileEngineType, HostCacheMode, NbdConfig, OverlayConfig,
+    VerityConfig,
+};
 use crate::devices::virtio::block::virtio::metrics::BlockMetricsPerDevice;
 use crate::devices::virtio::device::{DeviceState, IrqTrigger};
 use crate::devices::virtio::gen::virtio_blk::VIRTIO_BLK_F_RO;
-use crate::devices::virtio::persist::VirtioDeviceState;
+use crate::devices::virtio::persist::{PersistError, VirtioDeviceState};
 use crate::devices::virtio::TYPE_BLOCK;
 use crate::logger::warn;
 use crate::rate_limiter::persist::RateLimiterState;
@@ -33,6 +36,8 @@ pub enum FileEngineTypeState {
     Sync,
     /// Async File Engine.
     Async,
+    /// Qcow2 File Engine.
+    Qcow2,
 }
 
 impl From<FileEngineType> for FileEngineTypeState {
@@ -40,6 +45,7 @@ impl From<FileEngineType> for FileEngineTypeState {
         match file_engine_type {
             FileEngineType::Sync => FileEngineTypeState::Sync,
             FileEngineType::Async => FileEngineTypeState::Async,
+            FileEngineType::Qcow2 => FileEngineTypeState::Qcow2,
         }
     }
 }
@@ -49,6 +55,7 @@ impl From<FileEngineTypeState> for FileEngineType {
         match file_engine_type_state {
             FileEngineTypeState::Sync => FileEngineType::Sync,
             FileEngineTypeState::Async => FileEngineType::Async,
+            FileEngineTypeState::Qcow2 => FileEngineType::Qcow2,
         }
     }
 }
@@ -56,6 +63,37 @@ impl From<FileEngineTypeState> for FileEngineType {
 /// Holds info about the block device. Gets saved in snapshot.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VirtioBlockState {
+    /// Unique identifier of the drive.
+    pub id: String,
+    partuuid: Option<String>,
+    cache_type: CacheType,
+    root_device: bool,
+    /// Path of the file backing the drive.
+    pub disk_path: String,
+    virtio_state: VirtioDeviceState,
+    rate_limiter_state: RateLimiterState,
+    file_engine_type: FileEngineTypeState,
+    /// Copy-on-write overlay of the drive, if any.
+    pub overlay: Option<OverlayConfig>,
+    async_io: AsyncIoConfig,
+    host_cache_mode: HostCacheMode,
+    logical_block_size: u32,
+    /// Rate limiter group the drive is a member of, if any.
+    pub rate_limiter_group: Option<String>,
+    /// Hash tree checking the integrity of the drive, if any.
+    pub verity: Option<VerityConfig>,
+    /// Export of the NBD server backing the drive, if any.
+    pub nbd: Option<NbdConfig>,
+    /// Whether the data of the drive is encrypted. The key itself is never saved.
+    pub encrypted: bool,
+    /// Key of the encrypted drive, provided again when loading the snapshot.
+    #[serde(skip)]
+    pub encryption: Option<DriveEncryptionConfig>,
+}
+
+/// Layout of [`VirtioBlockState`] in snapshot format version 2.0.0.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VirtioBlockStateV2 {
     id: String,
     partuuid: Option<String>,
     cache_type: CacheType,
@@ -66,6 +104,32 @@ pub struct VirtioBlockState {
     file_engine_type: FileEngineTypeState,
 }
 
+impl From<VirtioBlockStateV2> for VirtioBlockState {
+    fn from(state: VirtioBlockStateV2) -> Self {
+        // Drives saved with format version 2.0.0 only went through the host page cache and
+        // could not use any of the features added since.
+        VirtioBlockState {
+            id: state.id,
+            partuuid: state.partuuid,
+            cache_type: state.cache_type,
+            root_device: state.root_device,
+            disk_path: state.disk_path,
+            virtio_state: state.virtio_state,
+            rate_limiter_state: state.rate_limiter_state,
+            file_engine_type: state.file_engine_type,
+            overlay: None,
+            async_io: AsyncIoConfig::default(),
+            host_cache_mode: HostCacheMode::Buffered,
+            logical_block_size: SECTOR_SIZE,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encrypted: false,
+            encryption: None,
+        }
+    }
+}
+
 impl Persist<'_> for VirtioBlock {
     type State = VirtioBlockState;
     type ConstructorArgs = BlockConstructorArgs;
@@ -82,6 +146,18 @@ impl Persist<'_> for VirtioBlock {
             virtio_state: VirtioDeviceState::from_device(self),
             rate_limiter_state: self.rate_limiter.save(),
             file_engine_type: FileEngineTypeState::from(self.file_engine_type()),
+            overlay: self.disk.overlay.clone(),
+            async_io: self.disk.async_io,
+            host_cache_mode: self.disk.host_cache_mode,
+            logical_block_size: self.disk.logical_block_size,
+            rate_limiter_group: self
+                .rate_limiter
+                .group()
+                .map(|group| group.id().to_string()),
+            verity: self.disk.verity.clone(),
+            nbd: self.disk.nbd.clone(),
+            encrypted: self.disk.cipher.is_some(),
+            encryption: None,
         }
     }
 
@@ -93,35 +169,71 @@ impl Persist<'_> for VirtioBlock {
         let rate_limiter = RateLimiter::restore((), &state.rate_limiter_state)
             .map_err(VirtioBlockError::RateLimiter)?;
 
-        let disk_properties = DiskProperties::new(
-            state.disk_path.clone(),
-            is_read_only,
-            state.file_engine_type.into(),
-        )
-        .or_else(|err| match err {
-            VirtioBlockError::FileEngine(io::BlockIoError::UnsupportedEngine(
-                FileEngineType::Async,
-            )) => {
-                // If the kernel does not support `Async`, fallback to `Sync`.
-                warn!(
-                    "The \"Async\" io_engine is supported for kernels starting with {}. \
-                     Defaulting to \"Sync\" mode.",
-                    utils::kernel_version::min_kernel_version_for_io_uring()
-                );
-                DiskProperties::new(state.disk_path.clone(), is_read_only, FileEngineType::Sync)
-            }
-            other => Err(other),
-        })?;
+        // The device has as many queues as were saved in the snapshot.
+        let num_queues = u16::try_from(state.virtio_state.queues.len())
+            .ok()
+            .filter(|num_queues| (1..=BLOCK_MAX_NUM_QUEUES).contains(num_queues))
+            .ok_or(VirtioBlockError::Persist(PersistError::InvalidInput))?;
+
+        let mut disk_properties = match &state.nbd {
+            Some(nbd) => DiskProperties::new_nbd(nbd.clone(), is_read_only, FileEngineType::Sync)?,
+            None => DiskProperties::new(
+                state.disk_path.clone(),
+                is_read_only,
+                state.file_engine_type.into(),
+                state.overlay.clone(),
+                state.verity.clone(),
+                state.async_io,
+                state.host_cache_mode,
+            )
+            .or_else(|err| match err {
+                VirtioBlockError::FileEngine(io::BlockIoError::UnsupportedEngine(
+                    FileEngineType::Async,
+                )) => {
+                    // If the kernel does not support `Async`, fallback to `Sync`.
+                    warn!(
+                        "The \"Async\" io_engine is supported for kernels starting with {}. \
+                         Defaulting to \"Sync\" mode.",
+                        utils::kernel_version::min_kernel_version_for_io_uring()
+                    );
+                    DiskProperties::new(
+                        state.disk_path.clone(),
+                        is_read_only,
+                        FileEngineType::Sync,
+                        state.overlay.clone(),
+                        state.verity.clone(),
+                        AsyncIoConfig::default(),
+                        state.host_cache_mode,
+                    )
+                }
+                other => Err(other),
+            })?,
+        };
+        // The driver keeps using the logical block size of the snapshotted backing file.
+        disk_properties.keep_logical_block_size(state.logical_block_size)?;
+        if state.encrypted {
+            let config = state
+                .encryption
+                .as_ref()
+                .ok_or(VirtioBlockError::MissingEncryptionKey)?;
+            let cipher =
+                io::XtsCipher::from_config(config).map_err(VirtioBlockError::Encryption)?;
+            disk_properties.set_cipher(cipher)?;
+        }
+        disk_properties.set_num_queues(num_queues, is_read_only)?;
 
-        let queue_evts = [EventFd::new(libc::EFD_NONBLOCK).map_err(VirtioBlockError::EventFd)?];
+        let queue_evts = (0..num_queues)
+            .map(|_| EventFd::new(libc::EFD_NONBLOCK))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(VirtioBlockError::EventFd)?;
 
         let queues = state
             .virtio_state
             .build_queues_checked(
                 &constructor_args.mem,
                 TYPE_BLOCK,
-                BLOCK_NUM_QUEUES,
-                FIRECRACKER_MAX_QUEUE_SIZE,
+                usize::from(num_queues),
+                BLOCK_QUEUE_SIZE,
             )
             .map_err(VirtioBlockError::Persist)?;
 
@@ -166,7 +278,9 @@ impl Persist<'_> for VirtioBlock {
 mod tests {
     use std::sync::atomic::Ordering;
 
+    use base64::Engine;
     use utils::tempfile::TempFile;
+    use zeroize::Zeroizing;
 
     use super::*;
     use crate::devices::virtio::block::virtio::device::VirtioBlockConfig;
@@ -174,6 +288,21 @@ mod tests {
     use crate::devices::virtio::test_utils::default_mem;
     use crate::snapshot::Snapshot;
 
+    impl From<VirtioBlockState> for VirtioBlockStateV2 {
+        fn from(state: VirtioBlockState) -> Self {
+            VirtioBlockStateV2 {
+                id: state.id,
+                partuuid: state.partuuid,
+                cache_type: state.cache_type,
+                root_device: state.root_device,
+                disk_path: state.disk_path,
+                virtio_state: state.virtio_state,
+                rate_limiter_state: state.rate_limiter_state,
+                file_engine_type: state.file_engine_type,
+            }
+        }
+    }
+
     #[test]
     fn test_cache_semantic_ser() {
         // We create the backing file here so that it exists for the whole lifetime of the test.
@@ -189,6 +318,14 @@ mod tests {
             cache_type: CacheType::Writeback,
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            overlay: None,
+            num_queues: 1,
+            async_io: AsyncIoConfig::default(),
+            host_cache_mode: HostCacheMode::Buffered,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
         };
 
         let block = VirtioBlock::new(config).unwrap();
@@ -212,6 +349,11 @@ mod tests {
         );
         assert_eq!(FileEngineType::Async, FileEngineTypeState::Async.into());
         assert_eq!(FileEngineType::Sync, FileEngineTypeState::Sync.into());
+        assert_eq!(
+            FileEngineTypeState::Qcow2,
+            FileEngineTypeState::from(FileEngineType::Qcow2)
+        );
+        assert_eq!(FileEngineType::Qcow2, FileEngineTypeState::Qcow2.into());
         // Test default impl.
         assert_eq!(FileEngineTypeState::default(), FileEngineTypeState::Sync);
 
@@ -233,6 +375,14 @@ mod tests {
                 // Need to use Sync because it will otherwise return an error.
                 // We'll overwrite the state instead.
                 file_engine_type: FileEngineType::Sync,
+                overlay: None,
+                num_queues: 1,
+                async_io: AsyncIoConfig::default(),
+                host_cache_mode: HostCacheMode::Buffered,
+                rate_limiter_group: None,
+                verity: None,
+                nbd: None,
+                encryption: None,
             };
 
             let block = VirtioBlock::new(config).unwrap();
@@ -274,6 +424,14 @@ mod tests {
             cache_type: CacheType::Unsafe,
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            overlay: None,
+            num_queues: 1,
+            async_io: AsyncIoConfig::default(),
+            host_cache_mode: HostCacheMode::Buffered,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
         };
 
         let block = VirtioBlock::new(config).unwrap();
@@ -305,4 +463,224 @@ mod tests {
         // Test that block specific fields are the same.
         assert_eq!(restored_block.disk.file_path, block.disk.file_path);
     }
+
+    #[test]
+    fn test_persistence_v2() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+
+        let config = VirtioBlockConfig {
+            drive_id: "test".to_string(),
+            path_on_host: f.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            cache_type: CacheType::Unsafe,
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            overlay: None,
+            num_queues: 1,
+            async_io: AsyncIoConfig::default(),
+            host_cache_mode: HostCacheMode::Buffered,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
+        };
+
+        let block = VirtioBlock::new(config).unwrap();
+
+        // Save the block device with the layout of format version 2.0.0.
+        let mut mem = vec![0; 4096];
+        Snapshot::serialize(
+            &mut mem.as_mut_slice(),
+            &VirtioBlockStateV2::from(block.save()),
+        )
+        .unwrap();
+
+        // The settings missing from the old layout get their defaults.
+        let state = VirtioBlockState::from(
+            Snapshot::deserialize::<_, VirtioBlockStateV2>(&mut mem.as_slice()).unwrap(),
+        );
+        assert_eq!(state.overlay, None);
+        assert_eq!(state.async_io, AsyncIoConfig::default());
+        assert_eq!(state.host_cache_mode, HostCacheMode::Buffered);
+        assert_eq!(state.logical_block_size, SECTOR_SIZE);
+        assert_eq!(state.rate_limiter_group, None);
+        assert!(state.verity.is_none());
+        assert!(state.nbd.is_none());
+        assert!(!state.encrypted);
+
+        let restored_block =
+            VirtioBlock::restore(BlockConstructorArgs { mem: default_mem() }, &state).unwrap();
+        assert_eq!(restored_block.avail_features(), block.avail_features());
+        assert_eq!(restored_block.queues(), block.queues());
+        assert_eq!(restored_block.config_space, block.config_space);
+        assert_eq!(restored_block.disk.file_path, block.disk.file_path);
+    }
+
+    #[test]
+    fn test_multi_queue_persistence() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+
+        let config = VirtioBlockConfig {
+            drive_id: "test".to_string(),
+            path_on_host: f.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            cache_type: CacheType::Unsafe,
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            overlay: None,
+            num_queues: 3,
+            async_io: AsyncIoConfig::default(),
+            host_cache_mode: HostCacheMode::Buffered,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
+        };
+
+        let block = VirtioBlock::new(config).unwrap();
+
+        // Save the block device.
+        let mut mem = vec![0; 4096];
+        Snapshot::serialize(&mut mem.as_mut_slice(), &block.save()).unwrap();
+
+        // Restore the block device, with as many queues as were saved.
+        let restored_block = VirtioBlock::restore(
+            BlockConstructorArgs { mem: default_mem() },
+            &Snapshot::deserialize(&mut mem.as_slice()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_block.queues(), block.queues());
+        assert_eq!(restored_block.queue_evts.len(), 3);
+        assert_eq!(restored_block.avail_features(), block.avail_features());
+        assert_eq!(restored_block.config_space, block.config_space);
+        assert_eq!(restored_block.config().num_queues, 3);
+
+        // A snapshot without queues is rejected.
+        let mut state = block.save();
+        state.virtio_state.queues.clear();
+        let res = VirtioBlock::restore(BlockConstructorArgs { mem: default_mem() }, &state);
+        assert!(
+            matches!(
+                res,
+                Err(VirtioBlockError::Persist(PersistError::InvalidInput))
+            ),
+            "{:?}",
+            res
+        );
+    }
+
+    #[test]
+    fn test_overlay_persistence() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let overlay_file = TempFile::new().unwrap();
+        let bitmap_file = TempFile::new().unwrap();
+
+        let config = VirtioBlockConfig {
+            drive_id: "test".to_string(),
+            path_on_host: f.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            cache_type: CacheType::Unsafe,
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            overlay: Some(OverlayConfig {
+                path_on_host: overlay_file.as_path().to_str().unwrap().to_string(),
+                bitmap_path_on_host: bitmap_file.as_path().to_str().unwrap().to_string(),
+            }),
+            num_queues: 1,
+            async_io: AsyncIoConfig::default(),
+            host_cache_mode: HostCacheMode::Buffered,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
+        };
+
+        let block = VirtioBlock::new(config).unwrap();
+
+        // Save the block device.
+        let mut mem = vec![0; 4096];
+        let state = block.save();
+        assert_eq!(state.overlay, block.disk.overlay);
+        Snapshot::serialize(&mut mem.as_mut_slice(), &state).unwrap();
+
+        // Restore the block device, which reopens the overlay.
+        let restored_block = VirtioBlock::restore(
+            BlockConstructorArgs { mem: default_mem() },
+            &Snapshot::deserialize(&mut mem.as_slice()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_block.disk.overlay, block.disk.overlay);
+        assert_eq!(restored_block.disk.image_id, block.disk.image_id);
+        assert_eq!(restored_block.config().overlay, block.disk.overlay);
+    }
+
+    #[test]
+    fn test_encryption_persistence() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let key = (0..64).collect::<Vec<u8>>();
+
+        let encryption = Some(DriveEncryptionConfig {
+            key: Some(Zeroizing::new(
+                base64::engine::general_purpose::STANDARD.encode(&key),
+            )),
+            key_fd: None,
+        });
+        let config = VirtioBlockConfig {
+            drive_id: "test".to_string(),
+            path_on_host: f.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            cache_type: CacheType::Unsafe,
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            overlay: None,
+            num_queues: 1,
+            async_io: AsyncIoConfig::default(),
+            host_cache_mode: HostCacheMode::Buffered,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: encryption.clone(),
+        };
+
+        let block = VirtioBlock::new(config).unwrap();
+
+        // Save the block device, whose key is not part of the state.
+        let mut mem = vec![0; 4096];
+        let state = block.save();
+        assert!(state.encrypted);
+        Snapshot::serialize(&mut mem.as_mut_slice(), &state).unwrap();
+        let mut state: VirtioBlockState = Snapshot::deserialize(&mut mem.as_slice()).unwrap();
+        assert!(state.encryption.is_none());
+
+        // The key has to be provided again to restore the block device.
+        assert!(matches!(
+            VirtioBlock::restore(BlockConstructorArgs { mem: default_mem() }, &state),
+            Err(VirtioBlockError::MissingEncryptionKey)
+        ));
+        state.encryption = encryption;
+        let restored_block =
+            VirtioBlock::restore(BlockConstructorArgs { mem: default_mem() }, &state).unwrap();
+        let mut sector = vec![0u8; io::XTS_SECTOR_SIZE];
+        let mut expected = sector.clone();
+        let restored_cipher = restored_block.disk.cipher.as_ref().unwrap();
+        restored_cipher.encrypt(0, &mut sector).unwrap();
+        io::XtsCipher::new(&key)
+            .unwrap()
+            .encrypt(0, &mut expected)
+            .unwrap();
+        assert_eq!(sector, expected);
+        assert_eq!(restored_block.avail_features(), block.avail_features());
+    }
 }
@@ -10,11 +10,12 @@ use std::convert::From;
 use vm_memory::GuestMemoryError;
 
 use super::{io as block_io, VirtioBlockError, SECTOR_SHIFT, SECTOR_SIZE};
-use crate::devices::virtio::block::virtio::device::DiskProperties;
+use crate::devices::virtio::block::virtio::device::{DiskProperties, HostCacheMode};
 use crate::devices::virtio::block::virtio::metrics::BlockDeviceMetrics;
 pub use crate::devices::virtio::gen::virtio_blk::{
     VIRTIO_BLK_ID_BYTES, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP,
-    VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
+    VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN,
+    VIRTIO_BLK_T_OUT, VIRTIO_BLK_T_WRITE_ZEROES, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
 };
 use crate::devices::virtio::queue::DescriptorChain;
 use crate::logger::{error, IncMetric};
@@ -26,6 +27,7 @@ pub enum IoErr {
     GetId(GuestMemoryError),
     PartialTransfer { completed: u32, expected: u32 },
     FileEngine(block_io::BlockIoError),
+    DirectIo(std::io::Error),
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -34,6 +36,8 @@ pub enum RequestType {
     Out,
     Flush,
     GetDeviceID,
+    Discard,
+    WriteZeroes,
     Unsupported(u32),
 }
 
@@ -44,6 +48,8 @@ impl From<u32> for RequestType {
             VIRTIO_BLK_T_OUT => RequestType::Out,
             VIRTIO_BLK_T_FLUSH => RequestType::Flush,
             VIRTIO_BLK_T_GET_ID => RequestType::GetDeviceID,
+            VIRTIO_BLK_T_DISCARD => RequestType::Discard,
+            VIRTIO_BLK_T_WRITE_ZEROES => RequestType::WriteZeroes,
             t => RequestType::Unsupported(t),
         }
     }
@@ -176,6 +182,18 @@ impl PendingRequest {
             (Ok(transferred_data_len), RequestType::GetDeviceID) => {
                 Status::from_data(self.data_len, transferred_data_len, true)
             }
+            (Ok(_), RequestType::Discard) => {
+                block_metrics.discard_count.inc();
+                Status::Ok {
+                    num_bytes_to_mem: 0,
+                }
+            }
+            (Ok(_), RequestType::WriteZeroes) => {
+                block_metrics.write_zeroes_count.inc();
+                Status::Ok {
+                    num_bytes_to_mem: 0,
+                }
+            }
             (_, RequestType::Unsupported(op)) => Status::Unsupported { op },
             (Err(err), _) => Status::IoErr {
                 num_bytes_to_mem: 0,
@@ -231,6 +249,35 @@ impl RequestHeader {
     }
 }
 
+/// A range of sectors to discard or to zero, as read from the data descriptor of discard and
+/// write zeroes requests.
+#[derive(Debug, Copy, Clone, Default)]
+#[repr(C)]
+pub struct DiscardWriteZeroesSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+// SAFETY: Safe because DiscardWriteZeroesSegment only contains plain data.
+unsafe impl ByteValued for DiscardWriteZeroesSegment {}
+
+impl DiscardWriteZeroesSegment {
+    pub fn new(sector: u64, num_sectors: u32, flags: u32) -> DiscardWriteZeroesSegment {
+        DiscardWriteZeroesSegment {
+            sector,
+            num_sectors,
+            flags,
+        }
+    }
+}
+
+/// Size of a discard or write zeroes segment.
+// The segment is 16 bytes long, so the conversion can't fail.
+#[allow(clippy::cast_possible_truncation)]
+pub const DISCARD_WRITE_ZEROES_SEGMENT_SIZE: u32 =
+    std::mem::size_of::<DiscardWriteZeroesSegment>() as u32;
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct Request {
     pub r#type: RequestType,
@@ -238,6 +285,10 @@ pub struct Request {
     pub status_addr: GuestAddress,
     sector: u64,
     data_addr: GuestAddress,
+    // Number of sectors affected by a discard or write zeroes request.
+    num_sectors: u32,
+    // Whether a write zeroes request may deallocate the zeroed sectors.
+    unmap: bool,
 }
 
 impl Request {
@@ -258,6 +309,8 @@ impl Request {
             data_addr: GuestAddress(0),
             data_len: 0,
             status_addr: GuestAddress(0),
+            num_sectors: 0,
+            unmap: false,
         };
 
         let data_desc;
@@ -287,6 +340,11 @@ impl Request {
             if !data_desc.is_write_only() && req.r#type == RequestType::GetDeviceID {
                 return Err(VirtioBlockError::UnexpectedReadOnlyDescriptor);
             }
+            if data_desc.is_write_only()
+                && (req.r#type == RequestType::Discard || req.r#type == RequestType::WriteZeroes)
+            {
+                return Err(VirtioBlockError::UnexpectedWriteOnlyDescriptor);
+            }
 
             req.data_addr = data_desc.addr;
             req.data_len = data_desc.len;
@@ -313,6 +371,34 @@ impl Request {
                     return Err(VirtioBlockError::InvalidDataLength);
                 }
             }
+            RequestType::Discard | RequestType::WriteZeroes => {
+                // We advertise a maximum of one segment per request.
+                if req.data_len != DISCARD_WRITE_ZEROES_SEGMENT_SIZE {
+                    return Err(VirtioBlockError::InvalidDataLength);
+                }
+                let segment: DiscardWriteZeroesSegment = mem
+                    .read_obj(req.data_addr)
+                    .map_err(VirtioBlockError::GuestMemory)?;
+                let top_sector = segment
+                    .sector
+                    .checked_add(u64::from(segment.num_sectors))
+                    .ok_or(VirtioBlockError::InvalidOffset)?;
+                if top_sector > num_disk_sectors {
+                    return Err(VirtioBlockError::InvalidOffset);
+                }
+                req.sector = segment.sector;
+                req.num_sectors = segment.num_sectors;
+                req.unmap = segment.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0;
+                // The unmap flag is only defined for write zeroes requests, and the device
+                // must report requests with unknown flags as unsupported.
+                let known_flags = match req.r#type {
+                    RequestType::WriteZeroes => VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+                    _ => 0,
+                };
+                if segment.flags & !known_flags != 0 {
+                    req.r#type = RequestType::Unsupported(request_header.request_type);
+                }
+            }
             _ => {}
         }
 
@@ -354,6 +440,27 @@ impl Request {
         self.sector << SECTOR_SHIFT
     }
 
+    fn num_bytes(&self) -> u64 {
+        u64::from(self.num_sectors) << SECTOR_SHIFT
+    }
+
+    // Whether the offset and the length of the request are aligned to the logical blocks of the
+    // storage, as direct IO requires. Drivers are told about logical blocks larger than sectors.
+    fn is_aligned_for_direct_io(&self, disk: &DiskProperties) -> bool {
+        let logical_block_size = u64::from(disk.logical_block_size);
+        disk.host_cache_mode != HostCacheMode::Direct
+            || (self.offset() % logical_block_size == 0
+                && u64::from(self.data_len) % logical_block_size == 0)
+    }
+
+    // Whether the data of the request has to go through a bounce buffer, because the guest buffer
+    // is not aligned to the logical blocks of the storage, as direct IO requires.
+    fn needs_bounce_buffer(&self, disk: &DiskProperties) -> bool {
+        disk.host_cache_mode == HostCacheMode::Direct
+            && self.data_len > 0
+            && self.data_addr.0 % u64::from(disk.logical_block_size) != 0
+    }
+
     fn to_pending_request(&self, desc_idx: u16) -> PendingRequest {
         PendingRequest {
             r#type: self.r#type,
@@ -366,23 +473,89 @@ impl Request {
     pub(crate) fn process(
         self,
         disk: &mut DiskProperties,
+        queue_index: usize,
         desc_idx: u16,
         mem: &GuestMemoryMmap,
         block_metrics: &BlockDeviceMetrics,
     ) -> ProcessingResult {
         let pending = self.to_pending_request(desc_idx);
         let res = match self.r#type {
+            RequestType::In | RequestType::Out if !self.is_aligned_for_direct_io(disk) => {
+                let res = Err(IoErr::DirectIo(std::io::Error::from_raw_os_error(
+                    libc::EINVAL,
+                )));
+                return ProcessingResult::Executed(pending.finish(mem, res, block_metrics));
+            }
+            RequestType::In if self.needs_bounce_buffer(disk) => {
+                let _metric = block_metrics.read_agg.record_latency_metrics();
+                block_metrics.bounced_reqs_count.inc();
+                let alignment = disk.logical_block_size as usize;
+                disk.file_engine_mut(queue_index).read_bounced(
+                    self.offset(),
+                    mem,
+                    self.data_addr,
+                    self.data_len,
+                    alignment,
+                    pending,
+                )
+            }
+            RequestType::Out if self.needs_bounce_buffer(disk) => {
+                let _metric = block_metrics.write_agg.record_latency_metrics();
+                block_metrics.bounced_reqs_count.inc();
+                let alignment = disk.logical_block_size as usize;
+                disk.file_engine_mut(queue_index).write_bounced(
+                    self.offset(),
+                    mem,
+                    self.data_addr,
+                    self.data_len,
+                    alignment,
+                    pending,
+                )
+            }
             RequestType::In => {
                 let _metric = block_metrics.read_agg.record_latency_metrics();
-                disk.file_engine
-                    .read(self.offset(), mem, self.data_addr, self.data_len, pending)
+                disk.file_engine_mut(queue_index).read(
+                    self.offset(),
+                    mem,
+                    self.data_addr,
+                    self.data_len,
+                    pending,
+                )
             }
             RequestType::Out => {
                 let _metric = block_metrics.write_agg.record_latency_metrics();
-                disk.file_engine
-                    .write(self.offset(), mem, self.data_addr, self.data_len, pending)
+                disk.file_engine_mut(queue_index).write(
+                    self.offset(),
+                    mem,
+                    self.data_addr,
+                    self.data_len,
+                    pending,
+                )
+            }
+            RequestType::Flush => disk.file_engine_mut(queue_index).flush(pending),
+            RequestType::Discard | RequestType::WriteZeroes if self.num_sectors == 0 => {
+                // `fallocate` rejects empty ranges, while there is nothing to do for them.
+                return ProcessingResult::Executed(pending.finish(mem, Ok(0), block_metrics));
+            }
+            RequestType::Discard => disk.file_engine_mut(queue_index).fallocate(
+                block_io::FallocateMode::Discard,
+                self.offset(),
+                self.num_bytes(),
+                pending,
+            ),
+            RequestType::WriteZeroes => {
+                // Deallocating the sectors is allowed if the driver set the unmap flag.
+                let mode = match self.unmap {
+                    true => block_io::FallocateMode::PunchHole,
+                    false => block_io::FallocateMode::ZeroRange,
+                };
+                disk.file_engine_mut(queue_index).fallocate(
+                    mode,
+                    self.offset(),
+                    self.num_bytes(),
+                    pending,
+                )
             }
-            RequestType::Flush => disk.file_engine.flush(pending),
             RequestType::GetDeviceID => {
                 let res = mem
                     .write_slice(&disk.image_id, self.data_addr)
@@ -404,6 +577,9 @@ impl Request {
                 if err.error.is_throttling_err() {
                     ProcessingResult::Throttled
                 } else {
+                    if err.error.is_integrity_err() {
+                        block_metrics.verity_fails.inc();
+                    }
                     ProcessingResult::Executed(err.user_data.finish(
                         mem,
                         Err(IoErr::FileEngine(err.error)),
@@ -438,6 +614,8 @@ mod tests {
             VIRTIO_BLK_T_OUT,
             VIRTIO_BLK_T_FLUSH,
             VIRTIO_BLK_T_GET_ID,
+            VIRTIO_BLK_T_DISCARD,
+            VIRTIO_BLK_T_WRITE_ZEROES,
         ];
 
         for request_type in supported_request_types {
@@ -464,6 +642,14 @@ mod tests {
             RequestType::from(VIRTIO_BLK_T_GET_ID),
             RequestType::GetDeviceID
         );
+        assert_eq!(
+            RequestType::from(VIRTIO_BLK_T_DISCARD),
+            RequestType::Discard
+        );
+        assert_eq!(
+            RequestType::from(VIRTIO_BLK_T_WRITE_ZEROES),
+            RequestType::WriteZeroes
+        );
         assert_eq!(RequestType::from(42), RequestType::Unsupported(42));
     }
 
@@ -659,6 +845,81 @@ mod tests {
         chain.check_parse(true);
     }
 
+    #[test]
+    fn test_parse_discard_write_zeroes() {
+        let mem = &default_mem();
+        let queue = VirtQueue::new(GuestAddress(0), mem, 16);
+        let chain = RequestDescriptorChain::new(&queue);
+        let data_addr = GuestAddress(chain.data_desc.addr.get());
+
+        for (request_type, virtio_request_id) in [
+            (RequestType::Discard, VIRTIO_BLK_T_DISCARD),
+            (RequestType::WriteZeroes, VIRTIO_BLK_T_WRITE_ZEROES),
+        ] {
+            chain.set_header(RequestHeader::new(virtio_request_id, 0));
+
+            // Write only data descriptor.
+            chain
+                .data_desc
+                .flags
+                .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+            chain.check_parse_err(VirtioBlockError::UnexpectedWriteOnlyDescriptor);
+
+            // More than one segment.
+            chain.data_desc.flags.set(VIRTQ_DESC_F_NEXT);
+            chain
+                .data_desc
+                .len
+                .set(2 * DISCARD_WRITE_ZEROES_SEGMENT_SIZE);
+            chain.check_parse_err(VirtioBlockError::InvalidDataLength);
+
+            // Segment beyond the end of the disk.
+            chain.data_desc.len.set(DISCARD_WRITE_ZEROES_SEGMENT_SIZE);
+            mem.write_obj(
+                DiscardWriteZeroesSegment::new(NUM_DISK_SECTORS - 1, 2, 0),
+                data_addr,
+            )
+            .unwrap();
+            chain.check_parse_err(VirtioBlockError::InvalidOffset);
+
+            // Valid segment.
+            mem.write_obj(
+                DiscardWriteZeroesSegment::new(NUM_DISK_SECTORS - 2, 2, 0),
+                data_addr,
+            )
+            .unwrap();
+            let mut q = chain.driver_queue.create_queue();
+            let request = Request::parse(&q.pop(mem).unwrap(), mem, NUM_DISK_SECTORS).unwrap();
+            assert_eq!(request.r#type, request_type);
+            assert_eq!(request.offset(), (NUM_DISK_SECTORS - 2) << SECTOR_SHIFT);
+            assert_eq!(request.num_bytes(), 2 << SECTOR_SHIFT);
+            assert!(!request.unmap);
+
+            // The unmap flag is only supported by write zeroes requests.
+            mem.write_obj(
+                DiscardWriteZeroesSegment::new(0, 1, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP),
+                data_addr,
+            )
+            .unwrap();
+            let mut q = chain.driver_queue.create_queue();
+            let request = Request::parse(&q.pop(mem).unwrap(), mem, NUM_DISK_SECTORS).unwrap();
+            match request_type {
+                RequestType::WriteZeroes => {
+                    assert_eq!(request.r#type, RequestType::WriteZeroes);
+                    assert!(request.unmap);
+                }
+                _ => assert_eq!(request.r#type, RequestType::Unsupported(virtio_request_id)),
+            }
+
+            // Unknown flags.
+            mem.write_obj(DiscardWriteZeroesSegment::new(0, 1, 0x2), data_addr)
+                .unwrap();
+            let mut q = chain.driver_queue.create_queue();
+            let request = Request::parse(&q.pop(mem).unwrap(), mem, NUM_DISK_SECTORS).unwrap();
+            assert_eq!(request.r#type, RequestType::Unsupported(virtio_request_id));
+        }
+    }
+
     use std::convert::TryInto;
 
     /// -------------------------------------
@@ -704,9 +965,9 @@ mod tests {
                     1u32,
                     std::sync::Arc::new(Strategy::prop_map(any::<u32>(), |id| {
                         // Random unsupported requests for our implementation start at
-                        // VIRTIO_BLK_T_GET_ID + 1 = 9.
-                        // This can be further refined to include unsupported requests ids < 9.
-                        RequestType::Unsupported(id.checked_add(9).unwrap_or(9))
+                        // VIRTIO_BLK_T_WRITE_ZEROES + 1 = 14.
+                        // This can be further refined to include unsupported requests ids < 14.
+                        RequestType::Unsupported(id.checked_add(14).unwrap_or(14))
                     })),
                 ),
             ))
@@ -720,6 +981,8 @@ mod tests {
                 RequestType::Out => VIRTIO_BLK_T_OUT,
                 RequestType::Flush => VIRTIO_BLK_T_FLUSH,
                 RequestType::GetDeviceID => VIRTIO_BLK_T_GET_ID,
+                RequestType::Discard => VIRTIO_BLK_T_DISCARD,
+                RequestType::WriteZeroes => VIRTIO_BLK_T_WRITE_ZEROES,
                 RequestType::Unsupported(id) => id,
             }
         }
@@ -732,6 +995,8 @@ mod tests {
             RequestType::Out => VIRTQ_DESC_F_NEXT,
             RequestType::Flush => VIRTQ_DESC_F_NEXT,
             RequestType::GetDeviceID => VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            RequestType::Discard => VIRTQ_DESC_F_NEXT,
+            RequestType::WriteZeroes => VIRTQ_DESC_F_NEXT,
             RequestType::Unsupported(_) => VIRTQ_DESC_F_NEXT,
         }
     }
@@ -823,6 +1088,8 @@ mod tests {
             status_addr,
             sector: sector & (NUM_DISK_SECTORS - sectors_len),
             data_addr,
+            num_sectors: 0,
+            unmap: false,
         };
         let mut request_header = RequestHeader::new(virtio_request_id, request.sector);
 
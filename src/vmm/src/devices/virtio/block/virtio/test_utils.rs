@@ -13,7 +13,7 @@ use utils::tempfile::TempFile;
 
 use super::device::VirtioBlockConfig;
 use super::RequestHeader;
-use crate::devices::virtio::block::virtio::device::FileEngineType;
+use crate::devices::virtio::block::virtio::device::{AsyncIoConfig, FileEngineType, HostCacheMode};
 #[cfg(test)]
 use crate::devices::virtio::block::virtio::io::FileEngine;
 use crate::devices::virtio::block::virtio::{CacheType, VirtioBlock};
@@ -66,6 +66,14 @@ pub fn default_block_with_path(path: String, file_engine_type: FileEngineType) -
             }),
         }),
         file_engine_type,
+        overlay: None,
+        num_queues: 1,
+        async_io: AsyncIoConfig::default(),
+        host_cache_mode: HostCacheMode::Buffered,
+        rate_limiter_group: None,
+        verity: None,
+        nbd: None,
+        encryption: None,
     };
 
     // The default block device is read-write and non-root.
@@ -89,7 +97,7 @@ pub fn simulate_queue_event(b: &mut VirtioBlock, maybe_expected_irq: Option<bool
     // Trigger the queue event.
     b.queue_evts[0].write(1).unwrap();
     // Handle event.
-    b.process_queue_event();
+    b.process_queue_event(0);
     // Validate the queue operation finished successfully.
     if let Some(expected_irq) = maybe_expected_irq {
         assert_eq!(b.irq_trigger.has_pending_irq(IrqType::Vring), expected_irq);
@@ -104,7 +112,7 @@ pub fn simulate_async_completion_event(b: &mut VirtioBlock, expected_irq: bool)
         // Wait for the async completion event to be sent.
         thread::sleep(Duration::from_millis(150));
         // Handle event.
-        b.process_async_completion_event();
+        b.process_async_completion_event(0);
     }
 
     // Validate if there are pending IRQs.
@@ -118,7 +126,11 @@ pub fn simulate_queue_and_async_completion_events(b: &mut VirtioBlock, expected_
             simulate_queue_event(b, None);
             simulate_async_completion_event(b, expected_irq);
         }
-        FileEngine::Sync(_) => {
+        FileEngine::Sync(_)
+        | FileEngine::Qcow2(_)
+        | FileEngine::Overlay(_)
+        | FileEngine::Verity(_)
+        | FileEngine::Nbd(_) => {
             simulate_queue_event(b, Some(expected_irq));
         }
     }
@@ -9,14 +9,14 @@
 use std::io::Read;
 use std::io::Write;
 use std::net::Ipv4Addr;
-use std::sync::atomic::AtomicU32;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
 use std::{cmp, mem};
 
 use libc::EAGAIN;
 use log::{error, warn};
 use utils::eventfd::EventFd;
-use utils::net::mac::MacAddr;
+use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
 use utils::u64_to_usize;
 use vm_memory::GuestMemoryError;
 
@@ -25,6 +25,7 @@ use crate::devices::virtio::gen::virtio_blk::VIRTIO_F_VERSION_1;
 use crate::devices::virtio::gen::virtio_net::{
     virtio_net_hdr_v1, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4,
     VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC,
+    VIRTIO_NET_F_STATUS,
 };
 use crate::devices::virtio::gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use crate::devices::virtio::iovec::IoVecBuffer;
@@ -41,11 +42,17 @@ use crate::dumbo::pdu::ethernet::{EthernetFrame, PAYLOAD_OFFSET};
 use crate::logger::{IncMetric, METRICS};
 use crate::mmds::data_store::Mmds;
 use crate::mmds::ns::MmdsNetworkStack;
+use crate::rate_limiter::group::RateLimiterGroup;
 use crate::rate_limiter::{BucketUpdate, RateLimiter, TokenType};
 use crate::vstate::memory::{ByteValued, Bytes, GuestMemoryMmap};
 
 const FRAME_HEADER_MAX_LEN: usize = PAYLOAD_OFFSET + ETH_IPV4_FRAME_LEN;
 
+/// Link status bit of the config space status field.
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+/// Offset of the status field in the config space.
+const CONFIG_STATUS_OFFSET: u64 = MAC_ADDR_LEN as u64;
+
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
 enum FrontendError {
     /// Add user.
@@ -95,9 +102,13 @@ fn init_vnet_hdr(buf: &mut [u8]) {
     buf[0..vnet_hdr_len()].fill(0);
 }
 
+/// Config space of the device. The guest only sees the status field when the link status is
+/// offered.
 #[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
 pub struct ConfigSpace {
     pub guest_mac: MacAddr,
+    pub status: u16,
 }
 
 // SAFETY: `ConfigSpace` contains only PODs.
@@ -134,6 +145,8 @@ pub struct Net {
 
     pub(crate) config_space: ConfigSpace,
     pub(crate) guest_mac: Option<MacAddr>,
+    /// Whether the next read of the link status reports the link as down.
+    link_down_pending: AtomicBool,
 
     pub(crate) device_state: DeviceState,
     pub(crate) activate_evt: EventFd,
@@ -162,7 +175,10 @@ impl Net {
             | 1 << VIRTIO_F_VERSION_1
             | 1 << VIRTIO_RING_F_EVENT_IDX;
 
-        let mut config_space = ConfigSpace::default();
+        let mut config_space = ConfigSpace {
+            status: VIRTIO_NET_S_LINK_UP,
+            ..Default::default()
+        };
         if let Some(mac) = guest_mac {
             config_space.guest_mac = mac;
             // Enabling feature for MAC address configuration
@@ -193,6 +209,7 @@ impl Net {
             irq_trigger: IrqTrigger::new().map_err(NetError::EventFd)?,
             config_space,
             guest_mac,
+            link_down_pending: AtomicBool::new(false),
             device_state: DeviceState::Inactive,
             activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(NetError::EventFd)?,
             mmds_ns: None,
@@ -231,6 +248,37 @@ impl Net {
         self.guest_mac.as_ref()
     }
 
+    /// Offers the link status to the guest driver, so that it can be told when the guest MAC is
+    /// reassigned.
+    pub fn enable_link_status(&mut self) {
+        self.avail_features |= 1 << VIRTIO_NET_F_STATUS;
+    }
+
+    /// Whether the link status is offered to the guest driver.
+    pub fn link_status(&self) -> bool {
+        self.avail_features & (1 << VIRTIO_NET_F_STATUS) != 0
+    }
+
+    /// Assigns a new MAC to the guest network interface.
+    ///
+    /// If the driver negotiated the link status, the guest is told that the link went down and
+    /// then up again, so that it reconfigures the interface.
+    pub fn reassign_guest_mac(&mut self, mac: MacAddr) -> Result<(), NetError> {
+        self.config_space.guest_mac = mac;
+        self.guest_mac = Some(mac);
+        self.metrics.mac_address_updates.inc();
+
+        if self.is_activated() {
+            if self.has_feature(u64::from(VIRTIO_NET_F_STATUS)) {
+                self.link_down_pending.store(true, Ordering::Relaxed);
+            }
+            self.irq_trigger
+                .trigger_irq(IrqType::Config)
+                .map_err(NetError::EventFd)?;
+        }
+        Ok(())
+    }
+
     /// Provides the host IFACE name of this net device.
     pub fn iface_name(&self) -> String {
         self.tap.if_name_as_str().to_string()
@@ -671,6 +719,12 @@ impl Net {
         self.tx_rate_limiter.update_buckets(tx_bytes, tx_ops);
     }
 
+    /// Makes both the RX and TX rate limiters members of `group`.
+    pub fn join_rate_limiter_group(&mut self, group: &RateLimiterGroup) {
+        self.rx_rate_limiter.join_group(group);
+        self.tx_rate_limiter.join_group(group);
+    }
+
     #[cfg(not(test))]
     fn read_tap(&mut self) -> std::io::Result<usize> {
         self.tap.read(&mut self.rx_frame_buf)
@@ -832,14 +886,28 @@ impl VirtioDevice for Net {
     }
 
     fn read_config(&self, offset: u64, mut data: &mut [u8]) {
-        let config_space_bytes = self.config_space.as_slice();
-        let config_len = config_space_bytes.len() as u64;
+        let mut config_space = self.config_space;
+        // The status field is only part of the config space when the link status is offered.
+        let config_len = if self.link_status() {
+            config_space.as_slice().len() as u64
+        } else {
+            CONFIG_STATUS_OFFSET
+        };
         if offset >= config_len {
             error!("Failed to read config space");
             self.metrics.cfg_fails.inc();
             return;
         }
         if let Some(end) = offset.checked_add(data.len() as u64) {
+            if CONFIG_STATUS_OFFSET < end && self.link_down_pending.swap(false, Ordering::Relaxed) {
+                // The guest sees the link down, and is then notified to read it up again.
+                config_space.status &= !VIRTIO_NET_S_LINK_UP;
+                if let Err(err) = self.irq_trigger.trigger_irq(IrqType::Config) {
+                    error!("Failed to signal config change: {err}");
+                    self.metrics.event_fails.inc();
+                }
+            }
+            let config_space_bytes = config_space.as_slice();
             // This write can't fail, offset and end are checked against config_len.
             data.write_all(
                 &config_space_bytes[u64_to_usize(offset)..u64_to_usize(cmp::min(end, config_len))],
@@ -849,7 +917,8 @@ impl VirtioDevice for Net {
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
-        let config_space_bytes = self.config_space.as_mut_slice();
+        // Only the MAC address is writable, the link status is read-only.
+        let config_space_bytes = &mut self.config_space.as_mut_slice()[..usize::from(MAC_ADDR_LEN)];
         let start = usize::try_from(offset).ok();
         let end = start.and_then(|s| s.checked_add(data.len()));
         let Some(dst) = start
@@ -917,6 +986,7 @@ pub mod tests {
     use crate::dumbo::EthernetFrame;
     use crate::logger::IncMetric;
     use crate::rate_limiter::{BucketUpdate, RateLimiter, TokenBucket, TokenType};
+    use crate::utilities::test_utils::single_region_mem;
     use crate::vstate::memory::{Address, GuestMemory};
 
     impl Net {
@@ -1009,6 +1079,15 @@ pub mod tests {
         }
 
         assert_eq!(net.acked_features, features);
+
+        // The link status is only offered when enabled.
+        assert!(!net.link_status());
+        net.enable_link_status();
+        assert!(net.link_status());
+        assert_eq!(
+            u64::from(net.avail_features_by_page(0)),
+            (features | 1 << VIRTIO_NET_F_STATUS) & 0xFFFFFFFF
+        );
     }
 
     #[test]
@@ -1026,6 +1105,55 @@ pub mod tests {
         config_mac = [0u8; MAC_ADDR_LEN as usize];
         net.read_config(u64::from(MAC_ADDR_LEN), &mut config_mac);
         assert_eq!(config_mac, [0u8, 0u8, 0u8, 0u8, 0u8, 0u8]);
+
+        // The status field follows the MAC when the link status is offered, and the link is up.
+        assert_eq!(mem::size_of::<ConfigSpace>(), usize::from(MAC_ADDR_LEN) + 2);
+        net.enable_link_status();
+        let mut status = [0u8; 2];
+        net.read_config(u64::from(MAC_ADDR_LEN), &mut status);
+        assert_eq!(status, VIRTIO_NET_S_LINK_UP.to_le_bytes());
+
+        // Invalid read.
+        config_mac = [0u8; MAC_ADDR_LEN as usize];
+        net.read_config(mem::size_of::<ConfigSpace>() as u64, &mut config_mac);
+        assert_eq!(config_mac, [0u8, 0u8, 0u8, 0u8, 0u8, 0u8]);
+    }
+
+    #[test]
+    fn test_reassign_guest_mac() {
+        let mut net = default_net();
+        set_mac(&mut net, MacAddr::from_str("11:22:33:44:55:66").unwrap());
+
+        // An inactive device only updates its config space.
+        let mac = MacAddr::from_str("02:22:33:44:55:66").unwrap();
+        net.reassign_guest_mac(mac).unwrap();
+        assert_eq!(net.guest_mac(), Some(&mac));
+        assert!(!net.irq_trigger.has_pending_irq(IrqType::Config));
+
+        net.enable_link_status();
+        net.acked_features = 1 << VIRTIO_NET_F_MAC | 1 << VIRTIO_NET_F_STATUS;
+        net.device_state = DeviceState::Activated(single_region_mem(0x1000));
+        let mac = MacAddr::from_str("06:22:33:44:55:66").unwrap();
+        net.reassign_guest_mac(mac).unwrap();
+        assert!(net.irq_trigger.has_pending_irq(IrqType::Config));
+        let mut config_mac = [0u8; MAC_ADDR_LEN as usize];
+        net.read_config(0, &mut config_mac);
+        assert_eq!(&config_mac, mac.get_bytes());
+        assert!(!net.irq_trigger.has_pending_irq(IrqType::Config));
+
+        // The guest first reads the link down, and is notified to read it again.
+        let mut status = [0u8; 2];
+        net.read_config(u64::from(MAC_ADDR_LEN), &mut status);
+        assert_eq!(status, [0, 0]);
+        assert!(net.irq_trigger.has_pending_irq(IrqType::Config));
+        net.read_config(u64::from(MAC_ADDR_LEN), &mut status);
+        assert_eq!(status, VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        assert!(!net.irq_trigger.has_pending_irq(IrqType::Config));
+
+        // The link status is read-only.
+        net.write_config(u64::from(MAC_ADDR_LEN), &[0, 0]);
+        net.read_config(u64::from(MAC_ADDR_LEN), &mut status);
+        assert_eq!(status, VIRTIO_NET_S_LINK_UP.to_le_bytes());
     }
 
     #[test]
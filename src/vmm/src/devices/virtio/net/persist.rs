@@ -28,23 +28,56 @@ use crate::vstate::memory::GuestMemoryMmap;
 /// at snapshot.
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct NetConfigSpaceState {
-    guest_mac: Option<MacAddr>,
+    /// MAC address of the guest network interface.
+    pub guest_mac: Option<MacAddr>,
 }
 
 /// Information about the network device that are saved
 /// at snapshot.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetState {
-    id: String,
-    tap_if_name: String,
+    /// Network interface identifier.
+    pub id: String,
+    /// Name of the tap device backing the interface.
+    pub tap_if_name: String,
     rx_rate_limiter_state: RateLimiterState,
     tx_rate_limiter_state: RateLimiterState,
     /// The associated MMDS network stack.
     pub mmds_ns: Option<MmdsNetworkStackState>,
+    /// The device config space.
+    pub config_space: NetConfigSpaceState,
+    virtio_state: VirtioDeviceState,
+    /// Rate limiter group the interface is a member of, if any.
+    pub rate_limiter_group: Option<String>,
+}
+
+/// Layout of [`NetState`] in snapshot format version 2.0.0.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetStateV2 {
+    id: String,
+    tap_if_name: String,
+    rx_rate_limiter_state: RateLimiterState,
+    tx_rate_limiter_state: RateLimiterState,
+    mmds_ns: Option<MmdsNetworkStackState>,
     config_space: NetConfigSpaceState,
     virtio_state: VirtioDeviceState,
 }
 
+impl From<NetStateV2> for NetState {
+    fn from(state: NetStateV2) -> Self {
+        NetState {
+            id: state.id,
+            tap_if_name: state.tap_if_name,
+            rx_rate_limiter_state: state.rx_rate_limiter_state,
+            tx_rate_limiter_state: state.tx_rate_limiter_state,
+            mmds_ns: state.mmds_ns,
+            config_space: state.config_space,
+            virtio_state: state.virtio_state,
+            rate_limiter_group: None,
+        }
+    }
+}
+
 /// Auxiliary structure for creating a device when resuming from a snapshot.
 #[derive(Debug)]
 pub struct NetConstructorArgs {
@@ -83,6 +116,10 @@ impl Persist<'_> for Net {
                 guest_mac: self.guest_mac,
             },
             virtio_state: VirtioDeviceState::from_device(self),
+            rate_limiter_group: self
+                .rx_rate_limiter
+                .group()
+                .map(|group| group.id().to_string()),
         }
     }
 
@@ -146,6 +183,20 @@ mod tests {
     use crate::devices::virtio::test_utils::default_mem;
     use crate::snapshot::Snapshot;
 
+    impl From<NetState> for NetStateV2 {
+        fn from(state: NetState) -> Self {
+            NetStateV2 {
+                id: state.id,
+                tap_if_name: state.tap_if_name,
+                rx_rate_limiter_state: state.rx_rate_limiter_state,
+                tx_rate_limiter_state: state.tx_rate_limiter_state,
+                mmds_ns: state.mmds_ns,
+                config_space: state.config_space,
+                virtio_state: state.virtio_state,
+            }
+        }
+    }
+
     fn validate_save_and_restore(net: Net, mmds_ds: Option<Arc<Mutex<Mmds>>>) {
         let guest_mem = default_mem();
         let mut mem = vec![0; 4096];
@@ -221,4 +272,38 @@ mod tests {
         // data store. This will return an error.
         validate_save_and_restore(default_net(), None);
     }
+
+    #[test]
+    fn test_persistence_v2() {
+        let net = default_net_no_mmds();
+        let state = net.save();
+
+        // Save the net device with the layout of format version 2.0.0.
+        let mut mem = vec![0; 4096];
+        Snapshot::serialize(&mut mem.as_mut_slice(), &NetStateV2::from(state.clone())).unwrap();
+
+        // Interfaces saved with format version 2.0.0 were not members of any group.
+        let restored_state =
+            NetState::from(Snapshot::deserialize::<_, NetStateV2>(&mut mem.as_slice()).unwrap());
+        assert_eq!(restored_state.id, state.id);
+        assert_eq!(restored_state.tap_if_name, state.tap_if_name);
+        assert_eq!(
+            restored_state.config_space.guest_mac,
+            state.config_space.guest_mac
+        );
+        assert!(restored_state.rate_limiter_group.is_none());
+
+        drop(net);
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_mem(),
+                mmds: None,
+            },
+            &restored_state,
+        )
+        .unwrap();
+        assert_eq!(restored_net.iface_name(), state.tap_if_name);
+        assert!(restored_net.rx_rate_limiter.group().is_none());
+        assert!(restored_net.tx_rate_limiter.group().is_none());
+    }
 }
@@ -30,6 +30,10 @@ pub enum VhostUserError {
     VhostUserGetFeatures(VhostError),
     /// Get protocol features failed: {0}
     VhostUserGetProtocolFeatures(VhostError),
+    /// Get vring base failed: {0}
+    VhostUserGetVringBase(VhostError),
+    /// Invalid vring base returned by the backend: {0}
+    VhostUserInvalidVringBase(u32),
     /// Set owner failed: {0}
     VhostUserSetOwner(VhostError),
     /// Set features failed: {0}
@@ -110,6 +114,11 @@ pub trait VhostUserHandleBackend: Sized {
         unimplemented!()
     }
 
+    /// Stops the vring and gets the offset of the next descriptor the backend would process.
+    fn get_vring_base(&self, _queue_index: usize) -> Result<u32, vhost::Error> {
+        unimplemented!()
+    }
+
     /// Set the event file descriptor to signal when buffers are used.
     /// Bits (0-7) of the payload contain the vring index. Bit 8 is the invalid FD flag. This flag
     /// is set when there is no file descriptor in the ancillary data. This signals that polling
@@ -210,6 +219,11 @@ impl VhostUserHandleBackend for Frontend {
         <Frontend as VhostBackend>::set_vring_base(self, queue_index, base)
     }
 
+    /// Stops the vring and gets the offset of the next descriptor the backend would process.
+    fn get_vring_base(&self, queue_index: usize) -> Result<u32, vhost::Error> {
+        <Frontend as VhostBackend>::get_vring_base(self, queue_index)
+    }
+
     /// Set the event file descriptor to signal when buffers are used.
     /// Bits (0-7) of the payload contain the vring index. Bit 8 is the invalid FD flag. This flag
     /// is set when there is no file descriptor in the ancillary data. This signals that polling
@@ -401,6 +415,22 @@ impl<T: VhostUserHandleBackend> VhostUserHandleImpl<T> {
         mem: &GuestMemoryMmap,
         queues: &[(usize, &Queue, &EventFd)],
         irq_trigger: &IrqTrigger,
+    ) -> Result<(), VhostUserError> {
+        let vring_bases = queues
+            .iter()
+            .map(|(_, queue, _)| queue.avail_idx(mem).0)
+            .collect::<Vec<_>>();
+        self.setup_backend_with_vring_bases(mem, queues, &vring_bases, irq_trigger)
+    }
+
+    /// Set up vhost-user backend, making it start processing each of the `queues` from the
+    /// matching entry of `vring_bases`. Used to resume rings stopped by [`Self::get_vring_base`].
+    pub fn setup_backend_with_vring_bases(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        queues: &[(usize, &Queue, &EventFd)],
+        vring_bases: &[u16],
+        irq_trigger: &IrqTrigger,
     ) -> Result<(), VhostUserError> {
         // Provide the memory table to the backend.
         self.update_mem_table(mem)?;
@@ -414,7 +444,7 @@ impl<T: VhostUserHandleBackend> VhostUserHandleImpl<T> {
                 .map_err(VhostUserError::VhostUserSetVringNum)?;
         }
 
-        for (queue_index, queue, queue_evt) in queues.iter() {
+        for ((queue_index, queue, queue_evt), vring_base) in queues.iter().zip(vring_bases) {
             let config_data = VringConfigData {
                 queue_max_size: queue.get_max_size(),
                 queue_size: queue.actual_size(),
@@ -436,7 +466,7 @@ impl<T: VhostUserHandleBackend> VhostUserHandleImpl<T> {
                 .set_vring_addr(*queue_index, &config_data)
                 .map_err(VhostUserError::VhostUserSetVringAddr)?;
             self.vu
-                .set_vring_base(*queue_index, queue.avail_idx(mem).0)
+                .set_vring_base(*queue_index, *vring_base)
                 .map_err(VhostUserError::VhostUserSetVringBase)?;
 
             // No matter the queue, we set irq_evt for signaling the guest that buffers were
@@ -456,6 +486,17 @@ impl<T: VhostUserHandleBackend> VhostUserHandleImpl<T> {
 
         Ok(())
     }
+
+    /// Stop the vring processing `queue_index` and get the index of the next available
+    /// descriptor the backend would have processed.
+    pub fn get_vring_base(&self, queue_index: usize) -> Result<u16, VhostUserError> {
+        let vring_base = self
+            .vu
+            .get_vring_base(queue_index)
+            .map_err(VhostUserError::VhostUserGetVringBase)?;
+        // Split virtqueue indexes are 16 bit wide.
+        u16::try_from(vring_base).map_err(|_| VhostUserError::VhostUserInvalidVringBase(vring_base))
+    }
 }
 
 #[cfg(test)]
@@ -536,6 +577,33 @@ mod tests {
         assert_eq!(unsafe { *vuh.vu.features.get() }, 0x69);
     }
 
+    #[test]
+    fn test_get_vring_base() {
+        struct MockFrontend {
+            vring_base: u32,
+        }
+
+        impl VhostUserHandleBackend for MockFrontend {
+            fn get_vring_base(&self, _queue_index: usize) -> Result<u32, vhost::Error> {
+                Ok(self.vring_base)
+            }
+        }
+
+        // Split virtqueue indexes are returned as is.
+        let mut vuh = VhostUserHandleImpl {
+            vu: MockFrontend { vring_base: 0x69 },
+            socket_path: "".to_string(),
+        };
+        assert_eq!(vuh.get_vring_base(0).unwrap(), 0x69);
+
+        // Indexes not fitting in 16 bits are rejected.
+        vuh.vu.vring_base = 0x10000;
+        assert!(matches!(
+            vuh.get_vring_base(0).unwrap_err(),
+            VhostUserError::VhostUserInvalidVringBase(0x10000)
+        ));
+    }
+
     #[test]
     fn test_set_protocol_features() {
         struct MockFrontend {
@@ -45,7 +45,7 @@ pub enum VsockBackendState {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VsockUdsState {
     /// The path for the UDS socket.
-    pub(crate) path: String,
+    pub path: String,
 }
 
 /// A helper structure that holds the constructor arguments for VsockUnixBackend
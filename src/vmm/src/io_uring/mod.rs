@@ -17,7 +17,7 @@ use std::io::Error as IOError;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 
 use bindings::io_uring_params;
-use operation::{Cqe, FixedFd, OpCode, Operation};
+use operation::{Cqe, FixedBuffer, FixedFd, OpCode, Operation};
 use probe::{ProbeWrapper, PROBE_LEN};
 pub use queue::completion::CQueueError;
 use queue::completion::CompletionQueue;
@@ -30,6 +30,8 @@ use utils::syscall::SyscallReturnCode;
 const REQUIRED_OPS: [OpCode; 2] = [OpCode::Read, OpCode::Write];
 // Taken from linux/fs/io_uring.c
 const IORING_MAX_FIXED_FILES: usize = 1 << 15;
+// Taken from linux/fs/io_uring.c
+const IORING_MAX_REG_BUFFERS: usize = 1 << 14;
 
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
 /// IoUring Error.
@@ -42,12 +44,18 @@ pub enum IoUringError {
     Fam(utils::fam::Error),
     /// The number of ops in the ring is >= CQ::count
     FullCQueue,
+    /// Buffer was not registered: {0}
+    InvalidFixedBuffer(FixedBuffer),
     /// Fd was not registered: {0}
     InvalidFixedFd(FixedFd),
     /// There are no registered fds.
     NoRegisteredFds,
     /// Error probing the io_uring subsystem: {0}
     Probe(IOError),
+    /// Could not register buffers: {0}
+    RegisterBuffers(IOError),
+    /// Attempted to register too many buffers.
+    RegisterBufferLimitExceeded,
     /// Could not register eventfd: {0}
     RegisterEventfd(IOError),
     /// Could not register file: {0}
@@ -80,6 +88,7 @@ impl IoUringError {
 #[derive(Debug)]
 pub struct IoUring<T> {
     registered_fds_count: u32,
+    registered_buffers_count: u32,
     squeue: SubmissionQueue,
     cqueue: CompletionQueue,
     // Make sure the fd is declared after the queues, so that it isn't dropped before them.
@@ -104,11 +113,14 @@ impl<T: Debug> IoUring<T> {
     /// * `files` - Files to be registered for IO.
     /// * `restrictions` - Vector of [`Restriction`](restriction/enum.Restriction.html)s
     /// * `eventfd` - Optional eventfd for receiving completion notifications.
+    /// * `sq_thread_idle` - Optional idle time in milliseconds, after which the kernel thread
+    /// polling the submission queue goes to sleep. Enables submission queue polling.
     pub fn new(
         num_entries: u32,
         files: Vec<&File>,
         restrictions: Vec<Restriction>,
         eventfd: Option<RawFd>,
+        sq_thread_idle: Option<u32>,
     ) -> Result<Self, IoUringError> {
         let mut params = io_uring_params {
             // Create the ring as disabled, so that we may register restrictions.
@@ -116,6 +128,10 @@ impl<T: Debug> IoUring<T> {
 
             ..Default::default()
         };
+        if let Some(sq_thread_idle) = sq_thread_idle {
+            params.flags |= bindings::IORING_SETUP_SQPOLL;
+            params.sq_thread_idle = sq_thread_idle;
+        }
 
         // SAFETY: Safe because values are valid and we check the return value.
         let fd = SyscallReturnCode(unsafe {
@@ -145,6 +161,7 @@ impl<T: Debug> IoUring<T> {
             cqueue,
             fd: file,
             registered_fds_count: 0,
+            registered_buffers_count: 0,
             num_ops: 0,
             slab,
         };
@@ -172,6 +189,11 @@ impl<T: Debug> IoUring<T> {
             0 => Err((IoUringError::NoRegisteredFds, op.user_data)),
             len if fd >= len => Err((IoUringError::InvalidFixedFd(fd), op.user_data)),
             _ => {
+                if let Some(index) = op.buf_index() {
+                    if u32::from(index) >= self.registered_buffers_count {
+                        return Err((IoUringError::InvalidFixedBuffer(index), op.user_data));
+                    }
+                }
                 if self.num_ops >= self.cqueue.count() {
                     return Err((IoUringError::FullCQueue, op.user_data));
                 }
@@ -246,6 +268,47 @@ impl<T: Debug> IoUring<T> {
         self.num_ops
     }
 
+    /// Register the memory ranges given as `(address, length)` pairs as buffers for the
+    /// `ReadFixed` and `WriteFixed` operations, which refer to them by their index. The pages of
+    /// the buffers are pinned until the instance is dropped.
+    ///
+    /// Once the instance is enabled, this requires the `AllowBufferRegistration` restriction.
+    pub fn register_buffers(&mut self, buffers: &[(usize, usize)]) -> Result<(), IoUringError> {
+        if buffers.is_empty() {
+            // No-op.
+            return Ok(());
+        }
+
+        if buffers.len() > IORING_MAX_REG_BUFFERS {
+            return Err(IoUringError::RegisterBufferLimitExceeded);
+        }
+
+        let iovecs = buffers
+            .iter()
+            .map(|&(addr, len)| libc::iovec {
+                iov_base: addr as *mut libc::c_void,
+                iov_len: len,
+            })
+            .collect::<Vec<_>>();
+
+        // SAFETY: Safe because values are valid and we check the return value.
+        SyscallReturnCode(unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_register,
+                self.fd.as_raw_fd(),
+                bindings::IORING_REGISTER_BUFFERS,
+                iovecs.as_ptr(),
+                iovecs.len(),
+            )
+        })
+        .into_empty_result()
+        .map_err(IoUringError::RegisterBuffers)?;
+
+        // Safe to unwrap since buffers.len() <= IORING_MAX_REG_BUFFERS
+        self.registered_buffers_count = u32::try_from(buffers.len()).unwrap();
+        Ok(())
+    }
+
     fn enable(&mut self) -> Result<(), IoUringError> {
         // SAFETY: Safe because values are valid and we check the return value.
         SyscallReturnCode(unsafe {
@@ -525,7 +588,7 @@ mod tests {
                 &proptest::collection::vec(arbitrary_rw_operation(FILE_LEN), OPS_COUNT),
                 |set| {
                     let mut ring =
-                        IoUring::new(RING_SIZE, vec![&file_async], vec![], None).unwrap();
+                        IoUring::new(RING_SIZE, vec![&file_async], vec![], None, None).unwrap();
 
                     for mut operation in set {
                         // Perform the sync op.
@@ -624,4 +687,52 @@ mod tests {
         free_mem_region(sync_read_mem_region);
         free_mem_region(async_read_mem_region);
     }
+
+    #[test]
+    fn test_fixed_buffers() {
+        skip_if_io_uring_unsupported!();
+
+        const LEN: u32 = 4096;
+        let file = TempFile::new().unwrap().into_file();
+        file.write_all_at(&[0xab; LEN as usize], 0).unwrap();
+
+        // Fixed buffers are used in the same way whether the submission queue is polled or not.
+        for sq_thread_idle in [None, Some(100)] {
+            let mem_region = setup_mem_region(LEN as usize);
+            let addr = mem_region.as_ptr() as usize;
+            let mut ring = IoUring::new(16, vec![&file], vec![], None, sq_thread_idle).unwrap();
+
+            // Operations on buffers that were not registered are rejected.
+            let (err, user_data) = ring
+                .push(Operation::read_fixed(0, addr, LEN, 0, 0, 0u32))
+                .unwrap_err();
+            assert!(matches!(err, IoUringError::InvalidFixedBuffer(0)), "{err}");
+            assert_eq!(user_data, 0);
+
+            ring.register_buffers(&[(addr, LEN as usize)]).unwrap();
+
+            // Read the file into the buffer.
+            ring.push(Operation::read_fixed(0, addr, LEN, 0, 0, LEN))
+                .unwrap();
+            ring.submit_and_wait_all().unwrap();
+            drain_cqueue(&mut ring);
+            assert_eq!(
+                read_entire_mem_region(&mem_region),
+                vec![0xab; LEN as usize]
+            );
+
+            // Write the buffer after the data read from the file.
+            ring.push(Operation::write_fixed(0, addr, LEN, u64::from(LEN), 0, LEN))
+                .unwrap();
+            ring.submit_and_wait_all().unwrap();
+            drain_cqueue(&mut ring);
+            let mut buf = vec![0u8; LEN as usize];
+            file.read_exact_at(&mut buf, u64::from(LEN)).unwrap();
+            assert_eq!(buf, vec![0xab; LEN as usize]);
+
+            // Unregister the buffers before unmapping them.
+            drop(ring);
+            free_mem_region(mem_region);
+        }
+    }
 }
@@ -38,6 +38,11 @@ impl<T: Debug> Cqe<T> {
         }
     }
 
+    /// Mark the operation as failed with the given error number.
+    pub fn set_error(&mut self, errno: i32) {
+        self.res = -errno;
+    }
+
     /// Create a new Cqe, applying the passed function to the user_data.
     pub fn map_user_data<U: Debug, F: FnOnce(T) -> U>(self, op: F) -> Cqe<U> {
         Cqe {
@@ -78,6 +83,15 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_set_error() {
+        let mut cqe: Cqe<u8> = Cqe::new(128, 10_u8);
+        cqe.set_error(libc::EIO);
+
+        assert_eq!(cqe.count(), 0);
+        assert!(cqe.result().is_err());
+    }
+
     #[test]
     fn test_user_data() {
         let user_data = 10_u8;
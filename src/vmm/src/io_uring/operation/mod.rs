@@ -11,12 +11,16 @@ use std::fmt::{self, Debug};
 
 pub use cqe::Cqe;
 pub(crate) use sqe::Sqe;
+use utils::u64_to_usize;
 
 use crate::io_uring::bindings::{self, io_uring_sqe, IOSQE_FIXED_FILE_BIT};
 
 /// The index of a registered fd.
 pub type FixedFd = u32;
 
+/// The index of a registered buffer.
+pub type FixedBuffer = u16;
+
 #[repr(u8)]
 #[derive(Debug, Clone, Copy)]
 // These constants are generated as u32, but we use u8; const try_from() is unstable
@@ -29,6 +33,12 @@ pub enum OpCode {
     Write = bindings::IORING_OP_WRITE as u8,
     /// Fsync operation.
     Fsync = bindings::IORING_OP_FSYNC as u8,
+    /// Fallocate operation.
+    Fallocate = bindings::IORING_OP_FALLOCATE as u8,
+    /// Read operation into a registered buffer.
+    ReadFixed = bindings::IORING_OP_READ_FIXED as u8,
+    /// Write operation from a registered buffer.
+    WriteFixed = bindings::IORING_OP_WRITE_FIXED as u8,
 }
 
 // Useful for outputting errors.
@@ -38,6 +48,9 @@ impl From<OpCode> for &'static str {
             OpCode::Read => "read",
             OpCode::Write => "write",
             OpCode::Fsync => "fsync",
+            OpCode::Fallocate => "fallocate",
+            OpCode::ReadFixed => "read fixed",
+            OpCode::WriteFixed => "write fixed",
         }
     }
 }
@@ -51,6 +64,7 @@ pub struct Operation<T> {
     pub(crate) len: Option<u32>,
     flags: u8,
     pub(crate) offset: Option<u64>,
+    buf_index: Option<FixedBuffer>,
     pub(crate) user_data: T,
 }
 
@@ -83,6 +97,7 @@ impl<T: Debug> Operation<T> {
             len: Some(len),
             flags: 0,
             offset: Some(offset),
+            buf_index: None,
             user_data,
         }
     }
@@ -96,6 +111,51 @@ impl<T: Debug> Operation<T> {
             len: Some(len),
             flags: 0,
             offset: Some(offset),
+            buf_index: None,
+            user_data,
+        }
+    }
+
+    /// Construct a read operation into the buffer registered at index `buf_index`, which must
+    /// hold the `len` bytes at `addr`.
+    pub fn read_fixed(
+        fd: FixedFd,
+        addr: usize,
+        len: u32,
+        offset: u64,
+        buf_index: FixedBuffer,
+        user_data: T,
+    ) -> Self {
+        Self {
+            fd,
+            opcode: OpCode::ReadFixed,
+            addr: Some(addr),
+            len: Some(len),
+            flags: 0,
+            offset: Some(offset),
+            buf_index: Some(buf_index),
+            user_data,
+        }
+    }
+
+    /// Construct a write operation from the buffer registered at index `buf_index`, which must
+    /// hold the `len` bytes at `addr`.
+    pub fn write_fixed(
+        fd: FixedFd,
+        addr: usize,
+        len: u32,
+        offset: u64,
+        buf_index: FixedBuffer,
+        user_data: T,
+    ) -> Self {
+        Self {
+            fd,
+            opcode: OpCode::WriteFixed,
+            addr: Some(addr),
+            len: Some(len),
+            flags: 0,
+            offset: Some(offset),
+            buf_index: Some(buf_index),
             user_data,
         }
     }
@@ -109,6 +169,24 @@ impl<T: Debug> Operation<T> {
             len: None,
             flags: 0,
             offset: None,
+            buf_index: None,
+            user_data,
+        }
+    }
+
+    /// Construct a fallocate operation, changing the allocation of `len` bytes of the file
+    /// starting at `offset` according to the fallocate `mode` flags.
+    pub fn fallocate(fd: FixedFd, mode: u32, offset: u64, len: u64, user_data: T) -> Self {
+        Self {
+            fd,
+            opcode: OpCode::Fallocate,
+            // The kernel reads the length from the address field and the mode from the length
+            // field of the submission entry.
+            addr: Some(u64_to_usize(len)),
+            len: Some(mode),
+            flags: 0,
+            offset: Some(offset),
+            buf_index: None,
             user_data,
         }
     }
@@ -117,6 +195,10 @@ impl<T: Debug> Operation<T> {
         self.fd
     }
 
+    pub(crate) fn buf_index(&self) -> Option<FixedBuffer> {
+        self.buf_index
+    }
+
     // Needed for proptesting.
     #[cfg(test)]
     pub(crate) fn set_linked(&mut self) {
@@ -146,6 +228,18 @@ impl<T: Debug> Operation<T> {
         if let Some(offset) = self.offset {
             inner.__bindgen_anon_1.off = offset;
         }
+
+        if let Some(buf_index) = self.buf_index {
+            inner.__bindgen_anon_4.__bindgen_anon_1 =
+                bindings::io_uring_sqe__bindgen_ty_4__bindgen_ty_1 {
+                    __bindgen_anon_1:
+                        bindings::io_uring_sqe__bindgen_ty_4__bindgen_ty_1__bindgen_ty_1 {
+                            buf_index,
+                        },
+                    personality: 0,
+                    splice_fd_in: 0,
+                };
+        }
         inner.user_data = slab.insert(self.user_data) as u64;
 
         Sqe::new(inner)
@@ -6,7 +6,7 @@ use std::io::Error as IOError;
 use std::mem;
 use std::num::Wrapping;
 use std::os::unix::io::RawFd;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{fence, Ordering};
 
 use utils::syscall::SyscallReturnCode;
 use vm_memory::{VolatileMemory, VolatileMemoryError};
@@ -36,6 +36,10 @@ pub(crate) struct SubmissionQueue {
     // Offsets.
     head_off: usize,
     tail_off: usize,
+    flags_off: usize,
+
+    // Whether a kernel thread polls the queue for new entries.
+    sq_poll: bool,
 
     // Cached values.
     ring_mask: u32,
@@ -72,6 +76,8 @@ impl SubmissionQueue {
             io_uring_fd,
             head_off: params.sq_off.head as usize,
             tail_off: params.sq_off.tail as usize,
+            flags_off: params.sq_off.flags as usize,
+            sq_poll: params.flags & bindings::IORING_SETUP_SQPOLL != 0,
             ring_mask,
             count: params.sq_entries,
             // We can init this to 0 and cache it because we are the only ones modifying it.
@@ -130,6 +136,18 @@ impl SubmissionQueue {
         if min_complete > 0 {
             flags |= bindings::IORING_ENTER_GETEVENTS;
         }
+
+        if self.sq_poll {
+            // The kernel thread polling the queue picks up the new entries by itself, unless it
+            // went to sleep and needs to be woken up.
+            if self.needs_wakeup()? {
+                flags |= bindings::IORING_ENTER_SQ_WAKEUP;
+            } else if min_complete == 0 {
+                let submitted = self.to_submit;
+                self.to_submit = 0;
+                return Ok(submitted);
+            }
+        }
         // SAFETY: Safe because values are valid and we check the return value.
         let submitted = SyscallReturnCode(unsafe {
             libc::syscall(
@@ -180,6 +198,17 @@ impl SubmissionQueue {
         Ok((sqe_ring, sqes))
     }
 
+    fn needs_wakeup(&self) -> Result<bool, SQueueError> {
+        // Make sure the tail update is visible before checking whether the kernel thread sleeps.
+        fence(Ordering::SeqCst);
+        let flags = self
+            .ring
+            .as_volatile_slice()
+            .load::<u32>(self.flags_off, Ordering::Relaxed)?;
+
+        Ok(flags & bindings::IORING_SQ_NEED_WAKEUP != 0)
+    }
+
     pub(crate) fn pending(&self) -> Result<u32, SQueueError> {
         let ring_slice = self.ring.as_volatile_slice();
         // get the sqe head
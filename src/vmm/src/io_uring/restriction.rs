@@ -22,6 +22,8 @@ pub enum Restriction {
     AllowOpCode(OpCode),
     /// Only allow operations on pre-registered fds.
     RequireFixedFds,
+    /// Allow registering buffers once the instance is enabled.
+    AllowBufferRegistration,
 }
 
 impl From<&Restriction> for bindings::io_uring_restriction {
@@ -41,6 +43,11 @@ impl From<&Restriction> for bindings::io_uring_restriction {
                     u16::try_from(bindings::IORING_RESTRICTION_SQE_FLAGS_REQUIRED).unwrap();
                 instance.__bindgen_anon_1.sqe_flags = 1 << bindings::IOSQE_FIXED_FILE_BIT;
             }
+            AllowBufferRegistration => {
+                instance.opcode = u16::try_from(bindings::IORING_RESTRICTION_REGISTER_OP).unwrap();
+                instance.__bindgen_anon_1.register_op =
+                    u8::try_from(bindings::IORING_REGISTER_BUFFERS).unwrap();
+            }
         };
 
         instance
@@ -77,6 +77,8 @@ pub mod rate_limiter;
 /// Currently, we only use ACPI on x86 microVMs.
 #[cfg(target_arch = "x86_64")]
 pub mod acpi;
+/// Writing guest memory to a snapshot while the microVM runs.
+pub mod background_snapshot;
 /// Handles setup and initialization a `Vmm` object.
 pub mod builder;
 /// Types for guest configuration.
@@ -89,6 +91,8 @@ pub mod devices;
 pub mod dumbo;
 /// Logger
 pub mod logger;
+/// Live migration of a microVM over a Unix domain socket.
+pub mod migration;
 /// microVM Metadata Service MMDS
 pub mod mmds;
 /// Save/restore utilities.
@@ -132,6 +136,7 @@ use utils::u64_to_usize;
 use vstate::vcpu::{self, KvmVcpuConfigureError, StartThreadedError, VcpuSendEventError};
 
 use crate::arch::DeviceType;
+use crate::background_snapshot::SnapshotWriter;
 use crate::cpu_config::templates::CpuConfiguration;
 #[cfg(target_arch = "x86_64")]
 use crate::device_manager::legacy::PortIODeviceManager;
@@ -148,6 +153,8 @@ use crate::persist::{MicrovmState, MicrovmStateError, VmInfo};
 use crate::rate_limiter::BucketUpdate;
 use crate::snapshot::Persist;
 use crate::vmm_config::instance_info::{InstanceInfo, VmState};
+use crate::vmm_config::snapshot::SnapshotStatus;
+use crate::vstate::dirty_ring::DirtyRingLog;
 use crate::vstate::memory::{
     GuestMemory, GuestMemoryExtension, GuestMemoryMmap, GuestMemoryRegion,
 };
@@ -213,6 +220,8 @@ pub enum VmmError {
     DeviceManager(device_manager::mmio::MmioError),
     /// Error getting the KVM dirty bitmap. {0}
     DirtyBitmap(kvm_ioctls::Error),
+    /// Error harvesting the KVM dirty rings. {0}
+    DirtyRing(vstate::dirty_ring::DirtyRingError),
     /// Event fd error: {0}
     EventFd(io::Error),
     /// I8042 error: {0}
@@ -230,6 +239,8 @@ pub enum VmmError {
     SeccompFilters(seccompiler::InstallationError),
     /// Error writing to the serial console: {0}
     Serial(io::Error),
+    /// Failed to start the background snapshot thread: {0}
+    SnapshotWriter(background_snapshot::BackgroundSnapshotError),
     /// Error creating timer fd: {0}
     TimerFd(io::Error),
     /// Error configuring the vcpu for boot: {0}
@@ -312,8 +323,6 @@ pub struct Vmm {
     vm: Vm,
     guest_memory: GuestMemoryMmap,
     // Save UFFD in order to keep it open in the Firecracker process, as well.
-    // Since this field is never read again, we need to allow `dead_code`.
-    #[allow(dead_code)]
     uffd: Option<Uffd>,
     vcpus_handles: Vec<VcpuHandle>,
     // Used by Vcpus and devices to initiate teardown; Vmm should never write here.
@@ -327,6 +336,11 @@ pub struct Vmm {
     pio_device_manager: PortIODeviceManager,
     #[cfg(target_arch = "x86_64")]
     acpi_device_manager: ACPIDeviceManager,
+    // Progress of the last snapshot written in the background.
+    snapshot_status: Arc<Mutex<SnapshotStatus>>,
+    // Thread writing snapshots in the background, spawned before the seccomp filters of the VMM
+    // thread are installed.
+    snapshot_writer: Option<SnapshotWriter>,
 }
 
 impl Vmm {
@@ -340,6 +354,11 @@ impl Vmm {
         self.instance_info.clone()
     }
 
+    /// Returns the progress of the last snapshot created in the background.
+    pub fn snapshot_status(&self) -> SnapshotStatus {
+        self.snapshot_status.lock().expect("Poisoned lock").clone()
+    }
+
     /// Provides the Vmm shutdown exit code if there is one.
     pub fn shutdown_exit_code(&self) -> Option<FcExitCode> {
         self.shutdown_exit_code
@@ -528,6 +547,9 @@ impl Vmm {
                 self.vm.save_state(&mpidrs).map_err(SaveVmState)?
             }
         };
+        self.mmio_device_manager
+            .prepare_save()
+            .map_err(MicrovmStateError::PrepareBlockDevices)?;
         let device_states = self.mmio_device_manager.save();
 
         let memory_state = self.guest_memory().describe();
@@ -603,6 +625,10 @@ impl Vmm {
 
     /// Retrieves the KVM dirty bitmap for each of the guest's memory regions.
     pub fn reset_dirty_bitmap(&self) {
+        if self.vm.dirty_ring().is_some() {
+            let _ = self.get_dirty_bitmap();
+            return;
+        }
         self.guest_memory
             .iter()
             .enumerate()
@@ -616,6 +642,10 @@ impl Vmm {
 
     /// Retrieves the KVM dirty bitmap for each of the guest's memory regions.
     pub fn get_dirty_bitmap(&self) -> Result<DirtyBitmap, VmmError> {
+        if let Some(dirty_ring) = self.vm.dirty_ring() {
+            self.harvest_dirty_rings(dirty_ring)?;
+            return Ok(dirty_ring.take_dirty_bitmap(&self.guest_memory));
+        }
         let mut bitmap: DirtyBitmap = HashMap::new();
         self.guest_memory
             .iter()
@@ -632,6 +662,25 @@ impl Vmm {
         Ok(bitmap)
     }
 
+    /// Makes the vCPUs harvest their dirty rings, then lets KVM reuse the harvested entries.
+    fn harvest_dirty_rings(&self, dirty_ring: &DirtyRingLog) -> Result<(), VmmError> {
+        self.vcpus_handles
+            .iter()
+            .try_for_each(|handle| handle.send_event(VcpuEvent::HarvestDirtyRing))
+            .map_err(|_| VmmError::VcpuMessage)?;
+
+        if self
+            .vcpus_handles
+            .iter()
+            .map(|handle| handle.response_receiver().recv_timeout(RECV_TIMEOUT_SEC))
+            .any(|response| !matches!(response, Ok(VcpuResponse::HarvestedDirtyRing)))
+        {
+            return Err(VmmError::VcpuMessage);
+        }
+
+        dirty_ring.reset().map_err(VmmError::DirtyRing)
+    }
+
     /// Enables or disables KVM dirty page tracking.
     pub fn set_dirty_page_tracking(&mut self, enable: bool) -> Result<(), VmmError> {
         // This function _always_ results in an ioctl update. The VMM is stateless in the sense
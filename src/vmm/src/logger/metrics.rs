@@ -440,6 +440,10 @@ pub struct PutRequestsMetrics {
     pub vsock_count: SharedIncMetric,
     /// Number of failures in creating a vsock device.
     pub vsock_fails: SharedIncMetric,
+    /// Number of PUTs for creating a rate limiter group.
+    pub rate_limiter_group_count: SharedIncMetric,
+    /// Number of failures in creating a rate limiter group.
+    pub rate_limiter_group_fails: SharedIncMetric,
 }
 impl PutRequestsMetrics {
     /// Const default construction.
@@ -465,6 +469,8 @@ impl PutRequestsMetrics {
             mmds_fails: SharedIncMetric::new(),
             vsock_count: SharedIncMetric::new(),
             vsock_fails: SharedIncMetric::new(),
+            rate_limiter_group_count: SharedIncMetric::new(),
+            rate_limiter_group_fails: SharedIncMetric::new(),
         }
     }
 }
@@ -488,6 +494,10 @@ pub struct PatchRequestsMetrics {
     pub mmds_count: SharedIncMetric,
     /// Number of failures in PATCHing an mmds.
     pub mmds_fails: SharedIncMetric,
+    /// Number of tries to PATCH a rate limiter group.
+    pub rate_limiter_group_count: SharedIncMetric,
+    /// Number of failures in PATCHing a rate limiter group.
+    pub rate_limiter_group_fails: SharedIncMetric,
 }
 impl PatchRequestsMetrics {
     /// Const default construction.
@@ -501,6 +511,8 @@ impl PatchRequestsMetrics {
             machine_cfg_fails: SharedIncMetric::new(),
             mmds_count: SharedIncMetric::new(),
             mmds_fails: SharedIncMetric::new(),
+            rate_limiter_group_count: SharedIncMetric::new(),
+            rate_limiter_group_fails: SharedIncMetric::new(),
         }
     }
 }
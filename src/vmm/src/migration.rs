@@ -0,0 +1,575 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements pre-copy live migration of a microVM over a Unix domain socket.
+//!
+//! The source streams guest memory to the destination while the guest keeps running, using KVM
+//! dirty page tracking to resend the pages modified in the meantime. Once the set of dirty pages
+//! is small enough (or the maximum number of iterations is reached), the source pauses the
+//! microVM, sends the last dirty pages followed by the serialized [`MicrovmState`], and waits for
+//! the destination to acknowledge that it built the microVM.
+//!
+//! The migration stream is a sequence of bincode encoded [`MigrationMessage`]s, some of them
+//! followed by a raw payload:
+//!
+//!  |----------------------------------------------|
+//!  |       Start { memory_state, huge_pages }     |
+//!  |----------------------------------------------|
+//!  |  Memory { guest_addr, len } + `len` bytes    |  (once per dirty range and iteration)
+//!  |----------------------------------------------|
+//!  |        State { len } + `len` bytes           |  (a complete vmstate snapshot)
+//!  |----------------------------------------------|
+//!
+//! The destination answers with a single `Complete` message.
+
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+
+use seccompiler::BpfThreadMap;
+use serde::{Deserialize, Serialize};
+use utils::{get_page_size, u64_to_usize};
+use vm_memory::{ReadVolatile, VolatileMemoryError, WriteVolatile};
+
+use crate::builder::{self, BuildMicrovmFromSnapshotError};
+use crate::logger::{info, warn};
+use crate::persist::{
+    self, MicrovmState, MicrovmStateError, RestoreFromSnapshotError, VmInfo, SNAPSHOT_VERSION,
+};
+use crate::resources::VmResources;
+use crate::snapshot::{Snapshot, SnapshotError};
+use crate::vmm_config::instance_info::{InstanceInfo, VmState};
+use crate::vmm_config::machine_config::HugePageConfig;
+use crate::vmm_config::migration::{ReceiveMigrationParams, SendMigrationParams};
+use crate::vstate::memory::{
+    Bitmap, GuestAddress, GuestMemory, GuestMemoryExtension, GuestMemoryMmap, GuestMemoryRegion,
+    GuestMemoryState, GuestRegionMmap, MemoryError, MemoryRegionAddress,
+};
+use crate::{DirtyBitmap, EventManager, Vmm, VmmError};
+
+/// Messages exchanged between the migration source and destination.
+#[derive(Debug, Serialize, Deserialize)]
+enum MigrationMessage {
+    /// Opens the migration stream, describing the guest memory the destination has to allocate.
+    Start {
+        /// Layout of the guest memory regions.
+        memory_state: GuestMemoryState,
+        /// Page size backing the guest memory on the source.
+        huge_pages: HugePageConfig,
+    },
+    /// Followed by `len` bytes of guest memory starting at `guest_addr`.
+    Memory {
+        /// Guest physical address of the first byte.
+        guest_addr: u64,
+        /// Length of the range, in bytes.
+        len: u64,
+    },
+    /// Followed by a `len` bytes long snapshot of the microVM state.
+    State {
+        /// Length of the snapshot, in bytes.
+        len: u64,
+    },
+    /// Sent by the destination once the microVM was successfully built.
+    Complete,
+}
+
+/// Errors associated with sending a microVM to a migration destination.
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum SendMigrationError {
+    /// Cannot connect to the migration destination: {0}
+    Connect(io::Error),
+    /// Cannot write to the migration stream: {0}
+    Write(io::Error),
+    /// Cannot encode migration message: {0}
+    Serialize(SnapshotError),
+    /// Cannot decode migration message: {0}
+    Deserialize(SnapshotError),
+    /// Received an unexpected message from the migration destination.
+    UnexpectedMessage,
+    /// Cannot get dirty bitmap: {0}
+    DirtyBitmap(VmmError),
+    /// Cannot send guest memory: {0}
+    Memory(MemoryError),
+    /// Cannot pause the microVM: {0}
+    Pause(VmmError),
+    /// Cannot save the microVM state: {0}
+    MicrovmState(MicrovmStateError),
+}
+
+/// Errors associated with receiving a microVM from a migration source.
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum ReceiveMigrationError {
+    /// Cannot bind the migration socket: {0}
+    Bind(io::Error),
+    /// Cannot accept the migration connection: {0}
+    Accept(io::Error),
+    /// Cannot read from the migration stream: {0}
+    Read(io::Error),
+    /// Cannot decode migration message: {0}
+    Deserialize(SnapshotError),
+    /// Cannot acknowledge the migration: {0}
+    Acknowledge(SnapshotError),
+    /// Received an unexpected message from the migration source.
+    UnexpectedMessage,
+    /// Cannot create guest memory: {0}
+    CreateMemory(MemoryError),
+    /// Received guest memory range {0:#x}+{1:#x} is not backed by guest memory.
+    InvalidMemoryRange(u64, u64),
+    /// Cannot load guest memory contents: {0}
+    ReadMemory(VolatileMemoryError),
+    /// Guest memory layout of the migrated microVM state does not match the received memory.
+    MemoryLayoutMismatch,
+    /// Cannot load the microVM state: {0}
+    LoadState(SnapshotError),
+    /// Invalid microVM state: {0}
+    Restore(#[from] RestoreFromSnapshotError),
+    /// Cannot build the microVM: {0}
+    Build(#[from] BuildMicrovmFromSnapshotError),
+}
+
+/// Live migrates the microVM running inside the given [`Vmm`] to the Firecracker process
+/// listening on `params.socket_path`.
+///
+/// Dirty page tracking must be enabled on the microVM. On success the microVM is left paused;
+/// on failure it is resumed if it was paused by the migration. A microVM which is already paused
+/// is migrated as is, and stays paused on failure.
+///
+/// The whole transfer runs on the calling (VMM) thread, so device emulation is stalled until
+/// the migration completes or fails.
+pub fn send_migration(
+    vmm: &mut Vmm,
+    vm_info: &VmInfo,
+    params: &SendMigrationParams,
+) -> Result<(), SendMigrationError> {
+    let mut stream =
+        UnixStream::connect(&params.socket_path).map_err(SendMigrationError::Connect)?;
+
+    Snapshot::serialize(
+        &mut stream,
+        &MigrationMessage::Start {
+            memory_state: vmm.guest_memory().describe(),
+            huge_pages: vm_info.huge_pages,
+        },
+    )
+    .map_err(SendMigrationError::Serialize)?;
+
+    // Start tracking from a clean slate, then copy all of guest memory while the guest runs.
+    vmm.reset_dirty_bitmap();
+    vmm.guest_memory().reset_dirty();
+    let mut sent_pages = send_all_pages(&mut stream, vmm.guest_memory())?;
+    info!("Live migration sent {} guest memory pages.", sent_pages);
+
+    let mut iteration = 0;
+    while sent_pages > params.max_dirty_pages && iteration < params.max_precopy_iterations {
+        let dirty_bitmap = vmm
+            .get_dirty_bitmap()
+            .map_err(SendMigrationError::DirtyBitmap)?;
+        sent_pages = send_dirty_pages(&mut stream, vmm.guest_memory(), &dirty_bitmap)?;
+        iteration += 1;
+        info!(
+            "Live migration pre-copy iteration {} sent {} dirty pages.",
+            iteration, sent_pages
+        );
+    }
+
+    let was_running = vmm.instance_info.state == VmState::Running;
+    if was_running {
+        vmm.pause_vm().map_err(SendMigrationError::Pause)?;
+    }
+
+    let result = send_final_state(&mut stream, vmm, vm_info);
+    if result.is_err() && was_running {
+        // The destination never acknowledged the migration, so the source remains the only
+        // running copy of the microVM.
+        if let Err(err) = vmm.resume_vm() {
+            warn!("Failed to resume microVM after failed migration: {}", err);
+        }
+    }
+    result
+}
+
+/// Sends the pages dirtied since the last pre-copy iteration and the state of the (paused)
+/// microVM, then waits for the destination to acknowledge it.
+fn send_final_state(
+    stream: &mut UnixStream,
+    vmm: &mut Vmm,
+    vm_info: &VmInfo,
+) -> Result<(), SendMigrationError> {
+    let dirty_bitmap = vmm
+        .get_dirty_bitmap()
+        .map_err(SendMigrationError::DirtyBitmap)?;
+    let sent_pages = send_dirty_pages(stream, vmm.guest_memory(), &dirty_bitmap)?;
+    info!(
+        "Live migration sent {} dirty pages while paused.",
+        sent_pages
+    );
+
+    let microvm_state = vmm
+        .save_state(vm_info)
+        .map_err(SendMigrationError::MicrovmState)?;
+    let mut state = Vec::new();
+    Snapshot::new(SNAPSHOT_VERSION)
+        .save(&mut state, &microvm_state)
+        .map_err(SendMigrationError::Serialize)?;
+
+    Snapshot::serialize(
+        stream,
+        &MigrationMessage::State {
+            len: state.len() as u64,
+        },
+    )
+    .map_err(SendMigrationError::Serialize)?;
+    stream
+        .write_all(&state)
+        .map_err(SendMigrationError::Write)?;
+
+    match Snapshot::deserialize(stream).map_err(SendMigrationError::Deserialize)? {
+        MigrationMessage::Complete => Ok(()),
+        _ => Err(SendMigrationError::UnexpectedMessage),
+    }
+}
+
+/// Sends `len` bytes of `region` starting at `offset` within the region.
+fn send_memory_range(
+    stream: &mut UnixStream,
+    region: &GuestRegionMmap,
+    offset: u64,
+    len: usize,
+) -> Result<(), SendMigrationError> {
+    Snapshot::serialize(
+        stream,
+        &MigrationMessage::Memory {
+            guest_addr: region.start_addr().0 + offset,
+            len: len as u64,
+        },
+    )
+    .map_err(SendMigrationError::Serialize)?;
+
+    let slice = region
+        .get_slice(MemoryRegionAddress(offset), len)
+        .map_err(|err| SendMigrationError::Memory(MemoryError::WriteMemory(err)))?;
+    stream
+        .write_all_volatile(&slice)
+        .map_err(|err| SendMigrationError::Memory(MemoryError::WriteMemory(err.into())))
+}
+
+/// Sends the whole guest memory, returning the number of pages sent.
+fn send_all_pages(
+    stream: &mut UnixStream,
+    guest_memory: &GuestMemoryMmap,
+) -> Result<u64, SendMigrationError> {
+    let page_size =
+        get_page_size().map_err(|err| SendMigrationError::Memory(MemoryError::PageSize(err)))?;
+
+    guest_memory.iter().try_fold(0, |sent_pages, region| {
+        send_memory_range(stream, region, 0, u64_to_usize(region.len()))?;
+        Ok(sent_pages + region.len() / page_size as u64)
+    })
+}
+
+/// Sends all pages marked as dirty either by KVM in `dirty_bitmap` or by the device emulation in
+/// the guest memory bitmaps, returning the number of pages sent.
+fn send_dirty_pages(
+    stream: &mut UnixStream,
+    guest_memory: &GuestMemoryMmap,
+    dirty_bitmap: &DirtyBitmap,
+) -> Result<u64, SendMigrationError> {
+    let page_size =
+        get_page_size().map_err(|err| SendMigrationError::Memory(MemoryError::PageSize(err)))?;
+    let mut sent_pages = 0;
+
+    for (slot, region) in guest_memory.iter().enumerate() {
+        let kvm_bitmap = dirty_bitmap.get(&slot).map_or(&[][..], Vec::as_slice);
+        let firecracker_bitmap = region.bitmap();
+        let num_pages = u64_to_usize(region.len()) / page_size;
+
+        // Collect the dirty ranges as (first page, number of pages) pairs.
+        let mut dirty_ranges: Vec<(usize, usize)> = Vec::new();
+        for page in 0..num_pages {
+            let is_kvm_page_dirty = kvm_bitmap
+                .get(page / 64)
+                .map_or(false, |word| (word >> (page % 64)) & 1 != 0);
+            let is_firecracker_page_dirty = firecracker_bitmap.dirty_at(page * page_size);
+            if !is_kvm_page_dirty && !is_firecracker_page_dirty {
+                continue;
+            }
+
+            match dirty_ranges.last_mut() {
+                Some((first, count)) if *first + *count == page => *count += 1,
+                _ => dirty_ranges.push((page, 1)),
+            }
+        }
+        // Reset the bitmap before sending, so that writes racing with the transfer are
+        // picked up by the next iteration.
+        if let Some(bitmap) = firecracker_bitmap {
+            bitmap.reset();
+        }
+
+        for (first, count) in dirty_ranges {
+            send_memory_range(
+                stream,
+                region,
+                (first * page_size) as u64,
+                count * page_size,
+            )?;
+            sent_pages += count as u64;
+        }
+    }
+
+    Ok(sent_pages)
+}
+
+/// Waits for a migration source to connect on `params.socket_path` and builds a 'paused' microVM
+/// from the migration stream it sends.
+pub fn receive_migration(
+    instance_info: &InstanceInfo,
+    event_manager: &mut EventManager,
+    seccomp_filters: &BpfThreadMap,
+    params: &ReceiveMigrationParams,
+    vm_resources: &mut VmResources,
+) -> Result<Arc<Mutex<Vmm>>, ReceiveMigrationError> {
+    let mut stream = accept_migration(params)?;
+    let track_dirty_pages = params.enable_diff_snapshots;
+
+    let (memory_state, guest_memory) =
+        match Snapshot::deserialize(&mut stream).map_err(ReceiveMigrationError::Deserialize)? {
+            MigrationMessage::Start {
+                memory_state,
+                huge_pages,
+            } => {
+                let guest_memory =
+                    GuestMemoryMmap::from_state(None, &memory_state, track_dirty_pages, huge_pages)
+                        .map_err(ReceiveMigrationError::CreateMemory)?;
+                (memory_state, guest_memory)
+            }
+            _ => return Err(ReceiveMigrationError::UnexpectedMessage),
+        };
+
+    let microvm_state = receive_memory_and_state(&mut stream, &guest_memory)?;
+    if microvm_state.memory_state != memory_state {
+        return Err(ReceiveMigrationError::MemoryLayoutMismatch);
+    }
+
+    persist::update_vm_resources_from_state(vm_resources, &microvm_state, track_dirty_pages)?;
+
+    let vmm = builder::build_microvm_from_snapshot(
+        instance_info,
+        event_manager,
+        microvm_state,
+        guest_memory,
+        None,
+        seccomp_filters,
+        vm_resources,
+    )?;
+
+    Snapshot::serialize(&mut stream, &MigrationMessage::Complete)
+        .map_err(ReceiveMigrationError::Acknowledge)?;
+
+    Ok(vmm)
+}
+
+/// Binds the migration socket and accepts a single connection on it.
+fn accept_migration(params: &ReceiveMigrationParams) -> Result<UnixStream, ReceiveMigrationError> {
+    let listener = UnixListener::bind(&params.socket_path).map_err(ReceiveMigrationError::Bind)?;
+    info!(
+        "Waiting for migration source on {}.",
+        params.socket_path.display()
+    );
+    let accepted = listener.accept().map_err(ReceiveMigrationError::Accept);
+
+    // Only one migration source is ever accepted, so the socket file is no longer needed.
+    if let Err(err) = std::fs::remove_file(&params.socket_path) {
+        warn!("Failed to remove migration socket: {}", err);
+    }
+
+    accepted.map(|(stream, _)| stream)
+}
+
+/// Populates `guest_memory` from the incoming memory ranges until the microVM state arrives.
+fn receive_memory_and_state(
+    stream: &mut UnixStream,
+    guest_memory: &GuestMemoryMmap,
+) -> Result<MicrovmState, ReceiveMigrationError> {
+    loop {
+        match Snapshot::deserialize(stream).map_err(ReceiveMigrationError::Deserialize)? {
+            MigrationMessage::Memory { guest_addr, len } => {
+                let mut slice = guest_memory
+                    .get_slice(GuestAddress(guest_addr), u64_to_usize(len))
+                    .map_err(|_| ReceiveMigrationError::InvalidMemoryRange(guest_addr, len))?;
+                stream
+                    .read_exact_volatile(&mut slice)
+                    .map_err(ReceiveMigrationError::ReadMemory)?;
+            }
+            MigrationMessage::State { len } => {
+                let mut state = Vec::new();
+                stream
+                    .by_ref()
+                    .take(len)
+                    .read_to_end(&mut state)
+                    .map_err(ReceiveMigrationError::Read)?;
+                if state.len() as u64 != len {
+                    return Err(ReceiveMigrationError::Read(
+                        io::ErrorKind::UnexpectedEof.into(),
+                    ));
+                }
+
+                return Snapshot::new(SNAPSHOT_VERSION)
+                    .load_with_version_check(&mut state.as_slice(), state.len())
+                    .map_err(ReceiveMigrationError::LoadState);
+            }
+            _ => return Err(ReceiveMigrationError::UnexpectedMessage),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::tempfile::TempFile;
+
+    use super::*;
+    use crate::vstate::memory::{Bytes, GuestMemoryRegionState};
+
+    fn create_test_memory() -> GuestMemoryMmap {
+        let page_size = get_page_size().unwrap();
+        GuestMemoryMmap::from_raw_regions(
+            &[
+                (GuestAddress(0), page_size * 4),
+                (GuestAddress(page_size as u64 * 8), page_size * 4),
+            ],
+            true,
+            HugePageConfig::None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_send_dirty_pages() {
+        let page_size = get_page_size().unwrap();
+        let src_memory = create_test_memory();
+        let dst_memory = create_test_memory();
+
+        // Fill the source memory with a recognizable pattern.
+        for (i, region) in src_memory.iter().enumerate() {
+            let fill = vec![u8::try_from(i + 1).unwrap(); u64_to_usize(region.len())];
+            src_memory.write_slice(&fill, region.start_addr()).unwrap();
+        }
+        src_memory.reset_dirty();
+
+        // Page 1 of the first region is dirtied by KVM, page 3 of the second one by a device.
+        let mut dirty_bitmap = DirtyBitmap::new();
+        dirty_bitmap.insert(0, vec![0b10]);
+        dirty_bitmap.insert(1, vec![0]);
+        src_memory.mark_dirty(GuestAddress(page_size as u64 * 11), 1);
+
+        let (mut sender, mut receiver) = UnixStream::pair().unwrap();
+        let sent_pages = send_dirty_pages(&mut sender, &src_memory, &dirty_bitmap).unwrap();
+        assert_eq!(sent_pages, 2);
+
+        // The device bitmap is consumed by the transfer.
+        assert!(!src_memory
+            .iter()
+            .any(|region| region.bitmap().dirty_at(3 * page_size)));
+
+        Snapshot::serialize(&mut sender, &MigrationMessage::State { len: 0 }).unwrap();
+        drop(sender);
+        // An empty state cannot be loaded, but all memory ranges before it must have been applied.
+        receive_memory_and_state(&mut receiver, &dst_memory).unwrap_err();
+
+        let mut page = vec![0u8; page_size];
+        dst_memory
+            .read_slice(&mut page, GuestAddress(page_size as u64))
+            .unwrap();
+        assert!(page.iter().all(|&byte| byte == 1));
+        dst_memory
+            .read_slice(&mut page, GuestAddress(page_size as u64 * 11))
+            .unwrap();
+        assert!(page.iter().all(|&byte| byte == 2));
+        dst_memory.read_slice(&mut page, GuestAddress(0)).unwrap();
+        assert!(page.iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn test_receive_invalid_memory_range() {
+        let dst_memory = create_test_memory();
+        let (mut sender, mut receiver) = UnixStream::pair().unwrap();
+
+        Snapshot::serialize(
+            &mut sender,
+            &MigrationMessage::Memory {
+                guest_addr: 0x1000_0000,
+                len: 0x1000,
+            },
+        )
+        .unwrap();
+        assert!(matches!(
+            receive_memory_and_state(&mut receiver, &dst_memory),
+            Err(ReceiveMigrationError::InvalidMemoryRange(
+                0x1000_0000,
+                0x1000
+            ))
+        ));
+
+        Snapshot::serialize(&mut sender, &MigrationMessage::Complete).unwrap();
+        assert!(matches!(
+            receive_memory_and_state(&mut receiver, &dst_memory),
+            Err(ReceiveMigrationError::UnexpectedMessage)
+        ));
+    }
+
+    #[test]
+    fn test_receive_state() {
+        let dst_memory = create_test_memory();
+        let (mut sender, mut receiver) = UnixStream::pair().unwrap();
+
+        let microvm_state = MicrovmState {
+            memory_state: GuestMemoryState {
+                regions: vec![GuestMemoryRegionState {
+                    base_address: 0,
+                    size: 0x1000,
+                    offset: 0,
+                }],
+            },
+            ..Default::default()
+        };
+        let mut state = Vec::new();
+        Snapshot::new(SNAPSHOT_VERSION)
+            .save(&mut state, &microvm_state)
+            .unwrap();
+        Snapshot::serialize(
+            &mut sender,
+            &MigrationMessage::State {
+                len: state.len() as u64,
+            },
+        )
+        .unwrap();
+        sender.write_all(&state).unwrap();
+
+        let restored_state = receive_memory_and_state(&mut receiver, &dst_memory).unwrap();
+        assert_eq!(restored_state.memory_state, microvm_state.memory_state);
+    }
+
+    #[test]
+    fn test_accept_migration() {
+        let socket_path = TempFile::new().unwrap();
+        let socket_path = socket_path.as_path().to_path_buf();
+        std::fs::remove_file(&socket_path).unwrap();
+
+        let params = ReceiveMigrationParams {
+            socket_path: socket_path.clone(),
+            enable_diff_snapshots: false,
+            resume_vm: false,
+        };
+        let client_path = socket_path.clone();
+        let client = std::thread::spawn(move || loop {
+            if let Ok(stream) = UnixStream::connect(&client_path) {
+                return stream;
+            }
+            std::thread::yield_now();
+        });
+
+        let _stream = accept_migration(&params).unwrap();
+        client.join().unwrap();
+        // The socket file is removed once the source is connected.
+        assert!(!socket_path.exists());
+    }
+}
@@ -124,6 +124,14 @@ impl Mmds {
         }
     }
 
+    /// Invalidates all the session tokens generated so far when MMDS version 2 is enabled.
+    pub fn invalidate_tokens(&mut self) -> Result<(), MmdsDatastoreError> {
+        if let Some(ta) = self.token_authority.as_mut() {
+            ta.rotate_key()?;
+        }
+        Ok(())
+    }
+
     /// Checks if the provided token has not expired.
     pub fn is_valid_token(&self, token: &str) -> Result<bool, TokenError> {
         self.token_authority
@@ -613,4 +621,21 @@ mod tests {
             TokenError::InvalidState.to_string()
         );
     }
+
+    #[test]
+    fn test_invalidate_tokens() {
+        let mut mmds = Mmds::default();
+        // Nothing to invalidate with MMDS version 1.
+        mmds.invalidate_tokens().unwrap();
+
+        mmds.set_version(MmdsVersion::V2).unwrap();
+        mmds.set_aad("foo");
+        let token = mmds.generate_token(60).unwrap();
+        assert!(mmds.is_valid_token(&token).unwrap());
+
+        mmds.invalidate_tokens().unwrap();
+        assert!(!mmds.is_valid_token(&token).unwrap());
+        let token = mmds.generate_token(60).unwrap();
+        assert!(mmds.is_valid_token(&token).unwrap());
+    }
 }
@@ -102,6 +102,15 @@ impl TokenAuthority {
         self.aad = format!("microvmid={}", instance_id);
     }
 
+    /// Reinitialize the cipher under a new key, which invalidates all the tokens
+    /// created under the previous one.
+    pub fn rotate_key(&mut self) -> Result<(), MmdsTokenError> {
+        self.cipher = TokenAuthority::create_cipher(&mut self.entropy_pool)?;
+        // Reset encrypted tokens count.
+        self.num_encrypted_tokens = 0;
+        Ok(())
+    }
+
     /// Generate encoded token string using the token time to live provided.
     pub fn generate_token_secret(&mut self, ttl_seconds: u32) -> Result<String, MmdsTokenError> {
         // Check number of tokens encrypted under the current key. We need to
@@ -236,9 +245,7 @@ impl TokenAuthority {
             // healthy interactions with MMDS. However, if it happens, we expect the
             // customer code to have a retry mechanism in place and regenerate the
             // session token if the previous ones become invalid.
-            self.cipher = TokenAuthority::create_cipher(&mut self.entropy_pool)?;
-            // Reset encrypted tokens count.
-            self.num_encrypted_tokens = 0;
+            self.rotate_key()?;
             crate::logger::warn!(
                 "The limit of tokens generated under current MMDS token authority
                 has been reached. MMDS's token authority entity has been reseeded
@@ -5,21 +5,25 @@
 
 use std::fmt::Debug;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Write};
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::os::unix::fs::{FileExt, MetadataExt};
 use std::os::unix::io::AsRawFd;
 use std::os::unix::net::UnixStream;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 
+use aws_lc_rs::rand;
 use seccompiler::BpfThreadMap;
 use semver::Version;
 use serde::{Deserialize, Serialize};
 use userfaultfd::{FeatureFlags, Uffd, UffdBuilder};
+use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
 use utils::sock_ctrl_msg::ScmSocket;
 use utils::u64_to_usize;
 
 #[cfg(target_arch = "aarch64")]
 use crate::arch::aarch64::vcpu::{get_manufacturer_id_from_host, get_manufacturer_id_from_state};
+use crate::background_snapshot::{start_background_snapshot, BackgroundSnapshotError};
 use crate::builder::{self, BuildMicrovmFromSnapshotError};
 use crate::cpu_config::templates::StaticCpuTemplate;
 #[cfg(target_arch = "x86_64")]
@@ -28,18 +32,39 @@ use crate::cpu_config::x86_64::cpuid::common::get_vendor_id_from_host;
 use crate::cpu_config::x86_64::cpuid::CpuidTrait;
 #[cfg(target_arch = "x86_64")]
 use crate::device_manager::persist::ACPIDeviceManagerState;
-use crate::device_manager::persist::{DevicePersistError, DeviceStates};
+use crate::device_manager::persist::{DevicePersistError, DeviceStates, DeviceStatesV2};
+use crate::devices::virtio::block::persist::BlockState;
+use crate::devices::virtio::block::BlockError;
+use crate::devices::virtio::net::{Net, NetError};
+use crate::devices::virtio::vsock::persist::VsockBackendState;
+use crate::devices::virtio::TYPE_NET;
 use crate::logger::{info, warn};
+use crate::mmds::data_store::MmdsDatastoreError;
 use crate::resources::VmResources;
-use crate::snapshot::Snapshot;
+use crate::snapshot::compression::{
+    compress_memory, CompressedMemoryFile, CompressionError, DEFAULT_CHUNK_SIZE,
+};
+use crate::snapshot::encryption::{
+    encrypt_memory, EncryptedMemoryFile, EncryptionError, EncryptionKey, EncryptionKeyError,
+    DEFAULT_ENCRYPTED_CHUNK_SIZE,
+};
+use crate::snapshot::integrity::{
+    integrity_key, IntegrityError, SnapshotManifest, DEFAULT_MANIFEST_CHUNK_SIZE,
+};
+use crate::snapshot::translation::TranslatorRegistry;
+use crate::snapshot::{Snapshot, SnapshotError};
 use crate::vmm_config::boot_source::BootSourceConfig;
 use crate::vmm_config::instance_info::InstanceInfo;
-use crate::vmm_config::machine_config::{HugePageConfig, MachineConfigUpdate, VmConfigError};
+use crate::vmm_config::machine_config::{
+    DirtyTrackingBackend, HugePageConfig, MachineConfigUpdate, VmConfigError,
+};
 use crate::vmm_config::snapshot::{
-    CreateSnapshotParams, LoadSnapshotParams, MemBackendType, SnapshotType,
+    CreateSnapshotParams, DriveOverride, LoadSnapshotParams, MemBackendType, MemoryCompression,
+    SnapshotOutput, SnapshotStatus, SnapshotType,
 };
 use crate::vstate::memory::{
-    GuestMemory, GuestMemoryExtension, GuestMemoryMmap, GuestMemoryState, MemoryError,
+    GuestMemory, GuestMemoryExtension, GuestMemoryMmap, GuestMemoryRegion, GuestMemoryState,
+    MemoryError,
 };
 use crate::vstate::vcpu::{VcpuSendEventError, VcpuState};
 use crate::vstate::vm::VmState;
@@ -58,6 +83,8 @@ pub struct VmInfo {
     pub boot_source: BootSourceConfig,
     /// Huge page configuration
     pub huge_pages: HugePageConfig,
+    /// Dirty page tracking backend
+    pub dirty_tracking_backend: DirtyTrackingBackend,
 }
 
 impl From<&VmResources> for VmInfo {
@@ -68,6 +95,30 @@ impl From<&VmResources> for VmInfo {
             cpu_template: StaticCpuTemplate::from(&value.vm_config.cpu_template),
             boot_source: value.boot_source_config().clone(),
             huge_pages: value.vm_config.huge_pages,
+            dirty_tracking_backend: value.vm_config.dirty_tracking_backend,
+        }
+    }
+}
+
+/// Layout of [`VmInfo`] in snapshot format version 2.0.0.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct VmInfoV2 {
+    mem_size_mib: u64,
+    smt: bool,
+    cpu_template: StaticCpuTemplate,
+    boot_source: BootSourceConfig,
+    huge_pages: HugePageConfig,
+}
+
+impl From<VmInfoV2> for VmInfo {
+    fn from(info: VmInfoV2) -> Self {
+        VmInfo {
+            mem_size_mib: info.mem_size_mib,
+            smt: info.smt,
+            cpu_template: info.cpu_template,
+            boot_source: info.boot_source,
+            huge_pages: info.huge_pages,
+            dirty_tracking_backend: DirtyTrackingBackend::Bitmap,
         }
     }
 }
@@ -134,14 +185,46 @@ pub enum MicrovmStateError {
     SignalVcpu(VcpuSendEventError),
     /// Vcpu is in unexpected state.
     UnexpectedVcpuResponse,
+    /// Cannot prepare block devices for saving: {0}
+    PrepareBlockDevices(BlockError),
 }
 
 /// Errors associated with creating a snapshot.
 #[rustfmt::skip]
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
 pub enum CreateSnapshotError {
+    /// Cannot write the guest memory in the background: {0}
+    BackgroundSnapshot(BackgroundSnapshotError),
+    /// Only full, uncompressed and unencrypted snapshots can be created in the background.
+    BackgroundSnapshotFormat,
+    /// A snapshot is already being created in the background.
+    BackgroundSnapshotInProgress,
+    /// Background snapshots can only write the guest memory to regular files.
+    BackgroundSequentialSnapshot,
+    /// Background snapshots are not supported for microVMs with {0}.
+    BackgroundUnsupportedMicrovm(&'static str),
+    /// Cannot compress memory file: {0}
+    CompressMemory(CompressionError),
+    /// Diff snapshots cannot be compressed.
+    CompressedDiffSnapshot,
+    /// Compressed snapshots cannot be encrypted.
+    CompressedEncryptedSnapshot,
     /// Cannot get dirty bitmap: {0}
     DirtyBitmap(VmmError),
+    /// Cannot encrypt memory file: {0}
+    EncryptMemory(EncryptionError),
+    /// Diff snapshots cannot be encrypted.
+    EncryptedDiffSnapshot,
+    /// Invalid snapshot encryption key: {0}
+    InvalidEncryptionKey(EncryptionKeyError),
+    /// Invalid snapshot integrity key: {0}
+    InvalidIntegrityKey(EncryptionKeyError),
+    /// Cannot create the snapshot integrity manifest: {0}
+    Manifest(IntegrityError),
+    /// Integrity manifests cannot be created for snapshots written in the background.
+    ManifestBackgroundSnapshot,
+    /// Integrity manifests can only be created for snapshots written to regular files.
+    ManifestSequentialSnapshot,
     #[rustfmt::skip]
     /// Cannot translate microVM version to snapshot data version
     UnsupportedVersion,
@@ -149,18 +232,82 @@ pub enum CreateSnapshotError {
     Memory(MemoryError),
     /// Cannot perform {0} on the memory backing file: {1}
     MemoryBackingFile(&'static str, io::Error),
+    /// Cannot write a compressed or encrypted snapshot to the memory file the microVM is running from.
+    MemoryBackingFileInUse,
+    /// Diff snapshots can only be written to regular files.
+    SequentialDiffSnapshot,
     /// Cannot save the microVM state: {0}
     MicrovmState(MicrovmStateError),
+    /// Cannot pause the microVM: {0}
+    PauseMicrovm(VmmError),
+    /// Cannot resume the microVM: {0}
+    ResumeMicrovm(VmmError),
     /// Cannot serialize the microVM state: {0}
     SerializeMicrovmState(crate::snapshot::SnapshotError),
     /// Cannot perform {0} on the snapshot backing file: {1}
     SnapshotBackingFile(&'static str, io::Error),
     /// Size mismatch when writing diff snapshot on top of base layer: base layer size is {0} but diff layer is size {1}.
     SnapshotBackingFileLengthMismatch(u64, u64),
+    /// Diff snapshots are not supported for microVMs with vhost-user block devices.
+    VhostUserDiffSnapshot,
 }
 
 /// Snapshot version
-pub const SNAPSHOT_VERSION: Version = Version::new(2, 0, 0);
+pub const SNAPSHOT_VERSION: Version = Version::new(3, 0, 0);
+
+/// Returns the translators upgrading microVM states written with older snapshot format versions
+/// to [`SNAPSHOT_VERSION`].
+pub fn microvm_state_translators() -> TranslatorRegistry {
+    let mut translators = TranslatorRegistry::default();
+    translators.register(
+        Version::new(1, 0, 0),
+        Version::new(2, 0, 0),
+        add_acpi_device_state,
+    );
+    translators.register(
+        Version::new(2, 0, 0),
+        Version::new(3, 0, 0),
+        add_device_settings,
+    );
+    translators
+}
+
+/// Format version 2.0.0 appended the ACPI devices state to the x86_64 microVM state. Microvms
+/// snapshotted with older versions had no VMGenID device, so the default state is appended.
+fn add_acpi_device_state(state: Vec<u8>) -> Result<Vec<u8>, SnapshotError> {
+    #[cfg(target_arch = "x86_64")]
+    let state = {
+        let mut state = state;
+        Snapshot::serialize(&mut state, &ACPIDeviceManagerState::default())?;
+        state
+    };
+    Ok(state)
+}
+
+/// Format version 3.0.0 appended the dirty tracking backend to the VM info, the vring bases to the
+/// vhost-user block device states, the overlay, io_uring, host page cache, logical block size, rate
+/// limiter group, verity, NBD and encryption settings to the block device states, the rate limiter
+/// group to the network device states and the shared rate limiter groups to the device states.
+/// Microvms snapshotted with older versions used none of these settings, and could not be
+/// snapshotted with vhost-user block devices, so their states are rewritten with the defaults.
+fn add_device_settings(state: Vec<u8>) -> Result<Vec<u8>, SnapshotError> {
+    let mut reader = state.as_slice();
+    let vm_info: VmInfoV2 = Snapshot::deserialize(&mut reader)?;
+    let memory_state: GuestMemoryState = Snapshot::deserialize(&mut reader)?;
+    let vm_state: VmState = Snapshot::deserialize(&mut reader)?;
+    let vcpu_states: Vec<VcpuState> = Snapshot::deserialize(&mut reader)?;
+    let device_states: DeviceStatesV2 = Snapshot::deserialize(&mut reader)?;
+
+    let mut translated = Vec::with_capacity(state.len());
+    Snapshot::serialize(&mut translated, &VmInfo::from(vm_info))?;
+    Snapshot::serialize(&mut translated, &memory_state)?;
+    Snapshot::serialize(&mut translated, &vm_state)?;
+    Snapshot::serialize(&mut translated, &vcpu_states)?;
+    Snapshot::serialize(&mut translated, &DeviceStates::from(device_states))?;
+    // The states following the device states did not change.
+    translated.extend_from_slice(reader);
+    Ok(translated)
+}
 
 /// Creates a Microvm snapshot.
 pub fn create_snapshot(
@@ -168,62 +315,344 @@ pub fn create_snapshot(
     vm_info: &VmInfo,
     params: &CreateSnapshotParams,
 ) -> Result<(), CreateSnapshotError> {
+    if params.background {
+        return create_background_snapshot(vmm, vm_info, params);
+    }
+    if params.snapshot_type == SnapshotType::Diff && params.compression != MemoryCompression::None {
+        return Err(CreateSnapshotError::CompressedDiffSnapshot);
+    }
+    if params.snapshot_type == SnapshotType::Diff && params.encryption.is_some() {
+        return Err(CreateSnapshotError::EncryptedDiffSnapshot);
+    }
+    if params.compression != MemoryCompression::None && params.encryption.is_some() {
+        return Err(CreateSnapshotError::CompressedEncryptedSnapshot);
+    }
+    let encryption_key = params
+        .encryption
+        .as_ref()
+        .map(EncryptionKey::from_config)
+        .transpose()
+        .map_err(CreateSnapshotError::InvalidEncryptionKey)?;
+    let manifest_key = params
+        .integrity
+        .as_ref()
+        .map(integrity_key)
+        .transpose()
+        .map_err(CreateSnapshotError::InvalidIntegrityKey)?;
+
+    let snapshot_sequential = is_sequential(&params.snapshot_output)
+        .map_err(|err| CreateSnapshotError::SnapshotBackingFile("get_metadata", err))?;
+    let mem_file_sequential = is_sequential(&params.mem_file_output)
+        .map_err(|err| CreateSnapshotError::MemoryBackingFile("get_metadata", err))?;
+    // Diff layers are merged into the memory file by seeking over the unmodified pages.
+    if params.snapshot_type == SnapshotType::Diff && mem_file_sequential {
+        return Err(CreateSnapshotError::SequentialDiffSnapshot);
+    }
+    // The manifest is computed by reading the snapshot files back.
+    if params.integrity.is_some() && (snapshot_sequential || mem_file_sequential) {
+        return Err(CreateSnapshotError::ManifestSequentialSnapshot);
+    }
+
     let microvm_state = vmm
         .save_state(vm_info)
         .map_err(CreateSnapshotError::MicrovmState)?;
+    // Guest memory written by vhost-user backends is not tracked as dirty.
+    if params.snapshot_type == SnapshotType::Diff
+        && microvm_state
+            .device_states
+            .block_devices
+            .iter()
+            .any(|block| matches!(block.device_state, BlockState::VhostUser(_)))
+    {
+        return Err(CreateSnapshotError::VhostUserDiffSnapshot);
+    }
 
-    snapshot_state_to_file(&microvm_state, &params.snapshot_path)?;
+    snapshot_state_to_file(
+        &microvm_state,
+        &params.snapshot_output,
+        snapshot_sequential,
+        encryption_key.as_ref(),
+    )?;
 
-    snapshot_memory_to_file(vmm, &params.mem_file_path, params.snapshot_type)?;
+    match (params.compression, encryption_key) {
+        (_, Some(key)) => snapshot_memory_to_encoded_file(
+            vmm,
+            &params.mem_file_output,
+            mem_file_sequential,
+            MemoryFileEncoding::Encrypted(&key),
+        )?,
+        (MemoryCompression::None, None) => snapshot_memory_to_file(
+            vmm,
+            &params.mem_file_output,
+            mem_file_sequential,
+            params.snapshot_type,
+        )?,
+        (MemoryCompression::Lz4, None) => snapshot_memory_to_encoded_file(
+            vmm,
+            &params.mem_file_output,
+            mem_file_sequential,
+            MemoryFileEncoding::Compressed,
+        )?,
+    }
+
+    if let (Some(config), Some(key)) = (&params.integrity, manifest_key) {
+        write_snapshot_manifest(
+            &params.snapshot_output,
+            &params.mem_file_output,
+            &config.manifest_path,
+            &key,
+        )?;
+    }
 
     Ok(())
 }
 
+/// Creates a full snapshot of the microVM while it keeps running. The microVM is only paused
+/// while its state is saved, then guest memory is written to the memory file in the background.
+fn create_background_snapshot(
+    vmm: &mut Vmm,
+    vm_info: &VmInfo,
+    params: &CreateSnapshotParams,
+) -> Result<(), CreateSnapshotError> {
+    use self::CreateSnapshotError::*;
+
+    if params.snapshot_type != SnapshotType::Full
+        || params.compression != MemoryCompression::None
+        || params.encryption.is_some()
+    {
+        return Err(BackgroundSnapshotFormat);
+    }
+    if params.integrity.is_some() {
+        return Err(ManifestBackgroundSnapshot);
+    }
+    if vmm.snapshot_status() == SnapshotStatus::InProgress {
+        return Err(BackgroundSnapshotInProgress);
+    }
+    // Guest memory is write protected until it is written to the memory file, which only
+    // applies to anonymous memory written by this process.
+    if vm_info.huge_pages != HugePageConfig::None {
+        return Err(BackgroundUnsupportedMicrovm("huge pages"));
+    }
+    if vmm.uffd.is_some() {
+        return Err(BackgroundUnsupportedMicrovm("the Uffd memory backend"));
+    }
+    if vmm.balloon_config().is_ok() {
+        return Err(BackgroundUnsupportedMicrovm("a balloon device"));
+    }
+    // io_uring writes the guest memory registered as fixed buffers through its pinned pages,
+    // without faulting on the write protection.
+    if vmm.mmio_device_manager.has_fixed_buffers_block_device() {
+        return Err(BackgroundUnsupportedMicrovm(
+            "drives using io_uring fixed buffers",
+        ));
+    }
+    // This also covers the memfd backed memory shared with vhost-user backends.
+    if vmm
+        .guest_memory()
+        .iter()
+        .any(|region| region.file_offset().is_some())
+    {
+        return Err(BackgroundUnsupportedMicrovm(
+            "guest memory mapped from a file",
+        ));
+    }
+
+    let snapshot_sequential = is_sequential(&params.snapshot_output)
+        .map_err(|err| SnapshotBackingFile("get_metadata", err))?;
+    if is_sequential(&params.mem_file_output)
+        .map_err(|err| MemoryBackingFile("get_metadata", err))?
+    {
+        return Err(BackgroundSequentialSnapshot);
+    }
+
+    let was_running = vmm.instance_info.state == crate::vmm_config::instance_info::VmState::Running;
+    if was_running {
+        vmm.pause_vm().map_err(PauseMicrovm)?;
+    }
+    let result = start_background_memory_snapshot(vmm, vm_info, params, snapshot_sequential);
+    if was_running {
+        vmm.resume_vm().map_err(ResumeMicrovm)?;
+    }
+    result
+}
+
+/// Saves the state of the paused microVM and starts writing its guest memory in the background.
+fn start_background_memory_snapshot(
+    vmm: &mut Vmm,
+    vm_info: &VmInfo,
+    params: &CreateSnapshotParams,
+    snapshot_sequential: bool,
+) -> Result<(), CreateSnapshotError> {
+    use self::CreateSnapshotError::*;
+
+    let microvm_state = vmm
+        .save_state(vm_info)
+        .map_err(CreateSnapshotError::MicrovmState)?;
+    snapshot_state_to_file(
+        &microvm_state,
+        &params.snapshot_output,
+        snapshot_sequential,
+        None,
+    )?;
+
+    // Pages are written at their offset in the file, leaving holes in place of zero pages.
+    let file = open_snapshot_output(&params.mem_file_output)
+        .map_err(|err| MemoryBackingFile("open", err))?;
+    file.set_len(0)
+        .map_err(|err| MemoryBackingFile("truncate", err))?;
+    file.set_len(mem_size_mib(vmm.guest_memory()) * 1024 * 1024)
+        .map_err(|err| MemoryBackingFile("set_length", err))?;
+
+    // As for full snapshots, the next diff snapshot only contains the pages dirtied from now on.
+    vmm.reset_dirty_bitmap();
+    vmm.guest_memory().reset_dirty();
+
+    // The status is set first, as the snapshot thread may complete at any time.
+    *vmm.snapshot_status.lock().expect("Poisoned lock") = SnapshotStatus::InProgress;
+    vmm.snapshot_writer
+        .as_ref()
+        .ok_or(BackgroundSnapshotError::ThreadExited)
+        .and_then(|snapshot_writer| {
+            start_background_snapshot(
+                snapshot_writer,
+                vmm.guest_memory(),
+                file,
+                vmm.snapshot_status.clone(),
+            )
+        })
+        .map_err(|err| {
+            *vmm.snapshot_status.lock().expect("Poisoned lock") = SnapshotStatus::Failed {
+                error: err.to_string(),
+            };
+            BackgroundSnapshot(err)
+        })
+}
+
+/// Returns whether `output` is a FIFO, socket or any other file that can only be written
+/// sequentially, as opposed to a regular file.
+fn is_sequential(output: &SnapshotOutput) -> io::Result<bool> {
+    let metadata = match output {
+        SnapshotOutput::Path(path) => match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            // Missing files are created as regular files.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        },
+        SnapshotOutput::File(file) => file.metadata()?,
+    };
+    Ok(!metadata.file_type().is_file())
+}
+
+/// Opens the file written to `output` for reading.
+fn open_written_output(output: &SnapshotOutput) -> io::Result<File> {
+    match output {
+        SnapshotOutput::Path(path) => File::open(path),
+        SnapshotOutput::File(file) => file.try_clone(),
+    }
+}
+
+/// Opens `output` for writing, creating it if it is a missing file.
+fn open_snapshot_output(output: &SnapshotOutput) -> io::Result<File> {
+    match output {
+        SnapshotOutput::Path(path) => OpenOptions::new().write(true).create(true).open(path),
+        SnapshotOutput::File(file) => file.try_clone(),
+    }
+}
+
 fn snapshot_state_to_file(
     microvm_state: &MicrovmState,
-    snapshot_path: &Path,
+    snapshot_output: &SnapshotOutput,
+    sequential: bool,
+    encryption_key: Option<&EncryptionKey>,
 ) -> Result<(), CreateSnapshotError> {
     use self::CreateSnapshotError::*;
-    let mut snapshot_file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(snapshot_path)
-        .map_err(|err| SnapshotBackingFile("open", err))?;
+    let mut snapshot_file =
+        open_snapshot_output(snapshot_output).map_err(|err| SnapshotBackingFile("open", err))?;
+    if !sequential {
+        snapshot_file
+            .set_len(0)
+            .map_err(|err| SnapshotBackingFile("truncate", err))?;
+        snapshot_file
+            .rewind()
+            .map_err(|err| SnapshotBackingFile("seek", err))?;
+    }
 
     let snapshot = Snapshot::new(SNAPSHOT_VERSION);
-    snapshot
-        .save(&mut snapshot_file, microvm_state)
-        .map_err(SerializeMicrovmState)?;
+    match encryption_key {
+        Some(key) => snapshot.save_encrypted(&mut snapshot_file, microvm_state, key),
+        None => snapshot.save(&mut snapshot_file, microvm_state),
+    }
+    .map_err(SerializeMicrovmState)?;
     snapshot_file
         .flush()
         .map_err(|err| SnapshotBackingFile("flush", err))?;
+    // There is nothing to sync to storage for FIFOs and sockets.
+    if !sequential {
+        snapshot_file
+            .sync_all()
+            .map_err(|err| SnapshotBackingFile("sync_all", err))?;
+    }
+    Ok(())
+}
+
+/// Writes to `manifest_path` the integrity manifest of the snapshot files written to
+/// `snapshot_output` and `mem_file_output`, authenticated using `key`.
+fn write_snapshot_manifest(
+    snapshot_output: &SnapshotOutput,
+    mem_file_output: &SnapshotOutput,
+    manifest_path: &Path,
+    key: &EncryptionKey,
+) -> Result<(), CreateSnapshotError> {
+    use self::CreateSnapshotError::*;
+
+    let snapshot_file =
+        open_written_output(snapshot_output).map_err(|err| SnapshotBackingFile("open", err))?;
+    let snapshot_len = snapshot_file
+        .metadata()
+        .map_err(|err| SnapshotBackingFile("get_metadata", err))?
+        .len();
+    let mut snapshot = vec![0u8; u64_to_usize(snapshot_len)];
     snapshot_file
-        .sync_all()
-        .map_err(|err| SnapshotBackingFile("sync_all", err))
+        .read_exact_at(&mut snapshot, 0)
+        .map_err(|err| SnapshotBackingFile("read", err))?;
+    let mem_file =
+        open_written_output(mem_file_output).map_err(|err| MemoryBackingFile("open", err))?;
+
+    SnapshotManifest::create(&snapshot, &mem_file, DEFAULT_MANIFEST_CHUNK_SIZE, key)
+        .and_then(|manifest| manifest.save(manifest_path))
+        .map_err(Manifest)
 }
 
 /// Takes a snapshot of the virtual machine running inside the given [`Vmm`] and saves it to
-/// `mem_file_path`.
+/// `mem_file_output`.
 ///
-/// If `snapshot_type` is [`SnapshotType::Diff`], and `mem_file_path` exists and is a snapshot file
-/// of matching size, then the diff snapshot will be directly merged into the existing snapshot.
-/// Otherwise, existing files are simply overwritten.
+/// If `snapshot_type` is [`SnapshotType::Diff`], and `mem_file_output` exists and is a snapshot
+/// file of matching size, then the diff snapshot will be directly merged into the existing
+/// snapshot. Otherwise, existing files are simply overwritten. Full snapshots are written
+/// sequentially to `sequential` outputs.
 fn snapshot_memory_to_file(
     vmm: &Vmm,
-    mem_file_path: &Path,
+    mem_file_output: &SnapshotOutput,
+    sequential: bool,
     snapshot_type: SnapshotType,
 ) -> Result<(), CreateSnapshotError> {
     use self::CreateSnapshotError::*;
 
     // Need to check this here, as we create the file in the line below
-    let file_existed = mem_file_path.exists();
+    let file_existed = match mem_file_output {
+        SnapshotOutput::Path(path) => path.exists(),
+        SnapshotOutput::File(_) => true,
+    };
 
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(mem_file_path)
-        .map_err(|err| MemoryBackingFile("open", err))?;
+    let mut file =
+        open_snapshot_output(mem_file_output).map_err(|err| MemoryBackingFile("open", err))?;
+
+    if sequential {
+        vmm.guest_memory().dump(&mut file).map_err(Memory)?;
+        vmm.reset_dirty_bitmap();
+        vmm.guest_memory().reset_dirty();
+        return file.flush().map_err(|err| MemoryBackingFile("flush", err));
+    }
 
     // Determine what size our total memory area is.
     let mem_size_mib = mem_size_mib(vmm.guest_memory());
@@ -260,7 +689,7 @@ fn snapshot_memory_to_file(
                 .map_err(Memory)
         }
         SnapshotType::Full => {
-            let dump_res = vmm.guest_memory().dump(&mut file).map_err(Memory);
+            let dump_res = vmm.guest_memory().dump_sparse(&mut file).map_err(Memory);
             if dump_res.is_ok() {
                 vmm.reset_dirty_bitmap();
                 vmm.guest_memory().reset_dirty();
@@ -275,6 +704,80 @@ fn snapshot_memory_to_file(
         .map_err(|err| MemoryBackingFile("sync_all", err))
 }
 
+/// Format of a guest memory file whose contents differ from the guest memory.
+#[derive(Debug, Clone, Copy)]
+enum MemoryFileEncoding<'a> {
+    Compressed,
+    Encrypted(&'a EncryptionKey),
+}
+
+/// Takes a full snapshot of the guest memory of the microVM running inside the given [`Vmm`] and
+/// saves it, compressed or encrypted, to `mem_file_output`.
+fn snapshot_memory_to_encoded_file(
+    vmm: &Vmm,
+    mem_file_output: &SnapshotOutput,
+    sequential: bool,
+    encoding: MemoryFileEncoding,
+) -> Result<(), CreateSnapshotError> {
+    use self::CreateSnapshotError::*;
+
+    let mut file =
+        open_snapshot_output(mem_file_output).map_err(|err| MemoryBackingFile("open", err))?;
+
+    if !sequential {
+        // Unlike full raw snapshots, the encoded contents differ from the guest memory,
+        // so they cannot be written over the file the microVM memory is mapped from.
+        let metadata = file
+            .metadata()
+            .map_err(|err| MemoryBackingFile("get_metadata", err))?;
+        for region in vmm.guest_memory().iter() {
+            if let Some(file_offset) = region.file_offset() {
+                let backing_metadata = file_offset
+                    .file()
+                    .metadata()
+                    .map_err(|err| MemoryBackingFile("get_metadata", err))?;
+                if backing_metadata.dev() == metadata.dev()
+                    && backing_metadata.ino() == metadata.ino()
+                {
+                    return Err(MemoryBackingFileInUse);
+                }
+            }
+        }
+
+        file.set_len(0)
+            .map_err(|err| MemoryBackingFile("truncate", err))?;
+        file.rewind()
+            .map_err(|err| MemoryBackingFile("seek", err))?;
+    }
+
+    let mut writer = BufWriter::new(file);
+    match encoding {
+        MemoryFileEncoding::Compressed => {
+            compress_memory(vmm.guest_memory(), &mut writer, DEFAULT_CHUNK_SIZE)
+                .map_err(CompressMemory)?
+        }
+        MemoryFileEncoding::Encrypted(key) => encrypt_memory(
+            vmm.guest_memory(),
+            &mut writer,
+            key,
+            DEFAULT_ENCRYPTED_CHUNK_SIZE,
+        )
+        .map_err(EncryptMemory)?,
+    }
+    let file = writer
+        .into_inner()
+        .map_err(|err| MemoryBackingFile("flush", err.into_error()))?;
+
+    vmm.reset_dirty_bitmap();
+    vmm.guest_memory().reset_dirty();
+
+    if sequential {
+        return Ok(());
+    }
+    file.sync_all()
+        .map_err(|err| MemoryBackingFile("sync_all", err))
+}
+
 /// Validates that snapshot CPU vendor matches the host CPU vendor.
 ///
 /// # Errors
@@ -373,14 +876,38 @@ pub fn snapshot_state_sanity_check(
 /// Error type for [`restore_from_snapshot`].
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
 pub enum RestoreFromSnapshotError {
+    /// Invalid snapshot encryption key: {0}
+    EncryptionKey(#[from] EncryptionKeyError),
+    /// Invalid snapshot integrity key: {0}
+    IntegrityKey(EncryptionKeyError),
+    /// Cannot load the snapshot integrity manifest: {0}
+    Manifest(IntegrityError),
+    /// Snapshot authentication failed: wrong encryption key or tampered snapshot files.
+    Authentication,
     /// Failed to get snapshot state from file: {0}
-    File(#[from] SnapshotStateFromFileError),
+    File(SnapshotStateFromFileError),
     /// Invalid snapshot state: {0}
     Invalid(#[from] SnapShotStateSanityCheckError),
     /// Failed to load guest memory: {0}
     GuestMemory(#[from] RestoreFromSnapshotGuestMemoryError),
     /// Failed to build microVM from snapshot: {0}
     Build(#[from] BuildMicrovmFromSnapshotError),
+    /// Drive override for unknown drive: {0}
+    UnknownDrive(String),
+    /// Drive override for {0} does not match the type or the encryption of the block device.
+    InvalidDriveOverride(String),
+    /// Network override for unknown network interface: {0}
+    UnknownNetworkInterface(String),
+    /// Vsock override for a snapshot without a vsock device.
+    MissingVsockDevice,
+    /// Cannot load vhost-user block devices using the Uffd memory backend.
+    VhostUserUffd,
+    /// Cannot generate a guest MAC address: {0}
+    GuestMac(aws_lc_rs::error::Unspecified),
+    /// Cannot assign a new guest MAC to network interface {0}: {1}
+    ReassignGuestMac(String, NetError),
+    /// Cannot invalidate the MMDS session tokens: {0}
+    MmdsTokens(MmdsDatastoreError),
 }
 /// Sub-Error type for [`restore_from_snapshot`] to contain either [`GuestMemoryFromFileError`] or
 /// [`GuestMemoryFromUffdError`] within [`RestoreFromSnapshotError`].
@@ -392,6 +919,29 @@ pub enum RestoreFromSnapshotGuestMemoryError {
     Uffd(#[from] GuestMemoryFromUffdError),
 }
 
+// Authentication failures are reported as such, whichever encrypted snapshot file failed it.
+impl From<SnapshotStateFromFileError> for RestoreFromSnapshotError {
+    fn from(err: SnapshotStateFromFileError) -> Self {
+        match err {
+            SnapshotStateFromFileError::Load(SnapshotError::Authentication) => {
+                RestoreFromSnapshotError::Authentication
+            }
+            err => RestoreFromSnapshotError::File(err),
+        }
+    }
+}
+
+impl From<GuestMemoryFromFileError> for RestoreFromSnapshotError {
+    fn from(err: GuestMemoryFromFileError) -> Self {
+        match err {
+            GuestMemoryFromFileError::Decrypt(EncryptionError::Authentication(_)) => {
+                RestoreFromSnapshotError::Authentication
+            }
+            err => RestoreFromSnapshotError::GuestMemory(err.into()),
+        }
+    }
+}
+
 /// Loads a Microvm snapshot producing a 'paused' Microvm.
 pub fn restore_from_snapshot(
     instance_info: &InstanceInfo,
@@ -400,32 +950,39 @@ pub fn restore_from_snapshot(
     params: &LoadSnapshotParams,
     vm_resources: &mut VmResources,
 ) -> Result<Arc<Mutex<Vmm>>, RestoreFromSnapshotError> {
-    let microvm_state = snapshot_state_from_file(&params.snapshot_path)?;
-    let track_dirty_pages = params.enable_diff_snapshots;
-
-    let vcpu_count = microvm_state
-        .vcpu_states
-        .len()
-        .try_into()
-        .map_err(|_| VmConfigError::InvalidVcpuCount)
-        .map_err(BuildMicrovmFromSnapshotError::VmUpdateConfig)?;
-
-    vm_resources
-        .update_vm_config(&MachineConfigUpdate {
-            vcpu_count: Some(vcpu_count),
-            mem_size_mib: Some(u64_to_usize(microvm_state.vm_info.mem_size_mib)),
-            smt: Some(microvm_state.vm_info.smt),
-            cpu_template: Some(microvm_state.vm_info.cpu_template),
-            track_dirty_pages: Some(track_dirty_pages),
-            huge_pages: Some(microvm_state.vm_info.huge_pages),
+    let encryption_key = params
+        .encryption
+        .as_ref()
+        .map(EncryptionKey::from_config)
+        .transpose()?;
+    let integrity = params
+        .integrity
+        .as_ref()
+        .map(|config| {
+            let key = integrity_key(config).map_err(RestoreFromSnapshotError::IntegrityKey)?;
+            let manifest = SnapshotManifest::load(&config.manifest_path)
+                .map_err(RestoreFromSnapshotError::Manifest)?;
+            Ok::<_, RestoreFromSnapshotError>((manifest, key))
         })
-        .map_err(BuildMicrovmFromSnapshotError::VmUpdateConfig)?;
+        .transpose()?;
+    let mut microvm_state = snapshot_state_from_file(
+        &params.snapshot_path,
+        encryption_key.as_ref(),
+        integrity.as_ref().map(|(manifest, key)| (manifest, key)),
+    )?;
+    let track_dirty_pages = params.enable_diff_snapshots;
 
-    // Some sanity checks before building the microvm.
-    snapshot_state_sanity_check(&microvm_state)?;
+    apply_device_overrides(&mut microvm_state.device_states, params)?;
+    update_vm_resources_from_state(vm_resources, &microvm_state, track_dirty_pages)?;
 
     let mem_backend_path = &params.mem_backend.backend_path;
     let mem_state = &microvm_state.memory_state;
+    // vhost-user backends access guest memory through a shared mapping of its backing file.
+    let vhost_user_device_used = microvm_state
+        .device_states
+        .block_devices
+        .iter()
+        .any(|block| matches!(block.device_state, BlockState::VhostUser(_)));
 
     let (guest_memory, uffd) = match params.mem_backend.backend_type {
         MemBackendType::File => (
@@ -434,10 +991,16 @@ pub fn restore_from_snapshot(
                 mem_state,
                 track_dirty_pages,
                 vm_resources.vm_config.huge_pages,
-            )
-            .map_err(RestoreFromSnapshotGuestMemoryError::File)?,
+                encryption_key.as_ref(),
+                integrity.as_ref().map(|(manifest, _)| manifest),
+                vhost_user_device_used,
+            )?,
             None,
         ),
+        MemBackendType::Uffd if vhost_user_device_used => {
+            return Err(RestoreFromSnapshotError::VhostUserUffd)
+        }
+        // The chunks of the memory file are verified by the page fault handler serving them.
         MemBackendType::Uffd => guest_memory_from_uffd(
             mem_backend_path,
             mem_state,
@@ -449,7 +1012,7 @@ pub fn restore_from_snapshot(
         )
         .map_err(RestoreFromSnapshotGuestMemoryError::Uffd)?,
     };
-    builder::build_microvm_from_snapshot(
+    let vmm = builder::build_microvm_from_snapshot(
         instance_info,
         event_manager,
         microvm_state,
@@ -458,7 +1021,178 @@ pub fn restore_from_snapshot(
         seccomp_filters,
         vm_resources,
     )
-    .map_err(RestoreFromSnapshotError::Build)
+    .map_err(RestoreFromSnapshotError::Build)?;
+
+    if params.clone {
+        regenerate_guest_identity(&vmm.lock().expect("Poisoned lock"), vm_resources)?;
+    }
+    Ok(vmm)
+}
+
+/// Gives a microVM loaded from a snapshot an identity of its own, so that it can run alongside
+/// other microVMs loaded from the same snapshot.
+///
+/// The VM generation ID is regenerated whenever a snapshot is loaded, which makes the guest kernel
+/// reseed its random number generator, so only the network interfaces and the MMDS are handled
+/// here.
+fn regenerate_guest_identity(
+    vmm: &Vmm,
+    vm_resources: &VmResources,
+) -> Result<(), RestoreFromSnapshotError> {
+    vmm.mmio_device_manager.for_each_virtio_device(
+        |virtio_type, id, _info, dev| -> Result<(), RestoreFromSnapshotError> {
+            if virtio_type != TYPE_NET {
+                return Ok(());
+            }
+            let mut virtio = dev.lock().expect("Poisoned lock");
+            let net = virtio.as_mut_any().downcast_mut::<Net>().unwrap();
+            // Interfaces without a configured MAC use the one their driver generated, which the
+            // device can not change.
+            if net.guest_mac().is_some() {
+                let mac = random_guest_mac()?;
+                info!("Assigning MAC {mac} to network interface {id}.");
+                net.reassign_guest_mac(mac)
+                    .map_err(|err| RestoreFromSnapshotError::ReassignGuestMac(id.clone(), err))?;
+            }
+            Ok(())
+        },
+    )?;
+
+    if let Some(mmds) = &vm_resources.mmds {
+        mmds.lock()
+            .expect("Poisoned lock")
+            .invalidate_tokens()
+            .map_err(RestoreFromSnapshotError::MmdsTokens)?;
+    }
+    Ok(())
+}
+
+/// Generates a random locally administered unicast MAC address.
+fn random_guest_mac() -> Result<MacAddr, RestoreFromSnapshotError> {
+    let mut bytes = [0u8; MAC_ADDR_LEN as usize];
+    rand::fill(&mut bytes).map_err(RestoreFromSnapshotError::GuestMac)?;
+    bytes[0] = (bytes[0] & 0xfe) | 0x02;
+    Ok(MacAddr::from_bytes_unchecked(&bytes))
+}
+
+/// Replaces the host resources recorded in `device_states` by the ones the load request
+/// overrides, so that devices are rebuilt on top of them.
+fn apply_device_overrides(
+    device_states: &mut DeviceStates,
+    params: &LoadSnapshotParams,
+) -> Result<(), RestoreFromSnapshotError> {
+    for drive_override in &params.drive_overrides {
+        apply_drive_override(device_states, drive_override)?;
+    }
+
+    for net_override in &params.network_overrides {
+        let net_state = device_states
+            .net_devices
+            .iter_mut()
+            .find(|net| net.device_state.id == net_override.iface_id)
+            .ok_or_else(|| {
+                RestoreFromSnapshotError::UnknownNetworkInterface(net_override.iface_id.clone())
+            })?;
+        net_state
+            .device_state
+            .tap_if_name
+            .clone_from(&net_override.host_dev_name);
+    }
+
+    if let Some(vsock_override) = &params.vsock_override {
+        let vsock_state = device_states
+            .vsock_device
+            .as_mut()
+            .ok_or(RestoreFromSnapshotError::MissingVsockDevice)?;
+        match &mut vsock_state.device_state.backend {
+            VsockBackendState::Uds(uds_state) => {
+                uds_state.path.clone_from(&vsock_override.uds_path)
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn apply_drive_override(
+    device_states: &mut DeviceStates,
+    drive_override: &DriveOverride,
+) -> Result<(), RestoreFromSnapshotError> {
+    let block_state = device_states
+        .block_devices
+        .iter_mut()
+        .map(|block| &mut block.device_state)
+        .find(|state| match state {
+            BlockState::Virtio(state) => state.id == drive_override.drive_id,
+            BlockState::VhostUser(state) => state.id == drive_override.drive_id,
+        })
+        .ok_or_else(|| RestoreFromSnapshotError::UnknownDrive(drive_override.drive_id.clone()))?;
+
+    match (block_state, drive_override) {
+        (
+            BlockState::Virtio(state),
+            DriveOverride {
+                path_on_host,
+                socket: None,
+                encryption,
+                ..
+            },
+        ) if (path_on_host.is_some() || encryption.is_some())
+            && (encryption.is_none() || state.encrypted) =>
+        {
+            if let Some(path_on_host) = path_on_host {
+                state.disk_path.clone_from(path_on_host);
+            }
+            state.encryption.clone_from(encryption);
+        }
+        (
+            BlockState::VhostUser(state),
+            DriveOverride {
+                path_on_host: None,
+                socket: Some(socket),
+                encryption: None,
+                ..
+            },
+        ) => state.socket_path.clone_from(socket),
+        _ => {
+            return Err(RestoreFromSnapshotError::InvalidDriveOverride(
+                drive_override.drive_id.clone(),
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// Updates the machine configuration in `vm_resources` to match the one the microVM described
+/// by `microvm_state` was running with, and sanity checks the state before building from it.
+pub(crate) fn update_vm_resources_from_state(
+    vm_resources: &mut VmResources,
+    microvm_state: &MicrovmState,
+    track_dirty_pages: bool,
+) -> Result<(), RestoreFromSnapshotError> {
+    let vcpu_count = microvm_state
+        .vcpu_states
+        .len()
+        .try_into()
+        .map_err(|_| VmConfigError::InvalidVcpuCount)
+        .map_err(BuildMicrovmFromSnapshotError::VmUpdateConfig)?;
+
+    vm_resources
+        .update_vm_config(&MachineConfigUpdate {
+            vcpu_count: Some(vcpu_count),
+            mem_size_mib: Some(u64_to_usize(microvm_state.vm_info.mem_size_mib)),
+            smt: Some(microvm_state.vm_info.smt),
+            cpu_template: Some(microvm_state.vm_info.cpu_template),
+            track_dirty_pages: Some(track_dirty_pages),
+            huge_pages: Some(microvm_state.vm_info.huge_pages),
+            dirty_tracking_backend: Some(microvm_state.vm_info.dirty_tracking_backend),
+        })
+        .map_err(BuildMicrovmFromSnapshotError::VmUpdateConfig)?;
+
+    // Some sanity checks before building the microvm.
+    snapshot_state_sanity_check(microvm_state)?;
+
+    Ok(())
 }
 
 /// Error type for [`snapshot_state_from_file`]
@@ -468,21 +1202,39 @@ pub enum SnapshotStateFromFileError {
     Open(std::io::Error),
     /// Failed to read snapshot file metadata: {0}
     Meta(std::io::Error),
+    /// Failed to read snapshot file: {0}
+    Read(std::io::Error),
+    /// Snapshot file failed integrity verification: {0}
+    Integrity(#[from] IntegrityError),
     /// Failed to load snapshot state from file: {0}
     Load(#[from] crate::snapshot::SnapshotError),
 }
 
 fn snapshot_state_from_file(
     snapshot_path: &Path,
+    encryption_key: Option<&EncryptionKey>,
+    integrity: Option<(&SnapshotManifest, &EncryptionKey)>,
 ) -> Result<MicrovmState, SnapshotStateFromFileError> {
     let snapshot = Snapshot::new(SNAPSHOT_VERSION);
     let mut snapshot_reader =
         File::open(snapshot_path).map_err(SnapshotStateFromFileError::Open)?;
     let metadata = std::fs::metadata(snapshot_path).map_err(SnapshotStateFromFileError::Meta)?;
-    let snapshot_len = u64_to_usize(metadata.len());
-    let state: MicrovmState = snapshot
-        .load_with_version_check(&mut snapshot_reader, snapshot_len)
-        .map_err(SnapshotStateFromFileError::Load)?;
+    // The file is read at once, so that the verified contents are the ones deserialized.
+    let mut contents = Vec::with_capacity(u64_to_usize(metadata.len()));
+    snapshot_reader
+        .read_to_end(&mut contents)
+        .map_err(SnapshotStateFromFileError::Read)?;
+    if let Some((manifest, key)) = integrity {
+        manifest.verify_mac(&contents, key)?;
+    }
+    let snapshot_len = contents.len();
+    let state: MicrovmState = match encryption_key {
+        Some(key) => {
+            snapshot.load_encrypted_with_version_check(&mut contents.as_slice(), snapshot_len, key)
+        }
+        None => snapshot.load_with_version_check(&mut contents.as_slice(), snapshot_len),
+    }
+    .map_err(SnapshotStateFromFileError::Load)?;
     Ok(state)
 }
 
@@ -493,6 +1245,16 @@ pub enum GuestMemoryFromFileError {
     File(#[from] std::io::Error),
     /// Failed to restore guest memory: {0}
     Restore(#[from] MemoryError),
+    /// Failed to decompress guest memory: {0}
+    Decompress(#[from] CompressionError),
+    /// Failed to decrypt guest memory: {0}
+    Decrypt(#[from] EncryptionError),
+    /// Memory file is encrypted, but no encryption key was provided.
+    MissingEncryptionKey,
+    /// Memory file is not encrypted, but an encryption key was provided.
+    NotEncrypted,
+    /// Memory file failed integrity verification: {0}
+    Integrity(#[from] IntegrityError),
 }
 
 fn guest_memory_from_file(
@@ -500,8 +1262,60 @@ fn guest_memory_from_file(
     mem_state: &GuestMemoryState,
     track_dirty_pages: bool,
     huge_pages: HugePageConfig,
+    encryption_key: Option<&EncryptionKey>,
+    manifest: Option<&SnapshotManifest>,
+    shared: bool,
 ) -> Result<GuestMemoryMmap, GuestMemoryFromFileError> {
-    let mem_file = File::open(mem_file_path)?;
+    let mut mem_file = File::open(mem_file_path)?;
+    // The whole file is verified before it is used, whatever its format.
+    if let Some(manifest) = manifest {
+        manifest.verify_memory_file(&mem_file)?;
+    }
+    let new_guest_memory = || {
+        if shared {
+            GuestMemoryMmap::memfd_backed_from_state(mem_state, track_dirty_pages, huge_pages)
+        } else {
+            GuestMemoryMmap::from_state(None, mem_state, track_dirty_pages, huge_pages)
+        }
+    };
+
+    // Like compressed ones, encrypted memory files are decrypted into anonymous memory.
+    match (
+        EncryptedMemoryFile::is_encrypted(&mem_file)?,
+        encryption_key,
+    ) {
+        (true, Some(key)) => {
+            let encrypted_file = EncryptedMemoryFile::open(mem_file, key)?;
+            let guest_mem = new_guest_memory()?;
+            encrypted_file.restore(&guest_mem, mem_state)?;
+            guest_mem.reset_dirty();
+            return Ok(guest_mem);
+        }
+        (true, None) => return Err(GuestMemoryFromFileError::MissingEncryptionKey),
+        (false, Some(_)) => return Err(GuestMemoryFromFileError::NotEncrypted),
+        (false, None) => (),
+    }
+
+    // Compressed memory files cannot be mapped, so their contents are decompressed into
+    // anonymous memory.
+    if CompressedMemoryFile::is_compressed(&mem_file)? {
+        let compressed_file = CompressedMemoryFile::open(mem_file)?;
+        let guest_mem = new_guest_memory()?;
+        compressed_file.restore(&guest_mem, mem_state)?;
+        // Populating guest memory is not a guest write.
+        guest_mem.reset_dirty();
+        return Ok(guest_mem);
+    }
+
+    // A private mapping of the memory file cannot be shared with vhost-user backends, so its
+    // contents are copied into a memfd instead.
+    if shared {
+        let guest_mem = new_guest_memory()?;
+        guest_mem.load(&mut mem_file, mem_state)?;
+        guest_mem.reset_dirty();
+        return Ok(guest_mem);
+    }
+
     let guest_mem =
         GuestMemoryMmap::from_state(Some(&mem_file), mem_state, track_dirty_pages, huge_pages)?;
     Ok(guest_mem)
@@ -626,8 +1440,11 @@ fn send_uffd_handshake(
 
 #[cfg(test)]
 mod tests {
+    use std::os::unix::io::OwnedFd;
     use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
 
+    use base64::Engine;
     use utils::tempfile::TempFile;
 
     use super::*;
@@ -639,12 +1456,18 @@ mod tests {
     };
     #[cfg(target_arch = "aarch64")]
     use crate::construct_kvm_mpidrs;
+    use crate::devices::virtio::block::virtio::device::DriveEncryptionConfig;
     use crate::devices::virtio::block::CacheType;
+    use crate::mmds::data_store::MmdsVersion;
     use crate::snapshot::Persist;
     use crate::vmm_config::balloon::BalloonDeviceConfig;
     use crate::vmm_config::net::NetworkInterfaceConfig;
+    use crate::vmm_config::snapshot::{
+        MemBackendConfig, NetworkOverride, SnapshotEncryptionConfig, SnapshotIntegrityConfig,
+        VsockOverride,
+    };
     use crate::vmm_config::vsock::tests::default_config;
-    use crate::vstate::memory::GuestMemoryRegionState;
+    use crate::vstate::memory::{Bitmap, Bytes, GuestAddress, GuestMemoryRegionState};
     use crate::Vmm;
 
     fn default_vmm_with_devices() -> Vmm {
@@ -678,6 +1501,8 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rate_limiter_group: None,
+            link_status: false,
         };
         insert_net_device(
             &mut vmm,
@@ -721,6 +1546,7 @@ mod tests {
             vcpu_states,
             vm_info: VmInfo {
                 mem_size_mib: 1u64,
+                dirty_tracking_backend: DirtyTrackingBackend::Ring,
                 ..Default::default()
             },
             #[cfg(target_arch = "aarch64")]
@@ -744,6 +1570,528 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_microvm_state_translators() {
+        let vmm = default_vmm_with_devices();
+        let vcpu_states = vec![VcpuState::default()];
+        #[cfg(target_arch = "aarch64")]
+        let mpidrs = construct_kvm_mpidrs(&vcpu_states);
+        #[cfg(target_arch = "aarch64")]
+        let vm_state = vmm.vm.save_state(&mpidrs).unwrap();
+        #[cfg(target_arch = "x86_64")]
+        let vm_state = vmm.vm.save_state().unwrap();
+        let vm_info = VmInfo {
+            mem_size_mib: 1u64,
+            ..Default::default()
+        };
+        let vm_info_v2 = VmInfoV2 {
+            mem_size_mib: 1u64,
+            ..Default::default()
+        };
+        let device_states = vmm.mmio_device_manager.save();
+        let device_states_v2 = DeviceStatesV2::from(device_states.clone());
+
+        let check_device_states = |microvm_state: &MicrovmState| {
+            assert_eq!(microvm_state.device_states, device_states);
+            assert!(microvm_state.device_states.rate_limiter_groups.is_empty());
+            match &microvm_state.device_states.block_devices[0].device_state {
+                BlockState::Virtio(state) => {
+                    assert!(state.overlay.is_none());
+                    assert!(state.rate_limiter_group.is_none());
+                    assert!(state.verity.is_none());
+                    assert!(state.nbd.is_none());
+                    assert!(!state.encrypted);
+                }
+                BlockState::VhostUser(_) => panic!("unexpected vhost-user block device"),
+            }
+            assert!(microvm_state.device_states.net_devices[0]
+                .device_state
+                .rate_limiter_group
+                .is_none());
+        };
+
+        // Format version 2.0.0 had the same layout, with the VM info of microVMs tracking dirty
+        // pages with bitmaps and the device states of the devices that could not share rate
+        // limiters nor use the newer block device settings.
+        let mut data = Vec::new();
+        Snapshot::new(Version::new(2, 0, 0))
+            .save(
+                &mut data,
+                &(
+                    &vm_info_v2,
+                    vmm.guest_memory().describe(),
+                    &vm_state,
+                    &vcpu_states,
+                    &device_states_v2,
+                    #[cfg(target_arch = "x86_64")]
+                    vmm.acpi_device_manager.save(),
+                ),
+            )
+            .unwrap();
+
+        // The old layout is rejected without translation.
+        Snapshot::new(SNAPSHOT_VERSION)
+            .load_with_version_check::<_, MicrovmState>(&mut data.as_slice(), data.len())
+            .unwrap_err();
+        let (microvm_state, version): (MicrovmState, _) = Snapshot::new(SNAPSHOT_VERSION)
+            .load_with_translation(
+                &mut data.as_slice(),
+                data.len(),
+                &microvm_state_translators(),
+            )
+            .unwrap();
+        assert_eq!(version, Version::new(2, 0, 0));
+        assert_eq!(microvm_state.vm_info, vm_info);
+        check_device_states(&microvm_state);
+
+        // Format version 1.0.0 had the layout of 2.0.0, without the ACPI devices state.
+        let mut data = Vec::new();
+        Snapshot::new(Version::new(1, 0, 0))
+            .save(
+                &mut data,
+                &(
+                    &vm_info_v2,
+                    vmm.guest_memory().describe(),
+                    &vm_state,
+                    &vcpu_states,
+                    &device_states_v2,
+                ),
+            )
+            .unwrap();
+
+        let (microvm_state, version): (MicrovmState, _) = Snapshot::new(SNAPSHOT_VERSION)
+            .load_with_translation(
+                &mut data.as_slice(),
+                data.len(),
+                &microvm_state_translators(),
+            )
+            .unwrap();
+        assert_eq!(version, Version::new(1, 0, 0));
+        assert_eq!(microvm_state.vm_info, vm_info);
+        check_device_states(&microvm_state);
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut acpi_dev_state = Vec::new();
+            Snapshot::serialize(&mut acpi_dev_state, &microvm_state.acpi_dev_state).unwrap();
+            let mut default_acpi_dev_state = Vec::new();
+            Snapshot::serialize(
+                &mut default_acpi_dev_state,
+                &ACPIDeviceManagerState::default(),
+            )
+            .unwrap();
+            assert_eq!(acpi_dev_state, default_acpi_dev_state);
+        }
+    }
+
+    #[test]
+    fn test_guest_memory_from_compressed_file() {
+        let guest_memory = GuestMemoryMmap::from_raw_regions(
+            &[(GuestAddress(0), 0x4000), (GuestAddress(0x10000), 0x2000)],
+            false,
+            HugePageConfig::None,
+        )
+        .unwrap();
+        guest_memory
+            .write_slice(&[0xAA; 0x1000], GuestAddress(0x1000))
+            .unwrap();
+        guest_memory
+            .write_slice(&[0x55; 0x1000], GuestAddress(0x11000))
+            .unwrap();
+        let mem_state = guest_memory.describe();
+
+        let mem_file = TempFile::new().unwrap();
+        compress_memory(
+            &guest_memory,
+            &mut mem_file.as_file().try_clone().unwrap(),
+            0x1000,
+        )
+        .unwrap();
+
+        let restored_memory = guest_memory_from_file(
+            mem_file.as_path(),
+            &mem_state,
+            true,
+            HugePageConfig::None,
+            None,
+            None,
+            false,
+        )
+        .unwrap();
+        let mut page = [0u8; 0x1000];
+        restored_memory
+            .read_slice(&mut page, GuestAddress(0x1000))
+            .unwrap();
+        assert_eq!(page, [0xAA; 0x1000]);
+        restored_memory
+            .read_slice(&mut page, GuestAddress(0x11000))
+            .unwrap();
+        assert_eq!(page, [0x55; 0x1000]);
+        restored_memory
+            .read_slice(&mut page, GuestAddress(0x10000))
+            .unwrap();
+        assert_eq!(page, [0; 0x1000]);
+        // Decompressing the file does not dirty guest memory.
+        assert!(!restored_memory.iter().any(|region| region
+            .bitmap()
+            .as_ref()
+            .unwrap()
+            .dirty_at(0x1000)));
+    }
+
+    #[test]
+    fn test_create_compressed_diff_snapshot() {
+        let mut vmm = default_vmm();
+        let params = CreateSnapshotParams {
+            snapshot_type: SnapshotType::Diff,
+            snapshot_output: SnapshotOutput::Path(PathBuf::new()),
+            mem_file_output: SnapshotOutput::Path(PathBuf::new()),
+            compression: MemoryCompression::Lz4,
+            encryption: None,
+            background: false,
+            integrity: None,
+        };
+        assert!(matches!(
+            create_snapshot(&mut vmm, &VmInfo::default(), &params),
+            Err(CreateSnapshotError::CompressedDiffSnapshot)
+        ));
+    }
+
+    #[test]
+    fn test_create_sequential_diff_snapshot() {
+        let mut vmm = default_vmm();
+        let (sender, _receiver) = UnixStream::pair().unwrap();
+        let params = CreateSnapshotParams {
+            snapshot_type: SnapshotType::Diff,
+            snapshot_output: SnapshotOutput::Path(PathBuf::new()),
+            mem_file_output: SnapshotOutput::File(File::from(OwnedFd::from(sender))),
+            compression: MemoryCompression::None,
+            encryption: None,
+            background: false,
+            integrity: None,
+        };
+        assert!(matches!(
+            create_snapshot(&mut vmm, &VmInfo::default(), &params),
+            Err(CreateSnapshotError::SequentialDiffSnapshot)
+        ));
+    }
+
+    #[test]
+    fn test_create_background_snapshot_params() {
+        let mut vmm = default_vmm();
+        let (sender, _receiver) = UnixStream::pair().unwrap();
+        let mut params = CreateSnapshotParams {
+            snapshot_type: SnapshotType::Diff,
+            snapshot_output: SnapshotOutput::Path(PathBuf::new()),
+            mem_file_output: SnapshotOutput::File(File::from(OwnedFd::from(sender))),
+            compression: MemoryCompression::None,
+            encryption: None,
+            background: true,
+            integrity: None,
+        };
+        assert!(matches!(
+            create_snapshot(&mut vmm, &VmInfo::default(), &params),
+            Err(CreateSnapshotError::BackgroundSnapshotFormat)
+        ));
+
+        params.snapshot_type = SnapshotType::Full;
+        let vm_info = VmInfo {
+            huge_pages: HugePageConfig::Hugetlbfs2M,
+            ..Default::default()
+        };
+        assert!(matches!(
+            create_snapshot(&mut vmm, &vm_info, &params),
+            Err(CreateSnapshotError::BackgroundUnsupportedMicrovm(
+                "huge pages"
+            ))
+        ));
+        assert!(matches!(
+            create_snapshot(&mut vmm, &VmInfo::default(), &params),
+            Err(CreateSnapshotError::BackgroundSequentialSnapshot)
+        ));
+
+        *vmm.snapshot_status.lock().unwrap() = SnapshotStatus::InProgress;
+        assert!(matches!(
+            create_snapshot(&mut vmm, &VmInfo::default(), &params),
+            Err(CreateSnapshotError::BackgroundSnapshotInProgress)
+        ));
+
+        *vmm.snapshot_status.lock().unwrap() = SnapshotStatus::None;
+        params.integrity = Some(SnapshotIntegrityConfig {
+            manifest_path: PathBuf::new(),
+            key: None,
+            key_fd: None,
+        });
+        assert!(matches!(
+            create_snapshot(&mut vmm, &VmInfo::default(), &params),
+            Err(CreateSnapshotError::ManifestBackgroundSnapshot)
+        ));
+    }
+
+    #[test]
+    fn test_create_snapshot_with_manifest_params() {
+        let mut vmm = default_vmm();
+        let (sender, _receiver) = UnixStream::pair().unwrap();
+        let mut params = CreateSnapshotParams {
+            snapshot_type: SnapshotType::Full,
+            snapshot_output: SnapshotOutput::Path(PathBuf::new()),
+            mem_file_output: SnapshotOutput::File(File::from(OwnedFd::from(sender))),
+            compression: MemoryCompression::None,
+            encryption: None,
+            background: false,
+            integrity: Some(SnapshotIntegrityConfig {
+                manifest_path: PathBuf::new(),
+                key: None,
+                key_fd: None,
+            }),
+        };
+        assert!(matches!(
+            create_snapshot(&mut vmm, &VmInfo::default(), &params),
+            Err(CreateSnapshotError::InvalidIntegrityKey(
+                EncryptionKeyError::InvalidConfig
+            ))
+        ));
+
+        // The manifest is computed by reading back the snapshot files.
+        params.integrity.as_mut().unwrap().key =
+            Some(base64::engine::general_purpose::STANDARD.encode([0x42; 32]));
+        assert!(matches!(
+            create_snapshot(&mut vmm, &VmInfo::default(), &params),
+            Err(CreateSnapshotError::ManifestSequentialSnapshot)
+        ));
+    }
+
+    #[test]
+    fn test_restore_with_manifest() {
+        let key = EncryptionKey::from_bytes(&[0x42; 32]).unwrap();
+        let guest_memory = GuestMemoryMmap::from_raw_regions(
+            &[(GuestAddress(0), 0x4000)],
+            false,
+            HugePageConfig::None,
+        )
+        .unwrap();
+        guest_memory
+            .write_slice(&[0xAA; 0x1000], GuestAddress(0x1000))
+            .unwrap();
+        let mem_state = guest_memory.describe();
+        let mem_file = TempFile::new().unwrap();
+        guest_memory
+            .dump(&mut mem_file.as_file().try_clone().unwrap())
+            .unwrap();
+        let snapshot_file = TempFile::new().unwrap();
+        std::fs::write(snapshot_file.as_path(), b"not a snapshot").unwrap();
+
+        let manifest =
+            SnapshotManifest::create(b"not a snapshot", mem_file.as_file(), 0x1000, &key).unwrap();
+
+        // The state file is authenticated before being deserialized.
+        assert!(matches!(
+            snapshot_state_from_file(snapshot_file.as_path(), None, Some((&manifest, &key))),
+            Err(SnapshotStateFromFileError::Load(_))
+        ));
+        std::fs::write(snapshot_file.as_path(), b"not a snapshoT").unwrap();
+        assert!(matches!(
+            snapshot_state_from_file(snapshot_file.as_path(), None, Some((&manifest, &key))),
+            Err(SnapshotStateFromFileError::Integrity(
+                IntegrityError::Authentication
+            ))
+        ));
+
+        let restored_memory = guest_memory_from_file(
+            mem_file.as_path(),
+            &mem_state,
+            false,
+            HugePageConfig::None,
+            None,
+            Some(&manifest),
+            false,
+        )
+        .unwrap();
+        let mut page = [0u8; 0x1000];
+        restored_memory
+            .read_slice(&mut page, GuestAddress(0x1000))
+            .unwrap();
+        assert_eq!(page, [0xAA; 0x1000]);
+
+        mem_file
+            .as_file()
+            .write_all_at(&[0x55; 0x10], 0x3000)
+            .unwrap();
+        assert!(matches!(
+            guest_memory_from_file(
+                mem_file.as_path(),
+                &mem_state,
+                false,
+                HugePageConfig::None,
+                None,
+                Some(&manifest),
+                false,
+            ),
+            Err(GuestMemoryFromFileError::Integrity(
+                IntegrityError::ChunkHash(3)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_is_sequential() {
+        let tmp_file = TempFile::new().unwrap();
+        assert!(!is_sequential(&SnapshotOutput::Path(tmp_file.as_path().to_path_buf())).unwrap());
+        assert!(!is_sequential(&SnapshotOutput::File(tmp_file.into_file())).unwrap());
+
+        let mut missing_file = TempFile::new().unwrap();
+        missing_file.remove().unwrap();
+        assert!(
+            !is_sequential(&SnapshotOutput::Path(missing_file.as_path().to_path_buf())).unwrap()
+        );
+
+        let (sender, _receiver) = UnixStream::pair().unwrap();
+        assert!(is_sequential(&SnapshotOutput::File(File::from(OwnedFd::from(sender)))).unwrap());
+    }
+
+    #[test]
+    fn test_guest_memory_from_encrypted_file() {
+        let guest_memory = GuestMemoryMmap::from_raw_regions(
+            &[(GuestAddress(0), 0x4000), (GuestAddress(0x10000), 0x2000)],
+            false,
+            HugePageConfig::None,
+        )
+        .unwrap();
+        guest_memory
+            .write_slice(&[0xAA; 0x1000], GuestAddress(0x11000))
+            .unwrap();
+        let mem_state = guest_memory.describe();
+        let key = EncryptionKey::from_bytes(&[0x42; 32]).unwrap();
+
+        let mem_file = TempFile::new().unwrap();
+        encrypt_memory(
+            &guest_memory,
+            &mut mem_file.as_file().try_clone().unwrap(),
+            &key,
+            0x1000,
+        )
+        .unwrap();
+
+        let restored_memory = guest_memory_from_file(
+            mem_file.as_path(),
+            &mem_state,
+            false,
+            HugePageConfig::None,
+            Some(&key),
+            None,
+            false,
+        )
+        .unwrap();
+        let mut page = [0u8; 0x1000];
+        restored_memory
+            .read_slice(&mut page, GuestAddress(0x11000))
+            .unwrap();
+        assert_eq!(page, [0xAA; 0x1000]);
+
+        assert!(matches!(
+            guest_memory_from_file(
+                mem_file.as_path(),
+                &mem_state,
+                false,
+                HugePageConfig::None,
+                None,
+                None,
+                false,
+            ),
+            Err(GuestMemoryFromFileError::MissingEncryptionKey)
+        ));
+        let wrong_key = EncryptionKey::from_bytes(&[0x43; 32]).unwrap();
+        assert!(matches!(
+            guest_memory_from_file(
+                mem_file.as_path(),
+                &mem_state,
+                false,
+                HugePageConfig::None,
+                Some(&wrong_key),
+                None,
+                false,
+            ),
+            Err(GuestMemoryFromFileError::Decrypt(
+                EncryptionError::Authentication(0)
+            ))
+        ));
+        // The restore path reports it as an authentication failure rather than a memory error.
+        let err = guest_memory_from_file(
+            mem_file.as_path(),
+            &mem_state,
+            false,
+            HugePageConfig::None,
+            Some(&wrong_key),
+            None,
+            false,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            RestoreFromSnapshotError::from(err),
+            RestoreFromSnapshotError::Authentication
+        ));
+        assert!(matches!(
+            RestoreFromSnapshotError::from(SnapshotStateFromFileError::Load(
+                SnapshotError::Authentication
+            )),
+            RestoreFromSnapshotError::Authentication
+        ));
+
+        // A plaintext memory file cannot be substituted for an encrypted one.
+        let plain_file = TempFile::new().unwrap();
+        guest_memory
+            .dump(&mut plain_file.as_file().try_clone().unwrap())
+            .unwrap();
+        assert!(matches!(
+            guest_memory_from_file(
+                plain_file.as_path(),
+                &mem_state,
+                false,
+                HugePageConfig::None,
+                Some(&key),
+                None,
+                false,
+            ),
+            Err(GuestMemoryFromFileError::NotEncrypted)
+        ));
+    }
+
+    #[test]
+    fn test_create_encrypted_snapshot_params() {
+        let mut vmm = default_vmm();
+        let encryption = SnapshotEncryptionConfig {
+            key: Some("invalid base64!".to_string()),
+            key_fd: None,
+        };
+        let mut params = CreateSnapshotParams {
+            snapshot_type: SnapshotType::Diff,
+            snapshot_output: SnapshotOutput::Path(PathBuf::new()),
+            mem_file_output: SnapshotOutput::Path(PathBuf::new()),
+            compression: MemoryCompression::None,
+            encryption: Some(encryption),
+            background: false,
+            integrity: None,
+        };
+        assert!(matches!(
+            create_snapshot(&mut vmm, &VmInfo::default(), &params),
+            Err(CreateSnapshotError::EncryptedDiffSnapshot)
+        ));
+
+        params.snapshot_type = SnapshotType::Full;
+        params.compression = MemoryCompression::Lz4;
+        assert!(matches!(
+            create_snapshot(&mut vmm, &VmInfo::default(), &params),
+            Err(CreateSnapshotError::CompressedEncryptedSnapshot)
+        ));
+
+        params.compression = MemoryCompression::None;
+        assert!(matches!(
+            create_snapshot(&mut vmm, &VmInfo::default(), &params),
+            Err(CreateSnapshotError::InvalidEncryptionKey(
+                EncryptionKeyError::Base64(_)
+            ))
+        ));
+    }
+
     #[test]
     fn test_create_guest_memory() {
         let mem_state = GuestMemoryState {
@@ -804,4 +2152,212 @@ mod tests {
 
         assert_eq!(uffd_regions, deserialized);
     }
+
+    fn load_snapshot_params() -> LoadSnapshotParams {
+        LoadSnapshotParams {
+            snapshot_path: PathBuf::new(),
+            mem_backend: MemBackendConfig {
+                backend_path: PathBuf::new(),
+                backend_type: MemBackendType::File,
+            },
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            encryption: None,
+            integrity: None,
+            drive_overrides: vec![],
+            network_overrides: vec![],
+            vsock_override: None,
+            clone: false,
+        }
+    }
+
+    #[test]
+    fn test_apply_drive_overrides() {
+        let vmm = default_vmm_with_devices();
+        let mut device_states = vmm.mmio_device_manager.save();
+        let mut params = load_snapshot_params();
+        params.drive_overrides = vec![DriveOverride {
+            drive_id: "root".to_string(),
+            path_on_host: Some("/dev/null".to_string()),
+            socket: None,
+            encryption: None,
+        }];
+
+        apply_device_overrides(&mut device_states, &params).unwrap();
+        match &device_states.block_devices[0].device_state {
+            BlockState::Virtio(state) => assert_eq!(state.disk_path, "/dev/null"),
+            BlockState::VhostUser(_) => panic!("Unexpected vhost-user block device"),
+        }
+
+        // A virtio block device can only have its backing file overridden.
+        params.drive_overrides[0].socket = Some("/tmp/vhost-user-blk.sock".to_string());
+        assert!(matches!(
+            apply_device_overrides(&mut device_states, &params),
+            Err(RestoreFromSnapshotError::InvalidDriveOverride(drive_id)) if drive_id == "root"
+        ));
+
+        // Only encrypted drives can have their key provided.
+        params.drive_overrides[0].socket = None;
+        params.drive_overrides[0].encryption = Some(DriveEncryptionConfig {
+            key: None,
+            key_fd: Some(0),
+        });
+        assert!(matches!(
+            apply_device_overrides(&mut device_states, &params),
+            Err(RestoreFromSnapshotError::InvalidDriveOverride(drive_id)) if drive_id == "root"
+        ));
+    }
+
+    #[test]
+    fn test_apply_network_overrides() {
+        let vmm = default_vmm_with_devices();
+        let mut device_states = vmm.mmio_device_manager.save();
+        let mut params = load_snapshot_params();
+        params.network_overrides = vec![NetworkOverride {
+            iface_id: "netif".to_string(),
+            host_dev_name: "tap1".to_string(),
+        }];
+
+        apply_device_overrides(&mut device_states, &params).unwrap();
+        assert_eq!(
+            device_states.net_devices[0].device_state.tap_if_name,
+            "tap1"
+        );
+        // Devices without an override keep their host resources.
+        assert!(matches!(
+            &device_states.block_devices[0].device_state,
+            BlockState::Virtio(state) if state.disk_path != "/dev/null"
+        ));
+
+        // The last override of an interface wins.
+        params.network_overrides.push(NetworkOverride {
+            iface_id: "netif".to_string(),
+            host_dev_name: "tap2".to_string(),
+        });
+        apply_device_overrides(&mut device_states, &params).unwrap();
+        assert_eq!(
+            device_states.net_devices[0].device_state.tap_if_name,
+            "tap2"
+        );
+    }
+
+    #[test]
+    fn test_apply_vsock_override() {
+        let vmm = default_vmm_with_devices();
+        let mut device_states = vmm.mmio_device_manager.save();
+        let mut params = load_snapshot_params();
+        params.vsock_override = Some(VsockOverride {
+            uds_path: "/tmp/vsock.sock".to_string(),
+        });
+
+        apply_device_overrides(&mut device_states, &params).unwrap();
+        let vsock_state = &device_states.vsock_device.as_ref().unwrap().device_state;
+        match &vsock_state.backend {
+            VsockBackendState::Uds(uds_state) => assert_eq!(uds_state.path, "/tmp/vsock.sock"),
+        }
+
+        // The snapshot must have a vsock device to override.
+        assert!(matches!(
+            apply_device_overrides(&mut DeviceStates::default(), &params),
+            Err(RestoreFromSnapshotError::MissingVsockDevice)
+        ));
+        params.vsock_override = None;
+        apply_device_overrides(&mut DeviceStates::default(), &params).unwrap();
+    }
+
+    #[test]
+    fn test_apply_unknown_device_overrides() {
+        let vmm = default_vmm_with_devices();
+        let mut device_states = vmm.mmio_device_manager.save();
+
+        // Overrides have to match a device of the snapshot.
+        let mut params = load_snapshot_params();
+        params.drive_overrides = vec![DriveOverride {
+            drive_id: "rootfs".to_string(),
+            path_on_host: Some("/dev/null".to_string()),
+            socket: None,
+            encryption: None,
+        }];
+        assert!(matches!(
+            apply_device_overrides(&mut device_states, &params),
+            Err(RestoreFromSnapshotError::UnknownDrive(drive_id)) if drive_id == "rootfs"
+        ));
+
+        let mut params = load_snapshot_params();
+        params.network_overrides = vec![NetworkOverride {
+            iface_id: "eth0".to_string(),
+            host_dev_name: "tap1".to_string(),
+        }];
+        assert!(matches!(
+            apply_device_overrides(&mut device_states, &params),
+            Err(RestoreFromSnapshotError::UnknownNetworkInterface(iface_id)) if iface_id == "eth0"
+        ));
+
+        // Device IDs are not shared between device types.
+        let mut params = load_snapshot_params();
+        params.drive_overrides = vec![DriveOverride {
+            drive_id: "netif".to_string(),
+            path_on_host: Some("/dev/null".to_string()),
+            socket: None,
+            encryption: None,
+        }];
+        assert!(matches!(
+            apply_device_overrides(&mut device_states, &params),
+            Err(RestoreFromSnapshotError::UnknownDrive(drive_id)) if drive_id == "netif"
+        ));
+    }
+
+    #[test]
+    fn test_regenerate_guest_identity() {
+        let mut event_manager = EventManager::new().expect("Cannot create EventManager");
+        let mut vmm = default_vmm();
+        let mut cmdline = default_kernel_cmdline();
+        let guest_mac = MacAddr::from_bytes_unchecked(&[0x06, 0, 0, 0, 0, 1]);
+        for (iface_id, guest_mac) in [("eth0", Some(guest_mac)), ("eth1", None)] {
+            let network_interface = NetworkInterfaceConfig {
+                iface_id: iface_id.to_string(),
+                host_dev_name: format!("{iface_id}_host"),
+                guest_mac,
+                rx_rate_limiter: None,
+                tx_rate_limiter: None,
+                rate_limiter_group: None,
+                link_status: false,
+            };
+            insert_net_device(
+                &mut vmm,
+                &mut cmdline,
+                &mut event_manager,
+                network_interface,
+            );
+        }
+
+        let mut vm_resources = VmResources::default();
+        let mut mmds = vm_resources.locked_mmds_or_default();
+        mmds.set_version(MmdsVersion::V2).unwrap();
+        let token = mmds.generate_token(60).unwrap();
+        drop(mmds);
+
+        regenerate_guest_identity(&vmm, &vm_resources).unwrap();
+
+        let guest_mac_of = |iface_id| {
+            let mut mac = None;
+            vmm.mmio_device_manager
+                .with_virtio_device_with_id(TYPE_NET, iface_id, |net: &mut Net| {
+                    mac = net.guest_mac().copied();
+                    Ok(())
+                })
+                .unwrap();
+            mac
+        };
+        // Interfaces with a configured MAC get a new locally administered unicast one.
+        let new_mac = guest_mac_of("eth0").unwrap();
+        assert_ne!(new_mac, guest_mac);
+        assert_eq!(new_mac.get_bytes()[0] & 0x03, 0x02);
+        assert_eq!(guest_mac_of("eth1"), None);
+        // Previously issued MMDS tokens are invalidated.
+        assert!(!vm_resources
+            .locked_mmds_or_default()
+            .is_valid_token(&token)
+            .unwrap());
+    }
 }
@@ -0,0 +1,289 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Token buckets shared by the rate limiters of several devices.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::{BucketReduction, BucketUpdate, TokenBucket, TokenType};
+
+#[derive(Debug, Default)]
+struct GroupBuckets {
+    bandwidth: Option<TokenBucket>,
+    ops: Option<TokenBucket>,
+    // Members which ran out of tokens, in the order they did so.
+    bytes_waiters: VecDeque<u64>,
+    ops_waiters: VecDeque<u64>,
+    next_member_id: u64,
+}
+
+impl GroupBuckets {
+    fn bucket_and_waiters(
+        &mut self,
+        token_type: TokenType,
+    ) -> (Option<&mut TokenBucket>, &mut VecDeque<u64>) {
+        match token_type {
+            TokenType::Bytes => (self.bandwidth.as_mut(), &mut self.bytes_waiters),
+            TokenType::Ops => (self.ops.as_mut(), &mut self.ops_waiters),
+        }
+    }
+}
+
+/// Bandwidth and ops/s budget shared by the rate limiters that joined the group.
+///
+/// On top of the budget of their own buckets, the members of a group consume tokens from the
+/// buckets of the group. When the group runs out of tokens, the members that could not consume
+/// are served first, in the order they ran out, so that busy members don't starve the others.
+///
+/// Cloning a group returns a handle to the same buckets.
+#[derive(Debug, Clone)]
+pub struct RateLimiterGroup {
+    id: String,
+    buckets: Arc<Mutex<GroupBuckets>>,
+}
+
+impl RateLimiterGroup {
+    /// Creates a group with the given buckets. A `None` bucket disables limiting for its token
+    /// type.
+    pub fn new(id: String, bandwidth: Option<TokenBucket>, ops: Option<TokenBucket>) -> Self {
+        RateLimiterGroup {
+            id,
+            buckets: Arc::new(Mutex::new(GroupBuckets {
+                bandwidth,
+                ops,
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// Returns the unique identifier of the group.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn lock(&self) -> MutexGuard<GroupBuckets> {
+        self.buckets.lock().expect("Poisoned lock")
+    }
+
+    /// Updates the parameters of the token buckets of the group, for all its members.
+    pub fn update_buckets(&self, bytes: BucketUpdate, ops: BucketUpdate) {
+        let mut buckets = self.lock();
+        match bytes {
+            BucketUpdate::Disabled => {
+                buckets.bandwidth = None;
+                buckets.bytes_waiters.clear();
+            }
+            BucketUpdate::Update(tb) => buckets.bandwidth = Some(tb),
+            BucketUpdate::None => (),
+        };
+        match ops {
+            BucketUpdate::Disabled => {
+                buckets.ops = None;
+                buckets.ops_waiters.clear();
+            }
+            BucketUpdate::Update(tb) => buckets.ops = Some(tb),
+            BucketUpdate::None => (),
+        };
+    }
+
+    /// Returns a copy of the bandwidth token bucket of the group.
+    pub fn bandwidth(&self) -> Option<TokenBucket> {
+        self.lock().bandwidth.clone()
+    }
+
+    /// Returns a copy of the ops token bucket of the group.
+    pub fn ops(&self) -> Option<TokenBucket> {
+        self.lock().ops.clone()
+    }
+
+    pub(super) fn join(&self) -> GroupMember {
+        let mut buckets = self.lock();
+        let id = buckets.next_member_id;
+        buckets.next_member_id += 1;
+
+        GroupMember {
+            group: self.clone(),
+            id,
+        }
+    }
+}
+
+/// Membership of a rate limiter in a group.
+#[derive(Debug)]
+pub(super) struct GroupMember {
+    group: RateLimiterGroup,
+    id: u64,
+}
+
+impl GroupMember {
+    pub(super) fn group(&self) -> &RateLimiterGroup {
+        &self.group
+    }
+
+    /// Attempts to consume `tokens` from the bucket of the group. Returns the outcome along with
+    /// the refill time of the bucket, or `None` if the group does not limit `token_type`.
+    pub(super) fn reduce(
+        &self,
+        tokens: u64,
+        token_type: TokenType,
+    ) -> Option<(BucketReduction, u64)> {
+        let mut buckets = self.group.lock();
+        let (bucket, waiters) = buckets.bucket_and_waiters(token_type);
+        let bucket = bucket?;
+
+        let is_waiting = waiters.contains(&self.id);
+        // Members which ran out of tokens before this one are served first.
+        if waiters.front().is_some_and(|&front| front != self.id) {
+            if !is_waiting {
+                waiters.push_back(self.id);
+            }
+            return Some((BucketReduction::Failure, bucket.refill_time_ms()));
+        }
+
+        let reduction = bucket.reduce(tokens);
+        match reduction {
+            BucketReduction::Failure if !is_waiting => waiters.push_back(self.id),
+            BucketReduction::Failure => (),
+            BucketReduction::Success | BucketReduction::OverConsumption(_) if is_waiting => {
+                waiters.pop_front();
+            }
+            BucketReduction::Success | BucketReduction::OverConsumption(_) => (),
+        }
+
+        Some((reduction, bucket.refill_time_ms()))
+    }
+
+    /// Adds `tokens` back to the bucket of the group.
+    pub(super) fn replenish(&self, tokens: u64, token_type: TokenType) {
+        let mut buckets = self.group.lock();
+        if let (Some(bucket), _) = buckets.bucket_and_waiters(token_type) {
+            bucket.force_replenish(tokens);
+        }
+    }
+}
+
+impl Drop for GroupMember {
+    fn drop(&mut self) {
+        // Don't leave the other members waiting for this one.
+        let mut buckets = self.group.lock();
+        buckets.bytes_waiters.retain(|&id| id != self.id);
+        buckets.ops_waiters.retain(|&id| id != self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rate_limiter::RateLimiter;
+
+    #[test]
+    fn test_group_shared_budget() {
+        let group =
+            RateLimiterGroup::new("group".to_string(), TokenBucket::new(1000, 0, 1000), None);
+        assert_eq!(group.id(), "group");
+
+        let mut first = RateLimiter::default();
+        let mut second = RateLimiter::default();
+        first.join_group(&group);
+        second.join_group(&group);
+        assert_eq!(first.group().unwrap().id(), "group");
+
+        // The members consume from the same budget.
+        assert!(first.consume(600, TokenType::Bytes));
+        assert!(!second.consume(600, TokenType::Bytes));
+        assert!(second.is_blocked());
+        assert_eq!(group.bandwidth().unwrap().budget(), 400);
+
+        // The group does not limit ops.
+        assert!(first.consume(u64::MAX, TokenType::Ops));
+
+        // Tokens given back are available to the other members.
+        first.manual_replenish(600, TokenType::Bytes);
+        assert_eq!(group.bandwidth().unwrap().budget(), 1000);
+    }
+
+    #[test]
+    fn test_group_refusal_after_own_over_consumption() {
+        let group =
+            RateLimiterGroup::new("group".to_string(), TokenBucket::new(1000, 0, 1000), None);
+        let other = group.join();
+        assert!(matches!(
+            other.reduce(1000, TokenType::Bytes),
+            Some((BucketReduction::Success, _))
+        ));
+
+        // The request is larger than the own bucket, which over-consumes, and its one time
+        // burst, but the group has no tokens left.
+        let mut limiter = RateLimiter::new(100, 50, 1000, 0, 0, 0).unwrap();
+        limiter.join_group(&group);
+        let own_bucket = limiter.bandwidth().cloned().unwrap();
+        assert!(!limiter.consume(500, TokenType::Bytes));
+        assert!(limiter.is_blocked());
+
+        // The own bucket is left exactly as it was, budget and one time burst included.
+        assert_eq!(limiter.bandwidth(), Some(&own_bucket));
+        assert_eq!(own_bucket.budget(), 100);
+        assert_eq!(own_bucket.one_time_burst(), 50);
+        assert_eq!(group.bandwidth().unwrap().budget(), 0);
+    }
+
+    #[test]
+    fn test_group_fair_sharing() {
+        let group = RateLimiterGroup::new("group".to_string(), None, TokenBucket::new(1, 0, 1000));
+        let first = group.join();
+        let second = group.join();
+
+        assert!(matches!(
+            first.reduce(1, TokenType::Ops),
+            Some((BucketReduction::Success, 1000))
+        ));
+        assert!(matches!(
+            second.reduce(1, TokenType::Ops),
+            Some((BucketReduction::Failure, _))
+        ));
+
+        // Once tokens are available again, the member which ran out first gets them, even if the
+        // other one asks first.
+        group.lock().ops.as_mut().unwrap().force_replenish(1);
+        assert!(matches!(
+            first.reduce(1, TokenType::Ops),
+            Some((BucketReduction::Failure, _))
+        ));
+        assert!(matches!(
+            second.reduce(1, TokenType::Ops),
+            Some((BucketReduction::Success, _))
+        ));
+        assert_eq!(group.lock().ops_waiters, VecDeque::from([first.id]));
+
+        // Members leaving the group don't keep the others waiting.
+        let first_id = first.id;
+        drop(first);
+        assert!(!group.lock().ops_waiters.contains(&first_id));
+        assert!(second.reduce(1, TokenType::Bytes).is_none());
+    }
+
+    #[test]
+    fn test_group_update_buckets() {
+        let group = RateLimiterGroup::new(
+            "group".to_string(),
+            TokenBucket::new(1000, 0, 1000),
+            TokenBucket::new(10, 0, 1000),
+        );
+        let member = group.join();
+        member.reduce(1000, TokenType::Bytes);
+        assert_eq!(group.bandwidth().unwrap().budget(), 0);
+
+        group.update_buckets(
+            BucketUpdate::Update(TokenBucket::new(2000, 0, 1000).unwrap()),
+            BucketUpdate::Disabled,
+        );
+        assert_eq!(group.bandwidth().unwrap().capacity(), 2000);
+        assert!(group.ops().is_none());
+        assert!(matches!(
+            member.reduce(1500, TokenType::Bytes),
+            Some((BucketReduction::Success, _))
+        ));
+        assert!(member.reduce(1, TokenType::Ops).is_none());
+    }
+}
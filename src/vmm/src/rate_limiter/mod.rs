@@ -9,8 +9,11 @@ use std::{fmt, io};
 
 use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
 
+pub mod group;
 pub mod persist;
 
+use group::{GroupMember, RateLimiterGroup};
+
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
 /// Describes the errors that may occur while handling rate limiter events.
 pub enum RateLimiterError {
@@ -265,7 +268,7 @@ impl TokenBucket {
 }
 
 /// Enum that describes the type of token used.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum TokenType {
     /// Token type used for bandwidth limiting.
     Bytes,
@@ -299,9 +302,13 @@ pub enum BucketUpdate {
 /// RateLimiters will generate events on the FDs provided by their `AsRawFd` trait
 /// implementation. These events are meant to be consumed by the user of this struct.
 /// On each such event, the user must call the `event_handler()` method.
+///
+/// A RateLimiter can also join a `RateLimiterGroup`, in which case `consume()` operations
+/// additionally draw from the token buckets shared by all the members of the group.
 pub struct RateLimiter {
     bandwidth: Option<TokenBucket>,
     ops: Option<TokenBucket>,
+    group: Option<GroupMember>,
 
     timer_fd: TimerFd,
     // Internal flag that quickly determines timer state.
@@ -374,6 +381,7 @@ impl RateLimiter {
         Ok(RateLimiter {
             bandwidth: bytes_token_bucket,
             ops: ops_token_bucket,
+            group: None,
             timer_fd,
             timer_active: false,
         })
@@ -400,42 +408,87 @@ impl RateLimiter {
             TokenType::Bytes => self.bandwidth.as_mut(),
             TokenType::Ops => self.ops.as_mut(),
         };
+        // When a group is involved, keep the state of our own bucket, so that it can be restored
+        // exactly if the group then refuses the tokens.
+        let own_bucket_state = match (&token_bucket, &self.group) {
+            (Some(bucket), Some(_)) => Some((**bucket).clone()),
+            _ => None,
+        };
         // Try to consume from the token bucket.
-        if let Some(bucket) = token_bucket {
-            let refill_time = bucket.refill_time_ms();
-            match bucket.reduce(tokens) {
-                // When we report budget is over, there will be no further calls here,
-                // register a timer to replenish the bucket and resume processing;
-                // make sure there is only one running timer for this limiter.
-                BucketReduction::Failure => {
-                    if !self.timer_active {
-                        self.activate_timer(TIMER_REFILL_STATE);
-                    }
-                    false
-                }
-                // The operation succeeded and further calls can be made.
-                BucketReduction::Success => true,
-                // The operation succeeded as the tokens have been consumed
-                // but the timer still needs to be armed.
-                BucketReduction::OverConsumption(ratio) => {
-                    // The operation "borrowed" a number of tokens `ratio` times
-                    // greater than the size of the bucket, and since it takes
-                    // `refill_time` milliseconds to fill an empty bucket, in
-                    // order to enforce the bandwidth limit we need to prevent
-                    // further calls to the rate limiter for
-                    // `ratio * refill_time` milliseconds.
-                    // The conversion should be safe because the ratio is positive.
-                    #[allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
-                    self.activate_timer(TimerState::Oneshot(Duration::from_millis(
-                        (ratio * refill_time as f64) as u64,
-                    )));
-                    true
+        // If bucket is not present rate limiting is disabled on token type.
+        let own_reduction =
+            token_bucket.map(|bucket| (bucket.reduce(tokens), bucket.refill_time_ms()));
+        if let Some((BucketReduction::Failure, refill_time)) = own_reduction {
+            return self.handle_reduction(BucketReduction::Failure, refill_time);
+        }
+
+        // Then try to consume from the bucket shared with the other members of the group.
+        let group_reduction = self
+            .group
+            .as_ref()
+            .and_then(|member| member.reduce(tokens, token_type));
+        if let Some((BucketReduction::Failure, refill_time)) = group_reduction {
+            // Undo the consumption from our own bucket, the operation will be retried. Giving
+            // the tokens back would not undo an over-consumption, which empties the bucket.
+            if let (Some(bucket), Some(state)) = (self.own_bucket_mut(token_type), own_bucket_state)
+            {
+                *bucket = state;
+            }
+            return self.handle_reduction(BucketReduction::Failure, refill_time);
+        }
+
+        // The tokens have been consumed, but the timer might still need to be armed.
+        for (reduction, refill_time) in [own_reduction, group_reduction].into_iter().flatten() {
+            self.handle_reduction(reduction, refill_time);
+        }
+        true
+    }
+
+    // Handles the outcome of a reduction of a token bucket with the given refill time and
+    // returns whether the tokens have been consumed.
+    fn handle_reduction(&mut self, reduction: BucketReduction, refill_time: u64) -> bool {
+        match reduction {
+            // When we report budget is over, there will be no further calls here,
+            // register a timer to replenish the bucket and resume processing;
+            // make sure there is only one running timer for this limiter.
+            BucketReduction::Failure => {
+                if !self.timer_active {
+                    self.activate_timer(TIMER_REFILL_STATE);
                 }
+                false
             }
-        } else {
-            // If bucket is not present rate limiting is disabled on token type,
-            // consume() will always succeed.
-            true
+            // The operation succeeded and further calls can be made.
+            BucketReduction::Success => true,
+            // The operation succeeded as the tokens have been consumed
+            // but the timer still needs to be armed.
+            BucketReduction::OverConsumption(ratio) => {
+                // The operation "borrowed" a number of tokens `ratio` times
+                // greater than the size of the bucket, and since it takes
+                // `refill_time` milliseconds to fill an empty bucket, in
+                // order to enforce the bandwidth limit we need to prevent
+                // further calls to the rate limiter for
+                // `ratio * refill_time` milliseconds.
+                // The conversion should be safe because the ratio is positive.
+                #[allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
+                self.activate_timer(TimerState::Oneshot(Duration::from_millis(
+                    (ratio * refill_time as f64) as u64,
+                )));
+                true
+            }
+        }
+    }
+
+    fn own_bucket_mut(&mut self, token_type: TokenType) -> Option<&mut TokenBucket> {
+        match token_type {
+            TokenType::Bytes => self.bandwidth.as_mut(),
+            TokenType::Ops => self.ops.as_mut(),
+        }
+    }
+
+    fn replenish_own(&mut self, tokens: u64, token_type: TokenType) {
+        // Add tokens to the token bucket.
+        if let Some(bucket) = self.own_bucket_mut(token_type) {
+            bucket.force_replenish(tokens);
         }
     }
 
@@ -444,14 +497,9 @@ impl RateLimiter {
     /// Can be used to *manually* add tokens to a bucket. Useful for reverting a
     /// `consume()` if needed.
     pub fn manual_replenish(&mut self, tokens: u64, token_type: TokenType) {
-        // Identify the required token bucket.
-        let token_bucket = match token_type {
-            TokenType::Bytes => self.bandwidth.as_mut(),
-            TokenType::Ops => self.ops.as_mut(),
-        };
-        // Add tokens to the token bucket.
-        if let Some(bucket) = token_bucket {
-            bucket.force_replenish(tokens);
+        self.replenish_own(tokens, token_type);
+        if let Some(member) = self.group.as_ref() {
+            member.replenish(tokens, token_type);
         }
     }
 
@@ -506,6 +554,16 @@ impl RateLimiter {
     pub fn ops(&self) -> Option<&TokenBucket> {
         self.ops.as_ref()
     }
+
+    /// Makes this RateLimiter a member of `group`, leaving the group it was a member of, if any.
+    pub fn join_group(&mut self, group: &RateLimiterGroup) {
+        self.group = Some(group.join());
+    }
+
+    /// Returns the group this RateLimiter is a member of, if any.
+    pub fn group(&self) -> Option<&RateLimiterGroup> {
+        self.group.as_ref().map(GroupMember::group)
+    }
 }
 
 impl AsRawFd for RateLimiter {
@@ -82,6 +82,7 @@ impl Persist<'_> for RateLimiter {
             } else {
                 None
             },
+            group: None,
             timer_fd: TimerFd::new_custom(ClockId::Monotonic, true, true)?,
             timer_active: false,
         };
@@ -90,6 +91,42 @@ impl Persist<'_> for RateLimiter {
     }
 }
 
+/// State for saving a RateLimiterGroup.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimiterGroupState {
+    /// Unique identifier of the group.
+    pub id: String,
+    ops: Option<TokenBucketState>,
+    bandwidth: Option<TokenBucketState>,
+}
+
+impl Persist<'_> for RateLimiterGroup {
+    type State = RateLimiterGroupState;
+    type ConstructorArgs = ();
+    type Error = io::Error;
+
+    fn save(&self) -> Self::State {
+        RateLimiterGroupState {
+            id: self.id().to_string(),
+            ops: self.ops().map(|ops| ops.save()),
+            bandwidth: self.bandwidth().map(|bw| bw.save()),
+        }
+    }
+
+    fn restore(_: Self::ConstructorArgs, state: &Self::State) -> Result<Self, Self::Error> {
+        let ops = match state.ops.as_ref() {
+            Some(ops) => Some(TokenBucket::restore((), ops)?),
+            None => None,
+        };
+        let bandwidth = match state.bandwidth.as_ref() {
+            Some(bw) => Some(TokenBucket::restore((), bw)?),
+            None => None,
+        };
+
+        Ok(RateLimiterGroup::new(state.id.clone(), bandwidth, ops))
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -193,4 +230,31 @@ mod tests {
             .unwrap()
             .partial_eq(restored_rate_limiter.bandwidth().unwrap()));
     }
+
+    #[test]
+    fn test_rate_limiter_group_persistence() {
+        let group =
+            RateLimiterGroup::new("group".to_string(), TokenBucket::new(1000, 0, 1000), None);
+        let mut rate_limiter = RateLimiter::default();
+        rate_limiter.join_group(&group);
+        rate_limiter.consume(100, TokenType::Bytes);
+
+        // Test serialization.
+        let mut mem = vec![0; 4096];
+        Snapshot::serialize(&mut mem.as_mut_slice(), &group.save()).unwrap();
+        let restored_group =
+            RateLimiterGroup::restore((), &Snapshot::deserialize(&mut mem.as_slice()).unwrap())
+                .unwrap();
+
+        assert_eq!(restored_group.id(), "group");
+        assert!(group
+            .bandwidth()
+            .unwrap()
+            .partial_eq(&restored_group.bandwidth().unwrap()));
+        assert!(restored_group.ops().is_none());
+
+        // Restored rate limiters are not part of any group until they join one.
+        let restored_rate_limiter = RateLimiter::restore((), &rate_limiter.save()).unwrap();
+        assert!(restored_rate_limiter.group().is_none());
+    }
 }
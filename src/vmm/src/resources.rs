@@ -14,6 +14,7 @@ use crate::logger::{info, log_dev_preview_warning};
 use crate::mmds;
 use crate::mmds::data_store::{Mmds, MmdsVersion};
 use crate::mmds::ns::MmdsNetworkStack;
+use crate::rate_limiter::group::RateLimiterGroup;
 use crate::vmm_config::balloon::*;
 use crate::vmm_config::boot_source::{
     BootConfig, BootSource, BootSourceConfig, BootSourceConfigError,
@@ -27,6 +28,7 @@ use crate::vmm_config::machine_config::{
 use crate::vmm_config::metrics::{init_metrics, MetricsConfig, MetricsConfigError};
 use crate::vmm_config::mmds::{MmdsConfig, MmdsConfigError};
 use crate::vmm_config::net::*;
+use crate::vmm_config::rate_limiter_group::*;
 use crate::vmm_config::vsock::*;
 
 /// Errors encountered when configuring microVM resources.
@@ -52,6 +54,8 @@ pub enum ResourcesError {
     MmdsConfig(#[from] MmdsConfigError),
     /// Network device error: {0}
     NetDevice(#[from] NetworkInterfaceError),
+    /// Rate limiter group error: {0}
+    RateLimiterGroup(#[from] RateLimiterGroupError),
     /// VM config error: {0}
     VmConfig(#[from] VmConfigError),
     /// Vsock device error: {0}
@@ -81,6 +85,8 @@ pub struct VmmConfig {
     mmds_config: Option<MmdsConfig>,
     #[serde(rename = "network-interfaces", default)]
     net_devices: Vec<NetworkInterfaceConfig>,
+    #[serde(rename = "rate-limiter-groups", default)]
+    rate_limiter_groups: Vec<RateLimiterGroupConfig>,
     #[serde(rename = "vsock")]
     vsock_device: Option<VsockDeviceConfig>,
     #[serde(rename = "entropy")]
@@ -105,6 +111,8 @@ pub struct VmResources {
     pub net_builder: NetBuilder,
     /// The entropy device builder.
     pub entropy: EntropyDeviceBuilder,
+    /// The rate limiter groups shared by devices.
+    pub rate_limiter_groups: RateLimiterGroupBuilder,
     /// The optional Mmds data store.
     // This is initialised on demand (if ever used), so that we don't allocate it unless it's
     // actually used.
@@ -151,6 +159,11 @@ impl VmResources {
 
         resources.build_boot_source(vmm_config.boot_source)?;
 
+        // The groups must exist before the devices joining them are configured.
+        for group_config in vmm_config.rate_limiter_groups.into_iter() {
+            resources.set_rate_limiter_group(group_config);
+        }
+
         for drive_config in vmm_config.block_devices.into_iter() {
             resources.set_block_device(drive_config)?;
         }
@@ -208,7 +221,14 @@ impl VmResources {
     ) -> Result<(), ResourcesError> {
         match device {
             SharedDeviceType::VirtioBlock(block) => {
+                let fixed_buffers = block.lock().expect("Poisoned lock").fixed_buffers();
                 self.block.add_virtio_device(block);
+
+                if fixed_buffers && self.balloon.get().is_some() {
+                    return Err(ResourcesError::BlockDevice(
+                        DriveError::FixedBuffersAndBalloon,
+                    ));
+                }
             }
 
             SharedDeviceType::Network(network) => {
@@ -221,6 +241,11 @@ impl VmResources {
                 if self.vm_config.huge_pages != HugePageConfig::None {
                     return Err(ResourcesError::BalloonDevice(BalloonConfigError::HugePages));
                 }
+                if self.block.has_fixed_buffers() {
+                    return Err(ResourcesError::BalloonDevice(
+                        BalloonConfigError::FixedBuffers,
+                    ));
+                }
             }
 
             SharedDeviceType::Vsock(vsock) => {
@@ -344,6 +369,11 @@ impl VmResources {
             return Err(BalloonConfigError::HugePages);
         }
 
+        // Pages released by the balloon would stay pinned by io_uring.
+        if self.block.has_fixed_buffers() {
+            return Err(BalloonConfigError::FixedBuffers);
+        }
+
         self.balloon.set(config)
     }
 
@@ -375,7 +405,28 @@ impl VmResources {
         &mut self,
         block_device_config: BlockDeviceConfig,
     ) -> Result<(), DriveError> {
-        self.block.insert(block_device_config)
+        let group = block_device_config
+            .rate_limiter_group
+            .as_deref()
+            .map(|group_id| self.rate_limiter_group(group_id))
+            .transpose()
+            .map_err(DriveError::RateLimiterGroup)?;
+        if block_device_config
+            .async_io
+            .is_some_and(|async_io| async_io.fixed_buffers)
+            && self.balloon.get().is_some()
+        {
+            return Err(DriveError::FixedBuffersAndBalloon);
+        }
+        let drive_id = block_device_config.drive_id.clone();
+        self.block.insert(block_device_config)?;
+
+        if let Some(group) = group {
+            self.block
+                .join_rate_limiter_group(&drive_id, &group)
+                .map_err(DriveError::CreateBlockDevice)?;
+        }
+        Ok(())
     }
 
     /// Builds a network device to be attached when the VM starts.
@@ -383,10 +434,44 @@ impl VmResources {
         &mut self,
         body: NetworkInterfaceConfig,
     ) -> Result<(), NetworkInterfaceError> {
-        let _ = self.net_builder.build(body)?;
+        let group = body
+            .rate_limiter_group
+            .as_deref()
+            .map(|group_id| self.rate_limiter_group(group_id))
+            .transpose()?;
+        let net = self.net_builder.build(body)?;
+
+        if let Some(group) = group {
+            net.lock()
+                .expect("Poisoned lock")
+                .join_rate_limiter_group(&group);
+        }
         Ok(())
     }
 
+    /// Inserts a rate limiter group, or replaces the buckets of an existing one.
+    pub fn set_rate_limiter_group(&mut self, config: RateLimiterGroupConfig) {
+        self.rate_limiter_groups.insert(config)
+    }
+
+    /// Updates the buckets of an existing rate limiter group.
+    pub fn update_rate_limiter_group(
+        &mut self,
+        config: RateLimiterGroupConfig,
+    ) -> Result<(), RateLimiterGroupError> {
+        self.rate_limiter_groups.update(config)
+    }
+
+    fn rate_limiter_group(
+        &self,
+        group_id: &str,
+    ) -> Result<RateLimiterGroup, RateLimiterGroupError> {
+        self.rate_limiter_groups
+            .get(group_id)
+            .cloned()
+            .ok_or_else(|| RateLimiterGroupError::GroupNotFound(group_id.to_string()))
+    }
+
     /// Sets a vsock device to be attached when the VM starts.
     pub fn set_vsock_device(&mut self, config: VsockDeviceConfig) -> Result<(), VsockConfigError> {
         self.vsock.insert(config)
@@ -487,6 +572,7 @@ impl From<&VmResources> for VmmConfig {
             metrics: None,
             mmds_config: resources.mmds_config(),
             net_devices: resources.net_builder.configs(),
+            rate_limiter_groups: resources.rate_limiter_groups.configs(),
             vsock_device: resources.vsock.config(),
             entropy_device: resources.entropy.config(),
         }
@@ -508,6 +594,8 @@ mod tests {
     use super::*;
     use crate::cpu_config::templates::{CpuTemplateType, StaticCpuTemplate};
     use crate::devices::virtio::balloon::Balloon;
+    use crate::devices::virtio::block::virtio::device::{AsyncIoConfig, FileEngineType};
+    use crate::devices::virtio::block::virtio::test_utils::default_engine_type_for_kv;
     use crate::devices::virtio::block::virtio::VirtioBlockError;
     use crate::devices::virtio::block::{BlockError, CacheType};
     use crate::devices::virtio::vsock::VSOCK_DEV_ID;
@@ -516,7 +604,9 @@ mod tests {
         BootConfig, BootSource, BootSourceConfig, DEFAULT_KERNEL_CMDLINE,
     };
     use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig};
-    use crate::vmm_config::machine_config::{HugePageConfig, MachineConfig, VmConfigError};
+    use crate::vmm_config::machine_config::{
+        DirtyTrackingBackend, HugePageConfig, MachineConfig, VmConfigError,
+    };
     use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig};
     use crate::vmm_config::vsock::tests::default_config;
     use crate::vmm_config::RateLimiterConfig;
@@ -536,6 +626,8 @@ mod tests {
             guest_mac: Some(MacAddr::from_str("01:23:45:67:89:0a").unwrap()),
             rx_rate_limiter: Some(RateLimiterConfig::default()),
             tx_rate_limiter: Some(RateLimiterConfig::default()),
+            rate_limiter_group: None,
+            link_status: false,
         }
     }
 
@@ -559,6 +651,14 @@ mod tests {
                 path_on_host: Some(tmp_file.as_path().to_str().unwrap().to_string()),
                 rate_limiter: Some(RateLimiterConfig::default()),
                 file_engine_type: None,
+                overlay: None,
+                num_queues: None,
+                async_io: None,
+                host_cache_mode: None,
+                rate_limiter_group: None,
+                verity: None,
+                nbd: None,
+                encryption: None,
 
                 socket: None,
             },
@@ -599,6 +699,7 @@ mod tests {
             boot_timer: false,
             mmds_size_limit: HTTP_MAX_PAYLOAD_SIZE,
             entropy: Default::default(),
+            rate_limiter_groups: Default::default(),
         }
     }
 
@@ -1332,6 +1433,7 @@ mod tests {
             cpu_template: Some(StaticCpuTemplate::V1N1),
             track_dirty_pages: Some(false),
             huge_pages: Some(HugePageConfig::None),
+            dirty_tracking_backend: Some(DirtyTrackingBackend::Bitmap),
         };
 
         assert_ne!(
@@ -1479,6 +1581,63 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_fixed_buffers_and_balloon() {
+        if default_engine_type_for_kv() != FileEngineType::Async {
+            return;
+        }
+        let balloon_cfg = BalloonDeviceConfig {
+            amount_mib: 0,
+            deflate_on_oom: false,
+            stats_polling_interval_s: 0,
+        };
+        let (mut block_cfg, _file) = default_block_cfg();
+        block_cfg.drive_id = "block2".to_string();
+        block_cfg.file_engine_type = Some(FileEngineType::Async);
+        block_cfg.async_io = Some(AsyncIoConfig {
+            fixed_buffers: true,
+            ..Default::default()
+        });
+
+        // Guest memory registered as fixed buffers stays pinned, so the balloon is rejected.
+        let mut vm_resources = default_vm_resources();
+        vm_resources.balloon = BalloonBuilder::new();
+        vm_resources.set_block_device(block_cfg).unwrap();
+        assert!(matches!(
+            vm_resources.set_balloon_device(balloon_cfg.clone()),
+            Err(BalloonConfigError::FixedBuffers)
+        ));
+        let err = vm_resources
+            .update_from_restored_device(SharedDeviceType::Balloon(Arc::new(Mutex::new(
+                Balloon::new(0, false, 0, true).unwrap(),
+            ))))
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ResourcesError::BalloonDevice(BalloonConfigError::FixedBuffers)
+            ),
+            "{:?}",
+            err
+        );
+
+        // And so are drives using fixed buffers once a balloon is configured.
+        let (mut block_cfg, _file) = default_block_cfg();
+        block_cfg.drive_id = "block2".to_string();
+        block_cfg.file_engine_type = Some(FileEngineType::Async);
+        block_cfg.async_io = Some(AsyncIoConfig {
+            fixed_buffers: true,
+            ..Default::default()
+        });
+        let mut vm_resources = default_vm_resources();
+        vm_resources.balloon = BalloonBuilder::new();
+        vm_resources.set_balloon_device(balloon_cfg).unwrap();
+        assert_eq!(
+            vm_resources.set_block_device(block_cfg),
+            Err(DriveError::FixedBuffersAndBalloon)
+        );
+    }
+
     #[test]
     fn test_set_entropy_device() {
         let mut vm_resources = default_vm_resources();
@@ -1604,4 +1763,40 @@ mod tests {
         vm_resources.build_net_device(new_net_device_cfg).unwrap();
         assert_eq!(vm_resources.net_builder.len(), 2);
     }
+    #[test]
+    fn test_set_rate_limiter_group() {
+        let mut vm_resources = default_vm_resources();
+        let (mut block_cfg, _file) = default_block_cfg();
+        block_cfg.rate_limiter_group = Some("group".to_string());
+
+        // Devices cannot join a group which does not exist.
+        assert!(matches!(
+            vm_resources.set_block_device(block_cfg.clone()),
+            Err(DriveError::RateLimiterGroup(
+                RateLimiterGroupError::GroupNotFound(_)
+            ))
+        ));
+        assert!(vm_resources
+            .update_rate_limiter_group(RateLimiterGroupConfig {
+                group_id: "group".to_string(),
+                bandwidth: None,
+                ops: None,
+            })
+            .is_err());
+
+        vm_resources.set_rate_limiter_group(RateLimiterGroupConfig {
+            group_id: "group".to_string(),
+            bandwidth: None,
+            ops: None,
+        });
+        vm_resources.set_block_device(block_cfg).unwrap();
+        let block = vm_resources.block.devices[0].lock().unwrap();
+        assert_eq!(block.rate_limiter_group().unwrap().id(), "group");
+        drop(block);
+
+        let mut net_cfg = default_net_cfg();
+        net_cfg.rate_limiter_group = Some("group".to_string());
+        vm_resources.build_net_device(net_cfg).unwrap();
+        assert_eq!(vm_resources.rate_limiter_groups.iter().count(), 1);
+    }
 }
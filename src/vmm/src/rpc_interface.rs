@@ -8,19 +8,20 @@ use seccompiler::BpfThreadMap;
 use serde_json::Value;
 #[cfg(test)]
 use tests::{
-    build_and_boot_microvm, create_snapshot, restore_from_snapshot, MockVmRes as VmResources,
-    MockVmm as Vmm,
+    build_and_boot_microvm, create_snapshot, receive_migration, restore_from_snapshot,
+    send_migration, MockVmRes as VmResources, MockVmm as Vmm,
 };
 
 use super::VmmError;
 #[cfg(not(test))]
 use super::{
-    builder::build_and_boot_microvm, persist::create_snapshot, persist::restore_from_snapshot,
-    resources::VmResources, Vmm,
+    builder::build_and_boot_microvm, migration::receive_migration, migration::send_migration,
+    persist::create_snapshot, persist::restore_from_snapshot, resources::VmResources, Vmm,
 };
 use crate::builder::StartMicrovmError;
 use crate::cpu_config::templates::{CustomCpuTemplate, GuestConfigError};
 use crate::logger::{info, warn, LoggerConfig, *};
+use crate::migration::{ReceiveMigrationError, SendMigrationError};
 use crate::mmds::data_store::{self, Mmds};
 use crate::persist::{CreateSnapshotError, RestoreFromSnapshotError, VmInfo};
 use crate::resources::VmmConfig;
@@ -34,11 +35,15 @@ use crate::vmm_config::entropy::{EntropyDeviceConfig, EntropyDeviceError};
 use crate::vmm_config::instance_info::InstanceInfo;
 use crate::vmm_config::machine_config::{MachineConfig, MachineConfigUpdate, VmConfigError};
 use crate::vmm_config::metrics::{MetricsConfig, MetricsConfigError};
+use crate::vmm_config::migration::{ReceiveMigrationParams, SendMigrationParams};
 use crate::vmm_config::mmds::{MmdsConfig, MmdsConfigError};
 use crate::vmm_config::net::{
     NetworkInterfaceConfig, NetworkInterfaceError, NetworkInterfaceUpdateConfig,
 };
-use crate::vmm_config::snapshot::{CreateSnapshotParams, LoadSnapshotParams, SnapshotType};
+use crate::vmm_config::rate_limiter_group::{RateLimiterGroupConfig, RateLimiterGroupError};
+use crate::vmm_config::snapshot::{
+    CreateSnapshotParams, LoadSnapshotParams, SnapshotStatus, SnapshotType,
+};
 use crate::vmm_config::vsock::{VsockConfigError, VsockDeviceConfig};
 use crate::vmm_config::{self, RateLimiterUpdate};
 use crate::EventManager;
@@ -57,7 +62,8 @@ pub enum VmmAction {
     /// before the microVM has booted.
     ConfigureMetrics(MetricsConfig),
     /// Create a snapshot using as input the `CreateSnapshotParams`. This action can only be called
-    /// after the microVM has booted and only when the microVM is in `Paused` state.
+    /// after the microVM has booted and, unless the snapshot is created in the background, only
+    /// when the microVM is in `Paused` state.
     CreateSnapshot(CreateSnapshotParams),
     /// Get the balloon device configuration.
     GetBalloonConfig,
@@ -67,6 +73,8 @@ pub enum VmmAction {
     GetFullVmConfig,
     /// Get MMDS contents.
     GetMMDS,
+    /// Get the progress of the last snapshot created in the background.
+    GetSnapshotStatus,
     /// Get the machine configuration of the microVM.
     GetVmMachineConfig,
     /// Get microVM instance information.
@@ -82,6 +90,10 @@ pub enum VmmAction {
     /// `NetworkInterfaceConfig` as input. This action can only be called before the microVM has
     /// booted.
     InsertNetworkDevice(NetworkInterfaceConfig),
+    /// Add a new rate limiter group or replace the buckets of one that already exists using the
+    /// `RateLimiterGroupConfig` as input. This action can only be called before the microVM has
+    /// booted.
+    InsertRateLimiterGroup(RateLimiterGroupConfig),
     /// Load the microVM state using as input the `LoadSnapshotParams`. This action can only be
     /// called before the microVM has booted. If this action is successful, the loaded microVM will
     /// be in `Paused` state. Should change this state to `Resumed` for the microVM to run.
@@ -94,6 +106,10 @@ pub enum VmmAction {
     PutMMDS(Value),
     /// Configure the guest vCPU features.
     PutCpuConfiguration(CustomCpuTemplate),
+    /// Receive a live migrated microVM using as input the `ReceiveMigrationParams`. This action
+    /// can only be called before the microVM has booted. If this action is successful, the
+    /// migrated microVM will be in `Paused` state, unless `resume_vm` is set.
+    ReceiveMigration(ReceiveMigrationParams),
     /// Resume the guest, by resuming the microVM VCPUs.
     Resume,
     /// Set the balloon device or update the one that already exists using the
@@ -115,6 +131,10 @@ pub enum VmmAction {
     /// driver is listening on the guest end, this can be used to shut down the microVM gracefully.
     #[cfg(target_arch = "x86_64")]
     SendCtrlAltDel,
+    /// Live migrate the microVM using as input the `SendMigrationParams`. This action can only be
+    /// called after the microVM has booted. If this action is successful, the microVM is left in
+    /// `Paused` state. Device emulation is stalled while the microVM is being sent.
+    SendMigration(SendMigrationParams),
     /// Update the balloon size, after microVM start.
     UpdateBalloon(BalloonUpdateConfig),
     /// Update the balloon statistics polling interval, after microVM start.
@@ -124,6 +144,8 @@ pub enum VmmAction {
     /// Update a network interface, after microVM start. Currently, the only updatable properties
     /// are the RX and TX rate limiters.
     UpdateNetworkInterface(NetworkInterfaceUpdateConfig),
+    /// Update the buckets of a rate limiter group, after microVM start.
+    UpdateRateLimiterGroup(RateLimiterGroupConfig),
     /// Update the microVM configuration (memory & vcpu) using `VmUpdateConfig` as input. This
     /// action can only be called before the microVM has booted.
     UpdateVmConfiguration(MachineConfigUpdate),
@@ -154,6 +176,8 @@ pub enum VmmActionError {
     MachineConfig(#[from] VmConfigError),
     /// Metrics error: {0}
     Metrics(#[from] MetricsConfigError),
+    /// Migration error: {0}
+    Migration(#[from] MigrationError),
     #[from(ignore)]
     /// MMDS error: {0}
     Mmds(#[from] data_store::MmdsDatastoreError),
@@ -170,6 +194,8 @@ pub enum VmmActionError {
     OperationNotSupportedPostBoot,
     /// The requested operation is not supported before starting the microVM.
     OperationNotSupportedPreBoot,
+    /// Rate limiter group error: {0}
+    RateLimiterGroup(#[from] RateLimiterGroupError),
     /// Start microvm error: {0}
     StartMicrovm(#[from] StartMicrovmError),
     /// Vsock config error: {0}
@@ -195,6 +221,8 @@ pub enum VmmData {
     MmdsValue(serde_json::Value),
     /// The microVM instance information.
     InstanceInformation(InstanceInfo),
+    /// The progress of the last snapshot created in the background.
+    SnapshotStatus(SnapshotStatus),
     /// The microVM version.
     VmmVersion(String),
 }
@@ -285,6 +313,19 @@ pub enum LoadSnapshotError {
     ResumeMicrovm(#[from] VmmError),
 }
 
+/// Error type for the live migration VMM actions.
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum MigrationError {
+    /// Receiving a migrated microVM not allowed after configuring boot-specific resources.
+    ReceiveMigrationNotAllowed,
+    /// Failed to receive microVM: {0}
+    Receive(#[from] ReceiveMigrationError),
+    /// Failed to send microVM: {0}
+    Send(#[from] SendMigrationError),
+    /// Failed to resume microVM: {0}
+    ResumeMicrovm(#[from] VmmError),
+}
+
 /// Shorthand type for a request containing a boxed VmmAction.
 pub type ApiRequest = Box<VmmAction>;
 /// Shorthand type for a response containing a boxed Result.
@@ -297,6 +338,8 @@ pub enum BuildMicrovmFromRequestsError {
     Mmds(data_store::MmdsDatastoreError),
     /// Loading snapshot failed.
     Restore,
+    /// Receiving migrated microVM failed.
+    Migration,
     /// Resuming MicroVM after loading snapshot failed.
     Resume,
 }
@@ -427,6 +470,7 @@ impl<'a> PrebootApiController<'a> {
             GetVmmVersion => Ok(VmmData::VmmVersion(self.instance_info.vmm_version.clone())),
             InsertBlockDevice(config) => self.insert_block_device(config),
             InsertNetworkDevice(config) => self.insert_net_device(config),
+            InsertRateLimiterGroup(config) => self.insert_rate_limiter_group(config),
             LoadSnapshot(config) => self
                 .load_snapshot(&config)
                 .map_err(VmmActionError::LoadSnapshot),
@@ -435,6 +479,9 @@ impl<'a> PrebootApiController<'a> {
                 self.set_custom_cpu_template(custom_cpu_template)
             }
             PutMMDS(value) => self.put_mmds(value),
+            ReceiveMigration(config) => self
+                .receive_migration(&config)
+                .map_err(VmmActionError::Migration),
             SetBalloonDevice(config) => self.set_balloon_device(config),
             SetVsockDevice(config) => self.set_vsock_device(config),
             SetMmdsConfiguration(config) => self.set_mmds_config(config),
@@ -447,10 +494,13 @@ impl<'a> PrebootApiController<'a> {
             | Pause
             | Resume
             | GetBalloonStats
+            | GetSnapshotStatus
+            | SendMigration(_)
             | UpdateBalloon(_)
             | UpdateBalloonStatistics(_)
             | UpdateBlockDevice(_)
-            | UpdateNetworkInterface(_) => Err(VmmActionError::OperationNotSupportedPreBoot),
+            | UpdateNetworkInterface(_)
+            | UpdateRateLimiterGroup(_) => Err(VmmActionError::OperationNotSupportedPreBoot),
             #[cfg(target_arch = "x86_64")]
             SendCtrlAltDel => Err(VmmActionError::OperationNotSupportedPreBoot),
         }
@@ -483,6 +533,15 @@ impl<'a> PrebootApiController<'a> {
             .map_err(VmmActionError::NetworkConfig)
     }
 
+    fn insert_rate_limiter_group(
+        &mut self,
+        cfg: RateLimiterGroupConfig,
+    ) -> Result<VmmData, VmmActionError> {
+        self.boot_path = true;
+        self.vm_resources.set_rate_limiter_group(cfg);
+        Ok(VmmData::Empty)
+    }
+
     fn set_balloon_device(&mut self, cfg: BalloonDeviceConfig) -> Result<VmmData, VmmActionError> {
         self.boot_path = true;
         self.vm_resources
@@ -609,6 +668,56 @@ impl<'a> PrebootApiController<'a> {
 
         Ok(VmmData::Empty)
     }
+
+    // On success, this command will end the pre-boot stage and this controller
+    // will be replaced by a runtime controller.
+    fn receive_migration(
+        &mut self,
+        params: &ReceiveMigrationParams,
+    ) -> Result<VmmData, MigrationError> {
+        log_dev_preview_warning("Live migration", Option::None);
+
+        let receive_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
+
+        if self.boot_path {
+            let err = MigrationError::ReceiveMigrationNotAllowed;
+            info!("{}", err);
+            return Err(err);
+        }
+
+        let vmm = receive_migration(
+            &self.instance_info,
+            self.event_manager,
+            self.seccomp_filters,
+            params,
+            self.vm_resources,
+        )
+        .map_err(|err| {
+            // If the migration fails, we consider the process is too dirty to recover.
+            self.fatal_error = Some(BuildMicrovmFromRequestsError::Migration);
+            err
+        })?;
+        if params.resume_vm {
+            vmm.lock()
+                .expect("Poisoned lock")
+                .resume_vm()
+                .map_err(|err| {
+                    // If resume fails, we consider the process is too dirty to recover.
+                    self.fatal_error = Some(BuildMicrovmFromRequestsError::Resume);
+                    err
+                })?;
+        }
+        self.built_vmm = Some(vmm);
+
+        let elapsed_time_us = utils::time::get_time_us(utils::time::ClockType::Monotonic)
+            .saturating_sub(receive_start_us);
+        info!(
+            "'receive migration' VMM action took {} us.",
+            elapsed_time_us
+        );
+
+        Ok(VmmData::Empty)
+    }
 }
 
 /// Enables RPC interaction with a running Firecracker VMM.
@@ -648,6 +757,9 @@ impl RuntimeApiController {
                 .map_err(|err| VmmActionError::BalloonConfig(BalloonConfigError::from(err))),
             GetFullVmConfig => Ok(VmmData::FullVmConfig((&self.vm_resources).into())),
             GetMMDS => self.get_mmds(),
+            GetSnapshotStatus => Ok(VmmData::SnapshotStatus(
+                self.vmm.lock().expect("Poisoned lock").snapshot_status(),
+            )),
             GetVmMachineConfig => Ok(VmmData::MachineConfiguration(MachineConfig::from(
                 &self.vm_resources.vm_config,
             ))),
@@ -663,6 +775,7 @@ impl RuntimeApiController {
             Resume => self.resume(),
             #[cfg(target_arch = "x86_64")]
             SendCtrlAltDel => self.send_ctrl_alt_del(),
+            SendMigration(params) => self.send_migration(&params),
             UpdateBalloon(balloon_update) => self
                 .vmm
                 .lock()
@@ -679,6 +792,11 @@ impl RuntimeApiController {
                 .map_err(|err| VmmActionError::BalloonConfig(BalloonConfigError::from(err))),
             UpdateBlockDevice(new_cfg) => self.update_block_device(new_cfg),
             UpdateNetworkInterface(netif_update) => self.update_net_rate_limiters(netif_update),
+            UpdateRateLimiterGroup(group_update) => self
+                .vm_resources
+                .update_rate_limiter_group(group_update)
+                .map(|()| VmmData::Empty)
+                .map_err(VmmActionError::RateLimiterGroup),
 
             // Operations not allowed post-boot.
             ConfigureBootSource(_)
@@ -686,8 +804,10 @@ impl RuntimeApiController {
             | ConfigureMetrics(_)
             | InsertBlockDevice(_)
             | InsertNetworkDevice(_)
+            | InsertRateLimiterGroup(_)
             | LoadSnapshot(_)
             | PutCpuConfiguration(_)
+            | ReceiveMigration(_)
             | SetBalloonDevice(_)
             | SetVsockDevice(_)
             | SetMmdsConfiguration(_)
@@ -774,6 +894,17 @@ impl RuntimeApiController {
 
         create_snapshot(&mut locked_vmm, &vm_info, create_params)?;
 
+        // Background snapshots only account for the time the microVM is paused.
+        if create_params.background {
+            let elapsed_time_us =
+                utils::time::get_time_us(utils::time::ClockType::Monotonic) - create_start_us;
+            info!(
+                "'create background snapshot' VMM action took {} us.",
+                elapsed_time_us
+            );
+            return Ok(VmmData::Empty);
+        }
+
         match create_params.snapshot_type {
             SnapshotType::Full => {
                 let elapsed_time_us = update_metric_with_elapsed_time(
@@ -799,6 +930,29 @@ impl RuntimeApiController {
         Ok(VmmData::Empty)
     }
 
+    fn send_migration(&mut self, params: &SendMigrationParams) -> Result<VmmData, VmmActionError> {
+        log_dev_preview_warning("Live migration", None);
+
+        if !self.vm_resources.track_dirty_pages() {
+            return Err(VmmActionError::NotSupported(
+                "Live migration is not allowed on uVMs with dirty page tracking disabled."
+                    .to_string(),
+            ));
+        }
+
+        let mut locked_vmm = self.vmm.lock().unwrap();
+        let vm_info = VmInfo::from(&self.vm_resources);
+        let send_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
+
+        send_migration(&mut locked_vmm, &vm_info, params).map_err(MigrationError::Send)?;
+
+        let elapsed_time_us = utils::time::get_time_us(utils::time::ClockType::Monotonic)
+            .saturating_sub(send_start_us);
+        info!("'send migration' VMM action took {} us.", elapsed_time_us);
+
+        Ok(VmmData::Empty)
+    }
+
     /// Updates block device properties:
     ///  - path of the host file backing the emulated block device, update the disk image on the
     ///    device and its virtio configuration
@@ -872,7 +1026,9 @@ mod tests {
     use crate::mmds::data_store::MmdsVersion;
     use crate::vmm_config::balloon::BalloonBuilder;
     use crate::vmm_config::machine_config::VmConfig;
-    use crate::vmm_config::snapshot::{MemBackendConfig, MemBackendType};
+    use crate::vmm_config::snapshot::{
+        MemBackendConfig, MemBackendType, MemoryCompression, SnapshotOutput,
+    };
     use crate::vmm_config::vsock::VsockBuilder;
     use crate::HTTP_MAX_PAYLOAD_SIZE;
 
@@ -889,6 +1045,7 @@ mod tests {
                     | (LoadSnapshot(_), LoadSnapshot(_))
                     | (MachineConfig(_), MachineConfig(_))
                     | (Metrics(_), Metrics(_))
+                    | (Migration(_), Migration(_))
                     | (Mmds(_), Mmds(_))
                     | (MmdsLimitExceeded(_), MmdsLimitExceeded(_))
                     | (MmdsConfig(_), MmdsConfig(_))
@@ -896,6 +1053,7 @@ mod tests {
                     | (NotSupported(_), NotSupported(_))
                     | (OperationNotSupportedPostBoot, OperationNotSupportedPostBoot)
                     | (OperationNotSupportedPreBoot, OperationNotSupportedPreBoot)
+                    | (RateLimiterGroup(_), RateLimiterGroup(_))
                     | (StartMicrovm(_), StartMicrovm(_))
                     | (VsockConfig(_), VsockConfig(_))
                     | (EntropyDevice(_), EntropyDevice(_))
@@ -917,6 +1075,7 @@ mod tests {
         vsock_set: bool,
         net_set: bool,
         entropy_set: bool,
+        rate_limiter_group_set: bool,
         pub mmds: Option<Arc<Mutex<Mmds>>>,
         pub mmds_size_limit: usize,
         pub boot_timer: bool,
@@ -1002,6 +1161,17 @@ mod tests {
             Ok(())
         }
 
+        pub fn set_rate_limiter_group(&mut self, _: RateLimiterGroupConfig) {
+            self.rate_limiter_group_set = true;
+        }
+
+        pub fn update_rate_limiter_group(
+            &mut self,
+            _: RateLimiterGroupConfig,
+        ) -> Result<(), RateLimiterGroupError> {
+            Ok(())
+        }
+
         pub fn set_vsock_device(&mut self, _: VsockDeviceConfig) -> Result<(), VsockConfigError> {
             if self.force_errors {
                 return Err(VsockConfigError::CreateVsockDevice(
@@ -1074,6 +1244,7 @@ mod tests {
                 cpu_template: StaticCpuTemplate::from(&value.vm_config.cpu_template),
                 boot_source: value.boot_source_config().clone(),
                 huge_pages: value.vm_config.huge_pages,
+                dirty_tracking_backend: value.vm_config.dirty_tracking_backend,
             }
         }
     }
@@ -1209,6 +1380,10 @@ mod tests {
         pub fn version(&self) -> String {
             String::default()
         }
+
+        pub fn snapshot_status(&self) -> SnapshotStatus {
+            SnapshotStatus::default()
+        }
     }
 
     // Need to redefine this since the non-test one uses real VmResources
@@ -1244,6 +1419,28 @@ mod tests {
         Ok(Arc::new(Mutex::new(MockVmm::default())))
     }
 
+    // Need to redefine this since the non-test one uses real Vmm
+    // instead of our mocks.
+    pub fn send_migration(
+        _: &mut Vmm,
+        _: &VmInfo,
+        _: &SendMigrationParams,
+    ) -> Result<(), SendMigrationError> {
+        Ok(())
+    }
+
+    // Need to redefine this since the non-test one uses real Vmm
+    // instead of our mocks.
+    pub fn receive_migration(
+        _: &InstanceInfo,
+        _: &mut EventManager,
+        _: &BpfThreadMap,
+        _: &ReceiveMigrationParams,
+        _: &mut MockVmRes,
+    ) -> Result<Arc<Mutex<Vmm>>, ReceiveMigrationError> {
+        Ok(Arc::new(Mutex::new(MockVmm::default())))
+    }
+
     fn default_preboot<'a>(
         vm_resources: &'a mut VmResources,
         event_manager: &'a mut EventManager,
@@ -1398,6 +1595,14 @@ mod tests {
             path_on_host: Some(String::new()),
             rate_limiter: None,
             file_engine_type: None,
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: None,
         };
@@ -1422,6 +1627,8 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rate_limiter_group: None,
+            link_status: false,
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1434,6 +1641,8 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rate_limiter_group: None,
+            link_status: false,
         });
         check_preboot_request_err(
             req,
@@ -1477,6 +1686,19 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_preboot_insert_rate_limiter_group() {
+        let req = VmmAction::InsertRateLimiterGroup(RateLimiterGroupConfig {
+            group_id: String::from("group"),
+            bandwidth: None,
+            ops: None,
+        });
+        check_preboot_request(req, |result, vm_res| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vm_res.rate_limiter_group_set);
+        });
+    }
+
     #[test]
     fn test_preboot_set_mmds_config() {
         let req = VmmAction::SetMmdsConfiguration(MmdsConfig {
@@ -1737,6 +1959,12 @@ mod tests {
             },
             enable_diff_snapshots: false,
             resume_vm: false,
+            encryption: None,
+            integrity: None,
+            drive_overrides: vec![],
+            network_overrides: vec![],
+            vsock_override: None,
+            clone: false,
         });
         // Request should succeed.
         preboot.handle_preboot_request(req).unwrap();
@@ -1753,6 +1981,12 @@ mod tests {
             },
             enable_diff_snapshots: false,
             resume_vm: true,
+            encryption: None,
+            integrity: None,
+            drive_overrides: vec![],
+            network_overrides: vec![],
+            vsock_override: None,
+            clone: false,
         });
         // Request should succeed.
         preboot.handle_preboot_request(req).unwrap();
@@ -1763,6 +1997,25 @@ mod tests {
         assert!(!vmm.pause_called);
     }
 
+    #[test]
+    fn test_preboot_receive_migration() {
+        let mut vm_resources = MockVmRes::default();
+        let mut evmgr = EventManager::new().unwrap();
+        let seccomp_filters = BpfThreadMap::new();
+        let mut preboot = default_preboot(&mut vm_resources, &mut evmgr, &seccomp_filters);
+
+        let req = VmmAction::ReceiveMigration(ReceiveMigrationParams {
+            socket_path: PathBuf::new(),
+            enable_diff_snapshots: false,
+            resume_vm: true,
+        });
+        // Request should succeed.
+        preboot.handle_preboot_request(req).unwrap();
+        let vmm = preboot.built_vmm.as_ref().unwrap().lock().unwrap();
+        // Should have built mock vmm then called resume on it.
+        assert!(vmm.resume_called);
+    }
+
     #[test]
     fn test_preboot_disallowed() {
         check_preboot_request_err(
@@ -1781,6 +2034,10 @@ mod tests {
             VmmAction::GetBalloonStats,
             VmmActionError::OperationNotSupportedPreBoot,
         );
+        check_preboot_request_err(
+            VmmAction::GetSnapshotStatus,
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
         check_preboot_request_err(
             VmmAction::UpdateBalloon(BalloonUpdateConfig { amount_mib: 0 }),
             VmmActionError::OperationNotSupportedPreBoot,
@@ -1806,8 +2063,28 @@ mod tests {
         check_preboot_request_err(
             VmmAction::CreateSnapshot(CreateSnapshotParams {
                 snapshot_type: SnapshotType::Full,
-                snapshot_path: PathBuf::new(),
-                mem_file_path: PathBuf::new(),
+                snapshot_output: SnapshotOutput::Path(PathBuf::new()),
+                mem_file_output: SnapshotOutput::Path(PathBuf::new()),
+                compression: MemoryCompression::None,
+                encryption: None,
+                background: false,
+                integrity: None,
+            }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        check_preboot_request_err(
+            VmmAction::SendMigration(SendMigrationParams {
+                socket_path: PathBuf::new(),
+                max_precopy_iterations: 0,
+                max_dirty_pages: 0,
+            }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        check_preboot_request_err(
+            VmmAction::UpdateRateLimiterGroup(RateLimiterGroupConfig {
+                group_id: String::new(),
+                bandwidth: None,
+                ops: None,
             }),
             VmmActionError::OperationNotSupportedPreBoot,
         );
@@ -1867,6 +2144,14 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_runtime_get_snapshot_status() {
+        let req = VmmAction::GetSnapshotStatus;
+        check_runtime_request(req, |result, _| {
+            assert_eq!(result, Ok(VmmData::SnapshotStatus(SnapshotStatus::None)));
+        });
+    }
+
     #[test]
     fn test_runtime_pause() {
         let req = VmmAction::Pause;
@@ -1999,6 +2284,18 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_runtime_update_rate_limiter_group() {
+        let req = VmmAction::UpdateRateLimiterGroup(RateLimiterGroupConfig {
+            group_id: String::from("group"),
+            bandwidth: None,
+            ops: None,
+        });
+        check_runtime_request(req, |result, _| {
+            assert_eq!(result, Ok(VmmData::Empty));
+        });
+    }
+
     #[test]
     fn test_runtime_update_block_device_vhost_user_config() {
         let req = VmmAction::UpdateBlockDevice(BlockDeviceUpdateConfig {
@@ -2078,6 +2375,14 @@ mod tests {
                 path_on_host: Some(String::new()),
                 rate_limiter: None,
                 file_engine_type: None,
+                overlay: None,
+                num_queues: None,
+                async_io: None,
+                host_cache_mode: None,
+                rate_limiter_group: None,
+                verity: None,
+                nbd: None,
+                encryption: None,
 
                 socket: None,
             }),
@@ -2090,6 +2395,8 @@ mod tests {
                 guest_mac: None,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                rate_limiter_group: None,
+                link_status: false,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -2134,6 +2441,12 @@ mod tests {
                 },
                 enable_diff_snapshots: false,
                 resume_vm: false,
+                encryption: None,
+                integrity: None,
+                drive_overrides: vec![],
+                network_overrides: vec![],
+                vsock_override: None,
+                clone: false,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -2141,6 +2454,45 @@ mod tests {
             VmmAction::SetEntropyDevice(EntropyDeviceConfig::default()),
             VmmActionError::OperationNotSupportedPostBoot,
         );
+        check_runtime_request_err(
+            VmmAction::InsertRateLimiterGroup(RateLimiterGroupConfig {
+                group_id: String::new(),
+                bandwidth: None,
+                ops: None,
+            }),
+            VmmActionError::OperationNotSupportedPostBoot,
+        );
+        check_runtime_request_err(
+            VmmAction::ReceiveMigration(ReceiveMigrationParams {
+                socket_path: PathBuf::new(),
+                enable_diff_snapshots: false,
+                resume_vm: false,
+            }),
+            VmmActionError::OperationNotSupportedPostBoot,
+        );
+    }
+
+    #[test]
+    fn test_runtime_send_migration() {
+        let params = SendMigrationParams {
+            socket_path: PathBuf::new(),
+            max_precopy_iterations: 0,
+            max_dirty_pages: 0,
+        };
+
+        // Dirty page tracking is disabled by default.
+        check_runtime_request_err(
+            VmmAction::SendMigration(params.clone()),
+            VmmActionError::NotSupported(String::new()),
+        );
+
+        let mut vm_res = MockVmRes::default();
+        vm_res.set_track_dirty_pages(true);
+        let vmm = Arc::new(Mutex::new(MockVmm::default()));
+        let mut runtime = RuntimeApiController::new(vm_res, vmm);
+        runtime
+            .handle_request(VmmAction::SendMigration(params))
+            .unwrap();
     }
 
     fn verify_load_snap_disallowed_after_boot_resources(res: VmmAction, res_name: &str) {
@@ -2160,6 +2512,12 @@ mod tests {
             },
             enable_diff_snapshots: false,
             resume_vm: false,
+            encryption: None,
+            integrity: None,
+            drive_overrides: vec![],
+            network_overrides: vec![],
+            vsock_override: None,
+            clone: false,
         });
         let err = preboot.handle_preboot_request(req);
         assert_eq!(
@@ -2188,6 +2546,14 @@ mod tests {
             path_on_host: Some(String::new()),
             rate_limiter: None,
             file_engine_type: None,
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: None,
         };
@@ -2201,6 +2567,8 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rate_limiter_group: None,
+            link_status: false,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "InsertNetworkDevice");
 
@@ -2225,4 +2593,28 @@ mod tests {
         });
         verify_load_snap_disallowed_after_boot_resources(req, "SetMmdsConfiguration");
     }
+
+    #[test]
+    fn test_preboot_receive_migration_disallowed_after_boot_resources() {
+        let mut vm_resources = MockVmRes::default();
+        let mut evmgr = EventManager::new().unwrap();
+        let seccomp_filters = BpfThreadMap::new();
+        let mut preboot = default_preboot(&mut vm_resources, &mut evmgr, &seccomp_filters);
+
+        preboot
+            .handle_preboot_request(VmmAction::ConfigureBootSource(BootSourceConfig::default()))
+            .unwrap();
+
+        let req = VmmAction::ReceiveMigration(ReceiveMigrationParams {
+            socket_path: PathBuf::new(),
+            enable_diff_snapshots: false,
+            resume_vm: false,
+        });
+        let err = preboot.handle_preboot_request(req).unwrap_err();
+        assert!(matches!(
+            err,
+            VmmActionError::Migration(MigrationError::ReceiveMigrationNotAllowed)
+        ));
+        assert!(preboot.fatal_error.is_none());
+    }
 }
@@ -0,0 +1,134 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements reading guest memory files which are split into fixed-size chunks.
+//!
+//! Both compressed (see [`compression`](super::compression)) and encrypted
+//! (see [`encryption`](super::encryption)) memory files split guest memory into chunks of
+//! `chunk_size` bytes (the last one may be shorter) which can be decoded independently. Only the
+//! decoding of a single chunk differs between the two formats.
+
+use utils::u64_to_usize;
+use vm_memory::GuestMemoryError;
+
+use crate::vstate::memory::{
+    Bytes, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, GuestMemoryState, MemoryRegionAddress,
+};
+
+/// Largest chunk size accepted in chunked memory files. The chunk size comes from the file
+/// header, so this bounds the buffers allocated to read a chunk before its contents are checked.
+pub const MAX_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Layout of guest memory in a chunked memory file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ChunkLayout {
+    /// Size of the chunks guest memory is split into.
+    pub chunk_size: u64,
+    /// Size of guest memory.
+    pub memory_size: u64,
+}
+
+impl ChunkLayout {
+    /// Creates a layout, validating the chunk size.
+    pub fn new(chunk_size: u64, memory_size: u64) -> Result<Self, &'static str> {
+        if chunk_size == 0 {
+            return Err("chunk size cannot be 0");
+        }
+        if chunk_size > MAX_CHUNK_SIZE {
+            return Err("chunk size too large");
+        }
+        Ok(Self {
+            chunk_size,
+            memory_size,
+        })
+    }
+
+    /// Number of chunks guest memory is split into.
+    pub fn chunk_count(&self) -> u64 {
+        self.memory_size.div_ceil(self.chunk_size)
+    }
+}
+
+/// A guest memory file split into chunks which can be read independently.
+pub(crate) trait ChunkedMemoryFile {
+    /// Error returned when reading the file.
+    type Error: From<GuestMemoryError>;
+
+    /// Layout of guest memory in the file.
+    fn layout(&self) -> ChunkLayout;
+
+    /// Error returned when reading `len` bytes at `offset` is out of the bounds of guest memory.
+    fn out_of_bounds(offset: u64, len: u64) -> Self::Error;
+
+    /// Reads the chunk with the given index into `buf`, which has the exact length of the chunk.
+    fn read_chunk(&self, chunk_index: u64, buf: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+/// Reads `buf.len()` bytes of guest memory starting at `offset` from `file`.
+pub(crate) fn read_at<F: ChunkedMemoryFile>(
+    file: &F,
+    buf: &mut [u8],
+    offset: u64,
+) -> Result<(), F::Error> {
+    let ChunkLayout {
+        chunk_size,
+        memory_size,
+    } = file.layout();
+    let len = buf.len() as u64;
+    match offset.checked_add(len) {
+        Some(end) if end <= memory_size => (),
+        _ => return Err(F::out_of_bounds(offset, len)),
+    }
+
+    let mut chunk = vec![0u8; u64_to_usize(chunk_size)];
+    let mut done = 0;
+    while done < len {
+        let position = offset + done;
+        let chunk_index = position / chunk_size;
+        let chunk_start = position % chunk_size;
+        let chunk_len = chunk_size.min(memory_size - position + chunk_start);
+        let count = (chunk_len - chunk_start).min(len - done);
+
+        file.read_chunk(chunk_index, &mut chunk[..u64_to_usize(chunk_len)])?;
+        buf[u64_to_usize(done)..u64_to_usize(done + count)]
+            .copy_from_slice(&chunk[u64_to_usize(chunk_start)..u64_to_usize(chunk_start + count)]);
+        done += count;
+    }
+    Ok(())
+}
+
+/// Populates `guest_memory`, created from `mem_state`, with the contents of `file`.
+pub(crate) fn restore<F: ChunkedMemoryFile>(
+    file: &F,
+    guest_memory: &GuestMemoryMmap,
+    mem_state: &GuestMemoryState,
+) -> Result<(), F::Error> {
+    let chunk_size = file.layout().chunk_size;
+    let mut buf = vec![0u8; u64_to_usize(chunk_size)];
+    for (region, state_region) in guest_memory.iter().zip(mem_state.regions.iter()) {
+        let mut region_offset = 0;
+        while region_offset < region.len() {
+            let len = chunk_size.min(region.len() - region_offset);
+            let data = &mut buf[..u64_to_usize(len)];
+            read_at(file, data, state_region.offset + region_offset)?;
+            region.write_slice(data, MemoryRegionAddress(region_offset))?;
+            region_offset += len;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_layout() {
+        assert_eq!(ChunkLayout::new(0x1800, 0x8000).unwrap().chunk_count(), 6);
+        assert_eq!(ChunkLayout::new(0x1000, 0).unwrap().chunk_count(), 0);
+        ChunkLayout::new(MAX_CHUNK_SIZE, 0x8000).unwrap();
+        ChunkLayout::new(0, 0x8000).unwrap_err();
+        ChunkLayout::new(MAX_CHUNK_SIZE + 1, 0x8000).unwrap_err();
+        ChunkLayout::new(u64::MAX, 0x8000).unwrap_err();
+    }
+}
@@ -0,0 +1,389 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements the compressed guest memory file format.
+//!
+//! Guest memory is split into fixed-size chunks (the last one may be shorter) which are
+//! compressed independently using LZ4, so that any range of guest memory can be read back
+//! without decompressing the whole file. This is what allows UFFD page fault handlers to
+//! decompress guest memory lazily.
+//!
+//! The file uses the following layout, all integers being little endian `u64`s:
+//!
+//!  |-----------------------------------------|
+//!  |              magic_id                   |
+//!  |-----------------------------------------|
+//!  |       chunk_size     |   memory_size    |
+//!  |-----------------------------------------|
+//!  |          compressed chunks              |
+//!  |-----------------------------------------|
+//!  |   chunk index: (offset, len) per chunk  |
+//!  |-----------------------------------------|
+//!  |          chunk index offset             |
+//!  |-----------------------------------------|
+//!
+//! Offsets in the chunk index are relative to the start of the file. A chunk of length 0 is
+//! all zeroes, and a chunk whose length equals its uncompressed size is stored uncompressed.
+//!
+//! Offsets of guest memory regions (see [`GuestMemoryState`]) refer to the uncompressed
+//! memory contents, exactly like for uncompressed memory files. Chunks are read through the
+//! shared [`chunked`](super::chunked) logic.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+
+use utils::u64_to_usize;
+use vm_memory::GuestMemoryError;
+
+use super::chunked::{self, ChunkLayout, ChunkedMemoryFile};
+use crate::vstate::memory::{
+    Bytes, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, GuestMemoryState, MemoryRegionAddress,
+};
+
+/// Magic value identifying a compressed guest memory file ("FCMEMLZ4").
+pub const COMPRESSED_MEMORY_MAGIC_ID: u64 = u64::from_le_bytes(*b"FCMEMLZ4");
+/// Size of the uncompressed chunks used by default.
+pub const DEFAULT_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Length of the header (magic id, chunk size, memory size).
+const HEADER_LEN: u64 = 24;
+/// Length of a chunk index entry (offset, length).
+const INDEX_ENTRY_LEN: u64 = 16;
+/// Length of the trailing chunk index offset.
+const FOOTER_LEN: u64 = 8;
+
+/// Errors associated with compressed guest memory files.
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum CompressionError {
+    /// Cannot access the memory file: {0}
+    Io(#[from] io::Error),
+    /// Cannot access guest memory: {0}
+    GuestMemory(#[from] GuestMemoryError),
+    /// Invalid compressed memory file: {0}
+    InvalidFile(&'static str),
+    /// Cannot decompress chunk {0}: {1}
+    Decompress(u64, lz4_flex::block::DecompressError),
+    /// Range {0:#x}+{1:#x} is out of the bounds of the compressed memory.
+    OutOfBounds(u64, u64),
+}
+
+/// Location of a compressed chunk inside the memory file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ChunkDescriptor {
+    offset: u64,
+    len: u64,
+}
+
+/// Compresses the contents of `guest_memory`, in region order, and writes them to `writer`.
+pub fn compress_memory<W: Write>(
+    guest_memory: &GuestMemoryMmap,
+    writer: &mut W,
+    chunk_size: u64,
+) -> Result<(), CompressionError> {
+    let memory_size: u64 = guest_memory.iter().map(|region| region.len()).sum();
+    ChunkLayout::new(chunk_size, memory_size).map_err(CompressionError::InvalidFile)?;
+
+    writer.write_all(&COMPRESSED_MEMORY_MAGIC_ID.to_le_bytes())?;
+    writer.write_all(&chunk_size.to_le_bytes())?;
+    writer.write_all(&memory_size.to_le_bytes())?;
+
+    let mut chunks = Vec::new();
+    let mut offset = HEADER_LEN;
+    let mut chunk = Vec::with_capacity(u64_to_usize(chunk_size));
+    let mut write_chunk = |chunk: &mut Vec<u8>, writer: &mut W| -> Result<(), io::Error> {
+        let len = if chunk.iter().all(|&byte| byte == 0) {
+            0
+        } else {
+            let compressed = lz4_flex::block::compress(chunk);
+            // Keep incompressible chunks as they are, so they never grow.
+            let data = if compressed.len() < chunk.len() {
+                &compressed
+            } else {
+                &*chunk
+            };
+            writer.write_all(data)?;
+            data.len() as u64
+        };
+        chunks.push(ChunkDescriptor { offset, len });
+        offset += len;
+        chunk.clear();
+        Ok(())
+    };
+
+    for region in guest_memory.iter() {
+        let mut region_offset = 0;
+        while region_offset < region.len() {
+            let filled = chunk.len() as u64;
+            let len = (chunk_size - filled).min(region.len() - region_offset);
+            chunk.resize(u64_to_usize(filled + len), 0);
+            region.read_slice(
+                &mut chunk[u64_to_usize(filled)..],
+                MemoryRegionAddress(region_offset),
+            )?;
+            region_offset += len;
+
+            if chunk.len() as u64 == chunk_size {
+                write_chunk(&mut chunk, writer)?;
+            }
+        }
+    }
+    if !chunk.is_empty() {
+        write_chunk(&mut chunk, writer)?;
+    }
+
+    let index_offset = offset;
+    for descriptor in chunks {
+        writer.write_all(&descriptor.offset.to_le_bytes())?;
+        writer.write_all(&descriptor.len.to_le_bytes())?;
+    }
+    writer.write_all(&index_offset.to_le_bytes())?;
+    Ok(())
+}
+
+/// A compressed guest memory file, opened for reading.
+#[derive(Debug)]
+pub struct CompressedMemoryFile {
+    file: File,
+    layout: ChunkLayout,
+    chunks: Vec<ChunkDescriptor>,
+}
+
+fn read_u64_at(file: &File, offset: u64) -> Result<u64, io::Error> {
+    let mut buf = [0u8; 8];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+impl CompressedMemoryFile {
+    /// Returns whether `file` is a compressed guest memory file.
+    pub fn is_compressed(file: &File) -> Result<bool, io::Error> {
+        if file.metadata()?.len() < HEADER_LEN + FOOTER_LEN {
+            return Ok(false);
+        }
+        Ok(read_u64_at(file, 0)? == COMPRESSED_MEMORY_MAGIC_ID)
+    }
+
+    /// Opens a compressed guest memory file, validating its header and chunk index.
+    pub fn open(file: File) -> Result<Self, CompressionError> {
+        let file_len = file.metadata()?.len();
+        if !Self::is_compressed(&file)? {
+            return Err(CompressionError::InvalidFile("missing magic id"));
+        }
+
+        let layout = ChunkLayout::new(read_u64_at(&file, 8)?, read_u64_at(&file, 16)?)
+            .map_err(CompressionError::InvalidFile)?;
+        let chunk_size = layout.chunk_size;
+        let chunk_count = layout.chunk_count();
+
+        let index_offset = read_u64_at(&file, file_len - FOOTER_LEN)?;
+        let index_len = chunk_count
+            .checked_mul(INDEX_ENTRY_LEN)
+            .ok_or(CompressionError::InvalidFile("chunk index too large"))?;
+        if index_offset < HEADER_LEN
+            || index_offset.checked_add(index_len) != Some(file_len - FOOTER_LEN)
+        {
+            return Err(CompressionError::InvalidFile("chunk index out of bounds"));
+        }
+
+        let mut index = vec![0u8; u64_to_usize(index_len)];
+        file.read_exact_at(&mut index, index_offset)?;
+        let chunks = index
+            .chunks_exact(u64_to_usize(INDEX_ENTRY_LEN))
+            .map(|entry| {
+                // The slices have the exact length of the arrays, so conversions cannot fail.
+                let offset = u64::from_le_bytes(entry[..8].try_into().unwrap());
+                let len = u64::from_le_bytes(entry[8..].try_into().unwrap());
+                match offset.checked_add(len) {
+                    Some(end)
+                        if offset >= HEADER_LEN && end <= index_offset && len <= chunk_size =>
+                    {
+                        Ok(ChunkDescriptor { offset, len })
+                    }
+                    _ => Err(CompressionError::InvalidFile("chunk out of bounds")),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            file,
+            layout,
+            chunks,
+        })
+    }
+
+    /// Size of the uncompressed chunks.
+    pub fn chunk_size(&self) -> u64 {
+        self.layout.chunk_size
+    }
+
+    /// Size of the uncompressed guest memory.
+    pub fn memory_size(&self) -> u64 {
+        self.layout.memory_size
+    }
+
+    /// Reads `buf.len()` bytes of uncompressed guest memory starting at `offset`.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), CompressionError> {
+        chunked::read_at(self, buf, offset)
+    }
+
+    /// Populates `guest_memory`, created from `mem_state`, with the contents of the file.
+    pub fn restore(
+        &self,
+        guest_memory: &GuestMemoryMmap,
+        mem_state: &GuestMemoryState,
+    ) -> Result<(), CompressionError> {
+        chunked::restore(self, guest_memory, mem_state)
+    }
+}
+
+impl ChunkedMemoryFile for CompressedMemoryFile {
+    type Error = CompressionError;
+
+    fn layout(&self) -> ChunkLayout {
+        self.layout
+    }
+
+    fn out_of_bounds(offset: u64, len: u64) -> CompressionError {
+        CompressionError::OutOfBounds(offset, len)
+    }
+
+    /// Decompresses the chunk with the given index into `buf`, which must have the exact
+    /// uncompressed length of the chunk.
+    fn read_chunk(&self, chunk_index: u64, buf: &mut [u8]) -> Result<(), CompressionError> {
+        let descriptor = self.chunks[u64_to_usize(chunk_index)];
+        if descriptor.len == 0 {
+            buf.fill(0);
+        } else if descriptor.len == buf.len() as u64 {
+            self.file.read_exact_at(buf, descriptor.offset)?;
+        } else {
+            let mut compressed = vec![0u8; u64_to_usize(descriptor.len)];
+            self.file
+                .read_exact_at(&mut compressed, descriptor.offset)?;
+            let decompressed = lz4_flex::block::decompress_into(&compressed, buf)
+                .map_err(|err| CompressionError::Decompress(chunk_index, err))?;
+            if decompressed != buf.len() {
+                return Err(CompressionError::InvalidFile("truncated chunk"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::tempfile::TempFile;
+
+    use super::*;
+    use crate::snapshot::chunked::MAX_CHUNK_SIZE;
+    use crate::vmm_config::machine_config::HugePageConfig;
+    use crate::vstate::memory::{GuestAddress, GuestMemoryExtension};
+
+    fn create_test_memory() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_raw_regions(
+            &[(GuestAddress(0), 0x3000), (GuestAddress(0x10000), 0x5000)],
+            false,
+            HugePageConfig::None,
+        )
+        .unwrap()
+    }
+
+    fn compress_to_file(guest_memory: &GuestMemoryMmap, chunk_size: u64) -> File {
+        let memory_file = TempFile::new().unwrap();
+        let mut file = memory_file.into_file();
+        compress_memory(guest_memory, &mut file, chunk_size).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_compress_restore() {
+        let guest_memory = create_test_memory();
+        // A compressible pattern in the first region, pseudo-random data straddling both regions
+        // and zeroes everywhere else.
+        guest_memory
+            .write_slice(&[0xAB; 0x1800], GuestAddress(0))
+            .unwrap();
+        let random: Vec<u8> = (0u32..0x2000)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13).to_le_bytes()[0])
+            .collect();
+        guest_memory
+            .write_slice(&random[..0x1000], GuestAddress(0x2000))
+            .unwrap();
+        guest_memory
+            .write_slice(&random[0x1000..], GuestAddress(0x10000))
+            .unwrap();
+
+        // A chunk size which does not divide the region sizes.
+        let file = compress_to_file(&guest_memory, 0x1800);
+        assert!(CompressedMemoryFile::is_compressed(&file).unwrap());
+        let compressed = CompressedMemoryFile::open(file).unwrap();
+        assert_eq!(compressed.memory_size(), 0x8000);
+        assert_eq!(compressed.chunk_size(), 0x1800);
+
+        let mem_state = guest_memory.describe();
+        let restored_memory = create_test_memory();
+        compressed.restore(&restored_memory, &mem_state).unwrap();
+
+        for (region, restored_region) in guest_memory.iter().zip(restored_memory.iter()) {
+            let mut expected = vec![0u8; u64_to_usize(region.len())];
+            let mut actual = vec![0u8; u64_to_usize(region.len())];
+            region
+                .read_slice(&mut expected, MemoryRegionAddress(0))
+                .unwrap();
+            restored_region
+                .read_slice(&mut actual, MemoryRegionAddress(0))
+                .unwrap();
+            assert_eq!(expected, actual);
+        }
+
+        // Random access across chunks.
+        let mut buf = vec![0u8; 0x100];
+        compressed.read_at(&mut buf, 0x2F80).unwrap();
+        assert_eq!(buf[..0x80], random[0xF80..0x1000]);
+        assert_eq!(buf[0x80..], random[0x1000..0x1080]);
+        compressed.read_at(&mut buf, 0x7F80).unwrap_err();
+    }
+
+    #[test]
+    fn test_compressed_size() {
+        let guest_memory = create_test_memory();
+        let file = compress_to_file(&guest_memory, DEFAULT_CHUNK_SIZE);
+        // Zero chunks only cost their index entry.
+        assert_eq!(
+            file.metadata().unwrap().len(),
+            HEADER_LEN + INDEX_ENTRY_LEN + FOOTER_LEN
+        );
+    }
+
+    #[test]
+    fn test_invalid_file() {
+        let file = TempFile::new().unwrap().into_file();
+        assert!(!CompressedMemoryFile::is_compressed(&file).unwrap());
+        CompressedMemoryFile::open(file).unwrap_err();
+
+        // Corrupt the chunk index offset.
+        let file = compress_to_file(&create_test_memory(), 0x1000);
+        let file_len = file.metadata().unwrap().len();
+        file.write_all_at(&u64::MAX.to_le_bytes(), file_len - FOOTER_LEN)
+            .unwrap();
+        assert!(matches!(
+            CompressedMemoryFile::open(file),
+            Err(CompressionError::InvalidFile(_))
+        ));
+
+        // Corrupt the chunk size, which must be rejected before any chunk buffer is allocated.
+        for chunk_size in [0, MAX_CHUNK_SIZE + 1, u64::MAX] {
+            let file = compress_to_file(&create_test_memory(), 0x1000);
+            file.write_all_at(&chunk_size.to_le_bytes(), 8).unwrap();
+            assert!(matches!(
+                CompressedMemoryFile::open(file),
+                Err(CompressionError::InvalidFile(_))
+            ));
+        }
+        let mut writer = Vec::new();
+        assert!(matches!(
+            compress_memory(&create_test_memory(), &mut writer, MAX_CHUNK_SIZE + 1),
+            Err(CompressionError::InvalidFile(_))
+        ));
+    }
+}
@@ -0,0 +1,645 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements authenticated encryption of snapshot files.
+//!
+//! Both the microVM state file and the guest memory file are encrypted using AES-256-GCM with
+//! a key provided by the user.
+//!
+//! An encrypted microVM state file wraps a regular snapshot (header, state and CRC64). The
+//! magic_id is stored in plaintext and authenticated along with the encrypted snapshot:
+//!
+//!  |-----------------------------|
+//!  |        64 bit magic_id      |
+//!  |-----------------------------|
+//!  |         96 bit nonce        |
+//!  |-----------------------------|
+//!  |      encrypted snapshot     |
+//!  |-----------------------------|
+//!  |       128 bit GCM tag       |
+//!  |-----------------------------|
+//!
+//! An encrypted guest memory file is split into fixed-size chunks (the last one may be shorter)
+//! which are encrypted independently, so that any range of guest memory can be decrypted without
+//! reading the whole file:
+//!
+//!  |-----------------------------------------|
+//!  |              magic_id                   |
+//!  |-----------------------------------------|
+//!  |       chunk_size     |   memory_size    |
+//!  |-----------------------------------------|
+//!  |           64 bit random file_id         |
+//!  |-----------------------------------------|
+//!  |    encrypted chunk 0  |  GCM tag 0      |
+//!  |-----------------------------------------|
+//!  |                  ...                    |
+//!  |-----------------------------------------|
+//!
+//! The nonce of a chunk is made of the file id followed by the chunk index, and the header is
+//! authenticated along with every chunk, so chunks cannot be reordered, dropped or moved between
+//! files without failing authentication. Chunks are read through the shared
+//! [`chunked`](super::chunked) logic.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::RawFd;
+
+use aes_gcm::{AeadInPlace, Aes256Gcm, Key, KeyInit, Nonce, Tag};
+use aws_lc_rs::rand;
+use base64::Engine;
+use utils::u64_to_usize;
+use vm_memory::GuestMemoryError;
+
+use super::chunked::{self, ChunkLayout, ChunkedMemoryFile};
+use crate::snapshot::SnapshotError;
+use crate::vmm_config::snapshot::SnapshotEncryptionConfig;
+use crate::vstate::memory::{
+    Bytes, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, GuestMemoryState, MemoryRegionAddress,
+};
+
+/// Magic value identifying an encrypted microVM state file ("FCSNPENC").
+pub const ENCRYPTED_SNAPSHOT_MAGIC_ID: u64 = u64::from_le_bytes(*b"FCSNPENC");
+/// Magic value identifying an encrypted guest memory file ("FCMEMENC").
+pub const ENCRYPTED_MEMORY_MAGIC_ID: u64 = u64::from_le_bytes(*b"FCMEMENC");
+/// Size of the chunks guest memory is split into by default.
+pub const DEFAULT_ENCRYPTED_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Length of the encryption key.
+pub const KEY_LEN: usize = 32;
+/// Length of the AES-GCM nonce.
+const NONCE_LEN: usize = 12;
+/// Length of the AES-GCM authentication tag.
+const TAG_LEN: usize = 16;
+/// Length of the header of an encrypted guest memory file.
+const MEMORY_HEADER_LEN: u64 = 32;
+
+/// Errors associated with snapshot encryption keys.
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum EncryptionKeyError {
+    /// Exactly one of `key` and `key_fd` must be provided.
+    InvalidConfig,
+    /// Invalid base64 encoding of the encryption key: {0}
+    Base64(base64::DecodeError),
+    /// Invalid encryption key length: expected 32 bytes, got {0}.
+    InvalidLength(usize),
+    /// Cannot read the encryption key from its file descriptor: {0}
+    ReadFd(io::Error),
+}
+
+/// Errors associated with encrypted guest memory files.
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum EncryptionError {
+    /// Cannot access the memory file: {0}
+    Io(#[from] io::Error),
+    /// Cannot access guest memory: {0}
+    GuestMemory(#[from] GuestMemoryError),
+    /// Cannot generate the memory file id: {0}
+    Random(aws_lc_rs::error::Unspecified),
+    /// Cannot encrypt memory chunk {0}.
+    Encrypt(u64),
+    /// Authentication of memory chunk {0} failed: the encryption key is wrong or the memory file
+    /// was tampered with.
+    Authentication(u64),
+    /// Invalid encrypted memory file: {0}
+    InvalidFile(&'static str),
+    /// Range {0:#x}+{1:#x} is out of the bounds of the encrypted memory.
+    OutOfBounds(u64, u64),
+}
+
+/// A 256-bit key used to encrypt and authenticate snapshot files.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; KEY_LEN]);
+
+// The key must never end up in logs.
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+impl EncryptionKey {
+    /// Creates a key from its raw bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EncryptionKeyError> {
+        bytes
+            .try_into()
+            .map(Self)
+            .map_err(|_| EncryptionKeyError::InvalidLength(bytes.len()))
+    }
+
+    /// Obtains the key described by `config`, either by decoding it or by reading it from the
+    /// given file descriptor.
+    pub fn from_config(config: &SnapshotEncryptionConfig) -> Result<Self, EncryptionKeyError> {
+        Self::from_source(config.key.as_deref(), config.key_fd)
+    }
+
+    /// Obtains the key from exactly one of its base64 encoding `key` and the file descriptor
+    /// `key_fd` it can be read from.
+    pub(crate) fn from_source(
+        key: Option<&str>,
+        key_fd: Option<RawFd>,
+    ) -> Result<Self, EncryptionKeyError> {
+        match (key, key_fd) {
+            (Some(key), None) => {
+                let bytes = base64::engine::general_purpose::STANDARD
+                    .decode(key)
+                    .map_err(EncryptionKeyError::Base64)?;
+                Self::from_bytes(&bytes)
+            }
+            (None, Some(fd)) => Self::from_fd(fd),
+            _ => Err(EncryptionKeyError::InvalidConfig),
+        }
+    }
+
+    /// Reads the key from `fd`, which is left open.
+    fn from_fd(fd: RawFd) -> Result<Self, EncryptionKeyError> {
+        let mut key = [0u8; KEY_LEN];
+        utils::read_exact_from_fd(fd, &mut key).map_err(EncryptionKeyError::ReadFd)?;
+        Ok(Self(key))
+    }
+
+    /// Returns the raw bytes of the key.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.0))
+    }
+}
+
+/// Returns whether `snapshot` is the contents of an encrypted microVM state file.
+pub(crate) fn is_encrypted_snapshot(snapshot: &[u8]) -> bool {
+    snapshot.get(..8) == Some(&ENCRYPTED_SNAPSHOT_MAGIC_ID.to_le_bytes()[..])
+}
+
+/// Encrypts the serialized `snapshot`, returning the contents of the encrypted state file.
+pub(crate) fn encrypt_snapshot(
+    mut snapshot: Vec<u8>,
+    key: &EncryptionKey,
+) -> Result<Vec<u8>, SnapshotError> {
+    let magic = ENCRYPTED_SNAPSHOT_MAGIC_ID.to_le_bytes();
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::fill(&mut nonce).map_err(|_| SnapshotError::Encryption)?;
+
+    let tag = key
+        .cipher()
+        .encrypt_in_place_detached(Nonce::from_slice(&nonce), &magic, &mut snapshot)
+        .map_err(|_| SnapshotError::Encryption)?;
+
+    let mut encrypted = Vec::with_capacity(magic.len() + NONCE_LEN + snapshot.len() + TAG_LEN);
+    encrypted.extend_from_slice(&magic);
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend_from_slice(&snapshot);
+    encrypted.extend_from_slice(&tag);
+    Ok(encrypted)
+}
+
+/// Authenticates and decrypts the contents of an encrypted state file, returning the serialized
+/// snapshot.
+pub(crate) fn decrypt_snapshot(
+    mut encrypted: Vec<u8>,
+    key: &EncryptionKey,
+) -> Result<Vec<u8>, SnapshotError> {
+    if !is_encrypted_snapshot(&encrypted) {
+        return Err(SnapshotError::NotEncrypted);
+    }
+    let magic_len = std::mem::size_of::<u64>();
+    if encrypted.len() < magic_len + NONCE_LEN + TAG_LEN {
+        return Err(SnapshotError::InvalidSnapshotSize);
+    }
+
+    let tag_offset = encrypted.len() - TAG_LEN;
+    let (header, rest) = encrypted.split_at_mut(magic_len + NONCE_LEN);
+    let (snapshot, tag) = rest.split_at_mut(tag_offset - header.len());
+    let (magic, nonce) = header.split_at(magic_len);
+    key.cipher()
+        .decrypt_in_place_detached(
+            Nonce::from_slice(nonce),
+            magic,
+            snapshot,
+            Tag::from_slice(tag),
+        )
+        .map_err(|_| SnapshotError::Authentication)?;
+
+    encrypted.truncate(tag_offset);
+    encrypted.drain(..magic_len + NONCE_LEN);
+    Ok(encrypted)
+}
+
+/// Header of an encrypted guest memory file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MemoryFileHeader {
+    layout: ChunkLayout,
+    file_id: [u8; 8],
+}
+
+impl MemoryFileHeader {
+    fn to_bytes(self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes[..8].copy_from_slice(&ENCRYPTED_MEMORY_MAGIC_ID.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.layout.chunk_size.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.layout.memory_size.to_le_bytes());
+        bytes[24..].copy_from_slice(&self.file_id);
+        bytes
+    }
+
+    fn nonce(&self, chunk_index: u64) -> Result<[u8; NONCE_LEN], EncryptionError> {
+        let chunk_index = u32::try_from(chunk_index)
+            .map_err(|_| EncryptionError::InvalidFile("too many chunks"))?;
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[..8].copy_from_slice(&self.file_id);
+        nonce[8..].copy_from_slice(&chunk_index.to_le_bytes());
+        Ok(nonce)
+    }
+}
+
+/// Encrypts the contents of `guest_memory`, in region order, and writes them to `writer`.
+pub fn encrypt_memory<W: Write>(
+    guest_memory: &GuestMemoryMmap,
+    writer: &mut W,
+    key: &EncryptionKey,
+    chunk_size: u64,
+) -> Result<(), EncryptionError> {
+    let memory_size: u64 = guest_memory.iter().map(|region| region.len()).sum();
+    let mut header = MemoryFileHeader {
+        layout: ChunkLayout::new(chunk_size, memory_size).map_err(EncryptionError::InvalidFile)?,
+        file_id: [0u8; 8],
+    };
+    rand::fill(&mut header.file_id).map_err(EncryptionError::Random)?;
+    // Fail early rather than after writing most of the file.
+    header.nonce(header.layout.chunk_count().saturating_sub(1))?;
+
+    let aad = header.to_bytes();
+    writer.write_all(&aad)?;
+
+    let cipher = key.cipher();
+    let mut chunk_index = 0;
+    let mut chunk = Vec::with_capacity(u64_to_usize(chunk_size));
+    let mut write_chunk = |chunk: &mut Vec<u8>, writer: &mut W| -> Result<(), EncryptionError> {
+        let nonce = header.nonce(chunk_index)?;
+        let tag = cipher
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), &aad, chunk)
+            .map_err(|_| EncryptionError::Encrypt(chunk_index))?;
+        writer.write_all(chunk)?;
+        writer.write_all(&tag)?;
+        chunk_index += 1;
+        chunk.clear();
+        Ok(())
+    };
+
+    for region in guest_memory.iter() {
+        let mut region_offset = 0;
+        while region_offset < region.len() {
+            let filled = chunk.len() as u64;
+            let len = (chunk_size - filled).min(region.len() - region_offset);
+            chunk.resize(u64_to_usize(filled + len), 0);
+            region.read_slice(
+                &mut chunk[u64_to_usize(filled)..],
+                MemoryRegionAddress(region_offset),
+            )?;
+            region_offset += len;
+
+            if chunk.len() as u64 == chunk_size {
+                write_chunk(&mut chunk, writer)?;
+            }
+        }
+    }
+    if !chunk.is_empty() {
+        write_chunk(&mut chunk, writer)?;
+    }
+    Ok(())
+}
+
+/// An encrypted guest memory file, opened for reading.
+pub struct EncryptedMemoryFile {
+    file: File,
+    cipher: Aes256Gcm,
+    header: MemoryFileHeader,
+}
+
+impl fmt::Debug for EncryptedMemoryFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedMemoryFile")
+            .field("file", &self.file)
+            .field("header", &self.header)
+            .finish()
+    }
+}
+
+impl EncryptedMemoryFile {
+    /// Returns whether `file` is an encrypted guest memory file.
+    pub fn is_encrypted(file: &File) -> Result<bool, io::Error> {
+        if file.metadata()?.len() < MEMORY_HEADER_LEN {
+            return Ok(false);
+        }
+        let mut magic = [0u8; 8];
+        file.read_exact_at(&mut magic, 0)?;
+        Ok(u64::from_le_bytes(magic) == ENCRYPTED_MEMORY_MAGIC_ID)
+    }
+
+    /// Opens an encrypted guest memory file, validating its header.
+    ///
+    /// The chunks are authenticated as they are read.
+    pub fn open(file: File, key: &EncryptionKey) -> Result<Self, EncryptionError> {
+        if !Self::is_encrypted(&file)? {
+            return Err(EncryptionError::InvalidFile("missing magic id"));
+        }
+        let mut bytes = [0u8; 32];
+        file.read_exact_at(&mut bytes, 0)?;
+        // The slices have the exact length of the arrays, so conversions cannot fail.
+        let layout = ChunkLayout::new(
+            u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+        )
+        .map_err(EncryptionError::InvalidFile)?;
+        let header = MemoryFileHeader {
+            layout,
+            file_id: bytes[24..].try_into().unwrap(),
+        };
+
+        let expected_len = layout
+            .chunk_count()
+            .checked_mul(TAG_LEN as u64)
+            .and_then(|tags_len| tags_len.checked_add(layout.memory_size))
+            .and_then(|len| len.checked_add(MEMORY_HEADER_LEN));
+        if expected_len != Some(file.metadata()?.len()) {
+            return Err(EncryptionError::InvalidFile("unexpected file size"));
+        }
+
+        Ok(Self {
+            file,
+            cipher: key.cipher(),
+            header,
+        })
+    }
+
+    /// Size of the chunks guest memory is split into.
+    pub fn chunk_size(&self) -> u64 {
+        self.header.layout.chunk_size
+    }
+
+    /// Size of the decrypted guest memory.
+    pub fn memory_size(&self) -> u64 {
+        self.header.layout.memory_size
+    }
+
+    /// Reads `buf.len()` bytes of decrypted guest memory starting at `offset`.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), EncryptionError> {
+        chunked::read_at(self, buf, offset)
+    }
+
+    /// Populates `guest_memory`, created from `mem_state`, with the decrypted contents of the
+    /// file.
+    pub fn restore(
+        &self,
+        guest_memory: &GuestMemoryMmap,
+        mem_state: &GuestMemoryState,
+    ) -> Result<(), EncryptionError> {
+        chunked::restore(self, guest_memory, mem_state)
+    }
+}
+
+impl ChunkedMemoryFile for EncryptedMemoryFile {
+    type Error = EncryptionError;
+
+    fn layout(&self) -> ChunkLayout {
+        self.header.layout
+    }
+
+    fn out_of_bounds(offset: u64, len: u64) -> EncryptionError {
+        EncryptionError::OutOfBounds(offset, len)
+    }
+
+    /// Authenticates and decrypts the chunk with the given index into `buf`, which must have the
+    /// exact length of the chunk.
+    fn read_chunk(&self, chunk_index: u64, buf: &mut [u8]) -> Result<(), EncryptionError> {
+        let chunk_offset =
+            MEMORY_HEADER_LEN + chunk_index * (self.header.layout.chunk_size + TAG_LEN as u64);
+        let mut tag = [0u8; TAG_LEN];
+        self.file.read_exact_at(buf, chunk_offset)?;
+        self.file
+            .read_exact_at(&mut tag, chunk_offset + buf.len() as u64)?;
+
+        let nonce = self.header.nonce(chunk_index)?;
+        self.cipher
+            .decrypt_in_place_detached(
+                Nonce::from_slice(&nonce),
+                &self.header.to_bytes(),
+                buf,
+                Tag::from_slice(&tag),
+            )
+            .map_err(|_| EncryptionError::Authentication(chunk_index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Seek;
+    use std::os::unix::io::AsRawFd;
+
+    use utils::tempfile::TempFile;
+
+    use super::*;
+    use crate::snapshot::chunked::MAX_CHUNK_SIZE;
+    use crate::vmm_config::machine_config::HugePageConfig;
+    use crate::vstate::memory::{GuestAddress, GuestMemoryExtension};
+
+    fn create_test_memory() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_raw_regions(
+            &[(GuestAddress(0), 0x3000), (GuestAddress(0x10000), 0x5000)],
+            false,
+            HugePageConfig::None,
+        )
+        .unwrap()
+    }
+
+    fn encrypt_to_file(guest_memory: &GuestMemoryMmap, key: &EncryptionKey) -> File {
+        let mut file = TempFile::new().unwrap().into_file();
+        encrypt_memory(guest_memory, &mut file, key, 0x1800).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_encryption_key() {
+        let config = SnapshotEncryptionConfig {
+            key: Some(base64::engine::general_purpose::STANDARD.encode([0xAA; KEY_LEN])),
+            key_fd: None,
+        };
+        assert_eq!(
+            EncryptionKey::from_config(&config).unwrap(),
+            EncryptionKey([0xAA; KEY_LEN])
+        );
+        assert_eq!(
+            format!("{:?}", EncryptionKey([0xAA; KEY_LEN])),
+            "EncryptionKey(..)"
+        );
+
+        let config = SnapshotEncryptionConfig {
+            key: Some(base64::engine::general_purpose::STANDARD.encode([0xAA; 16])),
+            key_fd: None,
+        };
+        assert!(matches!(
+            EncryptionKey::from_config(&config),
+            Err(EncryptionKeyError::InvalidLength(16))
+        ));
+
+        let config = SnapshotEncryptionConfig {
+            key: None,
+            key_fd: None,
+        };
+        assert!(matches!(
+            EncryptionKey::from_config(&config),
+            Err(EncryptionKeyError::InvalidConfig)
+        ));
+
+        let key_file = TempFile::new().unwrap();
+        key_file.as_file().write_all(&[0x55; KEY_LEN]).unwrap();
+        key_file.as_file().rewind().unwrap();
+        let config = SnapshotEncryptionConfig {
+            key: None,
+            key_fd: Some(key_file.as_file().as_raw_fd()),
+        };
+        assert_eq!(
+            EncryptionKey::from_config(&config).unwrap(),
+            EncryptionKey([0x55; KEY_LEN])
+        );
+        // The whole key was consumed.
+        assert!(matches!(
+            EncryptionKey::from_config(&config),
+            Err(EncryptionKeyError::ReadFd(_))
+        ));
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_snapshot() {
+        let key = EncryptionKey([0x42; KEY_LEN]);
+        let snapshot = b"microvm state".to_vec();
+
+        let encrypted = encrypt_snapshot(snapshot.clone(), &key).unwrap();
+        assert!(is_encrypted_snapshot(&encrypted));
+        assert_eq!(decrypt_snapshot(encrypted.clone(), &key).unwrap(), snapshot);
+
+        // Wrong key.
+        assert_eq!(
+            decrypt_snapshot(encrypted.clone(), &EncryptionKey([0x43; KEY_LEN])),
+            Err(SnapshotError::Authentication)
+        );
+        // Tampered contents.
+        let mut tampered = encrypted.clone();
+        tampered[NONCE_LEN + 8] ^= 1;
+        assert_eq!(
+            decrypt_snapshot(tampered, &key),
+            Err(SnapshotError::Authentication)
+        );
+        // Truncated file.
+        assert_eq!(
+            decrypt_snapshot(encrypted[..10].to_vec(), &key),
+            Err(SnapshotError::InvalidSnapshotSize)
+        );
+        // Not encrypted.
+        assert_eq!(
+            decrypt_snapshot(snapshot, &key),
+            Err(SnapshotError::NotEncrypted)
+        );
+    }
+
+    #[test]
+    fn test_encrypt_restore_memory() {
+        let key = EncryptionKey([0x42; KEY_LEN]);
+        let guest_memory = create_test_memory();
+        guest_memory
+            .write_slice(&[0xAB; 0x1800], GuestAddress(0x1000))
+            .unwrap();
+        guest_memory
+            .write_slice(&[0xCD; 0x1800], GuestAddress(0x12000))
+            .unwrap();
+
+        let file = encrypt_to_file(&guest_memory, &key);
+        assert!(EncryptedMemoryFile::is_encrypted(&file).unwrap());
+        // The plaintext must not be visible in the file.
+        let mut contents = vec![0u8; u64_to_usize(file.metadata().unwrap().len())];
+        file.read_exact_at(&mut contents, 0).unwrap();
+        assert!(!contents.windows(16).any(|window| window == [0xAB; 16]));
+
+        let encrypted = EncryptedMemoryFile::open(file, &key).unwrap();
+        assert_eq!(encrypted.memory_size(), 0x8000);
+        assert_eq!(encrypted.chunk_size(), 0x1800);
+
+        let restored_memory = create_test_memory();
+        encrypted
+            .restore(&restored_memory, &guest_memory.describe())
+            .unwrap();
+        for (region, restored_region) in guest_memory.iter().zip(restored_memory.iter()) {
+            let mut expected = vec![0u8; u64_to_usize(region.len())];
+            let mut actual = vec![0u8; u64_to_usize(region.len())];
+            region
+                .read_slice(&mut expected, MemoryRegionAddress(0))
+                .unwrap();
+            restored_region
+                .read_slice(&mut actual, MemoryRegionAddress(0))
+                .unwrap();
+            assert_eq!(expected, actual);
+        }
+        encrypted.read_at(&mut [0u8; 0x100], 0x7F80).unwrap_err();
+    }
+
+    #[test]
+    fn test_memory_authentication() {
+        let key = EncryptionKey([0x42; KEY_LEN]);
+        let guest_memory = create_test_memory();
+        let mut buf = [0u8; 0x100];
+
+        // Wrong key.
+        let file = encrypt_to_file(&guest_memory, &key);
+        let encrypted = EncryptedMemoryFile::open(file, &EncryptionKey([0x43; KEY_LEN])).unwrap();
+        assert!(matches!(
+            encrypted.read_at(&mut buf, 0),
+            Err(EncryptionError::Authentication(0))
+        ));
+
+        // Tampered chunk.
+        let file = encrypt_to_file(&guest_memory, &key);
+        let chunk_offset = MEMORY_HEADER_LEN + 0x1800 + TAG_LEN as u64;
+        file.write_all_at(&[0xFF], chunk_offset).unwrap();
+        let encrypted = EncryptedMemoryFile::open(file, &key).unwrap();
+        encrypted.read_at(&mut buf, 0).unwrap();
+        assert!(matches!(
+            encrypted.read_at(&mut buf, 0x1800),
+            Err(EncryptionError::Authentication(1))
+        ));
+
+        // Tampered header.
+        let file = encrypt_to_file(&guest_memory, &key);
+        file.write_all_at(&[0xFF], 24).unwrap();
+        let encrypted = EncryptedMemoryFile::open(file, &key).unwrap();
+        assert!(matches!(
+            encrypted.read_at(&mut buf, 0),
+            Err(EncryptionError::Authentication(0))
+        ));
+
+        // Truncated file.
+        let file = encrypt_to_file(&guest_memory, &key);
+        file.set_len(file.metadata().unwrap().len() - 1).unwrap();
+        assert!(matches!(
+            EncryptedMemoryFile::open(file, &key),
+            Err(EncryptionError::InvalidFile(_))
+        ));
+
+        // Tampered chunk size, which must be rejected before any chunk buffer is allocated.
+        for chunk_size in [0, MAX_CHUNK_SIZE + 1, u64::MAX] {
+            let file = encrypt_to_file(&guest_memory, &key);
+            file.write_all_at(&chunk_size.to_le_bytes(), 8).unwrap();
+            assert!(matches!(
+                EncryptedMemoryFile::open(file, &key),
+                Err(EncryptionError::InvalidFile(_))
+            ));
+        }
+        let mut writer = Vec::new();
+        assert!(matches!(
+            encrypt_memory(&guest_memory, &mut writer, &key, MAX_CHUNK_SIZE + 1),
+            Err(EncryptionError::InvalidFile(_))
+        ));
+    }
+}
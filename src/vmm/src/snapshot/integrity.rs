@@ -0,0 +1,334 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements integrity manifests of snapshots.
+//!
+//! The microVM state file is only protected against accidental corruption by its CRC64, and the
+//! guest memory file is not protected at all. A manifest, written next to the snapshot files,
+//! records the SHA-256 hash of every fixed-size chunk (the last one may be shorter) of the guest
+//! memory file as it is stored, and an HMAC-SHA256 computed with a key provided by the user over:
+//!
+//!  |-----------------------------------------|
+//!  |       contents of the state file        |
+//!  |-----------------------------------------|
+//!  |   version  |  chunk_size  |  file_size  |
+//!  |-----------------------------------------|
+//!  |       hash 0     |  ...  |   hash n     |
+//!  |-----------------------------------------|
+//!
+//! Once the MAC is verified, chunks of the memory file can be verified independently, so that
+//! pages served on demand do not require hashing the whole file first.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use aws_lc_rs::{digest, hmac};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use utils::u64_to_usize;
+
+use crate::snapshot::encryption::{EncryptionKey, EncryptionKeyError};
+use crate::vmm_config::snapshot::SnapshotIntegrityConfig;
+
+/// Version of the manifest format.
+pub const MANIFEST_VERSION: u32 = 1;
+/// Size of the chunks the memory file is split into by default.
+pub const DEFAULT_MANIFEST_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Errors associated with snapshot integrity manifests.
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum IntegrityError {
+    /// Cannot access the manifest or snapshot files: {0}
+    Io(#[from] io::Error),
+    /// Cannot (de)serialize the manifest: {0}
+    Json(#[from] serde_json::Error),
+    /// Unsupported manifest version: {0}
+    UnsupportedVersion(u32),
+    /// Invalid manifest: {0}
+    InvalidManifest(&'static str),
+    /// Authentication of the manifest failed: the integrity key is wrong, or the manifest or the
+    /// microVM state file was tampered with.
+    Authentication,
+    /// The memory file is {1} bytes long, but the manifest expects {0} bytes.
+    MemoryFileSize(u64, u64),
+    /// Hash mismatch of memory file chunk {0}: the memory file was tampered with.
+    ChunkHash(u64),
+}
+
+/// Integrity manifest of a snapshot.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotManifest {
+    /// Version of the manifest format.
+    version: u32,
+    /// Size of the chunks the memory file is split into.
+    chunk_size: u64,
+    /// Size of the memory file.
+    memory_file_size: u64,
+    /// Base64 encoding of the SHA-256 hash of every chunk of the memory file.
+    chunk_hashes: Vec<String>,
+    /// Base64 encoding of the HMAC-SHA256 authenticating the manifest and the state file.
+    mac: String,
+}
+
+/// Obtains the key described by `config`, either by decoding it or by reading it from the given
+/// file descriptor.
+pub fn integrity_key(
+    config: &SnapshotIntegrityConfig,
+) -> Result<EncryptionKey, EncryptionKeyError> {
+    EncryptionKey::from_source(config.key.as_deref(), config.key_fd)
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(bytes)
+}
+
+fn decode(encoded: &str) -> Result<Vec<u8>, IntegrityError> {
+    base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|_| IntegrityError::InvalidManifest("invalid base64 encoding"))
+}
+
+impl SnapshotManifest {
+    /// Creates the manifest of the snapshot made of the state file contents `snapshot` and of
+    /// `memory_file`, split into chunks of `chunk_size` bytes.
+    pub fn create(
+        snapshot: &[u8],
+        memory_file: &File,
+        chunk_size: u64,
+        key: &EncryptionKey,
+    ) -> Result<Self, IntegrityError> {
+        if chunk_size == 0 {
+            return Err(IntegrityError::InvalidManifest("chunk size is 0"));
+        }
+        let memory_file_size = memory_file.metadata()?.len();
+        let mut manifest = Self {
+            version: MANIFEST_VERSION,
+            chunk_size,
+            memory_file_size,
+            chunk_hashes: Vec::new(),
+            mac: String::new(),
+        };
+
+        let mut buf = vec![0u8; u64_to_usize(chunk_size)];
+        for index in 0..manifest.chunk_count() {
+            let (offset, len) = manifest.chunk_bounds(index);
+            let chunk = &mut buf[..len];
+            memory_file.read_exact_at(chunk, offset)?;
+            manifest
+                .chunk_hashes
+                .push(encode(hash_chunk(chunk).as_ref()));
+        }
+        let mac = hmac::sign(&hmac_key(key), &manifest.authenticated_data(snapshot)?);
+        manifest.mac = encode(mac.as_ref());
+        Ok(manifest)
+    }
+
+    /// Reads the manifest stored at `path`.
+    pub fn load(path: &Path) -> Result<Self, IntegrityError> {
+        let manifest: Self = serde_json::from_slice(&std::fs::read(path)?)?;
+        if manifest.version != MANIFEST_VERSION {
+            return Err(IntegrityError::UnsupportedVersion(manifest.version));
+        }
+        if manifest.chunk_size == 0 {
+            return Err(IntegrityError::InvalidManifest("chunk size is 0"));
+        }
+        if manifest.chunk_hashes.len() as u64 != manifest.chunk_count() {
+            return Err(IntegrityError::InvalidManifest(
+                "chunk hashes do not cover the memory file",
+            ));
+        }
+        Ok(manifest)
+    }
+
+    /// Writes the manifest to `path`, replacing any existing file.
+    pub fn save(&self, path: &Path) -> Result<(), IntegrityError> {
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Size of the chunks the memory file is split into.
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+
+    /// Size of the memory file.
+    pub fn memory_file_size(&self) -> u64 {
+        self.memory_file_size
+    }
+
+    /// Number of chunks the memory file is split into.
+    pub fn chunk_count(&self) -> u64 {
+        self.memory_file_size.div_ceil(self.chunk_size)
+    }
+
+    /// Returns the offset in the memory file and the length of the chunk at `index`.
+    pub fn chunk_bounds(&self, index: u64) -> (u64, usize) {
+        let offset = index * self.chunk_size;
+        let len = self.chunk_size.min(self.memory_file_size - offset);
+        (offset, u64_to_usize(len))
+    }
+
+    /// Verifies the MAC of the manifest, which authenticates both the chunk hashes and the state
+    /// file contents `snapshot`. Chunks must only be verified against an authenticated manifest.
+    pub fn verify_mac(&self, snapshot: &[u8], key: &EncryptionKey) -> Result<(), IntegrityError> {
+        let mac = decode(&self.mac)?;
+        hmac::verify(&hmac_key(key), &self.authenticated_data(snapshot)?, &mac)
+            .map_err(|_| IntegrityError::Authentication)
+    }
+
+    /// Verifies that `data` holds the contents of the chunk at `index`.
+    pub fn verify_chunk(&self, index: u64, data: &[u8]) -> Result<(), IntegrityError> {
+        let expected = self
+            .chunk_hashes
+            .get(u64_to_usize(index))
+            .ok_or(IntegrityError::ChunkHash(index))?;
+        if decode(expected)? != hash_chunk(data).as_ref() {
+            return Err(IntegrityError::ChunkHash(index));
+        }
+        Ok(())
+    }
+
+    /// Verifies every chunk of `memory_file`.
+    pub fn verify_memory_file(&self, memory_file: &File) -> Result<(), IntegrityError> {
+        let file_size = memory_file.metadata()?.len();
+        if file_size != self.memory_file_size {
+            return Err(IntegrityError::MemoryFileSize(
+                self.memory_file_size,
+                file_size,
+            ));
+        }
+        let mut buf = vec![0u8; u64_to_usize(self.chunk_size)];
+        for index in 0..self.chunk_count() {
+            let (offset, len) = self.chunk_bounds(index);
+            let chunk = &mut buf[..len];
+            memory_file.read_exact_at(chunk, offset)?;
+            self.verify_chunk(index, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the data covered by the MAC.
+    fn authenticated_data(&self, snapshot: &[u8]) -> Result<Vec<u8>, IntegrityError> {
+        let mut data = Vec::with_capacity(
+            snapshot.len() + 20 + self.chunk_hashes.len() * digest::SHA256_OUTPUT_LEN,
+        );
+        data.extend_from_slice(snapshot);
+        data.extend_from_slice(&self.version.to_le_bytes());
+        data.extend_from_slice(&self.chunk_size.to_le_bytes());
+        data.extend_from_slice(&self.memory_file_size.to_le_bytes());
+        for hash in &self.chunk_hashes {
+            data.extend_from_slice(&decode(hash)?);
+        }
+        Ok(data)
+    }
+}
+
+fn hash_chunk(data: &[u8]) -> digest::Digest {
+    digest::digest(&digest::SHA256, data)
+}
+
+fn hmac_key(key: &EncryptionKey) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use utils::tempfile::TempFile;
+
+    use super::*;
+
+    fn memory_file(contents: &[u8]) -> File {
+        let mut file = TempFile::new().unwrap().into_file();
+        file.write_all(contents).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_manifest() {
+        let key = EncryptionKey::from_bytes(&[7u8; 32]).unwrap();
+        let snapshot = b"microVM state";
+        let contents: Vec<u8> = (0..10_000u32)
+            .map(|i| u8::try_from(i % 251).unwrap())
+            .collect();
+        let file = memory_file(&contents);
+
+        let manifest = SnapshotManifest::create(snapshot, &file, 4096, &key).unwrap();
+        assert_eq!(manifest.chunk_count(), 3);
+        assert_eq!(manifest.chunk_bounds(2), (8192, 10_000 - 8192));
+        manifest.verify_mac(snapshot, &key).unwrap();
+        manifest.verify_memory_file(&file).unwrap();
+        manifest.verify_chunk(1, &contents[4096..8192]).unwrap();
+
+        // The manifest survives a round trip through its file.
+        let manifest_file = TempFile::new().unwrap();
+        manifest.save(manifest_file.as_path()).unwrap();
+        assert_eq!(
+            SnapshotManifest::load(manifest_file.as_path()).unwrap(),
+            manifest
+        );
+
+        // A wrong key or a modified state file fail authentication.
+        let other_key = EncryptionKey::from_bytes(&[8u8; 32]).unwrap();
+        assert!(matches!(
+            manifest.verify_mac(snapshot, &other_key),
+            Err(IntegrityError::Authentication)
+        ));
+        assert!(matches!(
+            manifest.verify_mac(b"microVM stat3", &key),
+            Err(IntegrityError::Authentication)
+        ));
+
+        // So does a manifest whose hashes were replaced.
+        let mut tampered = manifest.clone();
+        tampered.chunk_hashes.swap(0, 1);
+        assert!(matches!(
+            tampered.verify_mac(snapshot, &key),
+            Err(IntegrityError::Authentication)
+        ));
+
+        // Modified chunks are detected.
+        let mut modified = contents.clone();
+        modified[5000] ^= 1;
+        assert!(matches!(
+            manifest.verify_memory_file(&memory_file(&modified)),
+            Err(IntegrityError::ChunkHash(1))
+        ));
+        assert!(matches!(
+            manifest.verify_chunk(1, &modified[4096..8192]),
+            Err(IntegrityError::ChunkHash(1))
+        ));
+        assert!(matches!(
+            manifest.verify_memory_file(&memory_file(&contents[..8192])),
+            Err(IntegrityError::MemoryFileSize(10_000, 8192))
+        ));
+    }
+
+    #[test]
+    fn test_load_invalid_manifest() {
+        let key = EncryptionKey::from_bytes(&[7u8; 32]).unwrap();
+        let file = memory_file(&[1u8; 8192]);
+        let manifest = SnapshotManifest::create(b"state", &file, 4096, &key).unwrap();
+        let manifest_file = TempFile::new().unwrap();
+
+        let mut invalid = manifest.clone();
+        invalid.version = MANIFEST_VERSION + 1;
+        invalid.save(manifest_file.as_path()).unwrap();
+        assert!(matches!(
+            SnapshotManifest::load(manifest_file.as_path()),
+            Err(IntegrityError::UnsupportedVersion(_))
+        ));
+
+        let mut invalid = manifest;
+        invalid.chunk_hashes.pop();
+        invalid.save(manifest_file.as_path()).unwrap();
+        assert!(matches!(
+            SnapshotManifest::load(manifest_file.as_path()),
+            Err(IntegrityError::InvalidManifest(_))
+        ));
+    }
+}
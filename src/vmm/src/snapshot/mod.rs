@@ -25,8 +25,20 @@
 //!
 //! The snapshot format uses a version value in the form of `MAJOR.MINOR.PATCH`. The version is
 //! provided by the library clients (it is not tied to this crate).
+//!
+//! Snapshots can also be saved encrypted, in which case the whole layout above is encrypted and
+//! authenticated (see [`encryption`]). Both snapshot files can also be authenticated by an
+//! integrity manifest (see [`integrity`]).
+//!
+//! Snapshots written with an older format version can be upgraded by translating their state
+//! (see [`translation`]).
+pub(crate) mod chunked;
+pub mod compression;
 pub mod crc;
+pub mod encryption;
+pub mod integrity;
 mod persist;
+pub mod translation;
 use std::fmt::Debug;
 use std::io::{Read, Write};
 
@@ -36,7 +48,9 @@ use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
 use crate::snapshot::crc::{CRC64Reader, CRC64Writer};
+use crate::snapshot::encryption::EncryptionKey;
 pub use crate::snapshot::persist::Persist;
+use crate::snapshot::translation::TranslatorRegistry;
 
 #[cfg(target_arch = "x86_64")]
 const SNAPSHOT_MAGIC_ID: u64 = 0x0710_1984_8664_0000u64;
@@ -50,8 +64,13 @@ const SNAPSHOT_MAGIC_ID: u64 = 0x0710_1984_AAAA_0000u64;
 /// Error definitions for the Snapshot API.
 #[derive(Debug, thiserror::Error, displaydoc::Display, PartialEq)]
 pub enum SnapshotError {
+    /// Snapshot authentication failed: the encryption key is wrong or the snapshot file was
+    /// tampered with.
+    Authentication,
     /// CRC64 validation failed: {0}
     Crc64(u64),
+    /// Cannot encrypt snapshot.
+    Encryption,
     /// Invalid data version: {0}
     InvalidFormatVersion(Version),
     /// Magic value does not match arch: {0}
@@ -60,6 +79,10 @@ pub enum SnapshotError {
     InvalidSnapshotSize,
     /// An IO error occurred: {0}
     Io(i32),
+    /// Snapshot file is encrypted, but no encryption key was provided.
+    MissingEncryptionKey,
+    /// Snapshot file is not encrypted, but an encryption key was provided.
+    NotEncrypted,
     /// An error occured with serialization/deserialization: {0}
     Serde(String),
 }
@@ -73,6 +96,12 @@ struct SnapshotHdr {
     version: Version,
 }
 
+/// Checks whether a state written with format version `version` can be read by a snapshot of
+/// format version `supported`.
+fn is_compatible_version(version: &Version, supported: &Version) -> bool {
+    version.major == supported.major && version.minor <= supported.minor
+}
+
 impl SnapshotHdr {
     fn new(version: Version) -> Self {
         Self {
@@ -148,11 +177,10 @@ impl Snapshot {
         Ok((data, hdr.version))
     }
 
-    /// Load a snapshot from a reader and validate its CRC
-    pub fn load<T, O>(reader: &mut T, snapshot_len: usize) -> Result<(O, Version), SnapshotError>
+    /// Reads a snapshot from a reader, validates its CRC and returns it without the CRC.
+    fn read_with_crc_check<T>(reader: &mut T, snapshot_len: usize) -> Result<Vec<u8>, SnapshotError>
     where
         T: Read + Debug,
-        O: DeserializeOwned + Debug,
     {
         let mut crc_reader = CRC64Reader::new(reader);
 
@@ -166,6 +194,10 @@ impl Snapshot {
         crc_reader
             .read_exact(&mut snapshot)
             .map_err(|ref err| SnapshotError::Io(err.raw_os_error().unwrap_or(libc::EINVAL)))?;
+        // Encrypted snapshots would otherwise only fail the CRC check.
+        if encryption::is_encrypted_snapshot(&snapshot) {
+            return Err(SnapshotError::MissingEncryptionKey);
+        }
 
         // Since the reader updates the checksum as bytes ar being read from it, the order of these
         // 2 statements is important, we first get the checksum computed on the read bytes
@@ -176,7 +208,17 @@ impl Snapshot {
             return Err(SnapshotError::Crc64(computed_checksum));
         }
 
-        let mut snapshot_slice: &[u8] = snapshot.as_mut_slice();
+        Ok(snapshot)
+    }
+
+    /// Load a snapshot from a reader and validate its CRC
+    pub fn load<T, O>(reader: &mut T, snapshot_len: usize) -> Result<(O, Version), SnapshotError>
+    where
+        T: Read + Debug,
+        O: DeserializeOwned + Debug,
+    {
+        let snapshot = Self::read_with_crc_check(reader, snapshot_len)?;
+        let mut snapshot_slice: &[u8] = snapshot.as_slice();
         Snapshot::unchecked_load::<_, O>(&mut snapshot_slice)
     }
 
@@ -191,13 +233,59 @@ impl Snapshot {
         O: DeserializeOwned + Debug,
     {
         let (data, version) = Snapshot::load::<_, O>(reader, snapshot_len)?;
-        if version.major != self.version.major || version.minor > self.version.minor {
+        if !is_compatible_version(&version, &self.version) {
             Err(SnapshotError::InvalidFormatVersion(version))
         } else {
             Ok(data)
         }
     }
 
+    /// Load a snapshot from a reader object, validate its CRC and translate its state to the
+    /// snapshot format version using the `translators`.
+    ///
+    /// Returns the state along with the format version the snapshot was written with.
+    pub fn load_with_translation<T, O>(
+        &self,
+        reader: &mut T,
+        snapshot_len: usize,
+        translators: &TranslatorRegistry,
+    ) -> Result<(O, Version), SnapshotError>
+    where
+        T: Read + Debug,
+        O: DeserializeOwned + Debug,
+    {
+        let snapshot = Self::read_with_crc_check(reader, snapshot_len)?;
+        let mut snapshot_slice: &[u8] = snapshot.as_slice();
+        let hdr: SnapshotHdr = Self::deserialize(&mut snapshot_slice)?;
+        if hdr.magic != SNAPSHOT_MAGIC_ID {
+            return Err(SnapshotError::InvalidMagic(hdr.magic));
+        }
+
+        let state = translators.translate(snapshot_slice.to_vec(), &hdr.version, &self.version)?;
+        let data: O = Self::deserialize(&mut state.as_slice())?;
+        Ok((data, hdr.version))
+    }
+
+    /// Loads an encrypted snapshot from a reader object, authenticates and decrypts it using `key`
+    /// and performs a snapshot version check.
+    pub fn load_encrypted_with_version_check<T, O>(
+        &self,
+        reader: &mut T,
+        snapshot_len: usize,
+        key: &EncryptionKey,
+    ) -> Result<O, SnapshotError>
+    where
+        T: Read + Debug,
+        O: DeserializeOwned + Debug,
+    {
+        let mut encrypted = vec![0u8; snapshot_len];
+        reader
+            .read_exact(&mut encrypted)
+            .map_err(|ref err| SnapshotError::Io(err.raw_os_error().unwrap_or(libc::EINVAL)))?;
+        let snapshot = encryption::decrypt_snapshot(encrypted, key)?;
+        self.load_with_version_check(&mut snapshot.as_slice(), snapshot.len())
+    }
+
     /// Saves a snapshot and include a CRC64 checksum.
     pub fn save<T, O>(&self, writer: &mut T, object: &O) -> Result<(), SnapshotError>
     where
@@ -212,6 +300,25 @@ impl Snapshot {
         Self::serialize(&mut crc_writer, &checksum)
     }
 
+    /// Saves a snapshot, including a CRC64 checksum, encrypted and authenticated using `key`.
+    pub fn save_encrypted<T, O>(
+        &self,
+        writer: &mut T,
+        object: &O,
+        key: &EncryptionKey,
+    ) -> Result<(), SnapshotError>
+    where
+        T: Write + Debug,
+        O: Serialize + Debug,
+    {
+        let mut snapshot = Vec::new();
+        self.save(&mut snapshot, object)?;
+        let encrypted = encryption::encrypt_snapshot(snapshot, key)?;
+        writer
+            .write_all(&encrypted)
+            .map_err(|ref err| SnapshotError::Io(err.raw_os_error().unwrap_or(libc::EINVAL)))
+    }
+
     /// Save a snapshot with no CRC64 checksum included.
     pub fn save_without_crc<T, O>(
         &self,
@@ -325,6 +432,50 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_encrypted_snapshot() {
+        let key = EncryptionKey::from_bytes(&[0x42; encryption::KEY_LEN]).unwrap();
+        let snapshot = Snapshot::new(Version::new(1, 3, 12));
+        let mut data = Vec::new();
+        snapshot.save_encrypted(&mut data, &42u8, &key).unwrap();
+
+        assert_eq!(
+            snapshot
+                .load_encrypted_with_version_check::<_, u8>(&mut data.as_slice(), data.len(), &key)
+                .unwrap(),
+            42
+        );
+
+        // Loading without a key fails with a clear error rather than a CRC mismatch.
+        assert_eq!(
+            snapshot.load_with_version_check::<_, u8>(&mut data.as_slice(), data.len()),
+            Err(SnapshotError::MissingEncryptionKey)
+        );
+
+        let wrong_key = EncryptionKey::from_bytes(&[0x43; encryption::KEY_LEN]).unwrap();
+        assert_eq!(
+            snapshot.load_encrypted_with_version_check::<_, u8>(
+                &mut data.as_slice(),
+                data.len(),
+                &wrong_key
+            ),
+            Err(SnapshotError::Authentication)
+        );
+
+        // Plaintext snapshots are rejected when a key is provided, so they cannot be substituted
+        // for encrypted ones.
+        let mut plain_data = Vec::new();
+        snapshot.save(&mut plain_data, &42u8).unwrap();
+        assert_eq!(
+            snapshot.load_encrypted_with_version_check::<_, u8>(
+                &mut plain_data.as_slice(),
+                plain_data.len(),
+                &key
+            ),
+            Err(SnapshotError::NotEncrypted)
+        );
+    }
+
     #[test]
     fn test_bad_version() {
         let mut data = vec![0u8; 100];
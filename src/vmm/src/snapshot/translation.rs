@@ -0,0 +1,158 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Translation of snapshots written by older snapshot format versions.
+//!
+//! Bincode does not allow backwards compatible changes of the serialized state, so snapshots
+//! written with an older format version can not be deserialized into the current state
+//! structures. A [`TranslatorRegistry`] holds translators converting the serialized state of one
+//! format version into the serialized state of a newer one. Chaining them upgrades a snapshot to
+//! the current format version.
+
+use semver::Version;
+
+use super::{is_compatible_version, SnapshotError};
+
+/// Function translating a serialized state between two snapshot format versions.
+pub type TranslateFn = fn(Vec<u8>) -> Result<Vec<u8>, SnapshotError>;
+
+/// Translator of the serialized state of a snapshot format version into a newer one.
+#[derive(Debug)]
+struct Translator {
+    from: Version,
+    to: Version,
+    translate: TranslateFn,
+}
+
+/// Registry of the translators between snapshot format versions.
+#[derive(Debug, Default)]
+pub struct TranslatorRegistry {
+    translators: Vec<Translator>,
+}
+
+impl TranslatorRegistry {
+    /// Registers a translator of states written with format version `from` into states of the
+    /// newer format version `to`.
+    pub fn register(&mut self, from: Version, to: Version, translate: TranslateFn) {
+        self.translators.push(Translator {
+            from,
+            to,
+            translate,
+        });
+    }
+
+    /// Translates `state`, written with format version `version`, into a state that can be read
+    /// with the format version `target`, by chaining the registered translators.
+    pub fn translate(
+        &self,
+        mut state: Vec<u8>,
+        version: &Version,
+        target: &Version,
+    ) -> Result<Vec<u8>, SnapshotError> {
+        let mut version = version.clone();
+        while !is_compatible_version(&version, target) {
+            let translator = self
+                .translators
+                .iter()
+                // Only translating to newer versions guarantees that the chain terminates.
+                .find(|t| is_compatible_version(&version, &t.from) && t.to > version)
+                .ok_or_else(|| SnapshotError::InvalidFormatVersion(version.clone()))?;
+            state = (translator.translate)(state)?;
+            version.clone_from(&translator.to);
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::Snapshot;
+
+    fn append_byte(mut state: Vec<u8>) -> Result<Vec<u8>, SnapshotError> {
+        state.push(7);
+        Ok(state)
+    }
+
+    fn fail(_: Vec<u8>) -> Result<Vec<u8>, SnapshotError> {
+        Err(SnapshotError::Serde("translation failed".to_string()))
+    }
+
+    #[test]
+    fn test_translate() {
+        let mut translators = TranslatorRegistry::default();
+        translators.register(Version::new(1, 0, 0), Version::new(2, 0, 0), append_byte);
+        translators.register(Version::new(2, 0, 0), Version::new(3, 1, 0), append_byte);
+
+        // Compatible versions are not translated.
+        assert_eq!(
+            translators
+                .translate(vec![42], &Version::new(3, 0, 2), &Version::new(3, 1, 0))
+                .unwrap(),
+            vec![42]
+        );
+        // Translators are chained.
+        assert_eq!(
+            translators
+                .translate(vec![42], &Version::new(1, 0, 1), &Version::new(3, 1, 0))
+                .unwrap(),
+            vec![42, 7, 7]
+        );
+        assert_eq!(
+            translators
+                .translate(vec![42], &Version::new(2, 0, 0), &Version::new(3, 1, 0))
+                .unwrap(),
+            vec![42, 7]
+        );
+        // There is no translator from 0.x.
+        assert_eq!(
+            translators.translate(vec![42], &Version::new(0, 1, 0), &Version::new(3, 1, 0)),
+            Err(SnapshotError::InvalidFormatVersion(Version::new(0, 1, 0)))
+        );
+        // Snapshots newer than the target can not be translated.
+        assert_eq!(
+            translators.translate(vec![42], &Version::new(3, 2, 0), &Version::new(3, 1, 0)),
+            Err(SnapshotError::InvalidFormatVersion(Version::new(3, 2, 0)))
+        );
+
+        translators.register(Version::new(0, 1, 0), Version::new(1, 0, 0), fail);
+        assert_eq!(
+            translators.translate(vec![42], &Version::new(0, 1, 0), &Version::new(3, 1, 0)),
+            Err(SnapshotError::Serde("translation failed".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_load_with_translation() {
+        let mut translators = TranslatorRegistry::default();
+        translators.register(Version::new(1, 0, 0), Version::new(2, 0, 0), append_byte);
+
+        let mut data = Vec::new();
+        Snapshot::new(Version::new(1, 0, 0))
+            .save(&mut data, &42u8)
+            .unwrap();
+
+        let snapshot = Snapshot::new(Version::new(2, 0, 0));
+        // The old layout is rejected without translation.
+        snapshot
+            .load_with_version_check::<_, (u8, u8)>(&mut data.as_slice(), data.len())
+            .unwrap_err();
+        let (state, version) = snapshot
+            .load_with_translation::<_, (u8, u8)>(&mut data.as_slice(), data.len(), &translators)
+            .unwrap();
+        assert_eq!(state, (42, 7));
+        assert_eq!(version, Version::new(1, 0, 0));
+
+        // The CRC is still validated.
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        assert!(matches!(
+            snapshot.load_with_translation::<_, (u8, u8)>(
+                &mut data.as_slice(),
+                data.len(),
+                &translators
+            ),
+            Err(SnapshotError::Crc64(_))
+        ));
+    }
+}
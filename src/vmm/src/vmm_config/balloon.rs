@@ -30,6 +30,8 @@ pub enum BalloonConfigError {
     UpdateFailure(std::io::Error),
     /// Firecracker's huge pages support is incompatible with memory ballooning.
     HugePages,
+    /// Memory ballooning is incompatible with drives using io_uring fixed buffers.
+    FixedBuffers,
 }
 
 /// This struct represents the strongly typed equivalent of the json body
@@ -7,10 +7,15 @@ use std::sync::{Arc, Mutex};
 
 use serde::{Deserialize, Serialize};
 
+use super::rate_limiter_group::RateLimiterGroupError;
 use super::RateLimiterConfig;
 use crate::devices::virtio::block::device::Block;
-pub use crate::devices::virtio::block::virtio::device::FileEngineType;
+pub use crate::devices::virtio::block::virtio::device::{
+    AsyncIoConfig, DriveEncryptionConfig, FileEngineType, HostCacheMode, NbdConfig, NbdTransport,
+    OverlayConfig, VerityConfig,
+};
 use crate::devices::virtio::block::{BlockError, CacheType};
+use crate::rate_limiter::group::RateLimiterGroup;
 use crate::VmmError;
 
 /// Errors associated with the operations allowed on a drive.
@@ -22,8 +27,12 @@ pub enum DriveError {
     CreateRateLimiter(io::Error),
     /// Unable to patch the block device: {0} Please verify the request arguments.
     DeviceUpdate(VmmError),
+    /// {0}
+    RateLimiterGroup(RateLimiterGroupError),
     /// A root block device already exists!
     RootBlockDeviceAlreadyAdded,
+    /// io_uring fixed buffers are incompatible with memory ballooning.
+    FixedBuffersAndBalloon,
 }
 
 /// Use this structure to set up the Block Device before booting the kernel.
@@ -58,6 +67,23 @@ pub struct BlockDeviceConfig {
     // pub file_engine_type: FileEngineType,
     #[serde(rename = "io_engine")]
     pub file_engine_type: Option<FileEngineType>,
+    /// Copy-on-write overlay receiving the writes to the drive, which then only reads the
+    /// file at `path_on_host`.
+    pub overlay: Option<OverlayConfig>,
+    /// The number of request queues of the device.
+    pub num_queues: Option<u16>,
+    /// Tuning options of the Async engine.
+    pub async_io: Option<AsyncIoConfig>,
+    /// How the host caches the data of the backing file.
+    pub host_cache_mode: Option<HostCacheMode>,
+    /// Rate limiter group whose budget is shared with other devices.
+    pub rate_limiter_group: Option<String>,
+    /// Hash tree checking the integrity of the data read from a read-only drive.
+    pub verity: Option<VerityConfig>,
+    /// Export of an NBD server backing the drive, in place of `path_on_host`.
+    pub nbd: Option<NbdConfig>,
+    /// Key encrypting the data of the drive, so that the backing file only holds ciphertext.
+    pub encryption: Option<DriveEncryptionConfig>,
 
     // VhostUserBlock specific fields
     /// Path to the vhost-user socket.
@@ -166,6 +192,29 @@ impl BlockBuilder {
         Ok(())
     }
 
+    /// Makes the rate limiter of the device with the specified `drive_id`, if it exists, a member
+    /// of `group`.
+    pub fn join_rate_limiter_group(
+        &mut self,
+        drive_id: &str,
+        group: &RateLimiterGroup,
+    ) -> Result<(), BlockError> {
+        if let Some(index) = self.get_index_of_drive_id(drive_id) {
+            self.devices[index]
+                .lock()
+                .expect("Poisoned lock")
+                .join_rate_limiter_group(group)?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether any device registers the guest memory as io_uring fixed buffers.
+    pub fn has_fixed_buffers(&self) -> bool {
+        self.devices
+            .iter()
+            .any(|b| b.lock().expect("Poisoned lock").fixed_buffers())
+    }
+
     /// Returns a vec with the structures used to configure the devices.
     pub fn configs(&self) -> Vec<BlockDeviceConfig> {
         self.devices
@@ -202,6 +251,14 @@ mod tests {
                 path_on_host: self.path_on_host.clone(),
                 rate_limiter: self.rate_limiter,
                 file_engine_type: self.file_engine_type,
+                overlay: self.overlay.clone(),
+                num_queues: self.num_queues,
+                async_io: self.async_io,
+                host_cache_mode: self.host_cache_mode,
+                rate_limiter_group: self.rate_limiter_group.clone(),
+                verity: self.verity.clone(),
+                nbd: self.nbd.clone(),
+                encryption: self.encryption.clone(),
 
                 socket: self.socket.clone(),
             }
@@ -229,6 +286,14 @@ mod tests {
             path_on_host: Some(dummy_path),
             rate_limiter: None,
             file_engine_type: None,
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: None,
         };
@@ -261,6 +326,14 @@ mod tests {
             path_on_host: Some(dummy_path),
             rate_limiter: None,
             file_engine_type: None,
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: None,
         };
@@ -290,6 +363,14 @@ mod tests {
             path_on_host: Some(dummy_path_1),
             rate_limiter: None,
             file_engine_type: None,
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: None,
         };
@@ -306,6 +387,14 @@ mod tests {
             path_on_host: Some(dummy_path_2),
             rate_limiter: None,
             file_engine_type: None,
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: None,
         };
@@ -333,6 +422,14 @@ mod tests {
             path_on_host: Some(dummy_path_1),
             rate_limiter: None,
             file_engine_type: None,
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: None,
         };
@@ -349,6 +446,14 @@ mod tests {
             path_on_host: Some(dummy_path_2),
             rate_limiter: None,
             file_engine_type: None,
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: None,
         };
@@ -365,6 +470,14 @@ mod tests {
             path_on_host: Some(dummy_path_3),
             rate_limiter: None,
             file_engine_type: None,
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: None,
         };
@@ -406,6 +519,14 @@ mod tests {
             path_on_host: Some(dummy_path_1),
             rate_limiter: None,
             file_engine_type: None,
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: None,
         };
@@ -422,6 +543,14 @@ mod tests {
             path_on_host: Some(dummy_path_2),
             rate_limiter: None,
             file_engine_type: None,
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: None,
         };
@@ -438,6 +567,14 @@ mod tests {
             path_on_host: Some(dummy_path_3),
             rate_limiter: None,
             file_engine_type: None,
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: None,
         };
@@ -480,6 +617,14 @@ mod tests {
             path_on_host: Some(dummy_path_1.clone()),
             rate_limiter: None,
             file_engine_type: None,
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: None,
         };
@@ -496,6 +641,14 @@ mod tests {
             path_on_host: Some(dummy_path_2.clone()),
             rate_limiter: None,
             file_engine_type: None,
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: None,
         };
@@ -560,6 +713,14 @@ mod tests {
             path_on_host: Some(dummy_path_1),
             rate_limiter: None,
             file_engine_type: None,
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: None,
         };
@@ -576,6 +737,14 @@ mod tests {
             path_on_host: Some(dummy_path_2),
             rate_limiter: None,
             file_engine_type: None,
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: None,
         };
@@ -602,6 +771,14 @@ mod tests {
             path_on_host: Some(dummy_file.as_path().to_str().unwrap().to_string()),
             rate_limiter: None,
             file_engine_type: Some(FileEngineType::Sync),
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: None,
         };
@@ -630,6 +807,14 @@ mod tests {
             path_on_host: Some(backing_file.as_path().to_str().unwrap().to_string()),
             rate_limiter: None,
             file_engine_type: None,
+            overlay: None,
+            num_queues: None,
+            async_io: None,
+            host_cache_mode: None,
+            rate_limiter_group: None,
+            verity: None,
+            nbd: None,
+            encryption: None,
 
             socket: None,
         };
@@ -94,6 +94,16 @@ impl HugePageConfig {
     }
 }
 
+/// Describes how KVM reports the guest pages dirtied since the last snapshot.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DirtyTrackingBackend {
+    /// Fetch and clear a dirty bitmap for every memory slot.
+    #[default]
+    Bitmap,
+    /// Harvest the per vCPU dirty rings, which only hold the dirtied pages.
+    Ring,
+}
+
 impl From<HugePageConfig> for Option<memfd::HugetlbSize> {
     fn from(value: HugePageConfig) -> Self {
         match value {
@@ -123,6 +133,9 @@ pub struct MachineConfig {
     /// Configures what page size Firecracker should use to back guest memory.
     #[serde(default)]
     pub huge_pages: HugePageConfig,
+    /// Configures how dirty pages are tracked when dirty page tracking is enabled.
+    #[serde(default)]
+    pub dirty_tracking_backend: DirtyTrackingBackend,
 }
 
 impl Default for MachineConfig {
@@ -158,6 +171,9 @@ pub struct MachineConfigUpdate {
     /// Configures what page size Firecracker should use to back guest memory.
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub huge_pages: Option<HugePageConfig>,
+    /// Configures how dirty pages are tracked when dirty page tracking is enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dirty_tracking_backend: Option<DirtyTrackingBackend>,
 }
 
 impl MachineConfigUpdate {
@@ -178,6 +194,7 @@ impl From<MachineConfig> for MachineConfigUpdate {
             cpu_template: cfg.cpu_template,
             track_dirty_pages: Some(cfg.track_dirty_pages),
             huge_pages: Some(cfg.huge_pages),
+            dirty_tracking_backend: Some(cfg.dirty_tracking_backend),
         }
     }
 }
@@ -197,6 +214,8 @@ pub struct VmConfig {
     pub track_dirty_pages: bool,
     /// Configures what page size Firecracker should use to back guest memory.
     pub huge_pages: HugePageConfig,
+    /// Configures how dirty pages are tracked when dirty page tracking is enabled.
+    pub dirty_tracking_backend: DirtyTrackingBackend,
 }
 
 impl VmConfig {
@@ -254,6 +273,9 @@ impl VmConfig {
             cpu_template,
             track_dirty_pages: update.track_dirty_pages.unwrap_or(self.track_dirty_pages),
             huge_pages: page_config,
+            dirty_tracking_backend: update
+                .dirty_tracking_backend
+                .unwrap_or(self.dirty_tracking_backend),
         })
     }
 }
@@ -267,6 +289,7 @@ impl Default for VmConfig {
             cpu_template: None,
             track_dirty_pages: false,
             huge_pages: HugePageConfig::None,
+            dirty_tracking_backend: DirtyTrackingBackend::Bitmap,
         }
     }
 }
@@ -280,6 +303,7 @@ impl From<&VmConfig> for MachineConfig {
             cpu_template: value.cpu_template.as_ref().map(|template| template.into()),
             track_dirty_pages: value.track_dirty_pages,
             huge_pages: value.huge_pages,
+            dirty_tracking_backend: value.dirty_tracking_backend,
         }
     }
 }
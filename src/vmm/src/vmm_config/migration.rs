@@ -0,0 +1,51 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Configurations used in the live migration context.
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+/// Default maximum number of pre-copy iterations performed before the source microVM is paused.
+pub const DEFAULT_MAX_PRECOPY_ITERATIONS: u32 = 8;
+/// Default number of dirty pages below which pre-copy is considered converged.
+pub const DEFAULT_MAX_DIRTY_PAGES: u64 = 1024;
+
+fn default_max_precopy_iterations() -> u32 {
+    DEFAULT_MAX_PRECOPY_ITERATIONS
+}
+
+fn default_max_dirty_pages() -> u64 {
+    DEFAULT_MAX_DIRTY_PAGES
+}
+
+/// Stores the configuration used for sending a running microVM to a migration destination.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SendMigrationParams {
+    /// Path to the Unix domain socket on which the destination Firecracker process is waiting
+    /// for the migration stream.
+    pub socket_path: PathBuf,
+    /// Maximum number of pre-copy iterations. Once reached, the microVM is paused and the
+    /// remaining dirty pages are sent regardless of how many there are.
+    #[serde(default = "default_max_precopy_iterations")]
+    pub max_precopy_iterations: u32,
+    /// Pre-copy stops as soon as an iteration leaves at most this many dirty pages behind.
+    #[serde(default = "default_max_dirty_pages")]
+    pub max_dirty_pages: u64,
+}
+
+/// Stores the configuration used for receiving a microVM from a migration source.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReceiveMigrationParams {
+    /// Path of the Unix domain socket that will be created to receive the migration stream.
+    pub socket_path: PathBuf,
+    /// Whether or not to enable KVM dirty page tracking on the received microVM.
+    #[serde(default)]
+    pub enable_diff_snapshots: bool,
+    /// When set to true, the microVM is resumed once the migration completes successfully.
+    #[serde(default)]
+    pub resume_vm: bool,
+}
@@ -8,6 +8,7 @@ use std::sync::{Arc, Mutex};
 use serde::{Deserialize, Serialize};
 use utils::net::mac::MacAddr;
 
+use super::rate_limiter_group::RateLimiterGroupError;
 use super::RateLimiterConfig;
 use crate::devices::virtio::net::{Net, TapError};
 use crate::VmmError;
@@ -27,6 +28,13 @@ pub struct NetworkInterfaceConfig {
     pub rx_rate_limiter: Option<RateLimiterConfig>,
     /// Rate Limiter for transmitted packages.
     pub tx_rate_limiter: Option<RateLimiterConfig>,
+    /// Rate limiter group whose budget is shared with other devices, by both the RX and TX
+    /// rate limiters.
+    pub rate_limiter_group: Option<String>,
+    /// Offers the link status to the guest driver, which is then told when the guest MAC is
+    /// reassigned by loading a snapshot in clone mode.
+    #[serde(default)]
+    pub link_status: bool,
 }
 
 impl From<&Net> for NetworkInterfaceConfig {
@@ -39,6 +47,11 @@ impl From<&Net> for NetworkInterfaceConfig {
             guest_mac: net.guest_mac().copied(),
             rx_rate_limiter: rx_rl.into_option(),
             tx_rate_limiter: tx_rl.into_option(),
+            rate_limiter_group: net
+                .rx_rate_limiter()
+                .group()
+                .map(|group| group.id().to_string()),
+            link_status: net.link_status(),
         }
     }
 }
@@ -71,6 +84,8 @@ pub enum NetworkInterfaceError {
     GuestMacAddressInUse(String),
     /// Cannot open/create the tap device: {0}
     OpenTap(#[from] TapError),
+    /// {0}
+    RateLimiterGroup(#[from] RateLimiterGroupError),
 }
 
 /// Builder for a list of network devices.
@@ -155,14 +170,18 @@ impl NetBuilder {
             .map_err(NetworkInterfaceError::CreateRateLimiter)?;
 
         // Create and return the Net device
-        crate::devices::virtio::net::Net::new(
+        let mut net = crate::devices::virtio::net::Net::new(
             cfg.iface_id,
             &cfg.host_dev_name,
             cfg.guest_mac,
             rx_rate_limiter.unwrap_or_default(),
             tx_rate_limiter.unwrap_or_default(),
         )
-        .map_err(NetworkInterfaceError::CreateNetworkDevice)
+        .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        if cfg.link_status {
+            net.enable_link_status();
+        }
+        Ok(net)
     }
 
     /// Returns a vec with the structures used to configure the net devices.
@@ -199,6 +218,8 @@ mod tests {
             guest_mac: Some(MacAddr::from_str(mac).unwrap()),
             rx_rate_limiter: RateLimiterConfig::default().into_option(),
             tx_rate_limiter: RateLimiterConfig::default().into_option(),
+            rate_limiter_group: None,
+            link_status: false,
         }
     }
 
@@ -210,6 +231,8 @@ mod tests {
                 guest_mac: self.guest_mac,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                rate_limiter_group: self.rate_limiter_group.clone(),
+                link_status: self.link_status,
             }
         }
     }
@@ -327,6 +350,14 @@ mod tests {
         let configs = net_builder.configs();
         assert_eq!(configs.len(), 1);
         assert_eq!(configs.first().unwrap(), &net_if_cfg);
+        assert!(!net_builder.net_devices[0].lock().unwrap().link_status());
+
+        // The link status is only offered when requested.
+        let mut net_if_cfg = create_netif(net_id, host_dev_name, guest_mac);
+        net_if_cfg.link_status = true;
+        net_builder.build(net_if_cfg.clone()).unwrap();
+        assert!(net_builder.net_devices[0].lock().unwrap().link_status());
+        assert_eq!(net_builder.configs().first().unwrap(), &net_if_cfg);
     }
 
     #[test]
@@ -0,0 +1,181 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::{Deserialize, Serialize};
+
+use super::{RateLimiterConfig, RateLimiterUpdate, TokenBucketConfig};
+use crate::rate_limiter::group::RateLimiterGroup;
+use crate::rate_limiter::{BucketUpdate, TokenBucket};
+
+/// Errors associated with the operations allowed on a rate limiter group.
+#[derive(Debug, thiserror::Error, displaydoc::Display, PartialEq, Eq)]
+pub enum RateLimiterGroupError {
+    /// Rate limiter group {0} does not exist.
+    GroupNotFound(String),
+}
+
+/// This struct represents the strongly typed equivalent of the json body from rate limiter group
+/// related requests.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimiterGroupConfig {
+    /// ID of the rate limiter group.
+    pub group_id: String,
+    /// Data used to initialize the bandwidth bucket shared by the group.
+    pub bandwidth: Option<TokenBucketConfig>,
+    /// Data used to initialize the ops bucket shared by the group.
+    pub ops: Option<TokenBucketConfig>,
+}
+
+impl From<&RateLimiterGroup> for RateLimiterGroupConfig {
+    fn from(group: &RateLimiterGroup) -> Self {
+        RateLimiterGroupConfig {
+            group_id: group.id().to_string(),
+            bandwidth: group.bandwidth().as_ref().map(TokenBucketConfig::from),
+            ops: group.ops().as_ref().map(TokenBucketConfig::from),
+        }
+    }
+}
+
+fn token_bucket(cfg: Option<TokenBucketConfig>) -> Option<TokenBucket> {
+    cfg.and_then(|cfg| TokenBucket::new(cfg.size, cfg.one_time_burst.unwrap_or(0), cfg.refill_time))
+}
+
+/// Wrapper for the collection that holds all the rate limiter groups.
+#[derive(Debug, Default)]
+pub struct RateLimiterGroupBuilder {
+    groups: Vec<RateLimiterGroup>,
+}
+
+impl RateLimiterGroupBuilder {
+    /// Creates an empty rate limiter group store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the group with the given id, if it exists.
+    pub fn get(&self, group_id: &str) -> Option<&RateLimiterGroup> {
+        self.groups.iter().find(|group| group.id() == group_id)
+    }
+
+    /// Returns an immutable iterator over the rate limiter groups.
+    pub fn iter(&self) -> std::slice::Iter<'_, RateLimiterGroup> {
+        self.groups.iter()
+    }
+
+    /// Inserts a new rate limiter group or replaces the buckets of an existing one.
+    ///
+    /// The devices that are members of an existing group remain members of it.
+    pub fn insert(&mut self, config: RateLimiterGroupConfig) {
+        let bandwidth = token_bucket(config.bandwidth);
+        let ops = token_bucket(config.ops);
+
+        match self.get(&config.group_id) {
+            Some(group) => group.update_buckets(
+                bandwidth.map_or(BucketUpdate::Disabled, BucketUpdate::Update),
+                ops.map_or(BucketUpdate::Disabled, BucketUpdate::Update),
+            ),
+            None => self
+                .groups
+                .push(RateLimiterGroup::new(config.group_id, bandwidth, ops)),
+        }
+    }
+
+    /// Inserts an already created rate limiter group, replacing the one with the same id, if any.
+    pub fn add_group(&mut self, group: RateLimiterGroup) {
+        match self.groups.iter_mut().find(|g| g.id() == group.id()) {
+            Some(existing) => *existing = group,
+            None => self.groups.push(group),
+        }
+    }
+
+    /// Updates the buckets of an existing rate limiter group. Buckets which are not present in
+    /// `config` are left unchanged.
+    pub fn update(&self, config: RateLimiterGroupConfig) -> Result<(), RateLimiterGroupError> {
+        let group = self
+            .get(&config.group_id)
+            .ok_or_else(|| RateLimiterGroupError::GroupNotFound(config.group_id.clone()))?;
+        let update = RateLimiterUpdate::from(Some(RateLimiterConfig {
+            bandwidth: config.bandwidth,
+            ops: config.ops,
+        }));
+        group.update_buckets(update.bandwidth, update.ops);
+
+        Ok(())
+    }
+
+    /// Returns the configurations of all the rate limiter groups.
+    pub fn configs(&self) -> Vec<RateLimiterGroupConfig> {
+        self.groups
+            .iter()
+            .map(RateLimiterGroupConfig::from)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group_config(group_id: &str, size: u64) -> RateLimiterGroupConfig {
+        RateLimiterGroupConfig {
+            group_id: group_id.to_string(),
+            bandwidth: Some(TokenBucketConfig {
+                size,
+                one_time_burst: None,
+                refill_time: 100,
+            }),
+            ops: None,
+        }
+    }
+
+    #[test]
+    fn test_insert_rate_limiter_group() {
+        let mut builder = RateLimiterGroupBuilder::new();
+        assert!(builder.get("group").is_none());
+
+        builder.insert(group_config("group", 1000));
+        let group = builder.get("group").unwrap().clone();
+        assert_eq!(builder.configs(), vec![group_config("group", 1000)]);
+
+        // Inserting an existing group updates it in place.
+        builder.insert(group_config("group", 2000));
+        assert_eq!(builder.iter().count(), 1);
+        assert_eq!(group.bandwidth().unwrap().capacity(), 2000);
+
+        // Buckets left out are disabled.
+        builder.insert(RateLimiterGroupConfig {
+            bandwidth: None,
+            ..group_config("group", 0)
+        });
+        assert!(group.bandwidth().is_none());
+    }
+
+    #[test]
+    fn test_update_rate_limiter_group() {
+        let mut builder = RateLimiterGroupBuilder::new();
+        assert_eq!(
+            builder.update(group_config("group", 1000)),
+            Err(RateLimiterGroupError::GroupNotFound("group".to_string()))
+        );
+
+        builder.insert(RateLimiterGroupConfig {
+            ops: Some(TokenBucketConfig {
+                size: 10,
+                one_time_burst: None,
+                refill_time: 100,
+            }),
+            ..group_config("group", 1000)
+        });
+        builder.update(group_config("group", 2000)).unwrap();
+
+        // Buckets left out are not changed.
+        let group = builder.get("group").unwrap();
+        assert_eq!(group.bandwidth().unwrap().capacity(), 2000);
+        assert_eq!(group.ops().unwrap().capacity(), 10);
+
+        // Zero sized buckets are disabled.
+        builder.update(group_config("group", 0)).unwrap();
+        assert!(group.bandwidth().is_none());
+    }
+}
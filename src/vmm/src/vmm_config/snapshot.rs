@@ -3,12 +3,17 @@
 
 //! Configurations used in the snapshotting context.
 
+use std::fmt;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::PathBuf;
 
 /// For crates that depend on `vmm` we export.
 pub use semver::Version;
 use serde::{Deserialize, Serialize};
 
+use crate::devices::virtio::block::virtio::device::DriveEncryptionConfig;
+
 /// The snapshot type options that are available when
 /// creating a new snapshot.
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
@@ -20,6 +25,66 @@ pub enum SnapshotType {
     Full,
 }
 
+/// The compression options that are available for the guest memory file when
+/// creating a new snapshot.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum MemoryCompression {
+    /// Guest memory is saved as is.
+    #[default]
+    None,
+    /// Guest memory is split in chunks which are compressed independently using LZ4.
+    Lz4,
+}
+
+/// Specifies the 256-bit key used to encrypt or decrypt the snapshot files. Exactly one of
+/// the two fields must be provided.
+#[derive(Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotEncryptionConfig {
+    /// Base64 encoding of the key.
+    #[serde(default)]
+    pub key: Option<String>,
+    /// File descriptor, inherited by the Firecracker process, from which the key is read.
+    #[serde(default)]
+    pub key_fd: Option<RawFd>,
+}
+
+// The key must never end up in logs.
+impl fmt::Debug for SnapshotEncryptionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SnapshotEncryptionConfig")
+            .field("key", &self.key.as_ref().map(|_| ".."))
+            .field("key_fd", &self.key_fd)
+            .finish()
+    }
+}
+
+/// Specifies the integrity manifest of a snapshot and the 256-bit key used to authenticate it.
+/// Exactly one of the two key fields must be provided.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotIntegrityConfig {
+    /// Path to the manifest file.
+    pub manifest_path: PathBuf,
+    /// Base64 encoding of the key.
+    #[serde(default)]
+    pub key: Option<String>,
+    /// File descriptor, inherited by the Firecracker process, from which the key is read.
+    #[serde(default)]
+    pub key_fd: Option<RawFd>,
+}
+
+// The key must never end up in logs.
+impl fmt::Debug for SnapshotIntegrityConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SnapshotIntegrityConfig")
+            .field("manifest_path", &self.manifest_path)
+            .field("key", &self.key.as_ref().map(|_| ".."))
+            .field("key_fd", &self.key_fd)
+            .finish()
+    }
+}
+
 /// Specifies the method through which guest memory will get populated when
 /// resuming from a snapshot:
 /// 1) A file that contains the guest memory to be loaded,
@@ -33,18 +98,111 @@ pub enum MemBackendType {
     Uffd,
 }
 
+/// Destination a snapshot file is written to.
+#[derive(Debug)]
+pub enum SnapshotOutput {
+    /// File at the given path, which is created if it does not exist. The path may also name a
+    /// FIFO, which is then written sequentially.
+    Path(PathBuf),
+    /// File passed to Firecracker along with the API request. Unless it is a regular file, it is
+    /// written sequentially.
+    File(File),
+}
+
+impl PartialEq for SnapshotOutput {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Path(path), Self::Path(other_path)) => path == other_path,
+            (Self::File(file), Self::File(other_file)) => {
+                file.as_raw_fd() == other_file.as_raw_fd()
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Eq for SnapshotOutput {}
+
 /// Stores the configuration that will be used for creating a snapshot.
-#[derive(Debug, PartialEq, Eq, Deserialize)]
-#[serde(deny_unknown_fields)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct CreateSnapshotParams {
+    /// This marks the type of snapshot we want to create.
+    pub snapshot_type: SnapshotType,
+    /// Destination of the microVM state.
+    pub snapshot_output: SnapshotOutput,
+    /// Destination of the guest memory.
+    pub mem_file_output: SnapshotOutput,
+    /// Compression applied to the guest memory file. Only full snapshots can be compressed.
+    pub compression: MemoryCompression,
+    /// When present, both snapshot files are encrypted using this key. Only full, uncompressed
+    /// snapshots can be encrypted.
+    pub encryption: Option<SnapshotEncryptionConfig>,
+    /// When set, the microVM is resumed as soon as its state is saved and the guest memory is
+    /// written to the memory file in the background.
+    pub background: bool,
+    /// When present, an integrity manifest of both snapshot files is written, authenticated
+    /// using this key.
+    pub integrity: Option<SnapshotIntegrityConfig>,
+}
+
+/// Stores the configuration for creating a snapshot that is provided by the user.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreateSnapshotConfig {
     /// This marks the type of snapshot we want to create.
     /// The default value is `Full`, which means a full snapshot.
     #[serde(default = "SnapshotType::default")]
     pub snapshot_type: SnapshotType,
     /// Path to the file that will contain the microVM state.
-    pub snapshot_path: PathBuf,
+    /// Is not to be used in conjunction with `snapshot_fd`.
+    #[serde(default)]
+    pub snapshot_path: Option<PathBuf>,
+    /// Index, among the file descriptors passed along with the request, of the file that will
+    /// contain the microVM state. Is not to be used in conjunction with `snapshot_path`.
+    #[serde(default)]
+    pub snapshot_fd: Option<usize>,
     /// Path to the file that will contain the guest memory.
-    pub mem_file_path: PathBuf,
+    /// Is not to be used in conjunction with `mem_file_fd`.
+    #[serde(default)]
+    pub mem_file_path: Option<PathBuf>,
+    /// Index, among the file descriptors passed along with the request, of the file that will
+    /// contain the guest memory. Is not to be used in conjunction with `mem_file_path`.
+    #[serde(default)]
+    pub mem_file_fd: Option<usize>,
+    /// Compression applied to the guest memory file. Only full snapshots can be compressed.
+    #[serde(default)]
+    pub compression: MemoryCompression,
+    /// When present, both snapshot files are encrypted using this key. Only full, uncompressed
+    /// snapshots can be encrypted.
+    #[serde(default)]
+    pub encryption: Option<SnapshotEncryptionConfig>,
+    /// Whether to resume the microVM as soon as its state is saved and write the guest memory to
+    /// the memory file in the background. Only full, uncompressed and unencrypted snapshots can
+    /// be created in the background.
+    #[serde(default)]
+    pub background: bool,
+    /// When present, an integrity manifest of both snapshot files is written, authenticated
+    /// using this key. Background snapshots cannot have a manifest.
+    #[serde(default)]
+    pub integrity: Option<SnapshotIntegrityConfig>,
+}
+
+/// Progress of the last snapshot created in the background.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "state")]
+pub enum SnapshotStatus {
+    /// No snapshot has been created in the background.
+    #[default]
+    None,
+    /// The guest memory is being written to the memory file.
+    InProgress,
+    /// Both snapshot files are complete.
+    Completed,
+    /// Writing the guest memory failed and the memory file is not usable.
+    Failed {
+        /// Description of the failure.
+        error: String,
+    },
 }
 
 /// Stores the configuration that will be used for loading a snapshot.
@@ -60,6 +218,23 @@ pub struct LoadSnapshotParams {
     /// When set to true, the vm is also resumed if the snapshot load
     /// is successful.
     pub resume_vm: bool,
+    /// Key used to decrypt the snapshot files, which must be present if and only if they are
+    /// encrypted.
+    pub encryption: Option<SnapshotEncryptionConfig>,
+    /// When present, the snapshot files are verified against this integrity manifest before
+    /// being loaded.
+    pub integrity: Option<SnapshotIntegrityConfig>,
+    /// Host resources to use instead of the ones recorded in the snapshot for some drives.
+    pub drive_overrides: Vec<DriveOverride>,
+    /// Tap devices to use instead of the ones recorded in the snapshot for some network
+    /// interfaces.
+    pub network_overrides: Vec<NetworkOverride>,
+    /// Unix domain socket to use instead of the one recorded in the snapshot for the vsock
+    /// device.
+    pub vsock_override: Option<VsockOverride>,
+    /// When set to true, the guest identity is regenerated, so that several microVMs can be
+    /// loaded from the same snapshot.
+    pub clone: bool,
 }
 
 /// Stores the configuration for loading a snapshot that is provided by the user.
@@ -82,6 +257,63 @@ pub struct LoadSnapshotConfig {
     /// Whether or not to resume the vm post snapshot load.
     #[serde(default)]
     pub resume_vm: bool,
+    /// Key used to decrypt the snapshot files.
+    #[serde(default)]
+    pub encryption: Option<SnapshotEncryptionConfig>,
+    /// Integrity manifest the snapshot files are verified against.
+    #[serde(default)]
+    pub integrity: Option<SnapshotIntegrityConfig>,
+    /// Host resources to use instead of the ones recorded in the snapshot for some drives.
+    #[serde(default)]
+    pub drive_overrides: Vec<DriveOverride>,
+    /// Tap devices to use instead of the ones recorded in the snapshot for some network
+    /// interfaces.
+    #[serde(default)]
+    pub network_overrides: Vec<NetworkOverride>,
+    /// Unix domain socket to use instead of the one recorded in the snapshot for the vsock
+    /// device.
+    #[serde(default)]
+    pub vsock_override: Option<VsockOverride>,
+    /// Whether or not to regenerate the guest identity.
+    #[serde(default)]
+    pub clone: bool,
+}
+
+/// Replaces the host resources backing a drive when loading a snapshot.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DriveOverride {
+    /// Identifier of the drive in the snapshot.
+    pub drive_id: String,
+    /// Path of the file backing the drive. Only allowed for virtio block devices.
+    #[serde(default)]
+    pub path_on_host: Option<String>,
+    /// Socket path of the vhost-user backend the drive connects to. Only allowed for
+    /// vhost-user block devices.
+    #[serde(default)]
+    pub socket: Option<String>,
+    /// Key of the drive, which must be provided again for encrypted drives since it is not part
+    /// of the snapshot. Only allowed for encrypted virtio block devices.
+    #[serde(default)]
+    pub encryption: Option<DriveEncryptionConfig>,
+}
+
+/// Replaces the tap device backing a network interface when loading a snapshot.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkOverride {
+    /// Identifier of the network interface in the snapshot.
+    pub iface_id: String,
+    /// Name of the tap device backing the network interface.
+    pub host_dev_name: String,
+}
+
+/// Replaces the Unix domain socket backing the vsock device when loading a snapshot.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VsockOverride {
+    /// Path of the Unix domain socket the vsock device listens on.
+    pub uds_path: String,
 }
 
 /// Stores the configuration used for managing snapshot memory.
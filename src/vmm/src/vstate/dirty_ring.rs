@@ -0,0 +1,346 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Dirty page tracking using the KVM dirty ring interface.
+//!
+//! With the dirty ring, KVM does not mark dirty pages in a bitmap per memory slot, but pushes them
+//! to a ring shared with userspace by the vCPU that dirtied them. Collecting the dirty pages then
+//! costs time proportional to the number of pages dirtied, instead of to the size of guest memory.
+//! The vCPU threads harvest their ring into a [`DirtyRingLog`] shared by the microVM, which the
+//! VMM turns into a [`DirtyBitmap`] when creating diff snapshots.
+
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use kvm_bindings::kvm_enable_cap;
+use kvm_ioctls::{Kvm, VcpuFd, VmFd};
+use utils::ioctl::ioctl;
+use utils::{errno, get_page_size, ioctl_io_nr, ioctl_ioc_nr, u64_to_usize};
+
+use crate::vstate::memory::{GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use crate::DirtyBitmap;
+
+/// KVM capability of the dirty ring, on architectures with strongly ordered memory.
+const KVM_CAP_DIRTY_LOG_RING: u32 = 192;
+/// KVM capability of the dirty ring, for architectures with weakly ordered memory.
+const KVM_CAP_DIRTY_LOG_RING_ACQ_REL: u32 = 223;
+/// Offset, in pages, of the dirty ring in the mapping of a vCPU file descriptor.
+const KVM_DIRTY_LOG_PAGE_OFFSET: i64 = 64;
+/// Flag of the ring entries holding a dirty page not harvested yet.
+const KVM_DIRTY_GFN_F_DIRTY: u32 = 1;
+/// Flag of the ring entries harvested by userspace.
+const KVM_DIRTY_GFN_F_RESET: u32 = 2;
+/// Exit reason of a vCPU whose dirty ring is full.
+pub const KVM_EXIT_DIRTY_RING_FULL: u32 = 31;
+/// Number of entries of the dirty ring of each vCPU.
+const DIRTY_RING_ENTRIES: usize = 4096;
+
+const KVMIO: u32 = 0xAE;
+ioctl_io_nr!(KVM_RESET_DIRTY_RINGS, KVMIO, 0xc7);
+
+/// Errors associated with the KVM dirty ring.
+#[derive(Debug, PartialEq, Eq, thiserror::Error, displaydoc::Display)]
+pub enum DirtyRingError {
+    /// The host kernel does not support the KVM dirty ring.
+    Unsupported,
+    /// Cannot enable the KVM dirty ring: {0}
+    Enable(kvm_ioctls::Error),
+    /// Cannot duplicate the VM file descriptor: {0}
+    DuplicateVmFd(errno::Error),
+    /// Cannot map the dirty ring of a vCPU: {0}
+    Mmap(errno::Error),
+    /// Cannot reset the KVM dirty rings: {0}
+    Reset(errno::Error),
+}
+
+/// Entry of a dirty ring, the layout of `struct kvm_dirty_gfn`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct KvmDirtyGfn {
+    flags: u32,
+    slot: u32,
+    offset: u64,
+}
+
+/// Dirty ring interface supported by the host kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyRingSupport {
+    /// KVM capability enabling the dirty ring.
+    cap: u32,
+    /// Maximum size of the dirty ring of a vCPU, in bytes.
+    max_size: usize,
+}
+
+impl DirtyRingSupport {
+    /// Checks which dirty ring interface `kvm` supports, if any.
+    pub fn check(kvm: &Kvm) -> Option<Self> {
+        // The acquire/release variant is preferred, as it is the only one available on weakly
+        // ordered architectures.
+        [KVM_CAP_DIRTY_LOG_RING_ACQ_REL, KVM_CAP_DIRTY_LOG_RING]
+            .into_iter()
+            .find_map(|cap| {
+                let max_size = usize::try_from(kvm.check_extension_raw(u64::from(cap))).ok()?;
+                (max_size > 0).then_some(DirtyRingSupport { cap, max_size })
+            })
+    }
+}
+
+/// Dirty pages harvested from the dirty rings of the vCPUs of a microVM.
+#[derive(Debug)]
+pub struct DirtyRingLog {
+    /// VM file descriptor, used by the vCPU threads to reset the dirty rings.
+    vm_fd: File,
+    /// Size of the dirty ring of a vCPU, in bytes.
+    ring_size: usize,
+    /// Dirty pages harvested since the last call to `take_dirty_bitmap`, by memory slot.
+    dirty_pages: Mutex<DirtyBitmap>,
+}
+
+impl DirtyRingLog {
+    /// Enables the dirty ring of the VM `vm_fd`. This must be done before creating its vCPUs.
+    pub fn new(vm_fd: &VmFd, support: DirtyRingSupport) -> Result<Self, DirtyRingError> {
+        let page_size = get_page_size().map_err(DirtyRingError::Mmap)?;
+        // The ring must hold a power of two number of entries and span at least a page.
+        let ring_size = (DIRTY_RING_ENTRIES * std::mem::size_of::<KvmDirtyGfn>())
+            .min(support.max_size)
+            .max(page_size);
+        let cap = kvm_enable_cap {
+            cap: support.cap,
+            args: [ring_size as u64, 0, 0, 0],
+            ..Default::default()
+        };
+        vm_fd.enable_cap(&cap).map_err(DirtyRingError::Enable)?;
+
+        // SAFETY: Safe because the file descriptor is valid and we check the return value.
+        let fd = unsafe { libc::fcntl(vm_fd.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(DirtyRingError::DuplicateVmFd(errno::Error::last()));
+        }
+        Ok(DirtyRingLog {
+            // SAFETY: Safe because the file descriptor was just duplicated and is not owned by
+            // anything else.
+            vm_fd: unsafe { File::from_raw_fd(fd) },
+            ring_size,
+            dirty_pages: Mutex::new(DirtyBitmap::new()),
+        })
+    }
+
+    /// Maps the dirty ring of the vCPU `vcpu_fd`.
+    pub fn map_ring(self: &Arc<Self>, vcpu_fd: &VcpuFd) -> Result<DirtyRing, DirtyRingError> {
+        let page_size = get_page_size().map_err(DirtyRingError::Mmap)?;
+        // SAFETY: Safe because the file descriptor is valid and we check the return value.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                self.ring_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                vcpu_fd.as_raw_fd(),
+                KVM_DIRTY_LOG_PAGE_OFFSET * i64::try_from(page_size).unwrap(),
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(DirtyRingError::Mmap(errno::Error::last()));
+        }
+        Ok(DirtyRing {
+            // Safe to unwrap because mmap never returns a null mapping.
+            entries: NonNull::new(ptr.cast::<KvmDirtyGfn>()).unwrap(),
+            len: self.ring_size / std::mem::size_of::<KvmDirtyGfn>(),
+            next: 0,
+            log: self.clone(),
+        })
+    }
+
+    /// Lets KVM reuse the ring entries harvested so far, and track the writes to their pages
+    /// again.
+    pub fn reset(&self) -> Result<(), DirtyRingError> {
+        // SAFETY: Safe because the file descriptor is valid and we check the return value.
+        let ret = unsafe { ioctl(&self.vm_fd, KVM_RESET_DIRTY_RINGS()) };
+        if ret < 0 {
+            return Err(DirtyRingError::Reset(errno::Error::last()));
+        }
+        Ok(())
+    }
+
+    /// Returns the pages harvested since the last call, as a bitmap of each region of
+    /// `guest_memory`.
+    pub fn take_dirty_bitmap(&self, guest_memory: &GuestMemoryMmap) -> DirtyBitmap {
+        let mut dirty_pages = std::mem::take(&mut *self.dirty_pages.lock().expect("Poisoned lock"));
+        let page_size = get_page_size().expect("Cannot retrieve page size.");
+        guest_memory
+            .iter()
+            .enumerate()
+            .map(|(slot, region)| {
+                let pages = u64_to_usize(region.len()) / page_size;
+                let mut bitmap = dirty_pages.remove(&slot).unwrap_or_default();
+                bitmap.resize(pages.div_ceil(64), 0);
+                (slot, bitmap)
+            })
+            .collect()
+    }
+}
+
+/// Dirty ring of a vCPU, mapped from its file descriptor.
+#[derive(Debug)]
+pub struct DirtyRing {
+    entries: NonNull<KvmDirtyGfn>,
+    /// Number of entries of the ring.
+    len: usize,
+    /// Index of the next entry to harvest, wrapping around the ring.
+    next: usize,
+    log: Arc<DirtyRingLog>,
+}
+
+// SAFETY: The ring is only accessed through the `DirtyRing` owning its mapping, so it can be moved
+// to the vCPU thread.
+unsafe impl Send for DirtyRing {}
+
+impl DirtyRing {
+    /// Moves the dirty pages pushed to the ring since the last harvest to the dirty ring log of
+    /// the microVM, returning how many entries were harvested.
+    ///
+    /// The harvested entries can only be reused by KVM once [`DirtyRingLog::reset`] is called.
+    pub fn harvest(&mut self) -> usize {
+        let mut dirty_pages = self.log.dirty_pages.lock().expect("Poisoned lock");
+        let mut harvested = 0;
+        while harvested < self.len {
+            // SAFETY: Safe because the index is smaller than the number of entries of the ring.
+            let entry = unsafe { self.entries.as_ptr().add(self.next % self.len) };
+            // SAFETY: Safe because the entry is valid and suitably aligned for an `AtomicU32`.
+            // KVM publishes the entry by setting its flags with release semantics.
+            let flags = unsafe { &*std::ptr::addr_of!((*entry).flags).cast::<AtomicU32>() };
+            if flags.load(Ordering::Acquire) & KVM_DIRTY_GFN_F_DIRTY == 0 {
+                break;
+            }
+            // SAFETY: Safe because the entry is valid, and KVM does not change it until it is
+            // reset.
+            let KvmDirtyGfn { slot, offset, .. } = unsafe { entry.read() };
+            // The upper half of the slot holds the address space, which is always 0 for the
+            // memory of the microVM.
+            let bitmap = dirty_pages
+                .entry(usize::try_from(slot & 0xffff).unwrap())
+                .or_default();
+            let word = u64_to_usize(offset / 64);
+            if bitmap.len() <= word {
+                bitmap.resize(word + 1, 0);
+            }
+            bitmap[word] |= 1 << (offset % 64);
+            flags.store(KVM_DIRTY_GFN_F_RESET, Ordering::Release);
+
+            self.next = self.next.wrapping_add(1);
+            harvested += 1;
+        }
+        harvested
+    }
+
+    /// Returns the dirty ring log of the microVM this ring belongs to.
+    pub fn log(&self) -> &DirtyRingLog {
+        &self.log
+    }
+}
+
+impl Drop for DirtyRing {
+    fn drop(&mut self) {
+        // SAFETY: Safe because the ring was mapped with this size and is not used anymore.
+        unsafe {
+            libc::munmap(
+                self.entries.as_ptr().cast::<libc::c_void>(),
+                self.len * std::mem::size_of::<KvmDirtyGfn>(),
+            )
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::undocumented_unsafe_blocks)]
+
+    use utils::tempfile::TempFile;
+
+    use super::*;
+    use crate::vmm_config::machine_config::HugePageConfig;
+    use crate::vstate::memory::{GuestAddress, GuestMemoryExtension};
+    use crate::vstate::vm::Vm;
+
+    #[test]
+    fn test_harvest() {
+        let page_size = get_page_size().unwrap();
+        let log = Arc::new(DirtyRingLog {
+            vm_fd: TempFile::new().unwrap().into_file(),
+            ring_size: page_size,
+            dirty_pages: Mutex::new(DirtyBitmap::new()),
+        });
+        // An anonymous mapping stands in for the ring KVM would share.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                page_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(ptr, libc::MAP_FAILED);
+        let len = page_size / std::mem::size_of::<KvmDirtyGfn>();
+        let mut ring = DirtyRing {
+            entries: NonNull::new(ptr.cast::<KvmDirtyGfn>()).unwrap(),
+            len,
+            next: len - 2,
+            log: log.clone(),
+        };
+        let push = |index: usize, slot: u32, offset: u64| unsafe {
+            ring.entries.as_ptr().add(index).write(KvmDirtyGfn {
+                flags: KVM_DIRTY_GFN_F_DIRTY,
+                slot,
+                offset,
+            })
+        };
+        // The entries wrap around the end of the ring.
+        push(len - 2, 0, 3);
+        push(len - 1, 1, 0);
+        push(0, 0, 130);
+        assert_eq!(ring.harvest(), 3);
+        assert_eq!(ring.harvest(), 0);
+        for index in [len - 2, len - 1, 0] {
+            let entry = unsafe { ring.entries.as_ptr().add(index).read() };
+            assert_eq!(entry.flags, KVM_DIRTY_GFN_F_RESET);
+        }
+
+        let guest_memory = GuestMemoryMmap::from_raw_regions(
+            &[
+                (GuestAddress(0), page_size * 256),
+                (GuestAddress((page_size * 512) as u64), page_size * 64),
+            ],
+            false,
+            HugePageConfig::None,
+        )
+        .unwrap();
+        let bitmap = log.take_dirty_bitmap(&guest_memory);
+        assert_eq!(bitmap[&0], vec![1 << 3, 0, 1 << 2, 0]);
+        assert_eq!(bitmap[&1], vec![1]);
+        // Harvested pages are only reported once.
+        let bitmap = log.take_dirty_bitmap(&guest_memory);
+        assert_eq!(bitmap[&0], vec![0; 4]);
+        assert_eq!(bitmap[&1], vec![0]);
+    }
+
+    #[test]
+    fn test_enable_dirty_ring() {
+        let mut vm = Vm::new(vec![]).unwrap();
+        if vm.dirty_ring_support().is_none() {
+            vm.enable_dirty_ring().unwrap_err();
+            return;
+        }
+        vm.enable_dirty_ring().unwrap();
+        let log = vm.dirty_ring().unwrap().clone();
+        let vcpu_fd = vm.fd().create_vcpu(0).unwrap();
+        let mut ring = log.map_ring(&vcpu_fd).unwrap();
+        // No guest code ran, so no page is dirty.
+        assert_eq!(ring.harvest(), 0);
+        log.reset().unwrap();
+    }
+}
@@ -6,9 +6,11 @@
 // found in the THIRD-PARTY file.
 
 use std::fs::File;
-use std::io::SeekFrom;
+use std::io::{Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
 
 use serde::{Deserialize, Serialize};
+use utils::seek_hole::SeekHole;
 use utils::{errno, get_page_size, u64_to_usize};
 pub use vm_memory::bitmap::{AtomicBitmap, Bitmap, BitmapSlice, BS};
 pub use vm_memory::mmap::MmapRegionBuilder;
@@ -17,7 +19,7 @@ pub use vm_memory::{
     address, Address, ByteValued, Bytes, FileOffset, GuestAddress, GuestMemory, GuestMemoryRegion,
     GuestUsize, MemoryRegionAddress, MmapRegion,
 };
-use vm_memory::{Error as VmMemoryError, GuestMemoryError, WriteVolatile};
+use vm_memory::{Error as VmMemoryError, GuestMemoryError, ReadVolatile, WriteVolatile};
 
 use crate::vmm_config::machine_config::HugePageConfig;
 use crate::DirtyBitmap;
@@ -38,10 +40,14 @@ pub enum MemoryError {
     CreateMemory(VmMemoryError),
     /// Cannot create memory region: {0:?}
     CreateRegion(MmapRegionError),
+    /// Cannot map memory file hole as anonymous memory: {0}
+    MapHole(std::io::Error),
     /// Cannot fetch system's page size: {0:?}
     PageSize(errno::Error),
     /// Cannot dump memory: {0:?}
     WriteMemory(GuestMemoryError),
+    /// Cannot load memory: {0:?}
+    ReadMemory(GuestMemoryError),
     /// Cannot create mmap region: {0}
     MmapRegionError(MmapRegionError),
     /// Cannot create guest memory: {0}
@@ -89,6 +95,13 @@ where
         huge_pages: HugePageConfig,
     ) -> Result<Self, MemoryError>;
 
+    /// Creates a GuestMemoryMmap backed by a memfd, with the layout described in `state`.
+    fn memfd_backed_from_state(
+        state: &GuestMemoryState,
+        track_dirty_pages: bool,
+        huge_pages: HugePageConfig,
+    ) -> Result<Self, MemoryError>;
+
     /// Describes GuestMemoryMmap through a GuestMemoryState struct.
     fn describe(&self) -> GuestMemoryState;
 
@@ -98,6 +111,16 @@ where
     /// Dumps all contents of GuestMemoryMmap to a writer.
     fn dump<T: WriteVolatile>(&self, writer: &mut T) -> Result<(), MemoryError>;
 
+    /// Dumps all contents of GuestMemoryMmap to a file, leaving holes in place of zero pages.
+    fn dump_sparse(&self, file: &mut File) -> Result<(), MemoryError>;
+
+    /// Fills GuestMemoryMmap with the contents of a reader laid out as described in `state`.
+    fn load<T: ReadVolatile + std::io::Seek>(
+        &self,
+        reader: &mut T,
+        state: &GuestMemoryState,
+    ) -> Result<(), MemoryError>;
+
     /// Dumps all pages of GuestMemoryMmap present in `dirty_bitmap` to a writer.
     fn dump_dirty<T: WriteVolatile + std::io::Seek>(
         &self,
@@ -246,7 +269,15 @@ impl GuestMemoryExtension for GuestMemoryMmap {
                     .collect::<Result<Vec<_>, std::io::Error>>()
                     .map_err(MemoryError::FileError)?;
 
-                Self::from_raw_regions_file(regions, track_dirty_pages, false)
+                let guest_memory = Self::from_raw_regions_file(regions, track_dirty_pages, false)?;
+                // Holes of sparse memory files are backed by anonymous memory instead, so that
+                // the guest reads zeros from them without going through the page cache.
+                let mut file = f.try_clone().map_err(MemoryError::FileError)?;
+                guest_memory
+                    .iter()
+                    .zip(state.regions.iter())
+                    .try_for_each(|(region, r)| map_file_holes(&mut file, region, r.offset))?;
+                Ok(guest_memory)
             }
             None => {
                 let regions = state
@@ -259,6 +290,30 @@ impl GuestMemoryExtension for GuestMemoryMmap {
         }
     }
 
+    /// Creates a GuestMemoryMmap backed by a memfd, with the layout described in `state`.
+    fn memfd_backed_from_state(
+        state: &GuestMemoryState,
+        track_dirty_pages: bool,
+        huge_pages: HugePageConfig,
+    ) -> Result<Self, MemoryError> {
+        let mem_size = state.regions.iter().map(|r| r.size).sum::<usize>();
+        let memfd_file = create_memfd(mem_size >> 20, huge_pages.into())?.into_file();
+
+        let mut offset: u64 = 0;
+        let regions = state
+            .regions
+            .iter()
+            .map(|r| {
+                let file_clone = memfd_file.try_clone().map_err(MemoryError::FileError)?;
+                let file_offset = FileOffset::new(file_clone, offset);
+                offset += r.size as u64;
+                Ok((file_offset, GuestAddress(r.base_address), r.size))
+            })
+            .collect::<Result<Vec<_>, MemoryError>>()?;
+
+        Self::from_raw_regions_file(regions, track_dirty_pages, true)
+    }
+
     /// Describes GuestMemoryMmap through a GuestMemoryState struct.
     fn describe(&self) -> GuestMemoryState {
         let mut guest_memory_state = GuestMemoryState::default();
@@ -292,6 +347,56 @@ impl GuestMemoryExtension for GuestMemoryMmap {
             .map_err(MemoryError::WriteMemory)
     }
 
+    /// Dumps all contents of GuestMemoryMmap to a file, leaving holes in place of zero pages.
+    fn dump_sparse(&self, file: &mut File) -> Result<(), MemoryError> {
+        let page_size = get_page_size().map_err(MemoryError::PageSize)? as u64;
+        let mut page = vec![0u8; u64_to_usize(page_size)];
+        let mut file_offset = 0;
+
+        for region in self.iter() {
+            // Consecutive pages that are all zero or all non-zero are handled as a single run.
+            let mut run_start = 0;
+            let mut run_is_zero = false;
+            let mut addr = 0;
+            while addr < region.len() {
+                let len = page_size.min(region.len() - addr);
+                let buf = &mut page[..u64_to_usize(len)];
+                region
+                    .read_slice(buf, MemoryRegionAddress(addr))
+                    .map_err(MemoryError::WriteMemory)?;
+                let is_zero = buf.iter().all(|&byte| byte == 0);
+                if addr > run_start && is_zero != run_is_zero {
+                    write_sparse_run(file, file_offset, region, run_start..addr, run_is_zero)?;
+                    run_start = addr;
+                }
+                run_is_zero = is_zero;
+                addr += len;
+            }
+            if run_start < region.len() {
+                write_sparse_run(file, file_offset, region, run_start..addr, run_is_zero)?;
+            }
+            file_offset += region.len();
+        }
+        Ok(())
+    }
+
+    /// Fills GuestMemoryMmap with the contents of a reader laid out as described in `state`.
+    fn load<T: ReadVolatile + std::io::Seek>(
+        &self,
+        reader: &mut T,
+        state: &GuestMemoryState,
+    ) -> Result<(), MemoryError> {
+        self.iter()
+            .zip(state.regions.iter())
+            .try_for_each(|(region, state_region)| {
+                reader
+                    .seek(SeekFrom::Start(state_region.offset))
+                    .map_err(GuestMemoryError::IOError)?;
+                Ok(reader.read_exact_volatile(&mut region.as_volatile_slice()?)?)
+            })
+            .map_err(MemoryError::ReadMemory)
+    }
+
     /// Dumps all pages of GuestMemoryMmap present in `dirty_bitmap` to a writer.
     fn dump_dirty<T: WriteVolatile + std::io::Seek>(
         &self,
@@ -363,6 +468,105 @@ impl GuestMemoryExtension for GuestMemoryMmap {
     }
 }
 
+/// Writes the `range` of `region` to `file`, in which the region starts at `file_offset`. Ranges
+/// that only contain zeros are punched out of the file instead.
+fn write_sparse_run(
+    file: &mut File,
+    file_offset: u64,
+    region: &GuestRegionMmap,
+    range: std::ops::Range<u64>,
+    is_zero: bool,
+) -> Result<(), MemoryError> {
+    let offset = file_offset + range.start;
+    let len = range.end - range.start;
+    if is_zero {
+        match punch_hole(file, offset, len) {
+            Ok(()) => return Ok(()),
+            // File systems without support for holes get the zeros written instead.
+            Err(err) if err.raw_os_error() == Some(libc::EOPNOTSUPP) => (),
+            Err(err) => return Err(MemoryError::FileError(err)),
+        }
+    }
+
+    file.seek(SeekFrom::Start(offset))
+        .map_err(MemoryError::FileError)?;
+    region
+        .get_slice(MemoryRegionAddress(range.start), u64_to_usize(len))
+        .and_then(|slice| Ok(file.write_all_volatile(&slice)?))
+        .map_err(MemoryError::WriteMemory)
+}
+
+/// Deallocates `len` bytes of `file` starting at `offset`, which then read as zeros.
+fn punch_hole(file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+    let invalid_input = |_| std::io::Error::from(std::io::ErrorKind::InvalidInput);
+    let offset = libc::off_t::try_from(offset).map_err(invalid_input)?;
+    let len = libc::off_t::try_from(len).map_err(invalid_input)?;
+    // SAFETY: `fallocate` only operates on the file referred to by the valid descriptor.
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset,
+            len,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Replaces the pages of `region`, which is mapped from `file` starting at `file_offset`, that
+/// fall in holes of the file by anonymous memory.
+fn map_file_holes(
+    file: &mut File,
+    region: &GuestRegionMmap,
+    file_offset: u64,
+) -> Result<(), MemoryError> {
+    let page_size = get_page_size().map_err(MemoryError::PageSize)? as u64;
+    let region_end = file_offset + region.len();
+
+    let mut offset = file_offset;
+    while offset < region_end {
+        let hole_start = match file.seek_hole(offset) {
+            Ok(Some(hole_start)) if hole_start < region_end => hole_start,
+            Ok(_) => break,
+            Err(err) => return Err(MemoryError::MapHole(err)),
+        };
+        let hole_end = file
+            .seek_data(hole_start)
+            .map_err(MemoryError::MapHole)?
+            .map_or(region_end, |data_start| data_start.min(region_end));
+
+        // Only the pages entirely contained in the hole can be remapped.
+        let map_start = hole_start.div_ceil(page_size) * page_size;
+        let map_end = hole_end / page_size * page_size;
+        if map_start < map_end {
+            let host_addr = region
+                .get_host_address(MemoryRegionAddress(map_start - file_offset))
+                .map_err(|_| {
+                    MemoryError::MapHole(std::io::Error::from_raw_os_error(libc::EFAULT))
+                })?;
+            // SAFETY: The range is part of the mapping of `region`, which is not in use yet.
+            let ret = unsafe {
+                libc::mmap(
+                    host_addr.cast(),
+                    u64_to_usize(map_end - map_start),
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_FIXED | libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+                    -1,
+                    0,
+                )
+            };
+            if ret == libc::MAP_FAILED {
+                return Err(MemoryError::MapHole(std::io::Error::last_os_error()));
+            }
+        }
+        offset = hole_end;
+    }
+    Ok(())
+}
+
 fn create_memfd(
     size: usize,
     hugetlb_size: Option<memfd::HugetlbSize>,
@@ -399,7 +603,7 @@ mod tests {
     #![allow(clippy::undocumented_unsafe_blocks)]
 
     use std::collections::HashMap;
-    use std::io::{Read, Seek};
+    use std::io::{Read, Seek, Write};
 
     use utils::get_page_size;
     use utils::tempfile::TempFile;
@@ -724,6 +928,137 @@ mod tests {
         assert_eq!(second_region, restored_region);
     }
 
+    #[test]
+    fn test_dump_sparse() {
+        let page_size = get_page_size().unwrap();
+
+        // Two regions of four pages each, with a one page gap between them.
+        let region_1_address = GuestAddress(0);
+        let region_2_address = GuestAddress(page_size as u64 * 5);
+        let region_size = page_size * 4;
+        let mem_regions = [
+            (region_1_address, region_size),
+            (region_2_address, region_size),
+        ];
+        let guest_memory =
+            GuestMemoryMmap::from_raw_regions(&mem_regions, false, HugePageConfig::None).unwrap();
+
+        // Only the first and last pages of the first region and the third page of the second
+        // region contain data.
+        let data_page = vec![1u8; page_size];
+        let data_pages = [
+            region_1_address,
+            region_1_address.unchecked_add(page_size as u64 * 3),
+            region_2_address.unchecked_add(page_size as u64 * 2),
+        ];
+        for addr in data_pages {
+            guest_memory.write(&data_page, addr).unwrap();
+        }
+        let memory_state = guest_memory.describe();
+
+        // Stale contents of the file are overwritten by the dump.
+        let mut memory_file = TempFile::new().unwrap().into_file();
+        let mut file_contents = vec![2u8; region_size * 2];
+        memory_file.write_all(&file_contents).unwrap();
+        guest_memory.dump_sparse(&mut memory_file).unwrap();
+
+        let mut expected_contents = vec![0u8; region_size * 2];
+        for page in [0, 3, 6] {
+            expected_contents[page * page_size..(page + 1) * page_size].fill(1);
+        }
+        memory_file.rewind().unwrap();
+        memory_file.read_exact(&mut file_contents).unwrap();
+        assert_eq!(file_contents, expected_contents);
+
+        // The zero pages are holes in the file.
+        assert_eq!(
+            memory_file.seek_data(page_size as u64).unwrap(),
+            Some(page_size as u64 * 3)
+        );
+        assert_eq!(memory_file.seek_data(page_size as u64 * 7).unwrap(), None);
+
+        // Holes are restored as anonymous memory the guest can write to.
+        let restored_guest_memory = GuestMemoryMmap::from_state(
+            Some(&memory_file),
+            &memory_state,
+            false,
+            HugePageConfig::None,
+        )
+        .unwrap();
+        let mut restored_contents = vec![0u8; region_size];
+        restored_guest_memory
+            .read(restored_contents.as_mut_slice(), region_1_address)
+            .unwrap();
+        assert_eq!(restored_contents, expected_contents[..region_size]);
+        restored_guest_memory
+            .read(restored_contents.as_mut_slice(), region_2_address)
+            .unwrap();
+        assert_eq!(restored_contents, expected_contents[region_size..]);
+
+        let hole_address = region_1_address.unchecked_add(page_size as u64);
+        restored_guest_memory
+            .write(&data_page, hole_address)
+            .unwrap();
+        let mut restored_page = vec![0u8; page_size];
+        restored_guest_memory
+            .read(restored_page.as_mut_slice(), hole_address)
+            .unwrap();
+        assert_eq!(restored_page, data_page);
+    }
+
+    #[test]
+    fn test_load_memfd_backed() {
+        // Two regions of one MiB each, with a one MiB gap between them.
+        let region_1_address = GuestAddress(0);
+        let region_2_address = GuestAddress(0x20_0000);
+        let region_size = 0x10_0000;
+        let mem_regions = [
+            (region_1_address, region_size),
+            (region_2_address, region_size),
+        ];
+        let guest_memory =
+            GuestMemoryMmap::from_raw_regions(&mem_regions, false, HugePageConfig::None).unwrap();
+
+        // Fill the first region with 1s and the second with 2s.
+        let first_region = vec![1u8; region_size];
+        guest_memory.write(&first_region, region_1_address).unwrap();
+        let second_region = vec![2u8; region_size];
+        guest_memory
+            .write(&second_region, region_2_address)
+            .unwrap();
+
+        let memory_state = guest_memory.describe();
+        let mut memory_file = TempFile::new().unwrap().into_file();
+        guest_memory.dump(&mut memory_file).unwrap();
+
+        // Memfd backed memory has the layout described in the state, and each region is
+        // backed by the matching range of the same file.
+        let restored_guest_memory =
+            GuestMemoryMmap::memfd_backed_from_state(&memory_state, false, HugePageConfig::None)
+                .unwrap();
+        assert_eq!(restored_guest_memory.describe(), memory_state);
+        let file_offsets = restored_guest_memory
+            .iter()
+            .map(|r| r.file_offset().unwrap().start())
+            .collect::<Vec<_>>();
+        assert_eq!(file_offsets, vec![0, region_size as u64]);
+
+        restored_guest_memory
+            .load(&mut memory_file, &memory_state)
+            .unwrap();
+
+        // Check that the region contents are the same.
+        let mut restored_region = vec![0u8; region_size];
+        restored_guest_memory
+            .read(restored_region.as_mut_slice(), region_1_address)
+            .unwrap();
+        assert_eq!(first_region, restored_region);
+        restored_guest_memory
+            .read(restored_region.as_mut_slice(), region_2_address)
+            .unwrap();
+        assert_eq!(second_region, restored_region);
+    }
+
     #[test]
     fn test_dump_dirty() {
         let page_size = get_page_size().unwrap();
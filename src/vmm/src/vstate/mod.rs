@@ -1,6 +1,8 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+/// Module with the KVM dirty ring implementation.
+pub mod dirty_ring;
 /// Module with GuestMemory implementation.
 pub mod memory;
 /// Module with Vcpu implementation.
@@ -25,6 +25,7 @@ use utils::sm::StateMachine;
 
 use crate::cpu_config::templates::{CpuConfiguration, GuestConfigError};
 use crate::logger::{IncMetric, METRICS};
+use crate::vstate::dirty_ring::{DirtyRing, DirtyRingError, KVM_EXIT_DIRTY_RING_FULL};
 use crate::vstate::vm::Vm;
 use crate::FcExitCode;
 
@@ -58,6 +59,8 @@ pub enum VcpuError {
     VcpuResponse(KvmVcpuError),
     /// Cannot spawn a new vCPU thread: {0}
     VcpuSpawn(io::Error),
+    /// Cannot set up the dirty ring of the vcpu: {0}
+    DirtyRing(DirtyRingError),
     /// Cannot clean init vcpu TLS
     VcpuTlsInit,
     /// Vcpu not present in TLS
@@ -99,6 +102,8 @@ pub struct Vcpu {
     response_receiver: Option<Receiver<VcpuResponse>>,
     /// The transmitting end of the responses channel owned by the vcpu side.
     response_sender: Sender<VcpuResponse>,
+    /// Ring of the pages dirtied by this vcpu, if dirty pages are tracked with dirty rings.
+    dirty_ring: Option<DirtyRing>,
 
     /// Exit reason used to test run_emulation function.
     #[cfg(test)]
@@ -199,6 +204,11 @@ impl Vcpu {
         let (event_sender, event_receiver) = channel();
         let (response_sender, response_receiver) = channel();
         let kvm_vcpu = KvmVcpu::new(index, vm).unwrap();
+        let dirty_ring = vm
+            .dirty_ring()
+            .map(|log| log.map_ring(&kvm_vcpu.fd))
+            .transpose()
+            .map_err(VcpuError::DirtyRing)?;
 
         Ok(Vcpu {
             exit_evt,
@@ -206,6 +216,7 @@ impl Vcpu {
             event_sender: Some(event_sender),
             response_receiver: Some(response_receiver),
             response_sender,
+            dirty_ring,
             kvm_vcpu,
             #[cfg(test)]
             test_vcpu_exit_reason: Mutex::new(None),
@@ -217,6 +228,13 @@ impl Vcpu {
         self.kvm_vcpu.mmio_bus = Some(mmio_bus);
     }
 
+    /// Moves the pages reported in the dirty ring of this vcpu to the dirty ring log of the VM.
+    fn harvest_dirty_ring(&mut self) {
+        if let Some(dirty_ring) = self.dirty_ring.as_mut() {
+            dirty_ring.harvest();
+        }
+    }
+
     /// Moves the vcpu to its own thread and constructs a VcpuHandle.
     /// The handle can be used to control the remote vcpu.
     pub fn start_threaded(
@@ -323,6 +341,12 @@ impl Vcpu {
                     )))
                     .expect("vcpu channel unexpectedly closed");
             }
+            Ok(VcpuEvent::HarvestDirtyRing) => {
+                self.harvest_dirty_ring();
+                self.response_sender
+                    .send(VcpuResponse::HarvestedDirtyRing)
+                    .expect("vcpu channel unexpectedly closed");
+            }
             Ok(VcpuEvent::Finish) => return StateMachine::finish(),
             // Unhandled exit of the other end.
             Err(TryRecvError::Disconnected) => {
@@ -394,6 +418,13 @@ impl Vcpu {
 
                 StateMachine::next(Self::paused)
             }
+            Ok(VcpuEvent::HarvestDirtyRing) => {
+                self.harvest_dirty_ring();
+                self.response_sender
+                    .send(VcpuResponse::HarvestedDirtyRing)
+                    .expect("vcpu channel unexpectedly closed");
+                StateMachine::next(Self::paused)
+            }
             Ok(VcpuEvent::Finish) => StateMachine::finish(),
             // Unhandled exit of the other end.
             Err(_) => {
@@ -507,6 +538,19 @@ impl Vcpu {
                         VcpuExit::InternalError
                     )))
                 }
+                VcpuExit::Unsupported(KVM_EXIT_DIRTY_RING_FULL) => {
+                    // KVM stops the vcpu until the entries of its dirty ring are harvested and
+                    // reset.
+                    if let Some(dirty_ring) = self.dirty_ring.as_mut() {
+                        dirty_ring.harvest();
+                        dirty_ring.log().reset().map_err(|err| {
+                            METRICS.vcpu.failures.inc();
+                            error!("Failed to reset the dirty rings: {}", err);
+                            VcpuError::DirtyRing(err)
+                        })?;
+                    }
+                    Ok(VcpuEmulation::Handled)
+                }
                 VcpuExit::SystemEvent(event_type, event_flags) => match event_type {
                     KVM_SYSTEM_EVENT_RESET | KVM_SYSTEM_EVENT_SHUTDOWN => {
                         info!(
@@ -582,6 +626,8 @@ pub enum VcpuEvent {
     SaveState,
     /// Event to dump CPU configuration of a paused Vcpu.
     DumpCpuConfig,
+    /// Event to harvest the dirty ring of the Vcpu.
+    HarvestDirtyRing,
 }
 
 /// List of responses that the Vcpu reports.
@@ -600,6 +646,8 @@ pub enum VcpuResponse {
     SavedState(Box<VcpuState>),
     /// Vcpu is in the state where CPU config is dumped.
     DumpedCpuConfig(Box<CpuConfiguration>),
+    /// Vcpu dirty ring is harvested.
+    HarvestedDirtyRing,
 }
 
 impl fmt::Debug for VcpuResponse {
@@ -613,6 +661,7 @@ impl fmt::Debug for VcpuResponse {
             Error(ref err) => write!(f, "VcpuResponse::Error({:?})", err),
             NotAllowed(ref reason) => write!(f, "VcpuResponse::NotAllowed({})", reason),
             DumpedCpuConfig(_) => write!(f, "VcpuResponse::DumpedCpuConfig"),
+            HarvestedDirtyRing => write!(f, "VcpuResponse::HarvestedDirtyRing"),
         }
     }
 }
@@ -846,11 +895,13 @@ pub mod tests {
             use crate::VcpuResponse::*;
             // Guard match with no wildcard to make sure we catch new enum variants.
             match self {
-                Paused | Resumed | Exited(_) => (),
+                Paused | Resumed | Exited(_) | HarvestedDirtyRing => (),
                 Error(_) | NotAllowed(_) | SavedState(_) | DumpedCpuConfig(_) => (),
             };
             match (self, other) {
-                (Paused, Paused) | (Resumed, Resumed) => true,
+                (Paused, Paused)
+                | (Resumed, Resumed)
+                | (HarvestedDirtyRing, HarvestedDirtyRing) => true,
                 (Exited(code), Exited(other_code)) => code == other_code,
                 (NotAllowed(_), NotAllowed(_))
                 | (SavedState(_), SavedState(_))
@@ -1194,6 +1245,26 @@ pub mod tests {
         vcpu_handle.send_event(VcpuEvent::Finish).unwrap();
     }
 
+    #[test]
+    fn test_vcpu_harvest_dirty_ring() {
+        let (vcpu_handle, _) = vcpu_configured_for_boot();
+
+        // Dirty rings can be harvested both while paused and while running.
+        queue_event_expect_response(
+            &vcpu_handle,
+            VcpuEvent::HarvestDirtyRing,
+            VcpuResponse::HarvestedDirtyRing,
+        );
+        queue_event_expect_response(&vcpu_handle, VcpuEvent::Resume, VcpuResponse::Resumed);
+        queue_event_expect_response(
+            &vcpu_handle,
+            VcpuEvent::HarvestDirtyRing,
+            VcpuResponse::HarvestedDirtyRing,
+        );
+
+        vcpu_handle.send_event(VcpuEvent::Finish).unwrap();
+    }
+
     #[test]
     fn test_vcpu_rtsig_offset() {
         validate_signal_num(sigrtmin() + VCPU_RTSIG_OFFSET).unwrap();
@@ -7,6 +7,7 @@
 
 #[cfg(target_arch = "x86_64")]
 use std::fmt;
+use std::sync::Arc;
 
 #[cfg(target_arch = "x86_64")]
 use kvm_bindings::{
@@ -25,6 +26,7 @@ use crate::arch::aarch64::gic::GICDevice;
 #[cfg(target_arch = "aarch64")]
 use crate::arch::aarch64::gic::GicState;
 use crate::cpu_config::templates::KvmCapability;
+use crate::vstate::dirty_ring::{DirtyRingError, DirtyRingLog, DirtyRingSupport};
 use crate::vstate::memory::{Address, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
 
 /// Errors associated with the wrappers over KVM ioctls.
@@ -36,6 +38,8 @@ pub enum VmError {
     ApiVersion(i32),
     /// Missing KVM capabilities: {0:x?}
     Capabilities(u32),
+    /// Cannot set up the KVM dirty ring: {0}
+    DirtyRing(DirtyRingError),
     /**  Error creating KVM object: {0} Make sure the user launching the firecracker process is \
     configured on the /dev/kvm file's ACL. */
     Kvm(kvm_ioctls::Error),
@@ -113,6 +117,9 @@ pub enum RestoreStateError {
 pub struct Vm {
     fd: VmFd,
     max_memslots: usize,
+    dirty_ring_support: Option<DirtyRingSupport>,
+    /// Dirty pages harvested from the vCPU dirty rings, if dirty pages are tracked with them.
+    dirty_ring: Option<Arc<DirtyRingLog>>,
 
     /// Additional capabilities that were specified in cpu template.
     pub kvm_cap_modifiers: Vec<KvmCapability>,
@@ -147,6 +154,7 @@ impl Vm {
         Self::check_capabilities(&kvm, &total_caps).map_err(VmError::Capabilities)?;
 
         let max_memslots = kvm.get_nr_memslots();
+        let dirty_ring_support = DirtyRingSupport::check(&kvm);
         // Create fd for interacting with kvm-vm specific functions.
         let vm_fd = kvm.create_vm().map_err(VmError::VmFd)?;
 
@@ -155,6 +163,8 @@ impl Vm {
             Ok(Vm {
                 fd: vm_fd,
                 max_memslots,
+                dirty_ring_support,
+                dirty_ring: None,
                 kvm_cap_modifiers,
                 irqchip_handle: None,
             })
@@ -170,6 +180,8 @@ impl Vm {
             Ok(Vm {
                 fd: vm_fd,
                 max_memslots,
+                dirty_ring_support,
+                dirty_ring: None,
                 kvm_cap_modifiers,
                 supported_cpuid,
                 msrs_to_save,
@@ -257,6 +269,28 @@ impl Vm {
     pub fn fd(&self) -> &VmFd {
         &self.fd
     }
+
+    /// Returns the dirty ring interface supported by the host kernel, if any.
+    pub fn dirty_ring_support(&self) -> Option<DirtyRingSupport> {
+        self.dirty_ring_support
+    }
+
+    /// Tracks dirty pages with per vCPU dirty rings instead of per memory slot bitmaps. Must be
+    /// called before creating the vCPUs.
+    pub fn enable_dirty_ring(&mut self) -> Result<(), VmError> {
+        let support = self
+            .dirty_ring_support
+            .ok_or(VmError::DirtyRing(DirtyRingError::Unsupported))?;
+        let log = DirtyRingLog::new(&self.fd, support).map_err(VmError::DirtyRing)?;
+        self.dirty_ring = Some(Arc::new(log));
+        Ok(())
+    }
+
+    /// Returns the log of the pages harvested from the vCPU dirty rings, if dirty pages are
+    /// tracked with them.
+    pub fn dirty_ring(&self) -> Option<&Arc<DirtyRingLog>> {
+        self.dirty_ring.as_ref()
+    }
 }
 
 #[cfg(target_arch = "aarch64")]
@@ -17,7 +17,9 @@ use vmm::utilities::test_utils::dirty_tracking_vmm;
 use vmm::utilities::test_utils::{create_vmm, default_vmm, default_vmm_no_boot};
 use vmm::vmm_config::instance_info::{InstanceInfo, VmState};
 use vmm::vmm_config::machine_config::HugePageConfig;
-use vmm::vmm_config::snapshot::{CreateSnapshotParams, SnapshotType};
+use vmm::vmm_config::snapshot::{
+    CreateSnapshotParams, MemoryCompression, SnapshotOutput, SnapshotType,
+};
 use vmm::{DumpCpuConfigError, EventManager, FcExitCode};
 
 #[test]
@@ -187,8 +189,12 @@ fn verify_create_snapshot(is_diff: bool) -> (TempFile, TempFile) {
     };
     let snapshot_params = CreateSnapshotParams {
         snapshot_type,
-        snapshot_path: snapshot_file.as_path().to_path_buf(),
-        mem_file_path: memory_file.as_path().to_path_buf(),
+        snapshot_output: SnapshotOutput::Path(snapshot_file.as_path().to_path_buf()),
+        mem_file_output: SnapshotOutput::Path(memory_file.as_path().to_path_buf()),
+        compression: MemoryCompression::None,
+        encryption: None,
+        background: false,
+        integrity: None,
     };
     let vm_info = VmInfo {
         mem_size_mib: 1u64,
@@ -88,6 +88,10 @@ def validate_fc_metrics(metrics):
         "write_bytes",
         "read_count",
         "write_count",
+        "discard_count",
+        "write_zeroes_count",
+        "bounced_reqs_count",
+        "verity_fails",
         "rate_limiter_throttled_events",
         "io_engine_throttled_events",
         "remaining_reqs_count",
@@ -201,6 +205,8 @@ def validate_fc_metrics(metrics):
             "machine_cfg_fails",
             "mmds_count",
             "mmds_fails",
+            "rate_limiter_group_count",
+            "rate_limiter_group_fails",
         ],
         "put_api_requests": [
             "actions_count",
@@ -223,6 +229,8 @@ def validate_fc_metrics(metrics):
             "mmds_fails",
             "vsock_count",
             "vsock_fails",
+            "rate_limiter_group_count",
+            "rate_limiter_group_fails",
         ],
         "seccomp": [
             "num_faults",